@@ -0,0 +1,257 @@
+//! Dry-run action recording
+//!
+//! With `--dry-run`, file writes and commands are not carried out but
+//! recorded as [`Action`]s by an [`ActionRecorder`] carried in the
+//! [`RootContext`](crate::root::RootContext). Modules do not need to know:
+//! file operations go through the root context and commands through its
+//! runner, both of which record instead of acting when a recorder is set.
+//!
+//! The recorder also listens to reporting events, so every action is
+//! attributed to the stage or module that was running when it was
+//! recorded. The result is a [`Plan`], printed as text or JSON.
+
+use crate::CloudInitError;
+use crate::reporting::{Event, EventType, ReportingHandler};
+use async_trait::async_trait;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// A change to the system that would have been made
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// Create or overwrite a file
+    WriteFile { path: PathBuf, size: usize },
+    /// Append to a file, creating it if needed
+    AppendFile { path: PathBuf, size: usize },
+    /// Create a directory and its parents
+    CreateDir { path: PathBuf },
+    /// Change a file's permission bits
+    SetMode {
+        path: PathBuf,
+        #[serde(serialize_with = "octal")]
+        mode: u32,
+    },
+    /// Change a file's owner and group
+    SetOwner { path: PathBuf, uid: u32, gid: u32 },
+    /// Create a symbolic link at `path` pointing to `target`
+    Symlink { path: PathBuf, target: PathBuf },
+    /// Remove a file
+    RemoveFile { path: PathBuf },
+    /// Remove a directory and everything in it
+    RemoveDir { path: PathBuf },
+    /// Run a command
+    RunCommand { command: String },
+    /// Install packages with the system package manager
+    InstallPackages { packages: Vec<String> },
+}
+
+fn octal<S: Serializer>(mode: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{:04o}", mode))
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::WriteFile { path, size } => {
+                write!(f, "write {} ({} bytes)", path.display(), size)
+            }
+            Action::AppendFile { path, size } => {
+                write!(f, "append to {} ({} bytes)", path.display(), size)
+            }
+            Action::CreateDir { path } => write!(f, "mkdir {}", path.display()),
+            Action::SetMode { path, mode } => write!(f, "chmod {:04o} {}", mode, path.display()),
+            Action::SetOwner { path, uid, gid } => {
+                write!(f, "chown {}:{} {}", uid, gid, path.display())
+            }
+            Action::Symlink { path, target } => {
+                write!(f, "link {} -> {}", path.display(), target.display())
+            }
+            Action::RemoveFile { path } => write!(f, "remove {}", path.display()),
+            Action::RemoveDir { path } => write!(f, "remove {} recursively", path.display()),
+            Action::RunCommand { command } => write!(f, "run {}", command),
+            Action::InstallPackages { packages } => {
+                write!(f, "install packages {}", packages.join(" "))
+            }
+        }
+    }
+}
+
+/// An action and the stage or module that would have performed it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedAction {
+    /// Reporting event name, e.g. `modules-config/config-users`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+/// Everything a dry run would have done, in order
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Plan {
+    pub actions: Vec<PlannedAction>,
+}
+
+impl Plan {
+    /// Render as pretty-printed JSON
+    pub fn to_json(&self) -> Result<String, CloudInitError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.actions.is_empty() {
+            return writeln!(f, "No changes planned");
+        }
+
+        let mut current = None;
+        for planned in &self.actions {
+            let scope = Some(planned.scope.as_deref());
+            if scope != current {
+                writeln!(f, "{}:", planned.scope.as_deref().unwrap_or("(no stage)"))?;
+                current = scope;
+            }
+            writeln!(f, "  {}", planned.action)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct RecorderState {
+    actions: Vec<PlannedAction>,
+    /// Names of the reporting scopes currently open, innermost last
+    scopes: Vec<String>,
+}
+
+/// Collects the actions of a dry run
+///
+/// Cloning gives another handle to the same recording.
+#[derive(Debug, Clone, Default)]
+pub struct ActionRecorder {
+    state: Arc<Mutex<RecorderState>>,
+}
+
+impl ActionRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record `action` under the innermost open scope
+    pub fn record(&self, action: Action) {
+        let mut state = self.lock();
+        let scope = state.scopes.last().cloned();
+        state.actions.push(PlannedAction { scope, action });
+    }
+
+    /// Everything recorded so far
+    pub fn plan(&self) -> Plan {
+        Plan {
+            actions: self.lock().actions.clone(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RecorderState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Tracks which stage or module is running from its start/finish events
+#[async_trait]
+impl ReportingHandler for ActionRecorder {
+    fn name(&self) -> &'static str {
+        "dry-run"
+    }
+
+    async fn publish(&self, event: &Event) -> Result<(), CloudInitError> {
+        let mut state = self.lock();
+        match event.event_type {
+            EventType::Start => state.scopes.push(event.name.clone()),
+            EventType::Finish => {
+                if let Some(pos) = state.scopes.iter().rposition(|s| *s == event.name) {
+                    state.scopes.truncate(pos);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reporting::{EventResult, Reporter};
+
+    #[tokio::test]
+    async fn test_actions_attributed_to_scope() {
+        let recorder = ActionRecorder::new();
+        let mut reporter = Reporter::new();
+        reporter.add_handler(Box::new(recorder.clone()));
+
+        reporter.start("modules-config", "config stage").await;
+        reporter
+            .scope("modules-config/config-users", "create users", async {
+                recorder.record(Action::RunCommand {
+                    command: "useradd --create-home alice".to_string(),
+                });
+                Ok(())
+            })
+            .await
+            .unwrap();
+        recorder.record(Action::SetMode {
+            path: PathBuf::from("/etc/motd"),
+            mode: 0o644,
+        });
+        reporter
+            .finish("modules-config", "done", EventResult::Success)
+            .await;
+        recorder.record(Action::RemoveFile {
+            path: PathBuf::from("/tmp/x"),
+        });
+
+        let plan = recorder.plan();
+        let scopes: Vec<_> = plan.actions.iter().map(|a| a.scope.as_deref()).collect();
+        assert_eq!(
+            scopes,
+            vec![
+                Some("modules-config/config-users"),
+                Some("modules-config"),
+                None
+            ]
+        );
+        assert_eq!(
+            plan.to_string(),
+            "modules-config/config-users:\n  run useradd --create-home alice\n\
+             modules-config:\n  chmod 0644 /etc/motd\n\
+             (no stage):\n  remove /tmp/x\n"
+        );
+    }
+
+    #[test]
+    fn test_plan_json() {
+        let recorder = ActionRecorder::new();
+        recorder.record(Action::WriteFile {
+            path: PathBuf::from("/etc/hostname"),
+            size: 4,
+        });
+        recorder.record(Action::SetMode {
+            path: PathBuf::from("/etc/hostname"),
+            mode: 0o600,
+        });
+
+        let json: serde_json::Value =
+            serde_json::from_str(&recorder.plan().to_json().unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"actions": [
+                {"action": "write_file", "path": "/etc/hostname", "size": 4},
+                {"action": "set_mode", "path": "/etc/hostname", "mode": "0600"},
+            ]})
+        );
+        assert_eq!(Plan::default().to_string(), "No changes planned\n");
+    }
+}
@@ -0,0 +1,371 @@
+//! Boot performance analysis
+//!
+//! Backs the `analyze` subcommand. Reads the structured event log (one
+//! JSON object per line, in the same shape as Python cloud-init's
+//! reporting events) and summarises how long each stage and module took.
+//!
+//! ```json
+//! {"event_type": "start", "name": "init-local", "timestamp": 1700000000.1, "description": "..."}
+//! {"event_type": "finish", "name": "init-local/config-hostname", "timestamp": 1700000000.3, "result": "SUCCESS"}
+//! ```
+//!
+//! The log is appended to on every boot. [`boot_records`] splits it into
+//! one record per boot, each starting at an `init-local` start event, and
+//! `blame` and `show` report on each record separately.
+
+pub use crate::reporting::{Event, EventResult, EventType};
+
+use std::fmt::Write as _;
+use tracing::debug;
+
+/// Default location of the structured event log
+pub const DEFAULT_EVENT_LOG: &str = crate::reporting::EVENT_LOG_PATH;
+
+/// First stage of every boot
+const BOOT_STAGE: &str = "init-local";
+
+/// A matched start/finish pair
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    /// Event name
+    pub name: String,
+    /// Epoch seconds at start
+    pub start: f64,
+    /// Seconds between start and finish
+    pub duration: f64,
+    /// Result recorded on the finish event
+    pub result: Option<EventResult>,
+}
+
+impl Span {
+    /// Whether this span is a whole stage (no parent)
+    pub fn is_stage(&self) -> bool {
+        !self.name.contains('/')
+    }
+}
+
+/// Parse a JSON-lines event log, skipping lines that are not events
+pub fn parse_events(content: &str) -> Vec<Event> {
+    content
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(event) => Some(event),
+            Err(e) => {
+                debug!("Skipping unparseable event line: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Pair start and finish events into spans, ordered by start time
+///
+/// Unfinished events are dropped. If a name is started more than once
+/// (e.g. across reboots), each finish closes the most recent start.
+pub fn build_spans(events: &[Event]) -> Vec<Span> {
+    let mut open: Vec<&Event> = Vec::new();
+    let mut spans = Vec::new();
+
+    for event in events {
+        match event.event_type {
+            EventType::Start => open.push(event),
+            EventType::Finish => {
+                if let Some(pos) = open.iter().rposition(|s| s.name == event.name) {
+                    let start = open.remove(pos);
+                    spans.push(Span {
+                        name: event.name.clone(),
+                        start: start.timestamp,
+                        duration: (event.timestamp - start.timestamp).max(0.0),
+                        result: event.result,
+                    });
+                }
+            }
+        }
+    }
+
+    spans.sort_by(|a, b| a.start.total_cmp(&b.start));
+    spans
+}
+
+/// Split the event log into boots, in log order
+///
+/// A record starts at each `init-local` start event; events before the
+/// first one form a record of their own.
+pub fn boot_records(events: &[Event]) -> Vec<&[Event]> {
+    let mut records = Vec::new();
+    let mut start = 0;
+    for (i, event) in events.iter().enumerate() {
+        if i > start && event.event_type == EventType::Start && event.name == BOOT_STAGE {
+            records.push(&events[start..i]);
+            start = i;
+        }
+    }
+    if start < events.len() {
+        records.push(&events[start..]);
+    }
+    records
+}
+
+/// `analyze blame`: every module of each boot, slowest first
+pub fn blame(events: &[Event]) -> String {
+    let records = boot_records(events);
+    let mut out = String::new();
+    for (i, record) in records.iter().enumerate() {
+        let mut spans: Vec<Span> = build_spans(record)
+            .into_iter()
+            .filter(|s| !s.is_stage())
+            .collect();
+        spans.sort_by(|a, b| b.duration.total_cmp(&a.duration));
+
+        let _ = writeln!(out, "-- Boot Record {:02} --", i + 1);
+        for span in &spans {
+            let _ = writeln!(out, "{:>12.5}s ({})", span.duration, span.name);
+        }
+        out.push('\n');
+    }
+    let _ = writeln!(out, "{} boot records analyzed", records.len());
+    out
+}
+
+/// `analyze show`: for each boot, stages in order with the modules each
+/// one ran
+pub fn show(events: &[Event]) -> String {
+    let records = boot_records(events);
+    if records.is_empty() {
+        return "No events found\n".to_string();
+    }
+    let mut out = String::new();
+    for (i, record) in records.iter().enumerate() {
+        let _ = writeln!(out, "-- Boot Record {:02} --", i + 1);
+        show_record(&mut out, record);
+    }
+    let _ = writeln!(out, "{} boot records analyzed", records.len());
+    out
+}
+
+/// One boot of `analyze show`
+fn show_record(out: &mut String, events: &[Event]) {
+    let spans = build_spans(events);
+    let origin = spans.first().map_or(0.0, |first| first.start);
+    for stage in spans.iter().filter(|s| s.is_stage()) {
+        let _ = writeln!(out, "Starting stage: {}", stage.name);
+        let prefix = format!("{}/", stage.name);
+        for module in spans.iter().filter(|s| s.name.starts_with(&prefix)) {
+            let outcome = match module.result {
+                Some(EventResult::Fail) => "failed",
+                _ => "ran successfully",
+            };
+            let _ = writeln!(
+                out,
+                "|`->{} {} @{:09.5}s +{:08.5}s",
+                module.name,
+                outcome,
+                module.start - origin,
+                module.duration
+            );
+        }
+        let _ = writeln!(
+            out,
+            "Finished stage: ({}) {:.5} seconds\n",
+            stage.name, stage.duration
+        );
+    }
+
+    let total: f64 = spans
+        .iter()
+        .filter(|s| s.is_stage())
+        .map(|s| s.duration)
+        .sum();
+    let _ = writeln!(out, "Total Time: {:.5} seconds\n", total);
+}
+
+/// `analyze boot`: kernel boot time versus cloud-init activity in the
+/// latest boot
+///
+/// `btime` is the kernel boot time in epoch seconds (from `/proc/stat`).
+pub fn boot(events: &[Event], btime: Option<f64>) -> String {
+    let spans = build_spans(boot_records(events).last().copied().unwrap_or_default());
+    let stages: Vec<&Span> = spans.iter().filter(|s| s.is_stage()).collect();
+
+    let mut out = String::new();
+    let (Some(first), Some(last)) = (stages.first(), stages.last()) else {
+        return "No events found\n".to_string();
+    };
+    let end = last.start + last.duration;
+
+    if let Some(btime) = btime {
+        let _ = writeln!(out, "Kernel started at (epoch): {:.5}", btime);
+        let _ = writeln!(
+            out,
+            "Time between kernel start and cloud-init-rs activation: {:.5} seconds",
+            first.start - btime
+        );
+    }
+    let _ = writeln!(
+        out,
+        "cloud-init-rs activated at (epoch): {:.5}",
+        first.start
+    );
+    let _ = writeln!(out, "cloud-init-rs finished at (epoch): {:.5}", end);
+    let _ = writeln!(
+        out,
+        "cloud-init-rs total run time: {:.5} seconds",
+        end - first.start
+    );
+    out
+}
+
+/// Extract the kernel boot time (`btime`) from `/proc/stat` content
+pub fn parse_btime(proc_stat: &str) -> Option<f64> {
+    proc_stat
+        .lines()
+        .find_map(|l| l.strip_prefix("btime "))
+        .and_then(|v| v.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ev(event_type: EventType, name: &str, timestamp: f64, result: Option<EventResult>) -> Event {
+        Event {
+            event_type,
+            name: name.to_string(),
+            timestamp,
+            description: String::new(),
+            result,
+        }
+    }
+
+    fn sample_events() -> Vec<Event> {
+        use EventResult::{Fail, Success};
+        use EventType::*;
+        vec![
+            ev(Start, "init-local", 100.0, None),
+            ev(Start, "init-local/config-network", 100.1, None),
+            ev(Finish, "init-local/config-network", 100.4, Some(Success)),
+            ev(Finish, "init-local", 100.5, Some(Success)),
+            ev(Start, "modules-config", 101.0, None),
+            ev(Start, "modules-config/config-users", 101.0, None),
+            ev(Finish, "modules-config/config-users", 103.0, Some(Fail)),
+            ev(Start, "modules-config/config-hostname", 103.0, None),
+            ev(
+                Finish,
+                "modules-config/config-hostname",
+                103.25,
+                Some(EventResult::Success),
+            ),
+            ev(Finish, "modules-config", 103.5, Some(Fail)),
+        ]
+    }
+
+    #[test]
+    fn test_parse_events_json_lines() {
+        let content = concat!(
+            r#"{"event_type":"start","name":"init-local","timestamp":1.5,"description":"x"}"#,
+            "\n\nnot json\n",
+            r#"{"event_type":"finish","name":"init-local","timestamp":2.0,"result":"SUCCESS"}"#,
+            "\n"
+        );
+        let events = parse_events(content);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event_type, EventType::Start);
+        assert_eq!(events[1].result, Some(EventResult::Success));
+    }
+
+    #[test]
+    fn test_build_spans_pairs_and_orders() {
+        let spans = build_spans(&sample_events());
+        assert_eq!(spans.len(), 5);
+        assert_eq!(spans[0].name, "init-local");
+        assert!((spans[0].duration - 0.5).abs() < 1e-9);
+        assert!(spans.iter().all(|s| s.duration >= 0.0));
+    }
+
+    #[test]
+    fn test_build_spans_drops_unfinished() {
+        let events = vec![ev(EventType::Start, "init", 1.0, None)];
+        assert!(build_spans(&events).is_empty());
+    }
+
+    #[test]
+    fn test_blame_sorted_by_duration() {
+        let out = blame(&sample_events());
+        let lines: Vec<&str> = out.lines().filter(|l| l.contains("s (")).collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("modules-config/config-users"));
+        assert!(lines[0].contains("2.00000s"));
+        assert!(lines[2].contains("config-hostname"));
+    }
+
+    #[test]
+    fn test_show_groups_modules_under_stages() {
+        let out = show(&sample_events());
+        assert!(out.contains("Starting stage: init-local"));
+        assert!(out.contains("|`->init-local/config-network ran successfully"));
+        assert!(out.contains("|`->modules-config/config-users failed"));
+        assert!(out.contains("Finished stage: (modules-config) 2.50000 seconds"));
+        assert!(out.contains("Total Time: 3.00000 seconds"));
+    }
+
+    /// The sample boot, then a second boot 1000 seconds later
+    fn two_boots() -> Vec<Event> {
+        let mut events = sample_events();
+        events.extend(sample_events().into_iter().map(|mut event| {
+            event.timestamp += 1000.0;
+            event
+        }));
+        events
+    }
+
+    #[test]
+    fn test_boot_records_split_at_init_local() {
+        let events = two_boots();
+        let records = boot_records(&events);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].len(), sample_events().len());
+        assert_eq!(records[1][0].timestamp, 1100.0);
+        assert!(boot_records(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_blame_and_show_per_boot() {
+        let events = two_boots();
+        let out = blame(&events);
+        assert!(out.contains("-- Boot Record 02 --"));
+        assert!(out.ends_with("2 boot records analyzed\n"));
+        // Each record lists its own modules once
+        let first = out.split("-- Boot Record 02 --").next().unwrap();
+        assert_eq!(first.matches("config-users").count(), 1);
+
+        let out = show(&events);
+        assert_eq!(out.matches("Total Time: 3.00000 seconds").count(), 2);
+        assert!(out.contains("|`->modules-config/config-users failed @001.00000s"));
+        assert!(out.ends_with("2 boot records analyzed\n"));
+
+        // Only the latest boot counts against the kernel start
+        let out = boot(&events, Some(1090.0));
+        assert!(out.contains("activation: 10.00000 seconds"));
+    }
+
+    #[test]
+    fn test_show_empty() {
+        assert_eq!(show(&[]), "No events found\n");
+    }
+
+    #[test]
+    fn test_boot_summary() {
+        let out = boot(&sample_events(), Some(90.0));
+        assert!(out.contains("Kernel started at (epoch): 90.00000"));
+        assert!(out.contains("activation: 10.00000 seconds"));
+        assert!(out.contains("total run time: 3.50000 seconds"));
+    }
+
+    #[test]
+    fn test_parse_btime() {
+        let stat = "cpu  1 2 3\nbtime 1700000000\nprocesses 42\n";
+        assert_eq!(parse_btime(stat), Some(1_700_000_000.0));
+        assert_eq!(parse_btime("cpu 1\n"), None);
+    }
+}
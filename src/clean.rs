@@ -0,0 +1,268 @@
+//! Removing cloud-init state
+//!
+//! `cloud-init-rs clean` removes what earlier boots left behind in
+//! `/var/lib/cloud` and `/run/cloud-init`, so the next boot runs as a new
+//! instance's first. Seeds in `/var/lib/cloud/seed` are kept. `--logs`
+//! removes cloud-init's logs as well.
+//!
+//! `--seal` prepares a golden image, replacing the script usually run at
+//! the end of a Packer build. On top of cleaning state it:
+//!
+//! - truncates the logs, keeping the files and their permissions
+//! - removes the SSH host keys, so every instance generates its own
+//! - resets `/etc/machine-id` (to `uninitialized` under systemd, which
+//!   makes the next boot a first boot)
+//! - writes `/var/lib/cloud/data/sealed`, which makes the next boot
+//!   detect its datasource afresh whatever the cache settings say
+
+use crate::logging::{DEFAULT_LOG_FILE, DEFAULT_OUTPUT_LOG_FILE};
+use crate::reporting::EVENT_LOG_PATH;
+use crate::root::RootContext;
+use crate::service::ServiceManager;
+use crate::{CloudInitError, IoContext};
+use std::path::PathBuf;
+use tokio::fs;
+use tracing::{debug, info};
+
+/// Logs removed by `--logs` and truncated by `--seal`
+const LOG_FILES: &[&str] = &[DEFAULT_LOG_FILE, DEFAULT_OUTPUT_LOG_FILE, EVENT_LOG_PATH];
+
+/// Entry of the state directory that survives cleaning
+const SEED_DIR_NAME: &str = "seed";
+
+/// Directory holding the SSH host keys
+const SSH_DIR: &str = "/etc/ssh";
+
+/// Prefix of the SSH host key files
+const SSH_HOST_KEY_PREFIX: &str = "ssh_host_";
+
+/// systemd's machine ID
+const MACHINE_ID: &str = "/etc/machine-id";
+
+/// D-Bus's copy of the machine ID, usually a link to [`MACHINE_ID`]
+const DBUS_MACHINE_ID: &str = "/var/lib/dbus/machine-id";
+
+/// What to clean besides the state directories
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CleanOptions {
+    /// Remove the logs
+    pub logs: bool,
+    /// Seal the system as a golden image
+    pub seal: bool,
+}
+
+/// Clean the system at `root`, returning the paths removed or reset
+pub async fn clean(
+    root: &RootContext,
+    options: CleanOptions,
+) -> Result<Vec<PathBuf>, CloudInitError> {
+    let mut cleaned = clean_state(root).await?;
+
+    if options.seal {
+        cleaned.extend(truncate_logs(root).await?);
+        cleaned.extend(remove_host_keys(root).await?);
+        cleaned.extend(reset_machine_id(root).await?);
+
+        let marker = root.cloud_paths().sealed_marker();
+        if let Some(parent) = marker.parent() {
+            root.create_dir_all(parent).await?;
+        }
+        root.write_file(&marker, "").await?;
+        info!("Sealed; the next boot runs as a new instance's first");
+    } else if options.logs {
+        for log in LOG_FILES {
+            let path = root.path(log);
+            if path.exists() {
+                root.remove_file(&path).await?;
+                cleaned.push(path);
+            }
+        }
+    }
+    Ok(cleaned)
+}
+
+/// Empty the state directory except for its seeds, and the run directory
+async fn clean_state(root: &RootContext) -> Result<Vec<PathBuf>, CloudInitError> {
+    let paths = root.cloud_paths();
+    let mut cleaned = Vec::new();
+
+    match fs::read_dir(&paths.base).await {
+        Ok(mut entries) => {
+            while let Some(entry) = entries.next_entry().await.with_path(&paths.base)? {
+                if entry.file_name() == SEED_DIR_NAME {
+                    continue;
+                }
+                let path = entry.path();
+                remove(root, &path).await?;
+                cleaned.push(path);
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_path(&paths.base),
+    }
+
+    if paths.run.exists() {
+        remove(root, &paths.run).await?;
+        cleaned.push(paths.run);
+    }
+    Ok(cleaned)
+}
+
+/// Remove a file, link or directory tree
+async fn remove(root: &RootContext, path: &std::path::Path) -> Result<(), CloudInitError> {
+    debug!("Removing {}", path.display());
+    let metadata = fs::symlink_metadata(path).await.with_path(path)?;
+    if metadata.is_dir() {
+        root.remove_dir_all(path).await
+    } else {
+        root.remove_file(path).await
+    }
+}
+
+async fn truncate_logs(root: &RootContext) -> Result<Vec<PathBuf>, CloudInitError> {
+    let mut truncated = Vec::new();
+    for log in LOG_FILES {
+        let path = root.path(log);
+        if path.is_file() {
+            root.write_file(&path, "").await?;
+            truncated.push(path);
+        }
+    }
+    Ok(truncated)
+}
+
+async fn remove_host_keys(root: &RootContext) -> Result<Vec<PathBuf>, CloudInitError> {
+    let dir = root.path(SSH_DIR);
+    let mut removed = Vec::new();
+    let Ok(mut entries) = fs::read_dir(&dir).await else {
+        return Ok(removed);
+    };
+    while let Some(entry) = entries.next_entry().await.with_path(&dir)? {
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(SSH_HOST_KEY_PREFIX)
+        {
+            let path = entry.path();
+            root.remove_file(&path).await?;
+            removed.push(path);
+        }
+    }
+    Ok(removed)
+}
+
+async fn reset_machine_id(root: &RootContext) -> Result<Vec<PathBuf>, CloudInitError> {
+    let mut reset = Vec::new();
+    let machine_id = root.path(MACHINE_ID);
+    if machine_id.is_file() {
+        let contents = match ServiceManager::detect(root) {
+            ServiceManager::Systemd => "uninitialized\n",
+            ServiceManager::OpenRc => "",
+        };
+        root.write_file(&machine_id, contents).await?;
+        reset.push(machine_id);
+    }
+
+    // A link to /etc/machine-id follows it; a copy would keep the old ID
+    let dbus = root.path(DBUS_MACHINE_ID);
+    if fs::symlink_metadata(&dbus).await.is_ok_and(|m| m.is_file()) {
+        root.remove_file(&dbus).await?;
+        reset.push(dbus);
+    }
+    Ok(reset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn populated() -> (TempDir, RootContext) {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let paths = root.cloud_paths();
+        for dir in [
+            paths.instance_dir("i-1"),
+            paths.data_dir(),
+            paths.seed_dir().join("nocloud"),
+            paths.run.clone(),
+            root.path(SSH_DIR),
+            root.path("/var/log"),
+            root.path("/var/lib/dbus"),
+        ] {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        for (path, contents) in [
+            (paths.cached_instance_id(), "i-1\n"),
+            (
+                paths.seed_dir().join("nocloud/meta-data"),
+                "instance-id: i-1\n",
+            ),
+            (paths.run_status_file(), "{}"),
+            (root.path("/etc/ssh/ssh_host_ed25519_key"), "key"),
+            (root.path("/etc/ssh/ssh_host_ed25519_key.pub"), "pub"),
+            (root.path("/etc/ssh/sshd_config"), "Port 22\n"),
+            (root.path(DEFAULT_LOG_FILE), "log\n"),
+            (root.path(MACHINE_ID), "0123456789abcdef\n"),
+            (root.path(DBUS_MACHINE_ID), "0123456789abcdef\n"),
+        ] {
+            std::fs::write(path, contents).unwrap();
+        }
+        (temp, root)
+    }
+
+    #[tokio::test]
+    async fn test_clean_keeps_seed() {
+        let (_temp, root) = populated();
+        let paths = root.cloud_paths();
+
+        clean(&root, CleanOptions::default()).await.unwrap();
+        assert!(!paths.instances_dir().exists());
+        assert!(!paths.data_dir().exists());
+        assert!(!paths.run.exists());
+        assert!(paths.seed_dir().join("nocloud/meta-data").exists());
+        assert!(root.path(DEFAULT_LOG_FILE).exists());
+        assert!(root.path("/etc/ssh/ssh_host_ed25519_key").exists());
+
+        clean(
+            &root,
+            CleanOptions {
+                logs: true,
+                seal: false,
+            },
+        )
+        .await
+        .unwrap();
+        assert!(!root.path(DEFAULT_LOG_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn test_seal() {
+        let (_temp, root) = populated();
+        let paths = root.cloud_paths();
+
+        clean(
+            &root,
+            CleanOptions {
+                logs: false,
+                seal: true,
+            },
+        )
+        .await
+        .unwrap();
+        assert!(!paths.cached_instance_id().exists());
+        assert!(paths.sealed_marker().exists());
+        assert_eq!(
+            std::fs::read_to_string(root.path(DEFAULT_LOG_FILE)).unwrap(),
+            ""
+        );
+        assert!(!root.path("/etc/ssh/ssh_host_ed25519_key").exists());
+        assert!(!root.path("/etc/ssh/ssh_host_ed25519_key.pub").exists());
+        assert!(root.path("/etc/ssh/sshd_config").exists());
+        assert_eq!(
+            std::fs::read_to_string(root.path(MACHINE_ID)).unwrap(),
+            "uninitialized\n"
+        );
+        assert!(!root.path(DBUS_MACHINE_ID).exists());
+    }
+}
@@ -0,0 +1,279 @@
+//! Support tarball for bug reports
+//!
+//! `cloud-init-rs collect-logs` packs what is needed to diagnose a boot
+//! into one `.tar.gz`: the cloud-init logs, `status.json` and
+//! `result.json`, the network configuration cloud-init-rs rendered, the
+//! data fetched from the datasource as `instance-data.json`, and the
+//! version and compiled-in features. Secrets in the instance data and
+//! network files are masked as configured for [`crate::redact`], and user
+//! data is left out unless asked for, since it routinely carries secrets.
+
+use crate::config::load_merged_config;
+use crate::datasources::snapshot;
+use crate::redact::{REDACTED, Redactor};
+use crate::reporting::EVENT_LOG_PATH;
+use crate::root::RootContext;
+use crate::state::CloudPaths;
+use crate::{CloudInitError, IoContext, features};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tracing::{debug, info};
+
+/// Tarball written when no path is given
+pub const DEFAULT_TARBALL: &str = "cloud-init.tar.gz";
+
+/// Directory every entry of the tarball is placed in
+const ARCHIVE_DIR: &str = "cloud-init-logs";
+
+/// Directory searched for `cloud-init*.log`
+const LOG_DIR: &str = "/var/log";
+
+/// Where the network renderers write their files
+const NETWORK_CONFIG_PATHS: &[&str] = &[
+    "/etc/systemd/network",
+    "/etc/NetworkManager/system-connections",
+    "/etc/network/interfaces",
+    "/etc/network/interfaces.d",
+];
+
+/// What to put into the tarball
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CollectOptions {
+    /// Keep user data and vendor data in `instance-data.json`
+    pub include_userdata: bool,
+}
+
+/// Gather the logs and state under `root` into a tarball at `output`
+///
+/// Returns the number of files collected. Missing files are skipped: a
+/// boot that failed early leaves many of them unwritten.
+pub async fn collect_logs(
+    root: &RootContext,
+    output: &Path,
+    options: CollectOptions,
+) -> Result<usize, CloudInitError> {
+    let files = gather(root, options).await?;
+    let tarball = tarball(&files)?;
+    fs::write(output, tarball).await.with_path(output)?;
+    info!("Wrote {} files to {}", files.len(), output.display());
+    Ok(files.len())
+}
+
+/// Files to archive, as archive path and content
+async fn gather(
+    root: &RootContext,
+    options: CollectOptions,
+) -> Result<Vec<(PathBuf, Vec<u8>)>, CloudInitError> {
+    let paths = root.cloud_paths();
+    let redactor = Redactor::from_config(&load_merged_config(&paths).await.unwrap_or_default());
+    let mut files = Vec::new();
+
+    let mut logs = log_files(&root.path(LOG_DIR)).await;
+    logs.push(root.path(EVENT_LOG_PATH));
+    for path in logs
+        .into_iter()
+        .chain([paths.status_file(), paths.result_file()])
+    {
+        add_file(root, &mut files, &path, None).await;
+    }
+
+    if let Some(id) = cached_instance_id(&paths).await {
+        add_file(root, &mut files, &paths.network_config(&id), None).await;
+    }
+    for network in NETWORK_CONFIG_PATHS {
+        let path = root.path(network);
+        if path.is_dir() {
+            for file in dir_files(&path).await {
+                add_file(root, &mut files, &file, Some(&redactor)).await;
+            }
+        } else {
+            add_file(root, &mut files, &path, Some(&redactor)).await;
+        }
+    }
+
+    if let Some(fetched) = snapshot::load(&paths).await {
+        let mut data = serde_json::to_value(&fetched)?;
+        if !options.include_userdata {
+            omit_userdata(&mut data);
+        }
+        redactor.redact_json(&mut data);
+        files.push((
+            PathBuf::from("instance-data.json"),
+            serde_json::to_vec_pretty(&data)?,
+        ));
+    }
+
+    files.push((
+        PathBuf::from("version"),
+        features::long_version().into_bytes(),
+    ));
+    files.push((
+        PathBuf::from("features.json"),
+        serde_json::to_vec_pretty(&features::compiled_features())?,
+    ));
+    Ok(files)
+}
+
+/// Add the file at `path` under its in-system path, if it can be read
+///
+/// Logs were masked as they were written; configuration is masked here.
+async fn add_file(
+    root: &RootContext,
+    files: &mut Vec<(PathBuf, Vec<u8>)>,
+    path: &Path,
+    redactor: Option<&Redactor>,
+) {
+    let Ok(content) = fs::read(path).await else {
+        debug!("Not collecting {}: unreadable", path.display());
+        return;
+    };
+    let relative = path.strip_prefix(root.root()).unwrap_or(path);
+    let relative = relative.strip_prefix("/").unwrap_or(relative);
+    let content = match redactor {
+        Some(redactor) => redactor
+            .redact_text(&String::from_utf8_lossy(&content))
+            .into_bytes(),
+        None => content,
+    };
+    files.push((relative.to_path_buf(), content));
+}
+
+/// `cloud-init*.log` files in `dir`
+async fn log_files(dir: &Path) -> Vec<PathBuf> {
+    dir_files(dir)
+        .await
+        .into_iter()
+        .filter(|path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy())
+                .is_some_and(|name| name.starts_with("cloud-init") && name.ends_with(".log"))
+        })
+        .collect()
+}
+
+/// Regular files directly in `dir`, sorted
+async fn dir_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let Ok(mut entries) = fs::read_dir(dir).await else {
+        return files;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.file_type().await.is_ok_and(|t| t.is_file()) {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    files
+}
+
+async fn cached_instance_id(paths: &CloudPaths) -> Option<String> {
+    let id = fs::read_to_string(paths.cached_instance_id()).await.ok()?;
+    let id = id.trim();
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+/// Replace user data and vendor data in a serialized snapshot
+fn omit_userdata(data: &mut Value) {
+    if let Value::Object(map) = data {
+        for key in ["userdata", "vendordata"] {
+            if let Some(value) = map.get_mut(key) {
+                *value = Value::from(REDACTED);
+            }
+        }
+    }
+}
+
+/// Pack `files` into a gzip-compressed tar under [`ARCHIVE_DIR`]
+fn tarball(files: &[(PathBuf, Vec<u8>)]) -> Result<Vec<u8>, CloudInitError> {
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (path, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(mtime);
+        header.set_cksum();
+        archive.append_data(
+            &mut header,
+            Path::new(ARCHIVE_DIR).join(path),
+            content.as_slice(),
+        )?;
+    }
+    Ok(archive.into_inner()?.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use tempfile::TempDir;
+
+    fn unpack(tarball: &Path) -> Vec<(String, String)> {
+        let file = std::fs::File::open(tarball).unwrap();
+        let mut archive = tar::Archive::new(GzDecoder::new(file));
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().display().to_string();
+                let mut content = String::new();
+                entry.read_to_string(&mut content).unwrap();
+                (path, content)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_collect_logs() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let log_dir = root.path(LOG_DIR);
+        std::fs::create_dir_all(&log_dir).unwrap();
+        std::fs::write(log_dir.join("cloud-init.log"), "booted\n").unwrap();
+        std::fs::write(log_dir.join("syslog"), "unrelated\n").unwrap();
+        let nm = root.path("/etc/NetworkManager/system-connections");
+        std::fs::create_dir_all(&nm).unwrap();
+        std::fs::write(
+            nm.join("wlan0.nmconnection"),
+            "[wifi-security]\npsk=hunter2\n",
+        )
+        .unwrap();
+
+        let output = temp.path().join("out.tar.gz");
+        let count = collect_logs(&root, &output, CollectOptions::default())
+            .await
+            .unwrap();
+        let entries = unpack(&output);
+        assert_eq!(entries.len(), count);
+
+        let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert!(names.contains(&"cloud-init-logs/var/log/cloud-init.log"));
+        assert!(names.contains(&"cloud-init-logs/version"));
+        assert!(!names.iter().any(|name| name.ends_with("syslog")));
+        let (_, connection) = entries
+            .iter()
+            .find(|(name, _)| name.ends_with("wlan0.nmconnection"))
+            .unwrap();
+        assert_eq!(connection, "[wifi-security]\npsk=REDACTED\n");
+    }
+
+    #[test]
+    fn test_omit_userdata() {
+        let mut data = serde_json::json!({
+            "metadata": {"raw": {"hostname": "vm"}},
+            "userdata": {"CloudConfig": {"runcmd": ["echo hi"]}},
+        });
+        omit_userdata(&mut data);
+        assert_eq!(data["metadata"]["raw"]["hostname"], "vm");
+        assert_eq!(data["userdata"], REDACTED);
+    }
+}
@@ -3,7 +3,7 @@
 //! Loads and merges cloud-configs from standard locations.
 
 use super::{CloudConfig, merge};
-use crate::{CloudInitError, state::CloudPaths};
+use crate::{CloudInitError, IoContext, state::CloudPaths};
 use std::path::Path;
 use tokio::fs;
 use tracing::{debug, info, warn};
@@ -34,7 +34,7 @@ async fn load_config_file(path: impl AsRef<Path>) -> Result<Option<CloudConfig>,
         return Ok(None);
     }
 
-    let content = fs::read_to_string(path).await?;
+    let content = fs::read_to_string(path).await.with_path(path)?;
 
     match CloudConfig::from_yaml(&content) {
         Ok(config) => Ok(Some(config)),
@@ -57,8 +57,8 @@ async fn load_dropin_configs(dir: impl AsRef<Path>) -> Result<Vec<CloudConfig>,
     let mut entries = Vec::new();
 
     // Read directory entries
-    let mut read_dir = fs::read_dir(dir).await?;
-    while let Some(entry) = read_dir.next_entry().await? {
+    let mut read_dir = fs::read_dir(dir).await.with_path(dir)?;
+    while let Some(entry) = read_dir.next_entry().await.with_path(dir)? {
         let path = entry.path();
 
         // Only process .cfg files
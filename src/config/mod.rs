@@ -22,13 +22,29 @@ pub struct CloudConfig {
     /// Fully qualified domain name
     pub fqdn: Option<String>,
 
-    /// Whether to manage /etc/hosts
-    pub manage_etc_hosts: Option<bool>,
+    /// Whether (and how) to manage /etc/hosts
+    pub manage_etc_hosts: Option<ManageEtcHosts>,
+
+    /// Use the FQDN as the system hostname when one is configured
+    pub prefer_fqdn_if_set: Option<bool>,
+
+    /// Whether to write /etc/hostname (default `true`)
+    pub create_hostname_file: Option<bool>,
+
+    /// Leave the hostname alone, including on later boots
+    pub preserve_hostname: Option<bool>,
 
     /// Users to create
     #[serde(default)]
     pub users: Vec<UserConfig>,
 
+    /// Override of the distribution's default user
+    ///
+    /// A name or a full user entry; its settings replace those of the
+    /// `default` entry in `users`, which defaults to `[default]` when only
+    /// `user` is given.
+    pub user: Option<UserConfig>,
+
     /// Groups to create
     #[serde(default)]
     pub groups: Vec<GroupConfig>,
@@ -65,12 +81,39 @@ pub struct CloudConfig {
     #[serde(default)]
     pub ssh_authorized_keys: Vec<String>,
 
+    /// Delete the image's SSH host keys on a new instance (default true)
+    pub ssh_deletekeys: Option<bool>,
+
+    /// SSH host key types to generate (default rsa, ecdsa, ed25519)
+    pub ssh_genkeytypes: Option<Vec<String>>,
+
+    /// Whether sshd accepts password logins
+    pub ssh_pwauth: Option<SshPwauth>,
+
+    /// Restrict root's authorized keys to a login hint (default true)
+    pub disable_root: Option<bool>,
+
+    /// Key options for root's authorized keys when `disable_root` is set
+    pub disable_root_opts: Option<String>,
+
+    /// Extra sshd directives, e.g. `{ClientAliveInterval: 120}`
+    pub ssh_config: Option<std::collections::BTreeMap<String, serde_yaml::Value>>,
+
+    /// Block access to the instance metadata service once provisioned
+    pub disable_ec2_metadata: Option<bool>,
+
+    /// Seed data and command for the kernel's random pool
+    pub random_seed: Option<RandomSeedConfig>,
+
     /// Timezone to set
     pub timezone: Option<String>,
 
     /// Locale to set
     pub locale: Option<String>,
 
+    /// Override the file the locale is written to
+    pub locale_configfile: Option<String>,
+
     /// NTP configuration
     pub ntp: Option<NtpConfig>,
 
@@ -80,14 +123,26 @@ pub struct CloudConfig {
     /// Resize rootfs configuration
     pub resize_rootfs: Option<bool>,
 
+    /// fstab entries: `[device, mountpoint, type, options, freq, passno]`,
+    /// where the device may be an alias such as `ephemeral0` or `swap`
+    pub mounts: Option<Vec<Vec<serde_yaml::Value>>>,
+
+    /// Fields filled in for `mounts` entries that leave them out
+    pub mount_default_fields: Option<Vec<serde_yaml::Value>>,
+
     /// Phone home configuration
     pub phone_home: Option<PhoneHomeConfig>,
 
+    /// Reboot or power off once provisioning is done
+    pub power_state: Option<PowerStateConfig>,
+
     /// Final message template
     pub final_message: Option<String>,
 
-    /// Network configuration (inline v2 format)
-    pub network: Option<crate::network::NetworkConfig>,
+    /// Network configuration (inline v1 or v2), `{config: disabled}` to
+    /// keep cloud-init-rs from configuring the network at all, or
+    /// `disabled` to run offline
+    pub network: Option<serde_yaml::Value>,
 
     /// Red Hat subscription configuration
     pub rh_subscription: Option<RhSubscriptionConfig>,
@@ -95,6 +150,187 @@ pub struct CloudConfig {
     /// YUM repositories to add
     #[serde(default)]
     pub yum_repos: std::collections::HashMap<String, YumRepoConfig>,
+
+    /// Zypper repositories and zypp.conf settings
+    pub zypper: Option<ZypperConfig>,
+
+    /// SUSE Customer Center / RMT registration with SUSEConnect
+    pub suse_register: Option<SuseRegisterConfig>,
+
+    /// Chef client bootstrap
+    pub chef: Option<ChefConfig>,
+
+    /// Puppet agent bootstrap
+    pub puppet: Option<PuppetConfig>,
+
+    /// Salt minion bootstrap
+    pub salt_minion: Option<SaltMinionConfig>,
+
+    /// Ansible install and `ansible-pull` run
+    pub ansible: Option<AnsibleConfig>,
+
+    /// WireGuard tunnels brought up with wg-quick
+    pub wireguard: Option<WireguardConfig>,
+
+    /// Ubuntu Pro attachment (also accepted as `ubuntu_advantage`)
+    #[serde(alias = "ubuntu_advantage")]
+    pub ubuntu_pro: Option<UbuntuProConfig>,
+
+    /// Landscape client registration
+    pub landscape: Option<LandscapeConfig>,
+
+    /// GRUB install device preseeding (also accepted as `grub-dpkg`)
+    #[serde(alias = "grub-dpkg")]
+    pub grub_dpkg: Option<GrubDpkgConfig>,
+
+    /// Kernel and GRUB console on a serial port
+    pub serial_console: Option<SerialConsoleConfig>,
+
+    /// Launch byobu at login: `enable-user`, `enable-system`, `enable`,
+    /// `disable-user`, `disable-system` or `disable` (`user` and `system`
+    /// mean enabling)
+    pub byobu_by_default: Option<String>,
+
+    /// Message of the day shown at login
+    pub motd: Option<String>,
+
+    /// Ubuntu fan overlay network
+    pub fan: Option<FanConfig>,
+
+    /// rsyslog configuration and remote log targets
+    pub rsyslog: Option<RsyslogConfig>,
+
+    /// Ship the journal to a systemd-journal-remote server
+    pub journald_upload: Option<JournaldUploadConfig>,
+
+    /// What to publish to GCE guest attributes (default: everything)
+    pub gce_guest_attributes: Option<GceGuestAttributesConfig>,
+
+    /// Reporting handlers, keyed by an arbitrary handler name
+    #[serde(default)]
+    pub reporting: std::collections::HashMap<String, ReportingConfig>,
+
+    /// Which module failures fail the run (read from cloud.cfg)
+    pub module_policy: Option<ModulePolicyConfig>,
+
+    /// Per-datasource settings keyed by datasource name, e.g. `Ec2`
+    #[serde(default)]
+    pub datasource: std::collections::HashMap<String, Option<DatasourceSettings>>,
+
+    /// Watchdog limits for modules and stages (read from cloud.cfg)
+    pub timeouts: Option<TimeoutConfig>,
+
+    /// Independent modules run at once within a stage (read from cloud.cfg)
+    pub module_parallelism: Option<usize>,
+
+    /// Proxy for outbound HTTP (read from cloud.cfg)
+    pub http_proxy: Option<String>,
+
+    /// Proxy for outbound HTTPS (read from cloud.cfg)
+    pub https_proxy: Option<String>,
+
+    /// Comma-separated hosts, domains and CIDR blocks reached directly
+    pub no_proxy: Option<String>,
+
+    /// CA bundle and client certificate for outbound HTTPS (read from
+    /// cloud.cfg)
+    pub http_tls: Option<HttpTlsConfig>,
+
+    /// Extra secrets masked in logs and diagnostic output (read from
+    /// cloud.cfg)
+    pub redaction: Option<RedactionConfig>,
+
+    /// Size limits for user-data and `#include` URLs (read from cloud.cfg)
+    pub user_data_limits: Option<UserDataLimits>,
+
+    /// Trust the cached instance until `clean` removes it (read from cloud.cfg)
+    pub manual_cache_clean: Option<bool>,
+
+    /// How the cached instance is validated on boot: `instance-id` or `metadata`
+    pub cache_check: Option<crate::datasources::cache::CacheCheck>,
+
+    /// Events that re-apply configuration, e.g. `{network: {when: [hotplug]}}`
+    pub updates: Option<UpdatesConfig>,
+
+    /// Log file path (default `/var/log/cloud-init.log`)
+    pub def_log_file: Option<String>,
+
+    /// Log file format: `text` (default) or `json`
+    pub log_format: Option<crate::logging::LogFormat>,
+
+    /// Where logs go besides stderr: `file` (default) or `journald`
+    pub logging: Option<crate::logging::LogBackend>,
+
+    /// Output redirection, e.g. `{all: "| tee -a /var/log/cloud-init-output.log"}`
+    pub output: Option<serde_yaml::Value>,
+
+    /// Executables that handle user-data parts, keyed by MIME type (read
+    /// from cloud.cfg)
+    #[serde(default)]
+    pub handlers: std::collections::BTreeMap<String, String>,
+
+    /// Commands each WebAssembly plugin may run, keyed by plugin name
+    /// (read from cloud.cfg)
+    #[serde(default)]
+    pub plugin_commands: std::collections::BTreeMap<String, Vec<String>>,
+
+    /// Files and directories each WebAssembly plugin may write, keyed by
+    /// plugin name (read from cloud.cfg)
+    #[serde(default)]
+    pub plugin_paths: std::collections::BTreeMap<String, Vec<String>>,
+
+    /// Package mirror templates per architecture (read from cloud.cfg)
+    #[serde(default)]
+    pub package_mirrors: Vec<PackageMirrorConfig>,
+}
+
+/// `/etc/hosts` management mode
+///
+/// Accepts `true`/`false` as well as the Python cloud-init string modes
+/// `"template"` (same as `true`) and `"localhost"`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ManageEtcHosts {
+    /// Leave /etc/hosts untouched
+    #[default]
+    Disabled,
+    /// Render /etc/hosts from a template on every boot
+    Template,
+    /// Only maintain the 127.0.1.1 entry for the local hostname
+    Localhost,
+}
+
+impl Serialize for ManageEtcHosts {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ManageEtcHosts::Disabled => serializer.serialize_bool(false),
+            ManageEtcHosts::Template => serializer.serialize_bool(true),
+            ManageEtcHosts::Localhost => serializer.serialize_str("localhost"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ManageEtcHosts {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bool(bool),
+            Str(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Bool(true) => Ok(ManageEtcHosts::Template),
+            Raw::Bool(false) => Ok(ManageEtcHosts::Disabled),
+            Raw::Str(s) => match s.to_ascii_lowercase().as_str() {
+                "true" | "template" => Ok(ManageEtcHosts::Template),
+                "false" => Ok(ManageEtcHosts::Disabled),
+                "localhost" => Ok(ManageEtcHosts::Localhost),
+                other => Err(serde::de::Error::custom(format!(
+                    "invalid manage_etc_hosts value: {other}"
+                ))),
+            },
+        }
+    }
 }
 
 /// User configuration
@@ -107,6 +343,16 @@ pub enum UserConfig {
     Full(Box<UserFullConfig>),
 }
 
+impl UserConfig {
+    /// The user's name (`default` for the distribution's default user)
+    pub fn name(&self) -> &str {
+        match self {
+            UserConfig::Name(name) => name,
+            UserConfig::Full(config) => &config.name,
+        }
+    }
+}
+
 /// Full user configuration
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
@@ -121,11 +367,105 @@ pub struct UserFullConfig {
     pub sudo: Option<String>,
     pub lock_passwd: Option<bool>,
     pub passwd: Option<String>,
+    /// Pre-hashed password (takes precedence over `plain_text_passwd`)
+    pub hashed_passwd: Option<String>,
+    /// Clear-text password, hashed with SHA-512 crypt before use
+    pub plain_text_passwd: Option<String>,
+    /// Account expiry date (`YYYY-MM-DD`)
+    pub expiredate: Option<String>,
+    /// Days after password expiry before the account is disabled
+    #[serde(deserialize_with = "string_or_number", default)]
+    pub inactive: Option<String>,
+    /// Do not create the user's home directory
+    pub no_create_home: Option<bool>,
+    /// Do not create a group with the same name as the user
+    pub no_user_group: Option<bool>,
     #[serde(default)]
     pub ssh_authorized_keys: Vec<String>,
     pub ssh_import_id: Option<Vec<String>>,
     pub system: Option<bool>,
     pub uid: Option<u32>,
+    /// Create missing supplementary groups (default true); when false they
+    /// are skipped with a warning
+    pub create_groups: Option<bool>,
+    /// Install the platform's SSH keys with options that tell whoever
+    /// logs in to use the default user instead
+    pub ssh_redirect_user: Option<bool>,
+    /// Expire the password, forcing a change at the next login
+    pub expire: Option<bool>,
+    /// Delete the account; its home directory is kept
+    pub remove: Option<bool>,
+    /// Make `ssh_authorized_keys` the only keys in authorized_keys,
+    /// dropping any added outside cloud-init
+    pub ssh_authorized_keys_exclusive: Option<bool>,
+}
+
+/// Deserialize a scalar that may be written as either a string or a number
+fn string_or_number<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Scalar {
+        Str(String),
+        Int(i64),
+    }
+
+    Ok(
+        Option::<Scalar>::deserialize(deserializer)?.map(|s| match s {
+            Scalar::Str(s) => s,
+            Scalar::Int(i) => i.to_string(),
+        }),
+    )
+}
+
+/// `ssh_pwauth` setting
+///
+/// Accepts `true`/`false` (or `yes`/`no`) and Python cloud-init's
+/// `"unchanged"`, which leaves sshd's own setting alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SshPwauth {
+    /// `PasswordAuthentication yes`
+    Enabled,
+    /// `PasswordAuthentication no`
+    Disabled,
+    /// Leave sshd_config alone
+    Unchanged,
+}
+
+impl Serialize for SshPwauth {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            SshPwauth::Enabled => serializer.serialize_bool(true),
+            SshPwauth::Disabled => serializer.serialize_bool(false),
+            SshPwauth::Unchanged => serializer.serialize_str("unchanged"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SshPwauth {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bool(bool),
+            Str(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Bool(true) => Ok(SshPwauth::Enabled),
+            Raw::Bool(false) => Ok(SshPwauth::Disabled),
+            Raw::Str(s) => match s.to_ascii_lowercase().as_str() {
+                "true" | "yes" => Ok(SshPwauth::Enabled),
+                "false" | "no" => Ok(SshPwauth::Disabled),
+                "unchanged" => Ok(SshPwauth::Unchanged),
+                other => Err(serde::de::Error::custom(format!(
+                    "invalid ssh_pwauth value: {other}"
+                ))),
+            },
+        }
+    }
 }
 
 /// Group configuration
@@ -134,8 +474,20 @@ pub struct UserFullConfig {
 pub enum GroupConfig {
     /// Simple group name
     Name(String),
-    /// Group with members
-    WithMembers { name: String, members: Vec<String> },
+    /// Group with members and optional creation settings
+    WithMembers {
+        name: String,
+        #[serde(default)]
+        members: Vec<String>,
+        /// Numeric group ID to assign
+        #[serde(default)]
+        gid: Option<u32>,
+        /// Create as a system group
+        #[serde(default)]
+        system: Option<bool>,
+    },
+    /// Python cloud-init mapping form (`- admins: [alice, bob]`)
+    Mapping(std::collections::HashMap<String, Vec<String>>),
 }
 
 /// File to write
@@ -149,6 +501,28 @@ pub struct WriteFileConfig {
     pub permissions: Option<String>,
     pub append: Option<bool>,
     pub defer: Option<bool>,
+    /// Fetch the content from a URL; `content` is the fallback
+    pub source: Option<WriteFileSource>,
+    /// `sha256:<hex>` digest the written content must match
+    pub checksum: Option<String>,
+}
+
+/// Where a `write_files` entry is fetched from
+///
+/// ```yaml
+/// write_files:
+///   - path: /opt/app/app.tar.gz
+///     source:
+///       uri: https://artifacts.example.com/app.tar.gz
+///       headers: {Authorization: Bearer abc}
+///     checksum: sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriteFileSource {
+    pub uri: String,
+    /// Request headers, e.g. for authentication
+    #[serde(default)]
+    pub headers: std::collections::HashMap<String, String>,
 }
 
 /// Command to run (can be string or list of args)
@@ -199,6 +573,22 @@ pub struct GrowpartConfig {
     pub ignore_growroot_disabled: Option<bool>,
 }
 
+/// Random seed configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RandomSeedConfig {
+    /// File the data is appended to (default `/dev/urandom`)
+    pub file: Option<String>,
+    /// Seed data
+    pub data: Option<String>,
+    /// Encoding of `data`: `raw` (default), `base64`/`b64` or `gzip`/`gz`
+    pub encoding: Option<String>,
+    /// Command run afterwards, e.g. `pollinate`
+    pub command: Option<RunCmd>,
+    /// Fail if `command` is not installed (default false)
+    pub command_required: Option<bool>,
+}
+
 /// Phone home configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PhoneHomeConfig {
@@ -207,6 +597,29 @@ pub struct PhoneHomeConfig {
     pub tries: Option<u32>,
 }
 
+/// Power state change configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PowerStateConfig {
+    /// `reboot`, `poweroff` or `halt`
+    pub mode: String,
+    /// `now`, or minutes to wait as `+N` or `N` (default `now`)
+    pub delay: Option<serde_yaml::Value>,
+    /// Message passed to `shutdown`
+    pub message: Option<String>,
+    /// Seconds to wait for cloud-init to exit first (default 30)
+    pub timeout: Option<u64>,
+    /// Only act if true, or if this command exits 0
+    pub condition: Option<PowerStateCondition>,
+}
+
+/// Condition gating a power state change
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PowerStateCondition {
+    Bool(bool),
+    Command(RunCmd),
+}
+
 /// NTP configuration
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
@@ -269,6 +682,405 @@ pub struct RhSubscriptionConfig {
     pub disable_repo: Vec<String>,
 }
 
+/// Chef client configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChefConfig {
+    /// `packages` (default) or `omnibus`
+    pub install_type: Option<String>,
+    /// Install even if `chef-client` is already present
+    pub force_install: Option<bool>,
+    /// Omnibus installer script URL
+    pub omnibus_url: Option<String>,
+    /// Chef version for the omnibus installer
+    pub omnibus_version: Option<String>,
+    /// Chef server URL
+    pub server_url: Option<String>,
+    /// Node name (default: the instance ID)
+    pub node_name: Option<String>,
+    /// Chef environment (default `_default`)
+    pub environment: Option<String>,
+    /// Validation client name (default `chef-validator`)
+    pub validation_name: Option<String>,
+    /// Validation key contents, or `system` to use an existing key
+    pub validation_cert: Option<String>,
+    /// Where the validation key lives (default `/etc/chef/validation.pem`)
+    pub validation_key: Option<String>,
+    /// Run list for the first chef-client run
+    pub run_list: Vec<String>,
+    /// Node attributes for the first chef-client run
+    pub initial_attributes: Option<serde_yaml::Value>,
+    /// Start chef-client once configured
+    pub exec: Option<bool>,
+    /// Value for `chef_license`, e.g. `accept`
+    pub chef_license: Option<String>,
+}
+
+/// Puppet agent configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PuppetConfig {
+    /// Install the agent package if missing (default true)
+    pub install: Option<bool>,
+    /// Package to install (default `puppet`)
+    pub package_name: Option<String>,
+    /// puppet.conf location (default depends on the install layout)
+    pub conf_file: Option<String>,
+    /// Puppet SSL directory
+    pub ssl_dir: Option<String>,
+    /// csr_attributes.yaml location
+    pub csr_attributes_path: Option<String>,
+    /// puppet.conf settings by section; `ca_cert` holds the CA certificate
+    pub conf: std::collections::BTreeMap<String, serde_yaml::Value>,
+    /// Contents of csr_attributes.yaml
+    pub csr_attributes: Option<serde_yaml::Value>,
+    /// Enable and start the puppet service (default true)
+    pub start_service: Option<bool>,
+    /// Run `puppet agent` once configured
+    pub exec: Option<bool>,
+    /// Arguments for that run (default `--test`)
+    pub exec_args: Vec<String>,
+}
+
+/// Salt minion configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SaltMinionConfig {
+    /// Package to install (default `salt-minion`)
+    pub pkg_name: Option<String>,
+    /// Service to start (default `salt-minion`)
+    pub service_name: Option<String>,
+    /// Salt configuration directory (default `/etc/salt`)
+    pub config_dir: Option<String>,
+    /// Minion key directory (default `<config_dir>/pki/minion`)
+    pub pki_dir: Option<String>,
+    /// Minion configuration, written to `<config_dir>/minion`
+    pub conf: Option<serde_yaml::Value>,
+    /// Static grains, written to `<config_dir>/grains`
+    pub grains: Option<serde_yaml::Value>,
+    /// Minion ID
+    pub minion_id: Option<String>,
+    /// Pre-seeded minion public key
+    pub public_key: Option<String>,
+    /// Pre-seeded minion private key
+    pub private_key: Option<String>,
+}
+
+/// Ansible configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AnsibleConfig {
+    /// `distro` (default) or `pip`
+    pub install_method: Option<String>,
+    /// Package to install (default `ansible-core`)
+    pub package_name: Option<String>,
+    /// User to run `ansible-pull` as (default root)
+    pub run_user: Option<String>,
+    /// Playbook to pull and apply
+    pub pull: Option<AnsiblePullConfig>,
+}
+
+/// `ansible-pull` settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnsiblePullConfig {
+    /// Repository to pull the playbook from
+    pub url: String,
+    /// Playbook to run, relative to the repository
+    pub playbook_name: String,
+    /// Branch, tag or commit to check out
+    pub checkout: Option<String>,
+    /// Add the repository's host key if unknown
+    pub accept_host_key: Option<bool>,
+    /// Discard local changes in the checkout
+    pub clean: Option<bool>,
+    /// Do a full clone instead of a shallow one
+    pub full: Option<bool>,
+    /// Show the changes made to files
+    pub diff: Option<bool>,
+    /// SSH key for the repository
+    pub private_key: Option<String>,
+    /// Vault password file
+    pub vault_password_file: Option<String>,
+}
+
+/// WireGuard configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WireguardConfig {
+    /// Tunnels to configure
+    pub interfaces: Vec<WireguardInterface>,
+    /// Shell commands that must succeed once the tunnels are up
+    pub readinessprobe: Vec<String>,
+}
+
+/// A WireGuard tunnel, given either as `content` or field by field
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WireguardInterface {
+    /// Interface name, e.g. `wg0`
+    pub name: String,
+    /// wg-quick config file (default `/etc/wireguard/<name>.conf`)
+    pub config_path: Option<String>,
+    /// Complete wg-quick config, used verbatim
+    pub content: Option<String>,
+    /// Addresses of the interface in CIDR form
+    #[serde(default)]
+    pub address: Vec<String>,
+    pub listen_port: Option<u16>,
+    /// Private key itself
+    pub private_key: Option<String>,
+    /// File holding the private key, read when the tunnel comes up
+    pub private_key_file: Option<String>,
+    /// DNS servers while the tunnel is up
+    #[serde(default)]
+    pub dns: Vec<String>,
+    #[serde(default)]
+    pub peers: Vec<WireguardPeer>,
+}
+
+/// A WireGuard peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WireguardPeer {
+    pub public_key: String,
+    /// `host:port` to reach the peer at
+    pub endpoint: Option<String>,
+    /// Networks routed to the peer
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+    pub preshared_key: Option<String>,
+    /// Keepalive interval in seconds, for peers behind NAT
+    pub persistent_keepalive: Option<u32>,
+}
+
+/// Ubuntu Pro configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UbuntuProConfig {
+    /// Contract token to attach with
+    pub token: Option<String>,
+    /// Services to enable instead of the contract's defaults
+    pub enable: Option<Vec<String>>,
+    /// Beta services to enable
+    pub enable_beta: Vec<String>,
+    /// Settings applied with `pro config set`, e.g. `http_proxy`
+    pub config: std::collections::BTreeMap<String, serde_yaml::Value>,
+}
+
+/// Landscape client configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LandscapeConfig {
+    /// Settings of the `[client]` section of client.conf
+    pub client: std::collections::BTreeMap<String, serde_yaml::Value>,
+}
+
+/// Ubuntu fan configuration
+///
+/// ```yaml
+/// fan:
+///   config: |
+///     # fan 240
+///     10.0.0.0/8 eth0/16 dhcp
+///   config_path: /etc/network/fan
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FanConfig {
+    /// Content of the fan configuration file
+    pub config: Option<String>,
+    /// Where to write it (default `/etc/network/fan`)
+    pub config_path: Option<String>,
+}
+
+/// rsyslog configuration
+///
+/// ```yaml
+/// rsyslog:
+///   configs:
+///     - "*.* @@192.0.2.1"
+///     - content: "*.* @@192.0.2.2:10514"
+///       filename: 01-example.conf
+///   remotes:
+///     maas: 192.0.2.3
+///     juju: "@@[2001:db8::1]:514"
+///   service_reload_command: auto
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RsyslogConfig {
+    /// Directory relative `filename`s are in (default `/etc/rsyslog.d`)
+    pub config_dir: Option<String>,
+    /// File for entries without a `filename` (default `20-cloud-config.conf`)
+    pub config_filename: Option<String>,
+    /// Configuration snippets, appended in order to their files
+    pub configs: Vec<RsyslogConfigEntry>,
+    /// Remote targets by name: `[selector] [@|@@]host[:port]`
+    pub remotes: std::collections::BTreeMap<String, String>,
+    /// `auto` to restart rsyslog, or the command to run after changes
+    pub service_reload_command: Option<RsyslogReload>,
+}
+
+/// A snippet of rsyslog configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RsyslogConfigEntry {
+    /// Content for the default file
+    Content(String),
+    /// Content for a named file
+    File {
+        content: String,
+        #[serde(default)]
+        filename: Option<String>,
+    },
+}
+
+/// How rsyslog picks up changed configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RsyslogReload {
+    /// `auto`, or a shell command
+    Shell(String),
+    /// Command and arguments
+    Command(Vec<String>),
+}
+
+/// systemd-journal-upload configuration
+///
+/// ```yaml
+/// journald_upload:
+///   url: https://logs.example.com:19532
+///   trusted_certificate_file: /etc/ssl/certs/logs-ca.pem
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JournaldUploadConfig {
+    /// Address of the systemd-journal-remote server
+    pub url: String,
+    /// Client key for TLS
+    pub server_key_file: Option<String>,
+    /// Client certificate for TLS
+    pub server_certificate_file: Option<String>,
+    /// CA certificate the server's certificate is checked against
+    pub trusted_certificate_file: Option<String>,
+}
+
+/// GCE guest attributes published at the end of the final stage
+///
+/// ```yaml
+/// gce_guest_attributes:
+///   host_keys: true
+///   provisioned: false
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GceGuestAttributesConfig {
+    /// Publish SSH host keys under `hostkeys/` (default true)
+    pub host_keys: Option<bool>,
+    /// Set `cloud-init/provisioned` once provisioning is done (default true)
+    pub provisioned: Option<bool>,
+}
+
+/// GRUB debconf configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GrubDpkgConfig {
+    /// Set to false to leave GRUB alone
+    pub enabled: Option<bool>,
+    /// BIOS install device (default: the disk holding /boot)
+    #[serde(rename = "grub-pc/install_devices")]
+    pub install_devices: Option<String>,
+    /// Whether an empty install device is intended
+    #[serde(rename = "grub-pc/install_devices_empty")]
+    pub install_devices_empty: Option<bool>,
+    /// EFI install device (default: the disk holding /boot/efi)
+    #[serde(rename = "grub-efi/install_devices")]
+    pub efi_install_devices: Option<String>,
+}
+
+/// Serial console configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SerialConsoleConfig {
+    /// Serial port (default `ttyS0`)
+    pub device: Option<String>,
+    /// Speed in baud (default 115200)
+    pub baud: Option<u32>,
+}
+
+/// Zypper configuration
+///
+/// ```yaml
+/// zypper:
+///   repos:
+///     - id: opensuse-oss
+///       name: openSUSE OSS
+///       baseurl: http://download.opensuse.org/distribution/leap/15.6/repo/oss/
+///       enabled: true
+///       autorefresh: true
+///   config:
+///     download.use_deltarpm: false
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ZypperConfig {
+    /// Repositories, written to `/etc/zypp/repos.d/<id>.repo`
+    pub repos: Vec<ZypperRepoConfig>,
+    /// Settings for `/etc/zypp/zypp.conf`
+    pub config: std::collections::BTreeMap<String, serde_yaml::Value>,
+}
+
+/// A zypper repository
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ZypperRepoConfig {
+    /// Repository alias, the section name and file name
+    pub id: String,
+    /// Human-readable name (default: the id)
+    pub name: Option<String>,
+    /// Base URL of the repository
+    pub baseurl: Option<String>,
+    /// Further .repo settings, e.g. `enabled`, `autorefresh`, `gpgkey`
+    #[serde(flatten)]
+    pub settings: std::collections::BTreeMap<String, serde_yaml::Value>,
+}
+
+/// SUSEConnect registration
+///
+/// ```yaml
+/// suse_register:
+///   regcode: BASE-PRODUCT-KEY
+///   email: ops@example.com
+///   products:
+///     - sle-module-containers/15.6/x86_64
+///     - identifier: SLES-LTSS/15.6/x86_64
+///       regcode: LTSS-KEY
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SuseRegisterConfig {
+    /// Registration code of the base product
+    pub regcode: Option<String>,
+    /// Contact address for the registration
+    pub email: Option<String>,
+    /// Registration server, e.g. an RMT instance (default: SCC)
+    pub url: Option<String>,
+    /// Extensions and modules to activate after the base product
+    pub products: Vec<SuseProduct>,
+}
+
+/// A SUSE extension or module to activate
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SuseProduct {
+    /// `identifier/version/arch`, free of charge
+    Identifier(String),
+    /// A product with its own registration code
+    WithRegcode {
+        identifier: String,
+        #[serde(default)]
+        regcode: Option<String>,
+    },
+}
+
 /// Configuration for a single YUM/DNF repository
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
@@ -313,6 +1125,336 @@ pub struct YumRepoConfig {
     pub sslcacert: Option<String>,
 }
 
+/// A reporting handler from the `reporting` key
+///
+/// Selected by the `type` field, as in Python cloud-init.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ReportingConfig {
+    /// Log events through the normal logger
+    Log,
+    /// POST events to an HTTP endpoint
+    Webhook(WebhookReportingConfig),
+    /// Write events to the Hyper-V KVP pool (Azure)
+    Hyperv(HypervReportingConfig),
+}
+
+/// Webhook reporting handler settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookReportingConfig {
+    /// URL events are POSTed to
+    pub endpoint: String,
+    /// Shared secret used to HMAC-SHA256 sign each request body
+    pub secret: Option<String>,
+    /// Per-request timeout in seconds
+    pub timeout: Option<u64>,
+    /// Number of retries after a failed request
+    pub retries: Option<u32>,
+}
+
+/// Hyper-V KVP reporting handler settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HypervReportingConfig {
+    /// KVP pool file (default `/var/lib/hyperv/.kvp_pool_1`)
+    pub kvp_file_path: Option<String>,
+}
+
+/// Event that may re-apply datasource configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdateEvent {
+    /// Every boot
+    Boot,
+    /// The first boot of a new instance
+    BootNewInstance,
+    /// Boots handled by older cloud-init releases
+    BootLegacy,
+    /// A device, e.g. a NIC, was attached or detached
+    Hotplug,
+}
+
+/// Events that re-apply one kind of configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpdatePolicy {
+    pub when: Vec<UpdateEvent>,
+}
+
+impl Default for UpdatePolicy {
+    fn default() -> Self {
+        Self {
+            when: vec![UpdateEvent::BootNewInstance],
+        }
+    }
+}
+
+/// The `updates` key
+///
+/// ```yaml
+/// updates:
+///   network:
+///     when: [boot-new-instance, hotplug]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpdatesConfig {
+    pub network: UpdatePolicy,
+}
+
+/// What a module failure means for the run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ModulePolicy {
+    /// Log and record the failure, then carry on (default)
+    #[default]
+    #[serde(alias = "best_effort")]
+    BestEffort,
+    /// Fail the stage and exit non-zero once the stage's other modules ran
+    Required,
+}
+
+/// Per-module failure policy from the `module_policy` key
+///
+/// Modules are named as in reporting events, e.g. `users`, `write_files`
+/// or `set_hostname`:
+///
+/// ```yaml
+/// module_policy:
+///   default: best-effort
+///   required: [users, write_files]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModulePolicyConfig {
+    /// Policy for modules not listed below
+    pub default: ModulePolicy,
+    /// Modules whose failure fails the run
+    pub required: Vec<String>,
+    /// Modules whose failure is only recorded; wins over `required`
+    pub best_effort: Vec<String>,
+}
+
+impl ModulePolicyConfig {
+    /// Policy applying to `module`
+    pub fn policy_for(&self, module: &str) -> ModulePolicy {
+        if self.best_effort.iter().any(|m| m == module) {
+            ModulePolicy::BestEffort
+        } else if self.required.iter().any(|m| m == module) {
+            ModulePolicy::Required
+        } else {
+            self.default
+        }
+    }
+}
+
+/// Default seconds a datasource may take to answer (`max_wait`)
+pub const DEFAULT_MAX_WAIT_SECS: u64 = 120;
+
+/// Default seconds to wait for another run to release the lock
+pub const DEFAULT_LOCK_WAIT_SECS: u64 = 300;
+
+/// Default seconds a single module may run
+pub const DEFAULT_MODULE_TIMEOUT_SECS: u64 = 1800;
+
+/// Settings for one datasource under the `datasource` key
+///
+/// Other keys Python cloud-init accepts here are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DatasourceSettings {
+    /// Seconds to wait for the datasource to answer before moving on
+    pub max_wait: Option<u64>,
+    /// EC2: only trust the instance identity document if its signature
+    /// checks out against `identity_certificates`
+    pub verify_identity: Option<bool>,
+    /// EC2: PEM file with the AWS certificates for the instance's region
+    pub identity_certificates: Option<String>,
+    /// Metadata service base URLs to try in order, replacing the
+    /// datasource's IPv4 and IPv6 defaults
+    pub metadata_urls: Option<Vec<String>>,
+}
+
+/// TLS settings for outbound HTTPS from the `http_tls` key
+///
+/// ```yaml
+/// http_tls:
+///   ca_bundle: /etc/cloud/provisioning-ca.pem
+///   client_cert: /etc/cloud/client.pem
+///   client_key: /etc/cloud/client-key.pem
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpTlsConfig {
+    /// PEM bundle of CAs trusted in addition to the built-in roots
+    pub ca_bundle: Option<String>,
+    /// PEM certificate presented to servers that ask for one
+    pub client_cert: Option<String>,
+    /// PEM private key of `client_cert`, if not in the same file
+    pub client_key: Option<String>,
+}
+
+/// Package mirrors for some architectures, from the `package_mirrors` key
+///
+/// `search` holds templates per mirror (`primary`, `security`) whose
+/// `%(name)s` placeholders are filled from the instance metadata; the
+/// `failsafe` mirror is used when none of them works out. `default` in
+/// `arches` matches any architecture.
+///
+/// ```yaml
+/// package_mirrors:
+///   - arches: [amd64, i386]
+///     failsafe:
+///       primary: http://archive.ubuntu.com/ubuntu
+///       security: http://security.ubuntu.com/ubuntu
+///     search:
+///       primary:
+///         - http://%(ec2_region)s.ec2.archive.ubuntu.com/ubuntu/
+///         - http://%(region)s.clouds.archive.ubuntu.com/ubuntu/
+///       security: []
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PackageMirrorConfig {
+    /// Architectures this entry applies to, in Debian or kernel naming
+    pub arches: Vec<String>,
+    /// Mirror used when no search template resolves, by mirror name
+    pub failsafe: std::collections::BTreeMap<String, String>,
+    /// Templates tried in order, by mirror name
+    pub search: std::collections::BTreeMap<String, Vec<String>>,
+}
+
+/// Size limits for untrusted user-data, from the `user_data_limits` key
+///
+/// Sizes are in bytes. Decompressed gzip output counts against its own
+/// limit, so a small compressed payload cannot expand without bound.
+///
+/// ```yaml
+/// user_data_limits:
+///   max_size: 16777216
+///   max_include_size: 16777216
+///   max_decompressed_size: 67108864
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserDataLimits {
+    /// User-data handed over by the datasource (default 16 MiB)
+    pub max_size: Option<usize>,
+    /// One `#include` URL's response (default 16 MiB)
+    pub max_include_size: Option<usize>,
+    /// Output of decompressing gzip data (default 64 MiB)
+    pub max_decompressed_size: Option<usize>,
+}
+
+/// Default for [`UserDataLimits::max_size`]
+pub const DEFAULT_USER_DATA_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+/// Default for [`UserDataLimits::max_include_size`]
+pub const DEFAULT_INCLUDE_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+/// Default for [`UserDataLimits::max_decompressed_size`]
+pub const DEFAULT_DECOMPRESSED_MAX_SIZE: usize = 64 * 1024 * 1024;
+
+impl UserDataLimits {
+    pub fn max_size(&self) -> usize {
+        self.max_size.unwrap_or(DEFAULT_USER_DATA_MAX_SIZE)
+    }
+
+    pub fn max_include_size(&self) -> usize {
+        self.max_include_size.unwrap_or(DEFAULT_INCLUDE_MAX_SIZE)
+    }
+
+    pub fn max_decompressed_size(&self) -> usize {
+        self.max_decompressed_size
+            .unwrap_or(DEFAULT_DECOMPRESSED_MAX_SIZE)
+    }
+}
+
+/// Secrets masked by [`crate::redact`], from the `redaction` key
+///
+/// ```yaml
+/// redaction:
+///   sensitive_keys: [api_key]
+///   patterns: ['AKIA[0-9A-Z]{16}']
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RedactionConfig {
+    /// Key words masked in addition to the built-in ones
+    pub sensitive_keys: Vec<String>,
+    /// Regular expressions whose matches are masked
+    pub patterns: Vec<String>,
+}
+
+/// Watchdog limits from the `timeouts` key
+///
+/// All values are in seconds; `0` means no limit.
+///
+/// ```yaml
+/// timeouts:
+///   module: 600
+///   modules: {package_update_upgrade_install: 3600}
+///   stage: 5400
+///   lock: 60
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    /// Limit for any single module (default 1800)
+    pub module: Option<u64>,
+    /// Per-module overrides, keyed by module name
+    pub modules: std::collections::HashMap<String, u64>,
+    /// Limit for a whole stage (default: none)
+    pub stage: Option<u64>,
+    /// How long to wait for another run to release the state directory
+    /// (default 300); `0` fails at once instead of meaning no limit
+    pub lock: Option<u64>,
+}
+
+impl TimeoutConfig {
+    /// How long `module` may run, `None` for no limit
+    pub fn module_timeout(&self, module: &str) -> Option<std::time::Duration> {
+        let secs = self
+            .modules
+            .get(module)
+            .copied()
+            .or(self.module)
+            .unwrap_or(DEFAULT_MODULE_TIMEOUT_SECS);
+        (secs > 0).then(|| std::time::Duration::from_secs(secs))
+    }
+
+    /// How long to wait for the lock held by another run
+    pub fn lock_wait(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.lock.unwrap_or(DEFAULT_LOCK_WAIT_SECS))
+    }
+
+    /// How long a stage may run, `None` for no limit
+    pub fn stage_timeout(&self) -> Option<std::time::Duration> {
+        self.stage
+            .filter(|&secs| secs > 0)
+            .map(std::time::Duration::from_secs)
+    }
+}
+
+/// `path` as dotted keys, leaving out `Option` and newtype wrappers
+fn dotted_path(path: &serde_ignored::Path) -> String {
+    use serde_ignored::Path;
+    let (parent, segment) = match path {
+        Path::Root => return String::new(),
+        Path::Seq { parent, index } => (parent, index.to_string()),
+        Path::Map { parent, key } => (parent, key.clone()),
+        Path::Some { parent }
+        | Path::NewtypeStruct { parent }
+        | Path::NewtypeVariant { parent } => {
+            return dotted_path(parent);
+        }
+    };
+    match dotted_path(parent) {
+        prefix if prefix.is_empty() => segment,
+        prefix => format!("{}.{}", prefix, segment),
+    }
+}
+
 impl CloudConfig {
     /// Parse cloud-config from YAML string
     pub fn from_yaml(yaml: &str) -> Result<Self, serde_yaml::Error> {
@@ -325,10 +1467,123 @@ impl CloudConfig {
         serde_yaml::from_str(yaml)
     }
 
+    /// Parse cloud-config, also returning the keys that were ignored
+    ///
+    /// Keys are dotted paths such as `snap` or `phone_home.retries`:
+    /// settings of Python cloud-init this implementation does not support,
+    /// and misspellings. Keys below values that take several shapes (e.g.
+    /// `users` entries) and inside free-form values are not checked.
+    pub fn from_yaml_reporting(yaml: &str) -> Result<(Self, Vec<String>), serde_yaml::Error> {
+        let yaml = yaml
+            .strip_prefix("#cloud-config")
+            .map(|s| s.trim_start())
+            .unwrap_or(yaml);
+
+        let mut ignored = Vec::new();
+        let config =
+            serde_ignored::deserialize(serde_yaml::Deserializer::from_str(yaml), |path| {
+                ignored.push(dotted_path(&path))
+            })?;
+        Ok((config, ignored))
+    }
+
     /// Check if this looks like a cloud-config (starts with #cloud-config)
     pub fn is_cloud_config(data: &str) -> bool {
         data.trim_start().starts_with("#cloud-config")
     }
+
+    /// Whether `event` re-applies network configuration
+    pub fn network_update_enabled(&self, event: UpdateEvent) -> bool {
+        match &self.updates {
+            Some(updates) => updates.network.when.contains(&event),
+            None => UpdatePolicy::default().when.contains(&event),
+        }
+    }
+
+    /// How long datasource `name` may take to answer
+    ///
+    /// Names match case-insensitively, so `Ec2` configures `EC2`.
+    pub fn datasource_max_wait(&self, name: &str) -> std::time::Duration {
+        let secs = self
+            .datasource_settings(name)
+            .and_then(|settings| settings.max_wait)
+            .unwrap_or(DEFAULT_MAX_WAIT_SECS);
+        std::time::Duration::from_secs(secs)
+    }
+
+    /// Settings for datasource `name` under the `datasource` key
+    ///
+    /// Names match case-insensitively, so `Ec2` configures `EC2`.
+    pub fn datasource_settings(&self, name: &str) -> Option<&DatasourceSettings> {
+        self.datasource
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .and_then(|(_, settings)| settings.as_ref())
+    }
+
+    /// Log file path from `def_log_file`
+    pub fn log_file(&self) -> std::path::PathBuf {
+        self.def_log_file
+            .as_deref()
+            .unwrap_or(crate::logging::DEFAULT_LOG_FILE)
+            .into()
+    }
+
+    /// File command output is appended to, from `output: {all: ...}`
+    ///
+    /// Python cloud-init takes a shell redirection here; the last absolute
+    /// path in it is used (`| tee -a /var/log/x.log` and `>> /var/log/x.log`
+    /// both name `/var/log/x.log`). Defaults to
+    /// `/var/log/cloud-init-output.log`.
+    pub fn output_log_file(&self) -> std::path::PathBuf {
+        self.output
+            .as_ref()
+            .and_then(|o| o.get("all"))
+            .and_then(|all| all.as_str())
+            .and_then(|all| all.split_whitespace().rev().find(|w| w.starts_with('/')))
+            .unwrap_or(crate::logging::DEFAULT_OUTPUT_LOG_FILE)
+            .into()
+    }
+
+    /// Whether network configuration is turned off with
+    /// `network: {config: disabled}`, or by [`offline`](Self::offline)
+    pub fn network_disabled(&self) -> bool {
+        self.offline() || self.network_mode() == Some("disabled")
+    }
+
+    /// Whether `network: {config: preserve}` keeps the network files of
+    /// an instance that has booted before
+    ///
+    /// The network is then only configured on a new instance's first
+    /// boot, so files an administrator edited afterwards survive reboots.
+    pub fn network_preserved(&self) -> bool {
+        self.network_mode() == Some("preserve")
+    }
+
+    /// The `config` value of `network: {config: <mode>}`
+    fn network_mode(&self) -> Option<&str> {
+        self.network.as_ref()?.get("config")?.as_str()
+    }
+
+    /// Whether `network: disabled` asks for [`crate::offline`] mode
+    pub fn offline(&self) -> bool {
+        self.network.as_ref().and_then(|n| n.as_str()) == Some("disabled")
+    }
+
+    /// Network configuration given inline under `network:`
+    ///
+    /// Returns `None` when absent, disabled, preserved, or not a valid
+    /// v1/v2 config.
+    pub fn network_config(&self) -> Option<crate::network::NetworkConfig> {
+        if self.network_disabled() || self.network_mode().is_some() {
+            return None;
+        }
+        let yaml = serde_yaml::to_string(self.network.as_ref()?).ok()?;
+        crate::network::v1::parse_network_config(&yaml)
+            .inspect_err(|e| tracing::warn!("Ignoring invalid network config: {}", e))
+            .ok()
+            .filter(|n| n.has_interfaces())
+    }
 }
 
 #[cfg(test)]
@@ -351,6 +1606,42 @@ packages:
         assert_eq!(config.packages, vec!["nginx", "vim"]);
     }
 
+    #[test]
+    fn test_network_disabled() {
+        let config = CloudConfig::from_yaml("network:\n  config: disabled\n").unwrap();
+        assert!(config.network_disabled());
+        assert!(config.network_config().is_none());
+
+        assert!(!CloudConfig::default().network_disabled());
+    }
+
+    #[test]
+    fn test_network_preserved() {
+        let config = CloudConfig::from_yaml("network:\n  config: preserve\n").unwrap();
+        assert!(config.network_preserved());
+        assert!(!config.network_disabled());
+        assert!(config.network_config().is_none());
+
+        assert!(!CloudConfig::default().network_preserved());
+    }
+
+    #[test]
+    fn test_network_config_v1_and_v2() {
+        let v2 = CloudConfig::from_yaml(
+            "network:\n  version: 2\n  ethernets:\n    eth0:\n      dhcp4: true\n",
+        )
+        .unwrap();
+        let net = v2.network_config().unwrap();
+        assert_eq!(net.ethernets["eth0"].common.dhcp4, Some(true));
+
+        let v1 = CloudConfig::from_yaml(
+            "network:\n  version: 1\n  config:\n    - type: physical\n      name: eth1\n      subnets:\n        - type: dhcp\n",
+        )
+        .unwrap();
+        assert!(!v1.network_disabled());
+        assert!(v1.network_config().unwrap().ethernets.contains_key("eth1"));
+    }
+
     #[test]
     fn test_is_cloud_config() {
         assert!(CloudConfig::is_cloud_config(
@@ -398,7 +1689,36 @@ manage_etc_hosts: true
         let config = CloudConfig::from_yaml(yaml).unwrap();
         assert_eq!(config.hostname, Some("my-server".to_string()));
         assert_eq!(config.fqdn, Some("my-server.example.com".to_string()));
-        assert_eq!(config.manage_etc_hosts, Some(true));
+        assert_eq!(config.manage_etc_hosts, Some(ManageEtcHosts::Template));
+    }
+
+    #[test]
+    fn test_parse_manage_etc_hosts_modes() {
+        let cases = [
+            ("false", ManageEtcHosts::Disabled),
+            ("true", ManageEtcHosts::Template),
+            ("template", ManageEtcHosts::Template),
+            ("localhost", ManageEtcHosts::Localhost),
+        ];
+        for (value, expected) in cases {
+            let yaml = format!("manage_etc_hosts: {value}\n");
+            let config = CloudConfig::from_yaml(&yaml).unwrap();
+            assert_eq!(config.manage_etc_hosts, Some(expected), "value: {value}");
+        }
+        assert!(CloudConfig::from_yaml("manage_etc_hosts: bogus\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_hostname_options() {
+        let yaml = r#"
+#cloud-config
+fqdn: web.example.com
+prefer_fqdn_if_set: true
+create_hostname_file: false
+"#;
+        let config = CloudConfig::from_yaml(yaml).unwrap();
+        assert_eq!(config.prefer_fqdn_if_set, Some(true));
+        assert_eq!(config.create_hostname_file, Some(false));
     }
 
     #[test]
@@ -413,6 +1733,48 @@ locale: en_US.UTF-8
         assert_eq!(config.locale, Some("en_US.UTF-8".to_string()));
     }
 
+    #[test]
+    fn test_parse_locale_configfile() {
+        let yaml = "locale: de_DE.UTF-8\nlocale_configfile: /etc/sysconfig/i18n\n";
+        let config = CloudConfig::from_yaml(yaml).unwrap();
+        assert_eq!(
+            config.locale_configfile,
+            Some("/etc/sysconfig/i18n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_reporting_handlers() {
+        let yaml = r#"
+reporting:
+  logging:
+    type: log
+  telemetry:
+    type: hyperv
+  orchestrator:
+    type: webhook
+    endpoint: https://provision.example.com/events
+    secret: s3cret
+    retries: 2
+"#;
+        let config = CloudConfig::from_yaml(yaml).unwrap();
+        assert_eq!(config.reporting.len(), 3);
+        assert!(matches!(config.reporting["logging"], ReportingConfig::Log));
+        match &config.reporting["telemetry"] {
+            ReportingConfig::Hyperv(kvp) => assert_eq!(kvp.kvp_file_path, None),
+            other => panic!("expected hyperv, got {other:?}"),
+        }
+        match &config.reporting["orchestrator"] {
+            ReportingConfig::Webhook(hook) => {
+                assert_eq!(hook.endpoint, "https://provision.example.com/events");
+                assert_eq!(hook.secret.as_deref(), Some("s3cret"));
+                assert_eq!(hook.retries, Some(2));
+                assert_eq!(hook.timeout, None);
+            }
+            other => panic!("expected webhook, got {other:?}"),
+        }
+    }
+
     // ==================== User Configuration Tests ====================
 
     #[test]
@@ -463,6 +1825,43 @@ users:
         }
     }
 
+    #[test]
+    fn test_parse_user_password_and_expiry_fields() {
+        let yaml = r#"
+#cloud-config
+users:
+  - name: ops
+    plain_text_passwd: hunter2
+    hashed_passwd: $6$salt$hash
+    expiredate: '2030-01-01'
+    inactive: 5
+    no_create_home: true
+    no_user_group: true
+"#;
+        let config = CloudConfig::from_yaml(yaml).unwrap();
+        match &config.users[0] {
+            UserConfig::Full(user) => {
+                assert_eq!(user.plain_text_passwd.as_deref(), Some("hunter2"));
+                assert_eq!(user.hashed_passwd.as_deref(), Some("$6$salt$hash"));
+                assert_eq!(user.expiredate.as_deref(), Some("2030-01-01"));
+                assert_eq!(user.inactive.as_deref(), Some("5"));
+                assert_eq!(user.no_create_home, Some(true));
+                assert_eq!(user.no_user_group, Some(true));
+            }
+            _ => panic!("Expected full user config"),
+        }
+    }
+
+    #[test]
+    fn test_parse_user_inactive_as_string() {
+        let yaml = "users:\n  - name: ops\n    inactive: '30'\n";
+        let config = CloudConfig::from_yaml(yaml).unwrap();
+        match &config.users[0] {
+            UserConfig::Full(user) => assert_eq!(user.inactive.as_deref(), Some("30")),
+            _ => panic!("Expected full user config"),
+        }
+    }
+
     #[test]
     fn test_parse_mixed_users() {
         let yaml = r#"
@@ -478,6 +1877,38 @@ users:
         assert!(matches!(&config.users[1], UserConfig::Full(_)));
     }
 
+    #[test]
+    fn test_parse_user_override_and_controls() {
+        let yaml = r#"
+#cloud-config
+user:
+  name: ops
+  shell: /bin/zsh
+users:
+  - default
+  - name: guest
+    create_groups: false
+    ssh_redirect_user: true
+    expire: true
+    ssh_authorized_keys_exclusive: true
+  - name: olduser
+    remove: true
+"#;
+        let config = CloudConfig::from_yaml(yaml).unwrap();
+        assert_eq!(config.user.as_ref().map(UserConfig::name), Some("ops"));
+        let UserConfig::Full(guest) = &config.users[1] else {
+            panic!("Expected full user config");
+        };
+        assert_eq!(guest.create_groups, Some(false));
+        assert_eq!(guest.ssh_redirect_user, Some(true));
+        assert_eq!(guest.expire, Some(true));
+        assert_eq!(guest.ssh_authorized_keys_exclusive, Some(true));
+        let UserConfig::Full(olduser) = &config.users[2] else {
+            panic!("Expected full user config");
+        };
+        assert_eq!(olduser.remove, Some(true));
+    }
+
     // ==================== Group Configuration Tests ====================
 
     #[test]
@@ -492,6 +1923,55 @@ groups:
         assert_eq!(config.groups.len(), 2);
     }
 
+    #[test]
+    fn test_parse_group_with_gid_and_system() {
+        let yaml = r#"
+#cloud-config
+groups:
+  - name: svc
+    gid: 950
+    system: true
+    members: [app]
+  - name: empty
+"#;
+        let config = CloudConfig::from_yaml(yaml).unwrap();
+        match &config.groups[0] {
+            GroupConfig::WithMembers {
+                name,
+                members,
+                gid,
+                system,
+            } => {
+                assert_eq!(name, "svc");
+                assert_eq!(members, &vec!["app".to_string()]);
+                assert_eq!(*gid, Some(950));
+                assert_eq!(*system, Some(true));
+            }
+            other => panic!("Expected WithMembers, got {other:?}"),
+        }
+        assert!(
+            matches!(&config.groups[1], GroupConfig::WithMembers { members, .. } if members.is_empty())
+        );
+    }
+
+    #[test]
+    fn test_parse_group_mapping_form() {
+        let yaml = r#"
+#cloud-config
+groups:
+  - admingroup: [root, sys]
+  - cloud-users
+"#;
+        let config = CloudConfig::from_yaml(yaml).unwrap();
+        match &config.groups[0] {
+            GroupConfig::Mapping(map) => {
+                assert_eq!(map["admingroup"], vec!["root", "sys"]);
+            }
+            other => panic!("Expected Mapping, got {other:?}"),
+        }
+        assert!(matches!(&config.groups[1], GroupConfig::Name(n) if n == "cloud-users"));
+    }
+
     // ==================== Write Files Tests ====================
 
     #[test]
@@ -787,6 +2267,33 @@ phone_home:
         assert_eq!(phone_home.tries, Some(10));
     }
 
+    #[test]
+    fn test_parse_power_state() {
+        let yaml = r#"
+#cloud-config
+power_state:
+  mode: reboot
+  delay: 5
+  message: Rebooting after updates
+  condition: [test, -f, /var/run/reboot-required]
+"#;
+        let config = CloudConfig::from_yaml(yaml).unwrap();
+        let power_state = config.power_state.unwrap();
+        assert_eq!(power_state.mode, "reboot");
+        assert_eq!(power_state.timeout, None);
+        assert!(matches!(
+            power_state.condition,
+            Some(PowerStateCondition::Command(RunCmd::Args(args))) if args.len() == 3
+        ));
+
+        let config =
+            CloudConfig::from_yaml("power_state: {mode: poweroff, condition: false}").unwrap();
+        assert!(matches!(
+            config.power_state.unwrap().condition,
+            Some(PowerStateCondition::Bool(false))
+        ));
+    }
+
     #[test]
     fn test_parse_final_message() {
         let yaml = r#"
@@ -804,6 +2311,154 @@ final_message: |
         );
     }
 
+    #[test]
+    fn test_parse_module_policy() {
+        let yaml = r#"
+#cloud-config
+module_policy:
+  required: [users, write_files, runcmd]
+  best_effort: [runcmd]
+"#;
+        let policy = CloudConfig::from_yaml(yaml).unwrap().module_policy.unwrap();
+        assert_eq!(policy.policy_for("users"), ModulePolicy::Required);
+        assert_eq!(policy.policy_for("runcmd"), ModulePolicy::BestEffort);
+        assert_eq!(policy.policy_for("ntp"), ModulePolicy::BestEffort);
+
+        let yaml = "#cloud-config\nmodule_policy:\n  default: required\n  best_effort: [ntp]\n";
+        let policy = CloudConfig::from_yaml(yaml).unwrap().module_policy.unwrap();
+        assert_eq!(policy.policy_for("users"), ModulePolicy::Required);
+        assert_eq!(policy.policy_for("ntp"), ModulePolicy::BestEffort);
+    }
+
+    #[test]
+    fn test_parse_updates() {
+        let config = CloudConfig::from_yaml(
+            "#cloud-config\nupdates:\n  network:\n    when: [boot-new-instance, hotplug]\n",
+        )
+        .unwrap();
+        assert!(config.network_update_enabled(UpdateEvent::Hotplug));
+        assert!(!config.network_update_enabled(UpdateEvent::Boot));
+
+        let default = CloudConfig::default();
+        assert!(default.network_update_enabled(UpdateEvent::BootNewInstance));
+        assert!(!default.network_update_enabled(UpdateEvent::Hotplug));
+    }
+
+    #[test]
+    fn test_parse_sshd_settings() {
+        let config = CloudConfig::from_yaml(
+            "#cloud-config\nssh_pwauth: unchanged\ndisable_root: false\n\
+             ssh_config:\n  ClientAliveInterval: 120\n",
+        )
+        .unwrap();
+        assert_eq!(config.ssh_pwauth, Some(SshPwauth::Unchanged));
+        assert_eq!(config.disable_root, Some(false));
+        assert_eq!(
+            config.ssh_config.unwrap()["ClientAliveInterval"],
+            serde_yaml::Value::from(120)
+        );
+
+        for (value, expected) in [
+            ("true", SshPwauth::Enabled),
+            ("no", SshPwauth::Disabled),
+            ("\"yes\"", SshPwauth::Enabled),
+        ] {
+            let config = CloudConfig::from_yaml(&format!("ssh_pwauth: {}\n", value)).unwrap();
+            assert_eq!(config.ssh_pwauth, Some(expected));
+        }
+        assert!(CloudConfig::from_yaml("ssh_pwauth: maybe\n").is_err());
+    }
+
+    #[test]
+    fn test_reports_ignored_keys() {
+        let yaml = "#cloud-config\nhostname: web\nsnap:\n  commands: [snap install lxd]\nphone_home:\n  url: http://example.com\n  retries: 3\nubuntu_advantage:\n  token: abc\n";
+        let (config, ignored) = CloudConfig::from_yaml_reporting(yaml).unwrap();
+        assert_eq!(config.hostname.as_deref(), Some("web"));
+        // Aliases count as supported
+        assert!(config.ubuntu_pro.is_some());
+        assert_eq!(ignored, vec!["snap", "phone_home.retries"]);
+    }
+
+    #[test]
+    fn test_parse_timeouts() {
+        use std::time::Duration;
+
+        let yaml = r#"
+#cloud-config
+datasource:
+  Ec2:
+    max_wait: 30
+    timeout: 5
+  NoCloud:
+  Azure:
+    apply_network_config: false
+timeouts:
+  module: 600
+  modules:
+    package_update_upgrade_install: 3600
+    runcmd: 0
+  stage: 5400
+"#;
+        let config = CloudConfig::from_yaml(yaml).unwrap();
+        assert_eq!(config.datasource_max_wait("EC2"), Duration::from_secs(30));
+        assert_eq!(
+            config.datasource_max_wait("Azure"),
+            Duration::from_secs(DEFAULT_MAX_WAIT_SECS)
+        );
+        assert_eq!(
+            config.datasource_max_wait("NoCloud"),
+            Duration::from_secs(DEFAULT_MAX_WAIT_SECS)
+        );
+
+        let timeouts = config.timeouts.unwrap();
+        assert_eq!(
+            timeouts.module_timeout("users"),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(
+            timeouts.module_timeout("package_update_upgrade_install"),
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(timeouts.module_timeout("runcmd"), None);
+        assert_eq!(timeouts.stage_timeout(), Some(Duration::from_secs(5400)));
+
+        let defaults = TimeoutConfig::default();
+        assert_eq!(
+            defaults.module_timeout("users"),
+            Some(Duration::from_secs(DEFAULT_MODULE_TIMEOUT_SECS))
+        );
+        assert_eq!(defaults.stage_timeout(), None);
+    }
+
+    #[test]
+    fn test_parse_logging() {
+        use crate::logging::{DEFAULT_LOG_FILE, DEFAULT_OUTPUT_LOG_FILE, LogBackend, LogFormat};
+        use std::path::PathBuf;
+
+        let defaults = CloudConfig::default();
+        assert_eq!(defaults.log_file(), PathBuf::from(DEFAULT_LOG_FILE));
+        assert_eq!(
+            defaults.output_log_file(),
+            PathBuf::from(DEFAULT_OUTPUT_LOG_FILE)
+        );
+
+        let yaml = r#"
+#cloud-config
+def_log_file: /var/log/ci.log
+log_format: json
+logging: journald
+output: {all: '| tee -a /var/log/ci-output.log'}
+"#;
+        let config = CloudConfig::from_yaml(yaml).unwrap();
+        assert_eq!(config.log_file(), PathBuf::from("/var/log/ci.log"));
+        assert_eq!(config.log_format, Some(LogFormat::Json));
+        assert_eq!(config.logging, Some(LogBackend::Journald));
+        assert_eq!(
+            config.output_log_file(),
+            PathBuf::from("/var/log/ci-output.log")
+        );
+    }
+
     // ==================== Error Handling Tests ====================
 
     #[test]
@@ -879,7 +2534,7 @@ runcmd:
             config.fqdn,
             Some("production-server.example.com".to_string())
         );
-        assert_eq!(config.manage_etc_hosts, Some(true));
+        assert_eq!(config.manage_etc_hosts, Some(ManageEtcHosts::Template));
         assert_eq!(config.timezone, Some("UTC".to_string()));
         assert_eq!(config.locale, Some("en_US.UTF-8".to_string()));
         assert_eq!(config.users.len(), 2);
@@ -0,0 +1,311 @@
+//! Resident daemon mode
+//!
+//! `cloud-init-rs daemon` runs every stage in a single process instead of
+//! one process per stage. It listens on `/run/cloud-init/daemon.sock`, and
+//! the stage commands (`cloud-init-rs local`, `network`, ...) started by
+//! the systemd units hand their stage to it when it is running, waiting
+//! for the result. Without a daemon they run the stage themselves, so the
+//! units work either way.
+//!
+//! Because the stages share the process, the datasource is detected once
+//! and its metadata and user data fetched once (see
+//! [`datasources::shared`]). The daemon exits after the final stage.
+//!
+//! The protocol is one JSON line each way per connection: a [`Request`]
+//! naming the stage, answered by a [`Reply`] once the stage has run.
+//! Stages must advance; a stage that already ran, or one before it, is
+//! refused.
+
+use crate::datasources;
+use crate::root::RootContext;
+use crate::{CloudInit, CloudInitError, IoContext, RunSummary, Stage};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, info, warn};
+
+/// Ask the daemon to run a stage
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Request {
+    /// Stage name, as accepted on the command line
+    pub stage: String,
+}
+
+/// Outcome of a stage run by the daemon
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Reply {
+    /// The stage ran to completion, possibly with best-effort failures
+    Done { module_errors: Vec<RemoteError> },
+    /// The stage failed, or the request was refused
+    Failed { error: RemoteError },
+}
+
+/// An error passed from the daemon to the stage command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteError {
+    pub message: String,
+    pub exit_code: u8,
+}
+
+impl From<&CloudInitError> for RemoteError {
+    fn from(error: &CloudInitError) -> Self {
+        Self {
+            message: error.to_string(),
+            exit_code: error.exit_code(),
+        }
+    }
+}
+
+impl From<RemoteError> for CloudInitError {
+    fn from(error: RemoteError) -> Self {
+        CloudInitError::Daemon {
+            message: error.message,
+            exit_code: error.exit_code,
+        }
+    }
+}
+
+/// Run the daemon until the final stage has run, each with `cloud_init`
+///
+/// Only the running system can be served; alternate roots and dry runs
+/// run their stages directly.
+pub async fn serve(cloud_init: &CloudInit) -> Result<(), CloudInitError> {
+    let root = cloud_init.root();
+    if !root.is_host() || root.is_dry_run() {
+        return Err(CloudInitError::Config(
+            "The daemon cannot run with --root or --dry-run".to_string(),
+        ));
+    }
+
+    let path = root.cloud_paths().daemon_socket();
+    let listener = bind(&path).await?;
+    datasources::shared::enable();
+    notify_ready();
+    info!("Waiting for stage triggers on {}", path.display());
+
+    let mut last = None;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let run = async |stage| cloud_init.run(stage).await;
+        match handle_connection(stream, &mut last, run).await {
+            Ok(()) if last == Some(Stage::Final) => break,
+            Ok(()) => {}
+            Err(e) => warn!("Stage trigger failed: {}", e),
+        }
+    }
+
+    info!("Final stage done, daemon exiting");
+    if let Err(e) = fs::remove_file(&path).await {
+        debug!("Could not remove {}: {}", path.display(), e);
+    }
+    Ok(())
+}
+
+/// Bind the trigger socket, replacing a stale one
+async fn bind(path: &Path) -> Result<UnixListener, CloudInitError> {
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(CloudInitError::Config(format!(
+            "A daemon is already listening on {}",
+            path.display()
+        )));
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await.with_path(parent)?;
+    }
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).with_path(path);
+        }
+        _ => {}
+    }
+    let listener = UnixListener::bind(path).with_path(path)?;
+    RootContext::current().set_mode(path, 0o600).await?;
+    Ok(listener)
+}
+
+/// Tell systemd the daemon is ready, for `Type=notify` units
+fn notify_ready() {
+    let Some(addr) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent = std::os::unix::net::UnixDatagram::unbound().and_then(|socket| {
+        #[cfg(target_os = "linux")]
+        if let Some(name) = addr.as_encoded_bytes().strip_prefix(b"@") {
+            use std::os::linux::net::SocketAddrExt;
+            let abstract_addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(b"READY=1", &abstract_addr);
+        }
+        socket.send_to(b"READY=1", &addr)
+    });
+    if let Err(e) = sent {
+        warn!("Could not notify systemd: {}", e);
+    }
+}
+
+/// Serve one trigger: read the request, run the stage, send the reply
+///
+/// `last` is the last stage run and is advanced when `run` is called.
+async fn handle_connection(
+    stream: UnixStream,
+    last: &mut Option<Stage>,
+    run: impl AsyncFnOnce(Stage) -> Result<RunSummary, CloudInitError>,
+) -> Result<(), CloudInitError> {
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(read).read_line(&mut line).await?;
+    let request: Request = serde_json::from_str(&line)?;
+
+    let result = match request.stage.parse::<Stage>() {
+        Ok(stage) => match *last {
+            Some(done) if stage <= done => Err(CloudInitError::stage(
+                stage.to_string(),
+                format!("the daemon has already run the {} stage", done),
+            )),
+            _ => {
+                info!("Stage {} triggered", stage);
+                *last = Some(stage);
+                run(stage).await
+            }
+        },
+        Err(e) => Err(e),
+    };
+
+    let reply = match &result {
+        Ok(summary) => Reply::Done {
+            module_errors: summary.module_errors.iter().map(Into::into).collect(),
+        },
+        Err(e) => Reply::Failed { error: e.into() },
+    };
+    write
+        .write_all(format!("{}\n", serde_json::to_string(&reply)?).as_bytes())
+        .await?;
+    Ok(())
+}
+
+/// Have a running daemon run `stage`
+///
+/// Returns `None` when no daemon is listening, or for an alternate root
+/// or a dry run, in which case the caller runs the stage itself.
+pub async fn trigger(
+    root: &RootContext,
+    stage: Stage,
+) -> Result<Option<RunSummary>, CloudInitError> {
+    if !root.is_host() || root.is_dry_run() {
+        return Ok(None);
+    }
+    let Ok(stream) = UnixStream::connect(root.cloud_paths().daemon_socket()).await else {
+        return Ok(None);
+    };
+    info!("Handing {} stage to the daemon", stage);
+    request(stream, stage).await.map(Some)
+}
+
+/// Send the request for `stage` and wait for the reply
+async fn request(stream: UnixStream, stage: Stage) -> Result<RunSummary, CloudInitError> {
+    let (read, mut write) = stream.into_split();
+    let request = Request {
+        stage: stage.to_string(),
+    };
+    write
+        .write_all(format!("{}\n", serde_json::to_string(&request)?).as_bytes())
+        .await?;
+
+    let mut line = String::new();
+    BufReader::new(read).read_line(&mut line).await?;
+    if line.is_empty() {
+        return Err(CloudInitError::stage(
+            stage.to_string(),
+            "the daemon closed the connection without a result",
+        ));
+    }
+    match serde_json::from_str(&line)? {
+        Reply::Done { module_errors } => Ok(RunSummary {
+            module_errors: module_errors.into_iter().map(Into::into).collect(),
+        }),
+        Reply::Failed { error } => Err(error.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exit_code;
+
+    /// Trigger `stage` over a socket pair, running it with `run`
+    async fn roundtrip(
+        stage: Stage,
+        last: &mut Option<Stage>,
+        run: impl AsyncFnOnce(Stage) -> Result<RunSummary, CloudInitError>,
+    ) -> Result<RunSummary, CloudInitError> {
+        let (client, server) = UnixStream::pair().unwrap();
+        let (reply, handled) =
+            tokio::join!(request(client, stage), handle_connection(server, last, run));
+        handled.unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn test_stage_result_reaches_client() {
+        let mut last = None;
+        let summary = roundtrip(Stage::Local, &mut last, async |stage| {
+            assert_eq!(stage, Stage::Local);
+            Ok(RunSummary {
+                module_errors: vec![CloudInitError::module("ntp", "no chrony")],
+            })
+        })
+        .await
+        .unwrap();
+        assert_eq!(last, Some(Stage::Local));
+        assert_eq!(summary.module_errors.len(), 1);
+        assert_eq!(
+            summary.module_errors[0].to_string(),
+            "Module error in 'ntp': no chrony"
+        );
+
+        let err = roundtrip(Stage::Network, &mut last, async |_| {
+            Err(CloudInitError::NoDatasource)
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "No datasource found");
+        assert_eq!(err.exit_code(), exit_code::DATASOURCE);
+        assert_eq!(last, Some(Stage::Network));
+    }
+
+    #[tokio::test]
+    async fn test_stages_must_advance() {
+        let mut last = Some(Stage::Config);
+        let err = roundtrip(Stage::Network, &mut last, async |_| {
+            panic!("stage must not run")
+        })
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("already run the config stage"));
+        assert_eq!(err.exit_code(), exit_code::FAILURE);
+        assert_eq!(last, Some(Stage::Config));
+
+        roundtrip(Stage::Final, &mut last, async |_| Ok(RunSummary::default()))
+            .await
+            .unwrap();
+        assert_eq!(last, Some(Stage::Final));
+    }
+
+    #[test]
+    fn test_reply_format() {
+        let reply = Reply::Failed {
+            error: RemoteError::from(&CloudInitError::Config("bad".to_string())),
+        };
+        assert_eq!(
+            serde_json::to_value(&reply).unwrap(),
+            serde_json::json!({
+                "status": "failed",
+                "error": {"message": "Configuration error: bad", "exit_code": 3}
+            })
+        );
+        assert_eq!("final".parse::<Stage>().unwrap(), Stage::Final);
+        assert!("boot".parse::<Stage>().is_err());
+    }
+}
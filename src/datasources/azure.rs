@@ -2,6 +2,11 @@
 //!
 //! Fetches metadata from Azure Instance Metadata Service (IMDS).
 //! <https://docs.microsoft.com/en-us/azure/virtual-machines/linux/instance-metadata-service>
+//!
+//! The admin user and SSH keys set in the portal are in IMDS rather than
+//! in custom data. They are supplied as vendor-data, so a VM without
+//! custom data still gets its admin user and key, and user-data can add
+//! to or override them.
 
 use async_trait::async_trait;
 use reqwest::Client;
@@ -10,7 +15,9 @@ use std::time::Duration;
 use tracing::debug;
 
 use super::Datasource;
-use crate::{CloudInitError, InstanceMetadata, UserData, config::CloudConfig};
+use crate::config::{CloudConfig, SshPwauth, UserConfig, UserFullConfig};
+use crate::platform::{DmiInfo, Platform};
+use crate::{CloudInitError, InstanceMetadata, UserData};
 
 /// Azure IMDS base URL (link-local address)
 const AZURE_IMDS_URL: &str = "http://169.254.169.254/metadata";
@@ -22,6 +29,9 @@ const AZURE_API_VERSION: &str = "2021-02-01";
 #[derive(Debug, Deserialize)]
 struct AzureInstanceMetadata {
     compute: AzureCompute,
+    /// The whole response, for [`InstanceMetadata::raw`]
+    #[serde(skip)]
+    raw: serde_json::Value,
 }
 
 #[derive(Debug, Deserialize)]
@@ -39,6 +49,27 @@ struct AzureCompute {
     zone: String,
     #[serde(default)]
     computer_name: String,
+    #[serde(default)]
+    os_profile: AzureOsProfile,
+    #[serde(default)]
+    public_keys: Vec<AzurePublicKey>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AzureOsProfile {
+    #[serde(default)]
+    admin_username: String,
+    /// `"true"` or `"false"`
+    #[serde(default)]
+    disable_password_authentication: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AzurePublicKey {
+    #[serde(default)]
+    key_data: String,
 }
 
 /// Azure IMDS datasource
@@ -49,7 +80,7 @@ pub struct Azure {
 
 impl Azure {
     pub fn new() -> Self {
-        let client = Client::builder()
+        let client = crate::http::metadata_client_builder()
             .timeout(Duration::from_secs(5))
             .connect_timeout(Duration::from_secs(2))
             .build()
@@ -63,7 +94,7 @@ impl Azure {
 
     /// Create with a custom base URL (for testing)
     pub fn with_base_url(base_url: &str) -> Self {
-        let client = Client::builder()
+        let client = crate::http::metadata_client_builder()
             .timeout(Duration::from_secs(5))
             .connect_timeout(Duration::from_secs(2))
             .build()
@@ -91,7 +122,9 @@ impl Azure {
             .await?;
 
         if response.status().is_success() {
-            let metadata: AzureInstanceMetadata = response.json().await?;
+            let raw: serde_json::Value = response.json().await?;
+            let mut metadata: AzureInstanceMetadata = serde_json::from_value(raw.clone())?;
+            metadata.raw = raw;
             Ok(metadata)
         } else {
             Err(CloudInitError::Datasource(format!(
@@ -114,40 +147,73 @@ impl Azure {
             .await
             .is_ok()
     }
+}
 
-    /// Check DMI data for Azure indicators
-    async fn check_dmi_data() -> bool {
-        let dmi_paths = [
-            "/sys/class/dmi/id/product_name",
-            "/sys/class/dmi/id/bios_vendor",
-            "/sys/class/dmi/id/sys_vendor",
-            "/sys/class/dmi/id/chassis_asset_tag",
-        ];
-
-        for path in &dmi_paths {
-            if let Ok(content) = tokio::fs::read_to_string(path).await {
-                let content = content.to_lowercase();
-                if content.contains("microsoft")
-                    || content.contains("azure")
-                    || content.contains("virtual machine")
-                {
-                    return true;
-                }
-            }
-        }
-
-        // Check for Azure-specific chassis asset tag
-        if let Ok(asset_tag) =
-            tokio::fs::read_to_string("/sys/class/dmi/id/chassis_asset_tag").await
-            && asset_tag
-                .trim()
-                .eq_ignore_ascii_case("7783-7084-3265-9085-8269-3286-77")
-        {
-            return true;
-        }
+/// Cloud-config for the admin user and keys configured in IMDS
+///
+/// The admin user gets the keys and passwordless sudo; with password
+/// authentication disabled its password is locked and sshd refuses
+/// passwords. The keys are also root's, so that `disable_root` can point
+/// root logins at the admin user.
+fn provisioning_config(compute: &AzureCompute) -> Option<CloudConfig> {
+    let keys: Vec<String> = compute
+        .public_keys
+        .iter()
+        .map(|key| key.key_data.trim().to_string())
+        .filter(|key| !key.is_empty())
+        .collect();
+    let admin = compute.os_profile.admin_username.trim();
+    let password_disabled = compute
+        .os_profile
+        .disable_password_authentication
+        .eq_ignore_ascii_case("true");
+    if admin.is_empty() && keys.is_empty() {
+        return None;
+    }
 
-        false
+    let mut config = CloudConfig::default();
+    if !admin.is_empty() {
+        config.users.push(UserConfig::Full(Box::new(UserFullConfig {
+            name: admin.to_string(),
+            sudo: Some("ALL=(ALL) NOPASSWD:ALL".to_string()),
+            lock_passwd: Some(password_disabled),
+            ssh_authorized_keys: keys.clone(),
+            ..Default::default()
+        })));
+    }
+    if password_disabled {
+        config.ssh_pwauth = Some(SshPwauth::Disabled);
     }
+    config.ssh_authorized_keys = keys;
+    Some(config)
+}
+
+/// Swap the first three UUID fields between big- and little-endian
+///
+/// Gen1 VMs report the system UUID in the other byte order than IMDS.
+fn swap_uuid_byte_order(uuid: &str) -> Option<String> {
+    let fields: Vec<&str> = uuid.split('-').collect();
+    let [a, b, c, d, e] = fields.as_slice() else {
+        return None;
+    };
+    let swap = |field: &str| -> Option<String> {
+        if !field.len().is_multiple_of(2) || !field.is_ascii() {
+            return None;
+        }
+        let bytes: Vec<&str> = (0..field.len())
+            .step_by(2)
+            .map(|i| &field[i..i + 2])
+            .collect();
+        Some(bytes.into_iter().rev().collect())
+    };
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        swap(a)?,
+        swap(b)?,
+        swap(c)?,
+        d,
+        e
+    ))
 }
 
 impl Default for Azure {
@@ -164,7 +230,7 @@ impl Datasource for Azure {
 
     async fn is_available(&self) -> bool {
         // First check DMI data (fast, local check)
-        if Self::check_dmi_data().await {
+        if Platform::detect().await == Platform::Azure {
             return self.check_imds().await;
         }
 
@@ -172,6 +238,16 @@ impl Datasource for Azure {
         self.check_imds().await
     }
 
+    /// The vmId is the system UUID, byte-swapped on Gen1 VMs
+    async fn check_instance_id(&self, cached_id: &str) -> Option<bool> {
+        let dmi = DmiInfo::read().await;
+        let swapped = swap_uuid_byte_order(cached_id);
+        Some(
+            dmi.uuid_matches(cached_id)?
+                || swapped.is_some_and(|id| dmi.uuid_matches(&id) == Some(true)),
+        )
+    }
+
     async fn get_metadata(&self) -> Result<InstanceMetadata, CloudInitError> {
         debug!("Fetching Azure instance metadata");
 
@@ -207,10 +283,17 @@ impl Datasource for Azure {
         if !azure_meta.compute.vm_size.is_empty() {
             metadata.instance_type = Some(azure_meta.compute.vm_size);
         }
+        metadata.raw = azure_meta.raw;
 
         Ok(metadata)
     }
 
+    async fn get_vendordata(&self) -> Result<Option<UserData>, CloudInitError> {
+        let azure_meta = self.fetch_instance_metadata().await?;
+        Ok(provisioning_config(&azure_meta.compute)
+            .map(|config| UserData::CloudConfig(Box::new(config))))
+    }
+
     async fn get_userdata(&self) -> Result<UserData, CloudInitError> {
         debug!("Fetching Azure user-data");
 
@@ -232,7 +315,10 @@ impl Datasource for Azure {
             return Ok(UserData::None);
         }
 
-        let content = response.text().await?;
+        let limit = crate::userdata::limits::current().max_size();
+        let body =
+            crate::userdata::limits::read_body(response, "Custom data", limit, "max_size").await?;
+        let content = String::from_utf8_lossy(&body).into_owned();
 
         if content.is_empty() {
             return Ok(UserData::None);
@@ -267,6 +353,41 @@ impl Datasource for Azure {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_swap_uuid_byte_order() {
+        assert_eq!(
+            swap_uuid_byte_order("5d33a910-a7a0-4443-9f01-6a807801b29b").as_deref(),
+            Some("10a9335d-a0a7-4344-9f01-6a807801b29b")
+        );
+        assert_eq!(swap_uuid_byte_order("not-a-uuid"), None);
+    }
+
+    #[test]
+    fn test_provisioning_config() {
+        let compute: AzureCompute = serde_json::from_value(serde_json::json!({
+            "osProfile": {
+                "adminUsername": "azureuser",
+                "disablePasswordAuthentication": "true"
+            },
+            "publicKeys": [
+                {"keyData": "ssh-rsa AAAA azureuser\n", "path": "/home/azureuser/.ssh/authorized_keys"}
+            ]
+        }))
+        .unwrap();
+        let config = provisioning_config(&compute).unwrap();
+        let UserConfig::Full(user) = &config.users[0] else {
+            panic!("expected a full user config");
+        };
+        assert_eq!(user.name, "azureuser");
+        assert_eq!(user.lock_passwd, Some(true));
+        assert_eq!(user.ssh_authorized_keys, vec!["ssh-rsa AAAA azureuser"]);
+        assert_eq!(config.ssh_pwauth, Some(SshPwauth::Disabled));
+        assert_eq!(config.ssh_authorized_keys, user.ssh_authorized_keys);
+
+        let bare: AzureCompute = serde_json::from_value(serde_json::json!({})).unwrap();
+        assert!(provisioning_config(&bare).is_none());
+    }
+
     #[test]
     fn test_azure_default() {
         let azure = Azure::new();
@@ -0,0 +1,296 @@
+//! Instance cache policy
+//!
+//! After a datasource has been crawled, its name and the instance ID are
+//! cached under `/var/lib/cloud`. On later boots the cache policy decides
+//! whether that cache still describes this machine or detection has to
+//! run again:
+//!
+//! - `manual_cache_clean: true` trusts the cache until
+//!   `cloud-init-rs clean` removes it.
+//! - Otherwise `cache_check` selects the check. `instance-id` (the
+//!   default) asks the cached datasource to compare the cached ID against
+//!   what the system reports locally (DMI UUID, seed meta-data), without
+//!   touching the network; datasources that cannot tell are re-detected.
+//!   `metadata` always re-detects and compares the instance ID fetched
+//!   from the metadata service.
+//!
+//! Either way a stop/start cycle keeps the instance ID, so per-instance
+//! work is not repeated, while a re-imaged or cloned disk reports a new
+//! ID and is set up as a new instance.
+
+use super::{Datasource, detect_datasource, registry, shared, snapshot};
+use crate::CloudInitError;
+use crate::config::{CloudConfig, load_merged_config};
+use crate::state::CloudPaths;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+/// How a cached instance is validated on boot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheCheck {
+    /// Compare the cached instance ID with the system, locally (default)
+    #[default]
+    #[serde(alias = "instance_id")]
+    InstanceId,
+    /// Always re-detect and compare the instance ID from metadata
+    Metadata,
+}
+
+/// Cache settings from system config
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CachePolicy {
+    /// Trust the cache until it is cleaned manually
+    pub manual_clean: bool,
+    pub check: CacheCheck,
+}
+
+/// Datasource and instance ID cached by an earlier boot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedInstance {
+    pub datasource: String,
+    pub instance_id: String,
+}
+
+/// Outcome of checking the cache
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheDecision {
+    /// The cached instance is still valid; skip detection
+    Reuse(CachedInstance),
+    /// Detect the datasource again, for the given reason
+    Redetect(String),
+}
+
+impl CachePolicy {
+    /// Policy from the `manual_cache_clean` and `cache_check` keys
+    pub fn from_config(config: &CloudConfig) -> Self {
+        Self {
+            manual_clean: config.manual_cache_clean.unwrap_or(false),
+            check: config.cache_check.unwrap_or_default(),
+        }
+    }
+
+    /// Decide whether the cache under `paths` can be reused
+    ///
+    /// `candidates` are the compiled-in datasources; the cached one is
+    /// looked up among them by name to run its instance ID check.
+    pub async fn decide(
+        &self,
+        paths: &CloudPaths,
+        candidates: Vec<Box<dyn Datasource>>,
+    ) -> CacheDecision {
+        if paths.sealed_marker().exists() {
+            return CacheDecision::Redetect("image was sealed".to_string());
+        }
+        let Some(cached) = load_cached(paths).await else {
+            return CacheDecision::Redetect("no cached instance".to_string());
+        };
+        if self.manual_clean {
+            return CacheDecision::Reuse(cached);
+        }
+        if self.check == CacheCheck::Metadata {
+            return CacheDecision::Redetect("cache_check is metadata".to_string());
+        }
+
+        let Some(ds) = candidates
+            .into_iter()
+            .find(|ds| ds.name().eq_ignore_ascii_case(&cached.datasource))
+        else {
+            return CacheDecision::Redetect(format!(
+                "cached datasource {} is not available",
+                cached.datasource
+            ));
+        };
+        match ds.check_instance_id(&cached.instance_id).await {
+            Some(true) => CacheDecision::Reuse(cached),
+            Some(false) => CacheDecision::Redetect(format!(
+                "instance ID {} no longer matches {}",
+                cached.instance_id, cached.datasource
+            )),
+            None => CacheDecision::Redetect(format!(
+                "{} cannot check the instance ID locally",
+                cached.datasource
+            )),
+        }
+    }
+}
+
+/// Read the cached instance ID and the datasource that supplied it
+pub async fn load_cached(paths: &CloudPaths) -> Option<CachedInstance> {
+    let instance_id = read_trimmed(&paths.cached_instance_id()).await?;
+    let datasource = read_trimmed(&paths.datasource_file(&instance_id)).await?;
+    Some(CachedInstance {
+        datasource,
+        instance_id,
+    })
+}
+
+async fn read_trimmed(path: &std::path::Path) -> Option<String> {
+    let content = fs::read_to_string(path).await.ok()?;
+    let content = content.trim();
+    (!content.is_empty()).then(|| content.to_string())
+}
+
+/// Check the cache of the running system against its system config
+pub async fn check_cache() -> CacheDecision {
+    let paths = CloudPaths::new();
+    let system = load_merged_config(&paths).await.unwrap_or_else(|e| {
+        warn!("Failed to load system config: {}", e);
+        CloudConfig::default()
+    });
+    let decision = CachePolicy::from_config(&system)
+        .decide(&paths, registry())
+        .await;
+    match &decision {
+        CacheDecision::Reuse(cached) => info!(
+            "Reusing cached instance {} from {}",
+            cached.instance_id, cached.datasource
+        ),
+        CacheDecision::Redetect(reason) => debug!("Not reusing instance cache: {}", reason),
+    }
+    decision
+}
+
+/// The datasource for a stage of this boot
+///
+/// Like [`current_datasource`], but the [`snapshot`](super::snapshot) an
+/// earlier stage took this boot is used if there is one, so every stage
+/// works from the same data. Only the stage pipeline wants that; hotplug
+/// and queries need what the datasource says now.
+pub async fn stage_datasource() -> Result<Box<dyn Datasource>, CloudInitError> {
+    if let Some(ds) = shared::get() {
+        return Ok(ds);
+    }
+    if let Some(ds) = snapshot::load_datasource(&CloudPaths::new()).await {
+        return Ok(shared::keep(ds));
+    }
+    current_datasource().await
+}
+
+/// The cached datasource if the cache is valid, else a detected one
+///
+/// A datasource already [`shared`](super::shared) by an earlier stage in
+/// this process is returned without checking the cache again.
+pub async fn current_datasource() -> Result<Box<dyn Datasource>, CloudInitError> {
+    if let Some(ds) = shared::get() {
+        return Ok(ds);
+    }
+    if let CacheDecision::Reuse(cached) = check_cache().await
+        && let Some(ds) = registry()
+            .into_iter()
+            .find(|ds| ds.name().eq_ignore_ascii_case(&cached.datasource))
+    {
+        return Ok(shared::keep(ds));
+    }
+    detect_datasource().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasources::mock::MockDatasource;
+    use tempfile::TempDir;
+
+    fn cache(temp: &TempDir, datasource: &str, instance_id: &str) -> CloudPaths {
+        let paths = CloudPaths::with_base(temp.path());
+        std::fs::create_dir_all(paths.instance_dir(instance_id)).unwrap();
+        std::fs::create_dir_all(paths.data_dir()).unwrap();
+        std::fs::write(paths.cached_instance_id(), format!("{}\n", instance_id)).unwrap();
+        std::fs::write(paths.datasource_file(instance_id), datasource).unwrap();
+        paths
+    }
+
+    fn mock(check: Option<bool>) -> Vec<Box<dyn Datasource>> {
+        vec![Box::new(
+            MockDatasource::new()
+                .with_name("NoCloud")
+                .with_instance_check(check),
+        )]
+    }
+
+    #[tokio::test]
+    async fn test_no_cache_redetects() {
+        let temp = TempDir::new().unwrap();
+        let paths = CloudPaths::with_base(temp.path());
+        let decision = CachePolicy::default()
+            .decide(&paths, mock(Some(true)))
+            .await;
+        assert!(matches!(decision, CacheDecision::Redetect(_)));
+    }
+
+    #[tokio::test]
+    async fn test_instance_id_check() {
+        let temp = TempDir::new().unwrap();
+        let paths = cache(&temp, "NoCloud", "iid-1");
+        let policy = CachePolicy::default();
+
+        assert_eq!(
+            policy.decide(&paths, mock(Some(true))).await,
+            CacheDecision::Reuse(CachedInstance {
+                datasource: "NoCloud".to_string(),
+                instance_id: "iid-1".to_string(),
+            })
+        );
+        assert!(matches!(
+            policy.decide(&paths, mock(Some(false))).await,
+            CacheDecision::Redetect(_)
+        ));
+        assert!(matches!(
+            policy.decide(&paths, mock(None)).await,
+            CacheDecision::Redetect(_)
+        ));
+        assert!(matches!(
+            policy.decide(&paths, Vec::new()).await,
+            CacheDecision::Redetect(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_manual_clean_and_metadata_check() {
+        let temp = TempDir::new().unwrap();
+        let paths = cache(&temp, "NoCloud", "iid-1");
+
+        let manual = CachePolicy {
+            manual_clean: true,
+            check: CacheCheck::Metadata,
+        };
+        assert!(matches!(
+            manual.decide(&paths, mock(Some(false))).await,
+            CacheDecision::Reuse(_)
+        ));
+
+        let metadata = CachePolicy {
+            manual_clean: false,
+            check: CacheCheck::Metadata,
+        };
+        assert!(matches!(
+            metadata.decide(&paths, mock(Some(true))).await,
+            CacheDecision::Redetect(_)
+        ));
+
+        // Not even manual_cache_clean reuses the cache of a sealed image
+        std::fs::write(paths.sealed_marker(), "").unwrap();
+        assert!(matches!(
+            manual.decide(&paths, mock(Some(true))).await,
+            CacheDecision::Redetect(reason) if reason == "image was sealed"
+        ));
+    }
+
+    #[test]
+    fn test_policy_from_config() {
+        let config =
+            CloudConfig::from_yaml("manual_cache_clean: true\ncache_check: metadata\n").unwrap();
+        assert_eq!(
+            CachePolicy::from_config(&config),
+            CachePolicy {
+                manual_clean: true,
+                check: CacheCheck::Metadata,
+            }
+        );
+        assert_eq!(
+            CachePolicy::from_config(&CloudConfig::default()),
+            CachePolicy::default()
+        );
+    }
+}
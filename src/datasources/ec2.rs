@@ -2,31 +2,69 @@
 //!
 //! Fetches metadata from the EC2 Instance Metadata Service (IMDS).
 //! Supports both IMDSv1 and IMDSv2 (preferred for security).
+//!
+//! With `verify_identity` set for the datasource, the signed instance
+//! identity document is checked against the AWS certificates in
+//! `identity_certificates` before anything is fetched, and the instance
+//! ID, region and zone are taken from it:
+//!
+//! ```yaml
+//! datasource:
+//!   Ec2:
+//!     verify_identity: true
+//!     identity_certificates: /etc/cloud/ec2-identity.pem
+//! ```
+//!
+//! AWS publishes one RSA certificate per region; the file may hold
+//! several, and any of them may have signed the document.
+//!
+//! Instance tags are fetched from `meta-data/tags/instance` when the
+//! instance allows tags in its metadata, and show up as `v1.tags`.
 
 use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use reqwest::Client;
-use std::path::Path;
+use ring::signature::{RSA_PKCS1_2048_8192_SHA256, UnparsedPublicKey};
+use std::path::PathBuf;
 use std::time::Duration;
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
 
 use super::Datasource;
+use super::endpoint::MetadataEndpoint;
+use crate::network::{EthernetConfig, InterfaceCommon, MatchConfig, NetworkConfig};
+use crate::platform::Platform;
+use crate::state::CloudPaths;
 use crate::{CloudInitError, InstanceMetadata, UserData, config::CloudConfig};
 
-/// EC2 metadata service base URL (link-local address)
-const IMDS_BASE_URL: &str = "http://169.254.169.254";
+/// EC2 metadata service base URLs: IPv4 link-local, then the IPv6
+/// address Nitro instances serve it on
+const IMDS_BASE_URLS: &[&str] = &["http://169.254.169.254", "http://[fd00:ec2::254]"];
 
 /// IMDSv2 token TTL in seconds
 const TOKEN_TTL_SECONDS: u32 = 300;
 
+/// Default `identity_certificates`
+pub const DEFAULT_IDENTITY_CERTIFICATES: &str = "/etc/cloud/ec2-identity.pem";
+
 /// EC2 datasource for AWS and compatible clouds (OpenStack, etc.)
 pub struct Ec2 {
     client: Client,
-    base_url: String,
+    endpoint: MetadataEndpoint,
+    identity: IdentityCheck,
+}
+
+/// Whether the instance identity document must be verified
+enum IdentityCheck {
+    /// As the `datasource` key in the system config says
+    FromConfig,
+    Disabled,
+    /// Against the certificates in this PEM file
+    Certificates(PathBuf),
 }
 
 impl Ec2 {
     pub fn new() -> Self {
-        let client = Client::builder()
+        let client = crate::http::metadata_client_builder()
             .timeout(Duration::from_secs(5))
             .connect_timeout(Duration::from_secs(2))
             .build()
@@ -34,13 +72,14 @@ impl Ec2 {
 
         Self {
             client,
-            base_url: IMDS_BASE_URL.to_string(),
+            endpoint: MetadataEndpoint::new("EC2", IMDS_BASE_URLS),
+            identity: IdentityCheck::FromConfig,
         }
     }
 
     /// Create with a custom base URL (for testing)
     pub fn with_base_url(base_url: &str) -> Self {
-        let client = Client::builder()
+        let client = crate::http::metadata_client_builder()
             .timeout(Duration::from_secs(5))
             .connect_timeout(Duration::from_secs(2))
             .build()
@@ -48,13 +87,21 @@ impl Ec2 {
 
         Self {
             client,
-            base_url: base_url.to_string(),
+            endpoint: MetadataEndpoint::fixed(base_url),
+            identity: IdentityCheck::Disabled,
         }
     }
 
+    /// Verify the instance identity document against the certificates
+    /// in the PEM file at `path`
+    pub fn with_identity_certificates(mut self, path: impl Into<PathBuf>) -> Self {
+        self.identity = IdentityCheck::Certificates(path.into());
+        self
+    }
+
     /// Get IMDSv2 token for authenticated requests
     async fn get_imdsv2_token(&self) -> Option<String> {
-        let url = format!("{}/latest/api/token", self.base_url);
+        let url = format!("{}/latest/api/token", self.endpoint.url(&self.client).await);
         let response = self
             .client
             .put(&url)
@@ -75,7 +122,12 @@ impl Ec2 {
 
     /// Fetch a metadata path, trying IMDSv2 first then falling back to IMDSv1
     async fn fetch_metadata_path(&self, path: &str) -> Result<String, CloudInitError> {
-        let url = format!("{}/latest/meta-data/{}", self.base_url, path);
+        self.fetch_path(&format!("meta-data/{}", path)).await
+    }
+
+    /// Fetch a path below `/latest/`, trying IMDSv2 first then IMDSv1
+    async fn fetch_path(&self, path: &str) -> Result<String, CloudInitError> {
+        let url = format!("{}/latest/{}", self.endpoint.url(&self.client).await, path);
 
         // Try IMDSv2 first (more secure)
         if let Some(token) = self.get_imdsv2_token().await {
@@ -107,9 +159,112 @@ impl Ec2 {
         }
     }
 
+    /// Provider-specific metadata, laid out like the IMDS `meta-data` tree
+    ///
+    /// Paths that are missing (e.g. `public-keys` without a key pair) are
+    /// left out.
+    async fn fetch_raw_metadata(&self, metadata: &InstanceMetadata) -> serde_json::Value {
+        let mut raw = serde_json::Map::new();
+        if let Some(id) = &metadata.instance_id {
+            raw.insert("instance-id".to_string(), id.clone().into());
+        }
+        if let Some(hostname) = &metadata.local_hostname {
+            raw.insert("local-hostname".to_string(), hostname.clone().into());
+        }
+        if let Some(az) = &metadata.availability_zone {
+            raw.insert(
+                "placement".to_string(),
+                serde_json::json!({ "availability-zone": az }),
+            );
+        }
+        if let Ok(groups) = self.fetch_metadata_path("security-groups").await {
+            let groups: Vec<&str> = groups.lines().filter(|g| !g.is_empty()).collect();
+            raw.insert("security-groups".to_string(), groups.into());
+        }
+        if let Ok(key) = self.fetch_metadata_path("public-keys/0/openssh-key").await {
+            raw.insert("public-keys".to_string(), vec![key.trim()].into());
+        }
+        if let Ok(lifecycle) = self.fetch_metadata_path("instance-life-cycle").await {
+            raw.insert("instance-life-cycle".to_string(), lifecycle.into());
+        }
+        if let Ok(names) = self.fetch_metadata_path("block-device-mapping/").await {
+            let mut mapping = serde_json::Map::new();
+            for name in names.lines().map(str::trim).filter(|n| !n.is_empty()) {
+                let path = format!("block-device-mapping/{}", name);
+                if let Ok(device) = self.fetch_metadata_path(&path).await {
+                    mapping.insert(name.to_string(), device.trim().into());
+                }
+            }
+            raw.insert("block-device-mapping".to_string(), mapping.into());
+        }
+        // Only there when the instance allows tags in its metadata
+        if let Ok(keys) = self.fetch_metadata_path("tags/instance").await {
+            let mut tags = serde_json::Map::new();
+            for key in keys.lines().map(str::trim).filter(|k| !k.is_empty()) {
+                let path = format!("tags/instance/{}", key);
+                if let Ok(value) = self.fetch_metadata_path(&path).await {
+                    tags.insert(key.to_string(), value.into());
+                }
+            }
+            raw.insert("tags".to_string(), serde_json::json!({ "instance": tags }));
+        }
+        serde_json::Value::Object(raw)
+    }
+
+    /// PEM file to verify the identity document against, if verification
+    /// is enabled
+    async fn identity_certificates(&self) -> Option<PathBuf> {
+        match &self.identity {
+            IdentityCheck::Disabled => None,
+            IdentityCheck::Certificates(path) => Some(path.clone()),
+            IdentityCheck::FromConfig => {
+                let system = crate::config::load_merged_config(&CloudPaths::new())
+                    .await
+                    .unwrap_or_default();
+                let settings = system.datasource_settings(self.name())?;
+                (settings.verify_identity == Some(true)).then(|| {
+                    PathBuf::from(
+                        settings
+                            .identity_certificates
+                            .as_deref()
+                            .unwrap_or(DEFAULT_IDENTITY_CERTIFICATES),
+                    )
+                })
+            }
+        }
+    }
+
+    /// Fetch the instance identity document and check its signature
+    async fn verified_identity(
+        &self,
+        certificates: &std::path::Path,
+    ) -> Result<serde_json::Value, CloudInitError> {
+        let pem = tokio::fs::read_to_string(certificates).await.map_err(|e| {
+            CloudInitError::Datasource(format!(
+                "Cannot read EC2 identity certificates {}: {}",
+                certificates.display(),
+                e
+            ))
+        })?;
+        let document = self
+            .fetch_path("dynamic/instance-identity/document")
+            .await?;
+        let signature = self
+            .fetch_path("dynamic/instance-identity/signature")
+            .await?;
+        verify_identity_signature(&document, &signature, &pem)?;
+        info!("EC2 instance identity document signature verified");
+        serde_json::from_str(&document).map_err(|e| {
+            CloudInitError::Datasource(format!("Invalid EC2 instance identity document: {}", e))
+        })
+    }
+
     /// Check if IMDS is reachable
     async fn check_imds(&self) -> bool {
-        let url = format!("{}/latest/meta-data/", self.base_url);
+        let url = format!(
+            "{}/latest/meta-data/",
+            self.endpoint.url(&self.client).await
+        );
 
         // Try IMDSv2 first
         if let Some(token) = self.get_imdsv2_token().await {
@@ -127,25 +282,140 @@ impl Ec2 {
         // Fall back to IMDSv1
         self.client.get(&url).send().await.is_ok()
     }
+}
+
+/// Take instance ID, region and zone from a verified identity document
+///
+/// The unsigned meta-data paths must agree with it.
+fn apply_identity(
+    metadata: &mut InstanceMetadata,
+    identity: &serde_json::Value,
+) -> Result<(), CloudInitError> {
+    let field = |name: &str| {
+        identity
+            .get(name)
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+    };
+    let instance_id = field("instanceId").ok_or_else(|| {
+        CloudInitError::Datasource("EC2 identity document has no instanceId".to_string())
+    })?;
+    if metadata
+        .instance_id
+        .as_ref()
+        .is_some_and(|id| *id != instance_id)
+    {
+        return Err(CloudInitError::Datasource(format!(
+            "EC2 meta-data instance-id does not match the signed identity document ({})",
+            instance_id
+        )));
+    }
+    metadata.instance_id = Some(instance_id);
+    metadata.region = field("region").or(metadata.region.take());
+    metadata.availability_zone = field("availabilityZone").or(metadata.availability_zone.take());
+    metadata.instance_type = field("instanceType").or(metadata.instance_type.take());
+    Ok(())
+}
 
-    /// Check if we're running on EC2 by looking for DMI data
-    async fn check_dmi_data() -> bool {
-        let dmi_paths = [
-            "/sys/class/dmi/id/product_name",
-            "/sys/class/dmi/id/bios_vendor",
-            "/sys/class/dmi/id/sys_vendor",
-        ];
+/// Check the base64 RSA/SHA-256 `signature` of the identity `document`
+/// against the certificates in `pem`
+///
+/// Succeeds if any of the certificates verifies it.
+pub fn verify_identity_signature(
+    document: &str,
+    signature: &str,
+    pem: &str,
+) -> Result<(), CloudInitError> {
+    let signature: String = signature.split_whitespace().collect();
+    let signature = BASE64.decode(signature).map_err(|e| {
+        CloudInitError::Datasource(format!("Invalid EC2 identity signature: {}", e))
+    })?;
+    let certificates = pem_certificates(pem);
+    if certificates.is_empty() {
+        return Err(CloudInitError::Datasource(
+            "No certificates to verify the EC2 identity document against".to_string(),
+        ));
+    }
+    let verified = certificates.iter().any(|der| {
+        rsa_public_key(der).is_some_and(|key| {
+            UnparsedPublicKey::new(&RSA_PKCS1_2048_8192_SHA256, key)
+                .verify(document.as_bytes(), &signature)
+                .is_ok()
+        })
+    });
+    if verified {
+        Ok(())
+    } else {
+        Err(CloudInitError::Datasource(
+            "EC2 instance identity document signature does not verify".to_string(),
+        ))
+    }
+}
 
-        for path in &dmi_paths {
-            if let Ok(content) = tokio::fs::read_to_string(path).await {
-                let content = content.to_lowercase();
-                if content.contains("amazon") || content.contains("ec2") {
-                    return true;
+/// DER bodies of the `CERTIFICATE` blocks in `pem`
+fn pem_certificates(pem: &str) -> Vec<Vec<u8>> {
+    let mut certificates = Vec::new();
+    let mut body: Option<String> = None;
+    for line in pem.lines().map(str::trim) {
+        match line {
+            "-----BEGIN CERTIFICATE-----" => body = Some(String::new()),
+            "-----END CERTIFICATE-----" => {
+                if let Some(der) = body.take().and_then(|b| BASE64.decode(b).ok()) {
+                    certificates.push(der);
+                }
+            }
+            _ => {
+                if let Some(body) = &mut body {
+                    body.push_str(line);
                 }
             }
         }
+    }
+    certificates
+}
+
+/// Split one DER element off `input`: (tag, contents, rest)
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first & 0x80 == 0 {
+        (first as usize, rest)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n]
+            .iter()
+            .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        (len, &rest[n..])
+    };
+    (rest.len() >= len).then(|| (tag, &rest[..len], &rest[len..]))
+}
 
-        Path::new("/sys/hypervisor/uuid").exists()
+/// The RSAPublicKey in an X.509 certificate's subjectPublicKeyInfo
+fn rsa_public_key(certificate: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = der_element(certificate)?;
+    let (_, tbs, _) = der_element(certificate)?;
+    let mut rest = tbs;
+    // Optional [0] version
+    let (tag, _, after_version) = der_element(rest)?;
+    if tag == 0xa0 {
+        rest = after_version;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        rest = der_element(rest)?.2;
+    }
+    let (_, spki, _) = der_element(rest)?;
+    let (_, _algorithm, rest) = der_element(spki)?;
+    let (tag, bits, _) = der_element(rest)?;
+    if tag != 0x03 {
+        return None;
+    }
+    match bits.split_first()? {
+        (0, key) => Some(key),
+        _ => None,
     }
 }
 
@@ -163,7 +433,7 @@ impl Datasource for Ec2 {
 
     async fn is_available(&self) -> bool {
         // First check DMI data (fast, local check)
-        if Self::check_dmi_data().await {
+        if Platform::detect().await == Platform::Ec2 {
             // Then verify IMDS is reachable
             return self.check_imds().await;
         }
@@ -172,6 +442,10 @@ impl Datasource for Ec2 {
 
     async fn get_metadata(&self) -> Result<InstanceMetadata, CloudInitError> {
         debug!("Fetching EC2 instance metadata");
+        let identity = match self.identity_certificates().await {
+            Some(certificates) => Some(self.verified_identity(&certificates).await?),
+            None => None,
+        };
 
         let mut metadata = InstanceMetadata {
             cloud_name: Some("aws".to_string()),
@@ -199,13 +473,22 @@ impl Datasource for Ec2 {
             }
         }
 
+        if let Some(identity) = identity {
+            apply_identity(&mut metadata, &identity)?;
+            metadata.raw = self.fetch_raw_metadata(&metadata).await;
+            if let serde_json::Value::Object(raw) = &mut metadata.raw {
+                raw.insert("instance-identity".to_string(), identity);
+            }
+        } else {
+            metadata.raw = self.fetch_raw_metadata(&metadata).await;
+        }
         Ok(metadata)
     }
 
     async fn get_userdata(&self) -> Result<UserData, CloudInitError> {
         debug!("Fetching EC2 user-data");
 
-        let url = format!("{}/latest/user-data", self.base_url);
+        let url = format!("{}/latest/user-data", self.endpoint.url(&self.client).await);
 
         // Try IMDSv2 first
         let response = if let Some(token) = self.get_imdsv2_token().await {
@@ -229,7 +512,10 @@ impl Datasource for Ec2 {
             return Ok(UserData::None);
         }
 
-        let content = response.text().await?;
+        let limit = crate::userdata::limits::current().max_size();
+        let body =
+            crate::userdata::limits::read_body(response, "User-data", limit, "max_size").await?;
+        let content = String::from_utf8_lossy(&body).into_owned();
 
         if content.is_empty() {
             return Ok(UserData::None);
@@ -249,4 +535,63 @@ impl Datasource for Ec2 {
             }
         }
     }
+
+    /// Build DHCP network config for every attached ENI
+    ///
+    /// Each NIC is matched by MAC and named `eth<device-number>`; IPv6 DHCP
+    /// is enabled on NICs that have IPv6 addresses assigned, and IPv4 DHCP
+    /// is left off on those that have no IPv4 address.
+    async fn get_network_config(&self) -> Result<Option<NetworkConfig>, CloudInitError> {
+        let macs = match self.fetch_metadata_path("network/interfaces/macs/").await {
+            Ok(listing) => listing,
+            Err(e) => {
+                debug!("No EC2 network interface metadata: {}", e);
+                return Ok(None);
+            }
+        };
+
+        let mut config = NetworkConfig {
+            version: 2,
+            ..Default::default()
+        };
+        for mac in macs.lines().map(|l| l.trim().trim_end_matches('/')) {
+            if mac.is_empty() {
+                continue;
+            }
+            let base = format!("network/interfaces/macs/{}", mac);
+            let device = self
+                .fetch_metadata_path(&format!("{}/device-number", base))
+                .await
+                .ok()
+                .and_then(|d| d.trim().parse::<u32>().ok())
+                .unwrap_or(config.ethernets.len() as u32);
+            let has_ipv6 = self
+                .fetch_metadata_path(&format!("{}/ipv6s", base))
+                .await
+                .is_ok_and(|v| !v.trim().is_empty());
+            let has_ipv4 = self
+                .fetch_metadata_path(&format!("{}/local-ipv4s", base))
+                .await
+                .is_ok_and(|v| !v.trim().is_empty());
+
+            config.ethernets.insert(
+                format!("eth{}", device),
+                EthernetConfig {
+                    common: InterfaceCommon {
+                        // NICs in IPv6-only subnets have no IPv4 address
+                        // and would only wait out DHCPv4
+                        dhcp4: (has_ipv4 || !has_ipv6).then_some(true),
+                        dhcp6: has_ipv6.then_some(true),
+                        ..Default::default()
+                    },
+                    match_config: Some(MatchConfig {
+                        macaddress: Some(mac.to_lowercase()),
+                        ..Default::default()
+                    }),
+                },
+            );
+        }
+
+        Ok(Some(config).filter(|c| c.has_interfaces()))
+    }
 }
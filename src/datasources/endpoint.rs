@@ -0,0 +1,146 @@
+//! Metadata service address selection
+//!
+//! Metadata services answer on an IPv4 link-local address and, on some
+//! clouds, on an IPv6 address as well. IPv6-only subnets can reach only
+//! the latter, so a datasource tries its addresses in order and keeps the
+//! first that answers. On a host with only an IPv6 default route the IPv6
+//! addresses are tried first, so provisioning does not wait out an IPv4
+//! connect timeout. `metadata_urls` replaces the list for a datasource:
+//!
+//! ```yaml
+//! datasource:
+//!   Ec2:
+//!     metadata_urls: ["http://[fd00:ec2::254]"]
+//! ```
+
+use crate::config::load_merged_config;
+use crate::network::ephemeral;
+use crate::state::CloudPaths;
+use reqwest::Client;
+use tokio::sync::OnceCell;
+use tracing::{debug, info};
+
+/// Base URLs of a metadata service, probed lazily on first use
+pub struct MetadataEndpoint {
+    /// Datasource whose `metadata_urls` setting applies, if any
+    datasource: Option<&'static str>,
+    defaults: Vec<String>,
+    selected: OnceCell<String>,
+}
+
+impl MetadataEndpoint {
+    /// Try `defaults` in order unless `datasource` sets `metadata_urls`
+    pub fn new(datasource: &'static str, defaults: &[&str]) -> Self {
+        Self {
+            datasource: Some(datasource),
+            defaults: defaults.iter().map(ToString::to_string).collect(),
+            selected: OnceCell::new(),
+        }
+    }
+
+    /// Always use `url`, without probing
+    pub fn fixed(url: &str) -> Self {
+        Self {
+            datasource: None,
+            defaults: vec![url.to_string()],
+            selected: OnceCell::new(),
+        }
+    }
+
+    /// Base URLs tried when `metadata_urls` is not set
+    pub fn defaults(&self) -> &[String] {
+        &self.defaults
+    }
+
+    /// The base URL in use, probing the candidates on first call
+    ///
+    /// The first candidate that answers at all wins; if none does, the
+    /// first one is used and requests fail as they would have.
+    pub async fn url(&self, client: &Client) -> &str {
+        self.selected
+            .get_or_init(|| async {
+                let mut candidates = self.candidates().await;
+                if candidates.len() > 1 {
+                    if ephemeral::is_ipv6_only_host().await {
+                        prefer_ipv6(&mut candidates);
+                    }
+                    for url in &candidates {
+                        if client.get(format!("{}/", url)).send().await.is_ok() {
+                            info!("Using metadata service at {}", url);
+                            return url.clone();
+                        }
+                        debug!("No metadata service at {}", url);
+                    }
+                }
+                candidates.into_iter().next().unwrap_or_default()
+            })
+            .await
+    }
+
+    /// The configured or default base URLs
+    async fn candidates(&self) -> Vec<String> {
+        let Some(datasource) = self.datasource else {
+            return self.defaults.clone();
+        };
+        let system = load_merged_config(&CloudPaths::new())
+            .await
+            .unwrap_or_default();
+        match system
+            .datasource_settings(datasource)
+            .and_then(|settings| settings.metadata_urls.clone())
+        {
+            Some(urls) if !urls.is_empty() => urls
+                .into_iter()
+                .map(|url| url.trim_end_matches('/').to_string())
+                .collect(),
+            _ => self.defaults.clone(),
+        }
+    }
+}
+
+/// Move URLs with an IPv6 literal host ahead of the others, keeping the
+/// relative order within each group
+fn prefer_ipv6(urls: &mut [String]) {
+    urls.sort_by_key(|url| !url.contains("://["));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::MockServer;
+
+    #[tokio::test]
+    async fn test_first_answering_url_wins() {
+        let server = MockServer::start().await;
+        let endpoint = MetadataEndpoint {
+            datasource: None,
+            defaults: vec!["http://127.0.0.1:9".to_string(), server.uri()],
+            selected: OnceCell::new(),
+        };
+        let client = Client::new();
+        assert_eq!(endpoint.url(&client).await, server.uri());
+
+        let fixed = MetadataEndpoint::fixed("http://127.0.0.1:9");
+        assert_eq!(fixed.url(&client).await, "http://127.0.0.1:9");
+    }
+
+    #[test]
+    fn test_prefer_ipv6_keeps_order_within_family() {
+        let mut urls = vec![
+            "http://169.254.169.254".to_string(),
+            "http://[fd00:ec2::254]".to_string(),
+            "http://metadata.internal".to_string(),
+            "http://[fe80::a9fe:a9fe%25eth0]".to_string(),
+        ];
+        prefer_ipv6(&mut urls);
+        assert_eq!(
+            urls,
+            [
+                "http://[fd00:ec2::254]",
+                "http://[fe80::a9fe:a9fe%25eth0]",
+                "http://169.254.169.254",
+                "http://metadata.internal",
+            ]
+        );
+    }
+}
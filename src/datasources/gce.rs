@@ -4,15 +4,21 @@
 //! <https://cloud.google.com/compute/docs/metadata/overview>
 
 use async_trait::async_trait;
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use std::time::Duration;
 use tracing::debug;
 
 use super::Datasource;
+use super::endpoint::MetadataEndpoint;
+use crate::platform::Platform;
 use crate::{CloudInitError, InstanceMetadata, UserData, config::CloudConfig};
 
-/// GCE metadata service base URL
-const GCE_METADATA_URL: &str = "http://metadata.google.internal/computeMetadata/v1";
+/// GCE metadata service base URLs: by name, then the IPv6 address for
+/// IPv6-only subnets
+const GCE_METADATA_URLS: &[&str] = &[
+    "http://metadata.google.internal/computeMetadata/v1",
+    "http://[fd20:ce::254]/computeMetadata/v1",
+];
 
 /// Required header for GCE metadata requests
 const METADATA_FLAVOR_HEADER: &str = "Metadata-Flavor";
@@ -21,12 +27,12 @@ const METADATA_FLAVOR_VALUE: &str = "Google";
 /// GCE datasource for Google Cloud Platform
 pub struct Gce {
     client: Client,
-    base_url: String,
+    endpoint: MetadataEndpoint,
 }
 
 impl Gce {
     pub fn new() -> Self {
-        let client = Client::builder()
+        let client = crate::http::metadata_client_builder()
             .timeout(Duration::from_secs(5))
             .connect_timeout(Duration::from_secs(2))
             .build()
@@ -34,13 +40,13 @@ impl Gce {
 
         Self {
             client,
-            base_url: GCE_METADATA_URL.to_string(),
+            endpoint: MetadataEndpoint::new("GCE", GCE_METADATA_URLS),
         }
     }
 
     /// Create with a custom base URL (for testing)
     pub fn with_base_url(base_url: &str) -> Self {
-        let client = Client::builder()
+        let client = crate::http::metadata_client_builder()
             .timeout(Duration::from_secs(5))
             .connect_timeout(Duration::from_secs(2))
             .build()
@@ -48,13 +54,13 @@ impl Gce {
 
         Self {
             client,
-            base_url: base_url.to_string(),
+            endpoint: MetadataEndpoint::fixed(base_url),
         }
     }
 
     /// Fetch a metadata path with the required Metadata-Flavor header
     async fn fetch_metadata(&self, path: &str) -> Result<String, CloudInitError> {
-        let url = format!("{}/{}", self.base_url, path);
+        let url = format!("{}/{}", self.endpoint.url(&self.client).await, path);
         debug!("Fetching GCE metadata: {}", url);
 
         let response = self
@@ -65,7 +71,10 @@ impl Gce {
             .await?;
 
         if response.status().is_success() {
-            Ok(response.text().await?)
+            let limit = crate::userdata::limits::current().max_size();
+            let body =
+                crate::userdata::limits::read_body(response, path, limit, "max_size").await?;
+            Ok(String::from_utf8_lossy(&body).into_owned())
         } else {
             Err(CloudInitError::Datasource(format!(
                 "Failed to fetch {}: {}",
@@ -75,9 +84,44 @@ impl Gce {
         }
     }
 
+    /// Set the guest attribute `key`, given as `namespace/name`
+    ///
+    /// Returns `false` if guest attributes are not enabled for the
+    /// instance (`enable-guest-attributes` metadata), which the server
+    /// answers with 403.
+    pub async fn put_guest_attribute(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<bool, CloudInitError> {
+        let url = format!(
+            "{}/instance/guest-attributes/{}",
+            self.endpoint.url(&self.client).await,
+            key
+        );
+        debug!("Setting GCE guest attribute {}", key);
+
+        let response = self
+            .client
+            .put(&url)
+            .header(METADATA_FLAVOR_HEADER, METADATA_FLAVOR_VALUE)
+            .body(value.to_string())
+            .send()
+            .await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(true),
+            StatusCode::FORBIDDEN => Ok(false),
+            status => Err(CloudInitError::Datasource(format!(
+                "Failed to set guest attribute {}: {}",
+                key, status
+            ))),
+        }
+    }
+
     /// Check if GCE metadata server is reachable
     async fn check_metadata_server(&self) -> bool {
-        let url = format!("{}/", self.base_url);
+        let url = format!("{}/", self.endpoint.url(&self.client).await);
         self.client
             .get(&url)
             .header(METADATA_FLAVOR_HEADER, METADATA_FLAVOR_VALUE)
@@ -85,26 +129,6 @@ impl Gce {
             .await
             .is_ok()
     }
-
-    /// Check DMI data for GCE indicators
-    async fn check_dmi_data() -> bool {
-        let dmi_paths = [
-            "/sys/class/dmi/id/product_name",
-            "/sys/class/dmi/id/bios_vendor",
-            "/sys/class/dmi/id/sys_vendor",
-        ];
-
-        for path in &dmi_paths {
-            if let Ok(content) = tokio::fs::read_to_string(path).await {
-                let content = content.to_lowercase();
-                if content.contains("google") {
-                    return true;
-                }
-            }
-        }
-
-        false
-    }
 }
 
 impl Default for Gce {
@@ -121,7 +145,7 @@ impl Datasource for Gce {
 
     async fn is_available(&self) -> bool {
         // First check DMI data (fast, local check)
-        if Self::check_dmi_data().await {
+        if Platform::detect().await == Platform::Gce {
             return self.check_metadata_server().await;
         }
 
@@ -168,6 +192,11 @@ impl Datasource for Gce {
             }
         }
 
+        // Everything else about the instance, for the typed accessors
+        if let Ok(instance) = self.fetch_metadata("instance/?recursive=true").await {
+            metadata.raw = serde_json::from_str(&instance).unwrap_or_default();
+        }
+
         Ok(metadata)
     }
 
@@ -180,6 +209,7 @@ impl Datasource for Gce {
 
         let content = match userdata_result {
             Ok(content) if !content.is_empty() => content,
+            Err(e @ CloudInitError::InvalidData(_)) => return Err(e),
             _ => {
                 // Try startup-script as fallback
                 match self
@@ -219,6 +249,6 @@ mod tests {
     fn test_gce_default() {
         let gce = Gce::new();
         assert_eq!(gce.name(), "GCE");
-        assert_eq!(gce.base_url, GCE_METADATA_URL);
+        assert_eq!(gce.endpoint.defaults(), GCE_METADATA_URLS);
     }
 }
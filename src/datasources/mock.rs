@@ -3,6 +3,7 @@
 //! Provides a configurable mock datasource that can be used in unit tests.
 
 use async_trait::async_trait;
+use std::time::Duration;
 
 use super::Datasource;
 use crate::{CloudInitError, InstanceMetadata, UserData, config::CloudConfig};
@@ -24,6 +25,9 @@ use crate::{CloudInitError, InstanceMetadata, UserData, config::CloudConfig};
 pub struct MockDatasource {
     name: &'static str,
     available: bool,
+    delay: Option<Duration>,
+    seed_names: &'static [&'static str],
+    instance_check: Option<bool>,
     metadata: Option<InstanceMetadata>,
     userdata: Option<UserData>,
     metadata_error: Option<String>,
@@ -36,6 +40,9 @@ impl MockDatasource {
         Self {
             name: "Mock",
             available: true,
+            delay: None,
+            seed_names: &[],
+            instance_check: None,
             metadata: None,
             userdata: None,
             metadata_error: None,
@@ -55,6 +62,24 @@ impl MockDatasource {
         self
     }
 
+    /// Take `delay` to answer `is_available`, like a slow metadata service
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Set the seed directory names the datasource claims
+    pub fn with_seed_names(mut self, names: &'static [&'static str]) -> Self {
+        self.seed_names = names;
+        self
+    }
+
+    /// Set the answer to `check_instance_id`
+    pub fn with_instance_check(mut self, check: Option<bool>) -> Self {
+        self.instance_check = check;
+        self
+    }
+
     /// Set the metadata to return
     pub fn with_metadata(mut self, metadata: InstanceMetadata) -> Self {
         self.metadata = Some(metadata);
@@ -108,6 +133,9 @@ impl Datasource for MockDatasource {
     }
 
     async fn is_available(&self) -> bool {
+        if let Some(delay) = self.delay {
+            tokio::time::sleep(delay).await;
+        }
         self.available
     }
 
@@ -126,6 +154,14 @@ impl Datasource for MockDatasource {
 
         Ok(self.userdata.clone().unwrap_or(UserData::None))
     }
+
+    fn seed_names(&self) -> &'static [&'static str] {
+        self.seed_names
+    }
+
+    async fn check_instance_id(&self, _cached_id: &str) -> Option<bool> {
+        self.instance_check
+    }
 }
 
 #[cfg(test)]
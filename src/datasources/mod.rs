@@ -2,15 +2,28 @@
 //!
 //! Datasources provide instance metadata and user data from cloud providers.
 
+#[cfg(feature = "ds-azure")]
 pub mod azure;
+pub mod cache;
+#[cfg(feature = "ds-ec2")]
 pub mod ec2;
+pub mod endpoint;
+#[cfg(feature = "ds-gce")]
 pub mod gce;
 pub mod mock;
 pub mod nocloud;
+#[cfg(feature = "ds-openstack")]
 pub mod openstack;
+pub mod shared;
+pub mod snapshot;
 
+use crate::config::{CloudConfig, load_merged_config};
+use crate::network::NetworkConfig;
+use crate::state::CloudPaths;
 use crate::{CloudInitError, InstanceMetadata, UserData};
 use async_trait::async_trait;
+use std::time::Duration;
+use tracing::{debug, info, warn};
 
 /// Trait for cloud metadata datasources
 ///
@@ -30,6 +43,11 @@ pub trait Datasource: Send + Sync {
     /// Fetch instance metadata from this datasource
     async fn get_metadata(&self) -> Result<InstanceMetadata, CloudInitError>;
 
+    /// Fetch the provider-specific metadata, see [`InstanceMetadata::raw`]
+    async fn get_raw_metadata(&self) -> Result<serde_json::Value, CloudInitError> {
+        Ok(self.get_metadata().await?.raw)
+    }
+
     /// Fetch user data from this datasource
     async fn get_userdata(&self) -> Result<UserData, CloudInitError>;
 
@@ -37,24 +55,146 @@ pub trait Datasource: Send + Sync {
     async fn get_vendordata(&self) -> Result<Option<UserData>, CloudInitError> {
         Ok(None)
     }
+
+    /// Fetch network configuration supplied by the platform, if any
+    async fn get_network_config(&self) -> Result<Option<NetworkConfig>, CloudInitError> {
+        Ok(None)
+    }
+
+    /// Directories under `/var/lib/cloud/seed` this datasource reads
+    /// baked-in data from, e.g. `nocloud`
+    fn seed_names(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Whether `cached_id` is still this system's instance ID
+    ///
+    /// Only local sources (DMI, seed files) may be consulted. `None` means
+    /// the datasource cannot tell without the network, in which case the
+    /// cache policy re-runs detection.
+    async fn check_instance_id(&self, _cached_id: &str) -> Option<bool> {
+        None
+    }
 }
 
-/// Detect and return the appropriate datasource for this instance
-pub async fn detect_datasource() -> Result<Box<dyn Datasource>, CloudInitError> {
-    // Try datasources in order of priority
-    // NoCloud first (local config), then cloud providers
-    let datasources: Vec<Box<dyn Datasource>> = vec![
+/// Names of the compiled-in datasources, in detection order
+pub const COMPILED: &[&str] = &[
+    "NoCloud",
+    #[cfg(feature = "ds-ec2")]
+    "EC2",
+    #[cfg(feature = "ds-gce")]
+    "GCE",
+    #[cfg(feature = "ds-azure")]
+    "Azure",
+    #[cfg(feature = "ds-openstack")]
+    "OpenStack",
+];
+
+/// All compiled-in datasources, in detection order
+///
+/// NoCloud comes first (local config), then cloud providers. Providers
+/// are only present when their `ds-*` cargo feature is enabled.
+pub fn registry() -> Vec<Box<dyn Datasource>> {
+    vec![
         Box::new(nocloud::NoCloud::new()),
+        #[cfg(feature = "ds-ec2")]
         Box::new(ec2::Ec2::new()),
+        #[cfg(feature = "ds-gce")]
         Box::new(gce::Gce::new()),
+        #[cfg(feature = "ds-azure")]
         Box::new(azure::Azure::new()),
+        #[cfg(feature = "ds-openstack")]
         Box::new(openstack::OpenStack::new()),
-    ];
+    ]
+}
+
+/// The compiled-in datasource called `name` (case-insensitive), see
+/// [`COMPILED`]
+pub fn compiled(name: &str) -> Result<&'static str, CloudInitError> {
+    COMPILED
+        .iter()
+        .find(|c| c.eq_ignore_ascii_case(name))
+        .copied()
+        .ok_or_else(|| {
+            CloudInitError::Config(format!(
+                "Unknown datasource '{}' (compiled in: {})",
+                name,
+                COMPILED.join(", ")
+            ))
+        })
+}
 
-    for ds in datasources {
-        if ds.is_available().await {
-            tracing::info!("Detected datasource: {}", ds.name());
-            return Ok(ds);
+/// Detect and return the appropriate datasource for this instance
+///
+/// Datasources with a seed baked into the image are checked first. Each
+/// datasource gets its `max_wait` from the system config to answer, so a
+/// hung metadata service cannot hold up boot. With a datasource given to
+/// [`CloudInit`](crate::CloudInit) only that one is considered, and
+/// [`crate::offline`] only NoCloud.
+///
+/// With [`shared`] datasources enabled, an earlier detection is reused.
+pub async fn detect_datasource() -> Result<Box<dyn Datasource>, CloudInitError> {
+    if let Some(ds) = shared::get() {
+        return Ok(ds);
+    }
+    let paths = CloudPaths::new();
+    let system = load_merged_config(&paths).await.unwrap_or_else(|e| {
+        warn!("Failed to load system config: {}", e);
+        CloudConfig::default()
+    });
+    let mut candidates = registry();
+    if let Some(name) = crate::embed::Scope::with(|scope| scope.datasource).flatten() {
+        debug!("Datasource detection limited to {}", name);
+        candidates.retain(|ds| ds.name() == name);
+    }
+    if crate::offline::is_offline() || system.offline() {
+        debug!("Offline, only considering NoCloud");
+        candidates.retain(|ds| ds.name() == "NoCloud");
+    }
+    let candidates = seeded_first(candidates, &paths);
+    let ds = detect_from(candidates, |name| system.datasource_max_wait(name)).await?;
+    Ok(shared::keep(ds))
+}
+
+/// Move datasources that have a seed directory to the front
+///
+/// The order is otherwise kept, so with no seeds this is the registry order.
+pub fn seeded_first(
+    candidates: Vec<Box<dyn Datasource>>,
+    paths: &CloudPaths,
+) -> Vec<Box<dyn Datasource>> {
+    let (mut seeded, unseeded): (Vec<_>, Vec<_>) = candidates.into_iter().partition(|ds| {
+        ds.seed_names()
+            .iter()
+            .any(|name| paths.datasource_seed_dir(name).is_dir())
+    });
+    for ds in &seeded {
+        debug!("Found seed for datasource {}", ds.name());
+    }
+    seeded.extend(unseeded);
+    seeded
+}
+
+/// Return the first of `candidates` that is available
+///
+/// A datasource that does not answer within `max_wait(name)` is skipped.
+pub async fn detect_from(
+    candidates: Vec<Box<dyn Datasource>>,
+    max_wait: impl Fn(&str) -> Duration,
+) -> Result<Box<dyn Datasource>, CloudInitError> {
+    for ds in candidates {
+        let wait = max_wait(ds.name());
+        match tokio::time::timeout(wait, ds.is_available()).await {
+            Ok(true) => {
+                info!("Detected datasource: {}", ds.name());
+                return Ok(ds);
+            }
+            Ok(false) => {}
+            Err(_) => warn!(
+                "Datasource {} did not answer within {}s, skipping",
+                ds.name(),
+                wait.as_secs()
+            ),
         }
     }
 
@@ -66,6 +206,81 @@ mod tests {
     use super::*;
     use crate::datasources::mock::MockDatasource;
 
+    #[test]
+    fn test_override_must_be_compiled_in() {
+        let err = compiled("NoSuchCloud").unwrap_err();
+        assert!(err.to_string().contains("Unknown datasource 'NoSuchCloud'"));
+        assert_eq!(compiled("nocloud").unwrap(), "NoCloud");
+    }
+
+    #[test]
+    fn test_registry_matches_compiled_names() {
+        let names: Vec<_> = registry().iter().map(|ds| ds.name()).collect();
+        assert_eq!(names, COMPILED);
+        assert_eq!(names[0], "NoCloud");
+    }
+
+    #[tokio::test]
+    async fn test_detect_skips_hung_datasource() {
+        tokio::time::pause();
+        let candidates: Vec<Box<dyn Datasource>> = vec![
+            Box::new(
+                MockDatasource::new()
+                    .with_name("Gone")
+                    .with_available(false),
+            ),
+            Box::new(
+                MockDatasource::new()
+                    .with_name("Hung")
+                    .with_delay(Duration::from_secs(3600)),
+            ),
+            Box::new(
+                MockDatasource::new()
+                    .with_name("Slow")
+                    .with_delay(Duration::from_secs(5)),
+            ),
+        ];
+
+        let ds = detect_from(candidates, |name| {
+            Duration::from_secs(if name == "Slow" { 10 } else { 1 })
+        })
+        .await
+        .unwrap();
+        assert_eq!(ds.name(), "Slow");
+
+        let hung: Vec<Box<dyn Datasource>> = vec![Box::new(
+            MockDatasource::new().with_delay(Duration::from_secs(60)),
+        )];
+        let err = detect_from(hung, |_| Duration::from_secs(1)).await;
+        assert!(matches!(err, Err(CloudInitError::NoDatasource)));
+    }
+
+    #[test]
+    fn test_seeded_datasources_checked_first() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let paths = CloudPaths::with_base(temp.path());
+        std::fs::create_dir_all(paths.datasource_seed_dir("config_drive")).unwrap();
+        let candidates: Vec<Box<dyn Datasource>> = vec![
+            Box::new(
+                MockDatasource::new()
+                    .with_name("NoCloud")
+                    .with_seed_names(&["nocloud"]),
+            ),
+            Box::new(MockDatasource::new().with_name("EC2")),
+            Box::new(
+                MockDatasource::new()
+                    .with_name("OpenStack")
+                    .with_seed_names(&["config_drive"]),
+            ),
+        ];
+
+        let names: Vec<_> = seeded_first(candidates, &paths)
+            .iter()
+            .map(|ds| ds.name())
+            .collect();
+        assert_eq!(names, vec!["OpenStack", "NoCloud", "EC2"]);
+    }
+
     #[tokio::test]
     async fn test_get_vendordata_default() {
         let mock = MockDatasource::new();
@@ -2,36 +2,172 @@
 //!
 //! Reads metadata and user data from local files or mounted ISO.
 //! Common locations:
-//! - /var/lib/cloud/seed/nocloud/
+//! - /var/lib/cloud/seed/nocloud/ (below `--root` if given)
 //! - /var/lib/cloud/seed/nocloud-net/
 //! - Mounted filesystem with label 'cidata' or 'CIDATA'
+//!
+//! The SMBIOS system serial can point at the seed instead, as set by
+//! `virt-install --sysinfo system.serial='ds=nocloud;s=http://10.0.0.1/'`.
+//! `s=` (or `seedfrom=`) names a directory (`file://` or an absolute
+//! path) or an HTTP(S) base URL the seed files are fetched from; `i=`
+//! and `h=` set the instance ID and hostname inline, overriding meta-data.
 
 use async_trait::async_trait;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::fs;
-use tracing::debug;
+use tracing::{debug, warn};
 
 use super::Datasource;
+use crate::network::{NetworkConfig, eni::parse_eni, v1::parse_network_config};
+use crate::platform::{DmiInfo, SYSFS_ROOT};
+use crate::state::CloudPaths;
+use crate::userdata::limits;
 use crate::{CloudInitError, InstanceMetadata, UserData, config::CloudConfig};
 
 /// NoCloud datasource for local file-based configuration
 pub struct NoCloud {
     seed_dirs: Vec<PathBuf>,
+    /// sysfs tree the SMBIOS serial is read from
+    sysfs: PathBuf,
+}
+
+/// Seed directory names under `/var/lib/cloud/seed`, in search order
+const SEED_NAMES: &[&str] = &["nocloud", "nocloud-net"];
+
+/// Limit for fetching one seed file from a `seedfrom` URL
+const SEEDFROM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings from a `ds=nocloud;...` SMBIOS system serial
+#[derive(Debug, Default, PartialEq, Eq)]
+struct SerialSeed {
+    seedfrom: Option<String>,
+    /// Inline meta-data keys, applied over the seed's meta-data
+    metadata: Vec<(String, String)>,
+}
+
+impl SerialSeed {
+    /// Parse `ds=nocloud[-net][;key=value]...`, `None` for other serials
+    fn parse(serial: &str) -> Option<Self> {
+        let mut fields = serial.trim().split(';');
+        let name = fields.next()?.strip_prefix("ds=")?;
+        if !SEED_NAMES.contains(&name) {
+            return None;
+        }
+
+        let mut seed = Self::default();
+        for field in fields {
+            let Some((key, value)) = field.split_once('=') else {
+                continue;
+            };
+            let key = match key {
+                "s" | "seedfrom" => {
+                    seed.seedfrom = Some(value.to_string());
+                    continue;
+                }
+                "i" | "instance-id" => "instance-id",
+                "h" | "local-hostname" => "local-hostname",
+                other => {
+                    debug!("Ignoring unknown NoCloud serial field {}", other);
+                    continue;
+                }
+            };
+            seed.metadata.push((key.to_string(), value.to_string()));
+        }
+        Some(seed)
+    }
+}
+
+/// Where the seed files are read from
+#[derive(Debug, PartialEq, Eq)]
+enum SeedSource {
+    Dir(PathBuf),
+    /// Base URL ending in `/`
+    Url(String),
+}
+
+impl SeedSource {
+    fn from_seedfrom(seedfrom: &str) -> Option<Self> {
+        if let Some(path) = seedfrom.strip_prefix("file://") {
+            Some(Self::Dir(PathBuf::from(path)))
+        } else if seedfrom.starts_with('/') {
+            Some(Self::Dir(PathBuf::from(seedfrom)))
+        } else if seedfrom.starts_with("http://") || seedfrom.starts_with("https://") {
+            let base = match seedfrom.ends_with('/') {
+                true => seedfrom.to_string(),
+                false => format!("{}/", seedfrom),
+            };
+            Some(Self::Url(base))
+        } else {
+            warn!("Ignoring unsupported NoCloud seedfrom {}", seedfrom);
+            None
+        }
+    }
+}
+
+/// The seed of this instance
+#[derive(Debug)]
+struct Seed {
+    source: Option<SeedSource>,
+    metadata: Vec<(String, String)>,
+}
+
+impl Seed {
+    fn has_instance_id(&self) -> bool {
+        self.metadata.iter().any(|(key, _)| key == "instance-id")
+    }
 }
 
 impl NoCloud {
     pub fn new() -> Self {
+        let paths = CloudPaths::new();
         Self {
-            seed_dirs: vec![
-                PathBuf::from("/var/lib/cloud/seed/nocloud"),
-                PathBuf::from("/var/lib/cloud/seed/nocloud-net"),
-            ],
+            seed_dirs: SEED_NAMES
+                .iter()
+                .map(|name| paths.datasource_seed_dir(name))
+                .collect(),
+            sysfs: PathBuf::from(SYSFS_ROOT),
         }
     }
 
     /// Create with custom seed directories (for testing)
     pub fn with_seed_dirs(dirs: Vec<PathBuf>) -> Self {
-        Self { seed_dirs: dirs }
+        Self {
+            seed_dirs: dirs,
+            sysfs: PathBuf::from(SYSFS_ROOT),
+        }
+    }
+
+    /// Read the SMBIOS serial from the sysfs tree at `sysfs` (for testing)
+    pub fn with_sysfs(mut self, sysfs: impl Into<PathBuf>) -> Self {
+        self.sysfs = sysfs.into();
+        self
+    }
+
+    /// The seed named by the SMBIOS serial, else the first seed directory
+    ///
+    /// Without seed files, inline meta-data with an instance ID is a seed
+    /// of its own.
+    async fn find_seed(&self) -> Option<Seed> {
+        let serial = DmiInfo::read_from(&self.sysfs)
+            .await
+            .product_serial
+            .as_deref()
+            .and_then(SerialSeed::parse)
+            .unwrap_or_default();
+        let source = match serial
+            .seedfrom
+            .as_deref()
+            .and_then(SeedSource::from_seedfrom)
+        {
+            Some(source) => Some(source),
+            None => self.find_seed_dir().await.map(SeedSource::Dir),
+        };
+        let seed = Seed {
+            source,
+            metadata: serial.metadata,
+        };
+        (seed.source.is_some() || seed.has_instance_id()).then_some(seed)
     }
 
     /// Find the seed directory containing meta-data
@@ -68,9 +204,77 @@ impl NoCloud {
         None
     }
 
-    async fn read_file(&self, seed_dir: &Path, filename: &str) -> Option<String> {
-        let path = seed_dir.join(filename);
-        fs::read_to_string(&path).await.ok()
+    /// A seed file, `None` if the seed does not have it
+    ///
+    /// Fails only for a file over `user_data_limits.max_size`.
+    async fn read_file(
+        &self,
+        seed: &Seed,
+        filename: &str,
+    ) -> Result<Option<String>, CloudInitError> {
+        let body = match &seed.source {
+            None => return Ok(None),
+            Some(SeedSource::Dir(dir)) => match limits::read_file(&dir.join(filename)).await {
+                Ok(body) => body,
+                Err(e @ CloudInitError::InvalidData(_)) => return Err(e),
+                Err(_) => return Ok(None),
+            },
+            Some(SeedSource::Url(base)) => {
+                let url = format!("{}{}", base, filename);
+                let Some(client) = crate::http::client_builder()
+                    .ok()
+                    .and_then(|builder| builder.timeout(SEEDFROM_TIMEOUT).build().ok())
+                else {
+                    return Ok(None);
+                };
+                match client.get(&url).send().await {
+                    Ok(response) if response.status().is_success() => {
+                        let limit = limits::current().max_size();
+                        let what = format!("NoCloud {}", url);
+                        limits::read_body(response, &what, limit, "max_size")
+                            .await?
+                            .to_vec()
+                    }
+                    Ok(response) => {
+                        debug!("No NoCloud {} at {}: {}", filename, url, response.status());
+                        return Ok(None);
+                    }
+                    Err(e) => {
+                        warn!("Could not fetch NoCloud {}: {}", url, e);
+                        return Ok(None);
+                    }
+                }
+            }
+        };
+        Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+    }
+
+    /// Network config from the meta-data `network-interfaces` key, ENI
+    /// text written by old seed tools
+    async fn legacy_network_config(
+        &self,
+        seed: &Seed,
+    ) -> Result<Option<NetworkConfig>, CloudInitError> {
+        let Some(content) = self.read_file(seed, "meta-data").await? else {
+            return Ok(None);
+        };
+        let Some(eni) = serde_yaml::from_str::<serde_yaml::Value>(&content)
+            .ok()
+            .and_then(|parsed| {
+                parsed
+                    .get("network-interfaces")?
+                    .as_str()
+                    .map(str::to_string)
+            })
+        else {
+            return Ok(None);
+        };
+
+        debug!("Reading NoCloud network-interfaces from {:?}", seed.source);
+        let config = parse_eni(&eni).map_err(|e| {
+            CloudInitError::Datasource(format!("Invalid NoCloud network-interfaces: {}", e))
+        })?;
+        Ok(Some(config).filter(|c| c.has_interfaces()))
     }
 }
 
@@ -88,46 +292,78 @@ impl Datasource for NoCloud {
     }
 
     async fn is_available(&self) -> bool {
-        self.find_seed_dir().await.is_some()
+        let Some(seed) = self.find_seed().await else {
+            return false;
+        };
+        // A seed server that does not answer is no seed
+        match &seed.source {
+            Some(SeedSource::Url(_)) if !seed.has_instance_id() => self
+                .read_file(&seed, "meta-data")
+                .await
+                .is_ok_and(|content| content.is_some()),
+            _ => true,
+        }
+    }
+
+    fn seed_names(&self) -> &'static [&'static str] {
+        SEED_NAMES
+    }
+
+    /// Compares against the seed's meta-data, which re-imaging replaces
+    async fn check_instance_id(&self, cached_id: &str) -> Option<bool> {
+        self.find_seed().await?;
+        let metadata = self.get_metadata().await.ok()?;
+        Some(metadata.instance_id.as_deref() == Some(cached_id))
     }
 
     async fn get_metadata(&self) -> Result<InstanceMetadata, CloudInitError> {
-        let seed_dir = self
-            .find_seed_dir()
+        let seed = self
+            .find_seed()
             .await
             .ok_or_else(|| CloudInitError::Datasource("NoCloud seed directory not found".into()))?;
 
-        debug!("Reading NoCloud metadata from {:?}", seed_dir);
+        debug!("Reading NoCloud metadata from {:?}", seed.source);
 
         let mut metadata = InstanceMetadata {
             cloud_name: Some("nocloud".to_string()),
             ..Default::default()
         };
 
-        // Parse meta-data YAML
-        if let Some(content) = self.read_file(&seed_dir, "meta-data").await
-            && let Ok(parsed) = serde_yaml::from_str::<serde_yaml::Value>(&content)
-        {
+        // Parse meta-data YAML, then apply the serial's inline keys
+        let mut parsed = match self.read_file(&seed, "meta-data").await? {
+            Some(content) => serde_yaml::from_str::<serde_yaml::Value>(&content).ok(),
+            None => None,
+        };
+        if !seed.metadata.is_empty() {
+            let parsed = parsed.get_or_insert_with(|| serde_yaml::Mapping::new().into());
+            if let Some(map) = parsed.as_mapping_mut() {
+                for (key, value) in &seed.metadata {
+                    map.insert(key.as_str().into(), value.as_str().into());
+                }
+            }
+        }
+        if let Some(parsed) = parsed {
             if let Some(id) = parsed.get("instance-id").and_then(|v| v.as_str()) {
                 metadata.instance_id = Some(id.to_string());
             }
             if let Some(hostname) = parsed.get("local-hostname").and_then(|v| v.as_str()) {
                 metadata.local_hostname = Some(hostname.to_string());
             }
+            metadata.raw = serde_json::to_value(&parsed).unwrap_or_default();
         }
 
         Ok(metadata)
     }
 
     async fn get_userdata(&self) -> Result<UserData, CloudInitError> {
-        let seed_dir = self
-            .find_seed_dir()
+        let seed = self
+            .find_seed()
             .await
             .ok_or_else(|| CloudInitError::Datasource("NoCloud seed directory not found".into()))?;
 
-        debug!("Reading NoCloud user-data from {:?}", seed_dir);
+        debug!("Reading NoCloud user-data from {:?}", seed.source);
 
-        let content = match self.read_file(&seed_dir, "user-data").await {
+        let content = match self.read_file(&seed, "user-data").await? {
             Some(c) if !c.trim().is_empty() => c,
             _ => return Ok(UserData::None),
         };
@@ -146,6 +382,23 @@ impl Datasource for NoCloud {
             }
         }
     }
+
+    async fn get_network_config(&self) -> Result<Option<NetworkConfig>, CloudInitError> {
+        let Some(seed) = self.find_seed().await else {
+            return Ok(None);
+        };
+
+        let content = match self.read_file(&seed, "network-config").await? {
+            Some(c) if !c.trim().is_empty() => c,
+            _ => return self.legacy_network_config(&seed).await,
+        };
+
+        debug!("Reading NoCloud network-config from {:?}", seed.source);
+        let config = parse_network_config(&content).map_err(|e| {
+            CloudInitError::Datasource(format!("Invalid NoCloud network-config: {}", e))
+        })?;
+        Ok(Some(config).filter(|c| c.has_interfaces()))
+    }
 }
 
 #[cfg(test)]
@@ -183,7 +436,7 @@ mod tests {
         let seed = create_seed_dir(&temp);
         tokio::fs::write(
             seed.join("meta-data"),
-            "instance-id: i-nc123\nlocal-hostname: nc-host\n",
+            "instance-id: i-nc123\nlocal-hostname: nc-host\npublic-keys: [ssh-ed25519 AAAA]\n",
         )
         .await
         .unwrap();
@@ -194,6 +447,7 @@ mod tests {
         assert_eq!(metadata.cloud_name, Some("nocloud".to_string()));
         assert_eq!(metadata.instance_id, Some("i-nc123".to_string()));
         assert_eq!(metadata.local_hostname, Some("nc-host".to_string()));
+        assert_eq!(metadata.public_keys(), vec!["ssh-ed25519 AAAA"]);
     }
 
     #[tokio::test]
@@ -326,6 +580,143 @@ mod tests {
     #[test]
     fn test_nocloud_default() {
         let nc = NoCloud::default();
-        assert_eq!(nc.seed_dirs.len(), 2);
+        assert_eq!(
+            nc.seed_dirs,
+            vec![
+                PathBuf::from("/var/lib/cloud/seed/nocloud"),
+                PathBuf::from("/var/lib/cloud/seed/nocloud-net"),
+            ]
+        );
+        assert_eq!(nc.seed_names(), SEED_NAMES);
+    }
+
+    #[tokio::test]
+    async fn test_nocloud_get_network_config() {
+        let temp = TempDir::new().unwrap();
+        let seed = create_seed_dir(&temp);
+        std::fs::write(seed.join("meta-data"), "instance-id: i-1\n").unwrap();
+
+        let ds = NoCloud::with_seed_dirs(vec![seed.clone()]);
+        assert!(ds.get_network_config().await.unwrap().is_none());
+
+        std::fs::write(
+            seed.join("network-config"),
+            "version: 2\nethernets:\n  eth0:\n    dhcp4: true\n",
+        )
+        .unwrap();
+        let config = ds.get_network_config().await.unwrap().unwrap();
+        assert_eq!(config.ethernets["eth0"].common.dhcp4, Some(true));
+
+        std::fs::write(seed.join("network-config"), "version: [broken").unwrap();
+        assert!(ds.get_network_config().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_nocloud_legacy_network_interfaces() {
+        let temp = TempDir::new().unwrap();
+        let seed = create_seed_dir(&temp);
+        std::fs::write(
+            seed.join("meta-data"),
+            "instance-id: i-1\nnetwork-interfaces: |\n  auto eth0\n  iface eth0 inet static\n    address 10.0.0.5/24\n    gateway 10.0.0.1\n",
+        )
+        .unwrap();
+
+        let ds = NoCloud::with_seed_dirs(vec![seed.clone()]);
+        let config = ds.get_network_config().await.unwrap().unwrap();
+        assert_eq!(
+            config.ethernets["eth0"].common.addresses,
+            vec!["10.0.0.5/24"]
+        );
+
+        // network-config wins over the legacy key
+        std::fs::write(
+            seed.join("network-config"),
+            "version: 2\nethernets:\n  eth1:\n    dhcp4: true\n",
+        )
+        .unwrap();
+        let config = ds.get_network_config().await.unwrap().unwrap();
+        assert!(config.ethernets.contains_key("eth1"));
+        assert!(!config.ethernets.contains_key("eth0"));
+    }
+
+    fn fake_sysfs(serial: &str) -> TempDir {
+        let temp = TempDir::new().unwrap();
+        let dmi = temp.path().join("class/dmi/id");
+        std::fs::create_dir_all(&dmi).unwrap();
+        std::fs::write(dmi.join("product_serial"), format!("{}\n", serial)).unwrap();
+        temp
+    }
+
+    #[test]
+    fn test_parse_serial() {
+        let seed = SerialSeed::parse("ds=nocloud;s=http://10.0.0.1/seed/;h=vm1").unwrap();
+        assert_eq!(seed.seedfrom.as_deref(), Some("http://10.0.0.1/seed/"));
+        assert_eq!(
+            seed.metadata,
+            vec![("local-hostname".to_string(), "vm1".to_string())]
+        );
+
+        let seed = SerialSeed::parse("ds=nocloud-net;seedfrom=file:///srv/seed;i=iid-1").unwrap();
+        assert_eq!(
+            SeedSource::from_seedfrom(seed.seedfrom.as_deref().unwrap()),
+            Some(SeedSource::Dir(PathBuf::from("/srv/seed")))
+        );
+        assert_eq!(seed.metadata[0].1, "iid-1");
+
+        assert_eq!(SerialSeed::parse("ds=nocloud"), Some(SerialSeed::default()));
+        assert_eq!(SerialSeed::parse("VMware-56 4d 2b 1c"), None);
+        assert_eq!(SerialSeed::parse("ds=ec2;s=http://x/"), None);
+        assert_eq!(
+            SeedSource::from_seedfrom("http://10.0.0.1"),
+            Some(SeedSource::Url("http://10.0.0.1/".to_string()))
+        );
+        assert_eq!(SeedSource::from_seedfrom("ftp://10.0.0.1/"), None);
+    }
+
+    #[tokio::test]
+    async fn test_serial_seed_url() {
+        use wiremock::matchers::path;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(path("/seed/meta-data"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string("instance-id: iid-url\nlocal-hostname: old\n"),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(path("/seed/user-data"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("#cloud-config\n"))
+            .mount(&server)
+            .await;
+
+        let sysfs = fake_sysfs(&format!("ds=nocloud;s={}/seed/;h=new", server.uri()));
+        let nc = NoCloud::with_seed_dirs(vec![]).with_sysfs(sysfs.path());
+        assert!(nc.is_available().await);
+
+        let metadata = nc.get_metadata().await.unwrap();
+        assert_eq!(metadata.instance_id.as_deref(), Some("iid-url"));
+        assert_eq!(metadata.local_hostname.as_deref(), Some("new"));
+        assert!(matches!(
+            nc.get_userdata().await.unwrap(),
+            UserData::CloudConfig(_)
+        ));
+        assert!(nc.get_network_config().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_serial_inline_metadata_only() {
+        let sysfs = fake_sysfs("ds=nocloud;i=iid-inline;h=host1");
+        let nc = NoCloud::with_seed_dirs(vec![]).with_sysfs(sysfs.path());
+        assert!(nc.is_available().await);
+        let metadata = nc.get_metadata().await.unwrap();
+        assert_eq!(metadata.instance_id.as_deref(), Some("iid-inline"));
+        assert_eq!(metadata.local_hostname.as_deref(), Some("host1"));
+
+        // A hostname alone is not a seed
+        let sysfs = fake_sysfs("ds=nocloud;h=host1");
+        let nc = NoCloud::with_seed_dirs(vec![]).with_sysfs(sysfs.path());
+        assert!(!nc.is_available().await);
     }
 }
@@ -2,6 +2,10 @@
 //!
 //! Fetches metadata from OpenStack metadata service or config-drive.
 //! <https://docs.openstack.org/nova/latest/user/metadata.html>
+//!
+//! A config-drive nobody has mounted is found by its `config-2` label,
+//! mounted read-only on `/run/cloud-init/config-drive` while it is read
+//! and unmounted again.
 
 use async_trait::async_trait;
 use reqwest::Client;
@@ -9,12 +13,25 @@ use serde::Deserialize;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tokio::fs;
-use tracing::debug;
+use tracing::{debug, warn};
 
 use super::Datasource;
+use super::endpoint::MetadataEndpoint;
+use crate::network::v1::netmask_to_prefix;
+use crate::network::{
+    BondConfig, BondParameters, Dhcp6Mode, EthernetConfig, InterfaceCommon, MatchConfig,
+    NetworkConfig, RouteConfig, VlanConfig,
+};
+use crate::platform::{DmiInfo, Platform};
+use crate::runner::{HostRunner, SystemCommand, SystemRunner};
+use crate::state::CloudPaths;
 use crate::{CloudInitError, InstanceMetadata, UserData, config::CloudConfig};
 
 /// OpenStack metadata service URL (link-local address)
+///
+/// Nova's IPv6 service is on `fe80::a9fe:a9fe`, which needs an interface
+/// scope a URL cannot carry; IPv6-only deployments set `metadata_urls`
+/// to an address routed to it instead.
 const OPENSTACK_METADATA_URL: &str = "http://169.254.169.254/openstack";
 
 /// Config-drive mount locations to check
@@ -25,6 +42,21 @@ const CONFIG_DRIVE_PATHS: &[&str] = &[
     "/run/cloud-init/config-drive",
 ];
 
+/// Seed directory holding a config-drive tree baked into the image
+const CONFIG_DRIVE_SEED: &str = "config_drive";
+
+/// Filesystem labels of a config-drive (vfat labels are upper case)
+const CONFIG_DRIVE_LABELS: &[&str] = &["config-2", "CONFIG-2"];
+
+/// udev's links to block devices by filesystem label
+const DISK_BY_LABEL: &str = "/dev/disk/by-label";
+
+/// Where a config-drive device is mounted while it is read
+const CONFIG_DRIVE_MOUNT: &str = "/run/cloud-init/config-drive";
+
+/// Filesystems a config-drive is made with, tried in order
+const CONFIG_DRIVE_FILESYSTEMS: &[&str] = &["iso9660", "vfat"];
+
 /// OpenStack metadata JSON structure
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
@@ -41,17 +73,304 @@ struct OpenStackMetadata {
     project_id: String,
     #[serde(default)]
     meta: serde_json::Value,
+    /// The whole document, for [`InstanceMetadata::raw`]
+    #[serde(skip)]
+    raw: serde_json::Value,
+}
+
+/// `network_data.json` structure
+#[derive(Debug, Default, Deserialize)]
+struct NetworkData {
+    #[serde(default)]
+    links: Vec<NetworkLink>,
+    #[serde(default)]
+    networks: Vec<NetworkEntry>,
+    #[serde(default)]
+    services: Vec<NetworkService>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NetworkLink {
+    id: String,
+    #[serde(rename = "type", default)]
+    link_type: String,
+    ethernet_mac_address: Option<String>,
+    mtu: Option<u32>,
+    vlan_link: Option<String>,
+    vlan_id: Option<u16>,
+    #[serde(default)]
+    bond_links: Vec<String>,
+    bond_mode: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NetworkEntry {
+    #[serde(rename = "type")]
+    network_type: String,
+    link: String,
+    ip_address: Option<String>,
+    netmask: Option<String>,
+    #[serde(default)]
+    routes: Vec<NetworkRoute>,
+    #[serde(default)]
+    dns_nameservers: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NetworkRoute {
+    network: String,
+    netmask: String,
+    gateway: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NetworkService {
+    #[serde(rename = "type")]
+    service_type: String,
+    address: String,
+}
+
+impl NetworkData {
+    /// Convert to network config v2
+    ///
+    /// Physical links are matched by MAC and keyed by their link id;
+    /// bonds and VLANs reference those keys.
+    fn to_network_config(&self) -> NetworkConfig {
+        let mut config = NetworkConfig {
+            version: 2,
+            ..Default::default()
+        };
+
+        let dns: Vec<String> = self
+            .services
+            .iter()
+            .filter(|s| s.service_type == "dns")
+            .map(|s| s.address.clone())
+            .collect();
+
+        for link in &self.links {
+            let mut common = InterfaceCommon {
+                mtu: link.mtu,
+                ..Default::default()
+            };
+            for net in self.networks.iter().filter(|n| n.link == link.id) {
+                net.apply(&mut common);
+            }
+            if common.nameservers.addresses.is_empty() {
+                common.nameservers.addresses = dns.clone();
+            }
+
+            match link.link_type.as_str() {
+                "bond" => {
+                    config.bonds.insert(
+                        link.id.clone(),
+                        BondConfig {
+                            common,
+                            interfaces: link.bond_links.clone(),
+                            parameters: link.bond_mode.as_ref().map(|mode| BondParameters {
+                                mode: Some(mode.clone()),
+                                ..Default::default()
+                            }),
+                        },
+                    );
+                }
+                "vlan" => {
+                    let (Some(parent), Some(id)) = (&link.vlan_link, link.vlan_id) else {
+                        debug!("Skipping incomplete VLAN link {}", link.id);
+                        continue;
+                    };
+                    config.vlans.insert(
+                        link.id.clone(),
+                        VlanConfig {
+                            common,
+                            id,
+                            link: parent.clone(),
+                        },
+                    );
+                }
+                // phy, ovs, vif, tap, bridge and friends all appear as NICs
+                _ => {
+                    config.ethernets.insert(
+                        link.id.clone(),
+                        EthernetConfig {
+                            common,
+                            match_config: link.ethernet_mac_address.as_ref().map(|mac| {
+                                MatchConfig {
+                                    macaddress: Some(mac.to_lowercase()),
+                                    ..Default::default()
+                                }
+                            }),
+                        },
+                    );
+                }
+            }
+        }
+
+        config
+    }
+}
+
+impl NetworkEntry {
+    /// Apply this network to the interface it belongs to
+    fn apply(&self, common: &mut InterfaceCommon) {
+        match self.network_type.as_str() {
+            "ipv4_dhcp" => common.dhcp4 = Some(true),
+            "ipv6_dhcp" | "ipv6_dhcpv6-stateful" => {
+                common.dhcp6 = Some(true);
+                common.dhcp6_mode = Some(Dhcp6Mode::Stateful);
+            }
+            "ipv6_dhcpv6-stateless" => {
+                common.dhcp6 = Some(true);
+                common.dhcp6_mode = Some(Dhcp6Mode::Stateless);
+                common.accept_ra = Some(true);
+            }
+            "ipv6_slaac" => common.accept_ra = Some(true),
+            "ipv4" | "ipv6" => {
+                if let Some(ip) = &self.ip_address {
+                    let address = match (&self.netmask, ip.contains('/')) {
+                        (_, true) => ip.clone(),
+                        (Some(mask), false) => format!("{}/{}", ip, netmask_to_prefix(mask)),
+                        (None, false) if self.network_type == "ipv6" => format!("{}/64", ip),
+                        (None, false) => format!("{}/32", ip),
+                    };
+                    common.addresses.push(address);
+                }
+                for route in &self.routes {
+                    let prefix = netmask_to_prefix(&route.netmask);
+                    if prefix == 0 {
+                        if self.network_type == "ipv6" {
+                            common.gateway6 = Some(route.gateway.clone());
+                        } else {
+                            common.gateway4 = Some(route.gateway.clone());
+                        }
+                    } else {
+                        common.routes.push(RouteConfig {
+                            to: format!("{}/{}", route.network, prefix),
+                            via: Some(route.gateway.clone()),
+                            ..Default::default()
+                        });
+                    }
+                }
+                common
+                    .nameservers
+                    .addresses
+                    .extend(self.dns_nameservers.iter().cloned());
+            }
+            other => debug!("Ignoring unsupported network type {}", other),
+        }
+    }
+}
+
+/// Config-drive contents, mounted by us if nobody else had
+struct ConfigDrive {
+    path: PathBuf,
+    mounted: bool,
+}
+
+impl ConfigDrive {
+    /// A config-drive already in place, else the labeled device mounted
+    async fn find(runner: &dyn SystemRunner) -> Option<Self> {
+        if let Some(path) = OpenStack::find_config_drive().await {
+            return Some(Self {
+                path,
+                mounted: false,
+            });
+        }
+        Self::mount_labeled(
+            runner,
+            Path::new(DISK_BY_LABEL),
+            Path::new(CONFIG_DRIVE_MOUNT),
+        )
+        .await
+    }
+
+    /// Mount the device labeled `config-2` read-only on `mount_point`
+    ///
+    /// `None` if there is none, it mounts with none of the config-drive
+    /// filesystems, or it holds no OpenStack metadata.
+    async fn mount_labeled(
+        runner: &dyn SystemRunner,
+        by_label: &Path,
+        mount_point: &Path,
+    ) -> Option<Self> {
+        let device = CONFIG_DRIVE_LABELS
+            .iter()
+            .map(|label| by_label.join(label))
+            .find(|path| path.exists())?;
+        if let Err(e) = fs::create_dir_all(mount_point).await {
+            warn!("Cannot create {}: {}", mount_point.display(), e);
+            return None;
+        }
+
+        for fstype in CONFIG_DRIVE_FILESYSTEMS {
+            let mount = SystemCommand::new("mount")
+                .args(["-o", "ro", "-t", fstype])
+                .arg(device.to_string_lossy())
+                .arg(mount_point.to_string_lossy())
+                .probe();
+            match runner.run(&mount).await {
+                Ok(output) if output.is_success() => {}
+                Ok(output) => {
+                    debug!(
+                        "{} is not {}: {}",
+                        device.display(),
+                        fstype,
+                        output.stderr.trim()
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    debug!("Cannot mount {}: {}", device.display(), e);
+                    return None;
+                }
+            }
+            debug!("Mounted config-drive {} ({})", device.display(), fstype);
+            let drive = Self {
+                path: mount_point.to_path_buf(),
+                mounted: true,
+            };
+            let meta_path = mount_point.join("openstack/latest/meta_data.json");
+            if fs::metadata(&meta_path).await.is_ok() {
+                return Some(drive);
+            }
+            debug!("{} holds no OpenStack metadata", device.display());
+            drive.release(runner).await;
+            return None;
+        }
+        None
+    }
+
+    /// Unmount the drive if [`find`](Self::find) mounted it
+    async fn release(self, runner: &dyn SystemRunner) {
+        if !self.mounted {
+            return;
+        }
+        let umount = SystemCommand::new("umount").arg(self.path.to_string_lossy());
+        match runner.run(&umount).await {
+            Ok(output) if output.is_success() => {}
+            Ok(output) => warn!(
+                "Failed to unmount config-drive {}: {}",
+                self.path.display(),
+                output.stderr.trim()
+            ),
+            Err(e) => warn!(
+                "Failed to unmount config-drive {}: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
 }
 
 /// OpenStack datasource
 pub struct OpenStack {
     client: Client,
-    metadata_url: String,
+    endpoint: MetadataEndpoint,
 }
 
 impl OpenStack {
     pub fn new() -> Self {
-        let client = Client::builder()
+        let client = crate::http::metadata_client_builder()
             .timeout(Duration::from_secs(5))
             .connect_timeout(Duration::from_secs(2))
             .build()
@@ -59,13 +378,13 @@ impl OpenStack {
 
         Self {
             client,
-            metadata_url: OPENSTACK_METADATA_URL.to_string(),
+            endpoint: MetadataEndpoint::new("OpenStack", &[OPENSTACK_METADATA_URL]),
         }
     }
 
     /// Create with a custom base URL (for testing)
     pub fn with_base_url(base_url: &str) -> Self {
-        let client = Client::builder()
+        let client = crate::http::metadata_client_builder()
             .timeout(Duration::from_secs(5))
             .connect_timeout(Duration::from_secs(2))
             .build()
@@ -73,16 +392,18 @@ impl OpenStack {
 
         Self {
             client,
-            metadata_url: base_url.to_string(),
+            endpoint: MetadataEndpoint::fixed(base_url),
         }
     }
 
-    /// Find config-drive mount point
+    /// Find config-drive contents, seeded into the image or mounted
     async fn find_config_drive() -> Option<PathBuf> {
-        for path in CONFIG_DRIVE_PATHS {
-            let meta_path = Path::new(path).join("openstack/latest/meta_data.json");
+        let seed = CloudPaths::new().datasource_seed_dir(CONFIG_DRIVE_SEED);
+        let mounts = CONFIG_DRIVE_PATHS.iter().map(PathBuf::from);
+        for path in std::iter::once(seed).chain(mounts) {
+            let meta_path = path.join("openstack/latest/meta_data.json");
             if fs::metadata(&meta_path).await.is_ok() {
-                return Some(PathBuf::from(path));
+                return Some(path);
             }
         }
         None
@@ -90,19 +411,27 @@ impl OpenStack {
 
     /// Check if OpenStack metadata service is reachable
     async fn check_metadata_service(&self) -> bool {
-        let url = format!("{}/latest/meta_data.json", self.metadata_url);
+        let url = format!(
+            "{}/latest/meta_data.json",
+            self.endpoint.url(&self.client).await
+        );
         self.client.get(&url).send().await.is_ok()
     }
 
     /// Fetch metadata from HTTP service
     async fn fetch_metadata_http(&self) -> Result<OpenStackMetadata, CloudInitError> {
-        let url = format!("{}/latest/meta_data.json", self.metadata_url);
+        let url = format!(
+            "{}/latest/meta_data.json",
+            self.endpoint.url(&self.client).await
+        );
         debug!("Fetching OpenStack metadata from HTTP: {}", url);
 
         let response = self.client.get(&url).send().await?;
 
         if response.status().is_success() {
-            let metadata: OpenStackMetadata = response.json().await?;
+            let raw: serde_json::Value = response.json().await?;
+            let mut metadata: OpenStackMetadata = serde_json::from_value(raw.clone())?;
+            metadata.raw = raw;
             Ok(metadata)
         } else {
             Err(CloudInitError::Datasource(format!(
@@ -126,16 +455,20 @@ impl OpenStack {
             CloudInitError::Datasource(format!("Failed to read config-drive metadata: {}", e))
         })?;
 
-        let metadata: OpenStackMetadata = serde_json::from_str(&content).map_err(|e| {
+        let raw: serde_json::Value = serde_json::from_str(&content).map_err(|e| {
             CloudInitError::Datasource(format!("Failed to parse config-drive metadata: {}", e))
         })?;
+        let mut metadata: OpenStackMetadata = serde_json::from_value(raw.clone()).map_err(|e| {
+            CloudInitError::Datasource(format!("Failed to parse config-drive metadata: {}", e))
+        })?;
+        metadata.raw = raw;
 
         Ok(metadata)
     }
 
     /// Fetch user-data from HTTP service
     async fn fetch_userdata_http(&self) -> Result<Option<String>, CloudInitError> {
-        let url = format!("{}/latest/user_data", self.metadata_url);
+        let url = format!("{}/latest/user_data", self.endpoint.url(&self.client).await);
         debug!("Fetching OpenStack user-data from HTTP: {}", url);
 
         let response = self.client.get(&url).send().await?;
@@ -145,7 +478,10 @@ impl OpenStack {
         }
 
         if response.status().is_success() {
-            let content = response.text().await?;
+            let limit = crate::userdata::limits::current().max_size();
+            let body = crate::userdata::limits::read_body(response, "User-data", limit, "max_size")
+                .await?;
+            let content = String::from_utf8_lossy(&body).into_owned();
             if content.is_empty() {
                 Ok(None)
             } else {
@@ -166,35 +502,42 @@ impl OpenStack {
             userdata_path
         );
 
-        match fs::read_to_string(&userdata_path).await {
-            Ok(content) if !content.is_empty() => Ok(Some(content)),
+        match crate::userdata::limits::read_file(&userdata_path).await {
+            Ok(content) if !content.is_empty() => {
+                Ok(Some(String::from_utf8_lossy(&content).into_owned()))
+            }
+            Err(e @ CloudInitError::InvalidData(_)) => Err(e),
             _ => Ok(None),
         }
     }
 
-    /// Check DMI data for OpenStack indicators
-    async fn check_dmi_data() -> bool {
-        let dmi_paths = [
-            "/sys/class/dmi/id/product_name",
-            "/sys/class/dmi/id/sys_vendor",
-            "/sys/class/dmi/id/chassis_vendor",
-        ];
-
-        for path in &dmi_paths {
-            if let Ok(content) = tokio::fs::read_to_string(path).await {
-                let content = content.to_lowercase();
-                if content.contains("openstack")
-                    || content.contains("bochs")
-                    || content.contains("qemu")
-                    || content.contains("kvm")
-                    || content.contains("rhev")
-                {
-                    return true;
-                }
-            }
+    /// Fetch network_data.json from HTTP service
+    async fn fetch_network_data_http(&self) -> Result<Option<NetworkData>, CloudInitError> {
+        let url = format!(
+            "{}/latest/network_data.json",
+            self.endpoint.url(&self.client).await
+        );
+        debug!("Fetching OpenStack network data from HTTP: {}", url);
+
+        let response = self.client.get(&url).send().await?;
+        if !response.status().is_success() {
+            return Ok(None);
         }
+        Ok(Some(response.json().await?))
+    }
+
+    /// Fetch network_data.json from config-drive
+    async fn fetch_network_data_config_drive(
+        config_drive: &Path,
+    ) -> Result<Option<NetworkData>, CloudInitError> {
+        let path = config_drive.join("openstack/latest/network_data.json");
+        let Ok(content) = fs::read_to_string(&path).await else {
+            return Ok(None);
+        };
 
-        false
+        serde_json::from_str(&content).map(Some).map_err(|e| {
+            CloudInitError::Datasource(format!("Failed to parse network_data.json: {}", e))
+        })
     }
 }
 
@@ -210,14 +553,27 @@ impl Datasource for OpenStack {
         "OpenStack"
     }
 
+    fn seed_names(&self) -> &'static [&'static str] {
+        &[CONFIG_DRIVE_SEED]
+    }
+
+    /// Nova's instance UUID is also the system UUID
+    async fn check_instance_id(&self, cached_id: &str) -> Option<bool> {
+        DmiInfo::read().await.uuid_matches(cached_id)
+    }
+
     async fn is_available(&self) -> bool {
         // Check for config-drive first (no network needed)
-        if Self::find_config_drive().await.is_some() {
+        if let Some(drive) = ConfigDrive::find(&HostRunner).await {
+            drive.release(&HostRunner).await;
             return true;
         }
 
         // Check DMI data
-        if Self::check_dmi_data().await {
+        if matches!(
+            Platform::detect().await,
+            Platform::OpenStack | Platform::Kvm
+        ) {
             return self.check_metadata_service().await;
         }
 
@@ -229,8 +585,10 @@ impl Datasource for OpenStack {
         debug!("Fetching OpenStack instance metadata");
 
         // Try config-drive first, then HTTP
-        let os_meta = if let Some(config_drive) = Self::find_config_drive().await {
-            Self::fetch_metadata_config_drive(&config_drive).await?
+        let os_meta = if let Some(drive) = ConfigDrive::find(&HostRunner).await {
+            let os_meta = Self::fetch_metadata_config_drive(&drive.path).await;
+            drive.release(&HostRunner).await;
+            os_meta?
         } else {
             self.fetch_metadata_http().await?
         };
@@ -258,6 +616,7 @@ impl Datasource for OpenStack {
                 metadata.region = Some(os_meta.availability_zone[..idx].to_string());
             }
         }
+        metadata.raw = os_meta.raw;
 
         Ok(metadata)
     }
@@ -266,8 +625,10 @@ impl Datasource for OpenStack {
         debug!("Fetching OpenStack user-data");
 
         // Try config-drive first, then HTTP
-        let content = if let Some(config_drive) = Self::find_config_drive().await {
-            Self::fetch_userdata_config_drive(&config_drive).await?
+        let content = if let Some(drive) = ConfigDrive::find(&HostRunner).await {
+            let content = Self::fetch_userdata_config_drive(&drive.path).await;
+            drive.release(&HostRunner).await;
+            content?
         } else {
             self.fetch_userdata_http().await?
         };
@@ -293,18 +654,33 @@ impl Datasource for OpenStack {
             }
         }
     }
+
+    async fn get_network_config(&self) -> Result<Option<NetworkConfig>, CloudInitError> {
+        let data = if let Some(drive) = ConfigDrive::find(&HostRunner).await {
+            let data = Self::fetch_network_data_config_drive(&drive.path).await;
+            drive.release(&HostRunner).await;
+            data?
+        } else {
+            self.fetch_network_data_http().await?
+        };
+
+        Ok(data
+            .map(|d| d.to_network_config())
+            .filter(|c| c.has_interfaces()))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
     use tempfile::TempDir;
 
     #[test]
     fn test_openstack_default() {
         let openstack = OpenStack::new();
         assert_eq!(openstack.name(), "OpenStack");
-        assert_eq!(openstack.metadata_url, OPENSTACK_METADATA_URL);
+        assert_eq!(openstack.endpoint.defaults(), [OPENSTACK_METADATA_URL]);
     }
 
     fn create_config_drive(temp: &TempDir) -> PathBuf {
@@ -337,6 +713,7 @@ mod tests {
         let result = OpenStack::fetch_metadata_config_drive(&cd).await.unwrap();
         assert_eq!(result.uuid, "cd-uuid-123");
         assert_eq!(result.hostname, "cd-host");
+        assert_eq!(result.raw, metadata_json);
     }
 
     #[tokio::test]
@@ -362,6 +739,68 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn test_mount_labeled_config_drive() {
+        let temp = TempDir::new().unwrap();
+        let by_label = temp.path().join("by-label");
+        std::fs::create_dir_all(&by_label).unwrap();
+        std::fs::write(by_label.join("CONFIG-2"), "").unwrap();
+        // Stands in for the mounted vfat filesystem
+        let mount_point = create_config_drive(&temp);
+        std::fs::write(mount_point.join("openstack/latest/meta_data.json"), "{}").unwrap();
+
+        let device = by_label.join("CONFIG-2");
+        let mount = |fstype: &str| {
+            format!(
+                "mount -o ro -t {} {} {}",
+                fstype,
+                device.display(),
+                mount_point.display()
+            )
+        };
+        let runner = RecordingRunner::new().with_response(
+            &mount("iso9660"),
+            CommandOutput::failure(32, "wrong fs type"),
+        );
+        let drive = ConfigDrive::mount_labeled(&runner, &by_label, &mount_point)
+            .await
+            .unwrap();
+        assert_eq!(drive.path, mount_point);
+        assert_eq!(runner.commands(), vec![mount("iso9660"), mount("vfat")]);
+
+        drive.release(&runner).await;
+        assert_eq!(
+            runner.commands().last().unwrap(),
+            &format!("umount {}", mount_point.display())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mount_labeled_without_metadata_unmounts() {
+        let temp = TempDir::new().unwrap();
+        let by_label = temp.path().join("by-label");
+        std::fs::create_dir_all(&by_label).unwrap();
+        let mount_point = temp.path().join("mnt");
+
+        // No labeled device: nothing is mounted
+        let runner = RecordingRunner::new();
+        assert!(
+            ConfigDrive::mount_labeled(&runner, &by_label, &mount_point)
+                .await
+                .is_none()
+        );
+        assert!(runner.commands().is_empty());
+
+        std::fs::write(by_label.join("config-2"), "").unwrap();
+        assert!(
+            ConfigDrive::mount_labeled(&runner, &by_label, &mount_point)
+                .await
+                .is_none()
+        );
+        assert_eq!(runner.commands().len(), 2);
+        assert!(runner.commands()[1].starts_with("umount "));
+    }
+
     #[tokio::test]
     async fn test_fetch_userdata_config_drive() {
         let temp = TempDir::new().unwrap();
@@ -401,4 +840,130 @@ mod tests {
         let result = OpenStack::fetch_userdata_config_drive(&cd).await.unwrap();
         assert!(result.is_none());
     }
+
+    #[tokio::test]
+    async fn test_network_data_config_drive() {
+        let temp = TempDir::new().unwrap();
+        let cd = create_config_drive(&temp);
+
+        let network_data = serde_json::json!({
+            "links": [
+                {"id": "tap1", "type": "phy", "ethernet_mac_address": "FA:16:3E:00:00:01", "mtu": 1450},
+                {"id": "tap2", "type": "phy", "ethernet_mac_address": "fa:16:3e:00:00:02"},
+                {"id": "tap3", "type": "phy", "ethernet_mac_address": "fa:16:3e:00:00:03"},
+                {"id": "bond0", "type": "bond", "bond_links": ["tap2", "tap3"], "bond_mode": "802.3ad"},
+                {"id": "vlan101", "type": "vlan", "vlan_link": "bond0", "vlan_id": 101}
+            ],
+            "networks": [
+                {
+                    "id": "net0", "type": "ipv4", "link": "tap1",
+                    "ip_address": "10.0.0.5", "netmask": "255.255.255.0",
+                    "routes": [
+                        {"network": "0.0.0.0", "netmask": "0.0.0.0", "gateway": "10.0.0.1"},
+                        {"network": "192.168.0.0", "netmask": "255.255.0.0", "gateway": "10.0.0.254"}
+                    ]
+                },
+                {"id": "net1", "type": "ipv6_slaac", "link": "tap1"},
+                {"id": "net2", "type": "ipv4_dhcp", "link": "vlan101"}
+            ],
+            "services": [{"type": "dns", "address": "10.0.0.2"}]
+        });
+        fs::write(
+            cd.join("openstack/latest/network_data.json"),
+            network_data.to_string(),
+        )
+        .await
+        .unwrap();
+
+        let data = OpenStack::fetch_network_data_config_drive(&cd)
+            .await
+            .unwrap()
+            .unwrap();
+        let config = data.to_network_config();
+
+        let tap1 = &config.ethernets["tap1"];
+        assert_eq!(
+            tap1.match_config.as_ref().unwrap().macaddress.as_deref(),
+            Some("fa:16:3e:00:00:01")
+        );
+        assert_eq!(tap1.common.mtu, Some(1450));
+        assert_eq!(tap1.common.addresses, vec!["10.0.0.5/24"]);
+        assert_eq!(tap1.common.gateway4.as_deref(), Some("10.0.0.1"));
+        assert_eq!(tap1.common.routes[0].to, "192.168.0.0/16");
+        assert_eq!(tap1.common.accept_ra, Some(true));
+        assert_eq!(tap1.common.nameservers.addresses, vec!["10.0.0.2"]);
+
+        let bond = &config.bonds["bond0"];
+        assert_eq!(bond.interfaces, vec!["tap2", "tap3"]);
+        assert_eq!(
+            bond.parameters.as_ref().unwrap().mode.as_deref(),
+            Some("802.3ad")
+        );
+
+        let vlan = &config.vlans["vlan101"];
+        assert_eq!((vlan.id, vlan.link.as_str()), (101, "bond0"));
+        assert_eq!(vlan.common.dhcp4, Some(true));
+
+        assert!(crate::network::validate::check(&config).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_network_data_ipv6_only() {
+        let temp = TempDir::new().unwrap();
+        let cd = create_config_drive(&temp);
+
+        let network_data = serde_json::json!({
+            "links": [
+                {"id": "tap1", "type": "phy", "ethernet_mac_address": "fa:16:3e:00:00:01"},
+                {"id": "tap2", "type": "phy", "ethernet_mac_address": "fa:16:3e:00:00:02"}
+            ],
+            "networks": [
+                {
+                    "id": "net0", "type": "ipv6", "link": "tap1",
+                    "ip_address": "2001:db8::10", "netmask": "ffff:ffff:ffff:ffff::",
+                    "routes": [
+                        {"network": "::", "netmask": "::", "gateway": "2001:db8::1"},
+                        {"network": "2001:db8:1::", "netmask": "ffff:ffff:ffff::", "gateway": "2001:db8::fe"}
+                    ]
+                },
+                {"id": "net1", "type": "ipv6_dhcpv6-stateless", "link": "tap2"}
+            ],
+            "services": [{"type": "dns", "address": "2001:db8::53"}]
+        });
+        fs::write(
+            cd.join("openstack/latest/network_data.json"),
+            network_data.to_string(),
+        )
+        .await
+        .unwrap();
+
+        let config = OpenStack::fetch_network_data_config_drive(&cd)
+            .await
+            .unwrap()
+            .unwrap()
+            .to_network_config();
+
+        let tap1 = &config.ethernets["tap1"].common;
+        assert_eq!(tap1.addresses, vec!["2001:db8::10/64"]);
+        assert_eq!(tap1.gateway6.as_deref(), Some("2001:db8::1"));
+        assert_eq!(tap1.routes[0].to, "2001:db8:1::/48");
+        assert_eq!(tap1.nameservers.addresses, vec!["2001:db8::53"]);
+        assert!(tap1.is_ipv6_only());
+
+        let tap2 = &config.ethernets["tap2"].common;
+        assert!(tap2.dhcp6_stateless());
+        assert_eq!(tap2.accept_ra, Some(true));
+
+        assert!(crate::network::validate::check(&config).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_network_data_config_drive_missing() {
+        let temp = TempDir::new().unwrap();
+        let cd = create_config_drive(&temp);
+        let result = OpenStack::fetch_network_data_config_drive(&cd)
+            .await
+            .unwrap();
+        assert!(result.is_none());
+    }
 }
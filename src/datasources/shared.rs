@@ -0,0 +1,192 @@
+//! Datasource shared between stages
+//!
+//! Each stage normally runs in its own process, so the datasource is
+//! detected and its data fetched again by every stage that needs it. The
+//! daemon (see [`crate::daemon`]) runs all stages in one process and calls
+//! [`enable`]: from then on the first datasource detected is kept, and its
+//! metadata, user data, vendor data and network config are fetched at
+//! most once. Failed fetches are not kept, so a later stage retries them.
+
+use super::Datasource;
+use crate::network::NetworkConfig;
+use crate::{CloudInitError, InstanceMetadata, UserData};
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::OnceCell;
+
+static SESSION: OnceLock<Mutex<Option<SharedDatasource>>> = OnceLock::new();
+
+/// Keep the datasource detected from now on for the rest of the process
+pub fn enable() {
+    SESSION.get_or_init(Default::default);
+}
+
+/// Whether [`enable`] was called
+pub fn is_enabled() -> bool {
+    SESSION.get().is_some()
+}
+
+/// The kept datasource, if sharing is enabled and one was detected
+pub fn get() -> Option<Box<dyn Datasource>> {
+    let session = SESSION.get()?.lock().unwrap_or_else(|e| e.into_inner());
+    session
+        .clone()
+        .map(|ds| Box::new(ds) as Box<dyn Datasource>)
+}
+
+/// Keep `ds` if sharing is enabled, returning a handle to the kept one
+///
+/// Without sharing `ds` is returned unchanged.
+pub fn keep(ds: Box<dyn Datasource>) -> Box<dyn Datasource> {
+    let Some(session) = SESSION.get() else {
+        return ds;
+    };
+    let shared = SharedDatasource::new(ds);
+    *session.lock().unwrap_or_else(|e| e.into_inner()) = Some(shared.clone());
+    Box::new(shared)
+}
+
+/// A datasource that fetches each piece of data once
+///
+/// Cloning gives another handle to the same datasource and answers.
+#[derive(Clone)]
+pub struct SharedDatasource {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    ds: Box<dyn Datasource>,
+    metadata: OnceCell<InstanceMetadata>,
+    userdata: OnceCell<UserData>,
+    vendordata: OnceCell<Option<UserData>>,
+    network: OnceCell<Option<NetworkConfig>>,
+}
+
+impl SharedDatasource {
+    pub fn new(ds: Box<dyn Datasource>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                ds,
+                metadata: OnceCell::new(),
+                userdata: OnceCell::new(),
+                vendordata: OnceCell::new(),
+                network: OnceCell::new(),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl Datasource for SharedDatasource {
+    fn name(&self) -> &'static str {
+        self.inner.ds.name()
+    }
+
+    async fn is_available(&self) -> bool {
+        self.inner.ds.is_available().await
+    }
+
+    async fn get_metadata(&self) -> Result<InstanceMetadata, CloudInitError> {
+        let inner = &self.inner;
+        inner
+            .metadata
+            .get_or_try_init(|| inner.ds.get_metadata())
+            .await
+            .cloned()
+    }
+
+    async fn get_userdata(&self) -> Result<UserData, CloudInitError> {
+        let inner = &self.inner;
+        inner
+            .userdata
+            .get_or_try_init(|| inner.ds.get_userdata())
+            .await
+            .cloned()
+    }
+
+    async fn get_vendordata(&self) -> Result<Option<UserData>, CloudInitError> {
+        let inner = &self.inner;
+        inner
+            .vendordata
+            .get_or_try_init(|| inner.ds.get_vendordata())
+            .await
+            .cloned()
+    }
+
+    async fn get_network_config(&self) -> Result<Option<NetworkConfig>, CloudInitError> {
+        let inner = &self.inner;
+        inner
+            .network
+            .get_or_try_init(|| inner.ds.get_network_config())
+            .await
+            .cloned()
+    }
+
+    fn seed_names(&self) -> &'static [&'static str] {
+        self.inner.ds.seed_names()
+    }
+
+    async fn check_instance_id(&self, cached_id: &str) -> Option<bool> {
+        self.inner.ds.check_instance_id(cached_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasources::mock::MockDatasource;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts metadata fetches
+    #[derive(Default)]
+    struct Counting {
+        fetches: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Datasource for Counting {
+        fn name(&self) -> &'static str {
+            "Counting"
+        }
+
+        async fn is_available(&self) -> bool {
+            true
+        }
+
+        async fn get_metadata(&self) -> Result<InstanceMetadata, CloudInitError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            Ok(InstanceMetadata {
+                instance_id: Some("i-1".to_string()),
+                ..Default::default()
+            })
+        }
+
+        async fn get_userdata(&self) -> Result<UserData, CloudInitError> {
+            Ok(UserData::None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetches_once_across_handles() {
+        let counting = Counting::default();
+        let fetches = counting.fetches.clone();
+        let shared = SharedDatasource::new(Box::new(counting));
+        let other = shared.clone();
+
+        let first = shared.get_metadata().await.unwrap();
+        let second = other.get_metadata().await.unwrap();
+        assert_eq!(first.instance_id.as_deref(), Some("i-1"));
+        assert_eq!(second.instance_id, first.instance_id);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert_eq!(other.name(), "Counting");
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_not_kept() {
+        let shared =
+            SharedDatasource::new(Box::new(MockDatasource::new().with_metadata_error("down")));
+        assert!(shared.get_metadata().await.is_err());
+        assert!(shared.get_metadata().await.is_err());
+        assert!(shared.inner.metadata.get().is_none());
+    }
+}
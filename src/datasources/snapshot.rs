@@ -0,0 +1,236 @@
+//! Datasource data fetched this boot
+//!
+//! Once the datasource has been crawled, its metadata, user data, vendor
+//! data and network config are written to
+//! `/run/cloud-init/datasource-snapshot.json`. Later stages run in their
+//! own processes; they load the snapshot instead of querying the
+//! datasource and parsing its user data again (see
+//! [`stage_datasource`](super::cache::stage_datasource)). Hotplug and
+//! queries still query the datasource, as the data may have changed.
+//!
+//! The file carries two checksums. `checksum` covers the snapshot itself,
+//! so a truncated or edited file is ignored. `source` covers the instance
+//! cache the snapshot was taken for (cached instance ID and datasource),
+//! so caching a new instance invalidates it. `/run` is emptied on reboot,
+//! so every boot fetches afresh.
+
+use super::cache::load_cached;
+use super::{COMPILED, Datasource};
+use crate::network::NetworkConfig;
+use crate::root::RootContext;
+use crate::state::CloudPaths;
+use crate::{CloudInitError, InstanceMetadata, UserData};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tracing::debug;
+
+/// Everything fetched from a datasource
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Datasource name, e.g. `NoCloud`
+    pub datasource: String,
+    pub metadata: InstanceMetadata,
+    pub userdata: UserData,
+    pub vendordata: Option<UserData>,
+    pub network_config: Option<NetworkConfig>,
+}
+
+/// On-disk form of a [`Snapshot`]
+#[derive(Serialize, Deserialize)]
+struct SnapshotFile {
+    /// SHA-256 of `snapshot`
+    checksum: String,
+    /// SHA-256 of the instance cache the snapshot belongs to
+    source: String,
+    snapshot: serde_json::Value,
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Checksum of the instance cache under `paths`, if there is one
+async fn source_checksum(paths: &CloudPaths) -> Option<String> {
+    let cached = load_cached(paths).await?;
+    Some(sha256_hex(
+        format!("{}\0{}", cached.instance_id, cached.datasource).as_bytes(),
+    ))
+}
+
+/// Write `snapshot` for the instance currently cached under `paths`
+///
+/// Call after the instance cache has been updated, so the snapshot is
+/// tied to the right instance.
+pub async fn save(paths: &CloudPaths, snapshot: &Snapshot) -> Result<(), CloudInitError> {
+    let source = source_checksum(paths)
+        .await
+        .ok_or_else(|| CloudInitError::InvalidData("no cached instance to snapshot".to_string()))?;
+    let value = serde_json::to_value(snapshot)?;
+    let file = SnapshotFile {
+        checksum: sha256_hex(value.to_string().as_bytes()),
+        source,
+        snapshot: value,
+    };
+
+    let root = &RootContext::current();
+    root.create_dir_all(&paths.run_dir()).await?;
+    // Carries the user data, so only root may read it
+    root.write_file_mode(
+        &paths.datasource_snapshot(),
+        serde_json::to_vec(&file)?,
+        crate::state::PRIVATE_FILE_MODE,
+    )
+    .await
+}
+
+/// Load the snapshot under `paths` if it is intact and current
+pub async fn load(paths: &CloudPaths) -> Option<Snapshot> {
+    let path = paths.datasource_snapshot();
+    let content = fs::read(&path).await.ok()?;
+    let file: SnapshotFile = match serde_json::from_slice(&content) {
+        Ok(file) => file,
+        Err(e) => {
+            debug!("Ignoring unreadable {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    if sha256_hex(file.snapshot.to_string().as_bytes()) != file.checksum {
+        debug!("Ignoring {}: checksum mismatch", path.display());
+        return None;
+    }
+    if source_checksum(paths).await.as_deref() != Some(file.source.as_str()) {
+        debug!("Ignoring {}: instance cache changed", path.display());
+        return None;
+    }
+    serde_json::from_value(file.snapshot).ok()
+}
+
+/// A datasource answering from a [`Snapshot`]
+pub struct SnapshotDatasource {
+    name: &'static str,
+    snapshot: Snapshot,
+}
+
+impl SnapshotDatasource {
+    /// Serve `snapshot`, if its datasource is compiled in
+    pub fn new(snapshot: Snapshot) -> Option<Self> {
+        let name = COMPILED
+            .iter()
+            .find(|name| name.eq_ignore_ascii_case(&snapshot.datasource))?;
+        Some(Self { name, snapshot })
+    }
+}
+
+/// The datasource from the snapshot under `paths`, if it is usable
+pub async fn load_datasource(paths: &CloudPaths) -> Option<Box<dyn Datasource>> {
+    let ds = SnapshotDatasource::new(load(paths).await?)?;
+    debug!("Using {} data fetched earlier this boot", ds.name);
+    Some(Box::new(ds))
+}
+
+#[async_trait]
+impl Datasource for SnapshotDatasource {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn is_available(&self) -> bool {
+        true
+    }
+
+    async fn get_metadata(&self) -> Result<InstanceMetadata, CloudInitError> {
+        Ok(self.snapshot.metadata.clone())
+    }
+
+    async fn get_userdata(&self) -> Result<UserData, CloudInitError> {
+        Ok(self.snapshot.userdata.clone())
+    }
+
+    async fn get_vendordata(&self) -> Result<Option<UserData>, CloudInitError> {
+        Ok(self.snapshot.vendordata.clone())
+    }
+
+    async fn get_network_config(&self) -> Result<Option<NetworkConfig>, CloudInitError> {
+        Ok(self.snapshot.network_config.clone())
+    }
+
+    async fn check_instance_id(&self, cached_id: &str) -> Option<bool> {
+        Some(self.snapshot.metadata.instance_id.as_deref() == Some(cached_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn cache(paths: &CloudPaths, instance_id: &str) {
+        std::fs::create_dir_all(paths.instance_dir(instance_id)).unwrap();
+        std::fs::create_dir_all(paths.data_dir()).unwrap();
+        std::fs::write(paths.cached_instance_id(), instance_id).unwrap();
+        std::fs::write(paths.datasource_file(instance_id), "NoCloud").unwrap();
+    }
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            datasource: "NoCloud".to_string(),
+            metadata: InstanceMetadata {
+                instance_id: Some("iid-1".to_string()),
+                local_hostname: Some("web-1".to_string()),
+                ..Default::default()
+            },
+            userdata: UserData::Script("#!/bin/sh\necho hi\n".to_string()),
+            vendordata: None,
+            network_config: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let temp = TempDir::new().unwrap();
+        let paths = CloudPaths::with_base(temp.path());
+        cache(&paths, "iid-1");
+        save(&paths, &snapshot()).await.unwrap();
+
+        let ds = load_datasource(&paths).await.unwrap();
+        assert_eq!(ds.name(), "NoCloud");
+        assert_eq!(ds.get_metadata().await.unwrap(), snapshot().metadata);
+        assert!(
+            matches!(ds.get_userdata().await.unwrap(), UserData::Script(s) if s.contains("echo hi"))
+        );
+        assert_eq!(ds.check_instance_id("iid-1").await, Some(true));
+        assert_eq!(ds.check_instance_id("iid-2").await, Some(false));
+    }
+
+    #[tokio::test]
+    async fn test_tampered_snapshot_ignored() {
+        let temp = TempDir::new().unwrap();
+        let paths = CloudPaths::with_base(temp.path());
+        cache(&paths, "iid-1");
+        save(&paths, &snapshot()).await.unwrap();
+
+        let path = paths.datasource_snapshot();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, content.replace("web-1", "web-2")).unwrap();
+        assert!(load(&paths).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_new_instance_invalidates() {
+        let temp = TempDir::new().unwrap();
+        let paths = CloudPaths::with_base(temp.path());
+        assert!(save(&paths, &snapshot()).await.is_err());
+
+        cache(&paths, "iid-1");
+        save(&paths, &snapshot()).await.unwrap();
+        assert!(load(&paths).await.is_some());
+
+        cache(&paths, "iid-2");
+        assert!(load(&paths).await.is_none());
+    }
+}
@@ -0,0 +1,64 @@
+//! Switching cloud-init off
+//!
+//! Like Python cloud-init, cloud-init-rs does nothing when
+//! `/etc/cloud/cloud-init.disabled` exists or the kernel command line
+//! contains `cloud-init=disabled`. Every stage then returns straight away
+//! and `status` reports `disabled`.
+
+use crate::root::RootContext;
+use crate::systemd::{KERNEL_CMDLINE, is_disabled_by_cmdline};
+use std::fmt;
+
+/// Marker file that disables cloud-init
+pub const DISABLED_FILE: &str = "/etc/cloud/cloud-init.disabled";
+
+/// What disabled cloud-init
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisabledBy {
+    /// [`DISABLED_FILE`] exists
+    MarkerFile,
+    /// `cloud-init=disabled` on the kernel command line
+    KernelCmdline,
+}
+
+impl fmt::Display for DisabledBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MarkerFile => write!(f, "disabled by marker file {}", DISABLED_FILE),
+            Self::KernelCmdline => write!(f, "disabled by kernel command line"),
+        }
+    }
+}
+
+/// Why cloud-init is disabled for the system at `root`, if it is
+///
+/// The kernel command line is only consulted for the running system.
+pub async fn check(root: &RootContext) -> Option<DisabledBy> {
+    if root.path(DISABLED_FILE).exists() {
+        return Some(DisabledBy::MarkerFile);
+    }
+    if root.is_host()
+        && let Ok(cmdline) = tokio::fs::read_to_string(KERNEL_CMDLINE).await
+        && is_disabled_by_cmdline(&cmdline)
+    {
+        return Some(DisabledBy::KernelCmdline);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_marker_file_disables() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        assert_eq!(check(&root).await, None);
+
+        std::fs::create_dir_all(root.path("/etc/cloud")).unwrap();
+        std::fs::write(root.path(DISABLED_FILE), "").unwrap();
+        assert_eq!(check(&root).await, Some(DisabledBy::MarkerFile));
+    }
+}
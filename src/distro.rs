@@ -0,0 +1,315 @@
+//! Distribution detection and per-distribution behavior
+//!
+//! Modules used to guess at the system they run on: probing for package
+//! managers, trying both ssh unit names, parsing os-release for locale
+//! handling. [`Distro`] reads `/etc/os-release` (or `/usr/lib/os-release`)
+//! once and answers those questions by family:
+//!
+//! | Family  | Package manager | Sudo group | SSH unit | Locale           | Default user |
+//! |---------|-----------------|------------|----------|------------------|--------------|
+//! | Debian  | apt-get         | sudo       | ssh      | locale.gen       | ubuntu/debian |
+//! | RedHat  | dnf (yum < 8)   | wheel      | sshd     | locale.conf      | fedora/cloud-user |
+//! | Suse    | zypper          | wheel      | sshd     | locale.conf      | opensuse/sles |
+//! | Alpine  | apk             | wheel      | sshd     | (none)           | alpine       |
+//! | FreeBSD | pkg             | wheel      | sshd     | (none)           | freebsd      |
+//!
+//! Unknown distributions get `None` or the generic answer, and callers
+//! fall back to probing the system as before.
+
+use crate::network::render::RendererType;
+use crate::root::RootContext;
+use tokio::fs;
+
+/// Locations of os-release, in lookup order; FreeBSD generates it in
+/// /var/run at boot
+const OS_RELEASE_PATHS: &[&str] = &[
+    "/etc/os-release",
+    "/usr/lib/os-release",
+    "/var/run/os-release",
+];
+
+/// Distribution family, from os-release `ID` and `ID_LIKE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DistroFamily {
+    Debian,
+    RedHat,
+    Suse,
+    Alpine,
+    FreeBsd,
+    Other,
+}
+
+/// How the system locale is configured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocaleMechanism {
+    /// Enable in `/etc/locale.gen`, run `locale-gen`, write `/etc/default/locale`
+    LocaleGen,
+    /// `localectl`, or `/etc/locale.conf`
+    LocaleConf,
+    /// Not known; try everything
+    Unknown,
+}
+
+/// The distribution of a system, as described by its os-release
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Distro {
+    /// `ID`, e.g. `ubuntu`
+    pub id: String,
+    /// `ID_LIKE`, e.g. `["rhel", "centos", "fedora"]`
+    pub id_like: Vec<String>,
+    /// `VERSION_ID`, e.g. `24.04`
+    pub version_id: String,
+    /// `VERSION_CODENAME`, e.g. `noble`
+    pub version_codename: String,
+    /// `VARIANT_ID`
+    pub variant_id: String,
+}
+
+impl Distro {
+    /// The distribution installed under `root`
+    pub async fn detect(root: &RootContext) -> Self {
+        for path in OS_RELEASE_PATHS {
+            if let Ok(content) = fs::read_to_string(root.path(path)).await {
+                return Self::from_os_release(&content);
+            }
+        }
+        Self::default()
+    }
+
+    /// Parse os-release content
+    pub fn from_os_release(content: &str) -> Self {
+        let mut distro = Self::default();
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches(['"', '\'']);
+            match key.trim() {
+                "ID" => distro.id = value.to_ascii_lowercase(),
+                "ID_LIKE" => {
+                    distro.id_like = value
+                        .split_whitespace()
+                        .map(str::to_ascii_lowercase)
+                        .collect()
+                }
+                "VERSION_ID" => distro.version_id = value.to_string(),
+                "VERSION_CODENAME" => distro.version_codename = value.to_string(),
+                "VARIANT_ID" => distro.variant_id = value.to_string(),
+                _ => {}
+            }
+        }
+        distro
+    }
+
+    /// `ID` followed by `ID_LIKE`
+    fn ids(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.id.as_str()).chain(self.id_like.iter().map(String::as_str))
+    }
+
+    pub fn family(&self) -> DistroFamily {
+        for id in self.ids() {
+            match id {
+                "debian" | "ubuntu" => return DistroFamily::Debian,
+                "rhel" | "fedora" | "centos" | "rocky" | "almalinux" | "ol" | "amzn" => {
+                    return DistroFamily::RedHat;
+                }
+                "suse" | "opensuse" | "sles" | "sle-micro" => return DistroFamily::Suse,
+                "alpine" => return DistroFamily::Alpine,
+                "freebsd" => return DistroFamily::FreeBsd,
+                id if id.starts_with("opensuse") => return DistroFamily::Suse,
+                _ => {}
+            }
+        }
+        DistroFamily::Other
+    }
+
+    /// Major `VERSION_ID`, e.g. `8` for `8.9`
+    fn major_version(&self) -> Option<u32> {
+        self.version_id.split('.').next()?.parse().ok()
+    }
+
+    /// Command of the native package manager
+    pub fn package_manager(&self) -> Option<&'static str> {
+        match self.family() {
+            DistroFamily::Debian => Some("apt-get"),
+            // RHEL/CentOS 7 and Amazon Linux 2 predate dnf
+            DistroFamily::RedHat
+                if self.id != "fedora" && self.major_version().is_some_and(|v| v < 8) =>
+            {
+                Some("yum")
+            }
+            DistroFamily::RedHat => Some("dnf"),
+            DistroFamily::Suse => Some("zypper"),
+            DistroFamily::Alpine => Some("apk"),
+            DistroFamily::FreeBsd => Some("pkg"),
+            DistroFamily::Other => None,
+        }
+    }
+
+    /// Group whose members may use sudo
+    pub fn sudo_group(&self) -> &'static str {
+        match self.family() {
+            DistroFamily::Debian => "sudo",
+            _ => "wheel",
+        }
+    }
+
+    /// Name of the OpenSSH server's service
+    pub fn ssh_service(&self) -> &'static str {
+        match self.family() {
+            DistroFamily::Debian => "ssh",
+            _ => "sshd",
+        }
+    }
+
+    pub fn locale_mechanism(&self) -> LocaleMechanism {
+        match self.family() {
+            DistroFamily::Debian => LocaleMechanism::LocaleGen,
+            DistroFamily::RedHat | DistroFamily::Suse => LocaleMechanism::LocaleConf,
+            DistroFamily::Alpine | DistroFamily::FreeBsd | DistroFamily::Other => {
+                LocaleMechanism::Unknown
+            }
+        }
+    }
+
+    /// Network renderers in order of preference
+    pub fn renderer_preference(&self) -> &'static [RendererType] {
+        use RendererType::{Eni, NetworkManager, Networkd, Sysconfig};
+        match self.family() {
+            DistroFamily::Debian if self.id == "ubuntu" => &[Networkd, NetworkManager, Eni],
+            DistroFamily::Debian | DistroFamily::Alpine => &[Eni, Networkd, NetworkManager],
+            // RHEL/CentOS 7 images configure the network with network-scripts
+            DistroFamily::RedHat
+                if self.id != "fedora" && self.major_version().is_some_and(|v| v < 8) =>
+            {
+                &[Sysconfig, NetworkManager, Networkd, Eni]
+            }
+            DistroFamily::RedHat => &[NetworkManager, Networkd, Sysconfig, Eni],
+            DistroFamily::Suse => &[Sysconfig, NetworkManager, Networkd, Eni],
+            #[cfg(feature = "freebsd")]
+            DistroFamily::FreeBsd => &[RendererType::RcConf],
+            #[cfg(not(feature = "freebsd"))]
+            DistroFamily::FreeBsd => &[],
+            DistroFamily::Other => &[Networkd, NetworkManager, Eni],
+        }
+    }
+
+    /// Drop-in directory for sudo rules; ports install sudo under
+    /// /usr/local
+    pub fn sudoers_dir(&self) -> &'static str {
+        match self.family() {
+            DistroFamily::FreeBsd => "/usr/local/etc/sudoers.d",
+            _ => "/etc/sudoers.d",
+        }
+    }
+
+    /// Login shell of the default user
+    pub fn default_shell(&self) -> &'static str {
+        match self.family() {
+            DistroFamily::FreeBsd => "/bin/sh",
+            _ => "/bin/bash",
+        }
+    }
+
+    /// Name of the user that `users: [default]` creates
+    pub fn default_user(&self) -> &'static str {
+        match self.id.as_str() {
+            "ubuntu" => "ubuntu",
+            "debian" => "debian",
+            "fedora" => "fedora",
+            "rocky" => "rocky",
+            "almalinux" => "almalinux",
+            "amzn" => "ec2-user",
+            "alpine" => "alpine",
+            "freebsd" => "freebsd",
+            "sles" | "sle-micro" => "sles",
+            id if id.starts_with("opensuse") => "opensuse",
+            _ => "cloud-user",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_family() {
+        let family = |content: &str| Distro::from_os_release(content).family();
+        assert_eq!(family("ID=ubuntu\nID_LIKE=debian\n"), DistroFamily::Debian);
+        assert_eq!(
+            family("ID=\"rocky\"\nID_LIKE=\"rhel centos fedora\"\n"),
+            DistroFamily::RedHat
+        );
+        assert_eq!(family("ID=fedora\n"), DistroFamily::RedHat);
+        assert_eq!(
+            family("ID=\"opensuse-leap\"\nID_LIKE=\"suse opensuse\"\n"),
+            DistroFamily::Suse
+        );
+        assert_eq!(family("ID=alpine\n"), DistroFamily::Alpine);
+        assert_eq!(family("ID=nixos\n"), DistroFamily::Other);
+        assert_eq!(family(""), DistroFamily::Other);
+    }
+
+    #[test]
+    fn test_behaviors() {
+        let ubuntu = Distro::from_os_release("ID=ubuntu\nID_LIKE=debian\nVERSION_ID=\"24.04\"\n");
+        assert_eq!(ubuntu.package_manager(), Some("apt-get"));
+        assert_eq!(ubuntu.sudo_group(), "sudo");
+        assert_eq!(ubuntu.ssh_service(), "ssh");
+        assert_eq!(ubuntu.locale_mechanism(), LocaleMechanism::LocaleGen);
+        assert_eq!(ubuntu.renderer_preference()[0], RendererType::Networkd);
+        assert_eq!(ubuntu.default_user(), "ubuntu");
+
+        let centos7 =
+            Distro::from_os_release("ID=\"centos\"\nID_LIKE=\"rhel fedora\"\nVERSION_ID=\"7\"\n");
+        assert_eq!(centos7.package_manager(), Some("yum"));
+        assert_eq!(centos7.sudo_group(), "wheel");
+        assert_eq!(centos7.ssh_service(), "sshd");
+        assert_eq!(centos7.default_user(), "cloud-user");
+        assert_eq!(centos7.renderer_preference()[0], RendererType::Sysconfig);
+
+        let rocky =
+            Distro::from_os_release("ID=rocky\nID_LIKE=\"rhel centos fedora\"\nVERSION_ID=9.3\n");
+        assert_eq!(rocky.package_manager(), Some("dnf"));
+        assert_eq!(rocky.renderer_preference()[0], RendererType::NetworkManager);
+
+        let amzn2 = Distro::from_os_release(
+            "ID=\"amzn\"\nID_LIKE=\"centos rhel fedora\"\nVERSION_ID=\"2\"\n",
+        );
+        assert_eq!(amzn2.package_manager(), Some("yum"));
+        assert_eq!(amzn2.default_user(), "ec2-user");
+
+        let sles = Distro::from_os_release("ID=\"sles\"\nID_LIKE=\"suse\"\nVERSION_ID=\"15.5\"\n");
+        assert_eq!(sles.package_manager(), Some("zypper"));
+        assert_eq!(sles.locale_mechanism(), LocaleMechanism::LocaleConf);
+
+        let freebsd = Distro::from_os_release("NAME=FreeBSD\nID=freebsd\nVERSION_ID=14.1\n");
+        assert_eq!(freebsd.family(), DistroFamily::FreeBsd);
+        assert_eq!(freebsd.package_manager(), Some("pkg"));
+        assert_eq!(freebsd.sudoers_dir(), "/usr/local/etc/sudoers.d");
+        assert_eq!(freebsd.default_user(), "freebsd");
+
+        let unknown = Distro::default();
+        assert_eq!(unknown.package_manager(), None);
+        assert_eq!(unknown.locale_mechanism(), LocaleMechanism::Unknown);
+    }
+
+    #[tokio::test]
+    async fn test_detect_falls_back_to_usr_lib() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        assert_eq!(Distro::detect(&root).await, Distro::default());
+
+        std::fs::create_dir_all(root.path("/usr/lib")).unwrap();
+        std::fs::write(
+            root.path("/usr/lib/os-release"),
+            "ID=debian\nVERSION_CODENAME=bookworm\n",
+        )
+        .unwrap();
+        let distro = Distro::detect(&root).await;
+        assert_eq!(distro.id, "debian");
+        assert_eq!(distro.version_codename, "bookworm");
+    }
+}
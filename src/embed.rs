@@ -0,0 +1,263 @@
+//! Embedding cloud-init-rs in another program
+//!
+//! [`CloudInit`] runs stages as a library call, with the options the
+//! binary takes on its command line:
+//!
+//! ```no_run
+//! use cloud_init_rs::{CloudInit, Stage};
+//!
+//! # async fn provision() -> Result<(), cloud_init_rs::CloudInitError> {
+//! let summary = CloudInit::builder()
+//!     .root("/mnt/image")
+//!     .datasource("NoCloud")
+//!     .deny_modules(["runcmd"])
+//!     .run(Stage::Config)
+//!     .await?;
+//! for error in &summary.module_errors {
+//!     eprintln!("{}", error);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Every option, the root filesystem and dry-run recorder included,
+//! belongs to the `CloudInit` it was built into. Its stages see them
+//! through [`CloudInit::scope`], so any number of `CloudInit`s, with
+//! different roots or one after another, can run in the same process.
+
+use crate::actions::{ActionRecorder, Plan};
+use crate::root::RootContext;
+use crate::stages::ModuleFilter;
+use crate::{CloudInitError, RunSummary, Stage, datasources, run_stages_with};
+use std::future::Future;
+use std::path::PathBuf;
+
+tokio::task_local! {
+    /// Settings of the `CloudInit` running on this task, see [`CloudInit::scope`]
+    static SCOPE: Scope;
+}
+
+/// Configured cloud-init-rs, ready to run stages
+#[derive(Debug)]
+pub struct CloudInit {
+    scope: Scope,
+    filter: ModuleFilter,
+}
+
+/// The settings a [`CloudInit`] puts in effect while it runs
+#[derive(Debug, Clone)]
+pub(crate) struct Scope {
+    pub(crate) root: RootContext,
+    /// Datasource detection is limited to
+    pub(crate) datasource: Option<&'static str>,
+    /// Skip network datasources and modules, see [`crate::offline`]
+    pub(crate) offline: bool,
+}
+
+impl Scope {
+    /// `f` applied to the enclosing [`CloudInit::scope`]'s settings, or
+    /// `None` outside one
+    pub(crate) fn with<R>(f: impl FnOnce(&Scope) -> R) -> Option<R> {
+        SCOPE.try_with(f).ok()
+    }
+}
+
+/// Options for a [`CloudInit`]
+#[derive(Debug, Default)]
+pub struct CloudInitBuilder {
+    root: Option<PathBuf>,
+    datasource: Option<String>,
+    filter: ModuleFilter,
+    dry_run: bool,
+    offline: bool,
+}
+
+impl CloudInit {
+    /// Start configuring; the defaults match running the binary with no
+    /// options
+    pub fn builder() -> CloudInitBuilder {
+        CloudInitBuilder::default()
+    }
+
+    /// Run one stage
+    pub async fn run(&self, stage: Stage) -> Result<RunSummary, CloudInitError> {
+        self.run_stages(&[stage]).await
+    }
+
+    /// Run `stages` in order, see [`crate::run_stages`]
+    pub async fn run_stages(&self, stages: &[Stage]) -> Result<RunSummary, CloudInitError> {
+        // Boxed: the stages make for a large future
+        self.scope(Box::pin(run_stages_with(stages, &self.filter)))
+            .await
+    }
+
+    /// Run `fut` with this configuration's root, datasource override and
+    /// offline mode, for library calls other than stages (e.g. queries)
+    ///
+    /// Only `fut` itself sees them, not tasks it spawns.
+    pub async fn scope<F: Future>(&self, fut: F) -> F::Output {
+        SCOPE.scope(self.scope.clone(), fut).await
+    }
+
+    /// The root filesystem stages operate on
+    pub fn root(&self) -> &RootContext {
+        &self.scope.root
+    }
+
+    /// The changes recorded so far, for a dry run
+    pub fn plan(&self) -> Option<Plan> {
+        self.scope.root.recorder().map(ActionRecorder::plan)
+    }
+}
+
+impl CloudInitBuilder {
+    /// Operate on the root filesystem mounted at `dir`
+    pub fn root(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.root = Some(dir.into());
+        self
+    }
+
+    /// Use datasource `name` (e.g. `NoCloud`, `EC2`) instead of detecting one
+    pub fn datasource(mut self, name: impl Into<String>) -> Self {
+        self.datasource = Some(name.into());
+        self
+    }
+
+    /// Only run the listed modules
+    pub fn allow_modules<I, S>(mut self, modules: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.filter.allow = Some(modules.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Never run the listed modules
+    pub fn deny_modules<I, S>(mut self, modules: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.filter.deny.extend(modules.into_iter().map(Into::into));
+        self
+    }
+
+    /// Record the changes stages would make instead of making them
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Skip network datasources and modules, see [`crate::offline`]
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Return the configured runner
+    ///
+    /// Fails if the root directory does not exist or the datasource is not
+    /// compiled in.
+    pub fn build(self) -> Result<CloudInit, CloudInitError> {
+        let mut root = match self.root {
+            Some(dir) if !dir.is_dir() => {
+                return Err(CloudInitError::Config(format!(
+                    "Root directory {} does not exist",
+                    dir.display()
+                )));
+            }
+            Some(dir) => RootContext::new(dir),
+            None => RootContext::host(),
+        };
+        if self.dry_run {
+            root = root.with_recorder(ActionRecorder::new());
+        }
+        let datasource = self
+            .datasource
+            .as_deref()
+            .map(datasources::compiled)
+            .transpose()?;
+        Ok(CloudInit {
+            scope: Scope {
+                root,
+                datasource,
+                offline: self.offline,
+            },
+            filter: self.filter,
+        })
+    }
+
+    /// Build and run one stage
+    pub async fn run(self, stage: Stage) -> Result<RunSummary, CloudInitError> {
+        self.build()?.run(stage).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_each_cloud_init_keeps_its_options() {
+        let first = tempfile::TempDir::new().unwrap();
+        let second = tempfile::TempDir::new().unwrap();
+        let offline = CloudInit::builder()
+            .root(first.path())
+            .dry_run(true)
+            .offline(true)
+            .build()
+            .unwrap();
+        let online = CloudInit::builder().root(second.path()).build().unwrap();
+
+        offline
+            .scope(async {
+                let root = RootContext::current();
+                assert_eq!(root.root(), first.path());
+                assert!(root.is_dry_run());
+                assert!(crate::offline::is_offline());
+            })
+            .await;
+        online
+            .scope(async {
+                let root = RootContext::current();
+                assert_eq!(root.root(), second.path());
+                assert!(!root.is_dry_run());
+                assert!(!crate::offline::is_offline());
+            })
+            .await;
+        assert!(RootContext::current().is_host());
+        assert!(!crate::offline::is_offline());
+    }
+
+    #[tokio::test]
+    async fn test_dry_runs_one_after_another() {
+        let temp = tempfile::TempDir::new().unwrap();
+        for _ in 0..2 {
+            let cloud_init = CloudInit::builder()
+                .root(temp.path())
+                .dry_run(true)
+                .offline(true)
+                .build()
+                .unwrap();
+            cloud_init.run(Stage::Config).await.unwrap();
+            assert!(cloud_init.plan().is_some());
+        }
+    }
+
+    #[test]
+    fn test_build_checks_root_and_datasource() {
+        let err = CloudInit::builder()
+            .root("/nonexistent/root")
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/root does not exist"));
+
+        let err = CloudInit::builder()
+            .datasource("NoSuchCloud")
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("Unknown datasource 'NoSuchCloud'"));
+        assert!(CloudInit::builder().datasource("nocloud").build().is_ok());
+    }
+}
@@ -1,7 +1,33 @@
 //! Error types for cloud-init-rs
+//!
+//! Errors carry enough context to act on from a log line alone: filesystem
+//! errors name the path ([`CloudInitError::File`], via [`IoContext`]) and
+//! module failures name the module ([`CloudInitError::in_module`]). Each
+//! error maps to a process exit code through [`CloudInitError::exit_code`].
 
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
+/// Process exit codes
+///
+/// 0 and 1 match Python cloud-init: success and fatal error. Failures of
+/// best-effort modules are only recorded in `status.json` and exit 0, so
+/// Python's 2 (recoverable errors) is never returned. Fatal errors from
+/// specific subsystems get their own codes so callers can tell them
+/// apart.
+pub mod exit_code {
+    /// Everything succeeded
+    pub const SUCCESS: u8 = 0;
+    /// Fatal error not covered by a more specific code
+    pub const FAILURE: u8 = 1;
+    /// Invalid configuration or user data
+    pub const CONFIG: u8 = 3;
+    /// No usable datasource, or metadata could not be fetched
+    pub const DATASOURCE: u8 = 4;
+    /// Filesystem or permission error
+    pub const IO: u8 = 5;
+}
+
 /// Main error type for cloud-init-rs operations
 #[derive(Error, Debug)]
 pub enum CloudInitError {
@@ -20,6 +46,13 @@ pub enum CloudInitError {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
+    #[error("IO error on {}: {source}", path.display())]
+    File {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
     #[error("YAML parsing error: {0}")]
     Yaml(#[from] serde_yaml::Error),
 
@@ -32,6 +65,13 @@ pub enum CloudInitError {
     #[error("Module error in '{module}': {message}")]
     Module { module: String, message: String },
 
+    #[error("Module '{module}' failed: {source}")]
+    InModule {
+        module: String,
+        #[source]
+        source: Box<CloudInitError>,
+    },
+
     #[error("Stage '{stage}' failed: {message}")]
     Stage { stage: String, message: String },
 
@@ -49,6 +89,10 @@ pub enum CloudInitError {
 
     #[error("Invalid data: {0}")]
     InvalidData(String),
+
+    /// A stage failure reported by the daemon, see [`crate::daemon`]
+    #[error("{message}")]
+    Daemon { message: String, exit_code: u8 },
 }
 
 impl CloudInitError {
@@ -67,4 +111,116 @@ impl CloudInitError {
             message: message.into(),
         }
     }
+
+    /// Attribute this error to `module`
+    ///
+    /// Errors that already name the module are returned unchanged.
+    pub fn in_module(self, module: &str) -> Self {
+        match &self {
+            Self::Module { module: m, .. } | Self::InModule { module: m, .. } if m == module => {
+                self
+            }
+            _ => Self::InModule {
+                module: module.to_string(),
+                source: Box::new(self),
+            },
+        }
+    }
+
+    /// Module this error is attributed to, if any
+    pub fn module_name(&self) -> Option<&str> {
+        match self {
+            Self::Module { module, .. } | Self::InModule { module, .. } => Some(module),
+            _ => None,
+        }
+    }
+
+    /// Exit code for a run that failed with this error
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Config(_) | Self::Yaml(_) | Self::Json(_) | Self::InvalidData(_) => {
+                exit_code::CONFIG
+            }
+            Self::Datasource(_)
+            | Self::NoDatasource
+            | Self::Network(_)
+            | Self::Http(_)
+            | Self::Timeout(_) => exit_code::DATASOURCE,
+            Self::Io(_) | Self::File { .. } | Self::Permission(_) => exit_code::IO,
+            Self::InModule { source, .. } => source.exit_code(),
+            Self::Daemon { exit_code, .. } => *exit_code,
+            Self::Module { .. } | Self::Stage { .. } | Self::UserGroup(_) | Self::Command(_) => {
+                exit_code::FAILURE
+            }
+        }
+    }
+}
+
+/// Attach the affected path to IO errors
+pub trait IoContext<T> {
+    /// Turn an IO error into [`CloudInitError::File`] naming `path`
+    fn with_path(self, path: impl AsRef<Path>) -> Result<T, CloudInitError>;
+}
+
+impl<T> IoContext<T> for Result<T, std::io::Error> {
+    fn with_path(self, path: impl AsRef<Path>) -> Result<T, CloudInitError> {
+        self.map_err(|source| CloudInitError::File {
+            path: path.as_ref().to_path_buf(),
+            source,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_file_error_names_path() {
+        let err = std::fs::read("/nonexistent/cloud.cfg")
+            .with_path("/nonexistent/cloud.cfg")
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("IO error on /nonexistent/cloud.cfg:")
+        );
+        assert!(err.source().is_some());
+        assert_eq!(err.exit_code(), exit_code::IO);
+    }
+
+    #[test]
+    fn test_in_module_wraps_once() {
+        let err = CloudInitError::InvalidData("bad locale".to_string()).in_module("locale");
+        assert_eq!(err.module_name(), Some("locale"));
+        assert_eq!(
+            err.to_string(),
+            "Module 'locale' failed: Invalid data: bad locale"
+        );
+        // Exit code follows the underlying error
+        assert_eq!(err.exit_code(), exit_code::CONFIG);
+
+        let err = err.in_module("locale");
+        assert!(matches!(&err, CloudInitError::InModule { source, .. }
+            if matches!(**source, CloudInitError::InvalidData(_))));
+
+        let err = CloudInitError::module("users", "useradd failed").in_module("users");
+        assert!(matches!(err, CloudInitError::Module { .. }));
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(
+            CloudInitError::NoDatasource.exit_code(),
+            exit_code::DATASOURCE
+        );
+        assert_eq!(
+            CloudInitError::Config("x".to_string()).exit_code(),
+            exit_code::CONFIG
+        );
+        assert_eq!(
+            CloudInitError::Command("x".to_string()).exit_code(),
+            exit_code::FAILURE
+        );
+    }
 }
@@ -0,0 +1,97 @@
+//! Compiled-in capabilities
+//!
+//! Reports which datasources, configuration modules and network renderers
+//! this binary was built with, for the `features` subcommand and
+//! `--version --long`. Builds made with a subset of the `ds-*`/`mod-*`
+//! cargo features carry fewer entries, so tooling can check a binary's
+//! capabilities before relying on them.
+
+use serde::Serialize;
+use std::fmt;
+
+/// Crate version
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Short git revision the binary was built from (`unknown` outside git)
+pub const GIT_HASH: &str = env!("CLOUD_INIT_RS_GIT_HASH");
+
+/// Cargo profile used for the build (`debug` or `release`)
+pub const BUILD_PROFILE: &str = env!("CLOUD_INIT_RS_BUILD_PROFILE");
+
+/// Capabilities compiled into this binary
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Features {
+    /// Datasource names, in detection order
+    pub datasources: Vec<&'static str>,
+    /// Configuration module names
+    pub modules: Vec<&'static str>,
+    /// Network renderer names
+    pub renderers: Vec<&'static str>,
+}
+
+/// Capabilities of the running binary
+pub fn compiled_features() -> Features {
+    Features {
+        datasources: crate::datasources::COMPILED.to_vec(),
+        modules: crate::modules::COMPILED.to_vec(),
+        renderers: vec!["networkd", "network-manager", "eni"],
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "datasources: {}", self.datasources.join(" "))?;
+        writeln!(f, "modules: {}", self.modules.join(" "))?;
+        writeln!(f, "renderers: {}", self.renderers.join(" "))
+    }
+}
+
+/// Extended version string: version, git revision, profile and features
+pub fn long_version() -> String {
+    format!(
+        "cloud-init-rs {}\ngit: {}\nprofile: {}\n{}",
+        VERSION,
+        GIT_HASH,
+        BUILD_PROFILE,
+        compiled_features()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compiled_features_lists_nocloud() {
+        let features = compiled_features();
+        assert_eq!(features.datasources.first(), Some(&"NoCloud"));
+        assert!(features.modules.contains(&"users"));
+        assert!(!features.renderers.is_empty());
+    }
+
+    #[test]
+    fn test_features_display() {
+        let out = compiled_features().to_string();
+        assert!(out.starts_with("datasources: NoCloud"));
+        assert!(out.contains("\nmodules: "));
+        assert!(out.contains(" bootcmd "));
+        assert!(out.contains(" locale motd mounts "));
+        assert!(out.ends_with("networkd network-manager eni\n"));
+    }
+
+    #[test]
+    fn test_long_version() {
+        let out = long_version();
+        assert!(out.starts_with(&format!("cloud-init-rs {VERSION}\n")));
+        assert!(out.contains(&format!("git: {GIT_HASH}\n")));
+        assert!(out.contains("profile: "));
+        assert!(out.contains("datasources: "));
+    }
+
+    #[test]
+    fn test_features_json() {
+        let json = serde_json::to_value(compiled_features()).unwrap();
+        assert!(json["datasources"].is_array());
+        assert_eq!(json["renderers"][0], "networkd");
+    }
+}
@@ -0,0 +1,283 @@
+//! HTTP clients for outbound requests
+//!
+//! Requests to the outside world (reporting webhooks, `#include` URLs,
+//! package and installer downloads) go through the proxy set by
+//! `http_proxy`, `https_proxy` and `no_proxy` in cloud.cfg, falling back
+//! to the usual environment variables. Metadata services are on
+//! link-local or instance-local addresses that a proxy cannot reach, so
+//! they are never proxied:
+//!
+//! ```yaml
+//! http_proxy: http://proxy.example.com:3128
+//! https_proxy: http://proxy.example.com:3128
+//! no_proxy: .internal.example.com,10.0.0.0/8
+//! ```
+//!
+//! Private provisioning servers may use their own CA and require client
+//! certificates; `http_tls` (see [`HttpTlsConfig`]) applies to the same
+//! outbound clients.
+
+use crate::config::{CloudConfig, HttpTlsConfig};
+use crate::runner::SystemCommand;
+use crate::{CloudInitError, IoContext};
+use reqwest::{Certificate, ClientBuilder, Identity, NoProxy, Proxy};
+use std::sync::OnceLock;
+use tracing::{debug, warn};
+
+/// Metadata service addresses, always reached directly
+pub const METADATA_NO_PROXY: &str = "169.254.0.0/16,fe80::/10,fd00:ec2::254,fd20:ce::254,metadata.google.internal,localhost,127.0.0.1,::1";
+
+static PROXY: OnceLock<ProxySettings> = OnceLock::new();
+
+static TLS: OnceLock<HttpTlsConfig> = OnceLock::new();
+
+/// Use the proxy and TLS settings of `config` for the rest of the process
+///
+/// Only the first call has an effect.
+pub fn configure(config: &CloudConfig) {
+    ProxySettings::from_config(config).install();
+    if let Some(tls) = &config.http_tls {
+        let _ = TLS.set(tls.clone());
+    }
+}
+
+/// Proxy settings from cloud.cfg
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxySettings {
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    pub no_proxy: Option<String>,
+}
+
+impl ProxySettings {
+    /// The proxy keys of `config`
+    pub fn from_config(config: &CloudConfig) -> Self {
+        Self {
+            http_proxy: config.http_proxy.clone(),
+            https_proxy: config.https_proxy.clone(),
+            no_proxy: config.no_proxy.clone(),
+        }
+    }
+
+    /// Use these settings for the rest of the process
+    ///
+    /// Only the first call has an effect.
+    pub fn install(self) {
+        let _ = PROXY.set(self);
+    }
+
+    /// The installed settings, or none
+    pub fn current() -> &'static ProxySettings {
+        static NONE: ProxySettings = ProxySettings {
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: None,
+        };
+        PROXY.get().unwrap_or(&NONE)
+    }
+
+    /// Environment for commands that download, e.g. package managers
+    ///
+    /// Commands inherit the environment already, so only settings from
+    /// cloud.cfg are added, in both spellings tools look for.
+    pub fn command_env(&self) -> Vec<(String, String)> {
+        let mut env = Vec::new();
+        for (name, value) in [
+            ("http_proxy", &self.http_proxy),
+            ("https_proxy", &self.https_proxy),
+            ("no_proxy", &self.no_proxy),
+        ] {
+            if let Some(value) = value {
+                env.push((name.to_string(), value.clone()));
+                env.push((name.to_uppercase(), value.clone()));
+            }
+        }
+        env
+    }
+
+    /// Settings unset in cloud.cfg taken from the environment
+    fn or_env(&self) -> Self {
+        let env = |name: &str| {
+            std::env::var(name)
+                .or_else(|_| std::env::var(name.to_uppercase()))
+                .ok()
+                .filter(|value| !value.is_empty())
+        };
+        Self {
+            http_proxy: self.http_proxy.clone().or_else(|| env("http_proxy")),
+            https_proxy: self.https_proxy.clone().or_else(|| env("https_proxy")),
+            no_proxy: self.no_proxy.clone().or_else(|| env("no_proxy")),
+        }
+    }
+}
+
+/// `command` with the proxy settings from cloud.cfg in its environment
+pub fn with_proxy_env(command: SystemCommand) -> SystemCommand {
+    ProxySettings::current()
+        .command_env()
+        .into_iter()
+        .fold(command, |command, (key, value)| command.env(key, value))
+}
+
+/// Client builder for outbound requests, with the proxy and TLS
+/// settings applied
+///
+/// Fails if the configured CA bundle or client certificate cannot be
+/// loaded, rather than connecting without them.
+pub fn client_builder() -> Result<ClientBuilder, CloudInitError> {
+    let builder = proxied_builder(&ProxySettings::current().or_env());
+    match TLS.get() {
+        Some(tls) => with_tls(builder, tls),
+        None => Ok(builder),
+    }
+}
+
+/// Client builder for metadata services, which are never proxied
+pub fn metadata_client_builder() -> ClientBuilder {
+    reqwest::Client::builder().no_proxy()
+}
+
+/// Add the CA bundle and client identity of `tls` to `builder`
+fn with_tls(
+    mut builder: ClientBuilder,
+    tls: &HttpTlsConfig,
+) -> Result<ClientBuilder, CloudInitError> {
+    if let Some(path) = &tls.ca_bundle {
+        let certificates = Certificate::from_pem_bundle(&read_pem(path)?)
+            .map_err(|e| CloudInitError::Config(format!("Invalid CA bundle {}: {}", path, e)))?;
+        debug!(
+            "Trusting {} CA certificates from {}",
+            certificates.len(),
+            path
+        );
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if let Some(path) = &tls.client_cert {
+        let mut pem = read_pem(path)?;
+        if let Some(key) = &tls.client_key {
+            pem.push(b'\n');
+            pem.extend(read_pem(key)?);
+        }
+        let identity = Identity::from_pem(&pem).map_err(|e| {
+            CloudInitError::Config(format!("Invalid client certificate {}: {}", path, e))
+        })?;
+        builder = builder.identity(identity);
+    } else if tls.client_key.is_some() {
+        return Err(CloudInitError::Config(
+            "http_tls client_key is set without client_cert".to_string(),
+        ));
+    }
+    Ok(builder)
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, CloudInitError> {
+    std::fs::read(path).with_path(path)
+}
+
+fn proxied_builder(settings: &ProxySettings) -> ClientBuilder {
+    let exclusions = match &settings.no_proxy {
+        Some(no_proxy) => format!("{},{}", METADATA_NO_PROXY, no_proxy),
+        None => METADATA_NO_PROXY.to_string(),
+    };
+    let mut builder = reqwest::Client::builder().no_proxy();
+    for (name, url) in [
+        ("http_proxy", &settings.http_proxy),
+        ("https_proxy", &settings.https_proxy),
+    ] {
+        let Some(url) = url else { continue };
+        let proxy = if name == "http_proxy" {
+            Proxy::http(url.as_str())
+        } else {
+            Proxy::https(url.as_str())
+        };
+        match proxy {
+            Ok(proxy) => builder = builder.proxy(proxy.no_proxy(NoProxy::from_string(&exclusions))),
+            Err(e) => warn!("Ignoring invalid {} {}: {}", name, url, e),
+        }
+    }
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn fixture(name: &str) -> Option<String> {
+        Some(format!(
+            "{}/tests/fixtures/tls/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+    }
+
+    #[test]
+    fn test_with_tls() {
+        let tls = HttpTlsConfig {
+            ca_bundle: fixture("ca.pem"),
+            client_cert: fixture("client.pem"),
+            client_key: fixture("client-key.pem"),
+        };
+        assert!(with_tls(reqwest::Client::builder(), &tls).is_ok());
+
+        // A certificate without its key is no identity
+        let keyless = HttpTlsConfig {
+            client_key: None,
+            ..tls.clone()
+        };
+        assert!(with_tls(reqwest::Client::builder(), &keyless).is_err());
+        let missing = HttpTlsConfig {
+            ca_bundle: fixture("missing.pem"),
+            ..Default::default()
+        };
+        let err = with_tls(reqwest::Client::builder(), &missing).unwrap_err();
+        assert!(err.to_string().contains("missing.pem"));
+    }
+
+    #[test]
+    fn test_command_env() {
+        let config =
+            CloudConfig::from_yaml("http_proxy: http://proxy:3128\nno_proxy: .example.com\n")
+                .unwrap();
+        assert_eq!(
+            ProxySettings::from_config(&config).command_env(),
+            vec![
+                ("http_proxy".to_string(), "http://proxy:3128".to_string()),
+                ("HTTP_PROXY".to_string(), "http://proxy:3128".to_string()),
+                ("no_proxy".to_string(), ".example.com".to_string()),
+                ("NO_PROXY".to_string(), ".example.com".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_requests_go_through_proxy_except_metadata() {
+        // The mock stands in for the proxy: a proxied request for any
+        // host arrives there
+        let proxy = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("via proxy"))
+            .mount(&proxy)
+            .await;
+        let settings = ProxySettings {
+            http_proxy: Some(proxy.uri()),
+            ..Default::default()
+        };
+        let client = proxied_builder(&settings).build().unwrap();
+
+        let body = client
+            .get("http://updates.example.invalid/")
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, "via proxy");
+        // Excluded: tried directly, and nothing listens there
+        assert!(client.get("http://127.0.0.1:9/").send().await.is_err());
+    }
+}
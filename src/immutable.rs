@@ -0,0 +1,285 @@
+//! Immutable root filesystems
+//!
+//! Image-based systems cannot be changed everywhere: ostree systems
+//! (Fedora CoreOS, Silverblue) and Flatcar mount `/usr` read-only and keep
+//! `/etc` writable, while NixOS generates much of `/etc` as symlinks into
+//! the read-only `/nix/store`. [`ImmutableRoot::detect`] recognizes these,
+//! and [`write_etc_file`] writes configuration files the way such systems
+//! allow: a symlink into the read-only tree is replaced by a regular file
+//! in `/etc`, and a file that cannot be written at all is skipped with a
+//! warning rather than failing the stage.
+
+use crate::CloudInitError;
+use crate::distro::Distro;
+use crate::root::RootContext;
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs;
+use tracing::{debug, warn};
+
+/// Present on a running ostree-booted system
+const OSTREE_BOOTED: &str = "/run/ostree-booted";
+
+/// Repository of an ostree deployment, seen from the deployment or the
+/// physical root
+const OSTREE_REPOS: &[&str] = &["/ostree", "/sysroot/ostree"];
+
+/// Marker file every NixOS system has
+const NIXOS_MARKER: &str = "/etc/NIXOS";
+
+/// Symlinks followed before giving up, as the kernel does
+const MAX_SYMLINKS: usize = 40;
+
+/// Kind of immutable root filesystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImmutableRoot {
+    /// ostree deployment: read-only `/usr`, writable `/etc`
+    Ostree,
+    /// NixOS: `/etc` entries link into the read-only `/nix/store`
+    NixOs,
+    /// Flatcar Container Linux: read-only `/usr`, `/etc` overlay
+    Flatcar,
+}
+
+impl ImmutableRoot {
+    /// The kind of immutable root `root` is, if it is one
+    pub async fn detect(root: &RootContext) -> Option<Self> {
+        if root.path(OSTREE_BOOTED).exists() || OSTREE_REPOS.iter().any(|p| root.path(p).is_dir()) {
+            return Some(Self::Ostree);
+        }
+        match Distro::detect(root).await.id.as_str() {
+            "nixos" => Some(Self::NixOs),
+            "flatcar" => Some(Self::Flatcar),
+            _ if root.path(NIXOS_MARKER).exists() => Some(Self::NixOs),
+            _ => None,
+        }
+    }
+
+    /// In-system directories that cannot be written
+    pub fn read_only_dirs(&self) -> &'static [&'static str] {
+        match self {
+            Self::Ostree | Self::Flatcar => &["/usr"],
+            Self::NixOs => &["/nix/store"],
+        }
+    }
+
+    /// Whether the in-system `path` lies in a read-only directory
+    pub fn is_read_only(&self, path: &Path) -> bool {
+        self.read_only_dirs()
+            .iter()
+            .any(|dir| path.starts_with(dir))
+    }
+}
+
+impl std::fmt::Display for ImmutableRoot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ostree => write!(f, "ostree"),
+            Self::NixOs => write!(f, "NixOS"),
+            Self::Flatcar => write!(f, "Flatcar"),
+        }
+    }
+}
+
+/// Write a configuration file below `/etc` on a possibly immutable root
+///
+/// `path` is a host path, as for [`RootContext::write_file`]. Returns
+/// whether the file was written; `false` means it was skipped with a
+/// warning because the filesystem is read-only.
+pub async fn write_etc_file(
+    root: &RootContext,
+    immutable: Option<ImmutableRoot>,
+    path: &Path,
+    contents: impl AsRef<[u8]>,
+) -> Result<bool, CloudInitError> {
+    if let Some(kind) = immutable
+        && path.is_symlink()
+        && let Some(target) = resolve_in_root(root, path).await
+        && kind.is_read_only(&target)
+    {
+        // The system's own configuration restores the link on its next
+        // rebuild or update, which is what the warning points out
+        warn!(
+            "{} links into read-only {} on {}; replacing it with a file",
+            path.display(),
+            target.display(),
+            kind
+        );
+        root.remove_file(path).await?;
+    }
+
+    match root.write_file(path, contents).await {
+        Ok(()) => Ok(true),
+        Err(e) if is_read_only_error(&e) => {
+            warn!(
+                "{} is on a read-only filesystem; not writing it",
+                path.display()
+            );
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `error` reports a read-only filesystem
+pub fn is_read_only_error(error: &CloudInitError) -> bool {
+    match error {
+        CloudInitError::File { source, .. } | CloudInitError::Io(source) => {
+            source.kind() == ErrorKind::ReadOnlyFilesystem
+        }
+        _ => false,
+    }
+}
+
+/// In-system path a file below `root` finally resolves to
+///
+/// Symlinks are followed in every component, and absolute targets are
+/// taken relative to `root` rather than the running system, so links in a
+/// mounted image resolve as they will when it boots.
+async fn resolve_in_root(root: &RootContext, path: &Path) -> Option<PathBuf> {
+    let mut pending = components(path.strip_prefix(root.root()).ok()?);
+    let mut resolved = PathBuf::from("/");
+    let mut links = 0;
+    while let Some(part) = pending.pop() {
+        if part == ".." {
+            resolved.pop();
+            continue;
+        }
+        let candidate = resolved.join(&part);
+        let Ok(target) = fs::read_link(root.path(&candidate)).await else {
+            resolved = candidate;
+            continue;
+        };
+        links += 1;
+        if links > MAX_SYMLINKS {
+            debug!("Too many levels of symlinks at {}", path.display());
+            return None;
+        }
+        if target.is_absolute() {
+            resolved = PathBuf::from("/");
+        }
+        pending.extend(components(&target));
+    }
+    Some(resolved)
+}
+
+/// Names and `..` in `path`, last first so they can be popped in order
+fn components(path: &Path) -> Vec<OsString> {
+    path.components()
+        .rev()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn root_with_os_release(id: &str) -> (TempDir, RootContext) {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        std::fs::create_dir_all(root.path("/etc")).unwrap();
+        std::fs::write(root.path("/etc/os-release"), format!("ID={}\n", id)).unwrap();
+        (temp, root)
+    }
+
+    #[tokio::test]
+    async fn test_detect() {
+        let (_temp, root) = root_with_os_release("ubuntu");
+        assert_eq!(ImmutableRoot::detect(&root).await, None);
+        std::fs::create_dir_all(root.path("/sysroot/ostree")).unwrap();
+        assert_eq!(
+            ImmutableRoot::detect(&root).await,
+            Some(ImmutableRoot::Ostree)
+        );
+
+        let (_temp, root) = root_with_os_release("nixos");
+        assert_eq!(
+            ImmutableRoot::detect(&root).await,
+            Some(ImmutableRoot::NixOs)
+        );
+        let (_temp, root) = root_with_os_release("flatcar");
+        assert_eq!(
+            ImmutableRoot::detect(&root).await,
+            Some(ImmutableRoot::Flatcar)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_in_root() {
+        let (_temp, root) = root_with_os_release("flatcar");
+        std::fs::create_dir_all(root.path("/usr/lib")).unwrap();
+        std::os::unix::fs::symlink("../usr/lib/os-release", root.path("/etc/release")).unwrap();
+        std::os::unix::fs::symlink("/etc/release", root.path("/etc/release-link")).unwrap();
+        assert_eq!(
+            resolve_in_root(&root, &root.path("/etc/release-link")).await,
+            Some(PathBuf::from("/usr/lib/os-release"))
+        );
+
+        std::os::unix::fs::symlink("loop", root.path("/etc/loop")).unwrap();
+        assert_eq!(resolve_in_root(&root, &root.path("/etc/loop")).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_write_etc_file_replaces_store_link() {
+        let (_temp, root) = root_with_os_release("nixos");
+        let store = root.path("/nix/store/abc-etc/etc");
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::write(store.join("hostname"), "nixos\n").unwrap();
+        std::os::unix::fs::symlink("/nix/store/abc-etc/etc", root.path("/etc/static")).unwrap();
+        std::os::unix::fs::symlink("/etc/static/hostname", root.path("/etc/hostname")).unwrap();
+
+        let hostname = root.path("/etc/hostname");
+        let written = write_etc_file(&root, Some(ImmutableRoot::NixOs), &hostname, "web1\n")
+            .await
+            .unwrap();
+
+        assert!(written);
+        assert!(!hostname.is_symlink());
+        assert_eq!(std::fs::read_to_string(&hostname).unwrap(), "web1\n");
+        // The store is left alone
+        assert_eq!(
+            std::fs::read_to_string(store.join("hostname")).unwrap(),
+            "nixos\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_etc_file_follows_writable_link() {
+        let (_temp, root) = root_with_os_release("flatcar");
+        std::fs::write(root.path("/etc/hosts.real"), "").unwrap();
+        std::os::unix::fs::symlink("hosts.real", root.path("/etc/hosts")).unwrap();
+
+        let hosts = root.path("/etc/hosts");
+        write_etc_file(
+            &root,
+            Some(ImmutableRoot::Flatcar),
+            &hosts,
+            "127.0.0.1 localhost\n",
+        )
+        .await
+        .unwrap();
+
+        assert!(hosts.is_symlink());
+        assert_eq!(
+            std::fs::read_to_string(root.path("/etc/hosts.real")).unwrap(),
+            "127.0.0.1 localhost\n"
+        );
+    }
+
+    #[test]
+    fn test_is_read_only_error() {
+        let error = |kind| CloudInitError::File {
+            path: PathBuf::from("/etc/hostname"),
+            source: std::io::Error::from(kind),
+        };
+        assert!(is_read_only_error(&error(ErrorKind::ReadOnlyFilesystem)));
+        assert!(!is_read_only_error(&error(ErrorKind::PermissionDenied)));
+    }
+}
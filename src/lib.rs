@@ -9,23 +9,46 @@
 //! - **80% Compatibility**: Support the most common cloud-init features
 //! - **Backwards Compatible**: Parse existing cloud-config formats
 
+pub mod actions;
+pub mod analyze;
+pub mod clean;
+pub mod collect_logs;
 pub mod config;
+pub mod daemon;
 pub mod datasources;
+pub mod disabled;
+pub mod distro;
+pub mod embed;
+pub mod features;
+pub mod http;
+pub mod immutable;
+pub mod logging;
+pub mod metadata;
 pub mod modules;
 pub mod network;
+pub mod offline;
+pub mod platform;
+pub mod query;
+pub mod redact;
+pub mod reporting;
+pub mod root;
+pub mod runner;
+pub mod service;
 pub mod stages;
 pub mod state;
+pub mod systemd;
 pub mod template;
 pub mod userdata;
 
 mod error;
 
-pub use error::CloudInitError;
+pub use embed::{CloudInit, CloudInitBuilder};
+pub use error::{CloudInitError, IoContext, exit_code};
 
-use tracing::info;
+use tracing::{Instrument, info, info_span, warn};
 
-/// Cloud-init execution stages
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Cloud-init execution stages, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Stage {
     /// Local stage - runs before network is available
     /// Handles: disk setup, growpart, mounts
@@ -52,27 +75,214 @@ impl std::fmt::Display for Stage {
     }
 }
 
+impl std::str::FromStr for Stage {
+    type Err = CloudInitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(Stage::Local),
+            "network" => Ok(Stage::Network),
+            "config" => Ok(Stage::Config),
+            "final" => Ok(Stage::Final),
+            other => Err(CloudInitError::InvalidData(format!(
+                "Unknown stage '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// Outcome of [`run_stages`] when every stage ran to completion
+#[derive(Debug, Default)]
+pub struct RunSummary {
+    /// Failures of best-effort modules, in the order they ran
+    pub module_errors: Vec<CloudInitError>,
+}
+
 /// Run the specified cloud-init stages in order
-pub async fn run_stages(stages: &[Stage]) -> Result<(), CloudInitError> {
+///
+/// Progress is recorded in `status.json`, and `result.json` is written once
+/// the final stage completes. Start/finish events for every stage and
+/// module are published through [`reporting::Reporter`].
+///
+/// In a dry run (see [`actions`]) neither status files nor events leave
+/// the process; events only serve to attribute recorded actions.
+///
+/// A failing module never stops its stage, and every module failure is
+/// added to the stage's `errors` in `status.json`. What happens next
+/// depends on the module's policy (`module_policy` in cloud.cfg): a
+/// best-effort failure is returned in the [`RunSummary`], while a required
+/// one fails the stage and ends the run with an error once the stage's
+/// remaining modules have run.
+///
+/// Modules and stages run under watchdog limits from the `timeouts` key
+/// in cloud.cfg. A module that runs too long is abandoned and fails with
+/// a timeout; a stage that exceeds its deadline fails the run.
+pub async fn run_stages(stages: &[Stage]) -> Result<RunSummary, CloudInitError> {
+    run_stages_with(stages, &stages::ModuleFilter::default()).await
+}
+
+/// [`run_stages`], running only the modules `filter` allows
+pub async fn run_stages_with(
+    stages: &[Stage],
+    filter: &stages::ModuleFilter,
+) -> Result<RunSummary, CloudInitError> {
+    let root = root::RootContext::current();
+    if let Some(reason) = disabled::check(&root).await {
+        info!("cloud-init is {}; not running any stage", reason);
+        return Ok(RunSummary::default());
+    }
+    let paths = state::CloudPaths::new();
+    let system = config::load_merged_config(&paths)
+        .await
+        .unwrap_or_else(|e| {
+            warn!(
+                "Could not load system config, using default module policy and timeouts: {}",
+                e
+            );
+            config::CloudConfig::default()
+        });
+    http::configure(&system);
+    redact::configure(&system);
+    userdata::limits::configure(&system);
+    let offline = offline::is_offline() || system.offline();
+    let policy = system.module_policy.unwrap_or_default();
+    let timeouts = system.timeouts.unwrap_or_default();
+    let dry_run = root.recorder();
+    let events = match dry_run {
+        Some(recorder) => {
+            let mut events = reporting::Reporter::new();
+            events.add_handler(Box::new(reporting::LogHandler));
+            events.add_handler(Box::new(recorder.clone()));
+            events
+        }
+        None => reporting::Reporter::from_system(&root, &paths).await,
+    };
+    let lock_paths = paths.clone();
+    let mut reporter = state::BootReporter::load(paths).await;
+    let mut summary = RunSummary::default();
+
     for stage in stages {
         info!("Starting stage: {}", stage);
-        run_stage(*stage).await?;
-        info!("Completed stage: {}", stage);
+        // Dry runs change nothing, so they need not keep others out
+        let _lock = match dry_run {
+            Some(_) => None,
+            None => Some(
+                state::lock::RunLock::acquire(&lock_paths, *stage, timeouts.lock_wait()).await?,
+            ),
+        };
+        reporter.status_mut().stage_started(*stage);
+        if dry_run.is_none() {
+            write_report(&reporter, false).await;
+        }
+
+        let mut modules = stages::ModuleErrors::new(*stage, policy.clone())
+            .with_timeouts(timeouts.clone())
+            .with_filter(filter.clone())
+            .with_offline(offline)
+            .with_parallelism(system.module_parallelism.unwrap_or(1));
+        // Loaded per stage: an earlier stage may have cached a new instance
+        let mut instance = state::InstanceState::new();
+        if let Ok(Some(_)) = instance.load_cached_instance_id().await
+            && let Some(semaphores) = instance.semaphores()
+        {
+            modules = modules.with_semaphores(semaphores.clone());
+        }
+        let result = events
+            .scope(
+                reporting::stage_event_name(*stage),
+                &format!("running {} stage", stage),
+                async {
+                    let run = run_stage(*stage, &events, &mut modules);
+                    match timeouts.stage_timeout() {
+                        Some(limit) => tokio::time::timeout(limit, run).await.map_err(|_| {
+                            CloudInitError::Timeout(format!(
+                                "{} stage to finish (limit {}s)",
+                                stage,
+                                limit.as_secs()
+                            ))
+                        })??,
+                        None => run.await?,
+                    }
+                    modules.check_required()
+                },
+            )
+            .instrument(info_span!("stage", stage = %stage))
+            .await;
+        events.flush().await;
+        let failures = modules.into_failures();
+
+        reporter.refresh_datasource().await;
+        for warning in state::report::take_warnings() {
+            reporter.status_mut().add_warning(*stage, warning);
+        }
+        for failure in &failures {
+            reporter
+                .status_mut()
+                .add_error(*stage, failure.error.to_string());
+        }
+        reporter
+            .status_mut()
+            .stage_finished(*stage, result.as_ref().err().map(|e| e.to_string()));
+        let done = *stage == Stage::Final || result.is_err();
+        if dry_run.is_none() {
+            write_report(&reporter, done).await;
+            if done {
+                let instance_id = state::InstanceState::new()
+                    .load_cached_instance_id()
+                    .await
+                    .ok()
+                    .flatten();
+                let error = result.as_ref().err().map(|e| e.to_string());
+                events
+                    .provisioning_complete(instance_id.as_deref(), error.as_deref())
+                    .await;
+            }
+        }
+
+        result?;
+        if failures.is_empty() {
+            info!("Completed stage: {}", stage);
+        } else {
+            warn!(
+                "Completed stage: {} ({} module(s) failed)",
+                stage,
+                failures.len()
+            );
+        }
+        summary
+            .module_errors
+            .extend(failures.into_iter().map(|f| f.error));
+    }
+    Ok(summary)
+}
+
+/// Persist status (and optionally result) files, logging rather than failing
+async fn write_report(reporter: &state::BootReporter, with_result: bool) {
+    if let Err(e) = reporter.write_status().await {
+        warn!("Could not write status.json: {}", e);
+    }
+    if with_result && let Err(e) = reporter.write_result().await {
+        warn!("Could not write result.json: {}", e);
     }
-    Ok(())
 }
 
-async fn run_stage(stage: Stage) -> Result<(), CloudInitError> {
+async fn run_stage(
+    stage: Stage,
+    events: &reporting::Reporter,
+    modules: &mut stages::ModuleErrors,
+) -> Result<(), CloudInitError> {
+    // Boxed so no one future has to hold every stage's state
     match stage {
-        Stage::Local => stages::local::run().await,
-        Stage::Network => stages::network::run().await,
-        Stage::Config => stages::config::run().await,
-        Stage::Final => stages::final_stage::run().await,
+        Stage::Local => Box::pin(stages::local::run(events, modules)).await,
+        Stage::Network => Box::pin(stages::network::run(events, modules)).await,
+        Stage::Config => Box::pin(stages::config::run(events, modules)).await,
+        Stage::Final => Box::pin(stages::final_stage::run(events, modules)).await,
     }
 }
 
 /// Instance metadata retrieved from datasource
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct InstanceMetadata {
     pub instance_id: Option<String>,
     pub local_hostname: Option<String>,
@@ -81,10 +291,14 @@ pub struct InstanceMetadata {
     pub cloud_name: Option<String>,
     pub platform: Option<String>,
     pub instance_type: Option<String>,
+    /// Provider-specific metadata in the datasource's own layout, read
+    /// through the accessors in [`metadata`]
+    #[serde(default)]
+    pub raw: serde_json::Value,
 }
 
 /// User data (cloud-config or script)
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum UserData {
     /// Cloud-config YAML
     CloudConfig(Box<config::CloudConfig>),
@@ -97,7 +311,7 @@ pub enum UserData {
 }
 
 /// Part of multi-part user data
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct UserDataPart {
     pub content_type: String,
     pub content: String,
@@ -0,0 +1,258 @@
+//! journald native protocol
+//!
+//! Events are sent as datagrams to journald's socket, one per event, in
+//! the native `KEY=value` format: values containing a newline are sent as
+//! `KEY\n`, a little-endian 64-bit length and the raw bytes. This needs no
+//! libsystemd and no unsafe code.
+//!
+//! Every entry carries `SYSLOG_IDENTIFIER`, a syslog `PRIORITY` and the
+//! event's own fields upper-cased. Fields of the spans the event happened
+//! in (`stage`, `module`) are added with a `CLOUD_INIT_` prefix, so
+//! `journalctl CLOUD_INIT_STAGE=config` shows one stage.
+
+use crate::CloudInitError;
+use std::fmt::{self, Write as _};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// journald's native protocol socket
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// `SYSLOG_IDENTIFIER` of every entry
+pub const SYSLOG_IDENTIFIER: &str = "cloud-init-rs";
+
+/// Tracing layer sending events to journald
+#[derive(Debug)]
+pub struct JournaldLayer {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+impl JournaldLayer {
+    /// Send to the system journal
+    pub fn new() -> Result<Self, CloudInitError> {
+        Self::with_socket(JOURNALD_SOCKET)
+    }
+
+    /// Send to the journald-compatible socket at `path`
+    pub fn with_socket(path: impl Into<PathBuf>) -> Result<Self, CloudInitError> {
+        let path = path.into();
+        if !path.exists() {
+            return Err(CloudInitError::Config(format!(
+                "journald socket {} not found",
+                path.display()
+            )));
+        }
+        let socket = UnixDatagram::unbound()?;
+        Ok(Self { socket, path })
+    }
+
+    fn send(&self, payload: &[u8]) {
+        // Logging must never fail the caller; a full journal drops entries
+        let _ = self.socket.send_to(payload, &self.path);
+    }
+}
+
+/// Syslog priority of a tracing level
+fn priority(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "3",
+        Level::WARN => "4",
+        Level::INFO => "6",
+        Level::DEBUG | Level::TRACE => "7",
+    }
+}
+
+/// Append one field in the native protocol format
+fn put_field(payload: &mut Vec<u8>, name: &str, value: &str) {
+    payload.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        payload.push(b'\n');
+        payload.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        payload.push(b'=');
+    }
+    payload.extend_from_slice(value.as_bytes());
+    payload.push(b'\n');
+}
+
+/// Turn a tracing field name into a valid journal field name
+///
+/// Journal fields are upper-case ASCII letters, digits and underscores,
+/// and may not start with an underscore or digit.
+fn field_name(prefix: &str, name: &str) -> String {
+    let mut out = String::from(prefix);
+    for c in name.chars() {
+        out.push(if c.is_ascii_alphanumeric() {
+            c.to_ascii_uppercase()
+        } else {
+            '_'
+        });
+    }
+    let trimmed = out.trim_start_matches(|c: char| c == '_' || c.is_ascii_digit());
+    if trimmed.is_empty() {
+        "FIELD".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// Collects fields as `(journal name, value)` pairs
+struct FieldVisitor {
+    prefix: &'static str,
+    fields: Vec<(String, String)>,
+}
+
+impl FieldVisitor {
+    fn new(prefix: &'static str) -> Self {
+        Self {
+            prefix,
+            fields: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, field: &Field, value: String) {
+        let name = if self.prefix.is_empty() && field.name() == "message" {
+            "MESSAGE".to_string()
+        } else {
+            field_name(self.prefix, field.name())
+        };
+        self.fields.push((name, crate::redact::text(&value)));
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let mut text = String::new();
+        let _ = write!(text, "{:?}", value);
+        self.push(field, text);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value.to_string());
+    }
+}
+
+/// Span fields, stored in the span's extensions when it is created
+struct SpanFields(Vec<(String, String)>);
+
+impl<S> Layer<S> for JournaldLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut visitor = FieldVisitor::new("CLOUD_INIT_");
+        attrs.record(&mut visitor);
+        span.extensions_mut().insert(SpanFields(visitor.fields));
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let meta = event.metadata();
+        let mut payload = Vec::new();
+        put_field(&mut payload, "PRIORITY", priority(meta.level()));
+        put_field(&mut payload, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+        put_field(&mut payload, "TARGET", meta.target());
+
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(fields) = span.extensions().get::<SpanFields>() {
+                    for (name, value) in &fields.0 {
+                        put_field(&mut payload, name, value);
+                    }
+                }
+            }
+        }
+
+        let mut visitor = FieldVisitor::new("");
+        event.record(&mut visitor);
+        for (name, value) in &visitor.fields {
+            put_field(&mut payload, name, value);
+        }
+        self.send(&payload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Split a native protocol datagram into fields
+    fn parse(mut payload: &[u8]) -> Vec<(String, String)> {
+        let mut fields = Vec::new();
+        while !payload.is_empty() {
+            let end = payload.iter().position(|&b| b == b'\n').unwrap();
+            let line = std::str::from_utf8(&payload[..end]).unwrap();
+            if let Some((name, value)) = line.split_once('=') {
+                fields.push((name.to_string(), value.to_string()));
+                payload = &payload[end + 1..];
+            } else {
+                let len_bytes: [u8; 8] = payload[end + 1..end + 9].try_into().unwrap();
+                let len = u64::from_le_bytes(len_bytes) as usize;
+                let start = end + 9;
+                let value = std::str::from_utf8(&payload[start..start + len]).unwrap();
+                fields.push((line.to_string(), value.to_string()));
+                payload = &payload[start + len + 1..];
+            }
+        }
+        fields
+    }
+
+    #[test]
+    fn test_field_names() {
+        assert_eq!(field_name("", "module"), "MODULE");
+        assert_eq!(field_name("CLOUD_INIT_", "stage"), "CLOUD_INIT_STAGE");
+        assert_eq!(field_name("", "log.file"), "LOG_FILE");
+        assert_eq!(field_name("", "_private"), "PRIVATE");
+        assert_eq!(field_name("", "__"), "FIELD");
+    }
+
+    #[test]
+    fn test_events_sent_with_span_fields() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("journal.sock");
+        let journal = UnixDatagram::bind(&path).unwrap();
+        let layer = JournaldLayer::with_socket(&path).unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let stage = tracing::info_span!("stage", stage = "config");
+            let _stage = stage.enter();
+            let module = tracing::info_span!("module", module = "users");
+            let _module = module.enter();
+            tracing::warn!(count = 2, "useradd failed:\nuser exists");
+        });
+
+        let mut buf = vec![0; 4096];
+        let len = journal.recv(&mut buf).unwrap();
+        let fields = parse(&buf[..len]);
+        let get = |name: &str| {
+            fields
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(get("PRIORITY"), Some("4"));
+        assert_eq!(get("SYSLOG_IDENTIFIER"), Some(SYSLOG_IDENTIFIER));
+        assert_eq!(get("CLOUD_INIT_STAGE"), Some("config"));
+        assert_eq!(get("CLOUD_INIT_MODULE"), Some("users"));
+        assert_eq!(get("COUNT"), Some("2"));
+        assert_eq!(get("MESSAGE"), Some("useradd failed:\nuser exists"));
+    }
+
+    #[test]
+    fn test_missing_socket() {
+        let temp = TempDir::new().unwrap();
+        assert!(JournaldLayer::with_socket(temp.path().join("none")).is_err());
+    }
+}
@@ -0,0 +1,388 @@
+//! Log output
+//!
+//! Log events always go to stderr. Stage runs additionally append them to
+//! `/var/log/cloud-init.log` (cloud.cfg `def_log_file`), as plain text or
+//! one JSON object per line (`log_format: json`), and the output of every
+//! command run to `/var/log/cloud-init-output.log` (the path in cloud.cfg
+//! `output: {all: ...}`).
+//!
+//! Lines are only ever appended, each with its own timestamp, so the
+//! files can be rotated at any point. After logrotate moves a file away,
+//! SIGHUP makes the process reopen it at the configured path.
+//!
+//! With `logging: journald` events go to the systemd journal instead of
+//! the log file (see [`journald`]), which suits minimal images without a
+//! syslog daemon.
+
+#[cfg(unix)]
+pub mod journald;
+
+use crate::{CloudInitError, IoContext};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber, warn};
+use tracing_subscriber::filter::{LevelFilter, filter_fn};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, fmt as tfmt};
+
+/// Default log file, as in Python cloud-init
+pub const DEFAULT_LOG_FILE: &str = "/var/log/cloud-init.log";
+
+/// Default file command output is appended to
+pub const DEFAULT_OUTPUT_LOG_FILE: &str = "/var/log/cloud-init-output.log";
+
+static OUTPUT_LOG: OnceLock<LogFile> = OnceLock::new();
+
+/// Format of the log file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines (default)
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Where log events are kept besides stderr
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogBackend {
+    /// The log file (default)
+    #[default]
+    File,
+    /// The systemd journal, falling back to the log file without journald
+    Journald,
+}
+
+/// A log file opened for appending that can be reopened in place
+///
+/// Cloning gives another handle to the same file.
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+}
+
+impl LogFile {
+    /// Open `path` for appending, creating it (mode 0640) if needed
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, CloudInitError> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        Ok(Self {
+            path,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Path the file was opened at
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reopen the file at its path, e.g. after it was rotated away
+    pub fn reopen(&self) -> Result<(), CloudInitError> {
+        let file = open_append(&self.path)?;
+        *self.lock() = file;
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, File> {
+        self.file.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, CloudInitError> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o640);
+    }
+    options.open(path).with_path(path)
+}
+
+/// Each write is appended whole, so concurrent events do not interleave;
+/// secrets are masked first, see [`crate::redact`]
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().write_all(redact_bytes(buf).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogFile {
+    type Writer = LogFile;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+fn redact_bytes(buf: &[u8]) -> String {
+    crate::redact::text(&String::from_utf8_lossy(buf))
+}
+
+/// Stderr with secrets masked
+struct RedactedStderr;
+
+impl Write for RedactedStderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(redact_bytes(buf).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// Formats events as single-line JSON objects
+///
+/// Each object has `timestamp`, `level`, `target` and the event's fields,
+/// including `message`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+
+        let meta = event.metadata();
+        let mut object = serde_json::Map::new();
+        object.insert("timestamp".to_string(), timestamp.into());
+        object.insert("level".to_string(), meta.level().as_str().into());
+        object.insert("target".to_string(), meta.target().into());
+        event.record(&mut JsonVisitor(&mut object));
+
+        writeln!(writer, "{}", serde_json::Value::Object(object))
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+}
+
+/// Where and how to log
+#[derive(Debug, Clone)]
+pub struct LogSettings {
+    pub level: Level,
+    /// Log file events are appended to, if any
+    pub file: Option<PathBuf>,
+    /// File command output is appended to, if any
+    pub output_file: Option<PathBuf>,
+    pub format: LogFormat,
+    pub backend: LogBackend,
+}
+
+/// Install the global tracing subscriber
+///
+/// Files that cannot be opened (e.g. when not running as root) are
+/// skipped with a warning. Returns the files that were opened, for
+/// [`reopen_on_sighup`].
+pub fn init(settings: &LogSettings) -> Vec<LogFile> {
+    let mut problems = Vec::new();
+    let journald_layer = match settings.backend {
+        #[cfg(unix)]
+        LogBackend::Journald => journald::JournaldLayer::new()
+            .map_err(|e| problems.push(e))
+            .ok(),
+        _ => None,
+    };
+    let mut open = |path: &Option<PathBuf>| {
+        path.as_ref()
+            .and_then(|p| LogFile::open(p).map_err(|e| problems.push(e)).ok())
+    };
+    let log_file = if journald_layer.is_some() {
+        None
+    } else {
+        open(&settings.file)
+    };
+    let output_file = open(&settings.output_file);
+
+    // Stage and module spans only feed journald's fields; hiding them from
+    // the text layers keeps their lines unprefixed
+    let events_only = || filter_fn(|meta| !meta.is_span());
+    let file_layer = log_file.clone().map(|file| match settings.format {
+        LogFormat::Text => tfmt::layer()
+            .with_ansi(false)
+            .with_writer(file)
+            .with_filter(events_only())
+            .boxed(),
+        LogFormat::Json => tfmt::layer()
+            .event_format(JsonFormat)
+            .with_writer(file)
+            .with_filter(events_only())
+            .boxed(),
+    });
+    // Logs go to stderr so machine-readable output on stdout stays clean
+    let stderr_layer = tfmt::layer()
+        .with_writer(|| RedactedStderr)
+        .with_target(false)
+        .with_thread_ids(false)
+        .compact()
+        .with_filter(events_only());
+    let subscriber = tracing_subscriber::registry()
+        .with(LevelFilter::from_level(settings.level))
+        .with(stderr_layer)
+        .with(file_layer)
+        .with(journald_layer);
+    tracing::subscriber::set_global_default(subscriber).expect("Failed to set tracing subscriber");
+
+    for e in problems {
+        warn!("Log output unavailable: {}", e);
+    }
+    if let Some(output) = &output_file {
+        // Only fails if already installed, which init prevents
+        let _ = OUTPUT_LOG.set(output.clone());
+    }
+    log_file.into_iter().chain(output_file).collect()
+}
+
+/// Append a command's output to the output log, if one is open
+pub fn log_command_output(stdout: &str, stderr: &str) {
+    let Some(mut log) = OUTPUT_LOG.get().cloned() else {
+        return;
+    };
+    for output in [stdout, stderr] {
+        if !output.is_empty() && log.write_all(output.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Reopen `files` whenever the process receives SIGHUP
+///
+/// Must be called from within the tokio runtime.
+pub fn reopen_on_sighup(files: Vec<LogFile>) {
+    if files.is_empty() {
+        return;
+    }
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                warn!("Cannot reopen log files on SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            for file in &files {
+                if let Err(e) = file.reopen() {
+                    warn!("Could not reopen log file: {}", e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_reopen_after_rotation() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("cloud-init.log");
+        let mut log = LogFile::open(&path).unwrap();
+
+        log.write_all(b"before\n").unwrap();
+        std::fs::rename(&path, temp.path().join("cloud-init.log.1")).unwrap();
+        log.write_all(b"still old\n").unwrap();
+        log.reopen().unwrap();
+        log.write_all(b"after\n").unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(temp.path().join("cloud-init.log.1")).unwrap(),
+            "before\nstill old\n"
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "after\n");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+    }
+
+    #[test]
+    fn test_json_format() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("cloud-init.log");
+        let log = LogFile::open(&path).unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(tfmt::layer().event_format(JsonFormat).with_writer(log));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(module = "users", count = 2, "Created users");
+            tracing::warn!("second line");
+        });
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["message"], "Created users");
+        assert_eq!(lines[0]["module"], "users");
+        assert_eq!(lines[0]["count"], 2);
+        assert!(lines[0]["timestamp"].as_str().unwrap().contains('T'));
+        assert_eq!(lines[1]["level"], "WARN");
+        assert_eq!(lines[1]["message"], "second line");
+    }
+}
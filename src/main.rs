@@ -5,20 +5,49 @@
 //! - Memory safety (no unsafe code)
 //! - 80% compatibility with cloud-init functionality
 
-use clap::{Parser, Subcommand};
-use tracing::{Level, info};
-use tracing_subscriber::FmtSubscriber;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tracing::{Level, debug, info};
 
-use cloud_init_rs::{CloudInitError, Stage, run_stages};
+use cloud_init_rs::config::load_merged_config;
+use cloud_init_rs::logging::{self, LogBackend, LogFormat, LogSettings};
+use cloud_init_rs::state::CloudPaths;
+use cloud_init_rs::{CloudInit, CloudInitError, IoContext, RunSummary, Stage};
 
 #[derive(Parser)]
 #[command(name = "cloud-init-rs")]
 #[command(author, version, about = "Safe Rust implementation of cloud-init", long_about = None)]
+#[command(disable_version_flag = true)]
 struct Cli {
     /// Enable verbose output
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 
+    /// Print version
+    #[arg(short = 'V', long)]
+    version: bool,
+
+    /// With --version, also print git revision, build profile and features
+    #[arg(long, requires = "version")]
+    long: bool,
+
+    /// Operate on the root filesystem mounted at DIR instead of the running system
+    #[arg(long, global = true, value_name = "DIR")]
+    root: Option<PathBuf>,
+
+    /// Print the files, commands and packages stages would change instead of applying them
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Output format of the --dry-run plan
+    #[arg(long, global = true, value_enum, default_value_t = PlanFormat::Text, requires = "dry_run")]
+    plan_format: PlanFormat,
+
+    /// Skip network datasources and modules, for sandboxes without network access
+    #[arg(long, global = true)]
+    offline: bool,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -35,84 +64,439 @@ enum Commands {
     Config,
     /// Run final stage (user scripts, etc.)
     Final,
+    /// Stay resident and run each stage when its unit triggers it
+    Daemon,
     /// Query instance metadata
     Query {
-        /// Key to query (e.g., instance-id, local-hostname)
+        /// Key to query, e.g. `instance_id`, `v1.region` or `ds/meta_data`
         key: String,
     },
+    /// Serve instance data read-only over a unix socket, and optionally HTTP
+    ServeMetadata {
+        /// Socket to listen on
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+        /// Also listen on this loopback address, e.g. 127.0.0.1:8053
+        #[arg(long, value_name = "ADDR")]
+        listen: Option<std::net::SocketAddr>,
+    },
     /// Clean cloud-init artifacts
     Clean {
         /// Remove logs as well
         #[arg(long)]
         logs: bool,
+        /// Prepare a golden image: also truncate logs, remove SSH host
+        /// keys and reset the machine ID
+        #[arg(long)]
+        seal: bool,
     },
     /// Show status of cloud-init
     Status,
+    /// Print the canonical name of the cloud this instance runs on
+    CloudId,
+    /// Pack logs, status and instance data into a tarball for bug reports
+    CollectLogs {
+        /// Tarball to write
+        #[arg(short, long, default_value = cloud_init_rs::collect_logs::DEFAULT_TARBALL)]
+        tarfile: PathBuf,
+        /// Include user data, which may contain secrets
+        #[arg(short = 'u', long)]
+        include_userdata: bool,
+    },
+    /// List compiled-in datasources, modules and network renderers
+    Features {
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Analyze boot performance from the event log
+    Analyze {
+        /// Event log to read
+        #[arg(short, long, default_value = cloud_init_rs::analyze::DEFAULT_EVENT_LOG)]
+        infile: PathBuf,
+
+        #[command(subcommand)]
+        action: AnalyzeAction,
+    },
+    /// Developer and integration tools
+    Devel {
+        #[command(subcommand)]
+        action: DevelAction,
+    },
+    /// Run as a systemd generator, or emit the unit files
+    #[command(hide = true)]
+    SystemdGenerate {
+        /// Generator output directories (normal, early, late) as passed by systemd
+        dirs: Vec<PathBuf>,
+        /// Write the service unit files into this directory instead
+        #[arg(long)]
+        units_dir: Option<PathBuf>,
+    },
 }
 
-fn init_logging(verbosity: u8) {
+#[derive(Clone, Copy, ValueEnum)]
+enum PlanFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum DevelAction {
+    /// Configure network interfaces attached at runtime
+    HotplugHook {
+        /// Device subsystem; only `net` is supported
+        #[arg(short, long, default_value = "net")]
+        subsystem: String,
+
+        #[command(subcommand)]
+        action: HotplugHookAction,
+    },
+    /// Render a `## template: jinja` user-data file and print the result
+    Render {
+        /// Template to render
+        template: PathBuf,
+        /// Render against this instance-data.json instead of the instance's metadata
+        #[arg(long, value_name = "FILE")]
+        instance_data: Option<PathBuf>,
+        /// Undefined variables: lenient (empty), strict (error) or marker
+        /// (`CI_MISSING_JINJA_VAR/<name>`)
+        #[arg(long, value_name = "MODE", default_value = "lenient")]
+        undefined: cloud_init_rs::template::UndefinedVariables,
+    },
+}
+
+#[derive(Subcommand)]
+enum HotplugHookAction {
+    /// Print whether hotplug is enabled
+    Query,
+    /// Handle one udev event
+    Handle {
+        /// udev action, `add` or `remove`
+        #[arg(short, long, env = "ACTION")]
+        udevaction: String,
+        /// Interface the event is for
+        #[arg(short, long, env = "INTERFACE")]
+        interface: String,
+    },
+    /// Listen for kernel uevents and handle every interface added or removed
+    Listen,
+}
+
+#[derive(Subcommand)]
+enum AnalyzeAction {
+    /// Show stages and the modules they ran, in order
+    Show,
+    /// List modules sorted by time taken
+    Blame,
+    /// Show kernel boot time versus cloud-init-rs activity
+    Boot,
+}
+
+/// Log to stderr, and for stage runs also to the files set in cloud.cfg
+async fn init_logging(verbosity: u8, to_files: bool) {
     let level = match verbosity {
         0 => Level::INFO,
         1 => Level::DEBUG,
         _ => Level::TRACE,
     };
 
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(level)
-        .with_target(false)
-        .with_thread_ids(false)
-        .compact()
-        .finish();
-
-    tracing::subscriber::set_global_default(subscriber).expect("Failed to set tracing subscriber");
+    let mut settings = LogSettings {
+        level,
+        file: None,
+        output_file: None,
+        format: LogFormat::Text,
+        backend: LogBackend::File,
+    };
+    if to_files {
+        let system = load_merged_config(&CloudPaths::new())
+            .await
+            .unwrap_or_default();
+        settings.file = Some(system.log_file());
+        settings.output_file = Some(system.output_log_file());
+        settings.format = system.log_format.unwrap_or_default();
+        settings.backend = system.logging.unwrap_or_default();
+    }
+    logging::reopen_on_sighup(logging::init(&settings));
 }
 
+/// Exit with a status reflecting how the run went
+///
+/// 0 on success, including when only best-effort modules failed, and a
+/// per-subsystem code from [`CloudInitError::exit_code`] on a fatal error
+/// or a failed required module.
 #[tokio::main]
-async fn main() -> Result<(), CloudInitError> {
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+async fn run() -> Result<RunSummary, CloudInitError> {
     let cli = Cli::parse();
-    init_logging(cli.verbose);
 
-    match cli.command {
+    if cli.version {
+        if cli.long {
+            print!("{}", cloud_init_rs::features::long_version());
+        } else {
+            println!("cloud-init-rs {}", cloud_init_rs::features::VERSION);
+        }
+        return Ok(RunSummary::default());
+    }
+
+    let mut builder = CloudInit::builder()
+        .dry_run(cli.dry_run)
+        .offline(cli.offline);
+    if let Some(root) = cli.root {
+        builder = builder.root(root);
+    }
+    let cloud_init = builder.build()?;
+
+    // Dry runs leave the log files alone, like everything else
+    let runs_stages = matches!(
+        cli.command,
+        None | Some(
+            Commands::Init
+                | Commands::Local
+                | Commands::Network
+                | Commands::Config
+                | Commands::Final
+                | Commands::Daemon
+        )
+    );
+    // Logging settings, queries and the like come from below the chosen
+    // root too
+    let result = cloud_init
+        .scope(async {
+            init_logging(cli.verbose, runs_stages && !cli.dry_run).await;
+            let root = cloud_init.root();
+            if !root.is_host() {
+                info!("Using alternate root {}", root.root().display());
+            }
+            Box::pin(run_command(&cloud_init, cli.command)).await
+        })
+        .await;
+
+    // The plan is printed even if a stage failed, covering what ran before
+    if let Some(plan) = cloud_init.plan() {
+        match cli.plan_format {
+            PlanFormat::Text => print!("{}", plan),
+            PlanFormat::Json => println!("{}", plan.to_json()?),
+        }
+    }
+
+    result
+}
+
+/// Hand `stage` to the daemon if one is running, else run it here
+async fn run_stage(cloud_init: &CloudInit, stage: Stage) -> Result<RunSummary, CloudInitError> {
+    match cloud_init_rs::daemon::trigger(cloud_init.root(), stage).await? {
+        Some(summary) => Ok(summary),
+        None => cloud_init.run(stage).await,
+    }
+}
+
+async fn run_command(
+    cloud_init: &CloudInit,
+    command: Option<Commands>,
+) -> Result<RunSummary, CloudInitError> {
+    match command {
         Some(Commands::Init) => {
             info!("Running all cloud-init stages");
-            run_stages(&[Stage::Local, Stage::Network, Stage::Config, Stage::Final]).await?;
+            return cloud_init
+                .run_stages(&[Stage::Local, Stage::Network, Stage::Config, Stage::Final])
+                .await;
         }
         Some(Commands::Local) => {
             info!("Running local stage");
-            run_stages(&[Stage::Local]).await?;
+            return run_stage(cloud_init, Stage::Local).await;
         }
         Some(Commands::Network) => {
             info!("Running network stage");
-            run_stages(&[Stage::Network]).await?;
+            return run_stage(cloud_init, Stage::Network).await;
         }
         Some(Commands::Config) => {
             info!("Running config stage");
-            run_stages(&[Stage::Config]).await?;
+            return run_stage(cloud_init, Stage::Config).await;
         }
         Some(Commands::Final) => {
             info!("Running final stage");
-            run_stages(&[Stage::Final]).await?;
+            return run_stage(cloud_init, Stage::Final).await;
+        }
+        Some(Commands::Daemon) => {
+            info!("Starting daemon");
+            cloud_init_rs::daemon::serve(cloud_init).await?;
         }
         Some(Commands::Query { key }) => {
             info!("Querying metadata key: {}", key);
-            // TODO: Implement metadata query
-            println!("Query not yet implemented for key: {}", key);
+            let data = cloud_init_rs::query::instance_data().await?;
+            let value = cloud_init_rs::query::lookup(&data, &key).ok_or_else(|| {
+                CloudInitError::InvalidData(format!("No instance data at {}", key))
+            })?;
+            println!("{}", cloud_init_rs::query::render(value));
         }
-        Some(Commands::Clean { logs }) => {
-            info!("Cleaning cloud-init artifacts (logs: {})", logs);
-            // TODO: Implement clean
-            println!("Clean not yet implemented");
+        Some(Commands::ServeMetadata { socket, listen }) => {
+            let socket =
+                socket.unwrap_or_else(|| cloud_init.root().cloud_paths().metadata_socket());
+            let data = cloud_init_rs::query::instance_data().await?;
+            cloud_init_rs::query::serve(data, &socket, listen).await?;
+        }
+        Some(Commands::Clean { logs, seal }) => {
+            info!(
+                "Cleaning cloud-init artifacts (logs: {}, seal: {})",
+                logs, seal
+            );
+            let options = cloud_init_rs::clean::CleanOptions { logs, seal };
+            let cleaned = cloud_init_rs::clean::clean(cloud_init.root(), options).await?;
+            for path in &cleaned {
+                debug!("Cleaned {}", path.display());
+            }
+            println!("Cleaned {} paths", cleaned.len());
         }
         Some(Commands::Status) => {
             info!("Checking cloud-init status");
-            // TODO: Implement status
-            println!("Status not yet implemented");
+            let root = cloud_init.root();
+            match cloud_init_rs::disabled::check(root).await {
+                Some(reason) => println!("status: disabled\ndetail: cloud-init is {}", reason),
+                None => {
+                    let reporter =
+                        cloud_init_rs::state::BootReporter::load(root.cloud_paths()).await;
+                    println!("status: {}", reporter.status().summary());
+                    for warning in reporter.status().warnings() {
+                        println!("warning: {}", warning);
+                    }
+                }
+            }
+        }
+        Some(Commands::CloudId) => {
+            let paths = cloud_init.root().cloud_paths();
+            println!("{}", cloud_init_rs::state::cloud_id::read(&paths).await?);
+        }
+        Some(Commands::CollectLogs {
+            tarfile,
+            include_userdata,
+        }) => {
+            use cloud_init_rs::collect_logs::{CollectOptions, collect_logs};
+
+            let options = CollectOptions { include_userdata };
+            collect_logs(cloud_init.root(), &tarfile, options).await?;
+            println!("Wrote {}", tarfile.display());
+        }
+        Some(Commands::Features { json }) => {
+            let features = cloud_init_rs::features::compiled_features();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&features)?);
+            } else {
+                print!("{}", features);
+            }
+        }
+        Some(Commands::Analyze { infile, action }) => {
+            use cloud_init_rs::analyze;
+
+            let content = tokio::fs::read_to_string(&infile)
+                .await
+                .with_path(&infile)?;
+            let events = analyze::parse_events(&content);
+            let output = match action {
+                AnalyzeAction::Show => analyze::show(&events),
+                AnalyzeAction::Blame => analyze::blame(&events),
+                AnalyzeAction::Boot => {
+                    let btime = tokio::fs::read_to_string("/proc/stat")
+                        .await
+                        .ok()
+                        .and_then(|s| analyze::parse_btime(&s));
+                    analyze::boot(&events, btime)
+                }
+            };
+            print!("{}", output);
+        }
+        Some(Commands::Devel {
+            action: DevelAction::HotplugHook { subsystem, action },
+        }) => {
+            use cloud_init_rs::network::hotplug;
+
+            if subsystem != "net" {
+                return Err(CloudInitError::Config(format!(
+                    "Unsupported hotplug subsystem '{}'",
+                    subsystem
+                )));
+            }
+            match action {
+                HotplugHookAction::Query => {
+                    let enabled = hotplug::is_enabled().await;
+                    println!("{}", if enabled { "enabled" } else { "disabled" });
+                }
+                HotplugHookAction::Handle {
+                    udevaction,
+                    interface,
+                } => hotplug::handle(udevaction.parse()?, &interface).await?,
+                HotplugHookAction::Listen => hotplug::listen().await?,
+            }
+        }
+        Some(Commands::Devel {
+            action:
+                DevelAction::Render {
+                    template,
+                    instance_data,
+                    undefined,
+                },
+        }) => {
+            use cloud_init_rs::template;
+
+            let content = tokio::fs::read_to_string(&template)
+                .await
+                .with_path(&template)?;
+            if !template::is_jinja_template(&content) {
+                return Err(CloudInitError::InvalidData(format!(
+                    "{} has no '## template: jinja' header and would be used as is",
+                    template.display()
+                )));
+            }
+            let facts = template::SystemFacts::detect(cloud_init.root()).await;
+            let conf = load_merged_config(&CloudPaths::new())
+                .await
+                .unwrap_or_default();
+            let context = match instance_data {
+                Some(path) => {
+                    let data = tokio::fs::read(&path).await.with_path(&path)?;
+                    let mut context =
+                        template::context_from_instance_data(&serde_json::from_slice(&data)?)?;
+                    template::add_system_facts(&mut context, &facts, &conf);
+                    context
+                }
+                None => {
+                    let ds = cloud_init_rs::datasources::cache::current_datasource().await?;
+                    template::build_system_context(&ds.get_metadata().await?, &facts, &conf)
+                }
+            };
+            let mut renderer = template::TemplateRenderer::new().with_undefined(undefined);
+            renderer.add_vars(context);
+            // The renderer drops the template's final newline
+            println!("{}", renderer.render(&content)?);
+        }
+        Some(Commands::SystemdGenerate { dirs, units_dir }) => {
+            if let Some(dir) = units_dir {
+                cloud_init_rs::systemd::write_units(&dir).await?;
+            } else {
+                let normal_dir = dirs.first().ok_or_else(|| {
+                    CloudInitError::Config("systemd-generate requires an output directory".into())
+                })?;
+                let cmdline = tokio::fs::read_to_string(cloud_init_rs::systemd::KERNEL_CMDLINE)
+                    .await
+                    .unwrap_or_default();
+                cloud_init_rs::systemd::run_generator(normal_dir, &cmdline).await?;
+            }
         }
         None => {
             info!("No command specified, running init");
-            run_stages(&[Stage::Local, Stage::Network, Stage::Config, Stage::Final]).await?;
+            return cloud_init
+                .run_stages(&[Stage::Local, Stage::Network, Stage::Config, Stage::Final])
+                .await;
         }
     }
 
-    Ok(())
+    Ok(RunSummary::default())
 }
@@ -0,0 +1,270 @@
+//! Typed access to provider-specific metadata
+//!
+//! [`InstanceMetadata`] has fields for what every cloud provides. The rest
+//! stays in [`InstanceMetadata::raw`] in the layout of the provider's
+//! metadata service, and the accessors here know where each provider
+//! keeps it:
+//!
+//! | Accessor | EC2 | GCE | Azure | OpenStack | NoCloud |
+//! |----------|-----|-----|-------|-----------|---------|
+//! | `tags` | `tags.instance` | `tags` (values empty) | `tagsList` | `meta` | `tags` |
+//! | `security_groups` | `security-groups` | - | - | - | - |
+//! | `public_keys` | `public-keys` | `attributes.ssh-keys` | `publicKeys` | `public_keys` | `public-keys` |
+//! | `network_data` | - | `networkInterfaces` | `network` | - | - |
+//! | `is_preemptible` | `instance-life-cycle` | `scheduling` | `priority` | - | - |
+//! | `block_device_mapping` | `block-device-mapping` | - | - | - | - |
+//!
+//! Azure paths are below `compute` except `network`.
+
+use crate::InstanceMetadata;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+impl InstanceMetadata {
+    /// Value at a `/`-separated path in the raw metadata
+    ///
+    /// Path segments index objects by key and arrays by position, e.g.
+    /// `compute/tagsList/0/name`.
+    pub fn raw_get(&self, path: &str) -> Option<&Value> {
+        path.split('/')
+            .filter(|segment| !segment.is_empty())
+            .try_fold(&self.raw, |value, segment| match value {
+                Value::Object(map) => map.get(segment),
+                Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+                _ => None,
+            })
+    }
+
+    /// Instance tags or labels
+    pub fn tags(&self) -> BTreeMap<String, String> {
+        let mut tags = BTreeMap::new();
+        match self.platform.as_deref() {
+            Some("gce") => {
+                for tag in self.raw_strings("tags") {
+                    tags.insert(tag, String::new());
+                }
+            }
+            Some("azure") => {
+                for tag in self.raw_array("compute/tagsList") {
+                    if let (Some(name), Some(value)) = (
+                        tag.get("name").and_then(Value::as_str),
+                        tag.get("value").and_then(Value::as_str),
+                    ) {
+                        tags.insert(name.to_string(), value.to_string());
+                    }
+                }
+            }
+            platform => {
+                let path = match platform {
+                    Some("ec2") => "tags/instance",
+                    Some("openstack") => "meta",
+                    _ => "tags",
+                };
+                if let Some(Value::Object(map)) = self.raw_get(path) {
+                    for (key, value) in map {
+                        if let Some(value) = scalar(value) {
+                            tags.insert(key.clone(), value);
+                        }
+                    }
+                }
+            }
+        }
+        tags
+    }
+
+    /// Names of the security groups the instance is in
+    pub fn security_groups(&self) -> Vec<String> {
+        match self.platform.as_deref() {
+            Some("ec2") => self.raw_strings("security-groups"),
+            _ => Vec::new(),
+        }
+    }
+
+    /// SSH public keys the platform provides for the instance
+    pub fn public_keys(&self) -> Vec<String> {
+        match self.platform.as_deref() {
+            Some("gce") => {
+                // `user:ssh-rsa AAAA... comment` lines
+                let Some(keys) = self.raw_get("attributes/ssh-keys").and_then(Value::as_str) else {
+                    return Vec::new();
+                };
+                keys.lines()
+                    .filter_map(|line| line.split_once(':').map(|(_, key)| key.trim()))
+                    .filter(|key| !key.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }
+            Some("azure") => self
+                .raw_array("compute/publicKeys")
+                .iter()
+                .filter_map(|key| key.get("keyData").and_then(Value::as_str))
+                .map(|key| key.trim().to_string())
+                .collect(),
+            Some("openstack") => match self.raw_get("public_keys") {
+                Some(Value::Object(keys)) => keys
+                    .values()
+                    .filter_map(Value::as_str)
+                    .map(|key| key.trim().to_string())
+                    .collect(),
+                _ => Vec::new(),
+            },
+            _ => self.raw_strings("public-keys"),
+        }
+    }
+
+    /// The provider's description of the instance's network interfaces
+    pub fn network_data(&self) -> Option<&Value> {
+        match self.platform.as_deref() {
+            Some("gce") => self.raw_get("networkInterfaces"),
+            Some("azure") => self.raw_get("network"),
+            _ => None,
+        }
+    }
+
+    /// Whether the instance is spot/preemptible capacity that the
+    /// provider may reclaim
+    pub fn is_preemptible(&self) -> bool {
+        let text = |path| self.raw_get(path).and_then(Value::as_str);
+        match self.platform.as_deref() {
+            Some("ec2") => text("instance-life-cycle") == Some("spot"),
+            Some("gce") => {
+                text("scheduling/preemptible") == Some("TRUE")
+                    || text("scheduling/provisioningModel") == Some("SPOT")
+            }
+            Some("azure") => matches!(text("compute/priority"), Some("Spot" | "Low")),
+            _ => false,
+        }
+    }
+
+    /// Block devices by name (`ami`, `ephemeral0`, `swap`), as the
+    /// provider names the device, e.g. `sdb`
+    pub fn block_device_mapping(&self) -> BTreeMap<String, String> {
+        let mut mapping = BTreeMap::new();
+        if self.platform.as_deref() == Some("ec2")
+            && let Some(Value::Object(map)) = self.raw_get("block-device-mapping")
+        {
+            for (name, device) in map {
+                if let Some(device) = scalar(device) {
+                    mapping.insert(name.clone(), device);
+                }
+            }
+        }
+        mapping
+    }
+
+    fn raw_array(&self, path: &str) -> &[Value] {
+        match self.raw_get(path) {
+            Some(Value::Array(items)) => items,
+            _ => &[],
+        }
+    }
+
+    /// Strings at `path`, given either as an array or as one string
+    /// (multiple lines for EC2-style listings)
+    fn raw_strings(&self, path: &str) -> Vec<String> {
+        match self.raw_get(path) {
+            Some(Value::String(s)) => s
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect(),
+            Some(Value::Array(items)) => items.iter().filter_map(scalar).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn metadata(platform: &str, raw: Value) -> InstanceMetadata {
+        InstanceMetadata {
+            platform: Some(platform.to_string()),
+            raw,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_ec2_accessors() {
+        let ec2 = metadata(
+            "ec2",
+            json!({
+                "security-groups": "default\nweb\n",
+                "public-keys": ["ssh-ed25519 AAAA ops"],
+                "instance-life-cycle": "spot",
+                "tags": {"instance": {"role": "web"}},
+                "block-device-mapping": {"ami": "/dev/xvda", "ephemeral0": "sdb"},
+            }),
+        );
+        assert_eq!(ec2.security_groups(), vec!["default", "web"]);
+        assert_eq!(ec2.public_keys(), vec!["ssh-ed25519 AAAA ops"]);
+        assert!(ec2.is_preemptible());
+        assert_eq!(ec2.tags()["role"], "web");
+        assert_eq!(ec2.raw_get("tags/instance/role"), Some(&json!("web")));
+        assert!(ec2.network_data().is_none());
+        assert_eq!(ec2.block_device_mapping()["ephemeral0"], "sdb");
+    }
+
+    #[test]
+    fn test_gce_and_azure_accessors() {
+        let gce = metadata(
+            "gce",
+            json!({
+                "tags": ["http-server"],
+                "attributes": {"ssh-keys": "alice:ssh-rsa AAAA alice\nbob:ssh-ed25519 BBBB bob"},
+                "scheduling": {"preemptible": "FALSE", "provisioningModel": "SPOT"},
+                "networkInterfaces": [{"ip": "10.0.0.2"}],
+            }),
+        );
+        assert_eq!(gce.tags().keys().collect::<Vec<_>>(), vec!["http-server"]);
+        assert_eq!(
+            gce.public_keys(),
+            vec!["ssh-rsa AAAA alice", "ssh-ed25519 BBBB bob"]
+        );
+        assert!(gce.is_preemptible());
+        assert_eq!(
+            gce.network_data().and_then(|n| n.get(0)),
+            Some(&json!({"ip": "10.0.0.2"}))
+        );
+
+        let azure = metadata(
+            "azure",
+            json!({
+                "compute": {
+                    "tagsList": [{"name": "env", "value": "prod"}],
+                    "publicKeys": [{"keyData": "ssh-rsa CCCC\r\n", "path": "/home/azureuser/.ssh/authorized_keys"}],
+                    "priority": "Regular",
+                },
+            }),
+        );
+        assert_eq!(azure.tags()["env"], "prod");
+        assert_eq!(azure.public_keys(), vec!["ssh-rsa CCCC"]);
+        assert!(!azure.is_preemptible());
+        assert_eq!(
+            azure.raw_get("compute/tagsList/0/name"),
+            Some(&json!("env"))
+        );
+    }
+
+    #[test]
+    fn test_missing_raw_metadata() {
+        let empty = InstanceMetadata::default();
+        assert!(empty.raw_get("anything").is_none());
+        assert!(empty.tags().is_empty());
+        assert!(empty.public_keys().is_empty());
+        assert!(!empty.is_preemptible());
+    }
+}
@@ -0,0 +1,169 @@
+//! Ansible module
+//!
+//! Implements the `ansible` cloud-config key: installs Ansible from
+//! distro packages or pip, then runs `ansible-pull` to check out a
+//! repository and apply a playbook from it.
+//!
+//! A playbook run can take a while, so its output is streamed to the
+//! output log as it is produced. A failed run fails the module, which
+//! records the exit status among the errors in `result.json`.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! ansible:
+//!   install_method: pip
+//!   pull:
+//!     url: https://github.com/example/playbooks.git
+//!     checkout: main
+//!     playbook_name: site.yml
+//! ```
+
+use crate::CloudInitError;
+use crate::config::{AnsibleConfig, AnsiblePullConfig};
+use crate::modules::packages;
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use tracing::{debug, info};
+
+/// Install Ansible and run `ansible-pull`
+pub async fn configure_ansible(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &AnsibleConfig,
+) -> Result<(), CloudInitError> {
+    install(runner, root, config).await?;
+
+    let Some(pull) = &config.pull else {
+        return Ok(());
+    };
+    if !root.is_host() {
+        debug!("Not running ansible-pull for an alternate root");
+        return Ok(());
+    }
+
+    let command = pull_command(pull, config.run_user.as_deref());
+    info!("Running {}", command);
+    let output = runner.run(&command.stream()).await?;
+    if !output.is_success() {
+        return Err(CloudInitError::Command(format!(
+            "ansible-pull exited with status {}",
+            output
+                .code
+                .map_or_else(|| "unknown".to_string(), |c| c.to_string())
+        )));
+    }
+    Ok(())
+}
+
+/// Install Ansible unless `ansible-pull` is already present
+async fn install(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &AnsibleConfig,
+) -> Result<(), CloudInitError> {
+    if packages::command_exists(runner, "ansible-pull").await {
+        debug!("ansible-pull already installed");
+        return Ok(());
+    }
+    let package = config.package_name.as_deref().unwrap_or("ansible-core");
+    match config.install_method.as_deref().unwrap_or("distro") {
+        "distro" => packages::install_package(runner, root, package).await,
+        "pip" => {
+            let command = SystemCommand::new("python3").args(["-m", "pip", "install", package]);
+            let output = runner.run(&crate::http::with_proxy_env(command)).await?;
+            if !output.is_success() {
+                return Err(CloudInitError::Command(format!(
+                    "pip install {} failed: {}",
+                    package,
+                    output.stderr.trim()
+                )));
+            }
+            Ok(())
+        }
+        other => Err(CloudInitError::InvalidData(format!(
+            "Unknown ansible install_method: {}",
+            other
+        ))),
+    }
+}
+
+/// Build the `ansible-pull` invocation (pure function for testability)
+fn pull_command(pull: &AnsiblePullConfig, run_user: Option<&str>) -> SystemCommand {
+    let mut command = match run_user {
+        Some(user) => SystemCommand::new("sudo").args(["-H", "-u", user, "ansible-pull"]),
+        None => SystemCommand::new("ansible-pull"),
+    };
+    command = command.args(["--url", &pull.url]);
+    if let Some(checkout) = &pull.checkout {
+        command = command.args(["--checkout", checkout]);
+    }
+    for (flag, set) in [
+        ("--accept-host-key", pull.accept_host_key),
+        ("--clean", pull.clean),
+        ("--full", pull.full),
+        ("--diff", pull.diff),
+    ] {
+        if set == Some(true) {
+            command = command.arg(flag);
+        }
+    }
+    if let Some(key) = &pull.private_key {
+        command = command.args(["--private-key", key]);
+    }
+    if let Some(file) = &pull.vault_password_file {
+        command = command.args(["--vault-password-file", file]);
+    }
+    command.arg(&pull.playbook_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+
+    fn config(yaml: &str) -> AnsibleConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_pull_command() {
+        let config = config(
+            "pull: {url: 'https://example.com/pb.git', playbook_name: site.yml, checkout: main, clean: true}",
+        );
+        let pull = config.pull.unwrap();
+        assert_eq!(
+            pull_command(&pull, None).to_string(),
+            "ansible-pull --url https://example.com/pb.git --checkout main --clean site.yml"
+        );
+        assert!(
+            pull_command(&pull, Some("deploy"))
+                .to_string()
+                .starts_with("sudo -H -u deploy ansible-pull --url")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pip_install_and_failed_pull() {
+        let runner = RecordingRunner::new()
+            .with_response("which ansible-pull", CommandOutput::failure(1, ""))
+            .with_response("ansible-pull", CommandOutput::failure(4, "unreachable"));
+        let config = config(
+            "{install_method: pip, pull: {url: 'https://example.com/pb.git', playbook_name: site.yml}}",
+        );
+
+        let err = configure_ansible(&runner, &RootContext::host(), &config)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Command execution failed: ansible-pull exited with status 4"
+        );
+        let calls = runner.calls();
+        assert_eq!(
+            runner.commands()[..2],
+            ["which ansible-pull", "python3 -m pip install ansible-core"]
+        );
+        assert!(calls[2].stream);
+    }
+}
@@ -0,0 +1,205 @@
+//! Byobu module
+//!
+//! Implements the `byobu_by_default` cloud-config key. The `-user` values
+//! run `byobu-launcher-install` (or `-uninstall`) as the default user; the
+//! `-system` values preseed `byobu/launch-by-default` and reconfigure the
+//! package. As in Python cloud-init, enabling always sets the system
+//! default too, and plain `enable`/`disable` change both.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! byobu_by_default: enable-user
+//! ```
+
+use crate::CloudInitError;
+use crate::modules::packages;
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use tracing::{info, warn};
+
+/// What a `byobu_by_default` value changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Launch {
+    enable: bool,
+    user: bool,
+    system: bool,
+}
+
+impl Launch {
+    /// Parse a `byobu_by_default` value
+    fn parse(value: &str) -> Result<Self, CloudInitError> {
+        let value = match value {
+            "user" | "system" => format!("enable-{}", value),
+            other => other.to_string(),
+        };
+        let (enable, scope) = match value.split_once('-') {
+            Some((action, scope)) => (action, Some(scope)),
+            None => (value.as_str(), None),
+        };
+        let enable = match enable {
+            "enable" => true,
+            "disable" => false,
+            _ => return Err(invalid(&value)),
+        };
+        let (user, system) = match scope {
+            Some("user") => (true, enable),
+            Some("system") => (false, true),
+            None => (!enable, true),
+            Some(_) => return Err(invalid(&value)),
+        };
+        Ok(Self {
+            enable,
+            user,
+            system,
+        })
+    }
+}
+
+fn invalid(value: &str) -> CloudInitError {
+    CloudInitError::InvalidData(format!("Invalid byobu_by_default value: {}", value))
+}
+
+/// Enable or disable byobu at login
+///
+/// `default_user` is the default user's name, if the config creates one;
+/// without it the `-user` part is skipped.
+pub async fn configure_byobu(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    value: &str,
+    default_user: Option<&str>,
+) -> Result<(), CloudInitError> {
+    let launch = Launch::parse(value)?;
+
+    if launch.enable && !packages::command_exists(runner, "byobu").await {
+        packages::install_package(runner, root, "byobu").await?;
+    }
+
+    let action = if launch.enable {
+        "install"
+    } else {
+        "uninstall"
+    };
+    if launch.user {
+        match default_user {
+            Some(user) => {
+                run(
+                    runner,
+                    SystemCommand::new("sudo").args([
+                        "-Hu".to_string(),
+                        user.to_string(),
+                        format!("byobu-launcher-{}", action),
+                    ]),
+                )
+                .await?;
+            }
+            None => warn!("No default user for byobu_by_default; skipping the user launcher"),
+        }
+    }
+
+    if launch.system {
+        let selection = format!("byobu byobu/launch-by-default boolean {}\n", launch.enable);
+        run(
+            runner,
+            SystemCommand::new("debconf-set-selections").stdin(selection),
+        )
+        .await?;
+        run(
+            runner,
+            SystemCommand::new("dpkg-reconfigure").args(["byobu", "--frontend=noninteractive"]),
+        )
+        .await?;
+    }
+
+    info!("Applied byobu_by_default: {}", value);
+    Ok(())
+}
+
+async fn run(runner: &dyn SystemRunner, command: SystemCommand) -> Result<(), CloudInitError> {
+    let output = runner.run(&command).await?;
+    if !output.is_success() {
+        return Err(CloudInitError::Module {
+            module: "byobu_by_default".to_string(),
+            message: format!("{} failed: {}", command.program, output.stderr.trim()),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_values() {
+        let launch = |value| Launch::parse(value).unwrap();
+        let expect = |enable, user, system| Launch {
+            enable,
+            user,
+            system,
+        };
+        assert_eq!(launch("enable-user"), expect(true, true, true));
+        assert_eq!(launch("user"), expect(true, true, true));
+        assert_eq!(launch("enable-system"), expect(true, false, true));
+        assert_eq!(launch("system"), expect(true, false, true));
+        assert_eq!(launch("enable"), expect(true, false, true));
+        assert_eq!(launch("disable-user"), expect(false, true, false));
+        assert_eq!(launch("disable-system"), expect(false, false, true));
+        assert_eq!(launch("disable"), expect(false, true, true));
+        assert!(Launch::parse("sometimes").is_err());
+        assert!(Launch::parse("enable-everyone").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_enable_user_launches_for_default_user() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let runner = RecordingRunner::new();
+        configure_byobu(&runner, &root, "enable-user", Some("ubuntu"))
+            .await
+            .unwrap();
+
+        let calls = runner.calls();
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "which byobu",
+                "sudo -Hu ubuntu byobu-launcher-install",
+                "debconf-set-selections",
+                "dpkg-reconfigure byobu --frontend=noninteractive",
+            ]
+        );
+        assert_eq!(
+            calls[2].stdin.as_deref(),
+            Some("byobu byobu/launch-by-default boolean true\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disable_user_without_default_user() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let runner = RecordingRunner::new();
+        configure_byobu(&runner, &root, "disable-user", None)
+            .await
+            .unwrap();
+        assert!(runner.commands().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failure_is_error() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let runner = RecordingRunner::new().with_response(
+            "dpkg-reconfigure",
+            CommandOutput::failure(1, "package byobu is not installed"),
+        );
+        let err = configure_byobu(&runner, &root, "disable-system", None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not installed"));
+    }
+}
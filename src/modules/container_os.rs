@@ -0,0 +1,394 @@
+//! Container-optimized OS compatibility
+//!
+//! Flatcar and Fedora CoreOS have no package manager to install
+//! `packages` with, and expect work to be done by systemd units rather
+//! than at provisioning time. On them, `runcmd` is written to a script
+//! and `packages` and the script run from a one-shot unit,
+//! `cloud-init-rs-compat.service`, once per instance after the final
+//! stage. So the same user-data can serve Ubuntu and Flatcar nodes:
+//!
+//! - Flatcar: `packages` name official system extensions, enabled in
+//!   `/etc/flatcar/enabled-sysext.conf` and merged by the unit.
+//! - Fedora CoreOS: `packages` are layered with `rpm-ostree install
+//!   --apply-live`.
+//!
+//! `write_files` and the other file-based modules work as usual. Keys
+//! that need a package manager are reported and otherwise ignored.
+
+use crate::CloudInitError;
+use crate::config::{CloudConfig, ErrorHandlingMode, RunCmd};
+use crate::distro::Distro;
+use crate::immutable::ImmutableRoot;
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use crate::service::ServiceManager;
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+/// The generated unit
+pub const COMPAT_UNIT: &str = "cloud-init-rs-compat.service";
+
+/// Directory the unit is written to
+const UNIT_DIR: &str = "/etc/systemd/system";
+
+/// `runcmd` as a script, in the current instance's directory
+const RUNCMD_SCRIPT: &str = "/var/lib/cloud/instance/scripts/runcmd";
+
+/// Created when the unit has run for the current instance
+const DONE_MARKER: &str = "/var/lib/cloud/instance/compat-unit-finished";
+
+/// Official system extensions Flatcar enables at boot
+const FLATCAR_SYSEXT_CONF: &str = "/etc/flatcar/enabled-sysext.conf";
+
+/// Default shell of the runcmd script
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// Container-optimized operating system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContainerOs {
+    Flatcar,
+    FedoraCoreOs,
+}
+
+impl ContainerOs {
+    /// The container-optimized OS installed under `root`, if it is one
+    pub async fn detect(root: &RootContext) -> Option<Self> {
+        match ImmutableRoot::detect(root).await? {
+            ImmutableRoot::Flatcar => Some(Self::Flatcar),
+            ImmutableRoot::Ostree if Distro::detect(root).await.variant_id == "coreos" => {
+                Some(Self::FedoraCoreOs)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ContainerOs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Flatcar => write!(f, "Flatcar"),
+            Self::FedoraCoreOs => write!(f, "Fedora CoreOS"),
+        }
+    }
+}
+
+/// Cloud-config keys that are set but cannot be applied on a
+/// container-optimized OS
+pub fn unsupported_keys(config: &CloudConfig) -> Vec<&'static str> {
+    [
+        ("package_update", config.package_update == Some(true)),
+        ("package_upgrade", config.package_upgrade == Some(true)),
+        ("yum_repos", !config.yum_repos.is_empty()),
+        ("zypper", config.zypper.is_some()),
+        ("rh_subscription", config.rh_subscription.is_some()),
+        ("suse_register", config.suse_register.is_some()),
+        ("ubuntu_pro", config.ubuntu_pro.is_some()),
+        ("landscape", config.landscape.is_some()),
+        ("grub_dpkg", config.grub_dpkg.is_some()),
+        ("byobu_by_default", config.byobu_by_default.is_some()),
+        ("fan", config.fan.is_some()),
+        ("chef", config.chef.is_some()),
+        ("puppet", config.puppet.is_some()),
+        ("salt_minion", config.salt_minion.is_some()),
+        ("ansible", config.ansible.is_some()),
+    ]
+    .into_iter()
+    .filter(|(_, set)| *set)
+    .map(|(key, _)| key)
+    .collect()
+}
+
+/// Turn `packages` and `runcmd` into the compat unit and start it
+pub async fn configure_container_os(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    os: ContainerOs,
+    config: &CloudConfig,
+) -> Result<(), CloudInitError> {
+    for key in unsupported_keys(config) {
+        warn!("{} is not supported on {}; ignoring it", key, os);
+    }
+
+    let packages = valid_packages(&config.packages);
+    if os == ContainerOs::Flatcar && !packages.is_empty() {
+        enable_sysexts(root, &packages).await?;
+    }
+    let runcmd = render_runcmd_script(config);
+    let Some(unit) = render_unit(os, &packages, runcmd.is_some()) else {
+        debug!("No packages or runcmd for {}", COMPAT_UNIT);
+        return Ok(());
+    };
+
+    if let Some(script) = runcmd {
+        let path = root.path(RUNCMD_SCRIPT);
+        if let Some(dir) = path.parent() {
+            root.create_dir_all(dir).await?;
+        }
+        root.write_file_mode(&path, script, 0o700).await?;
+    }
+    let unit_dir = root.path(UNIT_DIR);
+    root.create_dir_all(&unit_dir).await?;
+    root.write_file(&unit_dir.join(COMPAT_UNIT), unit).await?;
+    info!("Wrote {} for {}", COMPAT_UNIT, os);
+
+    let mut commands = vec![ServiceManager::Systemd.enable(COMPAT_UNIT)];
+    if root.is_host() {
+        // The unit is ordered after the final stage, so waiting for it
+        // here would never finish
+        commands.push(SystemCommand::new("systemctl").args(["start", "--no-block", COMPAT_UNIT]));
+    }
+    for command in commands {
+        let output = runner.run(&command).await?;
+        if !output.is_success() {
+            return Err(CloudInitError::Command(format!(
+                "{} failed: {}",
+                command,
+                output.stderr.trim()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Packages whose names can be passed on safely, warning about the rest
+fn valid_packages(packages: &[String]) -> Vec<String> {
+    packages
+        .iter()
+        .filter(|name| {
+            let valid = !name.is_empty()
+                && !name.starts_with('-')
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._+-:".contains(c));
+            if !valid {
+                warn!("Ignoring invalid package name {:?}", name);
+            }
+            valid
+        })
+        .cloned()
+        .collect()
+}
+
+/// Add `packages` to Flatcar's enabled system extensions
+async fn enable_sysexts(root: &RootContext, packages: &[String]) -> Result<(), CloudInitError> {
+    let path = root.path(FLATCAR_SYSEXT_CONF);
+    let existing = fs::read_to_string(&path).await.unwrap_or_default();
+    let missing: Vec<&String> = packages
+        .iter()
+        .filter(|name| !existing.lines().any(|line| line.trim() == name.as_str()))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let mut content = existing;
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    for name in missing {
+        content.push_str(&format!("{}\n", name));
+    }
+    if let Some(dir) = path.parent() {
+        root.create_dir_all(dir).await?;
+    }
+    root.write_file(&path, content).await?;
+    info!("Enabled system extensions in {}", FLATCAR_SYSEXT_CONF);
+    Ok(())
+}
+
+/// `runcmd` as a shell script, if there is any
+fn render_runcmd_script(config: &CloudConfig) -> Option<String> {
+    if config.runcmd.is_empty() {
+        return None;
+    }
+    let runcmd_config = config.runcmd_config.as_ref();
+    let shell = runcmd_config
+        .and_then(|c| c.shell.as_deref())
+        .unwrap_or(DEFAULT_SHELL);
+    let abort =
+        runcmd_config.and_then(|c| c.error_handling.as_ref()) == Some(&ErrorHandlingMode::Abort);
+
+    let mut script = format!("#!{}\n# Generated by cloud-init-rs from runcmd\n", shell);
+    if abort {
+        script.push_str("set -e\n");
+    }
+    for command in &config.runcmd {
+        match command {
+            RunCmd::Shell(line) => script.push_str(line),
+            RunCmd::Args(args) => {
+                let args: Vec<String> = args.iter().map(|arg| quote(arg)).collect();
+                script.push_str(&args.join(" "));
+            }
+        }
+        script.push('\n');
+    }
+    Some(script)
+}
+
+/// The compat unit, if there is anything for it to do
+fn render_unit(os: ContainerOs, packages: &[String], runcmd: bool) -> Option<String> {
+    let mut exec = Vec::new();
+    if !packages.is_empty() {
+        match os {
+            ContainerOs::Flatcar => {
+                exec.push("ExecStartPre=/usr/bin/systemctl restart ensure-sysext.service".into())
+            }
+            ContainerOs::FedoraCoreOs => exec.push(format!(
+                "ExecStartPre=/usr/bin/rpm-ostree install --idempotent --apply-live --allow-inactive {}",
+                packages.join(" ")
+            )),
+        }
+    }
+    if runcmd {
+        exec.push(format!("ExecStart={}", RUNCMD_SCRIPT));
+    }
+    if exec.is_empty() {
+        return None;
+    }
+    exec.push(format!("ExecStartPost=/usr/bin/touch {}", DONE_MARKER));
+
+    Some(format!(
+        "# Generated by cloud-init-rs from packages and runcmd\n\
+         [Unit]\n\
+         Description=cloud-init-rs packages and runcmd\n\
+         Wants=network-online.target\n\
+         After=network-online.target cloud-final.service\n\
+         ConditionPathExists=!{}\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         RemainAfterExit=yes\n\
+         {}\n\
+         \n\
+         [Install]\n\
+         WantedBy=multi-user.target\n",
+        DONE_MARKER,
+        exec.join("\n")
+    ))
+}
+
+/// Quote an argument for the shell
+fn quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:/+@,=".contains(c))
+    {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::RecordingRunner;
+    use tempfile::TempDir;
+
+    fn config(yaml: &str) -> CloudConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn root_with_os_release(content: &str) -> (TempDir, RootContext) {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        std::fs::create_dir_all(root.path("/etc")).unwrap();
+        std::fs::write(root.path("/etc/os-release"), content).unwrap();
+        (temp, root)
+    }
+
+    #[tokio::test]
+    async fn test_detect() {
+        let (_temp, root) = root_with_os_release("ID=flatcar\n");
+        assert_eq!(ContainerOs::detect(&root).await, Some(ContainerOs::Flatcar));
+
+        let (_temp, root) = root_with_os_release("ID=fedora\nVARIANT_ID=coreos\n");
+        assert_eq!(ContainerOs::detect(&root).await, None);
+        std::fs::create_dir_all(root.path("/sysroot/ostree")).unwrap();
+        assert_eq!(
+            ContainerOs::detect(&root).await,
+            Some(ContainerOs::FedoraCoreOs)
+        );
+
+        let (_temp, root) = root_with_os_release("ID=ubuntu\n");
+        assert_eq!(ContainerOs::detect(&root).await, None);
+    }
+
+    #[test]
+    fn test_render_runcmd_script() {
+        let script = render_runcmd_script(&config(
+            r#"
+runcmd:
+  - echo "hello $HOSTNAME"
+  - [touch, "/tmp/it's here"]
+runcmd_config:
+  shell: /bin/bash
+  error_handling: abort
+"#,
+        ))
+        .unwrap();
+        assert_eq!(
+            script,
+            "#!/bin/bash\n\
+             # Generated by cloud-init-rs from runcmd\n\
+             set -e\n\
+             echo \"hello $HOSTNAME\"\n\
+             touch '/tmp/it'\\''s here'\n"
+        );
+        assert!(render_runcmd_script(&CloudConfig::default()).is_none());
+    }
+
+    #[test]
+    fn test_render_unit() {
+        let packages = vec!["htop".to_string(), "jq".to_string()];
+        let unit = render_unit(ContainerOs::FedoraCoreOs, &packages, true).unwrap();
+        assert!(unit.contains(
+            "ExecStartPre=/usr/bin/rpm-ostree install --idempotent --apply-live --allow-inactive htop jq\n"
+        ));
+        assert!(unit.contains("ExecStart=/var/lib/cloud/instance/scripts/runcmd\n"));
+        assert!(
+            unit.contains("ConditionPathExists=!/var/lib/cloud/instance/compat-unit-finished\n")
+        );
+
+        let unit = render_unit(ContainerOs::Flatcar, &packages, false).unwrap();
+        assert!(unit.contains("ExecStartPre=/usr/bin/systemctl restart ensure-sysext.service\n"));
+        assert!(!unit.contains("ExecStart="));
+        assert!(render_unit(ContainerOs::Flatcar, &[], false).is_none());
+    }
+
+    #[test]
+    fn test_unsupported_keys() {
+        let config = config("packages: [htop]\npackage_upgrade: true\nbyobu_by_default: enable\n");
+        assert_eq!(
+            unsupported_keys(&config),
+            vec!["package_upgrade", "byobu_by_default"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_configure_flatcar_under_root() {
+        let (_temp, root) = root_with_os_release("ID=flatcar\n");
+        std::fs::create_dir_all(root.path("/etc/flatcar")).unwrap();
+        std::fs::write(root.path(FLATCAR_SYSEXT_CONF), "docker-compose").unwrap();
+        let config = config("packages: [podman, docker-compose, 'bad name']\nruncmd: [uptime]\n");
+
+        let runner = RecordingRunner::new();
+        configure_container_os(&runner, &root, ContainerOs::Flatcar, &config)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(root.path(FLATCAR_SYSEXT_CONF)).unwrap(),
+            "docker-compose\npodman\n"
+        );
+        assert!(root.path(RUNCMD_SCRIPT).exists());
+        let unit = std::fs::read_to_string(root.path(UNIT_DIR).join(COMPAT_UNIT)).unwrap();
+        assert!(unit.contains("ensure-sysext.service"));
+        // Started when the image boots
+        assert_eq!(
+            runner.commands(),
+            vec!["systemctl enable cloud-init-rs-compat.service"]
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Disable EC2 metadata module
+//!
+//! Implements the `disable_ec2_metadata` cloud-config key. Once the
+//! instance is provisioned, a `prohibit` route to the instance metadata
+//! service (169.254.169.254) keeps applications from reaching it.
+//!
+//! On the running system the route is added with `ip route`, falling
+//! back to `route` where iproute2 is missing. Routes added that way do not
+//! survive a reboot, so the module runs every boot. Where networkd manages
+//! the network, a drop-in for the first `.network` file also adds the
+//! route whenever that link is configured, which is the only part applied
+//! under an alternate root.
+
+use crate::CloudInitError;
+use crate::network::render::RendererType;
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use std::path::PathBuf;
+use tokio::fs;
+use tracing::{debug, info};
+
+/// Address of the instance metadata service
+pub const METADATA_ADDRESS: &str = "169.254.169.254";
+
+/// Directory networkd reads `.network` files from
+const NETWORKD_DIR: &str = "/etc/systemd/network";
+
+/// Drop-in holding the route, below `<file>.network.d`
+const DROP_IN: &str = "50-disable-ec2-metadata.conf";
+
+/// Block access to the metadata service
+pub async fn disable_ec2_metadata(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    renderer: Option<RendererType>,
+) -> Result<(), CloudInitError> {
+    info!(
+        "Disabling access to the metadata service at {}",
+        METADATA_ADDRESS
+    );
+
+    if renderer == Some(RendererType::Networkd) {
+        write_networkd_drop_in(root).await?;
+    }
+
+    if root.is_host() {
+        add_route(runner).await?;
+    }
+    Ok(())
+}
+
+/// Contents of the networkd drop-in
+fn networkd_route() -> String {
+    format!(
+        "[Route]\nDestination={}/32\nType=prohibit\n",
+        METADATA_ADDRESS
+    )
+}
+
+/// Add the route to the first `.network` file's configuration
+async fn write_networkd_drop_in(root: &RootContext) -> Result<(), CloudInitError> {
+    let Some(network) = first_network_file(root).await else {
+        debug!("No .network file to attach the metadata route to");
+        return Ok(());
+    };
+    let dir = PathBuf::from(format!("{}.d", network.display()));
+    root.create_dir_all(&dir).await?;
+    root.write_file(&dir.join(DROP_IN), networkd_route())
+        .await?;
+    debug!("Wrote metadata route drop-in for {}", network.display());
+    Ok(())
+}
+
+/// The `.network` file that sorts first, i.e. is matched first
+async fn first_network_file(root: &RootContext) -> Option<PathBuf> {
+    let mut entries = fs::read_dir(root.path(NETWORKD_DIR)).await.ok()?;
+    let mut files = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "network") && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.into_iter().min()
+}
+
+/// Add the route on the running system
+///
+/// `ip route replace` keeps this idempotent; `route` is only tried when
+/// `ip` cannot be run at all.
+async fn add_route(runner: &dyn SystemRunner) -> Result<(), CloudInitError> {
+    let ip = SystemCommand::new("ip").args([
+        "route",
+        "replace",
+        "prohibit",
+        &format!("{}/32", METADATA_ADDRESS),
+    ]);
+    let output = match runner.run(&ip).await {
+        Ok(output) => output,
+        Err(e) => {
+            debug!("ip not available ({}), falling back to route", e);
+            let route =
+                SystemCommand::new("route").args(["add", "-host", METADATA_ADDRESS, "reject"]);
+            runner.run(&route).await?
+        }
+    };
+    if !output.is_success() {
+        return Err(CloudInitError::Command(format!(
+            "Adding a route to block {} failed: {}",
+            METADATA_ADDRESS,
+            output.stderr.trim()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_networkd_drop_in_for_first_network_file() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let dir = root.path(NETWORKD_DIR);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("20-eth1.network"), "").unwrap();
+        std::fs::write(dir.join("10-eth0.network"), "").unwrap();
+        std::fs::write(dir.join("10-eth0.link"), "").unwrap();
+
+        let runner = RecordingRunner::new();
+        disable_ec2_metadata(&runner, &root, Some(RendererType::Networkd))
+            .await
+            .unwrap();
+
+        let drop_in = dir.join("10-eth0.network.d").join(DROP_IN);
+        assert_eq!(
+            std::fs::read_to_string(drop_in).unwrap(),
+            "[Route]\nDestination=169.254.169.254/32\nType=prohibit\n"
+        );
+        assert!(!dir.join("20-eth1.network.d").exists());
+        // Not the running system: no route is added now
+        assert!(runner.commands().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_add_route() {
+        let runner = RecordingRunner::new();
+        add_route(&runner).await.unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec!["ip route replace prohibit 169.254.169.254/32"]
+        );
+
+        let failing =
+            RecordingRunner::new().with_response("ip", CommandOutput::failure(2, "RTNETLINK"));
+        assert!(matches!(
+            add_route(&failing).await,
+            Err(CloudInitError::Command(_))
+        ));
+    }
+}
@@ -0,0 +1,100 @@
+//! Ubuntu fan module
+//!
+//! Implements the `fan` cloud-config key: writes the fan configuration,
+//! installs ubuntu-fan unless `fanctl` is present, and enables and
+//! restarts the ubuntu-fan service so it picks the configuration up.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! fan:
+//!   config: |
+//!     # fan 240
+//!     10.0.0.0/8 eth0/16 dhcp
+//! ```
+
+use crate::CloudInitError;
+use crate::config::FanConfig;
+use crate::modules::{packages, start_service};
+use crate::root::RootContext;
+use crate::runner::SystemRunner;
+use std::path::Path;
+use tracing::{debug, info};
+
+/// Written unless `config_path` names another file
+const DEFAULT_CONFIG_PATH: &str = "/etc/network/fan";
+
+/// Configure and start the fan overlay network
+pub async fn configure_fan(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &FanConfig,
+) -> Result<(), CloudInitError> {
+    let Some(content) = config.config.as_deref().filter(|c| !c.trim().is_empty()) else {
+        debug!("fan: no config given, nothing to do");
+        return Ok(());
+    };
+
+    let config_path = config.config_path.as_deref().unwrap_or(DEFAULT_CONFIG_PATH);
+    let path = root.path(config_path);
+    if let Some(dir) = path.parent() {
+        root.create_dir_all(dir).await?;
+    }
+    root.write_file(&path, render_config(content)).await?;
+    info!("Wrote {}", Path::new(config_path).display());
+
+    if !packages::command_exists(runner, "fanctl").await {
+        packages::install_package(runner, root, "ubuntu-fan").await?;
+    }
+
+    start_service(runner, root, "ubuntu-fan").await
+}
+
+/// The configuration file content, ending in a newline
+fn render_config(content: &str) -> String {
+    if content.ends_with('\n') {
+        content.to_string()
+    } else {
+        format!("{}\n", content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::RecordingRunner;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_configure_fan_under_root() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let config: FanConfig =
+            serde_yaml::from_str("config: '10.0.0.0/8 eth0/16 dhcp'\nconfig_path: /etc/fan.conf")
+                .unwrap();
+
+        let runner = RecordingRunner::new();
+        configure_fan(&runner, &root, &config).await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(root.path("/etc/fan.conf")).unwrap(),
+            "10.0.0.0/8 eth0/16 dhcp\n"
+        );
+        assert_eq!(
+            runner.commands(),
+            vec!["which fanctl", "systemctl enable ubuntu-fan"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_configure_fan_without_config_is_noop() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let runner = RecordingRunner::new();
+        configure_fan(&runner, &root, &FanConfig::default())
+            .await
+            .unwrap();
+        assert!(runner.commands().is_empty());
+        assert!(!root.path(DEFAULT_CONFIG_PATH).exists());
+    }
+}
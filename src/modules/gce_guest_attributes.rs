@@ -0,0 +1,166 @@
+//! GCE guest attributes module
+//!
+//! Publishes the instance's SSH host keys to GCE guest attributes under
+//! `hostkeys/<key type>`, where `gcloud compute ssh` and OS Login read
+//! them to pin known hosts, and sets `cloud-init/provisioned` to `true`
+//! once provisioning is done. Both go through the metadata server's PUT
+//! API, which only accepts them when the instance has the
+//! `enable-guest-attributes` metadata set; otherwise the module logs that
+//! and does nothing.
+//!
+//! Runs at the end of the final stage on GCE, unless turned off:
+//!
+//! ```yaml
+//! gce_guest_attributes:
+//!   host_keys: true
+//!   provisioned: false
+//! ```
+
+use crate::CloudInitError;
+use crate::config::GceGuestAttributesConfig;
+use crate::datasources::gce::Gce;
+use crate::modules::ssh_keys;
+use crate::root::RootContext;
+use tracing::{debug, info};
+
+/// Namespace of the SSH host keys
+const HOST_KEYS_NAMESPACE: &str = "hostkeys";
+
+/// Attribute set once provisioning is done
+const PROVISIONED_ATTRIBUTE: &str = "cloud-init/provisioned";
+
+/// Publish host keys and the provisioning flag
+pub async fn publish_guest_attributes(
+    gce: &Gce,
+    root: &RootContext,
+    config: &GceGuestAttributesConfig,
+) -> Result<(), CloudInitError> {
+    let mut attributes = Vec::new();
+    if config.host_keys.unwrap_or(true) {
+        for (key_type, key) in ssh_keys::host_public_keys(root).await {
+            attributes.push((format!("{}/{}", HOST_KEYS_NAMESPACE, key_type), key));
+        }
+    }
+    // Last, so readers that see it also see the keys
+    if config.provisioned.unwrap_or(true) {
+        attributes.push((PROVISIONED_ATTRIBUTE.to_string(), "true".to_string()));
+    }
+    if attributes.is_empty() {
+        debug!("No guest attributes to publish");
+        return Ok(());
+    }
+
+    for (key, value) in &attributes {
+        if !gce.put_guest_attribute(key, value).await? {
+            info!("Guest attributes are not enabled for this instance; not publishing");
+            return Ok(());
+        }
+    }
+    info!("Published {} guest attributes", attributes.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use wiremock::matchers::{body_string, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const GUEST_ATTRIBUTES: &str = "/computeMetadata/v1/instance/guest-attributes";
+
+    fn root_with_host_key() -> (TempDir, RootContext) {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        std::fs::create_dir_all(root.path("/etc/ssh")).unwrap();
+        std::fs::write(
+            root.path("/etc/ssh/ssh_host_ed25519_key.pub"),
+            "ssh-ed25519 AAAAC3Nz root@image\n",
+        )
+        .unwrap();
+        (temp, root)
+    }
+
+    fn gce(server: &MockServer) -> Gce {
+        Gce::with_base_url(&format!("{}/computeMetadata/v1", server.uri()))
+    }
+
+    #[tokio::test]
+    async fn test_publishes_host_keys_then_provisioned() {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path(format!("{}/hostkeys/ssh-ed25519", GUEST_ATTRIBUTES)))
+            .and(header("Metadata-Flavor", "Google"))
+            .and(body_string("ssh-ed25519 AAAAC3Nz"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path(format!("{}/cloud-init/provisioned", GUEST_ATTRIBUTES)))
+            .and(body_string("true"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let (_temp, root) = root_with_host_key();
+        publish_guest_attributes(&gce(&server), &root, &GceGuestAttributesConfig::default())
+            .await
+            .unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        assert!(
+            requests
+                .last()
+                .unwrap()
+                .url
+                .path()
+                .ends_with("/provisioned")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disabled_guest_attributes_are_skipped() {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(403))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let (_temp, root) = root_with_host_key();
+        publish_guest_attributes(&gce(&server), &root, &GceGuestAttributesConfig::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_config_turns_attributes_off() {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let (_temp, root) = root_with_host_key();
+        let config = GceGuestAttributesConfig {
+            host_keys: Some(false),
+            provisioned: Some(false),
+        };
+        publish_guest_attributes(&gce(&server), &root, &config)
+            .await
+            .unwrap();
+
+        // Failures other than a disabled API are errors
+        let config = GceGuestAttributesConfig {
+            host_keys: Some(false),
+            provisioned: None,
+        };
+        let err = publish_guest_attributes(&gce(&server), &root, &config)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("cloud-init/provisioned"));
+    }
+}
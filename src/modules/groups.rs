@@ -1,18 +1,62 @@
 //! Group creation and configuration module
+//!
+//! Runs before the users module so that users can reference the groups
+//! created here. Both group creation and membership changes are idempotent:
+//! existing groups are left alone and users already in a group are skipped.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! groups:
+//!   - docker
+//!   - name: svc
+//!     gid: 950
+//!     system: true
+//!     members: [app]
+//!   - admingroup: [root, sys]
+//! ```
 
 use crate::CloudInitError;
 use crate::config::GroupConfig;
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use std::path::Path;
+use tokio::fs;
 use tracing::{debug, info};
 
+/// Path to the system group database
+pub(super) const GROUP_FILE: &str = "/etc/group";
+
 /// Create groups from cloud-config
-pub async fn create_groups(groups: &[GroupConfig]) -> Result<(), CloudInitError> {
+///
+/// Group membership is checked against `/etc/group` beneath `root`.
+pub async fn create_groups(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    groups: &[GroupConfig],
+) -> Result<(), CloudInitError> {
+    let group_file = root.path(GROUP_FILE);
     for group in groups {
         match group {
             GroupConfig::Name(name) => {
-                create_group_simple(name).await?;
+                create_group_simple(runner, &group_file, name).await?;
+            }
+            GroupConfig::WithMembers {
+                name,
+                members,
+                gid,
+                system,
+            } => {
+                create_group(runner, &group_file, name, *gid, system.unwrap_or(false)).await?;
+                add_members(runner, &group_file, name, members).await?;
             }
-            GroupConfig::WithMembers { name, members } => {
-                create_group_with_members(name, members).await?;
+            GroupConfig::Mapping(map) => {
+                // Sort for deterministic ordering across runs
+                let mut entries: Vec<_> = map.iter().collect();
+                entries.sort_by_key(|(name, _)| name.as_str());
+                for (name, members) in entries {
+                    create_group_with_members(runner, &group_file, name, members).await?;
+                }
             }
         }
     }
@@ -20,21 +64,39 @@ pub async fn create_groups(groups: &[GroupConfig]) -> Result<(), CloudInitError>
 }
 
 /// Create a simple group
-async fn create_group_simple(name: &str) -> Result<(), CloudInitError> {
+async fn create_group_simple(
+    runner: &dyn SystemRunner,
+    group_file: &Path,
+    name: &str,
+) -> Result<(), CloudInitError> {
+    create_group(runner, group_file, name, None, false).await
+}
+
+/// Create a group with an optional GID, skipping groups that already exist
+pub(super) async fn create_group(
+    runner: &dyn SystemRunner,
+    group_file: &Path,
+    name: &str,
+    gid: Option<u32>,
+    system: bool,
+) -> Result<(), CloudInitError> {
+    let existing = fs::read_to_string(group_file).await.unwrap_or_default();
+    if group_members(&existing, name).is_some() {
+        debug!("Group {} already exists, skipping creation", name);
+        return Ok(());
+    }
+
     info!("Creating group: {}", name);
 
-    let output = tokio::process::Command::new("groupadd")
-        .arg(name)
-        .output()
-        .await
-        .map_err(|e| CloudInitError::Command(e.to_string()))?;
+    let output = runner
+        .run(&SystemCommand::new("groupadd").args(build_groupadd_args(name, gid, system)))
+        .await?;
 
     // Exit code 9 means group already exists, which is fine
-    if !output.status.success() && output.status.code() != Some(9) {
-        let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.is_success() && output.code != Some(9) {
         return Err(CloudInitError::UserGroup(format!(
             "Failed to create group {}: {}",
-            name, stderr
+            name, output.stderr
         )));
     }
 
@@ -42,67 +104,222 @@ async fn create_group_simple(name: &str) -> Result<(), CloudInitError> {
 }
 
 /// Create a group and add members to it
-async fn create_group_with_members(name: &str, members: &[String]) -> Result<(), CloudInitError> {
+async fn create_group_with_members(
+    runner: &dyn SystemRunner,
+    group_file: &Path,
+    name: &str,
+    members: &[String],
+) -> Result<(), CloudInitError> {
     // First create the group
-    create_group_simple(name).await?;
+    create_group_simple(runner, group_file, name).await?;
 
     // Then add each member
+    add_members(runner, group_file, name, members).await
+}
+
+/// Add members to a group, skipping users that already belong to it
+async fn add_members(
+    runner: &dyn SystemRunner,
+    group_file: &Path,
+    group: &str,
+    members: &[String],
+) -> Result<(), CloudInitError> {
+    if members.is_empty() {
+        return Ok(());
+    }
+
+    let existing = fs::read_to_string(group_file).await.unwrap_or_default();
+    let current = group_members(&existing, group).unwrap_or_default();
+
     for member in members {
-        add_user_to_group(member, name).await?;
+        if current.iter().any(|m| m == member) {
+            debug!("User {} already in group {}", member, group);
+            continue;
+        }
+        add_user_to_group(runner, member, group).await?;
     }
 
     Ok(())
 }
 
 /// Add a user to a group
-async fn add_user_to_group(username: &str, group: &str) -> Result<(), CloudInitError> {
+async fn add_user_to_group(
+    runner: &dyn SystemRunner,
+    username: &str,
+    group: &str,
+) -> Result<(), CloudInitError> {
     debug!("Adding user {} to group {}", username, group);
 
-    let output = tokio::process::Command::new("usermod")
-        .args(["--append", "--groups", group, username])
-        .output()
-        .await
-        .map_err(|e| CloudInitError::Command(e.to_string()))?;
+    let output = runner
+        .run(&SystemCommand::new("usermod").args(["--append", "--groups", group, username]))
+        .await?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.is_success() {
         return Err(CloudInitError::UserGroup(format!(
             "Failed to add user {} to group {}: {}",
-            username, group, stderr
+            username, group, output.stderr
         )));
     }
 
     Ok(())
 }
 
+/// Build the `groupadd` argument list
+fn build_groupadd_args(name: &str, gid: Option<u32>, system: bool) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(gid) = gid {
+        args.extend(["--gid".to_string(), gid.to_string()]);
+    }
+    if system {
+        args.push("--system".to_string());
+    }
+    args.push(name.to_string());
+    args
+}
+
+/// Look up a group in `/etc/group` content, returning its member list if present
+pub(super) fn group_members(content: &str, group: &str) -> Option<Vec<String>> {
+    content.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() >= 4 && fields[0] == group {
+            Some(
+                fields[3]
+                    .split(',')
+                    .filter(|m| !m.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            )
+        } else {
+            None
+        }
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+    use tempfile::TempDir;
+
+    /// Fake root whose /etc/group holds `content`
+    fn fake_root(content: &str) -> (TempDir, RootContext) {
+        let temp = TempDir::new().unwrap();
+        std::fs::create_dir_all(temp.path().join("etc")).unwrap();
+        std::fs::write(temp.path().join("etc/group"), content).unwrap();
+        let root = RootContext::new(temp.path());
+        (temp, root)
+    }
 
     #[tokio::test]
     async fn test_create_groups_empty() {
-        let result = create_groups(&[]).await;
+        let runner = RecordingRunner::new();
+        let result = create_groups(&runner, &RootContext::host(), &[]).await;
         assert!(result.is_ok());
+        assert!(runner.commands().is_empty());
     }
 
     #[tokio::test]
     async fn test_create_group_simple_calls_groupadd() {
-        // Will fail on macOS (no groupadd) but should return error, not panic
-        let result = create_group_simple("test_group_xyz_12345").await;
-        let _ = result; // May be Ok or Err depending on platform
+        let (temp, _root) = fake_root("root:x:0:\n");
+        let runner = RecordingRunner::new();
+        create_group_simple(
+            &runner,
+            &temp.path().join("etc/group"),
+            "test_group_xyz_12345",
+        )
+        .await
+        .unwrap();
+        assert_eq!(runner.commands(), vec!["groupadd test_group_xyz_12345"]);
+    }
+
+    #[tokio::test]
+    async fn test_create_groups_skips_existing_members() {
+        let (_temp, root) = fake_root("root:x:0:\nsudo:x:27:alice\n");
+        let runner = RecordingRunner::new();
+        let groups = vec![
+            GroupConfig::Name("docker".to_string()),
+            GroupConfig::WithMembers {
+                name: "sudo".to_string(),
+                members: vec!["alice".to_string(), "bob".to_string()],
+                gid: None,
+                system: None,
+            },
+        ];
+        create_groups(&runner, &root, &groups).await.unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec!["groupadd docker", "usermod --append --groups sudo bob"]
+        );
     }
 
     #[tokio::test]
     async fn test_create_group_with_members_calls_groupadd() {
-        let result =
-            create_group_with_members("test_group_xyz_12345", &["user1".to_string()]).await;
-        let _ = result;
+        let (temp, _root) = fake_root("");
+        let runner = RecordingRunner::new();
+        create_group_with_members(
+            &runner,
+            &temp.path().join("etc/group"),
+            "test_group_xyz_12345",
+            &["user1".to_string()],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "groupadd test_group_xyz_12345",
+                "usermod --append --groups test_group_xyz_12345 user1",
+            ]
+        );
     }
 
     #[tokio::test]
     async fn test_add_user_to_group_calls_usermod() {
-        let result = add_user_to_group("nonexistent_user", "nonexistent_group").await;
-        let _ = result;
+        let runner = RecordingRunner::new()
+            .with_response("usermod", CommandOutput::failure(6, "no such group"));
+        let result = add_user_to_group(&runner, "nonexistent_user", "nonexistent_group").await;
+        assert!(matches!(result, Err(CloudInitError::UserGroup(_))));
+    }
+
+    #[tokio::test]
+    async fn test_add_members_empty_is_noop() {
+        let runner = RecordingRunner::new();
+        assert!(
+            add_members(&runner, Path::new("/etc/group"), "any_group", &[])
+                .await
+                .is_ok()
+        );
+        assert!(runner.commands().is_empty());
+    }
+
+    #[test]
+    fn test_build_groupadd_args_simple() {
+        assert_eq!(build_groupadd_args("docker", None, false), vec!["docker"]);
+    }
+
+    #[test]
+    fn test_build_groupadd_args_gid_and_system() {
+        assert_eq!(
+            build_groupadd_args("svc", Some(950), true),
+            vec!["--gid", "950", "--system", "svc"]
+        );
+    }
+
+    #[test]
+    fn test_group_members_lookup() {
+        let content = "root:x:0:\nsudo:x:27:alice,bob\ndocker:x:999:\n";
+        assert_eq!(
+            group_members(content, "sudo"),
+            Some(vec!["alice".to_string(), "bob".to_string()])
+        );
+        assert_eq!(group_members(content, "docker"), Some(vec![]));
+        assert_eq!(group_members(content, "missing"), None);
+    }
+
+    #[test]
+    fn test_group_members_ignores_prefix_match() {
+        let content = "sudoers:x:28:carol\n";
+        assert_eq!(group_members(content, "sudo"), None);
     }
 
     #[test]
@@ -119,9 +336,11 @@ mod tests {
         let config = GroupConfig::WithMembers {
             name: "mygroup".to_string(),
             members: vec!["user1".to_string(), "user2".to_string()],
+            gid: None,
+            system: None,
         };
         match config {
-            GroupConfig::WithMembers { name, members } => {
+            GroupConfig::WithMembers { name, members, .. } => {
                 assert_eq!(name, "mygroup");
                 assert_eq!(members.len(), 2);
             }
@@ -0,0 +1,284 @@
+//! GRUB module
+//!
+//! Implements two cloud-config keys:
+//!
+//! - `grub_dpkg`: preseeds the debconf answers for the GRUB install
+//!   device and runs `dpkg-reconfigure`, so later GRUB package upgrades
+//!   do not prompt for a disk that only existed on the image builder.
+//! - `serial_console`: adds `console=` arguments for a serial port to
+//!   `GRUB_CMDLINE_LINUX`, enables GRUB's serial terminal and regenerates
+//!   the GRUB config with `update-grub` or `grub2-mkconfig`.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! grub_dpkg:
+//!   grub-pc/install_devices: /dev/sda
+//! serial_console:
+//!   device: ttyS0
+//!   baud: 115200
+//! ```
+
+use crate::CloudInitError;
+use crate::config::{GrubDpkgConfig, SerialConsoleConfig};
+use crate::modules::packages;
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+/// Kernel command line and terminal settings for grub-mkconfig
+const GRUB_DEFAULTS: &str = "/etc/default/grub";
+
+/// Default serial port
+const DEFAULT_DEVICE: &str = "ttyS0";
+
+/// Default serial speed
+const DEFAULT_BAUD: u32 = 115200;
+
+/// Preseed the GRUB install device and reconfigure the GRUB package
+pub async fn configure_grub_dpkg(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &GrubDpkgConfig,
+) -> Result<(), CloudInitError> {
+    if config.enabled == Some(false) {
+        debug!("grub_dpkg disabled");
+        return Ok(());
+    }
+    if !packages::command_exists(runner, "dpkg-reconfigure").await {
+        debug!("dpkg-reconfigure not available, skipping grub_dpkg");
+        return Ok(());
+    }
+
+    let efi = root.path("/sys/firmware/efi").is_dir();
+    let (package, selections) = if efi {
+        let devices = match &config.efi_install_devices {
+            Some(devices) => devices.clone(),
+            None => probe_boot_disk(runner, "/boot/efi").await,
+        };
+        (
+            "grub-efi-amd64",
+            format!("grub-pc grub-efi/install_devices string {}\n", devices),
+        )
+    } else {
+        let devices = match &config.install_devices {
+            Some(devices) => devices.clone(),
+            None => probe_boot_disk(runner, "/boot").await,
+        };
+        let empty = config.install_devices_empty.unwrap_or(devices.is_empty());
+        (
+            "grub-pc",
+            format!(
+                "grub-pc grub-pc/install_devices string {}\n\
+                 grub-pc grub-pc/install_devices_empty boolean {}\n",
+                devices, empty
+            ),
+        )
+    };
+
+    run_checked(
+        runner,
+        SystemCommand::new("debconf-set-selections").stdin(selections),
+    )
+    .await?;
+    run_checked(
+        runner,
+        SystemCommand::new("dpkg-reconfigure")
+            .arg(package)
+            .env("DEBIAN_FRONTEND", "noninteractive"),
+    )
+    .await?;
+    info!("Reconfigured {}", package);
+    Ok(())
+}
+
+/// Disk holding `dir`, or an empty string when it cannot be determined
+async fn probe_boot_disk(runner: &dyn SystemRunner, dir: &str) -> String {
+    let command = SystemCommand::new("grub-probe")
+        .args(["-t", "disk", dir])
+        .probe();
+    match runner.run(&command).await {
+        Ok(output) if output.is_success() => output.stdout.trim().to_string(),
+        _ => {
+            warn!("Could not determine the disk holding {}", dir);
+            String::new()
+        }
+    }
+}
+
+/// Route the console to a serial port and regenerate the GRUB config
+pub async fn configure_serial_console(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &SerialConsoleConfig,
+) -> Result<(), CloudInitError> {
+    let device = config.device.as_deref().unwrap_or(DEFAULT_DEVICE);
+    let baud = config.baud.unwrap_or(DEFAULT_BAUD);
+    let Some(unit) = device
+        .strip_prefix("ttyS")
+        .and_then(|n| n.parse::<u32>().ok())
+    else {
+        return Err(CloudInitError::InvalidData(format!(
+            "Invalid serial console device: {}",
+            device
+        )));
+    };
+
+    let path = root.path(GRUB_DEFAULTS);
+    let Ok(existing) = fs::read_to_string(&path).await else {
+        warn!(
+            "{} not found, not configuring a serial console",
+            GRUB_DEFAULTS
+        );
+        return Ok(());
+    };
+    let updated = serial_grub_defaults(&existing, device, baud, unit);
+    if updated == existing {
+        debug!("Serial console already configured");
+        return Ok(());
+    }
+    root.write_file(&path, updated).await?;
+    info!("Configured serial console on {} at {} baud", device, baud);
+
+    let command = if packages::command_exists(runner, "update-grub").await {
+        SystemCommand::new("update-grub")
+    } else {
+        SystemCommand::new("grub2-mkconfig").args(["-o", "/boot/grub2/grub.cfg"])
+    };
+    run_checked(runner, command).await
+}
+
+/// Update /etc/default/grub for a serial console (pure function for
+/// testability)
+///
+/// Existing `console=` arguments are replaced; other settings are kept.
+fn serial_grub_defaults(existing: &str, device: &str, baud: u32, unit: u32) -> String {
+    let settings = [
+        ("GRUB_CMDLINE_LINUX", None),
+        ("GRUB_TERMINAL", Some("console serial".to_string())),
+        (
+            "GRUB_SERIAL_COMMAND",
+            Some(format!("serial --unit={} --speed={}", unit, baud)),
+        ),
+    ];
+    let consoles = format!("console=tty0 console={},{}n8", device, baud);
+
+    let mut seen = [false; 3];
+    let mut lines: Vec<String> = Vec::new();
+    for line in existing.lines() {
+        let key = line.split_once('=').map(|(key, _)| key.trim());
+        let Some(index) = settings.iter().position(|(name, _)| Some(*name) == key) else {
+            lines.push(line.to_string());
+            continue;
+        };
+        seen[index] = true;
+        let value = match &settings[index].1 {
+            Some(value) => value.clone(),
+            None => {
+                let current = line.split_once('=').map_or("", |(_, v)| v);
+                let mut args: Vec<&str> = current
+                    .trim()
+                    .trim_matches('"')
+                    .split_whitespace()
+                    .filter(|arg| !arg.starts_with("console="))
+                    .collect();
+                args.push(&consoles);
+                args.join(" ")
+            }
+        };
+        lines.push(format!("{}=\"{}\"", settings[index].0, value));
+    }
+    for (index, (name, value)) in settings.iter().enumerate() {
+        if !seen[index] {
+            let value = value.clone().unwrap_or_else(|| consoles.clone());
+            lines.push(format!("{}=\"{}\"", name, value));
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+async fn run_checked(
+    runner: &dyn SystemRunner,
+    command: SystemCommand,
+) -> Result<(), CloudInitError> {
+    let output = runner.run(&command).await?;
+    if !output.is_success() {
+        return Err(CloudInitError::Command(format!(
+            "{} failed: {}",
+            command,
+            output.stderr.trim()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_serial_grub_defaults() {
+        let existing = "GRUB_DEFAULT=0\n\
+                        GRUB_CMDLINE_LINUX_DEFAULT=\"quiet splash\"\n\
+                        GRUB_CMDLINE_LINUX=\"console=tty1 net.ifnames=0\"\n";
+        let updated = serial_grub_defaults(existing, "ttyS1", 9600, 1);
+        assert_eq!(
+            updated,
+            "GRUB_DEFAULT=0\n\
+             GRUB_CMDLINE_LINUX_DEFAULT=\"quiet splash\"\n\
+             GRUB_CMDLINE_LINUX=\"net.ifnames=0 console=tty0 console=ttyS1,9600n8\"\n\
+             GRUB_TERMINAL=\"console serial\"\n\
+             GRUB_SERIAL_COMMAND=\"serial --unit=1 --speed=9600\"\n"
+        );
+        // Applying it again changes nothing
+        assert_eq!(serial_grub_defaults(&updated, "ttyS1", 9600, 1), updated);
+    }
+
+    #[tokio::test]
+    async fn test_configure_serial_console_under_root() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        std::fs::create_dir_all(root.path("/etc/default")).unwrap();
+        std::fs::write(root.path(GRUB_DEFAULTS), "GRUB_TIMEOUT=5\n").unwrap();
+
+        let runner = RecordingRunner::new()
+            .with_response("which update-grub", CommandOutput::failure(1, ""));
+        configure_serial_console(&runner, &root, &SerialConsoleConfig::default())
+            .await
+            .unwrap();
+        let defaults = std::fs::read_to_string(root.path(GRUB_DEFAULTS)).unwrap();
+        assert!(defaults.contains("GRUB_CMDLINE_LINUX=\"console=tty0 console=ttyS0,115200n8\"\n"));
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "which update-grub",
+                "grub2-mkconfig -o /boot/grub2/grub.cfg"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_grub_dpkg_preseeds_probed_disk() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let runner = RecordingRunner::new().with_response(
+            "grub-probe -t disk /boot",
+            CommandOutput::success("/dev/vda\n"),
+        );
+        configure_grub_dpkg(&runner, &root, &GrubDpkgConfig::default())
+            .await
+            .unwrap();
+        let calls = runner.calls();
+        assert_eq!(
+            calls[2].stdin.as_deref(),
+            Some(
+                "grub-pc grub-pc/install_devices string /dev/vda\n\
+                 grub-pc grub-pc/install_devices_empty boolean false\n"
+            )
+        );
+        assert_eq!(runner.commands()[3], "dpkg-reconfigure grub-pc");
+    }
+}
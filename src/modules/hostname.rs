@@ -1,35 +1,203 @@
 //! Hostname configuration module
+//!
+//! Implements the `hostname`, `fqdn`, `prefer_fqdn_if_set`,
+//! `create_hostname_file`, `preserve_hostname` and `manage_etc_hosts`
+//! cloud-config keys.
+//!
+//! On systemd hosts `hostnamectl` is used, with `--transient` when
+//! `create_hostname_file` is false; otherwise `/etc/hostname` is written
+//! directly and the running hostname is set with `hostname(1)`.
+//! Under an alternate root only the files are written. On NixOS, whose
+//! `/etc/hostname` and `/etc/hosts` link into the read-only store, the
+//! links are replaced by files (see [`crate::immutable`]).
+//!
+//! [`configure_hostname`] runs on an instance's first boot.
+//! [`update_hostname`] and [`sync_etc_hosts`] run every boot, falling back
+//! to the metadata's hostname, so both follow a changed name. The hostname
+//! last set is kept in `previous-hostname`; if `/etc/hostname` no longer
+//! matches it, an administrator changed it and it is left alone.
 
 use crate::CloudInitError;
+use crate::config::{CloudConfig, ManageEtcHosts};
+use crate::immutable::{self, ImmutableRoot};
+use crate::root::RootContext;
+use crate::runner::SystemCommand;
+use crate::template::TemplateRenderer;
+use std::path::Path;
 use tokio::fs;
 use tracing::{debug, info};
 
+/// Directory whose presence indicates systemd is the running init system
+const SYSTEMD_RUN_DIR: &str = "/run/systemd/system";
+
+/// Optional user-provided template for /etc/hosts
+const HOSTS_TEMPLATE_PATH: &str = "/etc/cloud/templates/hosts.tmpl";
+
+/// Built-in /etc/hosts template used when no template file is installed
+const DEFAULT_HOSTS_TEMPLATE: &str = "\
+## template: jinja
+# Your system has configured 'manage_etc_hosts' as True.
+# As a result, if you wish for changes to this file to persist
+# then you will need to either
+# a.) make changes to the master file in /etc/cloud/templates/hosts.tmpl
+# b.) change or remove the value of 'manage_etc_hosts' in
+#     /etc/cloud/cloud.cfg or cloud-config from user-data
+#
+127.0.1.1 {{ fqdn }} {{ hostname }}
+127.0.0.1 localhost
+
+# The following lines are desirable for IPv6 capable hosts
+::1 localhost ip6-localhost ip6-loopback
+ff02::1 ip6-allnodes
+ff02::2 ip6-allrouters
+";
+
+/// Apply hostname-related cloud-config keys
+pub async fn configure_hostname(
+    root: &RootContext,
+    config: &CloudConfig,
+) -> Result<(), CloudInitError> {
+    if config.preserve_hostname == Some(true) {
+        debug!("preserve_hostname is set, not setting the hostname");
+        return Ok(());
+    }
+    let Some((hostname, fqdn)) = resolve_hostname_fqdn(config) else {
+        debug!("No hostname or fqdn configured");
+        return Ok(());
+    };
+
+    let create_file = config.create_hostname_file.unwrap_or(true);
+    apply_system_hostname(root, system_name(config, &hostname, &fqdn), create_file).await?;
+    manage_etc_hosts(root, config, &hostname, &fqdn).await
+}
+
+/// Keep the hostname in sync with cloud-config or metadata every boot
+///
+/// `previous` is the file recording the hostname cloud-init last set.
+pub async fn update_hostname(
+    root: &RootContext,
+    config: &CloudConfig,
+    metadata_hostname: Option<&str>,
+    previous: &Path,
+) -> Result<(), CloudInitError> {
+    if config.preserve_hostname == Some(true) {
+        debug!("preserve_hostname is set, not updating the hostname");
+        return Ok(());
+    }
+    let Some((hostname, fqdn)) = resolve_with_metadata(config, metadata_hostname) else {
+        debug!("No hostname in cloud-config or metadata");
+        return Ok(());
+    };
+    let name = system_name(config, &hostname, &fqdn);
+
+    let current = read_name(&root.path("/etc/hostname")).await;
+    if let (Some(current), Some(previous)) = (&current, read_name(previous).await)
+        && *current != previous
+        && current != name
+    {
+        info!(
+            "Hostname was changed to {} outside cloud-init, leaving it",
+            current
+        );
+        return Ok(());
+    }
+
+    let create_file = config.create_hostname_file.unwrap_or(true);
+    apply_system_hostname(root, name, create_file).await?;
+    if let Some(dir) = previous.parent() {
+        root.create_dir_all(dir).await?;
+    }
+    root.write_file(previous, format!("{}\n", name)).await
+}
+
+/// Keep /etc/hosts in sync with cloud-config or metadata every boot
+pub async fn sync_etc_hosts(
+    root: &RootContext,
+    config: &CloudConfig,
+    metadata_hostname: Option<&str>,
+) -> Result<(), CloudInitError> {
+    let Some((hostname, fqdn)) = resolve_with_metadata(config, metadata_hostname) else {
+        debug!("No hostname in cloud-config or metadata for /etc/hosts");
+        return Ok(());
+    };
+    manage_etc_hosts(root, config, &hostname, &fqdn).await
+}
+
+/// Rewrite /etc/hosts as `manage_etc_hosts` asks
+async fn manage_etc_hosts(
+    root: &RootContext,
+    config: &CloudConfig,
+    hostname: &str,
+    fqdn: &str,
+) -> Result<(), CloudInitError> {
+    match config.manage_etc_hosts.unwrap_or_default() {
+        ManageEtcHosts::Disabled => Ok(()),
+        ManageEtcHosts::Template => render_etc_hosts(root, hostname, fqdn).await,
+        ManageEtcHosts::Localhost => update_etc_hosts(root, hostname, fqdn).await,
+    }
+}
+
+/// The name to give the system: the FQDN if `prefer_fqdn_if_set`
+fn system_name<'a>(config: &CloudConfig, hostname: &'a str, fqdn: &'a str) -> &'a str {
+    if config.prefer_fqdn_if_set == Some(true) && config.fqdn.is_some() {
+        fqdn
+    } else {
+        hostname
+    }
+}
+
+/// First line of a hostname file, if it names anything
+async fn read_name(path: &Path) -> Option<String> {
+    let content = fs::read_to_string(path).await.ok()?;
+    let name = content.lines().next()?.trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
 /// Set the system hostname
-pub async fn set_hostname(hostname: &str) -> Result<(), CloudInitError> {
+pub async fn set_hostname(root: &RootContext, hostname: &str) -> Result<(), CloudInitError> {
+    apply_system_hostname(root, hostname, true).await
+}
+
+/// Set the system hostname, optionally skipping creation of /etc/hostname
+async fn apply_system_hostname(
+    root: &RootContext,
+    hostname: &str,
+    create_file: bool,
+) -> Result<(), CloudInitError> {
     info!("Setting hostname to: {}", hostname);
 
-    // Write to /etc/hostname
-    fs::write("/etc/hostname", format!("{}\n", hostname))
-        .await
-        .map_err(CloudInitError::Io)?;
+    // Prefer hostnamectl when systemd is running; unless the name is only
+    // transient, it persists /etc/hostname itself
+    if root.is_host()
+        && Path::new(SYSTEMD_RUN_DIR).exists()
+        && try_hostnamectl(root, hostname, create_file).await?
+    {
+        return Ok(());
+    }
+
+    // Fallback: write /etc/hostname directly (unless disabled and absent)
+    let hostname_file = root.path("/etc/hostname");
+    if create_file || hostname_file.exists() {
+        let immutable = ImmutableRoot::detect(root).await;
+        immutable::write_etc_file(root, immutable, &hostname_file, format!("{}\n", hostname))
+            .await?;
+    } else {
+        debug!("create_hostname_file is false, not creating /etc/hostname");
+    }
 
-    // Try hostnamectl first (systemd)
-    if try_hostnamectl(hostname).await? {
+    if !root.is_host() {
         return Ok(());
     }
 
-    // Fallback: hostname command
-    let output = tokio::process::Command::new("hostname")
-        .arg(hostname)
-        .output()
-        .await
-        .map_err(|e| CloudInitError::Command(e.to_string()))?;
+    let output = root
+        .runner()
+        .run(&SystemCommand::new("hostname").arg(hostname))
+        .await?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.is_success() {
         return Err(CloudInitError::Command(format!(
             "Failed to set hostname: {}",
-            stderr
+            output.stderr
         )));
     }
 
@@ -38,41 +206,76 @@ pub async fn set_hostname(hostname: &str) -> Result<(), CloudInitError> {
 
 /// Set hostname with FQDN support
 pub async fn set_hostname_fqdn(
+    root: &RootContext,
     hostname: &str,
     fqdn: Option<&str>,
     manage_etc_hosts: bool,
 ) -> Result<(), CloudInitError> {
     // Set the short hostname
-    set_hostname(hostname).await?;
+    set_hostname(root, hostname).await?;
 
     // If we have an FQDN and should manage /etc/hosts
     if manage_etc_hosts {
         let fqdn = fqdn.unwrap_or(hostname);
-        update_etc_hosts(hostname, fqdn).await?;
+        update_etc_hosts(root, hostname, fqdn).await?;
     }
 
     Ok(())
 }
 
+/// Work out the short hostname and FQDN from cloud-config
+///
+/// Follows Python cloud-init: an explicit `fqdn` wins, its first label is
+/// the hostname unless `hostname` is also set; a dotted `hostname` is
+/// treated as the FQDN.
+fn resolve_hostname_fqdn(config: &CloudConfig) -> Option<(String, String)> {
+    match (&config.hostname, &config.fqdn) {
+        (Some(hostname), Some(fqdn)) => Some((hostname.clone(), fqdn.clone())),
+        (None, Some(fqdn)) => Some((short_name(fqdn).to_string(), fqdn.clone())),
+        (Some(hostname), None) if hostname.contains('.') => {
+            Some((short_name(hostname).to_string(), hostname.clone()))
+        }
+        (Some(hostname), None) => Some((hostname.clone(), hostname.clone())),
+        (None, None) => None,
+    }
+}
+
+/// Like [`resolve_hostname_fqdn`], falling back to the metadata's name
+fn resolve_with_metadata(
+    config: &CloudConfig,
+    metadata_hostname: Option<&str>,
+) -> Option<(String, String)> {
+    resolve_hostname_fqdn(config).or_else(|| {
+        let name = metadata_hostname?;
+        Some((short_name(name).to_string(), name.to_string()))
+    })
+}
+
+/// First DNS label of a name
+fn short_name(name: &str) -> &str {
+    name.split('.').next().unwrap_or(name)
+}
+
 /// Try to set hostname via hostnamectl (systemd)
-async fn try_hostnamectl(hostname: &str) -> Result<bool, CloudInitError> {
+async fn try_hostnamectl(
+    root: &RootContext,
+    hostname: &str,
+    create_file: bool,
+) -> Result<bool, CloudInitError> {
     debug!("Attempting to set hostname via hostnamectl");
 
-    let output = tokio::process::Command::new("hostnamectl")
-        .args(["set-hostname", hostname])
-        .output()
+    let output = root
+        .runner()
+        .run(&hostnamectl_command(hostname, create_file))
         .await;
 
     match output {
-        Ok(output) if output.status.success() => {
+        Ok(output) if output.is_success() => {
             info!("Hostname set via hostnamectl");
             Ok(true)
         }
         Ok(output) => {
-            debug!(
-                "hostnamectl failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
+            debug!("hostnamectl failed: {}", output.stderr);
             Ok(false)
         }
         Err(e) => {
@@ -82,25 +285,75 @@ async fn try_hostnamectl(hostname: &str) -> Result<bool, CloudInitError> {
     }
 }
 
+/// `hostnamectl set-hostname`, only for the running system (`--transient`)
+/// when `/etc/hostname` is not to be created, as Python cloud-init does
+fn hostnamectl_command(hostname: &str, create_file: bool) -> SystemCommand {
+    let command = SystemCommand::new("hostnamectl").arg("set-hostname");
+    if create_file {
+        command.arg(hostname)
+    } else {
+        command.args(["--transient", hostname])
+    }
+}
+
+/// Render /etc/hosts from the hosts template (`manage_etc_hosts: true`)
+pub async fn render_etc_hosts(
+    root: &RootContext,
+    hostname: &str,
+    fqdn: &str,
+) -> Result<(), CloudInitError> {
+    debug!("Rendering /etc/hosts from template");
+
+    let template = fs::read_to_string(root.path(HOSTS_TEMPLATE_PATH))
+        .await
+        .unwrap_or_else(|_| DEFAULT_HOSTS_TEMPLATE.to_string());
+    let content = build_hosts_from_template(&template, hostname, fqdn)?;
+
+    let immutable = ImmutableRoot::detect(root).await;
+    if immutable::write_etc_file(root, immutable, &root.path("/etc/hosts"), &content).await? {
+        info!("Rendered /etc/hosts");
+    }
+    Ok(())
+}
+
+/// Render a hosts template with `hostname` and `fqdn` variables
+fn build_hosts_from_template(
+    template: &str,
+    hostname: &str,
+    fqdn: &str,
+) -> Result<String, CloudInitError> {
+    let mut renderer = TemplateRenderer::new();
+    renderer.add_var("hostname", hostname);
+    renderer.add_var("fqdn", fqdn);
+    let mut rendered = renderer.render(template)?;
+    if !rendered.ends_with('\n') {
+        rendered.push('\n');
+    }
+    Ok(rendered)
+}
+
 /// Update /etc/hosts with hostname entries
-pub async fn update_etc_hosts(hostname: &str, fqdn: &str) -> Result<(), CloudInitError> {
+pub async fn update_etc_hosts(
+    root: &RootContext,
+    hostname: &str,
+    fqdn: &str,
+) -> Result<(), CloudInitError> {
     debug!(
         "Updating /etc/hosts for hostname: {}, fqdn: {}",
         hostname, fqdn
     );
 
-    let hosts_path = "/etc/hosts";
-    let existing = fs::read_to_string(hosts_path)
+    let hosts_path = root.path("/etc/hosts");
+    let existing = fs::read_to_string(&hosts_path)
         .await
         .unwrap_or_else(|_| String::new());
 
     let content = build_hosts_content(&existing, hostname, fqdn);
 
-    fs::write(hosts_path, &content)
-        .await
-        .map_err(CloudInitError::Io)?;
-
-    info!("Updated /etc/hosts");
+    let immutable = ImmutableRoot::detect(root).await;
+    if immutable::write_etc_file(root, immutable, &hosts_path, &content).await? {
+        info!("Updated /etc/hosts");
+    }
     Ok(())
 }
 
@@ -153,6 +406,19 @@ fn build_hosts_content(existing: &str, hostname: &str, fqdn: &str) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_hostnamectl_command_transient_without_hostname_file() {
+        assert_eq!(
+            hostnamectl_command("web1", true).args,
+            vec!["set-hostname", "web1"]
+        );
+        assert_eq!(
+            hostnamectl_command("web1", false).args,
+            vec!["set-hostname", "--transient", "web1"]
+        );
+    }
 
     #[test]
     fn test_build_hosts_empty_existing() {
@@ -217,8 +483,218 @@ mod tests {
         assert!(lines[1].starts_with("127.0.1.1"));
     }
 
+    #[test]
+    fn test_build_hosts_from_default_template() {
+        let result =
+            build_hosts_from_template(DEFAULT_HOSTS_TEMPLATE, "web", "web.example.com").unwrap();
+        assert!(result.contains("127.0.1.1 web.example.com web\n"));
+        assert!(result.contains("127.0.0.1 localhost\n"));
+        assert!(result.contains("::1 localhost ip6-localhost ip6-loopback"));
+        assert!(!result.contains("## template"));
+    }
+
+    #[test]
+    fn test_build_hosts_from_custom_template() {
+        let result =
+            build_hosts_from_template("{{ hostname }} {{ fqdn }}", "a", "a.example").unwrap();
+        assert_eq!(result, "a a.example\n");
+    }
+
+    fn hostname_config(hostname: Option<&str>, fqdn: Option<&str>) -> CloudConfig {
+        CloudConfig {
+            hostname: hostname.map(str::to_string),
+            fqdn: fqdn.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resolve_hostname_fqdn_both_set() {
+        let config = hostname_config(Some("web"), Some("web01.example.com"));
+        assert_eq!(
+            resolve_hostname_fqdn(&config),
+            Some(("web".to_string(), "web01.example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_resolve_hostname_fqdn_only_fqdn() {
+        let config = hostname_config(None, Some("db.example.com"));
+        assert_eq!(
+            resolve_hostname_fqdn(&config),
+            Some(("db".to_string(), "db.example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_resolve_hostname_fqdn_dotted_hostname() {
+        let config = hostname_config(Some("cache.internal"), None);
+        assert_eq!(
+            resolve_hostname_fqdn(&config),
+            Some(("cache".to_string(), "cache.internal".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_resolve_hostname_fqdn_plain_hostname() {
+        let config = hostname_config(Some("simple"), None);
+        assert_eq!(
+            resolve_hostname_fqdn(&config),
+            Some(("simple".to_string(), "simple".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_resolve_hostname_fqdn_none() {
+        assert_eq!(resolve_hostname_fqdn(&hostname_config(None, None)), None);
+    }
+
+    #[tokio::test]
+    async fn test_configure_hostname_noop_without_names() {
+        assert!(
+            configure_hostname(&RootContext::host(), &CloudConfig::default())
+                .await
+                .is_ok()
+        );
+    }
+
     #[tokio::test]
     async fn test_set_hostname_fqdn_without_manage_hosts() {
-        let _ = set_hostname_fqdn("test-fqdn-host", Some("test-fqdn-host.local"), false).await;
+        let temp = TempDir::new().unwrap();
+        std::fs::create_dir_all(temp.path().join("etc")).unwrap();
+        let root = RootContext::new(temp.path());
+        set_hostname_fqdn(&root, "test-fqdn-host", Some("test-fqdn-host.local"), false)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(temp.path().join("etc/hostname")).unwrap(),
+            "test-fqdn-host\n"
+        );
+        assert!(!temp.path().join("etc/hosts").exists());
+    }
+
+    #[tokio::test]
+    async fn test_configure_hostname_under_root() {
+        let temp = TempDir::new().unwrap();
+        std::fs::create_dir_all(temp.path().join("etc")).unwrap();
+        std::fs::write(temp.path().join("etc/hosts"), "127.0.0.1 localhost\n").unwrap();
+        let root = RootContext::new(temp.path());
+
+        let mut config = hostname_config(Some("web"), Some("web.example.com"));
+        config.manage_etc_hosts = Some(ManageEtcHosts::Localhost);
+        configure_hostname(&root, &config).await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(temp.path().join("etc/hostname")).unwrap(),
+            "web\n"
+        );
+        let hosts = std::fs::read_to_string(temp.path().join("etc/hosts")).unwrap();
+        assert!(hosts.contains("web.example.com web"));
+    }
+
+    #[tokio::test]
+    async fn test_update_hostname_follows_metadata() {
+        let temp = TempDir::new().unwrap();
+        std::fs::create_dir_all(temp.path().join("etc")).unwrap();
+        let root = RootContext::new(temp.path());
+        let previous = temp.path().join("data/previous-hostname");
+        let config = CloudConfig::default();
+
+        update_hostname(&root, &config, Some("ip-10-0-0-1"), &previous)
+            .await
+            .unwrap();
+        // A new name in metadata is picked up on a later boot
+        update_hostname(&root, &config, Some("ip-10-0-0-2.internal"), &previous)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(temp.path().join("etc/hostname")).unwrap(),
+            "ip-10-0-0-2\n"
+        );
+        assert_eq!(std::fs::read_to_string(&previous).unwrap(), "ip-10-0-0-2\n");
+
+        // Changed by an administrator: left alone
+        std::fs::write(temp.path().join("etc/hostname"), "custom\n").unwrap();
+        update_hostname(&root, &config, Some("ip-10-0-0-3"), &previous)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(temp.path().join("etc/hostname")).unwrap(),
+            "custom\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_hostname_preserved() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let previous = temp.path().join("previous-hostname");
+        let mut config = hostname_config(Some("web"), None);
+        config.preserve_hostname = Some(true);
+
+        update_hostname(&root, &config, None, &previous)
+            .await
+            .unwrap();
+        configure_hostname(&root, &config).await.unwrap();
+        assert!(!temp.path().join("etc/hostname").exists());
+        assert!(!previous.exists());
+    }
+
+    #[tokio::test]
+    async fn test_sync_etc_hosts_every_boot() {
+        let temp = TempDir::new().unwrap();
+        std::fs::create_dir_all(temp.path().join("etc")).unwrap();
+        let root = RootContext::new(temp.path());
+        let config = CloudConfig {
+            manage_etc_hosts: Some(ManageEtcHosts::Template),
+            ..Default::default()
+        };
+
+        sync_etc_hosts(&root, &config, Some("old.example.com"))
+            .await
+            .unwrap();
+        sync_etc_hosts(&root, &config, Some("new.example.com"))
+            .await
+            .unwrap();
+        let hosts = std::fs::read_to_string(temp.path().join("etc/hosts")).unwrap();
+        assert!(hosts.contains("127.0.1.1 new.example.com new"));
+        assert!(!hosts.contains("old"));
+    }
+
+    #[tokio::test]
+    async fn test_configure_hostname_nixos_store_links() {
+        let temp = TempDir::new().unwrap();
+        let store = temp.path().join("nix/store/abc-etc/etc");
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::create_dir_all(temp.path().join("etc")).unwrap();
+        std::fs::write(temp.path().join("etc/NIXOS"), "").unwrap();
+        std::fs::write(store.join("hostname"), "nixos\n").unwrap();
+        std::fs::write(store.join("hosts"), "127.0.0.1 localhost\n").unwrap();
+        std::os::unix::fs::symlink("/nix/store/abc-etc/etc", temp.path().join("etc/static"))
+            .unwrap();
+        for name in ["hostname", "hosts"] {
+            std::os::unix::fs::symlink(
+                format!("/etc/static/{}", name),
+                temp.path().join("etc").join(name),
+            )
+            .unwrap();
+        }
+        let root = RootContext::new(temp.path());
+
+        let mut config = hostname_config(Some("web"), Some("web.example.com"));
+        config.manage_etc_hosts = Some(ManageEtcHosts::Localhost);
+        configure_hostname(&root, &config).await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(temp.path().join("etc/hostname")).unwrap(),
+            "web\n"
+        );
+        let hosts = std::fs::read_to_string(temp.path().join("etc/hosts")).unwrap();
+        assert!(hosts.contains("127.0.0.1 localhost"));
+        assert!(hosts.contains("web.example.com web"));
+        assert_eq!(
+            std::fs::read_to_string(store.join("hostname")).unwrap(),
+            "nixos\n"
+        );
     }
 }
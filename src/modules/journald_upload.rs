@@ -0,0 +1,141 @@
+//! Journal upload module
+//!
+//! Implements the `journald_upload` cloud-config key: writes a
+//! journal-upload.conf drop-in pointing systemd-journal-upload at a
+//! systemd-journal-remote server, installs systemd-journal-remote when the
+//! uploader is missing, and enables and restarts the upload service. The
+//! uploader sends the journal from its start, so provisioning logs of the
+//! first boot are shipped too.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! journald_upload:
+//!   url: https://logs.example.com:19532
+//!   server_key_file: /etc/ssl/private/journal-upload.pem
+//!   server_certificate_file: /etc/ssl/certs/journal-upload.pem
+//!   trusted_certificate_file: /etc/ssl/certs/logs-ca.pem
+//! ```
+
+use crate::CloudInitError;
+use crate::config::JournaldUploadConfig;
+#[cfg(feature = "mod-packages")]
+use crate::modules::packages;
+use crate::modules::start_service;
+use crate::root::RootContext;
+use crate::runner::SystemRunner;
+use tracing::info;
+
+/// Drop-in written for systemd-journal-upload
+const DROP_IN: &str = "/etc/systemd/journal-upload.conf.d/50-cloud-init.conf";
+
+/// The uploader, under either usual prefix
+const UPLOADER_BINARIES: &[&str] = &[
+    "/lib/systemd/systemd-journal-upload",
+    "/usr/lib/systemd/systemd-journal-upload",
+];
+
+/// Configure and start systemd-journal-upload
+pub async fn configure_journald_upload(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &JournaldUploadConfig,
+) -> Result<(), CloudInitError> {
+    let content = render_drop_in(config)?;
+
+    if !UPLOADER_BINARIES
+        .iter()
+        .any(|path| root.path(path).exists())
+    {
+        #[cfg(feature = "mod-packages")]
+        packages::install_package(runner, root, "systemd-journal-remote").await?;
+        #[cfg(not(feature = "mod-packages"))]
+        return Err(CloudInitError::Module {
+            module: "journald_upload".to_string(),
+            message: "systemd-journal-upload is not installed".to_string(),
+        });
+    }
+
+    let path = root.path(DROP_IN);
+    if let Some(dir) = path.parent() {
+        root.create_dir_all(dir).await?;
+    }
+    root.write_file(&path, content).await?;
+    info!("Wrote {}", DROP_IN);
+
+    start_service(runner, root, "systemd-journal-upload").await
+}
+
+/// Render the `[Upload]` drop-in (pure function for testability)
+fn render_drop_in(config: &JournaldUploadConfig) -> Result<String, CloudInitError> {
+    if config.url.trim().is_empty() {
+        return Err(CloudInitError::InvalidData(
+            "journald_upload needs a url".to_string(),
+        ));
+    }
+
+    let mut content = format!(
+        "# Configured by cloud-init-rs\n[Upload]\nURL={}\n",
+        config.url.trim()
+    );
+    for (key, value) in [
+        ("ServerKeyFile", &config.server_key_file),
+        ("ServerCertificateFile", &config.server_certificate_file),
+        ("TrustedCertificateFile", &config.trusted_certificate_file),
+    ] {
+        if let Some(value) = value {
+            content.push_str(&format!("{}={}\n", key, value));
+        }
+    }
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::RecordingRunner;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_render_drop_in() {
+        let config: JournaldUploadConfig = serde_yaml::from_str(
+            "url: https://logs.example.com:19532\ntrusted_certificate_file: /etc/ssl/ca.pem",
+        )
+        .unwrap();
+        assert_eq!(
+            render_drop_in(&config).unwrap(),
+            "# Configured by cloud-init-rs\n\
+             [Upload]\n\
+             URL=https://logs.example.com:19532\n\
+             TrustedCertificateFile=/etc/ssl/ca.pem\n"
+        );
+        assert!(render_drop_in(&JournaldUploadConfig::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_configure_journald_upload_under_root() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        std::fs::create_dir_all(root.path("/usr/lib/systemd")).unwrap();
+        std::fs::write(root.path(UPLOADER_BINARIES[1]), "").unwrap();
+        let config = JournaldUploadConfig {
+            url: "http://192.0.2.10:19532".to_string(),
+            ..Default::default()
+        };
+
+        let runner = RecordingRunner::new();
+        configure_journald_upload(&runner, &root, &config)
+            .await
+            .unwrap();
+
+        assert!(
+            std::fs::read_to_string(root.path(DROP_IN))
+                .unwrap()
+                .contains("URL=http://192.0.2.10:19532\n")
+        );
+        assert_eq!(
+            runner.commands(),
+            vec!["systemctl enable systemd-journal-upload"]
+        );
+    }
+}
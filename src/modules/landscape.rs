@@ -0,0 +1,119 @@
+//! Landscape client module
+//!
+//! Implements the `landscape` cloud-config key: installs
+//! landscape-client, renders `/etc/landscape/client.conf` from the
+//! `client` settings on top of the Landscape SaaS defaults, and enables
+//! and restarts the client so it registers.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! landscape:
+//!   client:
+//!     url: https://landscape.example.com/message-system
+//!     ping_url: http://landscape.example.com/ping
+//!     account_name: example
+//!     computer_title: web-1
+//!     registration_key: secret
+//!     tags: web,production
+//! ```
+
+use crate::CloudInitError;
+use crate::config::LandscapeConfig;
+use crate::modules::{packages, scalar_string, start_service};
+use crate::root::RootContext;
+use crate::runner::SystemRunner;
+use std::collections::BTreeMap;
+use tracing::info;
+
+/// Written for landscape-client
+const CLIENT_CONF: &str = "/etc/landscape/client.conf";
+
+/// Settings used unless the cloud-config overrides them
+const DEFAULTS: &[(&str, &str)] = &[
+    ("data_path", "/var/lib/landscape/client"),
+    ("log_level", "info"),
+    ("ping_url", "http://landscape.canonical.com/ping"),
+    ("url", "https://landscape.canonical.com/message-system"),
+];
+
+/// Install and configure landscape-client
+pub async fn configure_landscape(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &LandscapeConfig,
+) -> Result<(), CloudInitError> {
+    if !packages::command_exists(runner, "landscape-config").await {
+        packages::install_package(runner, root, "landscape-client").await?;
+    }
+
+    let path = root.path(CLIENT_CONF);
+    if let Some(dir) = path.parent() {
+        root.create_dir_all(dir).await?;
+    }
+    root.write_file(&path, render_client_conf(config)?).await?;
+    // The registration key is a secret
+    root.set_mode(&path, 0o600).await?;
+    info!("Wrote {}", CLIENT_CONF);
+
+    start_service(runner, root, "landscape-client").await
+}
+
+/// Render client.conf (pure function for testability)
+fn render_client_conf(config: &LandscapeConfig) -> Result<String, CloudInitError> {
+    let mut settings: BTreeMap<&str, String> = DEFAULTS
+        .iter()
+        .map(|(key, value)| (*key, value.to_string()))
+        .collect();
+    for (key, value) in &config.client {
+        let value = scalar_string(value).ok_or_else(|| {
+            CloudInitError::InvalidData(format!("Invalid landscape client value for {}", key))
+        })?;
+        settings.insert(key, value);
+    }
+
+    let mut content = String::from("# Configured by cloud-init-rs\n[client]\n");
+    for (key, value) in settings {
+        content.push_str(&format!("{} = {}\n", key, value));
+    }
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::RecordingRunner;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_configure_landscape_under_root() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let config: LandscapeConfig = serde_yaml::from_str(
+            "client: {account_name: example, url: 'https://landscape.example.com/message-system', http_proxy: 'http://proxy:3128'}",
+        )
+        .unwrap();
+
+        let runner = RecordingRunner::new();
+        configure_landscape(&runner, &root, &config).await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(root.path(CLIENT_CONF)).unwrap(),
+            "# Configured by cloud-init-rs\n\
+             [client]\n\
+             account_name = example\n\
+             data_path = /var/lib/landscape/client\n\
+             http_proxy = http://proxy:3128\n\
+             log_level = info\n\
+             ping_url = http://landscape.canonical.com/ping\n\
+             url = https://landscape.example.com/message-system\n"
+        );
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "which landscape-config",
+                "systemctl enable landscape-client"
+            ]
+        );
+    }
+}
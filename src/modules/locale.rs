@@ -1,45 +1,194 @@
 //! Locale configuration module
+//!
+//! Implements the `locale` and `locale_configfile` cloud-config keys.
+//!
+//! How the locale is set depends on the distribution's
+//! [`LocaleMechanism`]:
+//!
+//! - Debian family: enables the locale in `/etc/locale.gen`, runs
+//!   `locale-gen` and writes `/etc/default/locale`.
+//! - RHEL and SUSE families: use `localectl`, falling back to
+//!   `/etc/locale.conf`.
+//! - Anything else: tries `localectl`, then writes both files.
+//!
+//! On immutable roots `locale-gen` is skipped, since it writes the locale
+//! archive below the read-only `/usr`, and the files are written as
+//! [`crate::immutable::write_etc_file`] allows.
 
 use crate::CloudInitError;
+use crate::distro::{Distro, LocaleMechanism};
+use crate::immutable::{self, ImmutableRoot};
+use crate::root::RootContext;
+use crate::runner::SystemCommand;
 use std::path::Path;
 use tokio::fs;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+
+/// Debian's list of locales to generate
+const LOCALE_GEN_PATH: &str = "/etc/locale.gen";
+
+/// Debian/Ubuntu default locale file
+const DEBIAN_LOCALE_FILE: &str = "/etc/default/locale";
+
+/// systemd/RHEL locale file
+const RHEL_LOCALE_FILE: &str = "/etc/locale.conf";
 
 /// Set the system locale
-pub async fn set_locale(locale: &str) -> Result<(), CloudInitError> {
+pub async fn set_locale(root: &RootContext, locale: &str) -> Result<(), CloudInitError> {
+    configure_locale(root, locale, None).await
+}
+
+/// Set the system locale, optionally writing it to a custom config file
+///
+/// `localectl` is only tried on the running system; under an alternate
+/// root the config files are written directly.
+pub async fn configure_locale(
+    root: &RootContext,
+    locale: &str,
+    configfile: Option<&str>,
+) -> Result<(), CloudInitError> {
+    validate_locale(locale)?;
     info!("Setting locale to: {}", locale);
 
-    // Try localectl first (systemd systems)
-    if try_localectl(locale).await? {
-        return Ok(());
+    let mechanism = Distro::detect(root).await.locale_mechanism();
+    debug!("Locale mechanism: {:?}", mechanism);
+    let localectl = root.is_host();
+    let immutable = ImmutableRoot::detect(root).await;
+
+    match mechanism {
+        LocaleMechanism::LocaleGen => {
+            enable_in_locale_gen(root, immutable, locale).await?;
+            match immutable {
+                Some(kind) => warn!("Not running locale-gen on {}: /usr is read-only", kind),
+                None => generate_locale(root, locale).await?,
+            }
+            let path = configfile.unwrap_or(DEBIAN_LOCALE_FILE);
+            write_locale_file(root, immutable, &root.path(path), locale).await?;
+        }
+        LocaleMechanism::LocaleConf => {
+            if configfile.is_none() && localectl && try_localectl(root, locale).await? {
+                return Ok(());
+            }
+            let path = configfile.unwrap_or(RHEL_LOCALE_FILE);
+            write_locale_file(root, immutable, &root.path(path), locale).await?;
+        }
+        LocaleMechanism::Unknown => {
+            if let Some(path) = configfile {
+                return write_locale_file(root, immutable, &root.path(path), locale).await;
+            }
+            if localectl && try_localectl(root, locale).await? {
+                return Ok(());
+            }
+            for path in [RHEL_LOCALE_FILE, DEBIAN_LOCALE_FILE] {
+                write_locale_file(root, immutable, &root.path(path), locale).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Validate a locale name such as `en_US.UTF-8`, `de_DE@euro` or `C.UTF-8`
+fn validate_locale(locale: &str) -> Result<(), CloudInitError> {
+    let invalid = || CloudInitError::InvalidData(format!("Invalid locale: {:?}", locale));
+
+    let (rest, modifier) = match locale.split_once('@') {
+        Some((rest, modifier)) => (rest, Some(modifier)),
+        None => (locale, None),
+    };
+    let (name, codeset) = match rest.split_once('.') {
+        Some((name, codeset)) => (name, Some(codeset)),
+        None => (rest, None),
+    };
+
+    let name_ok = if name == "C" || name == "POSIX" {
+        true
+    } else {
+        let (lang, territory) = match name.split_once('_') {
+            Some((lang, territory)) => (lang, Some(territory)),
+            None => (name, None),
+        };
+        (2..=3).contains(&lang.len())
+            && lang.chars().all(|c| c.is_ascii_lowercase())
+            && territory.is_none_or(|t| {
+                (2..=3).contains(&t.len()) && t.chars().all(|c| c.is_ascii_alphanumeric())
+            })
+    };
+
+    let codeset_ok = codeset.is_none_or(|c| {
+        !c.is_empty() && c.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '-')
+    });
+    let modifier_ok =
+        modifier.is_none_or(|m| !m.is_empty() && m.chars().all(|ch| ch.is_ascii_alphanumeric()));
+
+    if name_ok && codeset_ok && modifier_ok {
+        Ok(())
+    } else {
+        Err(invalid())
     }
+}
+
+/// Ensure the locale is listed (uncommented) in /etc/locale.gen
+async fn enable_in_locale_gen(
+    root: &RootContext,
+    immutable: Option<ImmutableRoot>,
+    locale: &str,
+) -> Result<(), CloudInitError> {
+    let locale_gen = root.path(LOCALE_GEN_PATH);
+    let existing = fs::read_to_string(&locale_gen).await.unwrap_or_default();
+    let updated = update_locale_gen(&existing, locale);
 
-    // Fallback: write to config files directly
-    write_locale_conf(locale).await?;
-    write_default_locale(locale).await?;
+    if updated != existing
+        && immutable::write_etc_file(root, immutable, &locale_gen, &updated).await?
+    {
+        debug!("Enabled {} in {}", locale, LOCALE_GEN_PATH);
+    }
 
     Ok(())
 }
 
+/// Uncomment or append the entry for `locale` in locale.gen content
+fn update_locale_gen(existing: &str, locale: &str) -> String {
+    let mut found = false;
+    let mut lines: Vec<String> = Vec::new();
+
+    for line in existing.lines() {
+        let entry = line.trim_start_matches('#').trim();
+        if entry.split_whitespace().next() == Some(locale) {
+            found = true;
+            lines.push(entry.to_string());
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+
+    if !found {
+        let charset = locale
+            .split_once('.')
+            .map(|(_, c)| c.split('@').next().unwrap_or(c))
+            .unwrap_or("ISO-8859-1");
+        lines.push(format!("{} {}", locale, charset));
+    }
+
+    lines.join("\n") + "\n"
+}
+
 /// Try to set locale via localectl
-async fn try_localectl(locale: &str) -> Result<bool, CloudInitError> {
+async fn try_localectl(root: &RootContext, locale: &str) -> Result<bool, CloudInitError> {
     debug!("Attempting to set locale via localectl");
 
-    let output = tokio::process::Command::new("localectl")
-        .args(["set-locale", &format!("LANG={}", locale)])
-        .output()
+    let output = root
+        .runner()
+        .run(&SystemCommand::new("localectl").args(["set-locale", &format!("LANG={}", locale)]))
         .await;
 
     match output {
-        Ok(output) if output.status.success() => {
+        Ok(output) if output.is_success() => {
             info!("Locale set via localectl");
             Ok(true)
         }
         Ok(output) => {
-            debug!(
-                "localectl failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
+            debug!("localectl failed: {}", output.stderr);
             Ok(false)
         }
         Err(e) => {
@@ -49,61 +198,44 @@ async fn try_localectl(locale: &str) -> Result<bool, CloudInitError> {
     }
 }
 
-/// Write /etc/locale.conf (systemd/RHEL style)
-async fn write_locale_conf(locale: &str) -> Result<(), CloudInitError> {
-    let locale_conf = Path::new("/etc/locale.conf");
-
-    let content = format!("LANG={}\n", locale);
-    fs::write(locale_conf, &content)
-        .await
-        .map_err(CloudInitError::Io)?;
-
-    debug!("Wrote /etc/locale.conf");
-    Ok(())
-}
-
-/// Write /etc/default/locale (Debian/Ubuntu style)
-async fn write_default_locale(locale: &str) -> Result<(), CloudInitError> {
-    let default_locale = Path::new("/etc/default/locale");
-
+/// Write `LANG=<locale>` to a locale config file
+async fn write_locale_file(
+    root: &RootContext,
+    immutable: Option<ImmutableRoot>,
+    path: &Path,
+    locale: &str,
+) -> Result<(), CloudInitError> {
     // Create parent directory if needed
-    if let Some(parent) = default_locale.parent()
+    if let Some(parent) = path.parent()
         && !parent.exists()
     {
-        fs::create_dir_all(parent)
-            .await
-            .map_err(CloudInitError::Io)?;
+        root.create_dir_all(parent).await?;
     }
 
     let content = format!("LANG={}\n", locale);
-    fs::write(default_locale, &content)
-        .await
-        .map_err(CloudInitError::Io)?;
-
-    debug!("Wrote /etc/default/locale");
+    if immutable::write_etc_file(root, immutable, path, &content).await? {
+        debug!("Wrote {}", path.display());
+    }
     Ok(())
 }
 
 /// Generate locale if needed (Debian/Ubuntu)
-pub async fn generate_locale(locale: &str) -> Result<(), CloudInitError> {
+pub async fn generate_locale(root: &RootContext, locale: &str) -> Result<(), CloudInitError> {
     debug!("Attempting to generate locale: {}", locale);
 
     // Check if locale-gen exists
-    let output = tokio::process::Command::new("locale-gen")
-        .arg(locale)
-        .output()
+    let output = root
+        .runner()
+        .run(&SystemCommand::new("locale-gen").arg(locale))
         .await;
 
     match output {
-        Ok(output) if output.status.success() => {
+        Ok(output) if output.is_success() => {
             info!("Generated locale: {}", locale);
             Ok(())
         }
         Ok(output) => {
-            debug!(
-                "locale-gen failed (may be expected): {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
+            debug!("locale-gen failed (may be expected): {}", output.stderr);
             Ok(())
         }
         Err(e) => {
@@ -116,39 +248,144 @@ pub async fn generate_locale(locale: &str) -> Result<(), CloudInitError> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tempfile::TempDir;
 
     #[tokio::test]
     async fn test_set_locale_calls_localectl() {
         // On macOS this will fail gracefully and fall through to file writes
         // which will also fail (no /etc/locale.conf), but shouldn't panic
-        let _ = set_locale("en_US.UTF-8").await;
+        let _ = set_locale(&RootContext::host(), "en_US.UTF-8").await;
+    }
+
+    #[tokio::test]
+    async fn test_set_locale_rejects_invalid() {
+        let result = set_locale(&RootContext::host(), "en_US.UTF-8; rm -rf /").await;
+        assert!(matches!(result, Err(CloudInitError::InvalidData(_))));
     }
 
     #[tokio::test]
     async fn test_try_localectl_nonexistent() {
         // localectl may not exist on macOS, should return Ok(false)
-        let result = try_localectl("en_US.UTF-8").await;
+        let result = try_localectl(&RootContext::host(), "en_US.UTF-8").await;
         assert!(result.is_ok());
     }
 
     #[tokio::test]
     async fn test_generate_locale_nonexistent() {
         // locale-gen may not exist, should return Ok(()) gracefully
-        let result = generate_locale("en_US.UTF-8").await;
+        let result = generate_locale(&RootContext::host(), "en_US.UTF-8").await;
         assert!(result.is_ok());
     }
 
     #[tokio::test]
-    async fn test_write_locale_conf_requires_permissions() {
-        // On non-root systems this will fail with permission error
-        let result = write_locale_conf("en_US.UTF-8").await;
-        // Just verify it returns an error type, not a panic
-        let _ = result;
+    async fn test_write_locale_file_custom_path() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("sysconfig/i18n");
+        write_locale_file(&RootContext::host(), None, &path, "fr_FR.UTF-8")
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "LANG=fr_FR.UTF-8\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_configure_locale_under_root() {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("etc")).unwrap();
+        std::fs::write(dir.path().join("etc/os-release"), "ID=fedora\n").unwrap();
+        let root = RootContext::new(dir.path());
+
+        configure_locale(&root, "de_DE.UTF-8", None).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("etc/locale.conf")).unwrap(),
+            "LANG=de_DE.UTF-8\n"
+        );
+
+        configure_locale(&root, "de_DE.UTF-8", Some("/etc/sysconfig/i18n"))
+            .await
+            .unwrap();
+        assert!(dir.path().join("etc/sysconfig/i18n").exists());
     }
 
     #[tokio::test]
-    async fn test_write_default_locale_requires_permissions() {
-        let result = write_default_locale("en_US.UTF-8").await;
-        let _ = result;
+    async fn test_configure_locale_nixos_replaces_store_link() {
+        let dir = TempDir::new().unwrap();
+        let store = dir.path().join("nix/store/abc-etc/etc");
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::create_dir_all(dir.path().join("etc")).unwrap();
+        std::fs::write(dir.path().join("etc/os-release"), "ID=nixos\n").unwrap();
+        std::fs::write(store.join("locale.conf"), "LANG=en_US.UTF-8\n").unwrap();
+        std::os::unix::fs::symlink(
+            "/nix/store/abc-etc/etc/locale.conf",
+            dir.path().join("etc/locale.conf"),
+        )
+        .unwrap();
+        let root = RootContext::new(dir.path());
+
+        configure_locale(&root, "de_DE.UTF-8", None).await.unwrap();
+        let locale_conf = dir.path().join("etc/locale.conf");
+        assert!(!locale_conf.is_symlink());
+        assert_eq!(
+            std::fs::read_to_string(locale_conf).unwrap(),
+            "LANG=de_DE.UTF-8\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(store.join("locale.conf")).unwrap(),
+            "LANG=en_US.UTF-8\n"
+        );
+    }
+
+    #[test]
+    fn test_validate_locale_valid() {
+        for locale in [
+            "en_US.UTF-8",
+            "en_US.utf8",
+            "de_DE@euro",
+            "C",
+            "C.UTF-8",
+            "POSIX",
+            "fr",
+            "ast_ES.UTF-8",
+            "sr_RS.UTF-8@latin",
+        ] {
+            assert!(validate_locale(locale).is_ok(), "{locale} should be valid");
+        }
+    }
+
+    #[test]
+    fn test_validate_locale_invalid() {
+        for locale in ["", "EN_us", "en_US.", "en_US.UTF 8", "../etc", "en_US@"] {
+            assert!(
+                validate_locale(locale).is_err(),
+                "{locale} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn test_update_locale_gen_uncomments_existing() {
+        let existing = "# en_GB.UTF-8 UTF-8\n# en_US.UTF-8 UTF-8\n";
+        let updated = update_locale_gen(existing, "en_US.UTF-8");
+        assert_eq!(updated, "# en_GB.UTF-8 UTF-8\nen_US.UTF-8 UTF-8\n");
+    }
+
+    #[test]
+    fn test_update_locale_gen_appends_missing() {
+        let updated = update_locale_gen("# header\n", "de_DE.UTF-8");
+        assert_eq!(updated, "# header\nde_DE.UTF-8 UTF-8\n");
+    }
+
+    #[test]
+    fn test_update_locale_gen_default_charset() {
+        let updated = update_locale_gen("", "en_US");
+        assert_eq!(updated, "en_US ISO-8859-1\n");
+    }
+
+    #[test]
+    fn test_update_locale_gen_already_enabled() {
+        let existing = "en_US.UTF-8 UTF-8\n";
+        assert_eq!(update_locale_gen(existing, "en_US.UTF-8"), existing);
     }
 }
@@ -3,19 +3,132 @@
 //! Each module handles a specific aspect of cloud-init configuration.
 //! Modules are executed in a defined order during the config and final stages.
 
+use crate::CloudInitError;
+use crate::root::RootContext;
+use crate::runner::SystemRunner;
+use crate::service::ServiceManager;
+use tracing::info;
+
+#[cfg(feature = "mod-ansible")]
+pub mod ansible;
 pub mod bootcmd;
+#[cfg(feature = "mod-byobu")]
+pub mod byobu;
+#[cfg(feature = "mod-chef")]
+pub mod chef;
+pub mod container_os;
+pub mod disable_ec2_metadata;
+#[cfg(feature = "mod-fan")]
+pub mod fan;
+#[cfg(feature = "mod-gce-guest-attributes")]
+pub mod gce_guest_attributes;
 pub mod groups;
+#[cfg(feature = "mod-grub-dpkg")]
+pub mod grub_dpkg;
 pub mod hostname;
+pub mod journald_upload;
+#[cfg(feature = "mod-landscape")]
+pub mod landscape;
 pub mod locale;
+pub mod motd;
+pub mod mounts;
+#[cfg(feature = "mod-ntp")]
 pub mod ntp;
+#[cfg(feature = "mod-packages")]
+pub mod package_mirrors;
+#[cfg(feature = "mod-packages")]
 pub mod packages;
+pub mod plugins;
+pub mod power_state_change;
+#[cfg(feature = "mod-puppet")]
+pub mod puppet;
+#[cfg(feature = "freebsd")]
+mod pw;
+pub mod random_seed;
+#[cfg(feature = "mod-rh-subscription")]
 pub mod rh_subscription;
+pub mod rsyslog;
 pub mod runcmd;
+#[cfg(feature = "mod-salt-minion")]
+pub mod salt_minion;
 pub mod ssh_keys;
+pub mod sshd_config;
+#[cfg(feature = "mod-suse-register")]
+pub mod suse_register;
 pub mod timezone;
+#[cfg(feature = "mod-ubuntu-pro")]
+pub mod ubuntu_pro;
 pub mod users;
+#[cfg(feature = "plugins-wasm")]
+pub mod wasm_plugins;
+#[cfg(feature = "mod-wireguard")]
+pub mod wireguard;
 pub mod write_files;
+#[cfg(feature = "mod-yum-add-repo")]
 pub mod yum_add_repo;
+#[cfg(feature = "mod-zypper-add-repo")]
+pub mod zypper_add_repo;
+
+/// Names of the compiled-in modules
+///
+/// Optional modules are only present when their `mod-*` cargo feature is
+/// enabled.
+pub const COMPILED: &[&str] = &[
+    #[cfg(feature = "mod-ansible")]
+    "ansible",
+    "bootcmd",
+    #[cfg(feature = "mod-byobu")]
+    "byobu_by_default",
+    #[cfg(feature = "mod-chef")]
+    "chef",
+    "container_os",
+    "disable_ec2_metadata",
+    #[cfg(feature = "mod-fan")]
+    "fan",
+    #[cfg(feature = "mod-gce-guest-attributes")]
+    "gce_guest_attributes",
+    "groups",
+    #[cfg(feature = "mod-grub-dpkg")]
+    "grub_dpkg",
+    "hostname",
+    "journald_upload",
+    #[cfg(feature = "mod-landscape")]
+    "landscape",
+    "locale",
+    "motd",
+    "mounts",
+    #[cfg(feature = "mod-ntp")]
+    "ntp",
+    #[cfg(feature = "mod-packages")]
+    "package_mirrors",
+    #[cfg(feature = "mod-packages")]
+    "packages",
+    "power_state_change",
+    #[cfg(feature = "mod-puppet")]
+    "puppet",
+    "random_seed",
+    #[cfg(feature = "mod-rh-subscription")]
+    "rh_subscription",
+    "rsyslog",
+    "runcmd",
+    #[cfg(feature = "mod-salt-minion")]
+    "salt_minion",
+    "ssh_keys",
+    "sshd_config",
+    #[cfg(feature = "mod-suse-register")]
+    "suse_register",
+    "timezone",
+    #[cfg(feature = "mod-ubuntu-pro")]
+    "ubuntu_pro",
+    "users",
+    #[cfg(feature = "mod-wireguard")]
+    "wireguard",
+    "write_files",
+    #[cfg(feature = "mod-yum-add-repo")]
+    "yum_add_repo",
+    #[cfg(feature = "mod-zypper-add-repo")]
+    "zypper_add_repo",
+];
 
 /// Module execution frequency
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -41,6 +154,44 @@ pub trait Module {
     }
 }
 
+/// Enable `service` and restart it so it reads new configuration
+///
+/// Under an alternate root the service is only enabled, so it starts
+/// when the image boots.
+pub async fn start_service(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    service: &str,
+) -> Result<(), CloudInitError> {
+    let manager = ServiceManager::detect(root);
+    let mut commands = vec![manager.enable(service)];
+    if root.is_host() {
+        commands.push(manager.restart(service));
+    }
+    for command in commands {
+        let output = runner.run(&command).await?;
+        if !output.is_success() {
+            return Err(CloudInitError::Command(format!(
+                "{} failed: {}",
+                command,
+                output.stderr.trim()
+            )));
+        }
+    }
+    info!("Started {}", service);
+    Ok(())
+}
+
+/// A scalar YAML value as written in a config file
+pub fn scalar_string(value: &serde_yaml::Value) -> Option<String> {
+    match value {
+        serde_yaml::Value::String(s) => Some(s.clone()),
+        serde_yaml::Value::Number(n) => Some(n.to_string()),
+        serde_yaml::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -79,6 +230,18 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_start_service_under_openrc_root() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        std::fs::create_dir_all(root.path("/sbin")).unwrap();
+        std::fs::write(root.path("/sbin/openrc-run"), "").unwrap();
+
+        let runner = crate::runner::RecordingRunner::new();
+        start_service(&runner, &root, "chronyd").await.unwrap();
+        assert_eq!(runner.commands(), vec!["rc-update add chronyd default"]);
+    }
+
     #[test]
     fn test_module_trait_default_frequency() {
         let m = TestModule;
@@ -0,0 +1,94 @@
+//! Message of the day module
+//!
+//! Implements the `motd` cloud-config key. Where update-motd assembles the
+//! message from `/etc/update-motd.d` (Ubuntu), the text goes into a script
+//! there so it is shown alongside the generated parts; elsewhere it is
+//! written to `/etc/motd`.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! motd: |
+//!   Managed by the platform team; changes are overwritten.
+//! ```
+
+use crate::CloudInitError;
+use crate::root::RootContext;
+use tracing::info;
+
+/// Static message of the day
+const MOTD: &str = "/etc/motd";
+
+/// Scripts update-motd runs to build the message
+const UPDATE_MOTD_DIR: &str = "/etc/update-motd.d";
+
+/// Script added to `UPDATE_MOTD_DIR`, late so the text comes last
+const UPDATE_MOTD_SCRIPT: &str = "99-cloud-init";
+
+/// Write the message of the day
+pub async fn write_motd(root: &RootContext, motd: &str) -> Result<(), CloudInitError> {
+    let update_motd = root.path(UPDATE_MOTD_DIR);
+    if update_motd.is_dir() {
+        let script = update_motd.join(UPDATE_MOTD_SCRIPT);
+        root.write_file(&script, render_script(motd)).await?;
+        root.set_mode(&script, 0o755).await?;
+        info!("Wrote {}/{}", UPDATE_MOTD_DIR, UPDATE_MOTD_SCRIPT);
+    } else {
+        let path = root.path(MOTD);
+        if let Some(dir) = path.parent() {
+            root.create_dir_all(dir).await?;
+        }
+        let content = if motd.ends_with('\n') {
+            motd.to_string()
+        } else {
+            format!("{}\n", motd)
+        };
+        root.write_file(&path, content).await?;
+        root.set_mode(&path, 0o644).await?;
+        info!("Wrote {}", MOTD);
+    }
+    Ok(())
+}
+
+/// An update-motd script printing `motd` (pure function for testability)
+fn render_script(motd: &str) -> String {
+    let quoted = motd.trim_end_matches('\n').replace('\'', r"'\''");
+    format!(
+        "#!/bin/sh\n# Written by cloud-init-rs from the motd cloud-config key\nprintf '%s\\n' '{}'\n",
+        quoted
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_write_motd_static() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        write_motd(&root, "Welcome").await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.path(MOTD)).unwrap(),
+            "Welcome\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_motd_update_motd_script() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        std::fs::create_dir_all(root.path(UPDATE_MOTD_DIR)).unwrap();
+        write_motd(&root, "It's managed\nby ops\n").await.unwrap();
+
+        let script = root.path(UPDATE_MOTD_DIR).join(UPDATE_MOTD_SCRIPT);
+        assert_eq!(
+            std::fs::read_to_string(&script).unwrap(),
+            "#!/bin/sh\n\
+             # Written by cloud-init-rs from the motd cloud-config key\n\
+             printf '%s\\n' 'It'\\''s managed\nby ops'\n"
+        );
+        assert!(!root.path(MOTD).exists());
+    }
+}
@@ -0,0 +1,510 @@
+//! Mounts module
+//!
+//! Adds the `mounts` entries to `/etc/fstab` and mounts them. Devices may
+//! be named by alias, resolved against the datasource:
+//!
+//! - `ephemeral0`, `ephemeral1`, ... and `swap`: EC2's block device
+//!   mapping; on Azure `ephemeral0` is the resource disk
+//! - `<alias>.<n>`: partition `n` of the aliased disk
+//!
+//! Entries whose alias does not resolve to an existing device are left
+//! out. Lines this module wrote are marked `comment=cloudconfig` and
+//! replaced on every run, so removed entries disappear from fstab.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! mounts:
+//!   - [ephemeral0, /mnt, auto, "defaults,noexec"]
+//!   - [swap, none, swap, sw, "0", "0"]
+//!   - [sdc, null]          # no fstab entry for sdc
+//! mount_default_fields: [null, null, auto, "defaults,nofail", "0", "2"]
+//! ```
+
+use super::scalar_string;
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use crate::service::ServiceManager;
+use crate::{CloudInitError, InstanceMetadata};
+use std::path::Path;
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+/// Marker in the options of fstab lines this module owns
+const MARKER: &str = "comment=cloudconfig";
+
+/// Fields of an entry that leaves them out
+const DEFAULT_FIELDS: [Option<&str>; 6] = [
+    None,
+    None,
+    Some("auto"),
+    Some("defaults,nofail,x-systemd.after=cloud-init.service,_netdev"),
+    Some("0"),
+    Some("2"),
+];
+
+/// Entries used unless `mounts` names the same device
+const DEFAULT_MOUNTS: &[&[&str]] = &[
+    &["ephemeral0", "/mnt"],
+    &["swap", "none", "swap", "sw", "0", "0"],
+];
+
+/// Azure's resource disk, by udev rules old and new
+const AZURE_RESOURCE_DISKS: &[&str] =
+    &["/dev/disk/cloud/azure_resource", "/dev/disk/azure/resource"];
+
+/// Device names the kernel may give an EC2 `sdX` mapping
+const EC2_DEVICE_PREFIXES: &[&str] = &["sd", "xvd", "vd"];
+
+/// An fstab line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FstabEntry {
+    pub device: String,
+    pub mountpoint: String,
+    pub fstype: String,
+    pub options: String,
+    pub freq: String,
+    pub passno: String,
+}
+
+impl FstabEntry {
+    fn is_swap(&self) -> bool {
+        self.fstype == "swap"
+    }
+
+    fn line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{},{}\t{}\t{}",
+            self.device, self.mountpoint, self.fstype, self.options, MARKER, self.freq, self.passno
+        )
+    }
+}
+
+/// Write the `mounts` entries to fstab and mount them
+pub async fn configure_mounts(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    mounts: Option<&[Vec<serde_yaml::Value>]>,
+    default_fields: Option<&[serde_yaml::Value]>,
+    metadata: &InstanceMetadata,
+) -> Result<(), CloudInitError> {
+    let defaults = default_fields
+        .map(|fields| fields.iter().map(scalar_string).collect())
+        .unwrap_or_else(|| DEFAULT_FIELDS.map(|f| f.map(str::to_string)).to_vec());
+    let requested: Vec<Vec<Option<String>>> = mounts
+        .unwrap_or_default()
+        .iter()
+        .map(|entry| entry.iter().map(scalar_string).collect())
+        .collect();
+
+    let entries = fstab_entries(&requested, &defaults, |name| {
+        resolve_device(root, name, metadata)
+    });
+
+    let fstab = root.path("/etc/fstab");
+    let current = fs::read_to_string(&fstab).await.unwrap_or_default();
+    let updated = update_fstab(&current, &entries);
+    if updated == current || (current.is_empty() && entries.is_empty()) {
+        debug!("mounts: fstab unchanged");
+        return Ok(());
+    }
+
+    for entry in entries.iter().filter(|e| !e.is_swap()) {
+        if entry.mountpoint.starts_with('/') {
+            root.create_dir_all(&root.path(&entry.mountpoint)).await?;
+        }
+    }
+    info!("mounts: writing {} fstab entries", entries.len());
+    root.write_file_atomic(&fstab, updated).await?;
+
+    // An image built under an alternate root mounts them when it boots
+    if !root.is_host() {
+        return Ok(());
+    }
+    let mut commands = Vec::new();
+    if ServiceManager::detect(root) == ServiceManager::Systemd {
+        // Regenerate the mount units from the new fstab
+        commands.push(SystemCommand::new("systemctl").arg("daemon-reload"));
+    }
+    commands.push(SystemCommand::new("mount").arg("-a"));
+    if entries.iter().any(FstabEntry::is_swap) {
+        commands.push(SystemCommand::new("swapon").arg("-a"));
+    }
+    for command in commands {
+        let output = runner.run(&command).await?;
+        if !output.is_success() {
+            warn!("mounts: {} failed: {}", command, output.stderr.trim());
+        }
+    }
+    Ok(())
+}
+
+/// The fstab entries for `requested` plus the default mounts
+///
+/// `resolve` maps a device name to its path, `None` dropping the entry.
+/// An entry without a mount point only removes the device's default.
+pub fn fstab_entries(
+    requested: &[Vec<Option<String>>],
+    defaults: &[Option<String>],
+    resolve: impl Fn(&str) -> Option<String>,
+) -> Vec<FstabEntry> {
+    let named = |entry: &[Option<String>], device: &str| {
+        entry.first().and_then(Option::as_deref) == Some(device)
+    };
+    let default_mounts = DEFAULT_MOUNTS.iter().filter_map(|fields| {
+        let device = fields[0];
+        (!requested.iter().any(|entry| named(entry, device)))
+            .then(|| fields.iter().map(|f| Some(f.to_string())).collect())
+    });
+
+    let mut entries = Vec::new();
+    for fields in requested.iter().cloned().chain(default_mounts) {
+        let field = |i: usize| {
+            fields
+                .get(i)
+                .cloned()
+                .flatten()
+                .or_else(|| defaults.get(i).cloned().flatten())
+        };
+        let (Some(name), Some(mountpoint)) = (field(0), fields.get(1).cloned().flatten()) else {
+            continue;
+        };
+        let Some(device) = resolve(&name) else {
+            debug!("mounts: ignoring {}, no such device", name);
+            continue;
+        };
+        let fstype = field(2).unwrap_or_else(|| "auto".to_string());
+        let is_swap = fstype == "swap";
+        entries.push(FstabEntry {
+            device,
+            mountpoint: if is_swap {
+                "none".to_string()
+            } else {
+                mountpoint
+            },
+            fstype,
+            options: field(3).unwrap_or_else(|| "defaults".to_string()),
+            freq: field(4).unwrap_or_else(|| "0".to_string()),
+            passno: field(5).unwrap_or_else(|| if is_swap { "0" } else { "2" }.to_string()),
+        });
+    }
+    entries
+}
+
+/// `fstab` with the lines of earlier runs replaced by `entries`
+pub fn update_fstab(fstab: &str, entries: &[FstabEntry]) -> String {
+    let mut out: String = fstab
+        .lines()
+        .filter(|line| !line.contains(MARKER))
+        .flat_map(|line| [line, "\n"])
+        .collect();
+    for entry in entries {
+        out.push_str(&entry.line());
+        out.push('\n');
+    }
+    out
+}
+
+/// The device path for `name`, which may be an alias
+///
+/// Paths, `LABEL=`/`UUID=` specs and network shares are kept as they
+/// are; plain names such as `sdb` are taken to be in `/dev`.
+pub fn resolve_device(
+    root: &RootContext,
+    name: &str,
+    metadata: &InstanceMetadata,
+) -> Option<String> {
+    if name.starts_with('/') || name.contains('=') || name.contains(':') {
+        return Some(name.to_string());
+    }
+    let (alias, partition) = match name.rsplit_once('.') {
+        Some((alias, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => {
+            (alias, Some(n))
+        }
+        _ => (name, None),
+    };
+    let exists = |path: &str| root.path(path).exists();
+
+    let disk = if metadata.platform.as_deref() == Some("azure") && alias == "ephemeral0" {
+        AZURE_RESOURCE_DISKS
+            .iter()
+            .find(|disk| exists(disk))?
+            .to_string()
+    } else if let Some(device) = metadata.block_device_mapping().get(alias) {
+        ec2_device_candidates(device)
+            .into_iter()
+            .find(|path| exists(path))?
+    } else if is_alias(alias) {
+        return None;
+    } else {
+        format!("/dev/{}", alias)
+    };
+
+    Some(match partition {
+        None => disk,
+        Some(n) => partition_path(&disk, n),
+    })
+}
+
+/// Names only the datasource can resolve
+fn is_alias(name: &str) -> bool {
+    name == "swap"
+        || name == "ami"
+        || name == "root"
+        || name
+            .strip_prefix("ephemeral")
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Paths an EC2 mapping such as `sdb` may appear as: the Xen and virtio
+/// drivers rename `sdX` disks
+fn ec2_device_candidates(device: &str) -> Vec<String> {
+    let name = device.strip_prefix("/dev/").unwrap_or(device);
+    let suffix = EC2_DEVICE_PREFIXES
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix));
+    match suffix {
+        Some(suffix) => EC2_DEVICE_PREFIXES
+            .iter()
+            .map(|prefix| format!("/dev/{}{}", prefix, suffix))
+            .collect(),
+        None => vec![format!("/dev/{}", name)],
+    }
+}
+
+/// Partition `n` of `disk`: `-partN` for udev links, `pN` after a digit
+fn partition_path(disk: &str, n: &str) -> String {
+    if Path::new(disk).starts_with("/dev/disk") {
+        format!("{}-part{}", disk, n)
+    } else if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}p{}", disk, n)
+    } else {
+        format!("{}{}", disk, n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::RecordingRunner;
+    use serde_json::json;
+
+    fn fields(values: &[Option<&str>]) -> Vec<Option<String>> {
+        values.iter().map(|v| v.map(str::to_string)).collect()
+    }
+
+    fn defaults() -> Vec<Option<String>> {
+        DEFAULT_FIELDS.map(|f| f.map(str::to_string)).to_vec()
+    }
+
+    fn ec2(mapping: serde_json::Value) -> InstanceMetadata {
+        InstanceMetadata {
+            platform: Some("ec2".to_string()),
+            raw: json!({ "block-device-mapping": mapping }),
+            ..Default::default()
+        }
+    }
+
+    fn touch(root: &RootContext, path: &str) {
+        let path = root.path(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "").unwrap();
+    }
+
+    #[test]
+    fn test_resolve_ec2_aliases() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = RootContext::new(dir.path());
+        touch(&root, "/dev/xvdb");
+        touch(&root, "/dev/sdc");
+        let metadata = ec2(json!({"ephemeral0": "sdb", "swap": "/dev/sdc", "ephemeral1": "sdd"}));
+
+        // Xen names sdb xvdb
+        assert_eq!(
+            resolve_device(&root, "ephemeral0", &metadata).as_deref(),
+            Some("/dev/xvdb")
+        );
+        assert_eq!(
+            resolve_device(&root, "ephemeral0.1", &metadata).as_deref(),
+            Some("/dev/xvdb1")
+        );
+        assert_eq!(
+            resolve_device(&root, "swap", &metadata).as_deref(),
+            Some("/dev/sdc")
+        );
+        // Mapped, but not attached
+        assert_eq!(resolve_device(&root, "ephemeral1", &metadata), None);
+        assert_eq!(resolve_device(&root, "ephemeral2", &metadata), None);
+
+        assert_eq!(
+            resolve_device(&root, "sdf", &metadata).as_deref(),
+            Some("/dev/sdf")
+        );
+        assert_eq!(
+            resolve_device(&root, "LABEL=data", &metadata).as_deref(),
+            Some("LABEL=data")
+        );
+        assert_eq!(
+            resolve_device(&root, "nfs:/export", &metadata).as_deref(),
+            Some("nfs:/export")
+        );
+    }
+
+    #[test]
+    fn test_resolve_azure_resource_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = RootContext::new(dir.path());
+        let azure = InstanceMetadata {
+            platform: Some("azure".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(resolve_device(&root, "ephemeral0", &azure), None);
+
+        touch(&root, "/dev/disk/azure/resource");
+        assert_eq!(
+            resolve_device(&root, "ephemeral0.1", &azure).as_deref(),
+            Some("/dev/disk/azure/resource-part1")
+        );
+        touch(&root, "/dev/disk/cloud/azure_resource");
+        assert_eq!(
+            resolve_device(&root, "ephemeral0", &azure).as_deref(),
+            Some("/dev/disk/cloud/azure_resource")
+        );
+    }
+
+    #[test]
+    fn test_partition_path() {
+        assert_eq!(partition_path("/dev/sdb", "1"), "/dev/sdb1");
+        assert_eq!(partition_path("/dev/nvme1n1", "2"), "/dev/nvme1n1p2");
+        assert_eq!(
+            partition_path("/dev/disk/cloud/azure_resource", "1"),
+            "/dev/disk/cloud/azure_resource-part1"
+        );
+    }
+
+    #[test]
+    fn test_fstab_entries_merge_defaults() {
+        let resolve = |name: &str| match name {
+            "ephemeral0" => Some("/dev/xvdb".to_string()),
+            "swap" => Some("/dev/xvdc".to_string()),
+            "sdf" => Some("/dev/sdf".to_string()),
+            _ => None,
+        };
+        let requested = vec![
+            fields(&[
+                Some("ephemeral0"),
+                Some("/data"),
+                None,
+                Some("defaults,noexec"),
+            ]),
+            fields(&[Some("swap"), None]),
+            fields(&[Some("sdf"), Some("/srv")]),
+            fields(&[Some("ephemeral3"), Some("/scratch")]),
+        ];
+        let entries = fstab_entries(&requested, &defaults(), resolve);
+        assert_eq!(
+            entries,
+            vec![
+                FstabEntry {
+                    device: "/dev/xvdb".to_string(),
+                    mountpoint: "/data".to_string(),
+                    fstype: "auto".to_string(),
+                    options: "defaults,noexec".to_string(),
+                    freq: "0".to_string(),
+                    passno: "2".to_string(),
+                },
+                FstabEntry {
+                    device: "/dev/sdf".to_string(),
+                    mountpoint: "/srv".to_string(),
+                    fstype: "auto".to_string(),
+                    options: DEFAULT_FIELDS[3].unwrap().to_string(),
+                    freq: "0".to_string(),
+                    passno: "2".to_string(),
+                },
+            ]
+        );
+
+        // Unconfigured, the defaults mount ephemeral0 and enable swap
+        let entries = fstab_entries(&[], &defaults(), resolve);
+        assert_eq!(
+            entries[0].line(),
+            format!(
+                "/dev/xvdb\t/mnt\tauto\t{},{}\t0\t2",
+                DEFAULT_FIELDS[3].unwrap(),
+                MARKER
+            )
+        );
+        assert_eq!(
+            entries[1].line(),
+            "/dev/xvdc\tnone\tswap\tsw,comment=cloudconfig\t0\t0"
+        );
+    }
+
+    #[test]
+    fn test_update_fstab_replaces_earlier_entries() {
+        let fstab = "\
+LABEL=root\t/\text4\tdefaults\t0\t1
+/dev/xvdb\t/mnt\tauto\tdefaults,comment=cloudconfig\t0\t2
+";
+        let entry = FstabEntry {
+            device: "/dev/xvdb".to_string(),
+            mountpoint: "/data".to_string(),
+            fstype: "ext4".to_string(),
+            options: "defaults".to_string(),
+            freq: "0".to_string(),
+            passno: "2".to_string(),
+        };
+        assert_eq!(
+            update_fstab(fstab, &[entry]),
+            "\
+LABEL=root\t/\text4\tdefaults\t0\t1
+/dev/xvdb\t/data\text4\tdefaults,comment=cloudconfig\t0\t2
+"
+        );
+        assert_eq!(
+            update_fstab(fstab, &[]),
+            "LABEL=root\t/\text4\tdefaults\t0\t1\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_configure_mounts_under_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = RootContext::new(dir.path());
+        touch(&root, "/dev/sdb");
+        std::fs::create_dir_all(root.path("/etc")).unwrap();
+        std::fs::write(root.path("/etc/fstab"), "LABEL=root / ext4 defaults 0 1\n").unwrap();
+        let mounts: Vec<Vec<serde_yaml::Value>> =
+            serde_yaml::from_str("[[ephemeral0, /mnt/scratch, ext4]]").unwrap();
+
+        let runner = RecordingRunner::new();
+        configure_mounts(
+            &runner,
+            &root,
+            Some(&mounts),
+            None,
+            &ec2(json!({"ephemeral0": "sdb"})),
+        )
+        .await
+        .unwrap();
+
+        let fstab = std::fs::read_to_string(root.path("/etc/fstab")).unwrap();
+        assert!(
+            fstab.starts_with("LABEL=root / ext4 defaults 0 1\n/dev/sdb\t/mnt/scratch\text4\t")
+        );
+        assert!(root.path("/mnt/scratch").is_dir());
+        // Not mounted under an alternate root
+        assert!(runner.commands().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_configure_mounts_nothing_to_do() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = RootContext::new(dir.path());
+        let runner = RecordingRunner::new();
+        configure_mounts(&runner, &root, None, None, &InstanceMetadata::default())
+            .await
+            .unwrap();
+        assert!(!root.path("/etc/fstab").exists());
+    }
+}
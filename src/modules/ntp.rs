@@ -1,10 +1,12 @@
 //! NTP configuration module
 //!
 //! Configures NTP time synchronization via chrony, systemd-timesyncd, or ntpd.
+//! Services are only restarted on the running system, not under an
+//! alternate root.
 
 use crate::CloudInitError;
-use std::path::Path;
-use tokio::fs;
+use crate::root::RootContext;
+use crate::service::ServiceManager;
 use tracing::{debug, info, warn};
 
 /// NTP configuration
@@ -29,7 +31,7 @@ impl Default for NtpConfig {
 }
 
 /// Configure NTP based on available service
-pub async fn configure_ntp(config: &NtpConfig) -> Result<(), CloudInitError> {
+pub async fn configure_ntp(root: &RootContext, config: &NtpConfig) -> Result<(), CloudInitError> {
     if !config.enabled {
         info!("NTP disabled by configuration");
         return Ok(());
@@ -38,15 +40,15 @@ pub async fn configure_ntp(config: &NtpConfig) -> Result<(), CloudInitError> {
     info!("Configuring NTP");
 
     // Try services in order of preference
-    if try_configure_chrony(config).await? {
+    if try_configure_chrony(root, config).await? {
         return Ok(());
     }
 
-    if try_configure_timesyncd(config).await? {
+    if try_configure_timesyncd(root, config).await? {
         return Ok(());
     }
 
-    if try_configure_ntpd(config).await? {
+    if try_configure_ntpd(root, config).await? {
         return Ok(());
     }
 
@@ -72,9 +74,12 @@ fn build_chrony_content(config: &NtpConfig) -> String {
 }
 
 /// Configure chrony (preferred on RHEL/Fedora/newer Ubuntu)
-async fn try_configure_chrony(config: &NtpConfig) -> Result<bool, CloudInitError> {
-    let chrony_conf = Path::new("/etc/chrony.conf");
-    let chrony_d = Path::new("/etc/chrony/chrony.conf");
+async fn try_configure_chrony(
+    root: &RootContext,
+    config: &NtpConfig,
+) -> Result<bool, CloudInitError> {
+    let chrony_conf = root.path("/etc/chrony.conf");
+    let chrony_d = root.path("/etc/chrony/chrony.conf");
 
     let conf_path = if chrony_conf.exists() {
         chrony_conf
@@ -88,11 +93,9 @@ async fn try_configure_chrony(config: &NtpConfig) -> Result<bool, CloudInitError
     info!("Configuring chrony");
     let content = build_chrony_content(config);
 
-    fs::write(conf_path, &content)
-        .await
-        .map_err(CloudInitError::Io)?;
+    root.write_file(&conf_path, &content).await?;
 
-    restart_service("chronyd").await?;
+    restart_service(root, "chronyd").await?;
     Ok(true)
 }
 
@@ -109,15 +112,18 @@ fn build_timesyncd_content(config: &NtpConfig) -> String {
 }
 
 /// Configure systemd-timesyncd (default on many systemd systems)
-async fn try_configure_timesyncd(config: &NtpConfig) -> Result<bool, CloudInitError> {
-    let timesyncd_conf = Path::new("/etc/systemd/timesyncd.conf");
-
-    let status = tokio::process::Command::new("systemctl")
-        .args(["is-enabled", "systemd-timesyncd"])
-        .output()
+async fn try_configure_timesyncd(
+    root: &RootContext,
+    config: &NtpConfig,
+) -> Result<bool, CloudInitError> {
+    let timesyncd_conf = root.path("/etc/systemd/timesyncd.conf");
+
+    let status = root
+        .runner()
+        .run(&ServiceManager::detect(root).is_enabled("systemd-timesyncd"))
         .await;
 
-    if status.is_err() || !status.unwrap().status.success() {
+    if !status.is_ok_and(|s| s.is_success()) {
         debug!("systemd-timesyncd not available");
         return Ok(false);
     }
@@ -125,11 +131,9 @@ async fn try_configure_timesyncd(config: &NtpConfig) -> Result<bool, CloudInitEr
     info!("Configuring systemd-timesyncd");
     let content = build_timesyncd_content(config);
 
-    fs::write(timesyncd_conf, &content)
-        .await
-        .map_err(CloudInitError::Io)?;
+    root.write_file(&timesyncd_conf, &content).await?;
 
-    restart_service("systemd-timesyncd").await?;
+    restart_service(root, "systemd-timesyncd").await?;
     Ok(true)
 }
 
@@ -152,8 +156,11 @@ fn build_ntpd_content(config: &NtpConfig) -> String {
 }
 
 /// Configure ntpd (legacy systems)
-async fn try_configure_ntpd(config: &NtpConfig) -> Result<bool, CloudInitError> {
-    let ntp_conf = Path::new("/etc/ntp.conf");
+async fn try_configure_ntpd(
+    root: &RootContext,
+    config: &NtpConfig,
+) -> Result<bool, CloudInitError> {
+    let ntp_conf = root.path("/etc/ntp.conf");
 
     if !ntp_conf.exists() {
         debug!("ntpd not found");
@@ -163,34 +170,32 @@ async fn try_configure_ntpd(config: &NtpConfig) -> Result<bool, CloudInitError>
     info!("Configuring ntpd");
     let content = build_ntpd_content(config);
 
-    fs::write(ntp_conf, &content)
-        .await
-        .map_err(CloudInitError::Io)?;
+    root.write_file(&ntp_conf, &content).await?;
 
-    restart_service("ntpd").await?;
+    restart_service(root, "ntpd").await?;
     Ok(true)
 }
 
-/// Restart a systemd service
-async fn restart_service(service: &str) -> Result<(), CloudInitError> {
+/// Restart a service
+async fn restart_service(root: &RootContext, service: &str) -> Result<(), CloudInitError> {
+    if !root.is_host() {
+        debug!("Not restarting {} under alternate root", service);
+        return Ok(());
+    }
     debug!("Restarting service: {}", service);
 
-    let output = tokio::process::Command::new("systemctl")
-        .args(["restart", service])
-        .output()
+    let output = root
+        .runner()
+        .run(&ServiceManager::detect(root).restart(service))
         .await;
 
     match output {
-        Ok(output) if output.status.success() => {
+        Ok(output) if output.is_success() => {
             info!("Restarted {}", service);
             Ok(())
         }
         Ok(output) => {
-            warn!(
-                "Failed to restart {}: {}",
-                service,
-                String::from_utf8_lossy(&output.stderr)
-            );
+            warn!("Failed to restart {}: {}", service, output.stderr);
             Ok(())
         }
         Err(e) => {
@@ -327,7 +332,7 @@ mod tests {
             pools: vec![],
             enabled: false,
         };
-        let result = configure_ntp(&config).await;
+        let result = configure_ntp(&RootContext::host(), &config).await;
         assert!(result.is_ok());
     }
 
@@ -337,6 +342,6 @@ mod tests {
         // On macOS, /etc/ntp.conf may exist and fail with permission error.
         // On Linux CI without NTP services, this returns Ok(()).
         // Either outcome is acceptable.
-        let _ = configure_ntp(&config).await;
+        let _ = configure_ntp(&RootContext::host(), &config).await;
     }
 }
@@ -0,0 +1,580 @@
+//! Package mirror selection module
+//!
+//! Picks this instance's package mirrors from the `package_mirrors` system
+//! config, filling `%(name)s` placeholders in the search templates from
+//! the instance metadata, and points the package manager's sources at
+//! them. Source URLs that start with an entry's `failsafe` mirror are
+//! rewritten, so images ship sources pointing at the failsafe mirrors.
+//!
+//! Placeholders: `region`, `availability_zone`, `ec2_region` (on AWS
+//! only, derived from the zone when the region is unknown), `cloud_name`
+//! and `platform`. A template naming one the metadata lacks is skipped,
+//! as is one whose host does not resolve.
+//!
+//! # System config example
+//!
+//! ```yaml
+//! package_mirrors:
+//!   - arches: [amd64, i386]
+//!     failsafe:
+//!       primary: http://archive.ubuntu.com/ubuntu
+//!       security: http://security.ubuntu.com/ubuntu
+//!     search:
+//!       primary:
+//!         - http://%(ec2_region)s.ec2.archive.ubuntu.com/ubuntu/
+//!         - http://%(region)s.clouds.archive.ubuntu.com/ubuntu/
+//!       security: []
+//! ```
+
+use super::packages::PackageManager;
+use crate::config::PackageMirrorConfig;
+use crate::distro::Distro;
+use crate::root::RootContext;
+use crate::runner::SystemRunner;
+use crate::{CloudInitError, InstanceMetadata};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+/// How long a mirror's host gets to resolve before the next is tried
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A failsafe mirror and the mirror chosen in its place
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub from: String,
+    pub to: String,
+}
+
+/// Point the package manager's sources at the mirrors for this instance
+pub async fn configure(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    entries: &[PackageMirrorConfig],
+    metadata: &InstanceMetadata,
+) -> Result<(), CloudInitError> {
+    let Some(entry) = select_entry(entries, &arch_names()) else {
+        debug!("package_mirrors: no entry for {}", std::env::consts::ARCH);
+        return Ok(());
+    };
+    let Some(pm) = PackageManager::detect(runner, &Distro::detect(root).await).await else {
+        warn!("package_mirrors: no supported package manager found");
+        return Ok(());
+    };
+
+    let vars = placeholders(metadata);
+    let mut relocations = Vec::new();
+    for (name, failsafe) in &entry.failsafe {
+        let mut chosen = None;
+        for candidate in candidates(entry, name, &vars) {
+            // Under an alternate root the image boots elsewhere, so the
+            // build host's resolver says nothing about the mirror
+            if !root.is_host() || resolves(&candidate).await {
+                chosen = Some(candidate);
+                break;
+            }
+            debug!("package_mirrors: {} does not resolve", candidate);
+        }
+        let to = chosen.unwrap_or_else(|| failsafe.clone());
+        info!("package_mirrors: {} mirror is {}", name, to);
+        let relocation = Relocation {
+            from: trim_url(failsafe).to_string(),
+            to: trim_url(&to).to_string(),
+        };
+        if relocation.from != relocation.to {
+            relocations.push(relocation);
+        }
+    }
+    if relocations.is_empty() {
+        return Ok(());
+    }
+    // A failsafe mirror that is a prefix of another must not claim its URLs
+    relocations.sort_by_key(|r| std::cmp::Reverse(r.from.len()));
+
+    for path in source_files(root, pm).await {
+        let content = fs::read_to_string(&path).await?;
+        if let Some(updated) = rewrite_sources(pm, &content, &relocations) {
+            debug!("package_mirrors: updating {}", path.display());
+            root.write_file_atomic(&path, updated).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Names of this machine's architecture: Debian's, then the kernel's
+fn arch_names() -> Vec<&'static str> {
+    let kernel = std::env::consts::ARCH;
+    let debian = match kernel {
+        "x86_64" => "amd64",
+        "x86" => "i386",
+        "aarch64" => "arm64",
+        "arm" => "armhf",
+        "powerpc64" if cfg!(target_endian = "little") => "ppc64el",
+        other => other,
+    };
+    vec![debian, kernel]
+}
+
+/// The entry for `arches`: the last naming one of them, else the last
+/// `default` one, so drop-ins override cloud.cfg
+pub fn select_entry<'a>(
+    entries: &'a [PackageMirrorConfig],
+    arches: &[&str],
+) -> Option<&'a PackageMirrorConfig> {
+    let names = |entry: &&PackageMirrorConfig, wanted: &[&str]| {
+        entry.arches.iter().any(|a| wanted.contains(&a.as_str()))
+    };
+    entries
+        .iter()
+        .rfind(|entry| names(entry, arches))
+        .or_else(|| entries.iter().rfind(|entry| names(entry, &["default"])))
+}
+
+/// Placeholder values known from the metadata
+pub fn placeholders(metadata: &InstanceMetadata) -> BTreeMap<&'static str, String> {
+    let mut vars = BTreeMap::new();
+    if let Some(region) = &metadata.region {
+        vars.insert("region", region.clone());
+    }
+    if let Some(zone) = &metadata.availability_zone {
+        vars.insert("availability_zone", zone.clone());
+    }
+    if let Some(cloud) = &metadata.cloud_name {
+        vars.insert("cloud_name", cloud.clone());
+    }
+    if let Some(platform) = &metadata.platform {
+        vars.insert("platform", platform.clone());
+    }
+    if metadata.cloud_name.as_deref() == Some("aws") {
+        // us-east-1a is in us-east-1
+        let region = metadata.region.clone().or_else(|| {
+            metadata
+                .availability_zone
+                .as_deref()
+                .map(|zone| zone.trim_end_matches(|c: char| c.is_ascii_lowercase()))
+                .filter(|region| !region.is_empty())
+                .map(str::to_string)
+        });
+        if let Some(region) = region {
+            vars.insert("ec2_region", region);
+        }
+    }
+    vars
+}
+
+/// Fill the `%(name)s` placeholders in `template`
+///
+/// `None` if it names a placeholder without a value.
+pub fn render_template(template: &str, vars: &BTreeMap<&str, String>) -> Option<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("%(") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find(")s")?;
+        out.push_str(vars.get(&after[..end])?);
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+/// The search templates for mirror `name` that can be filled, in order
+pub fn candidates(
+    entry: &PackageMirrorConfig,
+    name: &str,
+    vars: &BTreeMap<&str, String>,
+) -> Vec<String> {
+    entry
+        .search
+        .get(name)
+        .into_iter()
+        .flatten()
+        .filter_map(|template| render_template(template, vars))
+        .collect()
+}
+
+/// Whether the host of `url` resolves
+async fn resolves(url: &str) -> bool {
+    let Ok(parsed) = reqwest::Url::parse(url) else {
+        return false;
+    };
+    let (Some(host), Some(port)) = (parsed.host_str(), parsed.port_or_known_default()) else {
+        return false;
+    };
+    let lookup = tokio::net::lookup_host((host, port));
+    match tokio::time::timeout(RESOLVE_TIMEOUT, lookup).await {
+        Ok(Ok(mut addrs)) => addrs.next().is_some(),
+        _ => false,
+    }
+}
+
+fn trim_url(url: &str) -> &str {
+    url.trim_end_matches('/')
+}
+
+/// The source files `pm` reads, as host paths
+async fn source_files(root: &RootContext, pm: PackageManager) -> Vec<PathBuf> {
+    let (files, dirs): (&[&str], &[(&str, &[&str])]) = match pm {
+        PackageManager::Apt => (
+            &["/etc/apt/sources.list"],
+            &[("/etc/apt/sources.list.d", &["list", "sources"])],
+        ),
+        PackageManager::Apk => (&["/etc/apk/repositories"], &[]),
+        PackageManager::Dnf | PackageManager::Yum => (&[], &[("/etc/yum.repos.d", &["repo"])]),
+        PackageManager::Zypper => (&[], &[("/etc/zypp/repos.d", &["repo"])]),
+    };
+    let mut paths: Vec<PathBuf> = files
+        .iter()
+        .map(|file| root.path(file))
+        .filter(|path| path.is_file())
+        .collect();
+    for (dir, extensions) in dirs {
+        let Ok(mut read_dir) = fs::read_dir(root.path(dir)).await else {
+            continue;
+        };
+        let mut found = Vec::new();
+        while let Ok(Some(entry)) = read_dir.next_entry().await {
+            let path = entry.path();
+            let matches = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| extensions.contains(&e));
+            if matches && path.is_file() {
+                found.push(path);
+            }
+        }
+        found.sort();
+        paths.extend(found);
+    }
+    paths
+}
+
+/// Rewrite the failsafe mirror URLs in a source file
+///
+/// For dnf and yum a relocated `#baseurl=` is enabled and the section's
+/// `mirrorlist=` and `metalink=` are commented out, since they would
+/// override it. `None` if nothing changed.
+pub fn rewrite_sources(
+    pm: PackageManager,
+    content: &str,
+    relocations: &[Relocation],
+) -> Option<String> {
+    let rpm = matches!(pm, PackageManager::Dnf | PackageManager::Yum);
+    let mut sections: Vec<Vec<String>> = vec![Vec::new()];
+    let mut pinned = vec![false];
+    let mut changed = false;
+
+    for line in content.lines() {
+        if rpm && line.trim_start().starts_with('[') {
+            sections.push(Vec::new());
+            pinned.push(false);
+        }
+        let line = match relocate_line(line, relocations) {
+            Some(relocated) => {
+                changed = true;
+                let baseurl = relocated.strip_prefix('#').unwrap_or(&relocated);
+                if rpm && baseurl.starts_with("baseurl=") {
+                    if let Some(pinned) = pinned.last_mut() {
+                        *pinned = true;
+                    }
+                    baseurl.to_string()
+                } else {
+                    relocated
+                }
+            }
+            None => line.to_string(),
+        };
+        if let Some(section) = sections.last_mut() {
+            section.push(line);
+        }
+    }
+    if !changed {
+        return None;
+    }
+
+    let mut out = String::with_capacity(content.len());
+    for (section, pinned) in sections.iter().zip(pinned) {
+        for line in section {
+            if pinned && (line.starts_with("mirrorlist=") || line.starts_with("metalink=")) {
+                out.push('#');
+            }
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    Some(out)
+}
+
+/// `line` with the first failsafe mirror URL in it relocated
+fn relocate_line(line: &str, relocations: &[Relocation]) -> Option<String> {
+    for relocation in relocations {
+        let mut from = 0;
+        while let Some(found) = line[from..].find(&relocation.from) {
+            let start = from + found;
+            let end = start + relocation.from.len();
+            // Only whole path segments: the mirror, not a longer name
+            let boundary = line[end..]
+                .chars()
+                .next()
+                .is_none_or(|c| c == '/' || c.is_whitespace() || c == '"' || c == '\'');
+            if boundary {
+                return Some(format!(
+                    "{}{}{}",
+                    &line[..start],
+                    relocation.to,
+                    &line[end..]
+                ));
+            }
+            from = end;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::RecordingRunner;
+
+    fn ubuntu_entry() -> PackageMirrorConfig {
+        serde_yaml::from_str(
+            r#"
+arches: [amd64, i386]
+failsafe:
+  primary: http://archive.ubuntu.com/ubuntu
+  security: http://security.ubuntu.com/ubuntu
+search:
+  primary:
+    - http://%(ec2_region)s.ec2.archive.ubuntu.com/ubuntu/
+    - http://%(region)s.clouds.archive.ubuntu.com/ubuntu/
+  security: []
+"#,
+        )
+        .unwrap()
+    }
+
+    fn aws(region: Option<&str>, zone: Option<&str>) -> InstanceMetadata {
+        InstanceMetadata {
+            region: region.map(str::to_string),
+            availability_zone: zone.map(str::to_string),
+            cloud_name: Some("aws".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn relocation(from: &str, to: &str) -> Relocation {
+        Relocation {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_render_template() {
+        let vars = placeholders(&aws(Some("us-east-1"), None));
+        assert_eq!(
+            render_template("http://%(region)s.example.com/%(cloud_name)s", &vars).as_deref(),
+            Some("http://us-east-1.example.com/aws")
+        );
+        assert_eq!(
+            render_template("http://plain.example.com", &vars).as_deref(),
+            Some("http://plain.example.com")
+        );
+        assert_eq!(
+            render_template("http://%(availability_zone)s.x", &vars),
+            None
+        );
+        assert_eq!(render_template("http://%(region.x", &vars), None);
+    }
+
+    #[test]
+    fn test_ec2_region_from_zone() {
+        let vars = placeholders(&aws(None, Some("eu-west-2b")));
+        assert_eq!(vars["ec2_region"], "eu-west-2");
+        assert!(!vars.contains_key("region"));
+
+        // Only AWS mirrors are named after EC2 regions
+        let gce = InstanceMetadata {
+            region: Some("us-central1".to_string()),
+            cloud_name: Some("gce".to_string()),
+            ..Default::default()
+        };
+        assert!(!placeholders(&gce).contains_key("ec2_region"));
+    }
+
+    #[test]
+    fn test_candidates_skip_unfilled_templates() {
+        let gce = InstanceMetadata {
+            region: Some("us-central1".to_string()),
+            cloud_name: Some("gce".to_string()),
+            ..Default::default()
+        };
+        let entry = ubuntu_entry();
+        assert_eq!(
+            candidates(&entry, "primary", &placeholders(&gce)),
+            vec!["http://us-central1.clouds.archive.ubuntu.com/ubuntu/"]
+        );
+        assert!(candidates(&entry, "security", &placeholders(&gce)).is_empty());
+        assert_eq!(
+            candidates(
+                &entry,
+                "primary",
+                &placeholders(&aws(Some("us-east-1"), None))
+            )[0],
+            "http://us-east-1.ec2.archive.ubuntu.com/ubuntu/"
+        );
+    }
+
+    #[test]
+    fn test_select_entry() {
+        let mut arm = ubuntu_entry();
+        arm.arches = vec!["arm64".to_string()];
+        let mut default = ubuntu_entry();
+        default.arches = vec!["default".to_string()];
+        let mut override_amd64 = ubuntu_entry();
+        override_amd64.failsafe.clear();
+        let entries = vec![ubuntu_entry(), arm, default, override_amd64];
+
+        let amd64 = select_entry(&entries, &["amd64", "x86_64"]).unwrap();
+        assert!(amd64.failsafe.is_empty());
+        assert_eq!(
+            select_entry(&entries, &["arm64"]).unwrap().arches,
+            ["arm64"]
+        );
+        assert_eq!(
+            select_entry(&entries, &["s390x"]).unwrap().arches,
+            ["default"]
+        );
+        assert!(select_entry(&entries[..2], &["s390x"]).is_none());
+    }
+
+    #[test]
+    fn test_rewrite_apt_sources() {
+        let sources = "\
+deb http://archive.ubuntu.com/ubuntu jammy main restricted
+# deb-src http://archive.ubuntu.com/ubuntu jammy main
+deb http://security.ubuntu.com/ubuntu jammy-security main
+deb http://archive.ubuntu.com/ubuntu-ports jammy main
+";
+        let relocations = [relocation(
+            "http://archive.ubuntu.com/ubuntu",
+            "http://us-east-1.ec2.archive.ubuntu.com/ubuntu",
+        )];
+        assert_eq!(
+            rewrite_sources(PackageManager::Apt, sources, &relocations).unwrap(),
+            "\
+deb http://us-east-1.ec2.archive.ubuntu.com/ubuntu jammy main restricted
+# deb-src http://us-east-1.ec2.archive.ubuntu.com/ubuntu jammy main
+deb http://security.ubuntu.com/ubuntu jammy-security main
+deb http://archive.ubuntu.com/ubuntu-ports jammy main
+"
+        );
+        assert_eq!(
+            rewrite_sources(
+                PackageManager::Apt,
+                "deb http://other/ jammy main\n",
+                &relocations
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_rewrite_apk_repositories() {
+        let repositories = "\
+http://dl-cdn.alpinelinux.org/alpine/v3.19/main
+#http://dl-cdn.alpinelinux.org/alpine/v3.19/community
+";
+        let relocations = [relocation(
+            "http://dl-cdn.alpinelinux.org/alpine",
+            "http://mirror.eu-west-1.example.com/alpine",
+        )];
+        assert_eq!(
+            rewrite_sources(PackageManager::Apk, repositories, &relocations).unwrap(),
+            "\
+http://mirror.eu-west-1.example.com/alpine/v3.19/main
+#http://mirror.eu-west-1.example.com/alpine/v3.19/community
+"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_dnf_repo_pins_baseurl() {
+        let repo = "\
+[baseos]
+name=Rocky Linux $releasever - BaseOS
+mirrorlist=https://mirrors.rockylinux.org/mirrorlist?repo=BaseOS-$releasever
+#baseurl=http://dl.rockylinux.org/$contentdir/$releasever/BaseOS/$basearch/os/
+enabled=1
+
+[extras]
+mirrorlist=https://mirrors.rockylinux.org/mirrorlist?repo=extras-$releasever
+#baseurl=http://elsewhere.example.com/extras/
+";
+        let relocations = [relocation(
+            "http://dl.rockylinux.org/$contentdir",
+            "http://rocky.us-east-1.example.com/pub/rocky",
+        )];
+        assert_eq!(
+            rewrite_sources(PackageManager::Dnf, repo, &relocations).unwrap(),
+            "\
+[baseos]
+name=Rocky Linux $releasever - BaseOS
+#mirrorlist=https://mirrors.rockylinux.org/mirrorlist?repo=BaseOS-$releasever
+baseurl=http://rocky.us-east-1.example.com/pub/rocky/$releasever/BaseOS/$basearch/os/
+enabled=1
+
+[extras]
+mirrorlist=https://mirrors.rockylinux.org/mirrorlist?repo=extras-$releasever
+#baseurl=http://elsewhere.example.com/extras/
+"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_configure_rewrites_sources_under_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = RootContext::new(dir.path());
+        let sources = root.path("/etc/apt/sources.list");
+        std::fs::create_dir_all(sources.parent().unwrap()).unwrap();
+        std::fs::write(
+            &sources,
+            "deb http://archive.ubuntu.com/ubuntu/ jammy main\n\
+             deb http://security.ubuntu.com/ubuntu/ jammy-security main\n",
+        )
+        .unwrap();
+        let mut entry = ubuntu_entry();
+        entry.arches = vec!["default".to_string()];
+
+        let runner = RecordingRunner::new();
+        configure(&runner, &root, &[entry], &aws(Some("us-east-1"), None))
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&sources).unwrap(),
+            "deb http://us-east-1.ec2.archive.ubuntu.com/ubuntu/ jammy main\n\
+             deb http://security.ubuntu.com/ubuntu/ jammy-security main\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_configure_keeps_failsafe_without_region() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = RootContext::new(dir.path());
+        let sources = root.path("/etc/apt/sources.list");
+        std::fs::create_dir_all(sources.parent().unwrap()).unwrap();
+        let original = "deb http://archive.ubuntu.com/ubuntu jammy main\n";
+        std::fs::write(&sources, original).unwrap();
+        let mut entry = ubuntu_entry();
+        entry.arches = vec!["default".to_string()];
+
+        let runner = RecordingRunner::new();
+        configure(&runner, &root, &[entry], &InstanceMetadata::default())
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&sources).unwrap(), original);
+    }
+}
@@ -3,8 +3,14 @@
 //! Installs packages using the appropriate package manager (apt, yum, dnf, zypper).
 
 use crate::CloudInitError;
+use crate::distro::Distro;
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
 use tracing::{debug, info, warn};
 
+/// Package manager commands probed when the distribution is not known
+const PROBE_ORDER: &[&str] = &["apt-get", "dnf", "yum", "zypper", "apk"];
+
 /// Detected package manager
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PackageManager {
@@ -17,26 +23,35 @@ pub enum PackageManager {
 
 impl PackageManager {
     /// Detect the system's package manager
-    pub async fn detect() -> Option<Self> {
-        // Check in order of preference
-        if command_exists("apt-get").await {
-            return Some(Self::Apt);
-        }
-        if command_exists("dnf").await {
-            return Some(Self::Dnf);
-        }
-        if command_exists("yum").await {
-            return Some(Self::Yum);
-        }
-        if command_exists("zypper").await {
-            return Some(Self::Zypper);
-        }
-        if command_exists("apk").await {
-            return Some(Self::Apk);
+    ///
+    /// The distribution's native one wins when it is installed; otherwise
+    /// the known ones are probed in order of preference.
+    pub async fn detect(runner: &dyn SystemRunner, distro: &Distro) -> Option<Self> {
+        let native = distro.package_manager();
+        let others = PROBE_ORDER
+            .iter()
+            .copied()
+            .filter(|command| Some(*command) != native);
+        for command in native.into_iter().chain(others) {
+            if command_exists(runner, command).await {
+                return Self::from_command(command);
+            }
         }
         None
     }
 
+    /// The manager run as `command`
+    pub fn from_command(command: &str) -> Option<Self> {
+        match command {
+            "apt-get" => Some(Self::Apt),
+            "dnf" => Some(Self::Dnf),
+            "yum" => Some(Self::Yum),
+            "zypper" => Some(Self::Zypper),
+            "apk" => Some(Self::Apk),
+            _ => None,
+        }
+    }
+
     fn install_command(&self) -> (&str, Vec<&str>) {
         match self {
             Self::Apt => ("apt-get", vec!["install", "-y"]),
@@ -69,17 +84,31 @@ impl PackageManager {
 }
 
 /// Check if a command exists
-async fn command_exists(cmd: &str) -> bool {
-    tokio::process::Command::new("which")
-        .arg(cmd)
-        .output()
+pub async fn command_exists(runner: &dyn SystemRunner, cmd: &str) -> bool {
+    runner
+        .run(&SystemCommand::new("which").arg(cmd).probe())
         .await
-        .is_ok_and(|o| o.status.success())
+        .is_ok_and(|o| o.is_success())
+}
+
+/// Run a package manager command non-interactively
+async fn run_package_command(
+    runner: &dyn SystemRunner,
+    cmd: &str,
+    args: &[&str],
+) -> Result<crate::runner::CommandOutput, CloudInitError> {
+    let command = SystemCommand::new(cmd)
+        .args(args.iter().copied())
+        .env("DEBIAN_FRONTEND", "noninteractive");
+    runner.run(&crate::http::with_proxy_env(command)).await
 }
 
 /// Update package cache
-pub async fn update_package_cache() -> Result<(), CloudInitError> {
-    let pm = PackageManager::detect()
+pub async fn update_package_cache(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+) -> Result<(), CloudInitError> {
+    let pm = PackageManager::detect(runner, &Distro::detect(root).await)
         .await
         .ok_or_else(|| CloudInitError::Module {
             module: "packages".to_string(),
@@ -89,17 +118,11 @@ pub async fn update_package_cache() -> Result<(), CloudInitError> {
     info!("Updating package cache using {:?}", pm);
 
     let (cmd, args) = pm.update_command();
-    let output = tokio::process::Command::new(cmd)
-        .args(&args)
-        .env("DEBIAN_FRONTEND", "noninteractive")
-        .output()
-        .await
-        .map_err(|e| CloudInitError::Command(e.to_string()))?;
+    let output = run_package_command(runner, cmd, &args).await?;
 
     // Note: yum/dnf check-update returns 100 if updates available, which is not an error
-    if !output.status.success() && output.status.code() != Some(100) {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        warn!("Package cache update had issues: {}", stderr);
+    if !output.is_success() && output.code != Some(100) {
+        warn!("Package cache update had issues: {}", output.stderr);
         // Don't fail - update issues are often non-fatal
     }
 
@@ -107,8 +130,11 @@ pub async fn update_package_cache() -> Result<(), CloudInitError> {
 }
 
 /// Upgrade all packages
-pub async fn upgrade_packages() -> Result<(), CloudInitError> {
-    let pm = PackageManager::detect()
+pub async fn upgrade_packages(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+) -> Result<(), CloudInitError> {
+    let pm = PackageManager::detect(runner, &Distro::detect(root).await)
         .await
         .ok_or_else(|| CloudInitError::Module {
             module: "packages".to_string(),
@@ -118,28 +144,26 @@ pub async fn upgrade_packages() -> Result<(), CloudInitError> {
     info!("Upgrading packages using {:?}", pm);
 
     let (cmd, args) = pm.upgrade_command();
-    let output = tokio::process::Command::new(cmd)
-        .args(&args)
-        .env("DEBIAN_FRONTEND", "noninteractive")
-        .output()
-        .await
-        .map_err(|e| CloudInitError::Command(e.to_string()))?;
+    let output = run_package_command(runner, cmd, &args).await?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        warn!("Package upgrade had issues: {}", stderr);
+    if !output.is_success() {
+        warn!("Package upgrade had issues: {}", output.stderr);
     }
 
     Ok(())
 }
 
 /// Install packages
-pub async fn install_packages(packages: &[String]) -> Result<(), CloudInitError> {
+pub async fn install_packages(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    packages: &[String],
+) -> Result<(), CloudInitError> {
     if packages.is_empty() {
         return Ok(());
     }
 
-    let pm = PackageManager::detect()
+    let pm = PackageManager::detect(runner, &Distro::detect(root).await)
         .await
         .ok_or_else(|| CloudInitError::Module {
             module: "packages".to_string(),
@@ -155,18 +179,12 @@ pub async fn install_packages(packages: &[String]) -> Result<(), CloudInitError>
         args.push(pkg.as_str());
     }
 
-    let output = tokio::process::Command::new(cmd)
-        .args(&args)
-        .env("DEBIAN_FRONTEND", "noninteractive")
-        .output()
-        .await
-        .map_err(|e| CloudInitError::Command(e.to_string()))?;
+    let output = run_package_command(runner, cmd, &args).await?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.is_success() {
         return Err(CloudInitError::Module {
             module: "packages".to_string(),
-            message: format!("Failed to install packages: {}", stderr),
+            message: format!("Failed to install packages: {}", output.stderr),
         });
     }
 
@@ -175,13 +193,23 @@ pub async fn install_packages(packages: &[String]) -> Result<(), CloudInitError>
 }
 
 /// Install a single package
-pub async fn install_package(package: &str) -> Result<(), CloudInitError> {
-    install_packages(&[package.to_string()]).await
+pub async fn install_package(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    package: &str,
+) -> Result<(), CloudInitError> {
+    install_packages(runner, root, &[package.to_string()]).await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::runner::{CommandOutput, HostRunner, RecordingRunner};
+
+    /// A root without os-release, so detection only probes
+    fn root() -> RootContext {
+        RootContext::new("/nonexistent")
+    }
 
     #[test]
     fn test_apt_install_command() {
@@ -284,15 +312,92 @@ mod tests {
 
     #[tokio::test]
     async fn test_command_exists_true() {
-        assert!(command_exists("echo").await);
+        assert!(command_exists(&HostRunner, "echo").await);
     }
     #[tokio::test]
     async fn test_command_exists_false() {
-        assert!(!command_exists("nonexistent_command_xyz_12345").await);
+        assert!(!command_exists(&HostRunner, "nonexistent_command_xyz_12345").await);
     }
 
     #[tokio::test]
     async fn test_install_packages_empty() {
-        assert!(install_packages(&[]).await.is_ok());
+        let runner = RecordingRunner::new();
+        assert!(install_packages(&runner, &root(), &[]).await.is_ok());
+        assert!(runner.commands().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_install_packages_commands() {
+        let runner = RecordingRunner::new();
+        install_packages(&runner, &root(), &["nginx".to_string(), "curl".to_string()])
+            .await
+            .unwrap();
+
+        let calls = runner.calls();
+        assert_eq!(
+            runner.commands(),
+            vec!["which apt-get", "apt-get install -y nginx curl"]
+        );
+        assert_eq!(
+            calls[1].env,
+            vec![("DEBIAN_FRONTEND".to_string(), "noninteractive".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_install_packages_failure() {
+        let runner = RecordingRunner::new().with_response(
+            "apt-get",
+            CommandOutput::failure(100, "E: Unable to locate"),
+        );
+        let err = install_packages(&runner, &root(), &["nope".to_string()])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Unable to locate"));
+    }
+
+    #[tokio::test]
+    async fn test_update_tolerates_check_update_exit_100() {
+        let runner = RecordingRunner::new()
+            .with_response("which apt-get", CommandOutput::failure(1, ""))
+            .with_response("dnf", CommandOutput::failure(100, ""));
+        assert!(update_package_cache(&runner, &root()).await.is_ok());
+        assert_eq!(
+            runner.commands(),
+            vec!["which apt-get", "which dnf", "dnf check-update"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_package_manager() {
+        let runner = RecordingRunner::new().with_response("which", CommandOutput::failure(1, ""));
+        assert!(matches!(
+            upgrade_packages(&runner, &root()).await,
+            Err(CloudInitError::Module { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_detect_prefers_native_manager() {
+        let rocky = Distro::from_os_release("ID=rocky\nVERSION_ID=9.3\n");
+        let runner = RecordingRunner::new();
+        assert_eq!(
+            PackageManager::detect(&runner, &rocky).await,
+            Some(PackageManager::Dnf)
+        );
+        assert_eq!(runner.commands(), vec!["which dnf"]);
+
+        // Not installed after all: probe the rest, without asking twice
+        let runner = RecordingRunner::new()
+            .with_response("which dnf", CommandOutput::failure(1, ""))
+            .with_response("which apt-get", CommandOutput::failure(1, ""));
+        assert_eq!(
+            PackageManager::detect(&runner, &rocky).await,
+            Some(PackageManager::Yum)
+        );
+        assert_eq!(
+            runner.commands(),
+            vec!["which dnf", "which apt-get", "which yum"]
+        );
     }
 }
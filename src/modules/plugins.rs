@@ -0,0 +1,268 @@
+//! Drop-in module plugins
+//!
+//! Executables in `/usr/lib/cloud-init-rs/modules.d` run as modules at the
+//! end of the Config stage, in name order, so images can add behavior
+//! without patching the crate. The file name is the module name: it is
+//! used for reporting events, `--module`/`--skip-module` filtering,
+//! timeouts, module policy and semaphores, and like most Config stage
+//! modules a plugin runs once per instance.
+//!
+//! A plugin reads the merged cloud-config as JSON on stdin, including keys
+//! cloud-init-rs does not know itself, and finds its name in
+//! `CLOUD_INIT_MODULE`. A non-zero exit status fails the module. A plugin
+//! may also print a JSON object as its last line of output:
+//!
+//! ```json
+//! {"status": "skipped", "message": "no my_plugin key"}
+//! ```
+//!
+//! `status` is `ok`, `skipped` or `error`; an `error` fails the module
+//! with `message` even if the plugin exited with 0.
+//!
+//! With the `plugins-wasm` feature, `<name>.wasm` files in the same
+//! directory run sandboxed instead, see `wasm_plugins.rs`.
+
+use super::COMPILED;
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use crate::{CloudInitError, IoContext};
+use serde::Deserialize;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+/// Directory plugins are discovered in
+pub const PLUGIN_DIR: &str = "/usr/lib/cloud-init-rs/modules.d";
+
+/// Extension of WebAssembly plugins
+const WASM_EXTENSION: &str = "wasm";
+
+/// How a plugin runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PluginKind {
+    /// An executable run inside the root
+    Executable,
+    /// A WebAssembly module run in a sandbox
+    Wasm,
+}
+
+/// A discovered plugin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plugin {
+    /// Module name, the file name without any `.wasm`
+    pub name: String,
+    /// Executable as seen inside the root, or the WebAssembly module on
+    /// disk
+    pub path: PathBuf,
+    pub kind: PluginKind,
+}
+
+/// Result a plugin may print as its last line of output
+#[derive(Debug, Deserialize)]
+struct PluginReport {
+    status: PluginStatus,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum PluginStatus {
+    Ok,
+    Skipped,
+    Error,
+}
+
+/// Whether `name` can be a module name: letters, digits, `_` and `-`
+///
+/// Leaves out editor backups and package manager leftovers such as
+/// `foo~` and `foo.dpkg-old`.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// The plugins installed under `root`, in name order
+///
+/// Files that are not executable, or whose names are not valid module
+/// names or clash with a built-in module, are skipped with a warning.
+pub async fn discover(root: &RootContext) -> Result<Vec<Plugin>, CloudInitError> {
+    let dir = root.path(PLUGIN_DIR);
+    let mut entries = match fs::read_dir(&dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_path(&dir),
+    };
+
+    let mut plugins = Vec::new();
+    while let Some(entry) = entries.next_entry().await.with_path(&dir)? {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let path = entry.path();
+        let (name, kind) = match file_name.rsplit_once('.') {
+            Some((stem, WASM_EXTENSION)) => (stem.to_string(), PluginKind::Wasm),
+            _ => (file_name, PluginKind::Executable),
+        };
+        if !is_valid_name(&name) {
+            debug!("Ignoring {}: not a module name", path.display());
+            continue;
+        }
+        if COMPILED.contains(&name.as_str()) {
+            warn!(
+                "Ignoring plugin {}: a built-in module has that name",
+                path.display()
+            );
+            continue;
+        }
+        let metadata = fs::metadata(&path).await.with_path(&path)?;
+        if kind == PluginKind::Wasm {
+            if !cfg!(feature = "plugins-wasm") {
+                warn!(
+                    "Ignoring plugin {}: built without the plugins-wasm feature",
+                    path.display()
+                );
+                continue;
+            }
+            if metadata.is_file() {
+                plugins.push(Plugin { name, path, kind });
+            }
+            continue;
+        }
+        if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+            warn!("Ignoring plugin {}: not an executable file", path.display());
+            continue;
+        }
+        plugins.push(Plugin {
+            path: Path::new(PLUGIN_DIR).join(&name),
+            name,
+            kind,
+        });
+    }
+    plugins.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(plugins)
+}
+
+/// Run `plugin` with `config`, the merged cloud-config, on its stdin
+pub async fn run(
+    runner: &dyn SystemRunner,
+    plugin: &Plugin,
+    config: &serde_json::Value,
+) -> Result<(), CloudInitError> {
+    let command = SystemCommand::new(plugin.path.to_string_lossy())
+        .env("CLOUD_INIT_MODULE", &plugin.name)
+        .stdin(config.to_string());
+    let output = runner.run(&command).await?;
+
+    let report = output
+        .stdout
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .and_then(|line| serde_json::from_str::<PluginReport>(line).ok());
+    let message = report
+        .as_ref()
+        .and_then(|r| r.message.as_deref())
+        .unwrap_or_else(|| output.stderr.trim());
+
+    if !output.is_success() {
+        return Err(CloudInitError::Command(format!(
+            "{} exited with {:?}: {}",
+            plugin.path.display(),
+            output.code,
+            message
+        )));
+    }
+    match report.as_ref().map(|r| &r.status) {
+        Some(PluginStatus::Error) => Err(CloudInitError::module(&plugin.name, message)),
+        Some(PluginStatus::Skipped) => {
+            info!("Plugin {} skipped: {}", plugin.name, message);
+            Ok(())
+        }
+        Some(PluginStatus::Ok) | None => {
+            debug!("Plugin {} finished", plugin.name);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+    use tempfile::TempDir;
+
+    fn install(root: &RootContext, name: &str, mode: u32) {
+        let path = root.path(PLUGIN_DIR).join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_discover() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        assert!(discover(&root).await.unwrap().is_empty());
+
+        install(&root, "zz-last", 0o755);
+        install(&root, "my_plugin", 0o755);
+        install(&root, "not-executable", 0o644);
+        install(&root, "my_plugin.dpkg-old", 0o755);
+        install(&root, "users", 0o755);
+        install(&root, "sandboxed.wasm", 0o644);
+
+        let plugins = discover(&root).await.unwrap();
+        let names: Vec<&str> = plugins.iter().map(|p| p.name.as_str()).collect();
+        if cfg!(feature = "plugins-wasm") {
+            assert_eq!(names, vec!["my_plugin", "sandboxed", "zz-last"]);
+            assert_eq!(plugins[1].kind, PluginKind::Wasm);
+        } else {
+            assert_eq!(names, vec!["my_plugin", "zz-last"]);
+        }
+        assert_eq!(
+            plugins[0].path,
+            Path::new("/usr/lib/cloud-init-rs/modules.d/my_plugin")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_passes_config_and_reads_status() {
+        let plugin = Plugin {
+            name: "my_plugin".to_string(),
+            path: PathBuf::from("/usr/lib/cloud-init-rs/modules.d/my_plugin"),
+            kind: PluginKind::Executable,
+        };
+        let config = serde_json::json!({"my_plugin": {"enabled": true}});
+
+        let runner = RecordingRunner::new();
+        run(&runner, &plugin, &config).await.unwrap();
+        let call = &runner.calls()[0];
+        assert_eq!(call.stdin.as_deref(), Some(config.to_string().as_str()));
+        assert!(
+            call.env
+                .contains(&("CLOUD_INIT_MODULE".to_string(), "my_plugin".to_string()))
+        );
+
+        let runner = RecordingRunner::new().with_response(
+            "/usr/lib/cloud-init-rs/modules.d/my_plugin",
+            CommandOutput::success("working\n{\"status\": \"error\", \"message\": \"bad key\"}\n"),
+        );
+        let err = run(&runner, &plugin, &config).await.unwrap_err();
+        assert!(err.to_string().contains("bad key"));
+
+        let runner = RecordingRunner::new().with_response(
+            "/usr/lib/cloud-init-rs/modules.d/my_plugin",
+            CommandOutput::failure(3, "crashed"),
+        );
+        let err = run(&runner, &plugin, &config).await.unwrap_err();
+        assert!(err.to_string().contains("crashed"));
+
+        let runner = RecordingRunner::new().with_response(
+            "/usr/lib/cloud-init-rs/modules.d/my_plugin",
+            CommandOutput::success("{\"status\": \"skipped\"}"),
+        );
+        run(&runner, &plugin, &config).await.unwrap();
+    }
+}
@@ -0,0 +1,215 @@
+//! Power state change module
+//!
+//! Implements the `power_state` cloud-config key: reboot, power off or
+//! halt once provisioning is done, e.g. after a kernel update. An optional
+//! `condition` gates the change; a command condition must exit 0.
+//!
+//! Shutting down at once would cut the final stage short before its
+//! status is recorded, so the module only schedules the change: a detached
+//! shell waits for this process to exit (at most `timeout` seconds) and
+//! then runs `shutdown`. The units running the final stage use
+//! `KillMode=process` so systemd leaves that shell alone.
+
+use crate::CloudInitError;
+use crate::config::{PowerStateCondition, PowerStateConfig, RunCmd};
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use tracing::{debug, info, warn};
+
+/// Seconds to wait for cloud-init to exit when no `timeout` is set
+pub const DEFAULT_TIMEOUT: u64 = 30;
+
+/// Waits for the PID in `$1` to exit, up to `$2` seconds, then runs the
+/// remaining arguments, all in the background
+const WAIT_THEN_RUN: &str = r#"pid=$1 timeout=$2; shift 2
+(
+    i=0
+    while [ "$i" -lt "$timeout" ] && kill -0 "$pid" 2>/dev/null; do
+        sleep 1
+        i=$((i + 1))
+    done
+    exec "$@"
+) </dev/null >/dev/null 2>&1 &"#;
+
+/// Schedule the configured power state change if its condition holds
+pub async fn power_state_change(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &PowerStateConfig,
+) -> Result<(), CloudInitError> {
+    if !root.is_host() {
+        debug!("Not changing the power state of an alternate root");
+        return Ok(());
+    }
+    let shutdown = shutdown_command(config)?;
+    if !condition_met(runner, config.condition.as_ref()).await? {
+        info!("Power state condition not met, not running {}", shutdown);
+        return Ok(());
+    }
+
+    let timeout = config.timeout.unwrap_or(DEFAULT_TIMEOUT);
+    info!(
+        "Scheduling {} once cloud-init exits (timeout {}s)",
+        shutdown, timeout
+    );
+    let waiter = SystemCommand::new("sh")
+        .args(["-c", WAIT_THEN_RUN, "power_state_change"])
+        .arg(std::process::id().to_string())
+        .arg(timeout.to_string())
+        .arg(shutdown.program)
+        .args(shutdown.args);
+    let output = runner.run(&waiter).await?;
+    if !output.is_success() {
+        return Err(CloudInitError::Command(format!(
+            "Scheduling the power state change failed: {}",
+            output.stderr.trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Build the `shutdown` invocation for `config`
+fn shutdown_command(config: &PowerStateConfig) -> Result<SystemCommand, CloudInitError> {
+    let flag = match config.mode.as_str() {
+        "reboot" => "-r",
+        "poweroff" => "-P",
+        "halt" => "-H",
+        other => {
+            return Err(CloudInitError::InvalidData(format!(
+                "Unknown power_state mode: {}",
+                other
+            )));
+        }
+    };
+    let command = SystemCommand::new("shutdown").args([flag, &shutdown_delay(config)?]);
+    Ok(match &config.message {
+        Some(message) => command.arg(message),
+        None => command,
+    })
+}
+
+/// Normalize `delay` to the `now`/`+N` form `shutdown` takes
+fn shutdown_delay(config: &PowerStateConfig) -> Result<String, CloudInitError> {
+    let delay = match &config.delay {
+        None => return Ok("now".to_string()),
+        Some(serde_yaml::Value::Number(n)) => n.to_string(),
+        Some(serde_yaml::Value::String(s)) => s.trim().to_string(),
+        Some(other) => format!("{:?}", other),
+    };
+    if delay == "now" {
+        return Ok(delay);
+    }
+    let minutes = delay.strip_prefix('+').unwrap_or(&delay);
+    minutes
+        .parse::<u64>()
+        .map(|m| format!("+{}", m))
+        .map_err(|_| CloudInitError::InvalidData(format!("Invalid power_state delay: {}", delay)))
+}
+
+/// Whether the power state change should go ahead
+async fn condition_met(
+    runner: &dyn SystemRunner,
+    condition: Option<&PowerStateCondition>,
+) -> Result<bool, CloudInitError> {
+    let command = match condition {
+        None => return Ok(true),
+        Some(PowerStateCondition::Bool(value)) => return Ok(*value),
+        Some(PowerStateCondition::Command(RunCmd::Shell(line))) => {
+            SystemCommand::new("sh").args(["-c", line.as_str()])
+        }
+        Some(PowerStateCondition::Command(RunCmd::Args(args))) => {
+            let Some((program, rest)) = args.split_first() else {
+                return Ok(true);
+            };
+            SystemCommand::new(program).args(rest)
+        }
+    };
+    let output = runner.run(&command).await?;
+    match output.code {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        code => {
+            warn!(
+                "Power state condition {} exited with {:?}, treating as false",
+                command, code
+            );
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+    use tempfile::TempDir;
+
+    fn config(yaml: &str) -> PowerStateConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_shutdown_command() {
+        let command = shutdown_command(&config("mode: reboot")).unwrap();
+        assert_eq!(command.to_string(), "shutdown -r now");
+
+        let command =
+            shutdown_command(&config("{mode: poweroff, delay: 5, message: bye}")).unwrap();
+        assert_eq!(command.args, vec!["-P", "+5", "bye"]);
+        let command = shutdown_command(&config("{mode: halt, delay: '+10'}")).unwrap();
+        assert_eq!(command.args, vec!["-H", "+10"]);
+
+        assert!(shutdown_command(&config("mode: sleep")).is_err());
+        assert!(shutdown_command(&config("{mode: reboot, delay: soon}")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_schedules_after_exit() {
+        let runner = RecordingRunner::new();
+        let root = RootContext::host();
+        power_state_change(&runner, &root, &config("{mode: reboot, timeout: 10}"))
+            .await
+            .unwrap();
+
+        let calls = runner.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].program, "sh");
+        assert_eq!(
+            calls[0].args[3..],
+            [
+                std::process::id().to_string(),
+                "10".to_string(),
+                "shutdown".to_string(),
+                "-r".to_string(),
+                "now".to_string()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_condition_gates_change() {
+        let root = RootContext::host();
+        let yaml = "{mode: reboot, condition: [test, -f, /var/run/reboot-required]}";
+        let runner = RecordingRunner::new().with_response("test", CommandOutput::failure(1, ""));
+        power_state_change(&runner, &root, &config(yaml))
+            .await
+            .unwrap();
+        assert_eq!(runner.commands(), vec!["test -f /var/run/reboot-required"]);
+
+        let runner = RecordingRunner::new();
+        power_state_change(&runner, &root, &config("{mode: reboot, condition: false}"))
+            .await
+            .unwrap();
+        assert!(runner.commands().is_empty());
+
+        let temp = TempDir::new().unwrap();
+        power_state_change(
+            &runner,
+            &RootContext::new(temp.path()),
+            &config("mode: reboot"),
+        )
+        .await
+        .unwrap();
+        assert!(runner.commands().is_empty());
+    }
+}
@@ -0,0 +1,316 @@
+//! Puppet agent module
+//!
+//! Implements the `puppet` cloud-config key: installs the agent package,
+//! merges `conf` into puppet.conf, installs the CA certificate and
+//! `csr_attributes.yaml`, then enables the puppet service and optionally
+//! runs `puppet agent` once.
+//!
+//! Paths default to the all-in-one layout under `/etc/puppetlabs` when
+//! that directory exists, and to the distro layout under `/etc/puppet`
+//! otherwise. In `certname`, `%i` is replaced with the instance ID and
+//! `%f` with the FQDN.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! puppet:
+//!   conf:
+//!     agent:
+//!       server: puppet.example.com
+//!       certname: "%i.%f"
+//!     ca_cert: |
+//!       -----BEGIN CERTIFICATE-----
+//!       ...
+//!   csr_attributes:
+//!     extension_requests:
+//!       pp_role: webserver
+//! ```
+
+use crate::CloudInitError;
+use crate::config::PuppetConfig;
+use crate::modules::{packages, scalar_string, start_service};
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::fs;
+use tracing::{debug, info};
+
+/// Configuration directory of the all-in-one packages
+const AIO_DIR: &str = "/etc/puppetlabs/puppet";
+
+/// Configuration directory of distro packages
+const DISTRO_DIR: &str = "/etc/puppet";
+
+/// Install and configure the puppet agent
+pub async fn configure_puppet(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &PuppetConfig,
+    instance_id: Option<&str>,
+    fqdn: Option<&str>,
+) -> Result<(), CloudInitError> {
+    info!("Configuring puppet");
+    if config.install.unwrap_or(true) && !packages::command_exists(runner, "puppet").await {
+        let package = config.package_name.as_deref().unwrap_or("puppet");
+        packages::install_package(runner, root, package).await?;
+    }
+
+    let aio = root.path(AIO_DIR).is_dir();
+    let default_dir = if aio { AIO_DIR } else { DISTRO_DIR };
+    let conf_file = match &config.conf_file {
+        Some(path) => path.clone(),
+        None => format!("{}/puppet.conf", default_dir),
+    };
+    let ssl_dir = match &config.ssl_dir {
+        Some(path) => path.clone(),
+        None if aio => format!("{}/ssl", AIO_DIR),
+        None => "/var/lib/puppet/ssl".to_string(),
+    };
+    let csr_attributes_path = match &config.csr_attributes_path {
+        Some(path) => path.clone(),
+        None => format!("{}/csr_attributes.yaml", default_dir),
+    };
+
+    let mut sections = BTreeMap::new();
+    for (section, settings) in &config.conf {
+        if section == "ca_cert" {
+            let Some(cert) = settings.as_str() else {
+                return Err(CloudInitError::InvalidData(
+                    "puppet ca_cert must be a string".to_string(),
+                ));
+            };
+            install_ca_cert(root, &ssl_dir, cert).await?;
+            continue;
+        }
+        sections.insert(
+            section.as_str(),
+            section_settings(section, settings, instance_id, fqdn)?,
+        );
+    }
+    if !sections.is_empty() {
+        let path = root.path(&conf_file);
+        let existing = fs::read_to_string(&path).await.unwrap_or_default();
+        if let Some(dir) = path.parent() {
+            root.create_dir_all(dir).await?;
+        }
+        root.write_file(&path, merge_conf(&existing, &sections))
+            .await?;
+    }
+
+    if let Some(attributes) = &config.csr_attributes {
+        let path = root.path(&csr_attributes_path);
+        if let Some(dir) = path.parent() {
+            root.create_dir_all(dir).await?;
+        }
+        root.write_file(&path, serde_yaml::to_string(attributes)?)
+            .await?;
+        root.set_mode(&path, 0o640).await?;
+    }
+
+    if config.start_service.unwrap_or(true) {
+        start_service(runner, root, "puppet").await?;
+    }
+
+    if config.exec == Some(true) && root.is_host() {
+        let args = if config.exec_args.is_empty() {
+            vec!["--test".to_string()]
+        } else {
+            config.exec_args.clone()
+        };
+        let output = runner
+            .run(&SystemCommand::new("puppet").arg("agent").args(args))
+            .await?;
+        // --test exits 2 when it applied changes
+        if !matches!(output.code, Some(0 | 2)) {
+            return Err(CloudInitError::Command(format!(
+                "puppet agent failed: {}",
+                output.stderr.trim()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Write the CA certificate where the agent looks for it
+async fn install_ca_cert(
+    root: &RootContext,
+    ssl_dir: &str,
+    cert: &str,
+) -> Result<(), CloudInitError> {
+    let certs = root.path(Path::new(ssl_dir).join("certs"));
+    root.create_dir_all(&certs).await?;
+    root.write_file(&certs.join("ca.pem"), cert).await?;
+    debug!("Installed puppet CA certificate in {}", certs.display());
+    Ok(())
+}
+
+/// Settings of one puppet.conf section as strings
+fn section_settings(
+    section: &str,
+    settings: &serde_yaml::Value,
+    instance_id: Option<&str>,
+    fqdn: Option<&str>,
+) -> Result<Vec<(String, String)>, CloudInitError> {
+    let invalid =
+        || CloudInitError::InvalidData(format!("Invalid puppet conf section: {}", section));
+    let serde_yaml::Value::Mapping(settings) = settings else {
+        return Err(invalid());
+    };
+    let mut result = Vec::new();
+    for (key, value) in settings {
+        let key = key.as_str().ok_or_else(invalid)?;
+        let mut value = scalar_string(value).ok_or_else(invalid)?;
+        if key == "certname" {
+            value = value
+                .replace("%i", instance_id.unwrap_or_default())
+                .replace("%f", fqdn.unwrap_or_default());
+        }
+        result.push((key.to_string(), value));
+    }
+    Ok(result)
+}
+
+/// Merge `sections` into an INI-style puppet.conf (pure function for
+/// testability)
+///
+/// Existing keys are updated in place, new keys are added at the end of
+/// their section and new sections at the end of the file.
+fn merge_conf(existing: &str, sections: &BTreeMap<&str, Vec<(String, String)>>) -> String {
+    let mut pending = sections.clone();
+    let mut lines: Vec<String> = Vec::new();
+    let mut current: Option<String> = None;
+
+    let flush = |lines: &mut Vec<String>,
+                 pending: &mut BTreeMap<&str, Vec<(String, String)>>,
+                 section: &Option<String>| {
+        if let Some(section) = section
+            && let Some(settings) = pending.remove(section.as_str())
+        {
+            let end = lines
+                .iter()
+                .rposition(|l| !l.trim().is_empty())
+                .map_or(0, |i| i + 1);
+            let added = settings.iter().map(|(k, v)| format!("{} = {}", k, v));
+            lines.splice(end..end, added);
+        }
+    };
+
+    for line in existing.lines() {
+        let trimmed = line.trim();
+        if let Some(name) = trimmed
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+        {
+            flush(&mut lines, &mut pending, &current);
+            current = Some(name.trim().to_string());
+            lines.push(line.to_string());
+            continue;
+        }
+        if !trimmed.starts_with('#')
+            && let Some((key, _)) = trimmed.split_once('=')
+            && let Some(section) = &current
+            && let Some(settings) = pending.get_mut(section.as_str())
+            && let Some(pos) = settings.iter().position(|(k, _)| k == key.trim())
+        {
+            let (key, value) = settings.remove(pos);
+            lines.push(format!("{} = {}", key, value));
+            continue;
+        }
+        lines.push(line.to_string());
+    }
+    flush(&mut lines, &mut pending, &current);
+
+    for (section, settings) in pending {
+        if !lines.is_empty() {
+            lines.push(String::new());
+        }
+        lines.push(format!("[{}]", section));
+        lines.extend(settings.iter().map(|(k, v)| format!("{} = {}", k, v)));
+    }
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::RecordingRunner;
+    use tempfile::TempDir;
+
+    fn section(settings: &[(&str, &str)]) -> Vec<(String, String)> {
+        settings
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_conf() {
+        let existing =
+            "[main]\nlogdir = /var/log/puppet\n\n[agent]\n# server = old\nserver = old\n";
+        let mut sections = BTreeMap::new();
+        sections.insert(
+            "agent",
+            section(&[("server", "new"), ("runinterval", "1h")]),
+        );
+        sections.insert("main", section(&[("vardir", "/var/lib/puppet")]));
+        sections.insert("user", section(&[("noop", "true")]));
+        assert_eq!(
+            merge_conf(existing, &sections),
+            "[main]\nlogdir = /var/log/puppet\nvardir = /var/lib/puppet\n\n\
+             [agent]\n# server = old\nserver = new\nruninterval = 1h\n\n\
+             [user]\nnoop = true\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_configure_puppet_under_root() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        std::fs::create_dir_all(root.path(AIO_DIR)).unwrap();
+        let config: PuppetConfig = serde_yaml::from_str(
+            r#"
+conf:
+  agent:
+    server: puppet.example.com
+    certname: "%i.%f"
+  ca_cert: "-----BEGIN CERTIFICATE-----\n"
+csr_attributes:
+  extension_requests:
+    pp_role: web
+exec: true
+"#,
+        )
+        .unwrap();
+
+        let runner = RecordingRunner::new();
+        configure_puppet(
+            &runner,
+            &root,
+            &config,
+            Some("i-1"),
+            Some("web.example.com"),
+        )
+        .await
+        .unwrap();
+
+        let conf =
+            std::fs::read_to_string(root.path("/etc/puppetlabs/puppet/puppet.conf")).unwrap();
+        assert_eq!(
+            conf,
+            "[agent]\nserver = puppet.example.com\ncertname = i-1.web.example.com\n"
+        );
+        assert!(
+            root.path("/etc/puppetlabs/puppet/ssl/certs/ca.pem")
+                .exists()
+        );
+        let csr = std::fs::read_to_string(root.path("/etc/puppetlabs/puppet/csr_attributes.yaml"))
+            .unwrap();
+        assert!(csr.contains("pp_role: web"));
+        // Enabled for the image's boot; no agent run under an alternate root
+        assert_eq!(
+            runner.commands(),
+            vec!["which puppet", "systemctl enable puppet"]
+        );
+    }
+}
@@ -0,0 +1,360 @@
+//! FreeBSD accounts with pw(8)
+//!
+//! The same `users:` entries as on Linux, created with `pw useradd`,
+//! updated with `pw usermod` when they exist and deleted with `pw userdel`.
+//! Supplementary groups are set with `pw usermod -G`, passwords are set
+//! from their crypt(3) hash with `pw usermod -H 0`, locked with `pw lock`
+//! and expired with `pw usermod -p`. `system`, `expiredate`, `inactive` and
+//! `no_user_group` have no pw equivalent and are ignored.
+
+use super::users::{
+    Accounts, configure_sudo, forget_user, record_added_groups, resolve_password_hash,
+};
+use crate::CloudInitError;
+use crate::config::{UserConfig, UserFullConfig};
+use crate::distro::Distro;
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use tracing::{debug, info, warn};
+
+/// pw's exit status for a user that already exists (EX_DATAERR)
+const EXIT_EXISTS: i32 = 65;
+
+/// pw's exit status for a user that does not exist (EX_NOUSER)
+const EXIT_NO_USER: i32 = 67;
+
+/// Create users from cloud-config with pw
+pub(super) async fn create_users(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    distro: &Distro,
+    users: &[UserConfig],
+    accounts: &Accounts<'_>,
+) -> Result<(), CloudInitError> {
+    for user in users {
+        let config = accounts.resolve(user);
+        if config.remove == Some(true) {
+            remove_user(runner, root, distro, &config.name).await?;
+        } else {
+            create_user(runner, root, distro, accounts, &config).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Delete a user, keeping its home directory
+async fn remove_user(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    distro: &Distro,
+    name: &str,
+) -> Result<(), CloudInitError> {
+    info!("Removing user with pw: {}", name);
+    let output = runner
+        .run(&SystemCommand::new("pw").args(["userdel", "-n", name]))
+        .await?;
+    if !output.is_success() && output.code != Some(EXIT_NO_USER) {
+        return Err(CloudInitError::UserGroup(format!(
+            "Failed to remove user {}: {}",
+            name, output.stderr
+        )));
+    }
+    forget_user(root, distro.sudoers_dir(), name).await
+}
+
+async fn create_user(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    distro: &Distro,
+    accounts: &Accounts<'_>,
+    config: &UserFullConfig,
+) -> Result<(), CloudInitError> {
+    info!("Creating user with pw: {}", config.name);
+
+    let output = runner
+        .run(&SystemCommand::new("pw").args(build_useradd_args(config)))
+        .await?;
+    if output.code == Some(EXIT_EXISTS) {
+        let args = build_usermod_args(config);
+        if args.len() > 3 {
+            info!("Updating existing user with pw: {}", config.name);
+            let output = runner.run(&SystemCommand::new("pw").args(args)).await?;
+            if !output.is_success() {
+                return Err(CloudInitError::UserGroup(format!(
+                    "Failed to update user {}: {}",
+                    config.name, output.stderr
+                )));
+            }
+        }
+    } else if !output.is_success() {
+        return Err(CloudInitError::UserGroup(format!(
+            "Failed to create user {}: {}",
+            config.name, output.stderr
+        )));
+    }
+
+    let change = accounts.supplementary_groups(runner, root, config).await?;
+    if !change.is_empty() {
+        debug!(
+            "Setting groups of user {}: {:?}",
+            config.name, change.groups
+        );
+        let output = runner
+            .run(&SystemCommand::new("pw").args([
+                "usermod",
+                "-n",
+                &config.name,
+                "-G",
+                &change.groups.join(","),
+            ]))
+            .await?;
+        if !output.is_success() {
+            return Err(CloudInitError::UserGroup(format!(
+                "Failed to set groups of user {}: {}",
+                config.name, output.stderr
+            )));
+        }
+    }
+    record_added_groups(root, &config.name, change.added).await?;
+
+    if let Some(hash) = resolve_password_hash(config)? {
+        // -H 0 reads the hash from stdin, keeping it off the command line
+        let output = runner
+            .run(
+                &SystemCommand::new("pw")
+                    .args(["usermod", "-n", &config.name, "-H", "0"])
+                    .stdin(hash),
+            )
+            .await?;
+        if !output.is_success() {
+            return Err(CloudInitError::UserGroup(format!(
+                "Failed to set password for {}: {}",
+                config.name, output.stderr
+            )));
+        }
+    }
+
+    if config.lock_passwd == Some(true) {
+        let output = runner
+            .run(&SystemCommand::new("pw").args(["lock", &config.name]))
+            .await?;
+        // Fails for an account that is locked already
+        if !output.is_success() {
+            warn!(
+                "Failed to lock password for {}: {}",
+                config.name, output.stderr
+            );
+        }
+    }
+
+    if config.expire == Some(true) {
+        // A change date in the past forces a new password at the next login
+        let output = runner
+            .run(&SystemCommand::new("pw").args([
+                "usermod",
+                "-n",
+                &config.name,
+                "-p",
+                "01-Jan-1970",
+            ]))
+            .await?;
+        if !output.is_success() {
+            return Err(CloudInitError::UserGroup(format!(
+                "Failed to expire password for {}: {}",
+                config.name, output.stderr
+            )));
+        }
+    }
+
+    if let Some(sudo) = &config.sudo {
+        configure_sudo(runner, root, distro.sudoers_dir(), &config.name, sudo).await?;
+    }
+
+    accounts
+        .install_authorized_keys(runner, root, config)
+        .await?;
+
+    Ok(())
+}
+
+/// Build the `pw usermod` argument list updating an existing user
+///
+/// Just the user when there is nothing to change.
+fn build_usermod_args(config: &UserFullConfig) -> Vec<String> {
+    let mut args = vec!["usermod".to_string(), "-n".to_string(), config.name.clone()];
+    let settings = [
+        ("-s", &config.shell),
+        ("-c", &config.gecos),
+        ("-g", &config.primary_group),
+    ];
+    for (flag, value) in settings {
+        if let Some(value) = value {
+            args.extend([flag.to_string(), value.clone()]);
+        }
+    }
+    args
+}
+
+/// Build the `pw useradd` argument list for a full user config
+fn build_useradd_args(config: &UserFullConfig) -> Vec<String> {
+    let mut args = vec!["useradd".to_string(), "-n".to_string(), config.name.clone()];
+
+    if config.no_create_home != Some(true) {
+        args.push("-m".to_string());
+    }
+    if let Some(shell) = &config.shell {
+        args.extend(["-s".to_string(), shell.clone()]);
+    }
+    if let Some(homedir) = &config.homedir {
+        args.extend(["-d".to_string(), homedir.clone()]);
+    }
+    if let Some(gecos) = &config.gecos {
+        args.extend(["-c".to_string(), gecos.clone()]);
+    }
+    if let Some(uid) = config.uid {
+        args.extend(["-u".to_string(), uid.to_string()]);
+    }
+    if let Some(primary_group) = &config.primary_group {
+        args.extend(["-g".to_string(), primary_group.clone()]);
+    }
+
+    let ignored: Vec<&str> = [
+        ("system", config.system == Some(true)),
+        ("expiredate", config.expiredate.is_some()),
+        ("inactive", config.inactive.is_some()),
+        ("no_user_group", config.no_user_group == Some(true)),
+    ]
+    .into_iter()
+    .filter_map(|(key, set)| set.then_some(key))
+    .collect();
+    if !ignored.is_empty() {
+        warn!(
+            "pw has no equivalent of {} for user {}; ignored",
+            ignored.join(", "),
+            config.name
+        );
+    }
+
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CloudConfig;
+    use crate::runner::{CommandOutput, RecordingRunner};
+
+    fn freebsd_root() -> (tempfile::TempDir, RootContext) {
+        let temp = tempfile::TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        std::fs::create_dir_all(root.path("/var/run")).unwrap();
+        std::fs::write(
+            root.path("/var/run/os-release"),
+            "NAME=FreeBSD\nID=freebsd\nVERSION_ID=14.1\n",
+        )
+        .unwrap();
+        std::fs::create_dir_all(root.path("/etc")).unwrap();
+        std::fs::write(root.path("/etc/group"), "wheel:*:0:root\n").unwrap();
+        (temp, root)
+    }
+
+    #[tokio::test]
+    async fn test_default_user_with_pw() {
+        let (_temp, root) = freebsd_root();
+        let runner = RecordingRunner::new();
+        let config = CloudConfig {
+            users: vec![UserConfig::Name("default".to_string())],
+            ..Default::default()
+        };
+        super::super::users::create_users(&runner, &root, &config, &[])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "pw useradd -n freebsd -m -s /bin/sh",
+                "pw usermod -n freebsd -G wheel",
+                "pw lock freebsd",
+                "visudo -c -f /usr/local/etc/sudoers.d/90-cloud-init-freebsd",
+            ]
+        );
+        assert!(
+            root.path("/usr/local/etc/sudoers.d/90-cloud-init-freebsd")
+                .exists()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_full_user_with_pw() {
+        let (_temp, root) = freebsd_root();
+        let runner = RecordingRunner::new().with_response(
+            "pw useradd -n alice -m -d /usr/home/alice -c Alice -u 1001",
+            CommandOutput::failure(65, "exists"),
+        );
+        let config = UserFullConfig {
+            name: "alice".to_string(),
+            gecos: Some("Alice".to_string()),
+            uid: Some(1001),
+            homedir: Some("/usr/home/alice".to_string()),
+            hashed_passwd: Some("$6$salt$hash".to_string()),
+            expire: Some(true),
+            ..Default::default()
+        };
+        let distro = Distro::detect(&root).await;
+        let cloud_config = CloudConfig::default();
+        let accounts = Accounts::new(&distro, &cloud_config, &[], &[]);
+        create_user(&runner, &root, &distro, &accounts, &config)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "pw useradd -n alice -m -d /usr/home/alice -c Alice -u 1001",
+                "pw usermod -n alice -c Alice",
+                "pw usermod -n alice -H 0",
+                "pw usermod -n alice -p 01-Jan-1970",
+            ]
+        );
+        assert_eq!(runner.calls()[2].stdin.as_deref(), Some("$6$salt$hash"));
+    }
+
+    #[tokio::test]
+    async fn test_remove_user_with_pw() {
+        let (_temp, root) = freebsd_root();
+        let runner = RecordingRunner::new();
+        let config =
+            CloudConfig::from_yaml("users:\n  - name: olduser\n    remove: true\n").unwrap();
+        super::super::users::create_users(&runner, &root, &config, &[])
+            .await
+            .unwrap();
+        assert_eq!(runner.commands(), vec!["pw userdel -n olduser"]);
+
+        let runner = RecordingRunner::new().with_response(
+            "pw userdel -n olduser",
+            CommandOutput::failure(EXIT_NO_USER, "unknown user"),
+        );
+        super::super::users::create_users(&runner, &root, &config, &[])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pw_failure_is_error() {
+        let (_temp, root) = freebsd_root();
+        let runner =
+            RecordingRunner::new().with_response("pw", CommandOutput::failure(67, "unknown group"));
+        let config = UserFullConfig {
+            name: "bob".to_string(),
+            ..Default::default()
+        };
+        let distro = Distro::detect(&root).await;
+        let cloud_config = CloudConfig::default();
+        let accounts = Accounts::new(&distro, &cloud_config, &[], &[]);
+        let err = create_user(&runner, &root, &distro, &accounts, &config)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("unknown group"));
+    }
+}
@@ -0,0 +1,189 @@
+//! Random seed module
+//!
+//! Implements the `random_seed` cloud-config key. Freshly booted VMs can
+//! be short of entropy, so seed data supplied by the platform or user is
+//! appended to `/dev/urandom` (or another `file`), and a `command` such
+//! as `pollinate` can fetch more. The command sees the seed file in
+//! `RANDOM_SEED_FILE`.
+//!
+//! A command that is not installed is skipped unless `command_required`
+//! is set. Under an alternate root only a regular seed file is written:
+//! devices and commands belong to the running system.
+
+use crate::CloudInitError;
+use crate::config::{RandomSeedConfig, RunCmd};
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use crate::userdata::limits;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use tracing::{debug, info};
+
+/// Where seed data goes when no `file` is set
+pub const DEFAULT_SEED_FILE: &str = "/dev/urandom";
+
+/// Write the seed data and run the seed command
+pub async fn seed_random(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &RandomSeedConfig,
+) -> Result<(), CloudInitError> {
+    let file = config.file.as_deref().unwrap_or(DEFAULT_SEED_FILE);
+
+    if let Some(data) = config.data.as_deref().filter(|d| !d.is_empty()) {
+        let seed = decode_seed(data, config.encoding.as_deref())?;
+        if !root.is_host() && file.starts_with("/dev/") {
+            debug!("Not seeding {} of an alternate root", file);
+        } else {
+            root.append_file(&root.path(file), &seed).await?;
+            info!("Added {} bytes of seed data to {}", seed.len(), file);
+        }
+    }
+
+    if let Some(command) = &config.command {
+        if root.is_host() {
+            run_seed_command(
+                runner,
+                command,
+                file,
+                config.command_required.unwrap_or(false),
+            )
+            .await?;
+        } else {
+            debug!("Not running the seed command for an alternate root");
+        }
+    }
+    Ok(())
+}
+
+/// Decode `data` from `encoding` into raw seed bytes
+fn decode_seed(data: &str, encoding: Option<&str>) -> Result<Vec<u8>, CloudInitError> {
+    match encoding.unwrap_or("raw") {
+        "raw" => Ok(data.as_bytes().to_vec()),
+        "base64" | "b64" => BASE64
+            .decode(data.trim())
+            .map_err(|e| CloudInitError::InvalidData(format!("Invalid base64 seed: {}", e))),
+        "gzip" | "gz" => {
+            // Bounded like any other gzip payload from user data
+            let mut seed = Vec::new();
+            limits::gunzip(
+                data.as_bytes(),
+                &mut seed,
+                limits::current().max_decompressed_size(),
+            )?;
+            Ok(seed)
+        }
+        other => Err(CloudInitError::InvalidData(format!(
+            "Unknown random_seed encoding: {}",
+            other
+        ))),
+    }
+}
+
+async fn run_seed_command(
+    runner: &dyn SystemRunner,
+    command: &RunCmd,
+    seed_file: &str,
+    required: bool,
+) -> Result<(), CloudInitError> {
+    let command = match command {
+        RunCmd::Shell(line) => SystemCommand::new("sh").args(["-c", line.as_str()]),
+        RunCmd::Args(args) => {
+            let Some((program, rest)) = args.split_first() else {
+                return Ok(());
+            };
+            let which = runner
+                .run(&SystemCommand::new("which").arg(program).probe())
+                .await?;
+            if !which.is_success() {
+                if required {
+                    return Err(CloudInitError::Command(format!(
+                        "random_seed command {} is not installed",
+                        program
+                    )));
+                }
+                debug!("Seed command {} not installed, skipping", program);
+                return Ok(());
+            }
+            SystemCommand::new(program).args(rest)
+        }
+    };
+
+    info!("Running seed command: {}", command);
+    let output = runner
+        .run(&command.env("RANDOM_SEED_FILE", seed_file))
+        .await?;
+    if !output.is_success() {
+        return Err(CloudInitError::Command(format!(
+            "random_seed command failed: {}",
+            output.stderr.trim()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_decode_seed() {
+        assert_eq!(decode_seed("abc", None).unwrap(), b"abc");
+        assert_eq!(
+            decode_seed("AAEC/w==", Some("b64")).unwrap(),
+            vec![0x00, 0x01, 0x02, 0xff]
+        );
+        assert!(decode_seed("%%", Some("base64")).is_err());
+        assert!(decode_seed("abc", Some("rot13")).is_err());
+        assert!(decode_seed("not gzip", Some("gzip")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_seed_file_appended() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        std::fs::create_dir_all(root.path("/var/lib")).unwrap();
+        std::fs::write(root.path("/var/lib/seed"), "old").unwrap();
+        let config = RandomSeedConfig {
+            file: Some("/var/lib/seed".to_string()),
+            data: Some("new".to_string()),
+            command: Some(RunCmd::Args(vec!["pollinate".to_string()])),
+            ..Default::default()
+        };
+
+        let runner = RecordingRunner::new();
+        seed_random(&runner, &root, &config).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.path("/var/lib/seed")).unwrap(),
+            "oldnew"
+        );
+        // No commands for an alternate root
+        assert!(runner.commands().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_seed_command() {
+        let runner = RecordingRunner::new();
+        let command = RunCmd::Args(vec!["pollinate".to_string(), "-q".to_string()]);
+        run_seed_command(&runner, &command, "/dev/urandom", false)
+            .await
+            .unwrap();
+        let calls = runner.calls();
+        assert_eq!(runner.commands(), vec!["which pollinate", "pollinate -q"]);
+        assert_eq!(
+            calls[1].env,
+            vec![("RANDOM_SEED_FILE".to_string(), "/dev/urandom".to_string())]
+        );
+
+        let missing = RecordingRunner::new().with_response("which", CommandOutput::failure(1, ""));
+        run_seed_command(&missing, &command, "/dev/urandom", false)
+            .await
+            .unwrap();
+        assert_eq!(missing.commands(), vec!["which pollinate"]);
+        assert!(matches!(
+            run_seed_command(&missing, &command, "/dev/urandom", true).await,
+            Err(CloudInitError::Command(_))
+        ));
+    }
+}
@@ -29,6 +29,7 @@
 
 use crate::CloudInitError;
 use crate::config::RhSubscriptionConfig;
+use crate::runner::{SystemCommand, SystemRunner};
 use tracing::{debug, info, warn};
 
 /// Register the system and apply subscription configuration.
@@ -37,6 +38,7 @@ use tracing::{debug, info, warn};
 /// the supplied config, registers the system with RHSM, optionally attaches
 /// pools, and enables/disables the requested repositories.
 pub async fn configure_rh_subscription(
+    runner: &dyn SystemRunner,
     config: &RhSubscriptionConfig,
 ) -> Result<(), CloudInitError> {
     info!("rh_subscription: starting Red Hat subscription configuration");
@@ -45,23 +47,23 @@ pub async fn configure_rh_subscription(
     validate_config(config)?;
 
     // Register (or verify already registered)
-    register(config).await?;
+    register(runner, config).await?;
 
     // Attach pools
     if !config.add_pool.is_empty() {
-        attach_pools(&config.add_pool).await?;
+        attach_pools(runner, &config.add_pool).await?;
     } else if config.auto_attach == Some(true) {
-        auto_attach(config.service_level.as_deref()).await?;
+        auto_attach(runner, config.service_level.as_deref()).await?;
     }
 
     // Enable repositories
     if !config.enable_repo.is_empty() {
-        enable_repos(&config.enable_repo).await?;
+        enable_repos(runner, &config.enable_repo).await?;
     }
 
     // Disable repositories
     if !config.disable_repo.is_empty() {
-        disable_repos(&config.disable_repo).await?;
+        disable_repos(runner, &config.disable_repo).await?;
     }
 
     info!("rh_subscription: subscription configuration complete");
@@ -86,7 +88,10 @@ fn validate_config(config: &RhSubscriptionConfig) -> Result<(), CloudInitError>
 }
 
 /// Register the system with subscription-manager.
-async fn register(config: &RhSubscriptionConfig) -> Result<(), CloudInitError> {
+async fn register(
+    runner: &dyn SystemRunner,
+    config: &RhSubscriptionConfig,
+) -> Result<(), CloudInitError> {
     let mut args: Vec<String> = vec!["register".to_string(), "--force".to_string()];
 
     // Optional server/RHSM overrides
@@ -108,11 +113,14 @@ async fn register(config: &RhSubscriptionConfig) -> Result<(), CloudInitError> {
         args.push(format!("--password={}", pass));
     }
 
-    run_subscription_manager(&args).await
+    run_subscription_manager(runner, &args).await
 }
 
 /// Auto-attach the best matching subscription, optionally with a service level.
-async fn auto_attach(service_level: Option<&str>) -> Result<(), CloudInitError> {
+async fn auto_attach(
+    runner: &dyn SystemRunner,
+    service_level: Option<&str>,
+) -> Result<(), CloudInitError> {
     let mut args = vec!["attach".to_string(), "--auto".to_string()];
 
     if let Some(level) = service_level {
@@ -120,57 +128,56 @@ async fn auto_attach(service_level: Option<&str>) -> Result<(), CloudInitError>
     }
 
     info!("rh_subscription: auto-attaching subscription");
-    run_subscription_manager(&args).await
+    run_subscription_manager(runner, &args).await
 }
 
 /// Attach one or more pool IDs.
-async fn attach_pools(pools: &[String]) -> Result<(), CloudInitError> {
+async fn attach_pools(runner: &dyn SystemRunner, pools: &[String]) -> Result<(), CloudInitError> {
     for pool in pools {
         info!("rh_subscription: attaching pool {}", pool);
         let args = vec!["attach".to_string(), format!("--pool={}", pool)];
-        run_subscription_manager(&args).await?;
+        run_subscription_manager(runner, &args).await?;
     }
     Ok(())
 }
 
 /// Enable one or more repositories via `subscription-manager repos --enable`.
-async fn enable_repos(repos: &[String]) -> Result<(), CloudInitError> {
+async fn enable_repos(runner: &dyn SystemRunner, repos: &[String]) -> Result<(), CloudInitError> {
     let mut args = vec!["repos".to_string()];
     for repo in repos {
         args.push(format!("--enable={}", repo));
     }
     info!("rh_subscription: enabling {} repo(s)", repos.len());
-    run_subscription_manager(&args).await
+    run_subscription_manager(runner, &args).await
 }
 
 /// Disable one or more repositories via `subscription-manager repos --disable`.
-async fn disable_repos(repos: &[String]) -> Result<(), CloudInitError> {
+async fn disable_repos(runner: &dyn SystemRunner, repos: &[String]) -> Result<(), CloudInitError> {
     let mut args = vec!["repos".to_string()];
     for repo in repos {
         args.push(format!("--disable={}", repo));
     }
     info!("rh_subscription: disabling {} repo(s)", repos.len());
-    run_subscription_manager(&args).await
+    run_subscription_manager(runner, &args).await
 }
 
 /// Execute `subscription-manager` with the given arguments.
-async fn run_subscription_manager(args: &[String]) -> Result<(), CloudInitError> {
+async fn run_subscription_manager(
+    runner: &dyn SystemRunner,
+    args: &[String],
+) -> Result<(), CloudInitError> {
     debug!("subscription-manager {}", args.join(" "));
 
-    let output = tokio::process::Command::new("subscription-manager")
-        .args(args)
-        .output()
-        .await
-        .map_err(|e| CloudInitError::Command(format!("subscription-manager: {}", e)))?;
+    let output = runner
+        .run(&SystemCommand::new("subscription-manager").args(args.iter().cloned()))
+        .await?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.is_success() {
         // subscription-manager sometimes writes errors to stdout
-        let detail = if stderr.trim().is_empty() {
-            stdout.trim().to_string()
+        let detail = if output.stderr.trim().is_empty() {
+            output.stdout.trim().to_string()
         } else {
-            stderr.trim().to_string()
+            output.stderr.trim().to_string()
         };
         warn!("subscription-manager failed: {}", detail);
         return Err(CloudInitError::Module {
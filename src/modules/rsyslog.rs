@@ -0,0 +1,347 @@
+//! rsyslog module
+//!
+//! Implements the `rsyslog` cloud-config key. `configs` snippets are
+//! appended in order to their files below `config_dir` (each file is
+//! rewritten from scratch on every run), and `remotes` become forwarding
+//! rules in the default file. rsyslog is restarted when a file changed,
+//! or `service_reload_command` is run instead.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! rsyslog:
+//!   configs:
+//!     - "*.* @@192.0.2.1"
+//!   remotes:
+//!     maas: 192.0.2.3
+//!     juju: "auth.* @@[2001:db8::1]:514"
+//! ```
+
+use crate::CloudInitError;
+use crate::config::{RsyslogConfig, RsyslogConfigEntry, RsyslogReload};
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use crate::service::ServiceManager;
+use std::path::Path;
+use tokio::fs;
+use tracing::{debug, info};
+
+/// Directory relative filenames are in
+const DEFAULT_CONFIG_DIR: &str = "/etc/rsyslog.d";
+
+/// File for snippets without a filename
+const DEFAULT_CONFIG_FILENAME: &str = "20-cloud-config.conf";
+
+/// Selector of remotes that do not give one
+const DEFAULT_SELECTOR: &str = "*.*";
+
+/// Write rsyslog configuration and reload rsyslog if it changed
+pub async fn configure_rsyslog(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &RsyslogConfig,
+) -> Result<(), CloudInitError> {
+    let files = render_files(config)?;
+    if files.is_empty() {
+        debug!("rsyslog: nothing to configure");
+        return Ok(());
+    }
+
+    let mut changed = false;
+    for (file, content) in &files {
+        let path = root.path(file);
+        if fs::read_to_string(&path).await.ok().as_deref() == Some(content.as_str()) {
+            debug!("{} is up to date", file);
+            continue;
+        }
+        if let Some(dir) = path.parent() {
+            root.create_dir_all(dir).await?;
+        }
+        root.write_file(&path, content).await?;
+        info!("Wrote {}", file);
+        changed = true;
+    }
+
+    if changed {
+        reload(runner, root, config.service_reload_command.as_ref()).await?;
+    }
+    Ok(())
+}
+
+/// Content of each configured file, in the order first written
+fn render_files(config: &RsyslogConfig) -> Result<Vec<(String, String)>, CloudInitError> {
+    let config_dir = config.config_dir.as_deref().unwrap_or(DEFAULT_CONFIG_DIR);
+    let default_file = config
+        .config_filename
+        .as_deref()
+        .unwrap_or(DEFAULT_CONFIG_FILENAME);
+
+    let mut entries: Vec<(&str, String)> = config
+        .configs
+        .iter()
+        .map(|entry| match entry {
+            RsyslogConfigEntry::Content(content) => (default_file, content.clone()),
+            RsyslogConfigEntry::File { content, filename } => {
+                (filename.as_deref().unwrap_or(default_file), content.clone())
+            }
+        })
+        .collect();
+    if let Some(remotes) = render_remotes(config)? {
+        entries.push((default_file, remotes));
+    }
+
+    let mut files: Vec<(String, String)> = Vec::new();
+    for (filename, content) in entries {
+        let path = Path::new(config_dir).join(filename);
+        let path = path.to_string_lossy().into_owned();
+        let content = if content.ends_with('\n') {
+            content
+        } else {
+            format!("{}\n", content)
+        };
+        match files.iter_mut().find(|(file, _)| *file == path) {
+            Some((_, existing)) => existing.push_str(&content),
+            None => files.push((path, content)),
+        }
+    }
+    Ok(files)
+}
+
+/// Forwarding rules for `remotes`, between begin and end comments
+fn render_remotes(config: &RsyslogConfig) -> Result<Option<String>, CloudInitError> {
+    let mut lines = Vec::new();
+    for (name, line) in &config.remotes {
+        if line.trim().is_empty() {
+            continue;
+        }
+        lines.push(Remote::parse(line)?.render(name));
+    }
+    if lines.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "# begin remotes\n{}\n# end remotes\n",
+        lines.join("\n")
+    )))
+}
+
+/// A parsed `remotes` value
+#[derive(Debug, PartialEq, Eq)]
+struct Remote {
+    selector: String,
+    tcp: bool,
+    host: String,
+    port: Option<u16>,
+}
+
+impl Remote {
+    /// Parse `[selector] [@|@@]host[:port]`, with IPv6 hosts in brackets
+    fn parse(line: &str) -> Result<Self, CloudInitError> {
+        let invalid = || CloudInitError::InvalidData(format!("Invalid rsyslog remote: {}", line));
+
+        // A trailing comment is dropped; the entry's name is used instead
+        let data = line.split('#').next().unwrap_or_default();
+        let tokens: Vec<&str> = data.split_whitespace().collect();
+        let (selector, target) = match tokens.as_slice() {
+            [target] => (DEFAULT_SELECTOR, *target),
+            [selector, target] => (*selector, *target),
+            _ => return Err(invalid()),
+        };
+
+        let (tcp, target) = if let Some(rest) = target.strip_prefix("@@") {
+            (true, rest)
+        } else {
+            (false, target.strip_prefix('@').unwrap_or(target))
+        };
+
+        let (host, port) = if let Some(rest) = target.strip_prefix('[') {
+            let (host, after) = rest.split_once(']').ok_or_else(invalid)?;
+            let port = after.strip_prefix(':').filter(|port| !port.is_empty());
+            (host, port)
+        } else if target.matches(':').count() == 1 {
+            let (host, port) = target.split_once(':').ok_or_else(invalid)?;
+            (host, Some(port))
+        } else {
+            (target, None)
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let port = port
+            .map(|port| port.parse::<u16>().map_err(|_| invalid()))
+            .transpose()?;
+
+        Ok(Self {
+            selector: selector.to_string(),
+            tcp,
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    /// The forwarding rule, commented with the remote's name
+    fn render(&self, name: &str) -> String {
+        let mut rule = format!("{} {}", self.selector, if self.tcp { "@@" } else { "@" });
+        if self.host.contains(':') {
+            rule.push_str(&format!("[{}]", self.host));
+        } else {
+            rule.push_str(&self.host);
+        }
+        if let Some(port) = self.port {
+            rule.push_str(&format!(":{}", port));
+        }
+        format!("{} # {}", rule, name)
+    }
+}
+
+/// Make rsyslog read the new configuration
+async fn reload(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    command: Option<&RsyslogReload>,
+) -> Result<(), CloudInitError> {
+    let command = match command {
+        None => ServiceManager::detect(root).try_restart("rsyslog"),
+        Some(RsyslogReload::Shell(shell)) if shell == "auto" => {
+            ServiceManager::detect(root).try_restart("rsyslog")
+        }
+        Some(RsyslogReload::Shell(shell)) => SystemCommand::new("sh").args(["-c", shell]),
+        Some(RsyslogReload::Command(args)) => {
+            let Some((program, args)) = args.split_first() else {
+                return Ok(());
+            };
+            SystemCommand::new(program).args(args)
+        }
+    };
+
+    // Under an alternate root rsyslog reads the files when the image boots
+    if !root.is_host() {
+        debug!("Not reloading rsyslog under {}", root.path("/").display());
+        return Ok(());
+    }
+    let output = runner.run(&command).await?;
+    if !output.is_success() {
+        return Err(CloudInitError::Command(format!(
+            "{} failed: {}",
+            command,
+            output.stderr.trim()
+        )));
+    }
+    info!("Reloaded rsyslog");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::RecordingRunner;
+    use tempfile::TempDir;
+
+    fn config(yaml: &str) -> RsyslogConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_parse_remotes() {
+        let remote = |line| Remote::parse(line).unwrap().render("r");
+        assert_eq!(remote("192.0.2.1"), "*.* @192.0.2.1 # r");
+        assert_eq!(remote("@@192.0.2.1:10514"), "*.* @@192.0.2.1:10514 # r");
+        assert_eq!(
+            remote("auth.* @logs.example.com"),
+            "auth.* @logs.example.com # r"
+        );
+        assert_eq!(remote("@@[2001:db8::1]:514"), "*.* @@[2001:db8::1]:514 # r");
+        assert_eq!(remote("2001:db8::1"), "*.* @[2001:db8::1] # r");
+        assert_eq!(remote("192.0.2.1 # old comment"), "*.* @192.0.2.1 # r");
+        assert!(Remote::parse("192.0.2.1:syslog").is_err());
+        assert!(Remote::parse("a b c").is_err());
+        assert!(Remote::parse("@@").is_err());
+    }
+
+    #[test]
+    fn test_render_files() {
+        let files = render_files(&config(
+            r#"
+configs:
+  - "*.* @@192.0.2.1"
+  - content: "*.* @@192.0.2.2:10514"
+    filename: 01-example.conf
+  - content: "$template Plain,\"%msg%\\n\""
+  - content: "local0.* /var/log/local0.log"
+    filename: /etc/rsyslog.conf.d/local.conf
+remotes:
+  maas: 192.0.2.3
+  unused: ""
+"#,
+        ))
+        .unwrap();
+        assert_eq!(
+            files,
+            vec![
+                (
+                    "/etc/rsyslog.d/20-cloud-config.conf".to_string(),
+                    "*.* @@192.0.2.1\n\
+                     $template Plain,\"%msg%\\n\"\n\
+                     # begin remotes\n\
+                     *.* @192.0.2.3 # maas\n\
+                     # end remotes\n"
+                        .to_string()
+                ),
+                (
+                    "/etc/rsyslog.d/01-example.conf".to_string(),
+                    "*.* @@192.0.2.2:10514\n".to_string()
+                ),
+                (
+                    "/etc/rsyslog.conf.d/local.conf".to_string(),
+                    "local0.* /var/log/local0.log\n".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_configure_rsyslog_under_root() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let runner = RecordingRunner::new();
+        let config = config("config_dir: /etc/syslog.d\nremotes: {maas: 192.0.2.3}\n");
+
+        configure_rsyslog(&runner, &root, &config).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.path("/etc/syslog.d/20-cloud-config.conf")).unwrap(),
+            "# begin remotes\n*.* @192.0.2.3 # maas\n# end remotes\n"
+        );
+        // Reloading waits for the image to boot
+        assert!(runner.commands().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reload_commands() {
+        let runner = RecordingRunner::new();
+        let root = RootContext::host();
+        reload(&runner, &root, None).await.unwrap();
+        reload(
+            &runner,
+            &root,
+            Some(&RsyslogReload::Shell("pkill -HUP rsyslogd".to_string())),
+        )
+        .await
+        .unwrap();
+        reload(
+            &runner,
+            &root,
+            Some(&RsyslogReload::Command(vec![
+                "systemctl".to_string(),
+                "restart".to_string(),
+                "rsyslog".to_string(),
+            ])),
+        )
+        .await
+        .unwrap();
+
+        let commands = runner.commands();
+        assert!(commands[0].ends_with("rsyslog"));
+        assert_eq!(commands[1], "sh -c pkill -HUP rsyslogd");
+        assert_eq!(commands[2], "systemctl restart rsyslog");
+    }
+}
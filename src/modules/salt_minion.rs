@@ -0,0 +1,163 @@
+//! Salt minion module
+//!
+//! Implements the `salt_minion` cloud-config key: installs the minion
+//! package, writes the minion configuration, grains, minion ID and
+//! pre-seeded keys, then enables and restarts the minion service.
+//!
+//! A masterless minion (`file_client: local` in `conf`) has no master to
+//! talk to, so its service is not started.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! salt_minion:
+//!   conf:
+//!     master: salt.example.com
+//!   grains:
+//!     role: web
+//!   minion_id: web-1
+//! ```
+
+use crate::CloudInitError;
+use crate::config::SaltMinionConfig;
+use crate::modules::{packages, start_service};
+use crate::root::RootContext;
+use crate::runner::SystemRunner;
+use std::path::PathBuf;
+use tracing::{debug, info};
+
+/// Default Salt configuration directory
+const DEFAULT_CONFIG_DIR: &str = "/etc/salt";
+
+/// Install and configure the salt minion
+pub async fn configure_salt_minion(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &SaltMinionConfig,
+) -> Result<(), CloudInitError> {
+    info!("Configuring salt minion");
+    if !packages::command_exists(runner, "salt-minion").await {
+        let package = config.pkg_name.as_deref().unwrap_or("salt-minion");
+        packages::install_package(runner, root, package).await?;
+    }
+
+    let config_dir = PathBuf::from(config.config_dir.as_deref().unwrap_or(DEFAULT_CONFIG_DIR));
+    let pki_dir = match &config.pki_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => config_dir.join("pki/minion"),
+    };
+    root.create_dir_all(&root.path(&config_dir)).await?;
+
+    if let Some(conf) = &config.conf {
+        root.write_file(
+            &root.path(config_dir.join("minion")),
+            serde_yaml::to_string(conf)?,
+        )
+        .await?;
+    }
+    if let Some(grains) = &config.grains {
+        root.write_file(
+            &root.path(config_dir.join("grains")),
+            serde_yaml::to_string(grains)?,
+        )
+        .await?;
+    }
+    if let Some(id) = &config.minion_id {
+        root.write_file(&root.path(config_dir.join("minion_id")), id)
+            .await?;
+    }
+
+    if config.public_key.is_some() || config.private_key.is_some() {
+        let pki = root.path(&pki_dir);
+        root.create_dir_all(&pki).await?;
+        root.set_mode(&pki, 0o700).await?;
+        if let Some(key) = &config.public_key {
+            root.write_file(&pki.join("minion.pub"), key).await?;
+        }
+        if let Some(key) = &config.private_key {
+            let path = pki.join("minion.pem");
+            root.write_file(&path, key).await?;
+            root.set_mode(&path, 0o400).await?;
+        }
+    }
+
+    let masterless = config
+        .conf
+        .as_ref()
+        .and_then(|conf| conf.get("file_client"))
+        .and_then(|v| v.as_str())
+        == Some("local");
+    if masterless {
+        debug!("Masterless minion, not starting the service");
+        return Ok(());
+    }
+    let service = config.service_name.as_deref().unwrap_or("salt-minion");
+    start_service(runner, root, service).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_configure_salt_minion_under_root() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let config: SaltMinionConfig = serde_yaml::from_str(
+            r#"
+conf:
+  master: salt.example.com
+grains:
+  role: web
+minion_id: web-1
+public_key: PUBLIC
+private_key: PRIVATE
+"#,
+        )
+        .unwrap();
+
+        let runner = RecordingRunner::new()
+            .with_response("which salt-minion", CommandOutput::failure(1, ""));
+        configure_salt_minion(&runner, &root, &config)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(root.path("/etc/salt/minion")).unwrap(),
+            "master: salt.example.com\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.path("/etc/salt/grains")).unwrap(),
+            "role: web\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.path("/etc/salt/minion_id")).unwrap(),
+            "web-1"
+        );
+        assert!(root.path("/etc/salt/pki/minion/minion.pem").exists());
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "which salt-minion",
+                "which apt-get",
+                "apt-get install -y salt-minion",
+                "systemctl enable salt-minion",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_masterless_minion_not_started() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let config: SaltMinionConfig = serde_yaml::from_str("conf: {file_client: local}").unwrap();
+
+        let runner = RecordingRunner::new();
+        configure_salt_minion(&runner, &root, &config)
+            .await
+            .unwrap();
+        assert_eq!(runner.commands(), vec!["which salt-minion"]);
+    }
+}
@@ -1,71 +1,384 @@
 //! SSH key configuration module
+//!
+//! Installs authorized keys for users and replaces the host keys of a new
+//! instance.
+//!
+//! Authorized keys are validated before they are written: the key type
+//! must be one sshd knows, the key data must be base64 and the line may
+//! not break into several. They are kept between marker comments, so
+//! keys added by other means survive and the next instance replaces
+//! rather than repeats them:
+//!
+//! ```text
+//! ssh-ed25519 AAAA... added-by-hand
+//! # BEGIN cloud-init-rs managed keys
+//! no-port-forwarding ssh-ed25519 AAAA... from-cloud-config
+//! # END cloud-init-rs managed keys
+//! ```
 
-use crate::CloudInitError;
-use std::path::PathBuf;
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use crate::{CloudInitError, IoContext};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use std::fmt;
+use std::path::{Path, PathBuf};
 use tokio::fs;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+
+/// Directory holding the SSH server's host keys
+const SSH_DIR: &str = "/etc/ssh";
+
+/// Line before the keys cloud-init-rs manages in authorized_keys
+const BEGIN_MARKER: &str = "# BEGIN cloud-init-rs managed keys";
+
+/// Line after the keys cloud-init-rs manages in authorized_keys
+const END_MARKER: &str = "# END cloud-init-rs managed keys";
+
+/// Key types sshd accepts in authorized_keys, certificates aside
+const KEY_TYPES: &[&str] = &[
+    "ssh-rsa",
+    "ssh-dss",
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// Whether `token` names a key type, or a certificate of one
+fn is_key_type(token: &str) -> bool {
+    let base = token
+        .strip_suffix("-cert-v01@openssh.com")
+        .map(|base| {
+            // sk-ssh-ed25519-cert-v01@openssh.com certifies sk-ssh-ed25519@openssh.com
+            if base.starts_with("sk-") {
+                format!("{}@openssh.com", base)
+            } else {
+                base.to_string()
+            }
+        })
+        .unwrap_or_else(|| token.to_string());
+    KEY_TYPES.contains(&base.as_str())
+}
+
+/// A validated authorized_keys entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthorizedKey {
+    /// Comma-separated options such as `no-port-forwarding`
+    pub options: Option<String>,
+    pub key_type: String,
+    /// Base64 key data
+    pub blob: String,
+    pub comment: Option<String>,
+}
+
+impl AuthorizedKey {
+    /// Parse and validate an authorized_keys line
+    pub fn parse(line: &str) -> Result<Self, CloudInitError> {
+        let invalid = |reason: &str| CloudInitError::InvalidData(format!("SSH key {}", reason));
+        let line = line.trim();
+        if line.contains(['\n', '\r', '\0']) {
+            return Err(invalid("spans several lines"));
+        }
+        if line.is_empty() || line.starts_with('#') {
+            return Err(invalid("is empty"));
+        }
+
+        let first = line.split_whitespace().next().unwrap_or_default();
+        let (options, rest) = if is_key_type(first) {
+            (None, line)
+        } else {
+            let (options, rest) = split_options(line)
+                .ok_or_else(|| invalid("has unbalanced quotes in its options"))?;
+            (Some(options.to_string()), rest)
+        };
+
+        let mut tokens = rest.split_whitespace();
+        let key_type = tokens.next().ok_or_else(|| invalid("has no key type"))?;
+        if !is_key_type(key_type) {
+            return Err(invalid(&format!("has unknown type {}", key_type)));
+        }
+        let blob = tokens.next().ok_or_else(|| invalid("has no key data"))?;
+        if BASE64.decode(blob).map_or(true, |data| data.is_empty()) {
+            return Err(invalid("data is not base64"));
+        }
+        let comment = tokens.collect::<Vec<_>>().join(" ");
+
+        Ok(Self {
+            options,
+            key_type: key_type.to_string(),
+            blob: blob.to_string(),
+            comment: (!comment.is_empty()).then_some(comment),
+        })
+    }
+}
+
+impl fmt::Display for AuthorizedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(options) = &self.options {
+            write!(f, "{} ", options)?;
+        }
+        write!(f, "{} {}", self.key_type, self.blob)?;
+        if let Some(comment) = &self.comment {
+            write!(f, " {}", comment)?;
+        }
+        Ok(())
+    }
+}
+
+/// Split the options off an authorized_keys line
+///
+/// Options end at the first whitespace outside double quotes, in which
+/// `\"` escapes a quote. `None` if a quote is left open.
+fn split_options(line: &str) -> Option<(&str, &str)> {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                return Some((&line[..i], line[i..].trim_start()));
+            }
+            _ => {}
+        }
+    }
+    (!quoted).then_some((line, ""))
+}
+
+/// authorized_keys `existing` with its managed keys replaced by `keys`
+///
+/// Keys outside the markers are kept unless `keys` has the same key
+/// data, which takes their place.
+pub fn merge_authorized_keys(existing: &str, keys: &[AuthorizedKey]) -> String {
+    let mut unmanaged = Vec::new();
+    let mut managed = false;
+    for line in existing.lines() {
+        match line.trim() {
+            BEGIN_MARKER => managed = true,
+            END_MARKER => managed = false,
+            _ if managed => {}
+            trimmed => {
+                let duplicate = AuthorizedKey::parse(trimmed)
+                    .is_ok_and(|key| keys.iter().any(|k| k.blob == key.blob));
+                if !duplicate {
+                    unmanaged.push(line);
+                }
+            }
+        }
+    }
+
+    let mut content = String::new();
+    for line in unmanaged {
+        content.push_str(line);
+        content.push('\n');
+    }
+    if !keys.is_empty() {
+        content.push_str(BEGIN_MARKER);
+        content.push('\n');
+        for key in keys {
+            content.push_str(&key.to_string());
+            content.push('\n');
+        }
+        content.push_str(END_MARKER);
+        content.push('\n');
+    }
+    content
+}
+
+/// The valid keys of `keys`, each once
+///
+/// Invalid keys are logged and left out; of keys with the same data the
+/// first is kept.
+pub fn validate_keys(username: &str, keys: &[String]) -> Vec<AuthorizedKey> {
+    let mut valid: Vec<AuthorizedKey> = Vec::new();
+    for key in keys {
+        match AuthorizedKey::parse(key) {
+            Ok(key) if valid.iter().any(|k| k.blob == key.blob) => {
+                debug!("Skipping duplicate SSH key for {}", username);
+            }
+            Ok(key) => valid.push(key),
+            Err(e) => warn!("Skipping SSH key for {}: {}", username, e),
+        }
+    }
+    valid
+}
+
+/// Host key types generated when `ssh_genkeytypes` is not set
+pub const DEFAULT_HOST_KEY_TYPES: &[&str] = &["rsa", "ecdsa", "ed25519"];
+
+/// Replace the SSH host keys, e.g. those baked into an image
+///
+/// With `delete_existing`, every `ssh_host_*_key` pair in `/etc/ssh` is
+/// removed first. A key of each of `key_types` is then generated unless
+/// one exists. Does nothing if there is no `/etc/ssh`.
+pub async fn regenerate_host_keys(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    delete_existing: bool,
+    key_types: &[String],
+) -> Result<(), CloudInitError> {
+    let ssh_dir = root.path(SSH_DIR);
+    if !ssh_dir.is_dir() {
+        debug!("No {} directory, not generating host keys", SSH_DIR);
+        return Ok(());
+    }
+
+    if delete_existing {
+        let mut entries = fs::read_dir(&ssh_dir).await.with_path(&ssh_dir)?;
+        while let Some(entry) = entries.next_entry().await.with_path(&ssh_dir)? {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with("ssh_host_") && name.contains("_key") {
+                debug!("Removing host key {}", name);
+                root.remove_file(&entry.path()).await?;
+            }
+        }
+    }
+
+    for key_type in key_types {
+        let key = format!("{}/ssh_host_{}_key", SSH_DIR, key_type);
+        if !delete_existing && root.path(&key).exists() {
+            continue;
+        }
+        info!("Generating {} SSH host key", key_type);
+        let output = runner
+            .run(&SystemCommand::new("ssh-keygen").args([
+                "-t",
+                key_type.as_str(),
+                "-N",
+                "",
+                "-q",
+                "-f",
+                key.as_str(),
+            ]))
+            .await?;
+        if !output.is_success() {
+            return Err(CloudInitError::Command(format!(
+                "ssh-keygen -t {} failed: {}",
+                key_type,
+                output.stderr.trim()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The SSH server's public host keys, as `(key type, "type base64")`
+///
+/// Sorted by file name; comments are dropped and unreadable or malformed
+/// files skipped.
+pub async fn host_public_keys(root: &RootContext) -> Vec<(String, String)> {
+    let ssh_dir = root.path(SSH_DIR);
+    let Ok(mut entries) = fs::read_dir(&ssh_dir).await else {
+        return Vec::new();
+    };
+    let mut files = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with("ssh_host_") && name.ends_with("_key.pub") {
+            files.push((name, entry.path()));
+        }
+    }
+    files.sort();
+
+    let mut keys = Vec::new();
+    for (name, path) in files {
+        let Ok(content) = fs::read_to_string(&path).await else {
+            continue;
+        };
+        let mut fields = content.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(key_type), Some(key)) if is_key_type(key_type) => {
+                keys.push((key_type.to_string(), format!("{} {}", key_type, key)));
+            }
+            _ => debug!("Skipping malformed host key {}", name),
+        }
+    }
+    keys
+}
 
 /// Configure SSH authorized keys for a user
+///
+/// The home directory is looked up in `/etc/passwd` beneath `root`.
+/// `keys` replace the ones managed before, see [`merge_authorized_keys`].
 pub async fn configure_user_ssh_keys(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
     username: &str,
     keys: &[String],
 ) -> Result<(), CloudInitError> {
     if keys.is_empty() {
         return Ok(());
     }
+    write_user_ssh_keys(runner, root, username, keys, false).await
+}
 
+/// Make `keys` the user's only SSH authorized keys
+///
+/// Unlike [`configure_user_ssh_keys`], keys added outside cloud-init are
+/// dropped too; with no `keys` the file is left empty.
+pub async fn replace_user_ssh_keys(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    username: &str,
+    keys: &[String],
+) -> Result<(), CloudInitError> {
+    write_user_ssh_keys(runner, root, username, keys, true).await
+}
+
+async fn write_user_ssh_keys(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    username: &str,
+    keys: &[String],
+    exclusive: bool,
+) -> Result<(), CloudInitError> {
+    let keys = validate_keys(username, keys);
     info!("Configuring {} SSH keys for user {}", keys.len(), username);
 
     // Get user's home directory
-    let home_dir = get_user_home(username).await?;
-    let ssh_dir = home_dir.join(".ssh");
+    let home_dir = get_user_home(root, username).await?;
+    let ssh_dir = root.path(home_dir.join(".ssh"));
     let authorized_keys_path = ssh_dir.join("authorized_keys");
 
     // Create .ssh directory if it doesn't exist
     if !ssh_dir.exists() {
         debug!("Creating SSH directory: {:?}", ssh_dir);
-        fs::create_dir_all(&ssh_dir)
-            .await
-            .map_err(CloudInitError::Io)?;
+        root.create_dir_all(&ssh_dir).await?;
 
         // Set permissions to 700
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            fs::set_permissions(&ssh_dir, std::fs::Permissions::from_mode(0o700))
-                .await
-                .map_err(CloudInitError::Io)?;
-        }
+        root.set_mode(&ssh_dir, 0o700).await?;
     }
 
-    // Write authorized_keys
-    let content = keys.join("\n") + "\n";
-    fs::write(&authorized_keys_path, &content)
-        .await
-        .map_err(CloudInitError::Io)?;
+    // Replace the managed keys, keeping any others unless exclusive
+    let existing = if exclusive {
+        String::new()
+    } else {
+        fs::read_to_string(&authorized_keys_path)
+            .await
+            .unwrap_or_default()
+    };
+    let content = merge_authorized_keys(&existing, &keys);
+    root.write_file(&authorized_keys_path, &content).await?;
 
     // Set permissions to 600
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        fs::set_permissions(
-            &authorized_keys_path,
-            std::fs::Permissions::from_mode(0o600),
-        )
-        .await
-        .map_err(CloudInitError::Io)?;
-    }
+    root.set_mode(&authorized_keys_path, 0o600).await?;
 
     // Change ownership to the user
-    change_ownership(&ssh_dir, username).await?;
-    change_ownership(&authorized_keys_path, username).await?;
+    // chown may run chrooted, so it gets the in-system paths
+    let ssh_dir = home_dir.join(".ssh");
+    change_ownership(runner, &ssh_dir, username).await?;
+    change_ownership(runner, &ssh_dir.join("authorized_keys"), username).await?;
 
     Ok(())
 }
 
-async fn get_user_home(username: &str) -> Result<PathBuf, CloudInitError> {
+async fn get_user_home(root: &RootContext, username: &str) -> Result<PathBuf, CloudInitError> {
     // Read /etc/passwd to find home directory
-    let passwd = fs::read_to_string("/etc/passwd")
+    let passwd = fs::read_to_string(root.path("/etc/passwd"))
         .await
         .map_err(CloudInitError::Io)?;
 
@@ -80,18 +393,22 @@ async fn get_user_home(username: &str) -> Result<PathBuf, CloudInitError> {
     Ok(PathBuf::from(format!("/home/{}", username)))
 }
 
-async fn change_ownership(path: &PathBuf, username: &str) -> Result<(), CloudInitError> {
-    let output = tokio::process::Command::new("chown")
-        .args([username, &path.to_string_lossy()])
-        .output()
-        .await
-        .map_err(|e| CloudInitError::Command(e.to_string()))?;
+async fn change_ownership(
+    runner: &dyn SystemRunner,
+    path: &Path,
+    username: &str,
+) -> Result<(), CloudInitError> {
+    let output = runner
+        .run(
+            &SystemCommand::new("chown")
+                .args([username.to_string(), path.to_string_lossy().into_owned()]),
+        )
+        .await?;
 
-    if !output.status.success() {
+    if !output.is_success() {
         debug!(
             "Failed to change ownership of {:?}: {}",
-            path,
-            String::from_utf8_lossy(&output.stderr)
+            path, output.stderr
         );
     }
 
@@ -101,18 +418,95 @@ async fn change_ownership(path: &PathBuf, username: &str) -> Result<(), CloudIni
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
     use tempfile::TempDir;
 
     #[tokio::test]
     async fn test_configure_user_ssh_keys_empty_keys() {
-        let result = configure_user_ssh_keys("testuser", &[]).await;
+        let runner = RecordingRunner::new();
+        let result = configure_user_ssh_keys(&runner, &RootContext::host(), "testuser", &[]).await;
         assert!(result.is_ok());
+        assert!(runner.commands().is_empty());
+    }
+
+    #[test]
+    fn test_parse_authorized_key() {
+        let key = AuthorizedKey::parse("ssh-ed25519 AAAAC3Nz  alice@laptop work\n").unwrap();
+        assert_eq!(key.options, None);
+        assert_eq!(key.key_type, "ssh-ed25519");
+        assert_eq!(key.comment.as_deref(), Some("alice@laptop work"));
+        assert_eq!(key.to_string(), "ssh-ed25519 AAAAC3Nz alice@laptop work");
+
+        let line = r#"no-port-forwarding,command="echo \"hi there\"" ecdsa-sha2-nistp256 AAAAE2Vj"#;
+        let key = AuthorizedKey::parse(line).unwrap();
+        assert_eq!(
+            key.options.as_deref(),
+            Some(r#"no-port-forwarding,command="echo \"hi there\"""#)
+        );
+        assert_eq!(key.key_type, "ecdsa-sha2-nistp256");
+        assert_eq!(key.to_string(), line);
+
+        assert!(AuthorizedKey::parse("ssh-ed25519-cert-v01@openssh.com AAAAC3Nz").is_ok());
+        assert!(AuthorizedKey::parse("sk-ssh-ed25519-cert-v01@openssh.com AAAAC3Nz").is_ok());
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_keys() {
+        for line in [
+            "",
+            "# comment",
+            "ssh-foo AAAAC3Nz",
+            "ssh-ed25519",
+            "ssh-ed25519 not-base64!",
+            "ssh-ed25519 AAAAC3Nz x\nssh-ed25519 AAAAB3Nz injected",
+            r#"command="unterminated ssh-ed25519 AAAAC3Nz"#,
+            "no-pty AAAAC3Nz",
+        ] {
+            assert!(AuthorizedKey::parse(line).is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_validate_keys_dedups() {
+        let keys = [
+            "ssh-ed25519 AAAAC3Nz first".to_string(),
+            "bogus".to_string(),
+            "no-pty ssh-ed25519 AAAAC3Nz again".to_string(),
+            "ssh-rsa AAAAB3Nz".to_string(),
+        ];
+        let valid = validate_keys("alice", &keys);
+        assert_eq!(valid.len(), 2);
+        assert_eq!(valid[0].comment.as_deref(), Some("first"));
+        assert_eq!(valid[1].key_type, "ssh-rsa");
+    }
+
+    #[test]
+    fn test_merge_authorized_keys() {
+        let existing = format!(
+            "ssh-rsa AAAAB3Nz by-hand\nssh-ed25519 AAAAC3Nz old-copy\n{}\nssh-ed25519 AAAAold0 previous-instance\n{}\n# a comment\n",
+            BEGIN_MARKER, END_MARKER
+        );
+        let keys = validate_keys("alice", &["no-pty ssh-ed25519 AAAAC3Nz new".to_string()]);
+        let merged = merge_authorized_keys(&existing, &keys);
+        assert_eq!(
+            merged,
+            format!(
+                "ssh-rsa AAAAB3Nz by-hand\n# a comment\n{}\nno-pty ssh-ed25519 AAAAC3Nz new\n{}\n",
+                BEGIN_MARKER, END_MARKER
+            )
+        );
+        // Applying the same keys again changes nothing
+        assert_eq!(merge_authorized_keys(&merged, &keys), merged);
+        assert_eq!(
+            merge_authorized_keys(&merged, &[]),
+            "ssh-rsa AAAAB3Nz by-hand\n# a comment\n"
+        );
     }
 
     #[tokio::test]
     async fn test_get_user_home_root() {
         // root should be in /etc/passwd on most systems
-        let result = get_user_home("root").await;
+        let result = get_user_home(&RootContext::host(), "root").await;
         if let Ok(path) = result {
             // On macOS root is /var/root, on Linux /root
             assert!(path.to_string_lossy().contains("root"));
@@ -121,12 +515,162 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_user_home_nonexistent_defaults() {
-        let result = get_user_home("nonexistent_user_xyz_12345").await;
+        let result = get_user_home(&RootContext::host(), "nonexistent_user_xyz_12345").await;
         if let Ok(path) = result {
             assert_eq!(path, PathBuf::from("/home/nonexistent_user_xyz_12345"));
         }
     }
 
+    #[tokio::test]
+    async fn test_regenerate_host_keys() {
+        let tmp = TempDir::new().unwrap();
+        let ssh = tmp.path().join("etc/ssh");
+        std::fs::create_dir_all(&ssh).unwrap();
+        for name in ["ssh_host_rsa_key", "ssh_host_rsa_key.pub", "sshd_config"] {
+            std::fs::write(ssh.join(name), "x").unwrap();
+        }
+        let root = RootContext::new(tmp.path());
+        let runner = RecordingRunner::new();
+
+        regenerate_host_keys(&runner, &root, true, &["ed25519".to_string()])
+            .await
+            .unwrap();
+
+        assert!(!ssh.join("ssh_host_rsa_key").exists());
+        assert!(!ssh.join("ssh_host_rsa_key.pub").exists());
+        assert!(ssh.join("sshd_config").exists());
+        assert_eq!(
+            runner.commands(),
+            vec!["ssh-keygen -t ed25519 -N  -q -f /etc/ssh/ssh_host_ed25519_key"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_host_public_keys() {
+        let tmp = TempDir::new().unwrap();
+        let ssh = tmp.path().join("etc/ssh");
+        std::fs::create_dir_all(&ssh).unwrap();
+        std::fs::write(
+            ssh.join("ssh_host_ed25519_key.pub"),
+            "ssh-ed25519 AAAAC3Nz root@image\n",
+        )
+        .unwrap();
+        std::fs::write(
+            ssh.join("ssh_host_ecdsa_key.pub"),
+            "ecdsa-sha2-nistp256 AAAAE2Vj\n",
+        )
+        .unwrap();
+        std::fs::write(ssh.join("ssh_host_rsa_key.pub"), "garbage\n").unwrap();
+        std::fs::write(ssh.join("ssh_host_ed25519_key"), "private").unwrap();
+
+        assert_eq!(
+            host_public_keys(&RootContext::new(tmp.path())).await,
+            vec![
+                (
+                    "ecdsa-sha2-nistp256".to_string(),
+                    "ecdsa-sha2-nistp256 AAAAE2Vj".to_string()
+                ),
+                (
+                    "ssh-ed25519".to_string(),
+                    "ssh-ed25519 AAAAC3Nz".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_regenerate_host_keys_keeps_existing() {
+        let tmp = TempDir::new().unwrap();
+        let ssh = tmp.path().join("etc/ssh");
+        std::fs::create_dir_all(&ssh).unwrap();
+        std::fs::write(ssh.join("ssh_host_rsa_key"), "x").unwrap();
+        let root = RootContext::new(tmp.path());
+        let runner = RecordingRunner::new()
+            .with_response("ssh-keygen", CommandOutput::failure(1, "unknown key type"));
+
+        let types = ["rsa".to_string(), "dsa".to_string()];
+        let err = regenerate_host_keys(&runner, &root, false, &types)
+            .await
+            .unwrap_err();
+
+        assert!(ssh.join("ssh_host_rsa_key").exists());
+        assert_eq!(runner.commands().len(), 1);
+        assert!(err.to_string().contains("unknown key type"));
+    }
+
+    #[tokio::test]
+    async fn test_configure_user_ssh_keys_under_root() {
+        let tmp = TempDir::new().unwrap();
+        std::fs::create_dir_all(tmp.path().join("etc")).unwrap();
+        std::fs::write(
+            tmp.path().join("etc/passwd"),
+            "alice:x:1000:1000::/srv/alice:/bin/sh\n",
+        )
+        .unwrap();
+        let root = RootContext::new(tmp.path());
+        let runner = RecordingRunner::new();
+
+        configure_user_ssh_keys(
+            &runner,
+            &root,
+            "alice",
+            &["ssh-ed25519 AAAA a@b".to_string()],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(tmp.path().join("srv/alice/.ssh/authorized_keys")).unwrap(),
+            format!("{}\nssh-ed25519 AAAA a@b\n{}\n", BEGIN_MARKER, END_MARKER)
+        );
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "chown alice /srv/alice/.ssh",
+                "chown alice /srv/alice/.ssh/authorized_keys",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replace_user_ssh_keys_drops_unmanaged() {
+        let tmp = TempDir::new().unwrap();
+        std::fs::create_dir_all(tmp.path().join("etc")).unwrap();
+        std::fs::write(
+            tmp.path().join("etc/passwd"),
+            "alice:x:1000:1000::/home/alice:/bin/sh\n",
+        )
+        .unwrap();
+        let ssh_dir = tmp.path().join("home/alice/.ssh");
+        std::fs::create_dir_all(&ssh_dir).unwrap();
+        std::fs::write(ssh_dir.join("authorized_keys"), "ssh-rsa AAAAold admin\n").unwrap();
+        let root = RootContext::new(tmp.path());
+        let runner = RecordingRunner::new();
+
+        let keys = ["ssh-ed25519 AAAA a@b".to_string()];
+        configure_user_ssh_keys(&runner, &root, "alice", &keys)
+            .await
+            .unwrap();
+        let merged = std::fs::read_to_string(ssh_dir.join("authorized_keys")).unwrap();
+        assert!(merged.starts_with("ssh-rsa AAAAold admin\n"));
+
+        replace_user_ssh_keys(&runner, &root, "alice", &keys)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(ssh_dir.join("authorized_keys")).unwrap(),
+            format!("{}\nssh-ed25519 AAAA a@b\n{}\n", BEGIN_MARKER, END_MARKER)
+        );
+
+        replace_user_ssh_keys(&runner, &root, "alice", &[])
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(ssh_dir.join("authorized_keys")).unwrap(),
+            ""
+        );
+    }
+
     #[tokio::test]
     async fn test_configure_user_ssh_keys_writes_files() {
         let tmp = TempDir::new().unwrap();
@@ -153,9 +697,14 @@ mod tests {
         let tmp = TempDir::new().unwrap();
         let file = tmp.path().join("test.txt");
         tokio::fs::write(&file, "test").await.unwrap();
-        // chown to nonexistent user should not panic
-        let result = change_ownership(&file.to_path_buf(), "nonexistent_xyz_12345").await;
+        let runner = RecordingRunner::new()
+            .with_response("chown", CommandOutput::failure(1, "invalid user"));
+        let result = change_ownership(&runner, &file, "nonexistent_xyz_12345").await;
         assert!(result.is_ok()); // function logs but doesn't error
+        assert_eq!(
+            runner.commands(),
+            vec![format!("chown nonexistent_xyz_12345 {}", file.display())]
+        );
     }
 
     #[tokio::test]
@@ -169,7 +718,7 @@ mod tests {
                 if fields.len() >= 6 {
                     let username = fields[0];
                     let expected_home = fields[5];
-                    let result = get_user_home(username).await.unwrap();
+                    let result = get_user_home(&RootContext::host(), username).await.unwrap();
                     assert_eq!(result, PathBuf::from(expected_home));
                     break; // Just test the first one
                 }
@@ -0,0 +1,365 @@
+//! sshd configuration module
+//!
+//! Implements the `ssh_pwauth` and `ssh_config` cloud-config keys, which
+//! become directives in `/etc/ssh/sshd_config.d/50-cloud-init.conf`, and
+//! `disable_root`/`disable_root_opts` for root's authorized keys.
+//!
+//! sshd uses the first value it reads for a keyword, so the drop-in
+//! directory is included at the top of `sshd_config` when it is not
+//! already. A drop-in that `sshd -t` rejects is rolled back before the
+//! error is reported; otherwise a running sshd is reloaded.
+//!
+//! With `disable_root` (the default), root's copy of the instance's
+//! `ssh_authorized_keys` carries `disable_root_opts`, which by default
+//! only prints which user to log in as instead.
+
+use super::ssh_keys;
+use crate::CloudInitError;
+use crate::config::{CloudConfig, SshPwauth, UserConfig};
+use crate::distro::Distro;
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use crate::service::ServiceManager;
+use std::collections::BTreeMap;
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+/// Drop-in holding the settings from cloud-config
+pub const DROP_IN: &str = "/etc/ssh/sshd_config.d/50-cloud-init.conf";
+
+/// Main sshd configuration file
+const SSHD_CONFIG: &str = "/etc/ssh/sshd_config";
+
+/// Line including the drop-in directory
+const INCLUDE: &str = "Include /etc/ssh/sshd_config.d/*.conf";
+
+/// Options for root's keys when `disable_root_opts` is not set
+///
+/// `$USER` is replaced by the default user, `$DISABLE_USER` by `root`.
+pub const DEFAULT_DISABLE_ROOT_OPTS: &str = "no-port-forwarding,no-agent-forwarding,\
+no-X11-forwarding,command=\"echo 'Please login as the user \\\"$USER\\\" rather than \
+the user \\\"$DISABLE_USER\\\".';echo;sleep 10;exit 142\"";
+
+/// Render the drop-in, or `None` if there is nothing to set
+///
+/// `ssh_pwauth` comes first, so it wins over a `PasswordAuthentication`
+/// in `ssh_config`. List values repeat the directive once per item.
+pub fn render_drop_in(
+    pwauth: Option<SshPwauth>,
+    settings: Option<&BTreeMap<String, serde_yaml::Value>>,
+) -> Result<Option<String>, CloudInitError> {
+    let mut lines = Vec::new();
+    match pwauth {
+        Some(SshPwauth::Enabled) => lines.push("PasswordAuthentication yes".to_string()),
+        Some(SshPwauth::Disabled) => lines.push("PasswordAuthentication no".to_string()),
+        Some(SshPwauth::Unchanged) | None => {}
+    }
+    for (key, value) in settings.into_iter().flatten() {
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(CloudInitError::InvalidData(format!(
+                "Invalid sshd keyword '{}'",
+                key
+            )));
+        }
+        let values = match value {
+            serde_yaml::Value::Sequence(items) => items.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            lines.push(format!("{} {}", key, directive_value(key, value)?));
+        }
+    }
+
+    if lines.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "# Written by cloud-init-rs\n{}\n",
+        lines.join("\n")
+    )))
+}
+
+/// An sshd_config value; booleans become `yes`/`no`
+fn directive_value(key: &str, value: &serde_yaml::Value) -> Result<String, CloudInitError> {
+    let rendered = match value {
+        serde_yaml::Value::Bool(b) => if *b { "yes" } else { "no" }.to_string(),
+        serde_yaml::Value::Number(n) => n.to_string(),
+        serde_yaml::Value::String(s) => s.clone(),
+        _ => String::new(),
+    };
+    if rendered.is_empty() || rendered.contains('\n') {
+        return Err(CloudInitError::InvalidData(format!(
+            "Invalid value for sshd keyword '{}'",
+            key
+        )));
+    }
+    Ok(rendered)
+}
+
+/// Write, validate and apply the sshd settings from `config`
+pub async fn configure_sshd(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &CloudConfig,
+) -> Result<(), CloudInitError> {
+    let Some(content) = render_drop_in(config.ssh_pwauth, config.ssh_config.as_ref())? else {
+        return Ok(());
+    };
+    let sshd_config = root.path(SSHD_CONFIG);
+    if !sshd_config.is_file() {
+        debug!("No {}, not configuring sshd", SSHD_CONFIG);
+        return Ok(());
+    }
+
+    ensure_include(root).await?;
+
+    let drop_in = root.path(DROP_IN);
+    let previous = fs::read_to_string(&drop_in).await.ok();
+    if let Some(parent) = drop_in.parent() {
+        root.create_dir_all(parent).await?;
+    }
+    root.write_file(&drop_in, &content).await?;
+    root.set_mode(&drop_in, 0o600).await?;
+
+    let check = runner
+        .run(&SystemCommand::new("sshd").args(["-t", "-f", SSHD_CONFIG]))
+        .await?;
+    if !check.is_success() {
+        match previous {
+            Some(previous) => root.write_file(&drop_in, previous).await?,
+            None => root.remove_file(&drop_in).await?,
+        }
+        return Err(CloudInitError::InvalidData(format!(
+            "sshd rejected the ssh settings: {}",
+            check.stderr.trim()
+        )));
+    }
+    info!("Wrote sshd settings to {}", DROP_IN);
+
+    if root.is_host() {
+        reload_sshd(runner, root).await;
+    }
+    Ok(())
+}
+
+/// Include the drop-in directory at the top of sshd_config if needed
+async fn ensure_include(root: &RootContext) -> Result<(), CloudInitError> {
+    let path = root.path(SSHD_CONFIG);
+    let current = fs::read_to_string(&path)
+        .await
+        .map_err(CloudInitError::Io)?;
+    let included = current.lines().any(|line| {
+        let line = line.trim();
+        line.get(..7)
+            .is_some_and(|word| word.eq_ignore_ascii_case("include"))
+            && line.contains("sshd_config.d")
+    });
+    if included {
+        return Ok(());
+    }
+    debug!("Including sshd_config.d from {}", SSHD_CONFIG);
+    root.write_file(&path, format!("{}\n{}", INCLUDE, current))
+        .await
+}
+
+/// Reload sshd if it is running
+///
+/// The distribution's unit name is tried first, then the other common one.
+async fn reload_sshd(runner: &dyn SystemRunner, root: &RootContext) {
+    let manager = ServiceManager::detect(root);
+    let native = Distro::detect(root).await.ssh_service();
+    let other = if native == "ssh" { "sshd" } else { "ssh" };
+    for unit in [native, other] {
+        let command = manager.try_reload(unit);
+        if let Ok(output) = runner.run(&command).await
+            && output.is_success()
+        {
+            debug!("Reloaded {}", unit);
+            return;
+        }
+    }
+    warn!("Could not reload sshd; new settings apply once it restarts");
+}
+
+/// Install the instance's `ssh_authorized_keys` for root
+///
+/// With `disable_root` each key is prefixed with the restricting options.
+/// Keys root already has are kept unless one of the new keys replaces
+/// them, so the restricted key is the only one left.
+pub async fn configure_root_keys(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &CloudConfig,
+) -> Result<(), CloudInitError> {
+    if config.ssh_authorized_keys.is_empty() {
+        return Ok(());
+    }
+
+    let new_keys: Vec<String> = if config.disable_root.unwrap_or(true) {
+        let options = config
+            .disable_root_opts
+            .as_deref()
+            .unwrap_or(DEFAULT_DISABLE_ROOT_OPTS)
+            .replace("$USER", default_user(config, &Distro::detect(root).await))
+            .replace("$DISABLE_USER", "root");
+        config
+            .ssh_authorized_keys
+            .iter()
+            .map(|key| format!("{} {}", options, key))
+            .collect()
+    } else {
+        config.ssh_authorized_keys.clone()
+    };
+
+    ssh_keys::configure_user_ssh_keys(runner, root, "root", &new_keys).await
+}
+
+/// The first configured user, which `$USER` in the options refers to
+///
+/// With only `default` listed that is the distribution's default user, or
+/// the top-level `user` that overrides it.
+fn default_user<'a>(config: &'a CloudConfig, distro: &Distro) -> &'a str {
+    let mut names = config.users.iter().map(UserConfig::name);
+    if let Some(name) = names.clone().find(|name| *name != "default") {
+        return name;
+    }
+    let listed = names.any(|name| name == "default");
+    match &config.user {
+        Some(user) if listed || config.users.is_empty() => match user.name() {
+            "" => distro.default_user(),
+            name => name,
+        },
+        None if listed => distro.default_user(),
+        _ => "NONE",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+    use tempfile::TempDir;
+
+    fn settings(yaml: &str) -> BTreeMap<String, serde_yaml::Value> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_render_drop_in() {
+        let rendered = render_drop_in(
+            Some(SshPwauth::Disabled),
+            Some(&settings(
+                "ClientAliveInterval: 120\nX11Forwarding: false\nAcceptEnv: [LANG, LC_ALL]\n",
+            )),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            rendered,
+            "# Written by cloud-init-rs\nPasswordAuthentication no\nAcceptEnv LANG\n\
+             AcceptEnv LC_ALL\nClientAliveInterval 120\nX11Forwarding no\n"
+        );
+
+        assert_eq!(
+            render_drop_in(Some(SshPwauth::Unchanged), None).unwrap(),
+            None
+        );
+        assert!(render_drop_in(None, Some(&settings("\"Match user\": x\n"))).is_err());
+        assert!(render_drop_in(None, Some(&settings("Banner: {a: b}\n"))).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_configure_sshd_validates_and_includes() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        std::fs::create_dir_all(root.path("/etc/ssh")).unwrap();
+        std::fs::write(root.path(SSHD_CONFIG), "PermitRootLogin no\n").unwrap();
+        let config = CloudConfig::from_yaml("ssh_pwauth: true\n").unwrap();
+
+        let runner = RecordingRunner::new();
+        configure_sshd(&runner, &root, &config).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.path(DROP_IN)).unwrap(),
+            "# Written by cloud-init-rs\nPasswordAuthentication yes\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.path(SSHD_CONFIG)).unwrap(),
+            "Include /etc/ssh/sshd_config.d/*.conf\nPermitRootLogin no\n"
+        );
+        // Not the running system: validated but not reloaded
+        assert_eq!(runner.commands(), vec!["sshd -t -f /etc/ssh/sshd_config"]);
+
+        // A second run leaves the include alone
+        configure_sshd(&runner, &root, &config).await.unwrap();
+        let main = std::fs::read_to_string(root.path(SSHD_CONFIG)).unwrap();
+        assert_eq!(main.matches("Include").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_drop_in_is_rolled_back() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        std::fs::create_dir_all(root.path("/etc/ssh")).unwrap();
+        std::fs::write(root.path(SSHD_CONFIG), "").unwrap();
+        let config = CloudConfig::from_yaml("ssh_config:\n  Bogus: 1\n").unwrap();
+
+        let runner =
+            RecordingRunner::new().with_response("sshd", CommandOutput::failure(255, "Bad"));
+        let result = configure_sshd(&runner, &root, &config).await;
+        assert!(matches!(result, Err(CloudInitError::InvalidData(_))));
+        assert!(!root.path(DROP_IN).exists());
+    }
+
+    #[tokio::test]
+    async fn test_root_keys_restricted_and_merged() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        std::fs::create_dir_all(root.path("/etc")).unwrap();
+        std::fs::create_dir_all(root.path("/root/.ssh")).unwrap();
+        std::fs::write(
+            root.path("/etc/passwd"),
+            "root:x:0:0:root:/root:/bin/bash\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.path("/root/.ssh/authorized_keys"),
+            "ssh-ed25519 AAAAold0 admin\nssh-ed25519 AAAAnew0 stale\n",
+        )
+        .unwrap();
+        let config = CloudConfig::from_yaml(
+            "users: [default, alice]\nssh_authorized_keys: [ssh-ed25519 AAAAnew0 me]\n",
+        )
+        .unwrap();
+
+        configure_root_keys(&RecordingRunner::new(), &root, &config)
+            .await
+            .unwrap();
+        let keys = std::fs::read_to_string(root.path("/root/.ssh/authorized_keys")).unwrap();
+        let lines: Vec<_> = keys.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "ssh-ed25519 AAAAold0 admin");
+        assert_eq!(lines[1], "# BEGIN cloud-init-rs managed keys");
+        assert!(lines[2].starts_with("no-port-forwarding,"));
+        assert!(lines[2].contains(r#"login as the user \"alice\" rather than the user \"root\""#));
+        assert!(lines[2].ends_with("exit 142\" ssh-ed25519 AAAAnew0 me"));
+        assert_eq!(lines[3], "# END cloud-init-rs managed keys");
+    }
+
+    #[test]
+    fn test_default_user_of_distro() {
+        let ubuntu = Distro::from_os_release("ID=ubuntu\n");
+        let user = |yaml: &str| {
+            let config = CloudConfig::from_yaml(yaml).unwrap();
+            default_user(&config, &ubuntu).to_string()
+        };
+        assert_eq!(user("users: [default, alice]\n"), "alice");
+        assert_eq!(user("users: [default]\n"), "ubuntu");
+        assert_eq!(user("{}\n"), "NONE");
+        assert_eq!(user("user: ops\n"), "ops");
+        assert_eq!(
+            user("user: {shell: /bin/zsh}\nusers: [default]\n"),
+            "ubuntu"
+        );
+        assert_eq!(user("user: ops\nusers: [alice]\n"), "alice");
+    }
+}
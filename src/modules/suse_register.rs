@@ -0,0 +1,201 @@
+//! SUSE registration module
+//!
+//! Registers a SLES system with the SUSE Customer Center, or an RMT server
+//! given as `url`, using `SUSEConnect` and the base product's registration
+//! code, then activates the listed extensions and modules. A system that
+//! already holds SCC credentials is not registered again; its products are
+//! still activated, which SUSEConnect treats as a no-op when they are.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! suse_register:
+//!   regcode: BASE-PRODUCT-KEY
+//!   email: ops@example.com
+//!   products:
+//!     - sle-module-containers/15.6/x86_64
+//!     - identifier: SLES-LTSS/15.6/x86_64
+//!       regcode: LTSS-KEY
+//! ```
+
+use crate::CloudInitError;
+use crate::config::{SuseProduct, SuseRegisterConfig};
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use tracing::{debug, info, warn};
+
+/// Written by SUSEConnect once the system is registered
+const SCC_CREDENTIALS: &str = "/etc/zypp/credentials.d/SCCcredentials";
+
+/// Register the system and activate products
+pub async fn configure_suse_register(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &SuseRegisterConfig,
+) -> Result<(), CloudInitError> {
+    if root.path(SCC_CREDENTIALS).exists() {
+        info!("suse_register: system is already registered");
+    } else {
+        let Some(regcode) = &config.regcode else {
+            return Err(CloudInitError::Module {
+                module: "suse_register".to_string(),
+                message: "suse_register requires the base product's regcode".to_string(),
+            });
+        };
+        info!("suse_register: registering the base product");
+        run_suseconnect(runner, &register_args(config, regcode)).await?;
+    }
+
+    for product in &config.products {
+        let (identifier, regcode) = match product {
+            SuseProduct::Identifier(identifier) => (identifier, None),
+            SuseProduct::WithRegcode {
+                identifier,
+                regcode,
+            } => (identifier, regcode.as_ref()),
+        };
+        info!("suse_register: activating {}", identifier);
+        let mut args = vec!["--product".to_string(), identifier.clone()];
+        if let Some(regcode) = regcode {
+            args.extend(["--regcode".to_string(), regcode.clone()]);
+        }
+        args.extend(server_args(config));
+        run_suseconnect(runner, &args).await?;
+    }
+    Ok(())
+}
+
+/// Arguments registering the base product
+fn register_args(config: &SuseRegisterConfig, regcode: &str) -> Vec<String> {
+    let mut args = vec!["--regcode".to_string(), regcode.to_string()];
+    if let Some(email) = &config.email {
+        args.extend(["--email".to_string(), email.clone()]);
+    }
+    args.extend(server_args(config));
+    args
+}
+
+/// `--url` for registrations against an RMT server
+fn server_args(config: &SuseRegisterConfig) -> Vec<String> {
+    config
+        .url
+        .iter()
+        .flat_map(|url| ["--url".to_string(), url.clone()])
+        .collect()
+}
+
+/// Execute `SUSEConnect` with the given arguments
+async fn run_suseconnect(runner: &dyn SystemRunner, args: &[String]) -> Result<(), CloudInitError> {
+    // Registration codes are secrets, so only the option names are logged
+    debug!(
+        "SUSEConnect {}",
+        args.iter()
+            .filter(|arg| arg.starts_with("--"))
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+
+    let output = runner
+        .run(&SystemCommand::new("SUSEConnect").args(args.iter().cloned()))
+        .await?;
+
+    if !output.is_success() {
+        // SUSEConnect reports errors on stdout
+        let detail = if output.stderr.trim().is_empty() {
+            output.stdout.trim().to_string()
+        } else {
+            output.stderr.trim().to_string()
+        };
+        warn!("SUSEConnect failed: {}", detail);
+        return Err(CloudInitError::Module {
+            module: "suse_register".to_string(),
+            message: format!("SUSEConnect failed: {}", detail),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+    use tempfile::TempDir;
+
+    fn config(yaml: &str) -> SuseRegisterConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_register_and_activate_products() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let runner = RecordingRunner::new();
+        let config = config(
+            r#"
+regcode: BASE-KEY
+email: ops@example.com
+url: https://rmt.example.com
+products:
+  - sle-module-containers/15.6/x86_64
+  - identifier: SLES-LTSS/15.6/x86_64
+    regcode: LTSS-KEY
+"#,
+        );
+        configure_suse_register(&runner, &root, &config)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "SUSEConnect --regcode BASE-KEY --email ops@example.com --url https://rmt.example.com",
+                "SUSEConnect --product sle-module-containers/15.6/x86_64 --url https://rmt.example.com",
+                "SUSEConnect --product SLES-LTSS/15.6/x86_64 --regcode LTSS-KEY --url https://rmt.example.com",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_registered_system_only_activates_products() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        std::fs::create_dir_all(root.path("/etc/zypp/credentials.d")).unwrap();
+        std::fs::write(root.path(SCC_CREDENTIALS), "username=SCC_x\n").unwrap();
+        let runner = RecordingRunner::new();
+        let config = config("products: [sle-module-public-cloud/15.6/x86_64]\n");
+        configure_suse_register(&runner, &root, &config)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec!["SUSEConnect --product sle-module-public-cloud/15.6/x86_64"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_regcode_required_and_failure_reported() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let runner = RecordingRunner::new();
+        let err = configure_suse_register(&runner, &root, &SuseRegisterConfig::default())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("regcode"));
+
+        let runner = RecordingRunner::new().with_response(
+            "SUSEConnect",
+            CommandOutput {
+                code: Some(67),
+                stdout: "Invalid registration code".to_string(),
+                ..Default::default()
+            },
+        );
+        let err = configure_suse_register(&runner, &root, &config("regcode: BAD\n"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Invalid registration code"));
+    }
+}
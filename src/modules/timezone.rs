@@ -1,56 +1,112 @@
 //! Timezone configuration module
+//!
+//! Implements the `timezone` cloud-config key. The zone is validated against
+//! the zoneinfo database, then applied with `timedatectl set-timezone` when
+//! available. Otherwise `/etc/localtime` is symlinked into the zoneinfo
+//! database and `/etc/timezone` is written for Debian-style systems.
+//!
+//! NixOS keeps the database in `/etc/zoneinfo` rather than `/usr/share`.
+//! On immutable roots a read-only `/etc` skips the files with a warning,
+//! see [`crate::immutable`].
 
 use crate::CloudInitError;
-use std::path::Path;
-use tokio::fs;
-use tracing::{debug, info};
+use crate::immutable::{self, ImmutableRoot};
+use crate::root::RootContext;
+use crate::runner::SystemCommand;
+use crate::service::ServiceManager;
+use std::path::{Component, Path, PathBuf};
+use tracing::{debug, info, warn};
+
+/// Locations of the zoneinfo database, relative to the root filesystem,
+/// in lookup order; NixOS only has the second
+const ZONEINFO_DIRS: &[&str] = &["usr/share/zoneinfo", "etc/zoneinfo"];
 
 /// Set the system timezone
-pub async fn set_timezone(timezone: &str) -> Result<(), CloudInitError> {
+///
+/// `timedatectl` only applies to a running systemd, so under an
+/// alternate root or OpenRC the files are written directly.
+pub async fn set_timezone(root: &RootContext, timezone: &str) -> Result<(), CloudInitError> {
+    let use_timedatectl = root.is_host() && ServiceManager::detect(root) == ServiceManager::Systemd;
+    apply_timezone(root, timezone, use_timedatectl).await
+}
+
+/// Apply a timezone beneath `root`, optionally trying `timedatectl` first
+async fn apply_timezone(
+    root: &RootContext,
+    timezone: &str,
+    use_timedatectl: bool,
+) -> Result<(), CloudInitError> {
     info!("Setting timezone to: {}", timezone);
 
-    // Validate timezone exists
-    let zoneinfo_path = format!("/usr/share/zoneinfo/{}", timezone);
-    if !Path::new(&zoneinfo_path).exists() {
-        return Err(CloudInitError::InvalidData(format!(
-            "Invalid timezone: {} (not found in /usr/share/zoneinfo)",
-            timezone
-        )));
-    }
+    let zonefile = validate_timezone(root.root(), timezone)?;
+    debug!("Found zone file: {}", zonefile.display());
 
     // Try timedatectl first (systemd systems)
-    if try_timedatectl(timezone).await? {
+    if use_timedatectl && try_timedatectl(root, timezone).await? {
         return Ok(());
     }
 
+    let immutable = ImmutableRoot::detect(root).await;
+
     // Fallback: symlink /etc/localtime
-    set_localtime_symlink(timezone).await?;
+    match set_localtime_symlink(root, &zonefile).await {
+        Err(e) if immutable::is_read_only_error(&e) => {
+            warn!("/etc/localtime is read-only; timezone not changed");
+            return Ok(());
+        }
+        result => result?,
+    }
 
     // Also write /etc/timezone for Debian-based systems
-    write_etc_timezone(timezone).await?;
+    write_etc_timezone(root, immutable, timezone).await?;
 
     Ok(())
 }
 
+/// Check that `timezone` names a zone file in the zoneinfo database
+///
+/// Returns the zone file's in-system path.
+fn validate_timezone(root: &Path, timezone: &str) -> Result<PathBuf, CloudInitError> {
+    let invalid = || {
+        CloudInitError::InvalidData(format!(
+            "Invalid timezone: {} (not found in /usr/share/zoneinfo)",
+            timezone
+        ))
+    };
+
+    // Only plain relative names like "Europe/Berlin" are allowed
+    let relative = Path::new(timezone);
+    if timezone.is_empty()
+        || !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(invalid());
+    }
+
+    ZONEINFO_DIRS
+        .iter()
+        .find(|dir| root.join(dir).join(relative).is_file())
+        .map(|dir| Path::new("/").join(dir).join(relative))
+        .ok_or_else(invalid)
+}
+
 /// Try to set timezone via timedatectl
-async fn try_timedatectl(timezone: &str) -> Result<bool, CloudInitError> {
+async fn try_timedatectl(root: &RootContext, timezone: &str) -> Result<bool, CloudInitError> {
     debug!("Attempting to set timezone via timedatectl");
 
-    let output = tokio::process::Command::new("timedatectl")
-        .args(["set-timezone", timezone])
-        .output()
+    let output = root
+        .runner()
+        .run(&SystemCommand::new("timedatectl").args(["set-timezone", timezone]))
         .await;
 
     match output {
-        Ok(output) if output.status.success() => {
+        Ok(output) if output.is_success() => {
             info!("Timezone set via timedatectl");
             Ok(true)
         }
         Ok(output) => {
-            debug!(
-                "timedatectl failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
+            debug!("timedatectl failed: {}", output.stderr);
             Ok(false)
         }
         Err(e) => {
@@ -60,51 +116,62 @@ async fn try_timedatectl(timezone: &str) -> Result<bool, CloudInitError> {
     }
 }
 
-/// Set /etc/localtime symlink
-async fn set_localtime_symlink(timezone: &str) -> Result<(), CloudInitError> {
+/// Set /etc/localtime symlink to the in-system `zoneinfo` file
+async fn set_localtime_symlink(root: &RootContext, zoneinfo: &Path) -> Result<(), CloudInitError> {
     debug!("Setting /etc/localtime symlink");
 
-    let localtime = Path::new("/etc/localtime");
-    let zoneinfo = format!("/usr/share/zoneinfo/{}", timezone);
+    let localtime = root.path("/etc/localtime");
+    // The link target is always the absolute in-system path, so it stays
+    // valid when `root` is a mounted image rather than the running system
 
-    // Remove existing localtime if it exists
-    if localtime.exists() {
-        fs::remove_file(localtime)
-            .await
-            .map_err(CloudInitError::Io)?;
+    if let Some(parent) = localtime.parent() {
+        root.create_dir_all(parent).await?;
     }
 
-    // Create symlink
-    #[cfg(unix)]
-    {
-        tokio::fs::symlink(&zoneinfo, localtime)
-            .await
-            .map_err(CloudInitError::Io)?;
+    // Remove existing localtime if it exists (including dangling symlinks)
+    if localtime.exists() || localtime.is_symlink() {
+        root.remove_file(&localtime).await?;
     }
 
-    info!("Created /etc/localtime symlink to {}", zoneinfo);
+    root.symlink(zoneinfo, &localtime).await?;
+
+    info!("Created /etc/localtime symlink to {}", zoneinfo.display());
     Ok(())
 }
 
 /// Write /etc/timezone file (Debian/Ubuntu)
-async fn write_etc_timezone(timezone: &str) -> Result<(), CloudInitError> {
-    let etc_timezone = Path::new("/etc/timezone");
+async fn write_etc_timezone(
+    root: &RootContext,
+    immutable: Option<ImmutableRoot>,
+    timezone: &str,
+) -> Result<(), CloudInitError> {
+    let etc_timezone = root.path("/etc/timezone");
 
-    fs::write(etc_timezone, format!("{}\n", timezone))
-        .await
-        .map_err(CloudInitError::Io)?;
-
-    debug!("Wrote /etc/timezone");
+    if immutable::write_etc_file(root, immutable, &etc_timezone, format!("{}\n", timezone)).await? {
+        debug!("Wrote /etc/timezone");
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tempfile::TempDir;
+
+    /// Build a fake root containing a minimal zoneinfo database
+    fn fake_root() -> TempDir {
+        let root = TempDir::new().unwrap();
+        let zoneinfo = root.path().join(ZONEINFO_DIRS[0]);
+        std::fs::create_dir_all(zoneinfo.join("America")).unwrap();
+        std::fs::write(zoneinfo.join("UTC"), b"TZif").unwrap();
+        std::fs::write(zoneinfo.join("America/New_York"), b"TZif").unwrap();
+        std::fs::create_dir_all(root.path().join("etc")).unwrap();
+        root
+    }
 
     #[tokio::test]
     async fn test_set_timezone_invalid() {
-        let result = set_timezone("Invalid/Not_A_Timezone").await;
+        let result = set_timezone(&RootContext::host(), "Invalid/Not_A_Timezone").await;
         assert!(result.is_err());
         let err = result.unwrap_err().to_string();
         assert!(err.contains("Invalid timezone"));
@@ -113,7 +180,7 @@ mod tests {
     #[tokio::test]
     async fn test_set_timezone_valid_utc() {
         // UTC exists on most systems in /usr/share/zoneinfo
-        let result = set_timezone("UTC").await;
+        let result = set_timezone(&RootContext::host(), "UTC").await;
         // May fail due to permissions but should not return InvalidData
         if let Err(e) = &result {
             let msg = e.to_string();
@@ -127,13 +194,13 @@ mod tests {
     #[tokio::test]
     async fn test_try_timedatectl_nonexistent_tz() {
         // timedatectl may not exist on macOS
-        let result = try_timedatectl("US/Eastern").await;
+        let result = try_timedatectl(&RootContext::host(), "US/Eastern").await;
         assert!(result.is_ok());
     }
 
     #[tokio::test]
     async fn test_set_timezone_america_new_york() {
-        let result = set_timezone("America/New_York").await;
+        let result = set_timezone(&RootContext::host(), "America/New_York").await;
         if let Err(e) = &result {
             let msg = e.to_string();
             assert!(!msg.contains("Invalid timezone"));
@@ -142,14 +209,83 @@ mod tests {
 
     #[tokio::test]
     async fn test_set_timezone_empty_string() {
-        let result = set_timezone("").await;
+        let result = set_timezone(&RootContext::host(), "").await;
         assert!(result.is_err());
     }
 
     #[tokio::test]
     async fn test_set_timezone_path_traversal() {
-        let result = set_timezone("../../etc/passwd").await;
+        let result = set_timezone(&RootContext::host(), "../../etc/passwd").await;
         // This should fail - the file won't exist in zoneinfo
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_validate_timezone_fake_root() {
+        let root = fake_root();
+        assert!(validate_timezone(root.path(), "UTC").is_ok());
+        assert!(validate_timezone(root.path(), "America/New_York").is_ok());
+        assert!(validate_timezone(root.path(), "Mars/Olympus").is_err());
+    }
+
+    #[test]
+    fn test_validate_timezone_rejects_directories_and_absolute_paths() {
+        let root = fake_root();
+        assert!(validate_timezone(root.path(), "America").is_err());
+        assert!(validate_timezone(root.path(), "/UTC").is_err());
+        assert!(validate_timezone(root.path(), "America/../UTC").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_apply_timezone_fake_root_writes_files() {
+        let root = fake_root();
+        apply_timezone(&RootContext::new(root.path()), "America/New_York", false)
+            .await
+            .unwrap();
+
+        let link = std::fs::read_link(root.path().join("etc/localtime")).unwrap();
+        assert_eq!(link, Path::new("/usr/share/zoneinfo/America/New_York"));
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("etc/timezone")).unwrap(),
+            "America/New_York\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apply_timezone_fake_root_replaces_existing_link() {
+        let root = fake_root();
+        apply_timezone(&RootContext::new(root.path()), "UTC", false)
+            .await
+            .unwrap();
+        apply_timezone(&RootContext::new(root.path()), "America/New_York", false)
+            .await
+            .unwrap();
+
+        let link = std::fs::read_link(root.path().join("etc/localtime")).unwrap();
+        assert_eq!(link, Path::new("/usr/share/zoneinfo/America/New_York"));
+    }
+
+    #[tokio::test]
+    async fn test_apply_timezone_nixos_zoneinfo() {
+        let root = TempDir::new().unwrap();
+        let zoneinfo = root.path().join(ZONEINFO_DIRS[1]);
+        std::fs::create_dir_all(zoneinfo.join("Europe")).unwrap();
+        std::fs::write(zoneinfo.join("Europe/Berlin"), b"TZif").unwrap();
+        std::fs::write(root.path().join("etc/NIXOS"), "").unwrap();
+
+        apply_timezone(&RootContext::new(root.path()), "Europe/Berlin", false)
+            .await
+            .unwrap();
+
+        let link = std::fs::read_link(root.path().join("etc/localtime")).unwrap();
+        assert_eq!(link, Path::new("/etc/zoneinfo/Europe/Berlin"));
+    }
+
+    #[tokio::test]
+    async fn test_apply_timezone_fake_root_invalid_leaves_files() {
+        let root = fake_root();
+        let result = apply_timezone(&RootContext::new(root.path()), "Nowhere/City", false).await;
+        assert!(matches!(result, Err(CloudInitError::InvalidData(_))));
+        assert!(!root.path().join("etc/timezone").exists());
+    }
 }
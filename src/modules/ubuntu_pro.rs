@@ -0,0 +1,188 @@
+//! Ubuntu Pro module
+//!
+//! Implements the `ubuntu_pro` cloud-config key (formerly
+//! `ubuntu_advantage`): applies `pro config` settings, attaches the
+//! machine to a Pro subscription with the given token and enables the
+//! requested services.
+//!
+//! Without `enable` the services the contract enables by default are
+//! used. A machine that is already attached is not attached again, so
+//! the module can be re-run on an existing instance.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! ubuntu_pro:
+//!   token: C1NWcZTHLteJXGVMM6YhvHDpGrhyy7
+//!   enable: [esm-infra, livepatch]
+//!   config:
+//!     http_proxy: http://proxy.example.com:3128
+//! ```
+
+use crate::CloudInitError;
+use crate::config::UbuntuProConfig;
+use crate::modules::{packages, scalar_string};
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use tracing::{debug, info};
+
+/// Configure the Pro client and attach the machine
+pub async fn configure_ubuntu_pro(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &UbuntuProConfig,
+) -> Result<(), CloudInitError> {
+    if !packages::command_exists(runner, "pro").await {
+        packages::install_package(runner, root, "ubuntu-advantage-tools").await?;
+    }
+    // A token attached in an image would be shared by every machine
+    // booted from it
+    if !root.is_host() {
+        debug!("Not attaching an alternate root to Ubuntu Pro");
+        return Ok(());
+    }
+
+    for (key, value) in &config.config {
+        let value = scalar_string(value).ok_or_else(|| {
+            CloudInitError::InvalidData(format!("Invalid ubuntu_pro config value for {}", key))
+        })?;
+        run_pro(
+            runner,
+            SystemCommand::new("pro").args(["config", "set", &format!("{}={}", key, value)]),
+        )
+        .await?;
+    }
+
+    let Some(token) = &config.token else {
+        if config.enable.is_some() || !config.enable_beta.is_empty() {
+            return Err(CloudInitError::InvalidData(
+                "ubuntu_pro needs a token to enable services".to_string(),
+            ));
+        }
+        return Ok(());
+    };
+
+    if is_attached(runner).await {
+        info!("Already attached to Ubuntu Pro");
+    } else {
+        let mut command = SystemCommand::new("pro").arg("attach");
+        if config.enable.is_some() {
+            command = command.arg("--no-auto-enable");
+        }
+        // The error must not echo the token
+        let output = runner.run(&command.arg(token)).await?;
+        if !output.is_success() {
+            return Err(CloudInitError::Command(format!(
+                "pro attach failed: {}",
+                output.stderr.trim()
+            )));
+        }
+        info!("Attached to Ubuntu Pro");
+    }
+
+    if let Some(services) = config.enable.as_ref().filter(|s| !s.is_empty()) {
+        run_pro(
+            runner,
+            SystemCommand::new("pro")
+                .args(["enable", "--assume-yes"])
+                .args(services),
+        )
+        .await?;
+    }
+    if !config.enable_beta.is_empty() {
+        run_pro(
+            runner,
+            SystemCommand::new("pro")
+                .args(["enable", "--assume-yes", "--beta"])
+                .args(&config.enable_beta),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Whether `pro status` reports the machine as attached
+async fn is_attached(runner: &dyn SystemRunner) -> bool {
+    let command = SystemCommand::new("pro").args(["status", "--format", "json"]);
+    match runner.run(&command).await {
+        Ok(output) if output.is_success() => {
+            serde_json::from_str::<serde_json::Value>(&output.stdout)
+                .ok()
+                .and_then(|status| status.get("attached").and_then(|a| a.as_bool()))
+                .unwrap_or(false)
+        }
+        _ => false,
+    }
+}
+
+async fn run_pro(runner: &dyn SystemRunner, command: SystemCommand) -> Result<(), CloudInitError> {
+    let output = runner.run(&command).await?;
+    if !output.is_success() {
+        return Err(CloudInitError::Command(format!(
+            "{} failed: {}",
+            command,
+            output.stderr.trim()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+
+    fn config(yaml: &str) -> UbuntuProConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_attach_and_enable() {
+        let runner = RecordingRunner::new();
+        let config = config(
+            "{token: TOKEN, enable: [esm-infra, livepatch], enable_beta: [realtime-kernel], config: {http_proxy: 'http://proxy:3128'}}",
+        );
+        configure_ubuntu_pro(&runner, &RootContext::host(), &config)
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "which pro",
+                "pro config set http_proxy=http://proxy:3128",
+                "pro status --format json",
+                "pro attach --no-auto-enable TOKEN",
+                "pro enable --assume-yes esm-infra livepatch",
+                "pro enable --assume-yes --beta realtime-kernel",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_already_attached() {
+        let runner = RecordingRunner::new().with_response(
+            "pro status --format json",
+            CommandOutput::success(r#"{"attached": true}"#),
+        );
+        configure_ubuntu_pro(&runner, &RootContext::host(), &config("token: TOKEN"))
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec!["which pro", "pro status --format json"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_attach_hides_token() {
+        let runner = RecordingRunner::new().with_response(
+            "pro attach SECRET",
+            CommandOutput::failure(1, "invalid token"),
+        );
+        let err = configure_ubuntu_pro(&runner, &RootContext::host(), &config("token: SECRET"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid token"));
+        assert!(!err.to_string().contains("SECRET"));
+    }
+}
@@ -1,182 +1,699 @@
 //! User creation and configuration module
+//!
+//! Accounts are managed with the shadow utilities (`useradd`, `usermod`,
+//! `chpasswd`); FreeBSD uses `pw`, see `pw.rs`.
+//!
+//! Every run reconciles existing accounts too. Their shell, gecos, primary
+//! group, expiry date and inactivity period are updated with `usermod`
+//! (uid and home directory are left alone). Listed supplementary groups
+//! are added with `usermod --append`; the groups cloud-init added are
+//! recorded under the instance directory, and one of those dropped from
+//! the config is left on the next run. Memberships cloud-init did not add,
+//! such as ones the image grants, are never removed.
+//!
+//! `remove: true` deletes an account with `userdel`, keeping its home
+//! directory, and `ssh_authorized_keys_exclusive: true` makes the listed
+//! keys the only ones in its authorized_keys.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! user:
+//!   name: ops
+//! users:
+//!   - default
+//!   - name: guest
+//!     groups: [audit]
+//!     create_groups: false
+//!     ssh_redirect_user: true
+//!     ssh_authorized_keys_exclusive: true
+//!     expire: true
+//!   - name: olduser
+//!     remove: true
+//! ```
 
+use super::groups::{self, GROUP_FILE};
+use super::ssh_keys;
+use super::sshd_config::DEFAULT_DISABLE_ROOT_OPTS;
 use crate::CloudInitError;
-use crate::config::{UserConfig, UserFullConfig};
-use std::path::Path;
+use crate::config::{CloudConfig, GroupConfig, UserConfig, UserFullConfig};
+use crate::distro::Distro;
+#[cfg(feature = "freebsd")]
+use crate::distro::DistroFamily;
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use std::collections::{BTreeMap, BTreeSet};
 use tokio::fs;
 use tracing::{debug, info, warn};
 
+/// Drop-in directory for per-user sudo rules
+const SUDOERS_DIR: &str = "/etc/sudoers.d";
+
 /// Create users from cloud-config
-pub async fn create_users(users: &[UserConfig]) -> Result<(), CloudInitError> {
+///
+/// A top-level `user` overrides the `default` entry and, with no `users`
+/// list, implies `users: [default]`. `public_keys` are the platform's SSH
+/// keys, installed for entries with `ssh_redirect_user`.
+pub async fn create_users(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &CloudConfig,
+    public_keys: &[String],
+) -> Result<(), CloudInitError> {
+    let implied = [UserConfig::Name("default".to_string())];
+    let users: &[UserConfig] = if config.users.is_empty() && config.user.is_some() {
+        &implied
+    } else {
+        &config.users
+    };
+    if users.is_empty() {
+        return Ok(());
+    }
+
+    let distro = Distro::detect(root).await;
+    let accounts = Accounts::new(&distro, config, users, public_keys);
+
+    #[cfg(feature = "freebsd")]
+    if distro.family() == DistroFamily::FreeBsd {
+        return super::pw::create_users(runner, root, &distro, users, &accounts).await;
+    }
+
     for user in users {
         match user {
-            UserConfig::Name(name) => {
-                // Handle special "default" user
-                if name == "default" {
-                    debug!("Skipping 'default' user (would use distro default)");
-                    continue;
+            UserConfig::Name(name) if name != "default" => {
+                create_user_simple(runner, name).await?;
+            }
+            user => {
+                let config = accounts.resolve(user);
+                if config.remove == Some(true) {
+                    remove_user(runner, root, &config.name).await?;
+                } else {
+                    create_user_full(runner, root, &accounts, &config).await?;
                 }
-                create_user_simple(name).await?;
             }
-            UserConfig::Full(config) => {
-                create_user_full(config).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Settings every `users` entry is applied against
+pub(super) struct Accounts<'a> {
+    /// The default user, with the top-level `user` override applied
+    default: UserFullConfig,
+    /// Whether `users` includes the default user, which redirected keys name
+    has_default: bool,
+    /// The platform's SSH keys
+    public_keys: &'a [String],
+    /// Top-level `groups`, whose listed members keep those memberships
+    groups: &'a [GroupConfig],
+}
+
+impl<'a> Accounts<'a> {
+    pub(super) fn new(
+        distro: &Distro,
+        config: &'a CloudConfig,
+        users: &[UserConfig],
+        public_keys: &'a [String],
+    ) -> Self {
+        Self {
+            default: default_user(distro, config.user.as_ref()),
+            has_default: users.iter().any(|user| user.name() == "default"),
+            public_keys,
+            groups: &config.groups,
+        }
+    }
+
+    /// The full settings for a `users` entry
+    pub(super) fn resolve(&self, user: &UserConfig) -> UserFullConfig {
+        match user {
+            UserConfig::Name(name) if name == "default" => self.default.clone(),
+            UserConfig::Name(name) => UserFullConfig {
+                name: name.clone(),
+                ..Default::default()
+            },
+            UserConfig::Full(config) => config.as_ref().clone(),
+        }
+    }
+
+    /// The entry's authorized keys, plus the platform's keys redirecting to
+    /// the default user when `ssh_redirect_user` is set
+    pub(super) fn authorized_keys(&self, config: &UserFullConfig) -> Vec<String> {
+        let mut keys = config.ssh_authorized_keys.clone();
+        if config.ssh_redirect_user != Some(true) {
+            return keys;
+        }
+        if !self.has_default || config.name == self.default.name {
+            warn!(
+                "ssh_redirect_user for {} needs another, default user in users; ignored",
+                config.name
+            );
+            return keys;
+        }
+        let options = DEFAULT_DISABLE_ROOT_OPTS
+            .replace("$USER", &self.default.name)
+            .replace("$DISABLE_USER", &config.name);
+        keys.extend(
+            self.public_keys
+                .iter()
+                .map(|key| format!("{options} {key}")),
+        );
+        keys
+    }
+
+    /// Install the entry's authorized keys
+    ///
+    /// With `ssh_authorized_keys_exclusive` they replace every key the user
+    /// had, even when there are none.
+    pub(super) async fn install_authorized_keys(
+        &self,
+        runner: &dyn SystemRunner,
+        root: &RootContext,
+        config: &UserFullConfig,
+    ) -> Result<(), CloudInitError> {
+        let keys = self.authorized_keys(config);
+        if config.ssh_authorized_keys_exclusive == Some(true) {
+            ssh_keys::replace_user_ssh_keys(runner, root, &config.name, &keys).await
+        } else {
+            ssh_keys::configure_user_ssh_keys(runner, root, &config.name, &keys).await
+        }
+    }
+
+    /// Supplementary group changes for the user
+    ///
+    /// Missing groups are created unless `create_groups` is false, in which
+    /// case they are left out. Only groups recorded in
+    /// [`added_groups`](crate::state::CloudPaths::added_groups) are
+    /// removed, and not while top-level `groups` grants the membership.
+    pub(super) async fn supplementary_groups(
+        &self,
+        runner: &dyn SystemRunner,
+        root: &RootContext,
+        config: &UserFullConfig,
+    ) -> Result<GroupChange, CloudInitError> {
+        let group_file = root.path(GROUP_FILE);
+        let content = fs::read_to_string(&group_file).await.unwrap_or_default();
+        let mut wanted: Vec<String> = Vec::new();
+        for group in &config.groups {
+            if wanted.contains(group) {
+                continue;
+            }
+            if groups::group_members(&content, group).is_none() {
+                if config.create_groups == Some(false) {
+                    warn!(
+                        "Group {} does not exist and create_groups is false; not adding {}",
+                        group, config.name
+                    );
+                    continue;
+                }
+                groups::create_group(runner, &group_file, group, None, false).await?;
             }
+            wanted.push(group.clone());
         }
+        let granted = granted_groups(self.groups, &config.name);
+        let current = member_of(&content, &config.name);
+        let recorded = read_added_groups(root)
+            .await
+            .remove(&config.name)
+            .unwrap_or_default();
+
+        let add: Vec<String> = wanted
+            .iter()
+            .filter(|group| !current.contains(group.as_str()))
+            .cloned()
+            .collect();
+        let remove: Vec<String> = recorded
+            .iter()
+            .filter(|group| {
+                current.contains(group.as_str())
+                    && !wanted.contains(group)
+                    && !granted.contains(group)
+            })
+            .cloned()
+            .collect();
+        let mut groups: Vec<String> = current
+            .iter()
+            .filter(|group| !remove.iter().any(|g| g == *group))
+            .map(|group| group.to_string())
+            .collect();
+        groups.extend(add.iter().cloned());
+        let added = recorded
+            .iter()
+            .filter(|group| wanted.contains(group))
+            .chain(&add)
+            .cloned()
+            .collect();
+
+        let change = GroupChange {
+            add,
+            remove,
+            groups,
+            added,
+        };
+        if change.is_empty() {
+            debug!("User {} already in groups {:?}", config.name, wanted);
+        }
+        Ok(change)
+    }
+}
+
+/// How a user's supplementary groups change
+#[derive(Debug, Default, PartialEq)]
+pub(super) struct GroupChange {
+    /// Groups to add the user to
+    pub(super) add: Vec<String>,
+    /// Groups cloud-init added the user to before that are no longer listed
+    pub(super) remove: Vec<String>,
+    /// The user's supplementary groups once the change is made
+    pub(super) groups: Vec<String>,
+    /// The groups cloud-init has added the user to, to record afterwards
+    pub(super) added: BTreeSet<String>,
+}
+
+impl GroupChange {
+    /// Whether the membership already matches
+    pub(super) fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.remove.is_empty()
+    }
+}
+
+/// Groups cloud-init added each user to, by user name
+async fn read_added_groups(root: &RootContext) -> BTreeMap<String, BTreeSet<String>> {
+    let path = root.cloud_paths().added_groups();
+    let Ok(content) = fs::read_to_string(&path).await else {
+        return BTreeMap::new();
+    };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        warn!("Ignoring unreadable {}: {}", path.display(), e);
+        BTreeMap::new()
+    })
+}
+
+/// Record `added` as the groups cloud-init added `user` to
+///
+/// An empty set forgets the user. Nothing is recorded without a current
+/// instance directory.
+pub(super) async fn record_added_groups(
+    root: &RootContext,
+    user: &str,
+    added: BTreeSet<String>,
+) -> Result<(), CloudInitError> {
+    let paths = root.cloud_paths();
+    if !paths.instance_link().is_dir() {
+        debug!(
+            "No instance directory; not recording groups added to {}",
+            user
+        );
+        return Ok(());
+    }
+    let mut record = read_added_groups(root).await;
+    let changed = if added.is_empty() {
+        record.remove(user).is_some()
+    } else {
+        record.insert(user.to_string(), added.clone()) != Some(added)
+    };
+    if changed {
+        let content = serde_json::to_string_pretty(&record)?;
+        root.write_file(&paths.added_groups(), &content).await?;
     }
     Ok(())
 }
 
-async fn create_user_simple(name: &str) -> Result<(), CloudInitError> {
+/// Forget a deleted user: its sudo rule and recorded groups
+pub(super) async fn forget_user(
+    root: &RootContext,
+    sudoers_dir: &str,
+    username: &str,
+) -> Result<(), CloudInitError> {
+    let sudoers_file = root
+        .path(sudoers_dir)
+        .join(format!("90-cloud-init-{}", username));
+    if sudoers_file.exists() {
+        root.remove_file(&sudoers_file).await?;
+    }
+    record_added_groups(root, username, BTreeSet::new()).await
+}
+
+/// The distribution's default user, as `users: [default]` creates it
+///
+/// A top-level `user` renames it or, as a full entry, replaces any setting
+/// it gives.
+pub(super) fn default_user(distro: &Distro, user: Option<&UserConfig>) -> UserFullConfig {
+    debug!(
+        "Default user for {:?}: {}",
+        distro.id,
+        distro.default_user()
+    );
+    let default = UserFullConfig {
+        name: distro.default_user().to_string(),
+        groups: vec![distro.sudo_group().to_string()],
+        shell: Some(distro.default_shell().to_string()),
+        sudo: Some("ALL=(ALL) NOPASSWD:ALL".to_string()),
+        lock_passwd: Some(true),
+        ..Default::default()
+    };
+    match user {
+        None => default,
+        Some(UserConfig::Name(name)) => UserFullConfig {
+            name: name.clone(),
+            ..default
+        },
+        Some(UserConfig::Full(user)) => {
+            let user = user.as_ref().clone();
+            UserFullConfig {
+                name: if user.name.is_empty() {
+                    default.name
+                } else {
+                    user.name
+                },
+                groups: if user.groups.is_empty() {
+                    default.groups
+                } else {
+                    user.groups
+                },
+                shell: user.shell.or(default.shell),
+                sudo: user.sudo.or(default.sudo),
+                lock_passwd: user.lock_passwd.or(default.lock_passwd),
+                ..user
+            }
+        }
+    }
+}
+
+/// Name of the default user, if the config creates it
+pub fn configured_default_user(config: &CloudConfig, distro: &Distro) -> Option<String> {
+    let listed = config.users.iter().any(|user| user.name() == "default");
+    (listed || (config.users.is_empty() && config.user.is_some()))
+        .then(|| default_user(distro, config.user.as_ref()).name)
+}
+
+/// Groups that top-level `groups` entries list `user` as a member of
+fn granted_groups(groups: &[GroupConfig], user: &str) -> Vec<String> {
+    let mut granted: Vec<String> = Vec::new();
+    for group in groups {
+        match group {
+            GroupConfig::Name(_) => {}
+            GroupConfig::WithMembers { name, members, .. } => {
+                if members.iter().any(|m| m == user) {
+                    granted.push(name.clone());
+                }
+            }
+            GroupConfig::Mapping(map) => {
+                granted.extend(
+                    map.iter()
+                        .filter(|(_, members)| members.iter().any(|m| m == user))
+                        .map(|(name, _)| name.clone()),
+                );
+            }
+        }
+    }
+    granted.sort();
+    granted
+}
+
+/// Groups whose `/etc/group` member list includes `user`
+fn member_of<'a>(content: &'a str, user: &str) -> BTreeSet<&'a str> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            (fields.len() >= 4 && fields[3].split(',').any(|m| m == user)).then_some(fields[0])
+        })
+        .collect()
+}
+
+async fn create_user_simple(runner: &dyn SystemRunner, name: &str) -> Result<(), CloudInitError> {
     info!("Creating user: {}", name);
 
-    let output = tokio::process::Command::new("useradd")
-        .args(["--create-home", name])
-        .output()
-        .await
-        .map_err(|e| CloudInitError::Command(e.to_string()))?;
+    let output = runner
+        .run(&SystemCommand::new("useradd").args(["--create-home", name]))
+        .await?;
 
     // Exit code 9 means user already exists, which is fine
-    if !output.status.success() && output.status.code() != Some(9) {
-        let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.is_success() && output.code != Some(9) {
         return Err(CloudInitError::UserGroup(format!(
             "Failed to create user {}: {}",
-            name, stderr
+            name, output.stderr
         )));
     }
 
     Ok(())
 }
 
-async fn create_user_full(config: &UserFullConfig) -> Result<(), CloudInitError> {
+async fn create_user_full(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    accounts: &Accounts<'_>,
+    config: &UserFullConfig,
+) -> Result<(), CloudInitError> {
     info!("Creating user with full config: {}", config.name);
 
-    let mut cmd = tokio::process::Command::new("useradd");
-    cmd.arg("--create-home");
+    let output = runner
+        .run(&SystemCommand::new("useradd").args(build_useradd_args(config)))
+        .await?;
 
-    if let Some(shell) = &config.shell {
-        cmd.args(["--shell", shell]);
+    // Exit code 9 means user already exists; bring it up to date
+    if output.code == Some(9) {
+        update_user(runner, config).await?;
+    } else if !output.is_success() {
+        return Err(CloudInitError::UserGroup(format!(
+            "Failed to create user {}: {}",
+            config.name, output.stderr
+        )));
     }
 
-    if let Some(homedir) = &config.homedir {
-        cmd.args(["--home-dir", homedir]);
+    // Add listed groups, dropping ones cloud-init added that no longer are
+    let change = accounts.supplementary_groups(runner, root, config).await?;
+    if !change.add.is_empty() {
+        add_user_groups(runner, &config.name, &change.add).await?;
+    }
+    for group in &change.remove {
+        remove_user_group(runner, &config.name, group).await?;
     }
+    record_added_groups(root, &config.name, change.added).await?;
 
-    if let Some(gecos) = &config.gecos {
-        cmd.args(["--comment", gecos]);
+    // Set password if provided
+    if let Some(passwd) = resolve_password_hash(config)? {
+        set_user_password(runner, &config.name, &passwd).await?;
     }
 
-    if let Some(uid) = config.uid {
-        cmd.args(["--uid", &uid.to_string()]);
+    // Lock password if requested
+    if config.lock_passwd == Some(true) {
+        lock_user_password(runner, &config.name).await?;
     }
 
-    if let Some(primary_group) = &config.primary_group {
-        cmd.args(["--gid", primary_group]);
+    // Force a password change at the next login
+    if config.expire == Some(true) {
+        expire_user_password(runner, &config.name).await?;
     }
 
-    if config.system == Some(true) {
-        cmd.arg("--system");
+    // Configure sudo access
+    if let Some(sudo) = &config.sudo {
+        configure_sudo(runner, root, SUDOERS_DIR, &config.name, sudo).await?;
     }
 
-    cmd.arg(&config.name);
+    // Configure SSH keys
+    accounts
+        .install_authorized_keys(runner, root, config)
+        .await?;
 
-    let output = cmd
-        .output()
-        .await
-        .map_err(|e| CloudInitError::Command(e.to_string()))?;
+    Ok(())
+}
 
-    // Exit code 9 means user already exists
-    if !output.status.success() && output.status.code() != Some(9) {
-        let stderr = String::from_utf8_lossy(&output.stderr);
+/// Apply the settings `useradd` would have to an existing user
+async fn update_user(
+    runner: &dyn SystemRunner,
+    config: &UserFullConfig,
+) -> Result<(), CloudInitError> {
+    let args = build_usermod_args(config);
+    if args.len() == 1 {
+        return Ok(());
+    }
+    info!("Updating existing user: {}", config.name);
+    let output = runner
+        .run(&SystemCommand::new("usermod").args(args))
+        .await?;
+    if !output.is_success() {
         return Err(CloudInitError::UserGroup(format!(
-            "Failed to create user {}: {}",
-            config.name, stderr
+            "Failed to update user {}: {}",
+            config.name, output.stderr
         )));
     }
+    Ok(())
+}
 
-    // Add to supplementary groups
-    if !config.groups.is_empty() {
-        add_user_to_groups(&config.name, &config.groups).await?;
+/// Delete a user, keeping its home directory
+async fn remove_user(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    name: &str,
+) -> Result<(), CloudInitError> {
+    info!("Removing user: {}", name);
+    let output = runner.run(&SystemCommand::new("userdel").arg(name)).await?;
+
+    // Exit code 6 means the user does not exist, which is fine
+    if !output.is_success() && output.code != Some(6) {
+        return Err(CloudInitError::UserGroup(format!(
+            "Failed to remove user {}: {}",
+            name, output.stderr
+        )));
     }
+    forget_user(root, SUDOERS_DIR, name).await
+}
 
-    // Set password if provided
-    if let Some(passwd) = &config.passwd {
-        set_user_password(&config.name, passwd).await?;
+/// Build the `useradd` argument list for a full user config
+fn build_useradd_args(config: &UserFullConfig) -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+
+    if config.no_create_home == Some(true) {
+        args.push("--no-create-home".to_string());
+    } else {
+        args.push("--create-home".to_string());
     }
 
-    // Lock password if requested
-    if config.lock_passwd == Some(true) {
-        lock_user_password(&config.name).await?;
+    if config.no_user_group == Some(true) {
+        args.push("--no-user-group".to_string());
     }
 
-    // Configure sudo access
-    if let Some(sudo) = &config.sudo {
-        configure_sudo(&config.name, sudo).await?;
+    if let Some(shell) = &config.shell {
+        args.extend(["--shell".to_string(), shell.clone()]);
     }
 
-    // Configure SSH keys
-    if !config.ssh_authorized_keys.is_empty() {
-        crate::modules::ssh_keys::configure_user_ssh_keys(
-            &config.name,
-            &config.ssh_authorized_keys,
-        )
-        .await?;
+    if let Some(homedir) = &config.homedir {
+        args.extend(["--home-dir".to_string(), homedir.clone()]);
     }
 
-    Ok(())
+    if let Some(gecos) = &config.gecos {
+        args.extend(["--comment".to_string(), gecos.clone()]);
+    }
+
+    if let Some(uid) = config.uid {
+        args.extend(["--uid".to_string(), uid.to_string()]);
+    }
+
+    if let Some(primary_group) = &config.primary_group {
+        args.extend(["--gid".to_string(), primary_group.clone()]);
+    }
+
+    if let Some(expiredate) = &config.expiredate {
+        args.extend(["--expiredate".to_string(), expiredate.clone()]);
+    }
+
+    if let Some(inactive) = &config.inactive {
+        args.extend(["--inactive".to_string(), inactive.clone()]);
+    }
+
+    if config.system == Some(true) {
+        args.push("--system".to_string());
+    }
+
+    args.push(config.name.clone());
+    args
+}
+
+/// Build the `usermod` argument list updating an existing user
+///
+/// Only the name when there is nothing to change.
+fn build_usermod_args(config: &UserFullConfig) -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+    let settings = [
+        ("--shell", &config.shell),
+        ("--comment", &config.gecos),
+        ("--gid", &config.primary_group),
+        ("--expiredate", &config.expiredate),
+        ("--inactive", &config.inactive),
+    ];
+    for (flag, value) in settings {
+        if let Some(value) = value {
+            args.extend([flag.to_string(), value.clone()]);
+        }
+    }
+    args.push(config.name.clone());
+    args
+}
+
+/// Determine the crypt(3) hash to apply for a user, if any.
+///
+/// Mirrors Python cloud-init precedence: `hashed_passwd` wins over
+/// `plain_text_passwd`, which wins over the legacy `passwd` key.
+pub(super) fn resolve_password_hash(
+    config: &UserFullConfig,
+) -> Result<Option<String>, CloudInitError> {
+    if let Some(hashed) = &config.hashed_passwd {
+        return Ok(Some(hashed.clone()));
+    }
+
+    if let Some(plain) = &config.plain_text_passwd {
+        let hashed = pwhash::sha512_crypt::hash(plain).map_err(|e| {
+            CloudInitError::UserGroup(format!(
+                "Failed to hash password for {}: {}",
+                config.name, e
+            ))
+        })?;
+        return Ok(Some(hashed));
+    }
+
+    Ok(config.passwd.clone())
 }
 
-/// Add user to supplementary groups
-async fn add_user_to_groups(username: &str, groups: &[String]) -> Result<(), CloudInitError> {
-    debug!("Adding user {} to groups: {:?}", username, groups);
+/// Add the user to `groups`, keeping its other memberships
+async fn add_user_groups(
+    runner: &dyn SystemRunner,
+    username: &str,
+    groups: &[String],
+) -> Result<(), CloudInitError> {
+    debug!("Adding user {} to groups {:?}", username, groups);
     let groups_str = groups.join(",");
-    let output = tokio::process::Command::new("usermod")
-        .args(["--append", "--groups", &groups_str, username])
-        .output()
-        .await
-        .map_err(|e| CloudInitError::Command(e.to_string()))?;
+    let output = runner
+        .run(&SystemCommand::new("usermod").args(["--append", "--groups", &groups_str, username]))
+        .await?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.is_success() {
         return Err(CloudInitError::UserGroup(format!(
             "Failed to add user {} to groups: {}",
-            username, stderr
+            username, output.stderr
+        )));
+    }
+    Ok(())
+}
+
+/// Remove the user from `group`
+async fn remove_user_group(
+    runner: &dyn SystemRunner,
+    username: &str,
+    group: &str,
+) -> Result<(), CloudInitError> {
+    debug!("Removing user {} from group {}", username, group);
+    let output = runner
+        .run(&SystemCommand::new("gpasswd").args(["--delete", username, group]))
+        .await?;
+
+    if !output.is_success() {
+        return Err(CloudInitError::UserGroup(format!(
+            "Failed to remove user {} from group {}: {}",
+            username, group, output.stderr
         )));
     }
     Ok(())
 }
 
 /// Set user password (expects pre-hashed password)
-async fn set_user_password(username: &str, hashed_password: &str) -> Result<(), CloudInitError> {
+async fn set_user_password(
+    runner: &dyn SystemRunner,
+    username: &str,
+    hashed_password: &str,
+) -> Result<(), CloudInitError> {
     debug!("Setting password for user {}", username);
 
     // Use chpasswd with -e for pre-encrypted passwords
     let input = format!("{}:{}", username, hashed_password);
-    let mut child = tokio::process::Command::new("chpasswd")
-        .arg("-e")
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::null())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .map_err(|e| CloudInitError::Command(e.to_string()))?;
-
-    if let Some(stdin) = child.stdin.as_mut() {
-        use tokio::io::AsyncWriteExt;
-        stdin
-            .write_all(input.as_bytes())
-            .await
-            .map_err(|e| CloudInitError::Command(e.to_string()))?;
-    }
-
-    let output = child
-        .wait_with_output()
-        .await
-        .map_err(|e| CloudInitError::Command(e.to_string()))?;
+    let output = runner
+        .run(&SystemCommand::new("chpasswd").arg("-e").stdin(input))
+        .await?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.is_success() {
         return Err(CloudInitError::UserGroup(format!(
             "Failed to set password for {}: {}",
-            username, stderr
+            username, output.stderr
         )));
     }
 
@@ -184,39 +701,68 @@ async fn set_user_password(username: &str, hashed_password: &str) -> Result<(),
 }
 
 /// Lock user password (disable password login)
-async fn lock_user_password(username: &str) -> Result<(), CloudInitError> {
+async fn lock_user_password(
+    runner: &dyn SystemRunner,
+    username: &str,
+) -> Result<(), CloudInitError> {
     debug!("Locking password for user {}", username);
 
-    let output = tokio::process::Command::new("passwd")
-        .args(["-l", username])
-        .output()
-        .await
-        .map_err(|e| CloudInitError::Command(e.to_string()))?;
+    let output = runner
+        .run(&SystemCommand::new("passwd").args(["-l", username]))
+        .await?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        warn!("Failed to lock password for {}: {}", username, stderr);
+    if !output.is_success() {
+        warn!(
+            "Failed to lock password for {}: {}",
+            username, output.stderr
+        );
         // Don't fail - user may not have a password set
     }
 
     Ok(())
 }
 
-/// Configure sudo access for a user
-async fn configure_sudo(username: &str, sudo_spec: &str) -> Result<(), CloudInitError> {
+/// Expire the user's password so that it must be changed at the next login
+async fn expire_user_password(
+    runner: &dyn SystemRunner,
+    username: &str,
+) -> Result<(), CloudInitError> {
+    debug!("Expiring password for user {}", username);
+
+    let output = runner
+        .run(&SystemCommand::new("passwd").args(["--expire", username]))
+        .await?;
+
+    if !output.is_success() {
+        return Err(CloudInitError::UserGroup(format!(
+            "Failed to expire password for {}: {}",
+            username, output.stderr
+        )));
+    }
+    Ok(())
+}
+
+/// Configure sudo access for a user with a rule in `sudoers_dir`
+pub(super) async fn configure_sudo(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    sudoers_dir: &str,
+    username: &str,
+    sudo_spec: &str,
+) -> Result<(), CloudInitError> {
     debug!("Configuring sudo for user {}: {}", username, sudo_spec);
 
     // Create sudoers.d directory if it doesn't exist
-    let sudoers_dir = Path::new("/etc/sudoers.d");
+    let sudoers_path = sudoers_dir;
+    let sudoers_dir = root.path(sudoers_path);
     if !sudoers_dir.exists() {
-        fs::create_dir_all(sudoers_dir)
-            .await
-            .map_err(CloudInitError::Io)?;
+        root.create_dir_all(&sudoers_dir).await?;
     }
 
     // Write sudoers file for this user
     // Filename is 90-cloud-init-users to match Python cloud-init
-    let sudoers_file = sudoers_dir.join(format!("90-cloud-init-{}", username));
+    let sudoers_name = format!("90-cloud-init-{}", username);
+    let sudoers_file = sudoers_dir.join(&sudoers_name);
 
     // Format: "username sudo_spec" or if sudo_spec contains username, use as-is
     let content = if sudo_spec.contains(username) || sudo_spec.starts_with("ALL") {
@@ -227,33 +773,26 @@ async fn configure_sudo(username: &str, sudo_spec: &str) -> Result<(), CloudInit
         format!("{} {}\n", username, sudo_spec)
     };
 
-    fs::write(&sudoers_file, &content)
-        .await
-        .map_err(CloudInitError::Io)?;
+    root.write_file(&sudoers_file, &content).await?;
 
     // Set permissions to 0440 (required for sudoers files)
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        fs::set_permissions(&sudoers_file, std::fs::Permissions::from_mode(0o440))
-            .await
-            .map_err(CloudInitError::Io)?;
-    }
+    root.set_mode(&sudoers_file, 0o440).await?;
 
-    // Validate sudoers file
-    let output = tokio::process::Command::new("visudo")
-        .args(["-c", "-f", &sudoers_file.to_string_lossy()])
-        .output()
-        .await
-        .map_err(|e| CloudInitError::Command(e.to_string()))?;
+    // Validate sudoers file; visudo may run chrooted, so pass the in-system path
+    let output = runner
+        .run(&SystemCommand::new("visudo").args([
+            "-c".to_string(),
+            "-f".to_string(),
+            format!("{}/{}", sudoers_path, sudoers_name),
+        ]))
+        .await?;
 
-    if !output.status.success() {
+    if !output.is_success() {
         // Remove invalid sudoers file
-        let _ = fs::remove_file(&sudoers_file).await;
-        let stderr = String::from_utf8_lossy(&output.stderr);
+        let _ = root.remove_file(&sudoers_file).await;
         return Err(CloudInitError::UserGroup(format!(
             "Invalid sudoers configuration for {}: {}",
-            username, stderr
+            username, output.stderr
         )));
     }
 
@@ -264,38 +803,100 @@ async fn configure_sudo(username: &str, sudo_spec: &str) -> Result<(), CloudInit
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+
+    /// Fake Fedora root whose /etc/group holds `groups`
+    fn fedora_root(groups: &str) -> (tempfile::TempDir, RootContext) {
+        let temp = tempfile::TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        std::fs::create_dir_all(root.path("/etc")).unwrap();
+        std::fs::write(root.path("/etc/os-release"), "ID=fedora\n").unwrap();
+        std::fs::write(root.path("/etc/group"), groups).unwrap();
+        (temp, root)
+    }
+
+    fn accounts(config: &CloudConfig) -> Accounts<'_> {
+        let distro = Distro::from_os_release("ID=fedora\n");
+        Accounts::new(&distro, config, &config.users, &[])
+    }
 
     #[tokio::test]
     async fn test_create_users_empty() {
-        let result = create_users(&[]).await;
+        let runner = RecordingRunner::new();
+        let result =
+            create_users(&runner, &RootContext::host(), &CloudConfig::default(), &[]).await;
         assert!(result.is_ok());
+        assert!(runner.commands().is_empty());
     }
 
     #[tokio::test]
-    async fn test_create_users_skips_default() {
-        let users = vec![UserConfig::Name("default".to_string())];
-        let result = create_users(&users).await;
-        assert!(result.is_ok());
+    async fn test_create_users_default_is_distro_user() {
+        let (_temp, root) = fedora_root("wheel:x:10:\n");
+        let runner = RecordingRunner::new();
+        let config = CloudConfig {
+            users: vec![UserConfig::Name("default".to_string())],
+            ..Default::default()
+        };
+        create_users(&runner, &root, &config, &[]).await.unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "useradd --create-home --shell /bin/bash fedora",
+                "usermod --append --groups wheel fedora",
+                "passwd -l fedora",
+                "visudo -c -f /etc/sudoers.d/90-cloud-init-fedora",
+            ]
+        );
+        let sudoers =
+            std::fs::read_to_string(root.path("/etc/sudoers.d/90-cloud-init-fedora")).unwrap();
+        assert_eq!(sudoers, "fedora ALL=(ALL) NOPASSWD:ALL\n");
     }
 
     #[tokio::test]
     async fn test_create_user_simple_calls_useradd() {
-        let result = create_user_simple("test_user_xyz_12345").await;
-        let _ = result; // May fail on macOS but should not panic
+        let runner = RecordingRunner::new();
+        create_user_simple(&runner, "alice").await.unwrap();
+        assert_eq!(runner.commands(), vec!["useradd --create-home alice"]);
+    }
+
+    #[tokio::test]
+    async fn test_create_user_existing_is_ok() {
+        let runner = RecordingRunner::new()
+            .with_response("useradd", CommandOutput::failure(9, "user exists"));
+        assert!(create_user_simple(&runner, "alice").await.is_ok());
+
+        let runner = RecordingRunner::new()
+            .with_response("useradd", CommandOutput::failure(1, "cannot lock"));
+        let err = create_user_simple(&runner, "alice").await.unwrap_err();
+        assert!(err.to_string().contains("cannot lock"));
     }
 
     #[tokio::test]
     async fn test_create_user_full_minimal() {
+        let runner = RecordingRunner::new();
         let config = UserFullConfig {
             name: "test_fulluser_xyz".to_string(),
             ..Default::default()
         };
-        let result = create_user_full(&config).await;
-        let _ = result;
+        let cloud_config = CloudConfig::default();
+        create_user_full(
+            &runner,
+            &RootContext::host(),
+            &accounts(&cloud_config),
+            &config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec!["useradd --create-home test_fulluser_xyz"]
+        );
     }
 
     #[tokio::test]
     async fn test_create_user_full_with_options() {
+        let (_temp, root) = fedora_root("sudo:x:27:\ndocker:x:990:\n");
+        let runner = RecordingRunner::new();
         let config = UserFullConfig {
             name: "test_opts_xyz".to_string(),
             shell: Some("/bin/bash".to_string()),
@@ -304,21 +905,407 @@ mod tests {
             uid: Some(9999),
             primary_group: Some("users".to_string()),
             system: Some(true),
+            groups: vec!["sudo".to_string(), "docker".to_string()],
+            hashed_passwd: Some("$6$salt$hash".to_string()),
+            lock_passwd: Some(true),
+            expire: Some(true),
             ..Default::default()
         };
-        let result = create_user_full(&config).await;
-        let _ = result;
+        let cloud_config = CloudConfig::default();
+        create_user_full(&runner, &root, &accounts(&cloud_config), &config)
+            .await
+            .unwrap();
+
+        let calls = runner.calls();
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "useradd --create-home --shell /bin/bash --home-dir /home/test_opts_xyz \
+                 --comment Test User --uid 9999 --gid users --system test_opts_xyz",
+                "usermod --append --groups sudo,docker test_opts_xyz",
+                "chpasswd -e",
+                "passwd -l test_opts_xyz",
+                "passwd --expire test_opts_xyz",
+            ]
+        );
+        assert_eq!(
+            calls[2].stdin.as_deref(),
+            Some("test_opts_xyz:$6$salt$hash")
+        );
     }
 
     #[tokio::test]
-    async fn test_add_user_to_groups_calls_usermod() {
-        let result = add_user_to_groups("nonexistent", &["group1".to_string()]).await;
-        let _ = result;
+    async fn test_configure_sudo_under_root() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let runner = RecordingRunner::new();
+
+        configure_sudo(
+            &runner,
+            &root,
+            SUDOERS_DIR,
+            "alice",
+            "ALL=(ALL) NOPASSWD:ALL",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(temp.path().join("etc/sudoers.d/90-cloud-init-alice")).unwrap(),
+            "alice ALL=(ALL) NOPASSWD:ALL\n"
+        );
+        assert_eq!(
+            runner.commands(),
+            vec!["visudo -c -f /etc/sudoers.d/90-cloud-init-alice"]
+        );
+    }
+
+    #[test]
+    fn test_build_useradd_args_minimal() {
+        let config = UserFullConfig {
+            name: "alice".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(build_useradd_args(&config), vec!["--create-home", "alice"]);
+    }
+
+    #[test]
+    fn test_build_useradd_args_expiry_and_home_flags() {
+        let config = UserFullConfig {
+            name: "bob".to_string(),
+            expiredate: Some("2030-01-01".to_string()),
+            inactive: Some("7".to_string()),
+            no_create_home: Some(true),
+            no_user_group: Some(true),
+            ..Default::default()
+        };
+        let args = build_useradd_args(&config);
+        assert_eq!(args[0], "--no-create-home");
+        assert!(!args.contains(&"--create-home".to_string()));
+        assert!(args.contains(&"--no-user-group".to_string()));
+        assert!(args.windows(2).any(|w| w == ["--expiredate", "2030-01-01"]));
+        assert!(args.windows(2).any(|w| w == ["--inactive", "7"]));
+        assert_eq!(args.last().unwrap(), "bob");
+    }
+
+    #[test]
+    fn test_resolve_password_hash_none() {
+        let config = UserFullConfig::default();
+        assert!(resolve_password_hash(&config).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_resolve_password_hash_plain_text() {
+        let config = UserFullConfig {
+            name: "carol".to_string(),
+            plain_text_passwd: Some("s3cret".to_string()),
+            ..Default::default()
+        };
+        let hashed = resolve_password_hash(&config).unwrap().unwrap();
+        assert!(hashed.starts_with("$6$"));
+        assert!(pwhash::sha512_crypt::verify("s3cret", &hashed));
+    }
+
+    #[test]
+    fn test_resolve_password_hash_prefers_hashed() {
+        let config = UserFullConfig {
+            name: "dave".to_string(),
+            passwd: Some("$6$legacy".to_string()),
+            hashed_passwd: Some("$6$explicit".to_string()),
+            plain_text_passwd: Some("ignored".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve_password_hash(&config).unwrap().as_deref(),
+            Some("$6$explicit")
+        );
+    }
+
+    #[test]
+    fn test_resolve_password_hash_legacy_passwd() {
+        let config = UserFullConfig {
+            name: "erin".to_string(),
+            passwd: Some("$6$legacy".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve_password_hash(&config).unwrap().as_deref(),
+            Some("$6$legacy")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_user_groups_appends() {
+        let runner = RecordingRunner::new()
+            .with_response("usermod", CommandOutput::failure(6, "group does not exist"));
+        let result = add_user_groups(&runner, "nonexistent", &["group1".to_string()]).await;
+        assert!(matches!(result, Err(CloudInitError::UserGroup(_))));
+        assert_eq!(
+            runner.commands(),
+            vec!["usermod --append --groups group1 nonexistent"]
+        );
+
+        let runner = RecordingRunner::new();
+        remove_user_group(&runner, "alice", "old").await.unwrap();
+        assert_eq!(runner.commands(), vec!["gpasswd --delete alice old"]);
+    }
+
+    #[tokio::test]
+    async fn test_supplementary_groups_reconcile_membership() {
+        let (_temp, root) = fedora_root(
+            "adm:x:4:alice\nwheel:x:10:alice\ndocker:x:990:alice\nold:x:991:alice,bob\n",
+        );
+        std::fs::create_dir_all(root.cloud_paths().instance_link()).unwrap();
+        record_added_groups(
+            &root,
+            "alice",
+            ["old".to_string(), "wheel".to_string()].into(),
+        )
+        .await
+        .unwrap();
+        let alice = |groups: &[&str]| UserFullConfig {
+            name: "alice".to_string(),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+            ..Default::default()
+        };
+        let runner = RecordingRunner::new();
+
+        // Dropping `old`, which cloud-init added, removes alice from it;
+        // `adm` and `docker` came from the image and stay
+        let config = CloudConfig::default();
+        let change = accounts(&config)
+            .supplementary_groups(&runner, &root, &alice(&["wheel"]))
+            .await
+            .unwrap();
+        assert!(change.add.is_empty());
+        assert_eq!(change.remove, vec!["old"]);
+        assert_eq!(change.groups, vec!["adm", "docker", "wheel"]);
+        assert_eq!(change.added, ["wheel".to_string()].into());
+
+        // With no groups listed only the recorded ones go
+        let change = accounts(&config)
+            .supplementary_groups(&runner, &root, &alice(&[]))
+            .await
+            .unwrap();
+        assert_eq!(change.remove, vec!["old", "wheel"]);
+        assert_eq!(change.groups, vec!["adm", "docker"]);
+        assert!(change.added.is_empty());
+
+        // Membership granted by top-level groups is kept
+        let config = CloudConfig::from_yaml("groups:\n  - old: [alice]\n").unwrap();
+        let change = accounts(&config)
+            .supplementary_groups(&runner, &root, &alice(&["wheel"]))
+            .await
+            .unwrap();
+        assert!(change.is_empty());
+
+        // Unchanged groups need no usermod
+        let config = CloudConfig::default();
+        let change = accounts(&config)
+            .supplementary_groups(&runner, &root, &alice(&["old", "wheel", "docker", "wheel"]))
+            .await
+            .unwrap();
+        assert!(change.is_empty());
+        assert_eq!(
+            change.added,
+            ["old".to_string(), "wheel".to_string()].into()
+        );
+        assert!(runner.commands().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_create_users_keeps_image_groups() {
+        let (_temp, root) = fedora_root("adm:x:4:alice\nwheel:x:10:\naudit:x:11:\n");
+        std::fs::create_dir_all(root.cloud_paths().instance_link()).unwrap();
+        let runner = RecordingRunner::new();
+        let config =
+            CloudConfig::from_yaml("users:\n  - name: alice\n    groups: [wheel, audit]\n")
+                .unwrap();
+        create_users(&runner, &root, &config, &[]).await.unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "useradd --create-home alice",
+                "usermod --append --groups wheel,audit alice",
+            ]
+        );
+
+        // The next run drops audit but never touches the image's adm
+        std::fs::write(
+            root.path("/etc/group"),
+            "adm:x:4:alice\nwheel:x:10:alice\naudit:x:11:alice\n",
+        )
+        .unwrap();
+        let runner =
+            RecordingRunner::new().with_response("useradd", CommandOutput::failure(9, "exists"));
+        let config =
+            CloudConfig::from_yaml("users:\n  - name: alice\n    groups: [wheel]\n").unwrap();
+        create_users(&runner, &root, &config, &[]).await.unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "useradd --create-home alice",
+                "gpasswd --delete alice audit"
+            ]
+        );
+        let record = std::fs::read_to_string(root.cloud_paths().added_groups()).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&record).unwrap(),
+            serde_json::json!({"alice": ["wheel"]})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_user_full_updates_existing_user() {
+        let runner =
+            RecordingRunner::new().with_response("useradd", CommandOutput::failure(9, "exists"));
+        let config = UserFullConfig {
+            name: "alice".to_string(),
+            shell: Some("/bin/zsh".to_string()),
+            gecos: Some("Alice".to_string()),
+            uid: Some(1234),
+            inactive: Some("7".to_string()),
+            ..Default::default()
+        };
+        let cloud_config = CloudConfig::default();
+        create_user_full(
+            &runner,
+            &RootContext::host(),
+            &accounts(&cloud_config),
+            &config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "useradd --create-home --shell /bin/zsh --comment Alice --uid 1234 \
+                 --inactive 7 alice",
+                "usermod --shell /bin/zsh --comment Alice --inactive 7 alice",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_users_remove() {
+        let (_temp, root) = fedora_root("wheel:x:10:\n");
+        std::fs::create_dir_all(root.path(SUDOERS_DIR)).unwrap();
+        let sudoers = root.path(SUDOERS_DIR).join("90-cloud-init-olduser");
+        std::fs::write(&sudoers, "olduser ALL=(ALL) ALL\n").unwrap();
+        let config =
+            CloudConfig::from_yaml("users:\n  - name: olduser\n    remove: true\n").unwrap();
+
+        let runner = RecordingRunner::new();
+        create_users(&runner, &root, &config, &[]).await.unwrap();
+        assert_eq!(runner.commands(), vec!["userdel olduser"]);
+        assert!(!sudoers.exists());
+
+        // Already gone is fine; other failures are not
+        let runner =
+            RecordingRunner::new().with_response("userdel", CommandOutput::failure(6, "no user"));
+        create_users(&runner, &root, &config, &[]).await.unwrap();
+        let runner =
+            RecordingRunner::new().with_response("userdel", CommandOutput::failure(8, "logged in"));
+        let err = create_users(&runner, &root, &config, &[])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("logged in"));
+    }
+
+    #[tokio::test]
+    async fn test_supplementary_groups_create_groups() {
+        let (_temp, root) = fedora_root("wheel:x:10:\n");
+        let config = CloudConfig::default();
+        let accounts = accounts(&config);
+        let mut user = UserFullConfig {
+            name: "alice".to_string(),
+            groups: vec!["wheel".to_string(), "audit".to_string()],
+            ..Default::default()
+        };
+
+        let runner = RecordingRunner::new();
+        let change = accounts
+            .supplementary_groups(&runner, &root, &user)
+            .await
+            .unwrap();
+        assert_eq!(change.add, vec!["wheel", "audit"]);
+        assert_eq!(runner.commands(), vec!["groupadd audit"]);
+
+        user.create_groups = Some(false);
+        let runner = RecordingRunner::new();
+        let change = accounts
+            .supplementary_groups(&runner, &root, &user)
+            .await
+            .unwrap();
+        assert_eq!(change.add, vec!["wheel"]);
+        assert!(runner.commands().is_empty());
+    }
+
+    #[test]
+    fn test_default_user_override() {
+        let fedora = Distro::from_os_release("ID=fedora\n");
+        let named = default_user(&fedora, Some(&UserConfig::Name("ops".to_string())));
+        assert_eq!(named.name, "ops");
+        assert_eq!(named.groups, vec!["wheel"]);
+
+        let config =
+            CloudConfig::from_yaml("user:\n  shell: /bin/zsh\n  lock_passwd: false\n").unwrap();
+        let full = default_user(&fedora, config.user.as_ref());
+        assert_eq!(full.name, "fedora");
+        assert_eq!(full.shell.as_deref(), Some("/bin/zsh"));
+        assert_eq!(full.lock_passwd, Some(false));
+        assert_eq!(full.sudo.as_deref(), Some("ALL=(ALL) NOPASSWD:ALL"));
+    }
+
+    #[tokio::test]
+    async fn test_create_users_user_implies_default() {
+        let (_temp, root) = fedora_root("wheel:x:10:ops\n");
+        let runner = RecordingRunner::new();
+        let config = CloudConfig::from_yaml("user:\n  name: ops\n").unwrap();
+        create_users(&runner, &root, &config, &[]).await.unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "useradd --create-home --shell /bin/bash ops",
+                "passwd -l ops",
+                "visudo -c -f /etc/sudoers.d/90-cloud-init-ops",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_authorized_keys_redirect_to_default_user() {
+        let fedora = Distro::from_os_release("ID=fedora\n");
+        let config = CloudConfig::from_yaml(
+            "users:\n  - default\n  - name: guest\n    ssh_redirect_user: true\n",
+        )
+        .unwrap();
+        let keys = ["ssh-ed25519 AAAAplatform0 cloud".to_string()];
+        let accounts = Accounts::new(&fedora, &config, &config.users, &keys);
+        let guest = accounts.resolve(&config.users[1]);
+
+        let installed = accounts.authorized_keys(&guest);
+        assert_eq!(installed.len(), 1);
+        assert!(installed[0].starts_with("no-port-forwarding,"));
+        assert!(
+            installed[0].contains(r#"login as the user \"fedora\" rather than the user \"guest\""#)
+        );
+        assert!(installed[0].ends_with("exit 142\" ssh-ed25519 AAAAplatform0 cloud"));
+
+        // Without a default user there is nobody to redirect to
+        let config = CloudConfig {
+            users: config.users[1..].to_vec(),
+            ..config
+        };
+        let accounts = Accounts::new(&fedora, &config, &config.users, &keys);
+        assert!(accounts.authorized_keys(&guest).is_empty());
     }
 
     #[tokio::test]
     async fn test_lock_user_password_calls_passwd() {
-        let result = lock_user_password("nonexistent_lock_test").await;
+        let runner = RecordingRunner::new()
+            .with_response("passwd", CommandOutput::failure(1, "no such user"));
+        let result = lock_user_password(&runner, "nonexistent_lock_test").await;
         // lock_user_password logs warning but returns Ok
         assert!(result.is_ok());
     }
@@ -365,9 +1352,18 @@ mod tests {
 
     #[tokio::test]
     async fn test_create_users_name_variant() {
-        let users = vec![UserConfig::Name("test_name_xyz_12345".to_string())];
-        let result = create_users(&users).await;
-        let _ = result;
+        let runner = RecordingRunner::new();
+        let config = CloudConfig {
+            users: vec![UserConfig::Name("test_name_xyz_12345".to_string())],
+            ..Default::default()
+        };
+        create_users(&runner, &RootContext::host(), &config, &[])
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec!["useradd --create-home test_name_xyz_12345"]
+        );
     }
 
     #[tokio::test]
@@ -376,8 +1372,17 @@ mod tests {
             name: "test_full_xyz_12345".to_string(),
             ..Default::default()
         };
-        let users = vec![UserConfig::Full(Box::new(full))];
-        let result = create_users(&users).await;
-        let _ = result;
+        let runner = RecordingRunner::new();
+        let config = CloudConfig {
+            users: vec![UserConfig::Full(Box::new(full))],
+            ..Default::default()
+        };
+        create_users(&runner, &RootContext::host(), &config, &[])
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec!["useradd --create-home test_full_xyz_12345"]
+        );
     }
 }
@@ -0,0 +1,608 @@
+//! Sandboxed WebAssembly module plugins
+//!
+//! With the `plugins-wasm` feature, `<name>.wasm` files in
+//! [`PLUGIN_DIR`](super::plugins::PLUGIN_DIR) run as modules like
+//! executable plugins, but inside an interpreter with no access to the
+//! host: no WASI, no files, no network. A plugin built for
+//! `wasm32-unknown-unknown` exports
+//!
+//! - `memory`
+//! - `alloc(len: i32) -> i32`, returning space for `len` bytes
+//! - `run(ptr: i32, len: i32) -> i32`, called with the merged
+//!   cloud-config as JSON; anything but 0 fails the module
+//!
+//! and may import these functions from the `cloud_init` module, which
+//! take strings as pointer and length pairs:
+//!
+//! - `log(level: i32, ptr, len)`: 0 debug, 1 info, anything else a warning
+//! - `error(ptr, len)`: the message to fail the module with
+//! - `write_file(path_ptr, path_len, data_ptr, data_len, mode: i32) -> i32`
+//! - `run_command(argv_ptr, argv_len) -> i32`, `argv` a JSON array
+//!
+//! `write_file` and `run_command` return 0 when the request is accepted
+//! and -1 when it is refused. Accepted requests are carried out in order
+//! once `run` returns 0, so a plugin never sees command output, and
+//! nothing happens if it fails. Files are written under the root being
+//! configured. A plugin may only write the files and directories, and run
+//! the commands, listed for it in cloud.cfg, and never sets the setuid,
+//! setgid or sticky bits:
+//!
+//! ```yaml
+//! plugin_paths:
+//!   my_plugin: [/etc/my_plugin, /etc/motd]
+//! plugin_commands:
+//!   my_plugin: [systemctl]
+//! ```
+//!
+//! Plugins run with limited fuel (roughly instructions) and memory, and
+//! may queue only so many requests, so a runaway plugin fails its module
+//! instead of hanging the boot or exhausting memory.
+
+use super::plugins::Plugin;
+use crate::config::CloudConfig;
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use crate::{CloudInitError, IoContext};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs;
+use tracing::{debug, info, warn};
+use wasmi::{
+    Caller, Config, Engine, Extern, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+/// Module name of the host functions
+const HOST_MODULE: &str = "cloud_init";
+
+/// Fuel a plugin gets for one run
+const FUEL: u64 = 1_000_000_000;
+
+/// Most linear memory a plugin may grow to
+const MEMORY_LIMIT: usize = 64 << 20;
+
+/// Most requests a plugin may queue in one run
+const MAX_REQUESTS: usize = 256;
+
+/// Most file content a plugin may queue in one run
+const MAX_REQUEST_BYTES: usize = MEMORY_LIMIT;
+
+/// Permission bits a plugin may set; no setuid, setgid or sticky bit
+const MODE_MASK: u32 = 0o777;
+
+/// Something a plugin asked the host to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Request {
+    WriteFile {
+        path: PathBuf,
+        content: Vec<u8>,
+        mode: u32,
+    },
+    RunCommand(Vec<String>),
+}
+
+/// What a plugin may do, from cloud.cfg
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Permissions<'a> {
+    /// Commands it may run (`plugin_commands`)
+    pub commands: &'a [String],
+    /// Files and directories it may write (`plugin_paths`)
+    pub paths: &'a [String],
+}
+
+impl<'a> Permissions<'a> {
+    /// What cloud.cfg allows `plugin`
+    pub fn from_config(config: &'a CloudConfig, plugin: &Plugin) -> Self {
+        let allowed = |lists: &'a BTreeMap<String, Vec<String>>| {
+            lists
+                .get(&plugin.name)
+                .map(Vec::as_slice)
+                .unwrap_or_default()
+        };
+        Self {
+            commands: allowed(&config.plugin_commands),
+            paths: allowed(&config.plugin_paths),
+        }
+    }
+}
+
+/// State behind the host functions of one run
+struct HostState {
+    plugin: String,
+    allowed_commands: Vec<String>,
+    allowed_paths: Vec<PathBuf>,
+    requests: Vec<Request>,
+    queued_bytes: usize,
+    error: Option<String>,
+    limits: StoreLimits,
+}
+
+impl HostState {
+    /// Queue `request` unless the plugin has queued too much already
+    fn queue(&mut self, request: Request) -> i32 {
+        let bytes = match &request {
+            Request::WriteFile { content, .. } => content.len(),
+            Request::RunCommand(argv) => argv.iter().map(String::len).sum(),
+        };
+        if self.requests.len() >= MAX_REQUESTS
+            || self.queued_bytes.saturating_add(bytes) > MAX_REQUEST_BYTES
+        {
+            warn!(
+                "{}: refused request; at most {} requests and {} bytes per run",
+                self.plugin, MAX_REQUESTS, MAX_REQUEST_BYTES
+            );
+            return -1;
+        }
+        self.queued_bytes += bytes;
+        self.requests.push(request);
+        0
+    }
+
+    /// Whether `path` is a file or below a directory listed in
+    /// `plugin_paths`
+    fn may_write(&self, path: &Path) -> bool {
+        is_allowed_path(path) && self.allowed_paths.iter().any(|dir| path.starts_with(dir))
+    }
+}
+
+/// Copy `len` bytes at `ptr` out of the calling plugin's memory
+fn read_bytes(caller: &Caller<'_, HostState>, ptr: i32, len: i32) -> Option<Vec<u8>> {
+    let memory = caller.get_export("memory").and_then(Extern::into_memory)?;
+    let start = usize::try_from(ptr).ok()?;
+    let end = start.checked_add(usize::try_from(len).ok()?)?;
+    memory.data(caller).get(start..end).map(<[u8]>::to_vec)
+}
+
+fn read_string(caller: &Caller<'_, HostState>, ptr: i32, len: i32) -> Option<String> {
+    String::from_utf8(read_bytes(caller, ptr, len)?).ok()
+}
+
+/// An absolute path without `..`, which cannot leave the root
+fn is_allowed_path(path: &Path) -> bool {
+    path.is_absolute()
+        && path
+            .components()
+            .all(|c| matches!(c, Component::RootDir | Component::Normal(_)))
+}
+
+fn log(caller: Caller<'_, HostState>, level: i32, ptr: i32, len: i32) {
+    let plugin = &caller.data().plugin;
+    let message = read_string(&caller, ptr, len).unwrap_or_default();
+    match level {
+        0 => debug!("{}: {}", plugin, message),
+        1 => info!("{}: {}", plugin, message),
+        _ => warn!("{}: {}", plugin, message),
+    }
+}
+
+fn error(mut caller: Caller<'_, HostState>, ptr: i32, len: i32) {
+    let message = read_string(&caller, ptr, len);
+    caller.data_mut().error = message;
+}
+
+fn write_file(
+    mut caller: Caller<'_, HostState>,
+    path_ptr: i32,
+    path_len: i32,
+    data_ptr: i32,
+    data_len: i32,
+    mode: i32,
+) -> i32 {
+    let (Some(path), Some(content)) = (
+        read_string(&caller, path_ptr, path_len),
+        read_bytes(&caller, data_ptr, data_len),
+    ) else {
+        return -1;
+    };
+    let path = PathBuf::from(path);
+    let Ok(mode) = u32::try_from(mode) else {
+        return -1;
+    };
+    let state = caller.data_mut();
+    if !state.may_write(&path) || mode & !MODE_MASK != 0 {
+        warn!(
+            "{}: refused to write {} with mode {:o}; allow the path under plugin_paths \
+             in cloud.cfg",
+            state.plugin,
+            path.display(),
+            mode
+        );
+        return -1;
+    }
+    state.queue(Request::WriteFile {
+        path,
+        content,
+        mode,
+    })
+}
+
+fn run_command(mut caller: Caller<'_, HostState>, ptr: i32, len: i32) -> i32 {
+    let Some(argv) = read_string(&caller, ptr, len)
+        .and_then(|json| serde_json::from_str::<Vec<String>>(&json).ok())
+    else {
+        return -1;
+    };
+    let state = caller.data_mut();
+    match argv.first() {
+        Some(program) if state.allowed_commands.contains(program) => {
+            state.queue(Request::RunCommand(argv))
+        }
+        _ => {
+            warn!(
+                "{}: refused to run {:?}; allow it under plugin_commands in cloud.cfg",
+                state.plugin, argv
+            );
+            -1
+        }
+    }
+}
+
+/// Run the plugin in `wasm` on `config`, returning what it asked for
+fn execute(
+    plugin: &str,
+    wasm: &[u8],
+    config: &str,
+    permissions: Permissions<'_>,
+) -> Result<Vec<Request>, String> {
+    let mut engine_config = Config::default();
+    engine_config.consume_fuel(true);
+    let engine = Engine::new(&engine_config);
+    let module = Module::new(&engine, wasm).map_err(|e| format!("invalid module: {}", e))?;
+
+    let mut store = Store::new(
+        &engine,
+        HostState {
+            plugin: plugin.to_string(),
+            allowed_commands: permissions.commands.to_vec(),
+            allowed_paths: permissions.paths.iter().map(PathBuf::from).collect(),
+            requests: Vec::new(),
+            queued_bytes: 0,
+            error: None,
+            limits: StoreLimitsBuilder::new().memory_size(MEMORY_LIMIT).build(),
+        },
+    );
+    store.limiter(|state| &mut state.limits);
+    store.set_fuel(FUEL).map_err(|e| e.to_string())?;
+
+    let mut linker = <Linker<HostState>>::new(&engine);
+    linker
+        .func_wrap(HOST_MODULE, "log", log)
+        .and_then(|l| l.func_wrap(HOST_MODULE, "error", error))
+        .and_then(|l| l.func_wrap(HOST_MODULE, "write_file", write_file))
+        .and_then(|l| l.func_wrap(HOST_MODULE, "run_command", run_command))
+        .map_err(|e| e.to_string())?;
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .and_then(|pre| pre.start(&mut store))
+        .map_err(|e| format!("could not instantiate: {}", e))?;
+
+    let memory = instance
+        .get_memory(&store, "memory")
+        .ok_or("exports no memory")?;
+    let alloc = instance
+        .get_typed_func::<i32, i32>(&store, "alloc")
+        .map_err(|e| format!("alloc: {}", e))?;
+    let run = instance
+        .get_typed_func::<(i32, i32), i32>(&store, "run")
+        .map_err(|e| format!("run: {}", e))?;
+
+    let len = i32::try_from(config.len()).map_err(|_| "cloud-config too large")?;
+    let ptr = alloc.call(&mut store, len).map_err(|e| e.to_string())?;
+    let offset = usize::try_from(ptr).map_err(|_| "alloc returned a negative pointer")?;
+    memory
+        .write(&mut store, offset, config.as_bytes())
+        .map_err(|e| format!("alloc returned unusable memory: {}", e))?;
+
+    let status = run
+        .call(&mut store, (ptr, len))
+        .map_err(|e| e.to_string())?;
+    let state = store.into_data();
+    if status != 0 {
+        return Err(state
+            .error
+            .unwrap_or_else(|| format!("run returned {}", status)));
+    }
+    Ok(state.requests)
+}
+
+/// Run the WebAssembly `plugin` with `config`, the merged cloud-config,
+/// allowing it what `permissions` list
+pub async fn run(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    plugin: &Plugin,
+    config: &serde_json::Value,
+    permissions: Permissions<'_>,
+) -> Result<(), CloudInitError> {
+    let wasm = fs::read(&plugin.path).await.with_path(&plugin.path)?;
+    // The interpreter does not yield, so it gets a thread of its own; fuel
+    // bounds how long that thread runs if the module is abandoned
+    let name = plugin.name.clone();
+    let config = config.to_string();
+    let commands = permissions.commands.to_vec();
+    let paths = permissions.paths.to_vec();
+    let span = tracing::Span::current();
+    let requests = tokio::task::spawn_blocking(move || {
+        let permissions = Permissions {
+            commands: &commands,
+            paths: &paths,
+        };
+        span.in_scope(|| execute(&name, &wasm, &config, permissions))
+    })
+    .await
+    .map_err(|e| CloudInitError::module(&plugin.name, e.to_string()))?
+    .map_err(|e| CloudInitError::module(&plugin.name, e))?;
+
+    for request in requests {
+        match request {
+            Request::WriteFile {
+                path,
+                content,
+                mode,
+            } => {
+                let path = root.path(&path);
+                if let Some(parent) = path.parent() {
+                    root.create_dir_all(parent).await?;
+                }
+                root.write_file_mode(&path, content, mode).await?;
+            }
+            Request::RunCommand(argv) => {
+                let command = SystemCommand::new(&argv[0]).args(&argv[1..]);
+                let output = runner.run(&command).await?;
+                if !output.is_success() {
+                    return Err(CloudInitError::Command(format!(
+                        "{} failed: {}",
+                        command,
+                        output.stderr.trim()
+                    )));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::plugins::PluginKind;
+    use crate::runner::RecordingRunner;
+    use tempfile::TempDir;
+
+    /// Signed LEB128, as `i32.const` takes it
+    fn sleb(mut value: i32) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+                out.push(byte);
+                return out;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+
+    fn section(id: u8, content: Vec<u8>) -> Vec<u8> {
+        let mut out = vec![id, content.len() as u8];
+        out.extend(content);
+        out
+    }
+
+    fn name(s: &str) -> Vec<u8> {
+        let mut out = vec![s.len() as u8];
+        out.extend(s.as_bytes());
+        out
+    }
+
+    /// A plugin whose `run` calls the host function `import` with `args`
+    /// over memory holding `data` at offset 0, and returns `status`
+    fn plugin(import: &str, data: &[u8], args: &[i32], status: i32) -> Vec<u8> {
+        let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+        let returns = !matches!(import, "log" | "error");
+
+        let mut types = vec![3, 0x60, args.len() as u8];
+        types.extend(std::iter::repeat_n(0x7f, args.len()));
+        match returns {
+            true => types.extend([0x01, 0x7f]),
+            false => types.push(0x00),
+        }
+        types.extend([0x60, 0x01, 0x7f, 0x01, 0x7f]);
+        types.extend([0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f]);
+        wasm.extend(section(1, types));
+
+        let mut imports = vec![1];
+        imports.extend(name(HOST_MODULE));
+        imports.extend(name(import));
+        imports.extend([0x00, 0x00]);
+        wasm.extend(section(2, imports));
+
+        wasm.extend(section(3, vec![2, 1, 2]));
+        wasm.extend(section(5, vec![1, 0x00, 0x01]));
+
+        let mut exports = vec![3];
+        exports.extend(name("memory"));
+        exports.extend([0x02, 0x00]);
+        exports.extend(name("alloc"));
+        exports.extend([0x00, 0x01]);
+        exports.extend(name("run"));
+        exports.extend([0x00, 0x02]);
+        wasm.extend(section(7, exports));
+
+        let mut alloc = vec![0x00, 0x41];
+        alloc.extend(sleb(1024));
+        alloc.push(0x0b);
+        let mut run = vec![0x00];
+        for arg in args {
+            run.push(0x41);
+            run.extend(sleb(*arg));
+        }
+        run.extend([0x10, 0x00]);
+        if returns {
+            run.push(0x1a);
+        }
+        run.push(0x41);
+        run.extend(sleb(status));
+        run.push(0x0b);
+        let mut code = vec![2, alloc.len() as u8];
+        code.extend(alloc);
+        code.push(run.len() as u8);
+        code.extend(run);
+        wasm.extend(section(10, code));
+
+        let mut segments = vec![1, 0x00, 0x41, 0x00, 0x0b, data.len() as u8];
+        segments.extend(data);
+        wasm.extend(section(11, segments));
+        wasm
+    }
+
+    fn paths(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_requests_are_collected() {
+        let allowed = paths(&["/etc/motd"]);
+        let motd = Permissions {
+            paths: &allowed,
+            ..Default::default()
+        };
+        let wasm = plugin("write_file", b"/etc/motdhello\n", &[0, 9, 9, 6, 0o644], 0);
+        assert_eq!(
+            execute("motd", &wasm, "{}", motd).unwrap(),
+            vec![Request::WriteFile {
+                path: PathBuf::from("/etc/motd"),
+                content: b"hello\n".to_vec(),
+                mode: 0o644,
+            }]
+        );
+        // Paths not listed in plugin_paths are refused; the plugin carries on
+        assert!(
+            execute("motd", &wasm, "{}", Permissions::default())
+                .unwrap()
+                .is_empty()
+        );
+
+        // Relative paths are refused
+        let wasm = plugin("write_file", b"etc/motdhello\n", &[0, 8, 8, 6, 0o644], 0);
+        assert!(execute("motd", &wasm, "{}", motd).unwrap().is_empty());
+
+        let argv = br#"["systemctl","restart","nginx"]"#;
+        let wasm = plugin("run_command", argv, &[0, argv.len() as i32], 0);
+        assert!(
+            execute("web", &wasm, "{}", Permissions::default())
+                .unwrap()
+                .is_empty()
+        );
+        let commands = paths(&["systemctl"]);
+        let web = Permissions {
+            commands: &commands,
+            ..Default::default()
+        };
+        assert_eq!(
+            execute("web", &wasm, "{}", web).unwrap(),
+            vec![Request::RunCommand(vec![
+                "systemctl".to_string(),
+                "restart".to_string(),
+                "nginx".to_string()
+            ])]
+        );
+    }
+
+    #[test]
+    fn test_write_file_restrictions() {
+        let allowed = paths(&["/etc/app"]);
+        let app = Permissions {
+            paths: &allowed,
+            ..Default::default()
+        };
+        let write = |path: &str, mode: i32| {
+            let mut data = path.as_bytes().to_vec();
+            data.extend(b"x");
+            let len = path.len() as i32;
+            execute(
+                "app",
+                &plugin("write_file", &data, &[0, len, len, 1, mode], 0),
+                "{}",
+                app,
+            )
+            .unwrap()
+            .len()
+        };
+        assert_eq!(write("/etc/app/app.conf", 0o600), 1);
+        assert_eq!(write("/etc/app", 0o644), 1);
+        assert_eq!(write("/etc/application", 0o644), 0);
+        assert_eq!(write("/etc/app/../sudoers.d/app", 0o440), 0);
+        assert_eq!(write("/etc/app/helper", 0o4755), 0);
+        assert_eq!(write("/etc/app/shared", 0o2775), 0);
+        assert_eq!(write("/etc/app/tmp", 0o1777), 0);
+    }
+
+    #[test]
+    fn test_queued_requests_are_capped() {
+        let mut state = HostState {
+            plugin: "p".to_string(),
+            allowed_commands: Vec::new(),
+            allowed_paths: Vec::new(),
+            requests: Vec::new(),
+            queued_bytes: 0,
+            error: None,
+            limits: StoreLimitsBuilder::new().build(),
+        };
+        let command = || Request::RunCommand(vec!["true".to_string()]);
+        for _ in 0..MAX_REQUESTS {
+            assert_eq!(state.queue(command()), 0);
+        }
+        assert_eq!(state.queue(command()), -1);
+        assert_eq!(state.requests.len(), MAX_REQUESTS);
+
+        state.requests.clear();
+        let big = Request::WriteFile {
+            path: PathBuf::from("/etc/app/big"),
+            content: vec![0; MAX_REQUEST_BYTES],
+            mode: 0o644,
+        };
+        assert_eq!(state.queue(big), -1);
+    }
+
+    #[test]
+    fn test_failure_reports_error() {
+        let wasm = plugin("error", b"missing key", &[0, 11], 1);
+        assert_eq!(
+            execute("p", &wasm, "{}", Permissions::default()).unwrap_err(),
+            "missing key"
+        );
+        assert!(execute("p", b"not wasm", "{}", Permissions::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_carries_out_requests() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let path = temp.path().join("motd.wasm");
+        std::fs::write(
+            &path,
+            plugin("write_file", b"/etc/motdhello\n", &[0, 9, 9, 6, 0o644], 0),
+        )
+        .unwrap();
+        let motd = Plugin {
+            name: "motd".to_string(),
+            path,
+            kind: PluginKind::Wasm,
+        };
+
+        let runner = RecordingRunner::new();
+        let allowed = paths(&["/etc/motd"]);
+        let permissions = Permissions {
+            paths: &allowed,
+            ..Default::default()
+        };
+        run(&runner, &root, &motd, &serde_json::json!({}), permissions)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.path("/etc/motd")).unwrap(),
+            "hello\n"
+        );
+        assert!(runner.commands().is_empty());
+    }
+}
@@ -0,0 +1,246 @@
+//! WireGuard module
+//!
+//! Implements the `wireguard` cloud-config key: writes a wg-quick config
+//! for each interface, enables `wg-quick@<name>` and, once the tunnels
+//! are up, runs the `readinessprobe` commands so provisioning fails when
+//! the VPN is not usable.
+//!
+//! An interface is either given as complete `content` or field by field.
+//! A `private_key_file` is not copied into the config; wg-quick loads it
+//! when the tunnel comes up, so the key can be delivered separately.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! wireguard:
+//!   interfaces:
+//!     - name: wg0
+//!       address: [10.8.0.2/24]
+//!       private_key_file: /etc/wireguard/wg0.key
+//!       peers:
+//!         - public_key: 6Vm1mZ7...=
+//!           endpoint: vpn.example.com:51820
+//!           allowed_ips: [10.8.0.0/24]
+//!           persistent_keepalive: 25
+//!   readinessprobe:
+//!     - ping -c 1 10.8.0.1
+//! ```
+
+use crate::CloudInitError;
+use crate::config::{WireguardConfig, WireguardInterface};
+use crate::modules::{packages, start_service};
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use std::path::Path;
+use tracing::{debug, info};
+
+/// Directory wg-quick reads configs from
+const WIREGUARD_DIR: &str = "/etc/wireguard";
+
+/// Configure the tunnels and probe them
+pub async fn configure_wireguard(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    config: &WireguardConfig,
+) -> Result<(), CloudInitError> {
+    if config.interfaces.is_empty() {
+        return Ok(());
+    }
+    if !packages::command_exists(runner, "wg-quick").await {
+        packages::install_package(runner, root, "wireguard-tools").await?;
+    }
+
+    for interface in &config.interfaces {
+        validate_name(&interface.name)?;
+        let path = match &interface.config_path {
+            Some(path) => root.path(path),
+            None => root.path(Path::new(WIREGUARD_DIR).join(format!("{}.conf", interface.name))),
+        };
+        if let Some(dir) = path.parent() {
+            root.create_dir_all(dir).await?;
+            root.set_mode(dir, 0o700).await?;
+        }
+        root.write_file(&path, render_config(interface)?).await?;
+        root.set_mode(&path, 0o600).await?;
+        info!("Wrote WireGuard config for {}", interface.name);
+
+        start_service(runner, root, &format!("wg-quick@{}", interface.name)).await?;
+    }
+
+    if !root.is_host() {
+        debug!("Not probing WireGuard tunnels of an alternate root");
+        return Ok(());
+    }
+    run_readiness_probes(runner, &config.readinessprobe).await
+}
+
+/// Run each probe, failing on the first one that does not succeed
+async fn run_readiness_probes(
+    runner: &dyn SystemRunner,
+    probes: &[String],
+) -> Result<(), CloudInitError> {
+    for probe in probes {
+        debug!("Running WireGuard readiness probe: {}", probe);
+        let output = runner
+            .run(&SystemCommand::new("sh").args(["-c", probe.as_str()]))
+            .await?;
+        if !output.is_success() {
+            return Err(CloudInitError::Command(format!(
+                "WireGuard readiness probe '{}' failed: {}",
+                probe,
+                output.stderr.trim()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Interface names are limited to 15 characters and used in unit names
+fn validate_name(name: &str) -> Result<(), CloudInitError> {
+    let valid = !name.is_empty()
+        && name.len() <= 15
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_=+.-".contains(c));
+    if valid {
+        Ok(())
+    } else {
+        Err(CloudInitError::InvalidData(format!(
+            "Invalid WireGuard interface name: {:?}",
+            name
+        )))
+    }
+}
+
+/// Render the wg-quick config (pure function for testability)
+fn render_config(interface: &WireguardInterface) -> Result<String, CloudInitError> {
+    if let Some(content) = &interface.content {
+        let mut content = content.clone();
+        if !content.ends_with('\n') {
+            content.push('\n');
+        }
+        return Ok(content);
+    }
+
+    let mut lines = vec!["# Configured by cloud-init-rs".to_string()];
+    lines.push("[Interface]".to_string());
+    if !interface.address.is_empty() {
+        lines.push(format!("Address = {}", interface.address.join(", ")));
+    }
+    if let Some(port) = interface.listen_port {
+        lines.push(format!("ListenPort = {}", port));
+    }
+    match (&interface.private_key, &interface.private_key_file) {
+        (Some(key), _) => lines.push(format!("PrivateKey = {}", key.trim())),
+        (None, Some(file)) => lines.push(format!("PostUp = wg set %i private-key {}", file)),
+        (None, None) => {
+            return Err(CloudInitError::InvalidData(format!(
+                "WireGuard interface {} needs content, private_key or private_key_file",
+                interface.name
+            )));
+        }
+    }
+    if !interface.dns.is_empty() {
+        lines.push(format!("DNS = {}", interface.dns.join(", ")));
+    }
+
+    for peer in &interface.peers {
+        lines.push(String::new());
+        lines.push("[Peer]".to_string());
+        lines.push(format!("PublicKey = {}", peer.public_key));
+        if let Some(key) = &peer.preshared_key {
+            lines.push(format!("PresharedKey = {}", key));
+        }
+        if let Some(endpoint) = &peer.endpoint {
+            lines.push(format!("Endpoint = {}", endpoint));
+        }
+        if !peer.allowed_ips.is_empty() {
+            lines.push(format!("AllowedIPs = {}", peer.allowed_ips.join(", ")));
+        }
+        if let Some(keepalive) = peer.persistent_keepalive {
+            lines.push(format!("PersistentKeepalive = {}", keepalive));
+        }
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+    use tempfile::TempDir;
+
+    fn config(yaml: &str) -> WireguardConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_render_config() {
+        let wg = config(
+            r#"
+interfaces:
+  - name: wg0
+    address: [10.8.0.2/24]
+    private_key_file: /etc/wireguard/wg0.key
+    peers:
+      - public_key: PEERKEY=
+        endpoint: vpn.example.com:51820
+        allowed_ips: [10.8.0.0/24, 10.9.0.0/16]
+        persistent_keepalive: 25
+"#,
+        );
+        assert_eq!(
+            render_config(&wg.interfaces[0]).unwrap(),
+            "# Configured by cloud-init-rs\n\
+             [Interface]\n\
+             Address = 10.8.0.2/24\n\
+             PostUp = wg set %i private-key /etc/wireguard/wg0.key\n\
+             \n\
+             [Peer]\n\
+             PublicKey = PEERKEY=\n\
+             Endpoint = vpn.example.com:51820\n\
+             AllowedIPs = 10.8.0.0/24, 10.9.0.0/16\n\
+             PersistentKeepalive = 25\n"
+        );
+
+        let keyless = config("interfaces: [{name: wg0}]");
+        assert!(render_config(&keyless.interfaces[0]).is_err());
+        assert!(validate_name("wg0").is_ok());
+        assert!(validate_name("wg0; rm").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_configure_wireguard_under_root() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let config = config(
+            "{interfaces: [{name: wg1, content: \"[Interface]\\nPrivateKey = KEY\"}], readinessprobe: [ping -c1 10.8.0.1]}",
+        );
+
+        let runner = RecordingRunner::new();
+        configure_wireguard(&runner, &root, &config).await.unwrap();
+
+        let path = root.path("/etc/wireguard/wg1.conf");
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "[Interface]\nPrivateKey = KEY\n"
+        );
+        // Enabled for the image's boot, probes only on the running system
+        assert_eq!(
+            runner.commands(),
+            vec!["which wg-quick", "systemctl enable wg-quick@wg1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_probe() {
+        let probes = vec!["ping -c1 10.8.0.1".to_string(), "true".to_string()];
+        let runner = RecordingRunner::new().with_response(
+            "sh -c ping -c1 10.8.0.1",
+            CommandOutput::failure(1, "unreachable"),
+        );
+        let err = run_readiness_probes(&runner, &probes).await.unwrap_err();
+        assert!(err.to_string().contains("'ping -c1 10.8.0.1' failed"));
+        assert_eq!(runner.commands().len(), 1);
+    }
+}
@@ -1,74 +1,158 @@
 //! Write files module
+//!
+//! An entry with a `source` is fetched over HTTP(S) instead of embedding
+//! its content in user-data; `content` is then only written if the fetch
+//! fails. A `checksum` is checked against whatever would be written, and
+//! a mismatch fails the entry before anything is written. Like an
+//! `#include` URL, a `source` is read up to
+//! `user_data_limits.max_include_size`.
 
 use crate::CloudInitError;
-use crate::config::WriteFileConfig;
+use crate::config::{WriteFileConfig, WriteFileSource};
+use crate::root::RootContext;
+use crate::runner::SystemCommand;
+use crate::userdata::limits;
 use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
-use flate2::read::GzDecoder;
-use std::io::Read;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
 use std::path::Path;
+use std::time::Duration;
 use tokio::fs;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+
+/// Limit for fetching one `source` URL
+const SOURCE_TIMEOUT: Duration = Duration::from_secs(300);
 
 /// Write files from cloud-config
-pub async fn write_files(files: &[WriteFileConfig]) -> Result<(), CloudInitError> {
+pub async fn write_files(
+    root: &RootContext,
+    files: &[WriteFileConfig],
+) -> Result<(), CloudInitError> {
     for file in files {
         // Skip deferred files - they'll be written later
         if file.defer == Some(true) {
             debug!("Deferring write of: {}", file.path);
             continue;
         }
-        write_file(file).await?;
+        write_file(root, file).await?;
     }
     Ok(())
 }
 
 /// Write deferred files (called in final stage)
-pub async fn write_deferred_files(files: &[WriteFileConfig]) -> Result<(), CloudInitError> {
+pub async fn write_deferred_files(
+    root: &RootContext,
+    files: &[WriteFileConfig],
+) -> Result<(), CloudInitError> {
     for file in files {
         if file.defer == Some(true) {
-            write_file(file).await?;
+            write_file(root, file).await?;
         }
     }
     Ok(())
 }
 
-pub async fn write_file(config: &WriteFileConfig) -> Result<(), CloudInitError> {
+/// Write one file beneath `root`
+pub async fn write_file(
+    root: &RootContext,
+    config: &WriteFileConfig,
+) -> Result<(), CloudInitError> {
     info!("Writing file: {}", config.path);
 
-    let path = Path::new(&config.path);
+    let path = &root.path(&config.path);
+
+    let content = file_content(config).await?;
+    if let Some(checksum) = &config.checksum {
+        verify_checksum(&config.path, &content, checksum)?;
+    }
 
     // Create parent directories if needed
     if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)
-            .await
-            .map_err(CloudInitError::Io)?;
+        root.create_dir_all(parent).await?;
     }
 
-    // Decode content based on encoding
-    let content = decode_content(&config.content, config.encoding.as_deref())?;
-
     // Write or append
     if config.append == Some(true) {
-        let mut existing = fs::read_to_string(path).await.unwrap_or_default();
-        existing.push_str(&content);
-        fs::write(path, existing)
-            .await
-            .map_err(CloudInitError::Io)?;
+        let mut existing = fs::read(path).await.unwrap_or_default();
+        existing.extend_from_slice(&content);
+        root.write_file(path, existing).await?;
     } else {
-        fs::write(path, &content)
-            .await
-            .map_err(CloudInitError::Io)?;
+        root.write_file(path, &content).await?;
     }
 
     // Set permissions (default to 0644 if not specified)
     let perms = config.permissions.as_deref().unwrap_or("0644");
-    set_permissions(path, perms).await?;
+    set_permissions(root, path, perms).await?;
 
     // Set ownership
     if let Some(owner) = &config.owner {
-        set_ownership(path, owner).await?;
+        set_ownership(root, Path::new(&config.path), owner).await?;
+    }
+
+    Ok(())
+}
+
+/// The bytes to write: fetched from `source`, else the decoded `content`
+async fn file_content(config: &WriteFileConfig) -> Result<Vec<u8>, CloudInitError> {
+    if let Some(source) = &config.source {
+        match fetch_source(source).await {
+            Ok(body) => return Ok(body.into()),
+            Err(e) if !config.content.is_empty() => warn!(
+                "Could not fetch {} for {}, writing its content instead: {}",
+                source.uri, config.path, e
+            ),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(decode_content(&config.content, config.encoding.as_deref())?.into_bytes())
+}
+
+async fn fetch_source(source: &WriteFileSource) -> Result<Bytes, CloudInitError> {
+    debug!("Fetching {}", source.uri);
+    let client = crate::http::client_builder()?
+        .timeout(SOURCE_TIMEOUT)
+        .build()?;
+    let mut request = client.get(&source.uri);
+    for (name, value) in &source.headers {
+        request = request.header(name, value);
     }
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(CloudInitError::Network(format!(
+            "Failed to fetch {}: {}",
+            source.uri,
+            response.status()
+        )));
+    }
+    limits::read_body(
+        response,
+        &source.uri,
+        limits::current().max_include_size(),
+        "max_include_size",
+    )
+    .await
+}
 
+/// Check `content` against a `sha256:<hex>` checksum
+fn verify_checksum(path: &str, content: &[u8], checksum: &str) -> Result<(), CloudInitError> {
+    let Some(expected) = checksum.strip_prefix("sha256:") else {
+        return Err(CloudInitError::InvalidData(format!(
+            "Unsupported checksum for {}: {} (expected sha256:<hex>)",
+            path, checksum
+        )));
+    };
+    let actual: String = Sha256::digest(content)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(CloudInitError::InvalidData(format!(
+            "Checksum mismatch for {}: expected sha256:{}, got sha256:{}",
+            path,
+            expected.trim(),
+            actual
+        )));
+    }
     Ok(())
 }
 
@@ -109,48 +193,49 @@ fn decode_content(content: &str, encoding: Option<&str>) -> Result<String, Cloud
 }
 
 /// Decompress gzip data
+///
+/// Output is bounded by `user_data_limits.max_decompressed_size`.
 fn decompress_gzip(data: &[u8]) -> Result<String, CloudInitError> {
-    let mut decoder = GzDecoder::new(data);
-    let mut decompressed = String::new();
-    decoder
-        .read_to_string(&mut decompressed)
-        .map_err(|e| CloudInitError::InvalidData(format!("Failed to decompress gzip: {}", e)))?;
-    Ok(decompressed)
+    let mut decompressed = Vec::new();
+    limits::gunzip(
+        data,
+        &mut decompressed,
+        limits::current().max_decompressed_size(),
+    )?;
+    String::from_utf8(decompressed)
+        .map_err(|e| CloudInitError::InvalidData(format!("Invalid UTF-8: {}", e)))
 }
 
-async fn set_permissions(path: &Path, perms: &str) -> Result<(), CloudInitError> {
+async fn set_permissions(
+    root: &RootContext,
+    path: &Path,
+    perms: &str,
+) -> Result<(), CloudInitError> {
     debug!("Setting permissions {} on {:?}", perms, path);
 
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-
-        // Parse octal permission string (e.g., "0644")
-        let mode = u32::from_str_radix(perms.trim_start_matches('0'), 8)
-            .map_err(|e| CloudInitError::InvalidData(format!("Invalid permissions: {}", e)))?;
-
-        fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
-            .await
-            .map_err(CloudInitError::Io)?;
-    }
+    // Parse octal permission string (e.g., "0644")
+    let mode = u32::from_str_radix(perms.trim_start_matches('0'), 8)
+        .map_err(|e| CloudInitError::InvalidData(format!("Invalid permissions: {}", e)))?;
 
-    Ok(())
+    root.set_mode(path, mode).await
 }
 
-async fn set_ownership(path: &Path, owner: &str) -> Result<(), CloudInitError> {
+/// Change the owner of `path`, given as the path inside `root`
+async fn set_ownership(root: &RootContext, path: &Path, owner: &str) -> Result<(), CloudInitError> {
     debug!("Setting ownership {} on {:?}", owner, path);
 
-    let output = tokio::process::Command::new("chown")
-        .args([owner, &path.to_string_lossy()])
-        .output()
-        .await
-        .map_err(|e| CloudInitError::Command(e.to_string()))?;
+    let output = root
+        .runner()
+        .run(
+            &SystemCommand::new("chown")
+                .args([owner.to_string(), path.to_string_lossy().into_owned()]),
+        )
+        .await?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.is_success() {
         return Err(CloudInitError::Command(format!(
             "Failed to set ownership: {}",
-            stderr
+            output.stderr
         )));
     }
 
@@ -269,8 +354,10 @@ mod tests {
             permissions: Some("0644".to_string()),
             append: None,
             defer: None,
+            source: None,
+            checksum: None,
         };
-        write_file(&config).await.unwrap();
+        write_file(&RootContext::host(), &config).await.unwrap();
         assert_eq!(
             tokio::fs::read_to_string(&path).await.unwrap(),
             "hello world"
@@ -289,8 +376,10 @@ mod tests {
             permissions: Some("0644".to_string()),
             append: None,
             defer: None,
+            source: None,
+            checksum: None,
         };
-        write_file(&config).await.unwrap();
+        write_file(&RootContext::host(), &config).await.unwrap();
         assert!(path.exists());
     }
 
@@ -307,8 +396,10 @@ mod tests {
             permissions: Some("0644".to_string()),
             append: Some(true),
             defer: None,
+            source: None,
+            checksum: None,
         };
-        write_file(&config).await.unwrap();
+        write_file(&RootContext::host(), &config).await.unwrap();
         let content = tokio::fs::read_to_string(&path).await.unwrap();
         assert!(content.contains("first") && content.contains("second"));
     }
@@ -325,8 +416,10 @@ mod tests {
             permissions: Some("0644".to_string()),
             append: Some(true),
             defer: None,
+            source: None,
+            checksum: None,
         };
-        write_file(&config).await.unwrap();
+        write_file(&RootContext::host(), &config).await.unwrap();
         assert_eq!(tokio::fs::read_to_string(&path).await.unwrap(), "content");
     }
 
@@ -343,8 +436,10 @@ mod tests {
             permissions: Some("0644".to_string()),
             append: None,
             defer: None,
+            source: None,
+            checksum: None,
         };
-        write_file(&config).await.unwrap();
+        write_file(&RootContext::host(), &config).await.unwrap();
         assert_eq!(
             tokio::fs::read_to_string(&path).await.unwrap(),
             "base64 content"
@@ -363,8 +458,10 @@ mod tests {
             permissions: None,
             append: None,
             defer: None,
+            source: None,
+            checksum: None,
         };
-        write_file(&config).await.unwrap();
+        write_file(&RootContext::host(), &config).await.unwrap();
         #[cfg(unix)]
         {
             use std::os::unix::fs::PermissionsExt;
@@ -387,6 +484,8 @@ mod tests {
                 permissions: Some("0644".to_string()),
                 append: None,
                 defer: None,
+                source: None,
+                checksum: None,
             },
             WriteFileConfig {
                 path: deferred_path.to_string_lossy().to_string(),
@@ -396,9 +495,11 @@ mod tests {
                 permissions: Some("0644".to_string()),
                 append: None,
                 defer: Some(true),
+                source: None,
+                checksum: None,
             },
         ];
-        write_files(&files).await.unwrap();
+        write_files(&RootContext::host(), &files).await.unwrap();
         assert!(normal_path.exists());
         assert!(!deferred_path.exists());
     }
@@ -417,6 +518,8 @@ mod tests {
                 permissions: Some("0644".to_string()),
                 append: None,
                 defer: None,
+                source: None,
+                checksum: None,
             },
             WriteFileConfig {
                 path: deferred_path.to_string_lossy().to_string(),
@@ -426,9 +529,13 @@ mod tests {
                 permissions: Some("0644".to_string()),
                 append: None,
                 defer: Some(true),
+                source: None,
+                checksum: None,
             },
         ];
-        write_deferred_files(&files).await.unwrap();
+        write_deferred_files(&RootContext::host(), &files)
+            .await
+            .unwrap();
         assert!(!normal_path.exists());
         assert!(deferred_path.exists());
     }
@@ -438,7 +545,9 @@ mod tests {
         let tmp = TempDir::new().unwrap();
         let path = tmp.path().join("exec.sh");
         tokio::fs::write(&path, "#!/bin/sh").await.unwrap();
-        set_permissions(&path, "0755").await.unwrap();
+        set_permissions(&RootContext::host(), &path, "0755")
+            .await
+            .unwrap();
         #[cfg(unix)]
         {
             use std::os::unix::fs::PermissionsExt;
@@ -454,7 +563,9 @@ mod tests {
         let tmp = TempDir::new().unwrap();
         let path = tmp.path().join("secret.key");
         tokio::fs::write(&path, "secret").await.unwrap();
-        set_permissions(&path, "0600").await.unwrap();
+        set_permissions(&RootContext::host(), &path, "0600")
+            .await
+            .unwrap();
         #[cfg(unix)]
         {
             use std::os::unix::fs::PermissionsExt;
@@ -470,7 +581,11 @@ mod tests {
         let tmp = TempDir::new().unwrap();
         let path = tmp.path().join("file.txt");
         tokio::fs::write(&path, "data").await.unwrap();
-        assert!(set_permissions(&path, "not_octal").await.is_err());
+        assert!(
+            set_permissions(&RootContext::host(), &path, "not_octal")
+                .await
+                .is_err()
+        );
     }
 
     #[tokio::test]
@@ -479,19 +594,104 @@ mod tests {
         let path = tmp.path().join("owned.txt");
         tokio::fs::write(&path, "data").await.unwrap();
         assert!(
-            set_ownership(&path, "nonexistent_user_12345")
+            set_ownership(&RootContext::host(), &path, "nonexistent_user_12345")
                 .await
                 .is_err()
         );
     }
 
+    #[tokio::test]
+    async fn test_write_file_under_root() {
+        let tmp = TempDir::new().unwrap();
+        let config = WriteFileConfig {
+            path: "/etc/motd".to_string(),
+            content: "welcome\n".to_string(),
+            encoding: None,
+            owner: None,
+            permissions: None,
+            append: None,
+            defer: None,
+            source: None,
+            checksum: None,
+        };
+        write_file(&RootContext::new(tmp.path()), &config)
+            .await
+            .unwrap();
+        assert_eq!(
+            tokio::fs::read_to_string(tmp.path().join("etc/motd"))
+                .await
+                .unwrap(),
+            "welcome\n"
+        );
+    }
+
     #[tokio::test]
     async fn test_write_files_empty() {
-        write_files(&[]).await.unwrap();
+        write_files(&RootContext::host(), &[]).await.unwrap();
     }
 
     #[tokio::test]
     async fn test_write_deferred_files_empty() {
-        write_deferred_files(&[]).await.unwrap();
+        write_deferred_files(&RootContext::host(), &[])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_file_from_source() {
+        use wiremock::matchers::{header, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(path("/app.conf"))
+            .and(header("Authorization", "Bearer abc"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("test"))
+            .mount(&server)
+            .await;
+
+        let tmp = TempDir::new().unwrap();
+        let source = |uri: &str| {
+            Some(WriteFileSource {
+                uri: format!("{}{}", server.uri(), uri),
+                headers: [("Authorization".to_string(), "Bearer abc".to_string())].into(),
+            })
+        };
+        let mut config = WriteFileConfig {
+            path: tmp.path().join("app.conf").to_string_lossy().to_string(),
+            content: String::new(),
+            encoding: None,
+            owner: None,
+            permissions: None,
+            append: None,
+            defer: None,
+            source: source("/app.conf"),
+            // sha256 of "test"
+            checksum: Some(
+                "sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+                    .to_string(),
+            ),
+        };
+        write_file(&RootContext::host(), &config).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&config.path).unwrap(), "test");
+
+        // A missing source falls back to the content, which must match too
+        config.path = tmp
+            .path()
+            .join("fallback.conf")
+            .to_string_lossy()
+            .to_string();
+        config.source = source("/missing");
+        config.content = "test".to_string();
+        write_file(&RootContext::host(), &config).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&config.path).unwrap(), "test");
+
+        config.path = tmp.path().join("bad.conf").to_string_lossy().to_string();
+        config.content = "tampered".to_string();
+        let err = write_file(&RootContext::host(), &config).await.unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"));
+        assert!(!Path::new(&config.path).exists());
+
+        config.content = String::new();
+        assert!(write_file(&RootContext::host(), &config).await.is_err());
     }
 }
@@ -22,8 +22,10 @@
 
 use crate::CloudInitError;
 use crate::config::YumRepoConfig;
+use crate::root::RootContext;
 use std::collections::HashMap;
 use std::fmt::Write as FmtWrite;
+use std::path::Path;
 use tracing::{debug, info, warn};
 
 /// Directory where YUM `.repo` files are stored.
@@ -33,7 +35,10 @@ const YUM_REPOS_DIR: &str = "/etc/yum.repos.d";
 ///
 /// The map key is used as the repo ID (i.e. the section header) and as the
 /// file name: `<id>.repo`.  Invalid/empty entries are skipped with a warning.
-pub async fn add_yum_repos(repos: &HashMap<String, YumRepoConfig>) -> Result<(), CloudInitError> {
+pub async fn add_yum_repos(
+    root: &RootContext,
+    repos: &HashMap<String, YumRepoConfig>,
+) -> Result<(), CloudInitError> {
     if repos.is_empty() {
         return Ok(());
     }
@@ -42,15 +47,16 @@ pub async fn add_yum_repos(repos: &HashMap<String, YumRepoConfig>) -> Result<(),
 
     // Ensure the repos directory exists (it should on any RPM-based system,
     // but we create it defensively so tests pass on non-RHEL hosts too).
-    tokio::fs::create_dir_all(YUM_REPOS_DIR)
+    let repos_dir = root.path(YUM_REPOS_DIR);
+    root.create_dir_all(&repos_dir)
         .await
         .map_err(|e| CloudInitError::Module {
             module: "yum_add_repo".to_string(),
-            message: format!("failed to create {}: {}", YUM_REPOS_DIR, e),
+            message: format!("failed to create {}: {}", repos_dir.display(), e),
         })?;
 
     for (id, repo_config) in repos {
-        if let Err(e) = write_repo_file(id, repo_config).await {
+        if let Err(e) = write_repo_file(root, &repos_dir, id, repo_config).await {
             warn!("yum_add_repo: failed to write repo '{}': {}", id, e);
         }
     }
@@ -60,8 +66,13 @@ pub async fn add_yum_repos(repos: &HashMap<String, YumRepoConfig>) -> Result<(),
 
 /// Write a single `.repo` file for the given repo ID and configuration.
 ///
-/// The file is written to `<YUM_REPOS_DIR>/<id>.repo`.
-pub async fn write_repo_file(id: &str, config: &YumRepoConfig) -> Result<(), CloudInitError> {
+/// The file is written to `<repos_dir>/<id>.repo`.
+pub async fn write_repo_file(
+    root: &RootContext,
+    repos_dir: &Path,
+    id: &str,
+    config: &YumRepoConfig,
+) -> Result<(), CloudInitError> {
     // At least one URL source must be present
     if config.baseurl.is_none() && config.mirrorlist.is_none() && config.metalink.is_none() {
         return Err(CloudInitError::Module {
@@ -74,18 +85,18 @@ pub async fn write_repo_file(id: &str, config: &YumRepoConfig) -> Result<(), Clo
     }
 
     let content = build_repo_content(id, config);
-    let path = format!("{}/{}.repo", YUM_REPOS_DIR, id);
+    let path = repos_dir.join(format!("{}.repo", id));
 
-    debug!("yum_add_repo: writing {}", path);
+    debug!("yum_add_repo: writing {}", path.display());
 
-    tokio::fs::write(&path, content)
+    root.write_file(&path, content)
         .await
         .map_err(|e| CloudInitError::Module {
             module: "yum_add_repo".to_string(),
-            message: format!("failed to write {}: {}", path, e),
+            message: format!("failed to write {}: {}", path.display(), e),
         })?;
 
-    info!("yum_add_repo: wrote {}", path);
+    info!("yum_add_repo: wrote {}", path.display());
     Ok(())
 }
 
@@ -0,0 +1,239 @@
+//! Zypper repository management module
+//!
+//! Implements the `zypper` cloud-config key: writes a `.repo` file to
+//! `/etc/zypp/repos.d/` for each entry of `repos`, and sets the `config`
+//! entries in `/etc/zypp/zypp.conf`, between markers so that later runs
+//! replace them instead of adding more.
+//!
+//! # Cloud-config example
+//!
+//! ```yaml
+//! zypper:
+//!   repos:
+//!     - id: opensuse-oss
+//!       name: openSUSE OSS
+//!       baseurl: http://download.opensuse.org/distribution/leap/15.6/repo/oss/
+//!       enabled: true
+//!       autorefresh: true
+//!   config:
+//!     download.use_deltarpm: false
+//! ```
+
+use crate::CloudInitError;
+use crate::config::{ZypperConfig, ZypperRepoConfig};
+use crate::modules::scalar_string;
+use crate::root::RootContext;
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+/// Directory zypper reads `.repo` files from
+const ZYPP_REPOS_DIR: &str = "/etc/zypp/repos.d";
+
+/// zypper's main configuration file
+const ZYPP_CONF: &str = "/etc/zypp/zypp.conf";
+
+/// Start of the settings written to zypp.conf
+const BEGIN_MARKER: &str = "# BEGIN cloud-init-rs zypper config";
+
+/// End of the settings written to zypp.conf
+const END_MARKER: &str = "# END cloud-init-rs zypper config";
+
+/// zypp.conf settings that cannot be changed from cloud-config
+const IGNORED_SETTINGS: &[&str] = &["configdir"];
+
+/// Write repositories and zypp.conf settings
+pub async fn configure_zypper(
+    root: &RootContext,
+    config: &ZypperConfig,
+) -> Result<(), CloudInitError> {
+    if !config.repos.is_empty() {
+        let repos_dir = root.path(ZYPP_REPOS_DIR);
+        root.create_dir_all(&repos_dir).await?;
+        for repo in &config.repos {
+            match build_repo_content(repo) {
+                Ok(content) => {
+                    let path = repos_dir.join(format!("{}.repo", repo.id));
+                    root.write_file(&path, content).await?;
+                    info!("zypper_add_repo: wrote {}", path.display());
+                }
+                Err(e) => warn!("zypper_add_repo: skipping repo '{}': {}", repo.id, e),
+            }
+        }
+    }
+
+    if !config.config.is_empty() {
+        let path = root.path(ZYPP_CONF);
+        let existing = fs::read_to_string(&path).await.unwrap_or_default();
+        let updated = update_zypp_conf(&existing, config)?;
+        if updated != existing {
+            if let Some(dir) = path.parent() {
+                root.create_dir_all(dir).await?;
+            }
+            root.write_file(&path, updated).await?;
+            info!("zypper_add_repo: updated {}", ZYPP_CONF);
+        } else {
+            debug!("zypper_add_repo: {} is up to date", ZYPP_CONF);
+        }
+    }
+    Ok(())
+}
+
+/// Build the `.repo` file content for a repository
+fn build_repo_content(repo: &ZypperRepoConfig) -> Result<String, CloudInitError> {
+    let invalid = |message: &str| CloudInitError::Module {
+        module: "zypper_add_repo".to_string(),
+        message: message.to_string(),
+    };
+    if repo.id.is_empty() || repo.id.contains(['/', ' ', '\t']) || repo.id.starts_with('.') {
+        return Err(invalid("id must be a plain name"));
+    }
+    let Some(baseurl) = &repo.baseurl else {
+        return Err(invalid("baseurl is required"));
+    };
+
+    let mut out = format!(
+        "# Written by cloud-init-rs\n[{}]\nname={}\nbaseurl={}\n",
+        repo.id,
+        repo.name.as_deref().unwrap_or(&repo.id),
+        baseurl
+    );
+    for (key, value) in &repo.settings {
+        let value =
+            repo_value(value).ok_or_else(|| invalid(&format!("invalid value for {}", key)))?;
+        out.push_str(&format!("{}={}\n", key, value));
+    }
+    Ok(out)
+}
+
+/// A `.repo` value: booleans as `1`/`0`, lists on continuation lines
+fn repo_value(value: &serde_yaml::Value) -> Option<String> {
+    match value {
+        serde_yaml::Value::Bool(b) => Some(if *b { "1" } else { "0" }.to_string()),
+        serde_yaml::Value::Sequence(items) => {
+            let items: Option<Vec<String>> = items.iter().map(scalar_string).collect();
+            Some(items?.join("\n    "))
+        }
+        other => scalar_string(other),
+    }
+}
+
+/// zypp.conf with the managed settings replaced by `config`'s
+fn update_zypp_conf(existing: &str, config: &ZypperConfig) -> Result<String, CloudInitError> {
+    let mut lines: Vec<&str> = Vec::new();
+    let mut managed = false;
+    for line in existing.lines() {
+        match line.trim() {
+            BEGIN_MARKER => managed = true,
+            END_MARKER => managed = false,
+            _ if !managed => lines.push(line),
+            _ => {}
+        }
+    }
+    let mut content = lines.join("\n");
+    if !content.is_empty() {
+        content.push('\n');
+    }
+
+    let mut settings = Vec::new();
+    for (key, value) in &config.config {
+        if IGNORED_SETTINGS.contains(&key.as_str()) {
+            warn!("zypper_add_repo: {} cannot be changed; ignored", key);
+            continue;
+        }
+        let value = scalar_string(value).ok_or_else(|| {
+            CloudInitError::InvalidData(format!("Invalid zypper config value for {}", key))
+        })?;
+        settings.push(format!("{}={}", key, value));
+    }
+    if !settings.is_empty() {
+        content.push_str(&format!(
+            "{}\n{}\n{}\n",
+            BEGIN_MARKER,
+            settings.join("\n"),
+            END_MARKER
+        ));
+    }
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn config(yaml: &str) -> ZypperConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_build_repo_content() {
+        let config = config(
+            r#"
+repos:
+  - id: oss
+    baseurl: http://download.opensuse.org/oss/
+    enabled: true
+    autorefresh: false
+    priority: 90
+    gpgkey: [https://example.com/a.key, https://example.com/b.key]
+"#,
+        );
+        assert_eq!(
+            build_repo_content(&config.repos[0]).unwrap(),
+            "# Written by cloud-init-rs\n\
+             [oss]\n\
+             name=oss\n\
+             baseurl=http://download.opensuse.org/oss/\n\
+             autorefresh=0\n\
+             enabled=1\n\
+             gpgkey=https://example.com/a.key\n    https://example.com/b.key\n\
+             priority=90\n"
+        );
+    }
+
+    #[test]
+    fn test_build_repo_content_rejects_invalid() {
+        let repo = |id: &str, baseurl: Option<&str>| ZypperRepoConfig {
+            id: id.to_string(),
+            baseurl: baseurl.map(str::to_string),
+            ..Default::default()
+        };
+        assert!(build_repo_content(&repo("oss", None)).is_err());
+        assert!(build_repo_content(&repo("../oss", Some("http://x/"))).is_err());
+        assert!(build_repo_content(&repo("", Some("http://x/"))).is_err());
+    }
+
+    #[test]
+    fn test_update_zypp_conf_replaces_managed_block() {
+        let config = config("config:\n  download.use_deltarpm: false\n  configdir: /tmp\n");
+        let first = update_zypp_conf("[main]\narch = x86_64\n", &config).unwrap();
+        assert_eq!(
+            first,
+            "[main]\n\
+             arch = x86_64\n\
+             # BEGIN cloud-init-rs zypper config\n\
+             download.use_deltarpm=false\n\
+             # END cloud-init-rs zypper config\n"
+        );
+        assert_eq!(update_zypp_conf(&first, &config).unwrap(), first);
+    }
+
+    #[tokio::test]
+    async fn test_configure_zypper_under_root() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let config = config(
+            "repos:\n  - id: oss\n    baseurl: http://x/\n  - id: broken\n\
+             config:\n  solver.onlyRequires: true\n",
+        );
+        configure_zypper(&root, &config).await.unwrap();
+
+        assert!(root.path("/etc/zypp/repos.d/oss.repo").exists());
+        assert!(!root.path("/etc/zypp/repos.d/broken.repo").exists());
+        assert!(
+            std::fs::read_to_string(root.path(ZYPP_CONF))
+                .unwrap()
+                .contains("solver.onlyRequires=true\n")
+        );
+    }
+}
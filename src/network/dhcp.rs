@@ -0,0 +1,677 @@
+//! Minimal DHCPv4 client for ephemeral provisioning networking
+//!
+//! Implements just enough of RFC 2131 to obtain a lease while the Local
+//! stage crawls metadata: DISCOVER, OFFER, REQUEST, ACK (or NAK). The lease
+//! is never renewed or released - the ephemeral network is torn down long
+//! before it expires - and nothing is written to the system. Classless
+//! static routes (option 121, RFC 3442) are decoded because several clouds
+//! rely on them to reach the metadata service.
+//!
+//! This replaces shelling out to `dhclient`, which is often missing from
+//! minimal images and initramfs environments.
+
+use super::ephemeral::{DhcpLease, StaticRoute};
+use crate::CloudInitError;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, timeout_at};
+use tracing::{debug, info};
+
+/// DHCP server port
+pub const SERVER_PORT: u16 = 67;
+
+/// DHCP client port
+pub const CLIENT_PORT: u16 = 68;
+
+/// Magic cookie that starts the options field
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// BOOTP fixed header length (before the magic cookie)
+const HEADER_LEN: usize = 236;
+
+const OP_BOOTREQUEST: u8 = 1;
+const OP_BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+/// Ask servers to broadcast replies, as we have no address yet
+const FLAG_BROADCAST: u16 = 0x8000;
+
+// Option codes
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_MTU: u8 = 26;
+const OPT_BROADCAST: u8 = 28;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAM_REQUEST: u8 = 55;
+const OPT_CLIENT_ID: u8 = 61;
+const OPT_CLASSLESS_ROUTES: u8 = 121;
+const OPT_END: u8 = 255;
+
+/// DHCP message types (option 53)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+    Inform = 8,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => Self::Discover,
+            2 => Self::Offer,
+            3 => Self::Request,
+            4 => Self::Decline,
+            5 => Self::Ack,
+            6 => Self::Nak,
+            7 => Self::Release,
+            8 => Self::Inform,
+            _ => return None,
+        })
+    }
+}
+
+/// A DHCP message (only the fields this client uses)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhcpMessage {
+    /// BOOTREQUEST or BOOTREPLY
+    pub op: u8,
+    /// Transaction ID
+    pub xid: u32,
+    /// Flags (broadcast bit)
+    pub flags: u16,
+    /// Client address (when already configured)
+    pub ciaddr: Ipv4Addr,
+    /// "Your" address offered by the server
+    pub yiaddr: Ipv4Addr,
+    /// Next server address
+    pub siaddr: Ipv4Addr,
+    /// Client hardware address
+    pub chaddr: [u8; 6],
+    /// Options in wire order, excluding pad and end
+    pub options: Vec<(u8, Vec<u8>)>,
+}
+
+impl DhcpMessage {
+    /// Create a client request of the given type
+    pub fn request(xid: u32, mac: [u8; 6], message_type: MessageType) -> Self {
+        let mut client_id = vec![HTYPE_ETHERNET];
+        client_id.extend_from_slice(&mac);
+
+        Self {
+            op: OP_BOOTREQUEST,
+            xid,
+            flags: FLAG_BROADCAST,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr: Ipv4Addr::UNSPECIFIED,
+            siaddr: Ipv4Addr::UNSPECIFIED,
+            chaddr: mac,
+            options: vec![
+                (OPT_MESSAGE_TYPE, vec![message_type as u8]),
+                (OPT_CLIENT_ID, client_id),
+                (
+                    OPT_PARAM_REQUEST,
+                    vec![
+                        OPT_SUBNET_MASK,
+                        OPT_ROUTER,
+                        OPT_DNS,
+                        OPT_MTU,
+                        OPT_BROADCAST,
+                        OPT_LEASE_TIME,
+                        OPT_CLASSLESS_ROUTES,
+                    ],
+                ),
+            ],
+        }
+    }
+
+    /// First value of an option
+    pub fn option(&self, code: u8) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Message type from option 53
+    pub fn message_type(&self) -> Option<MessageType> {
+        self.option(OPT_MESSAGE_TYPE)
+            .and_then(|v| v.first())
+            .and_then(|&t| MessageType::from_u8(t))
+    }
+
+    /// Serialize to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; HEADER_LEN];
+        buf[0] = self.op;
+        buf[1] = HTYPE_ETHERNET;
+        buf[2] = 6; // hlen
+        buf[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        buf[10..12].copy_from_slice(&self.flags.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.ciaddr.octets());
+        buf[16..20].copy_from_slice(&self.yiaddr.octets());
+        buf[20..24].copy_from_slice(&self.siaddr.octets());
+        buf[28..34].copy_from_slice(&self.chaddr);
+
+        buf.extend_from_slice(&MAGIC_COOKIE);
+        for (code, value) in &self.options {
+            // Longer values are split into consecutive options (RFC 3396)
+            for chunk in value.chunks(255) {
+                buf.push(*code);
+                buf.push(chunk.len() as u8);
+                buf.extend_from_slice(chunk);
+            }
+        }
+        buf.push(OPT_END);
+
+        // Some servers drop packets shorter than a BOOTP minimum
+        if buf.len() < 300 {
+            buf.resize(300, OPT_PAD);
+        }
+        buf
+    }
+
+    /// Parse from wire format
+    pub fn decode(data: &[u8]) -> Result<Self, CloudInitError> {
+        let invalid = |msg: &str| CloudInitError::InvalidData(format!("DHCP: {}", msg));
+
+        if data.len() < HEADER_LEN + MAGIC_COOKIE.len() {
+            return Err(invalid("packet too short"));
+        }
+        if data[HEADER_LEN..HEADER_LEN + 4] != MAGIC_COOKIE {
+            return Err(invalid("missing magic cookie"));
+        }
+
+        let addr = |at: usize| Ipv4Addr::new(data[at], data[at + 1], data[at + 2], data[at + 3]);
+        let mut chaddr = [0u8; 6];
+        chaddr.copy_from_slice(&data[28..34]);
+
+        let mut options: Vec<(u8, Vec<u8>)> = Vec::new();
+        let mut pos = HEADER_LEN + 4;
+        while pos < data.len() {
+            let code = data[pos];
+            pos += 1;
+            match code {
+                OPT_PAD => continue,
+                OPT_END => break,
+                _ => {}
+            }
+            let len = *data.get(pos).ok_or_else(|| invalid("truncated option"))? as usize;
+            pos += 1;
+            let value = data
+                .get(pos..pos + len)
+                .ok_or_else(|| invalid("truncated option"))?;
+            pos += len;
+
+            // Concatenate split options (RFC 3396)
+            match options.iter_mut().find(|(c, _)| *c == code) {
+                Some((_, existing)) => existing.extend_from_slice(value),
+                None => options.push((code, value.to_vec())),
+            }
+        }
+
+        Ok(Self {
+            op: data[0],
+            xid: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            flags: u16::from_be_bytes([data[10], data[11]]),
+            ciaddr: addr(12),
+            yiaddr: addr(16),
+            siaddr: addr(20),
+            chaddr,
+            options,
+        })
+    }
+
+    /// Build a lease from an ACK
+    pub fn to_lease(&self, interface: &str) -> DhcpLease {
+        let ipv4 = |v: &[u8]| Ipv4Addr::new(v[0], v[1], v[2], v[3]);
+        let addrs = |code| {
+            self.option(code)
+                .map(|v| v.chunks_exact(4).map(ipv4).collect::<Vec<_>>())
+                .unwrap_or_default()
+        };
+
+        let routes = self
+            .option(OPT_CLASSLESS_ROUTES)
+            .map(parse_classless_routes)
+            .unwrap_or_default();
+        // RFC 3442: the router option must be ignored when option 121 is present
+        let router = if routes.is_empty() {
+            addrs(OPT_ROUTER).first().copied()
+        } else {
+            None
+        };
+
+        DhcpLease {
+            interface: interface.to_string(),
+            address: self.yiaddr,
+            prefix_len: addrs(OPT_SUBNET_MASK)
+                .first()
+                .map_or(32, |m| u32::from(*m).count_ones() as u8),
+            router,
+            broadcast: addrs(OPT_BROADCAST).first().copied(),
+            mtu: self
+                .option(OPT_MTU)
+                .filter(|v| v.len() == 2)
+                .map(|v| u16::from_be_bytes([v[0], v[1]])),
+            dns_servers: addrs(OPT_DNS),
+            static_routes: routes,
+        }
+    }
+}
+
+/// Decode RFC 3442 classless static routes
+///
+/// Each route is a prefix length, the significant octets of the
+/// destination, then a 4-byte gateway. Malformed trailing data is ignored.
+pub fn parse_classless_routes(data: &[u8]) -> Vec<StaticRoute> {
+    let mut routes = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let prefix_len = data[pos];
+        if prefix_len > 32 {
+            break;
+        }
+        let significant = usize::from(prefix_len).div_ceil(8);
+        let Some(chunk) = data.get(pos + 1..pos + 1 + significant + 4) else {
+            break;
+        };
+
+        let mut dest = [0u8; 4];
+        dest[..significant].copy_from_slice(&chunk[..significant]);
+        let gw = &chunk[significant..];
+
+        routes.push(StaticRoute {
+            destination: Ipv4Addr::from(dest),
+            prefix_len,
+            gateway: Ipv4Addr::new(gw[0], gw[1], gw[2], gw[3]),
+        });
+        pos += 1 + significant + 4;
+    }
+
+    routes
+}
+
+/// Parse a MAC address like `52:54:00:12:34:56`
+pub fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let mut out = [0u8; 6];
+    let mut parts = mac.trim().split(':');
+    for byte in &mut out {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    parts.next().is_none().then_some(out)
+}
+
+/// DHCPv4 client for a single interface
+#[derive(Debug, Clone)]
+pub struct DhcpClient {
+    interface: String,
+    mac: [u8; 6],
+    bind_addr: SocketAddr,
+    server_addr: SocketAddr,
+    timeout: Duration,
+    attempts: u32,
+}
+
+impl DhcpClient {
+    /// Create a client for `interface`, reading its MAC from sysfs
+    pub async fn for_interface(interface: &str) -> Result<Self, CloudInitError> {
+        let path = format!("/sys/class/net/{}/address", interface);
+        let mac = tokio::fs::read_to_string(&path).await?;
+        let mac = parse_mac(&mac).ok_or_else(|| {
+            CloudInitError::InvalidData(format!("Invalid MAC address for {}: {}", interface, mac))
+        })?;
+        Ok(Self::new(interface, mac))
+    }
+
+    /// Create a client with an explicit MAC address
+    pub fn new(interface: &str, mac: [u8; 6]) -> Self {
+        Self {
+            interface: interface.to_string(),
+            mac,
+            bind_addr: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, CLIENT_PORT).into(),
+            server_addr: SocketAddrV4::new(Ipv4Addr::BROADCAST, SERVER_PORT).into(),
+            timeout: Duration::from_secs(5),
+            attempts: 3,
+        }
+    }
+
+    /// Use different socket addresses (for testing against a mock server)
+    pub fn with_addresses(mut self, bind_addr: SocketAddr, server_addr: SocketAddr) -> Self {
+        self.bind_addr = bind_addr;
+        self.server_addr = server_addr;
+        self
+    }
+
+    /// Per-attempt timeout and number of attempts
+    pub fn with_timeout(mut self, timeout: Duration, attempts: u32) -> Self {
+        self.timeout = timeout;
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// Run DISCOVER/OFFER/REQUEST/ACK and return the lease
+    pub async fn obtain_lease(&self) -> Result<DhcpLease, CloudInitError> {
+        let socket = self.bind()?;
+        let mut last_err = CloudInitError::Timeout(format!("DHCP lease on {}", self.interface));
+
+        for attempt in 1..=self.attempts {
+            let xid = transaction_id(&self.mac, attempt);
+            match self.exchange(&socket, xid).await {
+                Ok(lease) => {
+                    info!(
+                        "DHCP lease {}/{} on {}",
+                        lease.address, lease.prefix_len, self.interface
+                    );
+                    return Ok(lease);
+                }
+                Err(e) => {
+                    debug!(
+                        "DHCP attempt {} on {} failed: {}",
+                        attempt, self.interface, e
+                    );
+                    last_err = e;
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    fn bind(&self) -> Result<UdpSocket, CloudInitError> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+        // Only on the real client port: tests bind to loopback
+        #[cfg(target_os = "linux")]
+        if self.bind_addr.port() == CLIENT_PORT {
+            socket.bind_device(Some(self.interface.as_bytes()))?;
+        }
+        socket.bind(&self.bind_addr.into())?;
+        Ok(UdpSocket::from_std(socket.into())?)
+    }
+
+    async fn exchange(&self, socket: &UdpSocket, xid: u32) -> Result<DhcpLease, CloudInitError> {
+        let discover = DhcpMessage::request(xid, self.mac, MessageType::Discover);
+        socket.send_to(&discover.encode(), self.server_addr).await?;
+        let offer = self.receive(socket, xid, &[MessageType::Offer]).await?;
+
+        let server_id = offer.option(OPT_SERVER_ID).map(<[u8]>::to_vec);
+        let mut request = DhcpMessage::request(xid, self.mac, MessageType::Request);
+        request
+            .options
+            .push((OPT_REQUESTED_IP, offer.yiaddr.octets().to_vec()));
+        if let Some(server_id) = server_id {
+            request.options.push((OPT_SERVER_ID, server_id));
+        }
+        socket.send_to(&request.encode(), self.server_addr).await?;
+
+        let reply = self
+            .receive(socket, xid, &[MessageType::Ack, MessageType::Nak])
+            .await?;
+        if reply.message_type() == Some(MessageType::Nak) {
+            return Err(CloudInitError::Network(format!(
+                "DHCP server refused lease for {}",
+                offer.yiaddr
+            )));
+        }
+        Ok(reply.to_lease(&self.interface))
+    }
+
+    /// Wait for a reply to `xid` of one of the expected types
+    async fn receive(
+        &self,
+        socket: &UdpSocket,
+        xid: u32,
+        expected: &[MessageType],
+    ) -> Result<DhcpMessage, CloudInitError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = vec![0u8; 1500];
+
+        loop {
+            let (len, from) = timeout_at(deadline, socket.recv_from(&mut buf))
+                .await
+                .map_err(|_| {
+                    CloudInitError::Timeout(format!("DHCP reply on {}", self.interface))
+                })??;
+
+            // Other clients' traffic and junk are expected on a shared segment
+            let Ok(message) = DhcpMessage::decode(&buf[..len]) else {
+                continue;
+            };
+            if message.op == OP_BOOTREPLY
+                && message.xid == xid
+                && message.chaddr == self.mac
+                && message
+                    .message_type()
+                    .is_some_and(|t| expected.contains(&t))
+            {
+                debug!("DHCP {:?} from {}", message.message_type(), from);
+                return Ok(message);
+            }
+        }
+    }
+}
+
+/// Transaction ID unique enough to tell our replies apart
+fn transaction_id(mac: &[u8; 6], attempt: u32) -> u32 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let mac_bits = u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]]);
+    nanos ^ mac_bits ^ attempt.rotate_left(24)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    /// Build a server reply to `request`
+    fn reply(request: &DhcpMessage, message_type: MessageType) -> DhcpMessage {
+        DhcpMessage {
+            op: OP_BOOTREPLY,
+            xid: request.xid,
+            flags: request.flags,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr: Ipv4Addr::new(10, 0, 2, 15),
+            siaddr: Ipv4Addr::new(10, 0, 2, 2),
+            chaddr: request.chaddr,
+            options: vec![
+                (OPT_MESSAGE_TYPE, vec![message_type as u8]),
+                (OPT_SERVER_ID, vec![10, 0, 2, 2]),
+                (OPT_SUBNET_MASK, vec![255, 255, 255, 0]),
+                (OPT_ROUTER, vec![10, 0, 2, 2]),
+                (OPT_DNS, vec![10, 0, 2, 3, 1, 1, 1, 1]),
+                (OPT_MTU, 1460u16.to_be_bytes().to_vec()),
+                (OPT_LEASE_TIME, 86400u32.to_be_bytes().to_vec()),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        let msg = DhcpMessage::request(0xdead_beef, MAC, MessageType::Discover);
+        let wire = msg.encode();
+        assert!(wire.len() >= 300);
+        assert_eq!(wire[HEADER_LEN..HEADER_LEN + 4], MAGIC_COOKIE);
+
+        let decoded = DhcpMessage::decode(&wire).unwrap();
+        assert_eq!(decoded, msg);
+        assert_eq!(decoded.message_type(), Some(MessageType::Discover));
+        assert_eq!(decoded.flags, FLAG_BROADCAST);
+    }
+
+    #[test]
+    fn test_decode_rejects_garbage() {
+        assert!(DhcpMessage::decode(&[0u8; 10]).is_err());
+        assert!(DhcpMessage::decode(&[0u8; 300]).is_err());
+
+        let mut wire = DhcpMessage::request(1, MAC, MessageType::Offer).encode();
+        wire.truncate(HEADER_LEN + 6);
+        assert!(DhcpMessage::decode(&wire).is_err());
+    }
+
+    #[test]
+    fn test_decode_concatenates_split_options() {
+        let mut msg = DhcpMessage::request(1, MAC, MessageType::Ack);
+        msg.options.push((OPT_DNS, [1u8, 1, 1, 1].repeat(70)));
+        let decoded = DhcpMessage::decode(&msg.encode()).unwrap();
+        assert_eq!(decoded.option(OPT_DNS).unwrap().len(), 280);
+    }
+
+    #[test]
+    fn test_to_lease() {
+        let request = DhcpMessage::request(7, MAC, MessageType::Request);
+        let lease = reply(&request, MessageType::Ack).to_lease("eth0");
+        assert_eq!(lease.address, Ipv4Addr::new(10, 0, 2, 15));
+        assert_eq!(lease.prefix_len, 24);
+        assert_eq!(lease.router, Some(Ipv4Addr::new(10, 0, 2, 2)));
+        assert_eq!(lease.mtu, Some(1460));
+        assert_eq!(lease.dns_servers.len(), 2);
+        assert!(lease.static_routes.is_empty());
+    }
+
+    #[test]
+    fn test_classless_routes_override_router() {
+        let request = DhcpMessage::request(7, MAC, MessageType::Request);
+        let mut ack = reply(&request, MessageType::Ack);
+        // 169.254.169.254/32 via 10.0.2.1, default via 10.0.2.2
+        ack.options.push((
+            OPT_CLASSLESS_ROUTES,
+            vec![32, 169, 254, 169, 254, 10, 0, 2, 1, 0, 10, 0, 2, 2],
+        ));
+
+        let lease = ack.to_lease("eth0");
+        assert_eq!(lease.router, None);
+        assert_eq!(
+            lease.static_routes,
+            vec![
+                StaticRoute {
+                    destination: Ipv4Addr::new(169, 254, 169, 254),
+                    prefix_len: 32,
+                    gateway: Ipv4Addr::new(10, 0, 2, 1),
+                },
+                StaticRoute {
+                    destination: Ipv4Addr::UNSPECIFIED,
+                    prefix_len: 0,
+                    gateway: Ipv4Addr::new(10, 0, 2, 2),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_classless_routes_partial_octets() {
+        // 10.16.0.0/12 via 192.168.0.1, then truncated junk
+        let routes = parse_classless_routes(&[12, 10, 16, 192, 168, 0, 1, 24, 1]);
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].destination, Ipv4Addr::new(10, 16, 0, 0));
+        assert_eq!(routes[0].prefix_len, 12);
+        assert!(parse_classless_routes(&[33, 0, 0, 0, 0]).is_empty());
+    }
+
+    #[test]
+    fn test_parse_mac() {
+        assert_eq!(parse_mac("52:54:00:12:34:56\n"), Some(MAC));
+        assert_eq!(parse_mac("52:54:00:12:34"), None);
+        assert_eq!(parse_mac("52:54:00:12:34:56:78"), None);
+        assert_eq!(parse_mac("zz:54:00:12:34:56"), None);
+    }
+
+    /// Spawn a mock server answering DISCOVER and REQUEST
+    async fn mock_server(final_reply: MessageType, ignore_first: bool) -> SocketAddr {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 1500];
+            let mut ignored = !ignore_first;
+            loop {
+                let Ok((len, from)) = server.recv_from(&mut buf).await else {
+                    return;
+                };
+                let request = DhcpMessage::decode(&buf[..len]).unwrap();
+                if !ignored {
+                    ignored = true;
+                    continue;
+                }
+
+                // Noise first: a reply for someone else
+                let mut other = reply(&request, MessageType::Offer);
+                other.xid ^= 1;
+                server.send_to(&other.encode(), from).await.unwrap();
+
+                let answer = match request.message_type() {
+                    Some(MessageType::Discover) => reply(&request, MessageType::Offer),
+                    Some(MessageType::Request) => {
+                        assert_eq!(request.option(OPT_REQUESTED_IP), Some(&[10, 0, 2, 15][..]));
+                        assert_eq!(request.option(OPT_SERVER_ID), Some(&[10, 0, 2, 2][..]));
+                        reply(&request, final_reply)
+                    }
+                    other => panic!("unexpected {other:?}"),
+                };
+                server.send_to(&answer.encode(), from).await.unwrap();
+            }
+        });
+
+        addr
+    }
+
+    fn test_client(server: SocketAddr) -> DhcpClient {
+        DhcpClient::new("eth0", MAC)
+            .with_addresses("127.0.0.1:0".parse().unwrap(), server)
+            .with_timeout(Duration::from_millis(300), 2)
+    }
+
+    #[tokio::test]
+    async fn test_obtain_lease_from_mock_server() {
+        let server = mock_server(MessageType::Ack, false).await;
+        let lease = test_client(server).obtain_lease().await.unwrap();
+        assert_eq!(lease.interface, "eth0");
+        assert_eq!(lease.address, Ipv4Addr::new(10, 0, 2, 15));
+        assert_eq!(lease.router, Some(Ipv4Addr::new(10, 0, 2, 2)));
+    }
+
+    #[tokio::test]
+    async fn test_obtain_lease_retries_after_timeout() {
+        let server = mock_server(MessageType::Ack, true).await;
+        let lease = test_client(server).obtain_lease().await.unwrap();
+        assert_eq!(lease.prefix_len, 24);
+    }
+
+    #[tokio::test]
+    async fn test_obtain_lease_nak() {
+        let server = mock_server(MessageType::Nak, false).await;
+        let err = test_client(server).obtain_lease().await.unwrap_err();
+        assert!(err.to_string().contains("refused"));
+    }
+
+    #[tokio::test]
+    async fn test_obtain_lease_no_server() {
+        // A bound socket that never answers
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = silent.local_addr().unwrap();
+        let client = DhcpClient::new("eth0", MAC)
+            .with_addresses("127.0.0.1:0".parse().unwrap(), addr)
+            .with_timeout(Duration::from_millis(50), 1);
+        let err = client.obtain_lease().await.unwrap_err();
+        assert!(matches!(err, CloudInitError::Timeout(_)));
+    }
+}
@@ -0,0 +1,441 @@
+//! ENI (/etc/network/interfaces) parsing
+//!
+//! Old NoCloud seeds carry their network config in ifupdown format under
+//! the meta-data `network-interfaces` key. The stanzas are read into a v1
+//! config, which converts to v2 like any other v1 config.
+//!
+//! Supported: `auto`/`allow-*` lines, `iface` stanzas with the `dhcp`,
+//! `static`, `manual`, `auto` and `loopback` methods, addressing and DNS
+//! options, `mtu`, `hwaddress`, and the usual bond, bridge and VLAN
+//! options. `source`, `mapping` and `up`/`down` hooks cannot be followed
+//! and are ignored.
+
+use super::NetworkConfig;
+use super::v1::{
+    BondConfigV1, BridgeConfigV1, ConfigItem, NameserverConfigV1, NetworkConfigV1, PhysicalConfig,
+    SubnetConfig, VlanConfigV1,
+};
+use crate::CloudInitError;
+use std::collections::BTreeMap;
+use tracing::debug;
+
+/// One interface, from all of its `iface` stanzas
+#[derive(Debug, Default)]
+struct Interface {
+    subnets: Vec<SubnetConfig>,
+    options: BTreeMap<String, String>,
+}
+
+impl Interface {
+    fn option(&self, names: &[&str]) -> Option<&str> {
+        names
+            .iter()
+            .find_map(|name| self.options.get(*name))
+            .map(String::as_str)
+    }
+
+    fn list(&self, names: &[&str]) -> Vec<String> {
+        self.option(names)
+            .map(|value| {
+                value
+                    .split_whitespace()
+                    .filter(|word| *word != "none")
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn number<T: std::str::FromStr>(
+        &self,
+        name: &str,
+        names: &[&str],
+    ) -> Result<Option<T>, CloudInitError> {
+        self.option(names)
+            .map(|value| {
+                value.parse().map_err(|_| {
+                    CloudInitError::Network(format!("{}: invalid {} '{}'", name, names[0], value))
+                })
+            })
+            .transpose()
+    }
+
+    fn flag(&self, names: &[&str]) -> Option<bool> {
+        self.option(names)
+            .map(|value| matches!(value, "on" | "yes" | "true" | "1"))
+    }
+}
+
+/// Logical lines of `text`: comments and blank lines dropped, lines
+/// ending in `\` joined with the next
+fn logical_lines(text: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut pending = String::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if pending.is_empty() && (line.is_empty() || line.starts_with('#')) {
+            continue;
+        }
+        match line.strip_suffix('\\') {
+            Some(head) => {
+                pending.push_str(head);
+                pending.push(' ');
+            }
+            None => {
+                pending.push_str(line);
+                lines.push(std::mem::take(&mut pending));
+            }
+        }
+    }
+    if !pending.trim().is_empty() {
+        lines.push(pending);
+    }
+    lines
+}
+
+/// The subnet an `iface` method stands for, if any
+fn subnet_type(name: &str, family: &str, method: &str) -> Result<Option<String>, CloudInitError> {
+    let subnet = match (family, method) {
+        (_, "loopback" | "manual") => None,
+        ("inet", "dhcp") => Some("dhcp4"),
+        ("inet6", "dhcp") => Some("dhcp6"),
+        ("inet6", "auto") => Some("ipv6_slaac"),
+        ("inet", "static") => Some("static"),
+        ("inet6", "static") => Some("static6"),
+        _ => {
+            return Err(CloudInitError::Network(format!(
+                "{}: unsupported method '{} {}'",
+                name, family, method
+            )));
+        }
+    };
+    Ok(subnet.map(str::to_string))
+}
+
+/// Parse ENI `text` into a v1 config
+pub fn parse_eni_v1(text: &str) -> Result<NetworkConfigV1, CloudInitError> {
+    // Interfaces in order of first appearance
+    let mut order: Vec<String> = Vec::new();
+    let mut interfaces: BTreeMap<String, Interface> = BTreeMap::new();
+    // Interface and subnet the option lines belong to
+    let mut current: Option<(String, Option<usize>)> = None;
+
+    for line in logical_lines(text) {
+        let mut words = line.split_whitespace();
+        let Some(keyword) = words.next() else {
+            continue;
+        };
+        let rest: Vec<&str> = words.collect();
+
+        match keyword {
+            "iface" => {
+                let [name, family, method] = rest[..] else {
+                    return Err(CloudInitError::Network(format!(
+                        "malformed iface line '{}'",
+                        line.trim()
+                    )));
+                };
+                if !order.iter().any(|known| known == name) {
+                    order.push(name.to_string());
+                }
+                let interface = interfaces.entry(name.to_string()).or_default();
+                let subnet = subnet_type(name, family, method)?.map(|subnet_type| {
+                    interface.subnets.push(SubnetConfig {
+                        subnet_type,
+                        ..Default::default()
+                    });
+                    interface.subnets.len() - 1
+                });
+                current = Some((name.to_string(), subnet));
+            }
+            "auto" | "mapping" | "source" | "source-directory" => {
+                debug!("ENI: ignoring '{}'", line.trim());
+                current = None;
+            }
+            _ if keyword.starts_with("allow-") => current = None,
+            _ => {
+                let Some((name, subnet)) = &current else {
+                    debug!("ENI: ignoring '{}' outside an iface stanza", line.trim());
+                    continue;
+                };
+                let value = rest.join(" ");
+                let interface = interfaces.get_mut(name).expect("current interface exists");
+                let subnet = subnet.map(|index| &mut interface.subnets[index]);
+                match (keyword, subnet) {
+                    ("address", Some(subnet)) => match value.split_once('/') {
+                        Some((address, prefix)) => {
+                            subnet.address = Some(address.to_string());
+                            subnet.netmask = Some(prefix.to_string());
+                        }
+                        None => subnet.address = Some(value),
+                    },
+                    ("netmask", Some(subnet)) => subnet.netmask = Some(value),
+                    ("gateway", Some(subnet)) => subnet.gateway = Some(value),
+                    ("dns-nameservers", Some(subnet)) => subnet
+                        .dns_nameservers
+                        .extend(rest.iter().map(|s| s.to_string())),
+                    ("dns-search", Some(subnet)) => {
+                        subnet.dns_search.extend(rest.iter().map(|s| s.to_string()))
+                    }
+                    // inet6 auto with `dhcp 1` also runs stateless DHCPv6
+                    ("dhcp", Some(subnet)) if subnet.subnet_type == "ipv6_slaac" => {
+                        if value == "1" {
+                            subnet.subnet_type = "ipv6_dhcpv6-stateless".to_string();
+                        }
+                    }
+                    ("up" | "down" | "pre-up" | "post-up" | "pre-down" | "post-down", _) => {
+                        debug!("ENI: ignoring hook on {}: {}", name, line.trim())
+                    }
+                    _ => {
+                        interface.options.insert(keyword.to_string(), value);
+                    }
+                }
+            }
+        }
+    }
+
+    // Slaves naming their bond with bond-master
+    let mut bond_members: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for name in &order {
+        if let Some(master) = interfaces[name].option(&["bond-master", "bond_master"]) {
+            bond_members
+                .entry(master.to_string())
+                .or_default()
+                .push(name.clone());
+        }
+    }
+
+    let mut config = Vec::new();
+    let mut dns = NameserverConfigV1::default();
+    for name in order {
+        let interface = &interfaces[&name];
+        if name == "lo" {
+            // Loopback DNS settings are global
+            dns.address.extend(interface.list(&["dns-nameservers"]));
+            dns.search.extend(interface.list(&["dns-search"]));
+            for subnet in &interface.subnets {
+                dns.address.extend(subnet.dns_nameservers.iter().cloned());
+                dns.search.extend(subnet.dns_search.iter().cloned());
+            }
+            continue;
+        }
+        let mtu = interface.number(&name, &["mtu"])?;
+        let mac_address = interface
+            .option(&["hwaddress"])
+            .and_then(|value| value.split_whitespace().last())
+            .map(str::to_string);
+        let subnets = interface.subnets.clone();
+
+        let mut members = interface.list(&["bond-slaves", "bond_slaves"]);
+        for member in bond_members.remove(&name).unwrap_or_default() {
+            if !members.contains(&member) {
+                members.push(member);
+            }
+        }
+        let bridge_ports = interface.list(&["bridge_ports", "bridge-ports"]);
+        let vlan_link = interface
+            .option(&["vlan-raw-device", "vlan_raw_device"])
+            .map(str::to_string)
+            .or_else(|| name.rsplit_once('.').map(|(link, _)| link.to_string()));
+
+        let item = if !members.is_empty() || interface.option(&["bond-mode", "bond_mode"]).is_some()
+        {
+            ConfigItem::Bond(BondConfigV1 {
+                bond_interfaces: members,
+                bond_mode: interface
+                    .option(&["bond-mode", "bond_mode"])
+                    .map(str::to_string),
+                bond_miimon: interface.number(&name, &["bond-miimon", "bond_miimon"])?,
+                bond_xmit_hash_policy: interface
+                    .option(&["bond-xmit-hash-policy", "bond_xmit_hash_policy"])
+                    .map(str::to_string),
+                mtu,
+                mac_address,
+                subnets,
+                name,
+            })
+        } else if interface
+            .option(&["bridge_ports", "bridge-ports"])
+            .is_some()
+        {
+            ConfigItem::Bridge(BridgeConfigV1 {
+                bridge_interfaces: bridge_ports,
+                bridge_stp: interface.flag(&["bridge_stp", "bridge-stp"]),
+                bridge_fd: interface.number(&name, &["bridge_fd", "bridge-fd"])?,
+                mtu,
+                subnets,
+                name,
+            })
+        } else if let Some(vlan_link) = vlan_link {
+            let vlan_id = match interface.number(&name, &["vlan-id", "vlan_id"])? {
+                Some(id) => id,
+                None => name
+                    .rsplit_once('.')
+                    .and_then(|(_, id)| id.parse().ok())
+                    .ok_or_else(|| {
+                        CloudInitError::Network(format!("{}: VLAN without an id", name))
+                    })?,
+            };
+            ConfigItem::Vlan(VlanConfigV1 {
+                vlan_id,
+                vlan_link,
+                mtu,
+                subnets,
+                name,
+            })
+        } else {
+            ConfigItem::Physical(PhysicalConfig {
+                mac_address,
+                mtu,
+                subnets,
+                name,
+                ..Default::default()
+            })
+        };
+        config.push(item);
+    }
+    if !dns.address.is_empty() || !dns.search.is_empty() {
+        config.push(ConfigItem::Nameserver(dns));
+    }
+
+    Ok(NetworkConfigV1 { version: 1, config })
+}
+
+/// Parse ENI `text` into a network config
+pub fn parse_eni(text: &str) -> Result<NetworkConfig, CloudInitError> {
+    Ok(parse_eni_v1(text)?.to_v2())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_eni_static_and_dhcp() {
+        let text = "\
+# The loopback network interface
+auto lo
+iface lo inet loopback
+    dns-nameservers 10.0.0.2
+
+auto eth0
+iface eth0 inet static
+    address 192.168.1.10
+    netmask 255.255.255.0
+    gateway 192.168.1.1
+    dns-nameservers 192.168.1.53
+    dns-search example.com \\
+        corp.example.com
+    hwaddress ether 52:54:00:12:34:56
+    mtu 9000
+    post-up ip route add 10.1.0.0/16 via 192.168.1.254
+
+iface eth0 inet6 static
+    address 2001:db8::10/64
+
+allow-hotplug eth1
+iface eth1 inet dhcp
+";
+        let config = parse_eni(text).unwrap();
+        assert_eq!(config.ethernets.len(), 2);
+
+        let eth0 = &config.ethernets["eth0"];
+        assert_eq!(
+            eth0.common.addresses,
+            vec!["192.168.1.10/24", "2001:db8::10/64"]
+        );
+        assert_eq!(eth0.common.gateway4.as_deref(), Some("192.168.1.1"));
+        assert_eq!(eth0.common.mtu, Some(9000));
+        assert_eq!(
+            eth0.common.nameservers.search,
+            vec!["example.com", "corp.example.com"]
+        );
+        assert_eq!(
+            eth0.match_config.as_ref().unwrap().macaddress.as_deref(),
+            Some("52:54:00:12:34:56")
+        );
+        assert!(eth0.common.routes.is_empty());
+
+        let eth1 = &config.ethernets["eth1"];
+        assert_eq!(eth1.common.dhcp4, Some(true));
+        assert_eq!(eth1.common.nameservers.addresses, vec!["10.0.0.2"]);
+    }
+
+    #[test]
+    fn test_parse_eni_bond_bridge_vlan() {
+        let text = "\
+iface eth0 inet manual
+    bond-master bond0
+iface eth1 inet manual
+    bond-master bond0
+
+auto bond0
+iface bond0 inet manual
+    bond-mode active-backup
+    bond-miimon 100
+    bond-slaves none
+
+auto br0
+iface br0 inet dhcp
+    bridge_ports bond0
+    bridge_stp off
+    bridge_fd 0
+
+auto bond0.100
+iface bond0.100 inet static
+    address 10.100.0.5/24
+";
+        let config = parse_eni(text).unwrap();
+        assert_eq!(config.ethernets.len(), 2);
+
+        let bond = &config.bonds["bond0"];
+        assert_eq!(bond.interfaces, vec!["eth0", "eth1"]);
+        let parameters = bond.parameters.as_ref().unwrap();
+        assert_eq!(parameters.mode.as_deref(), Some("active-backup"));
+        assert_eq!(parameters.mii_monitor_interval, Some(100));
+
+        let bridge = &config.bridges["br0"];
+        assert_eq!(bridge.interfaces, vec!["bond0"]);
+        assert_eq!(bridge.common.dhcp4, Some(true));
+        assert_eq!(bridge.parameters.as_ref().unwrap().stp, Some(false));
+
+        let vlan = &config.vlans["bond0.100"];
+        assert_eq!(vlan.id, 100);
+        assert_eq!(vlan.link, "bond0");
+        assert_eq!(vlan.common.addresses, vec!["10.100.0.5/24"]);
+    }
+
+    #[test]
+    fn test_parse_eni_ipv6_only() {
+        let text = "\
+auto eth0
+iface eth0 inet6 static
+    address 2001:db8::10/64
+    gateway 2001:db8::1
+    dns-nameservers 2001:db8::53
+
+auto eth1
+iface eth1 inet6 auto
+    dhcp 1
+";
+        let config = parse_eni(text).unwrap();
+
+        let eth0 = &config.ethernets["eth0"].common;
+        assert_eq!(eth0.addresses, vec!["2001:db8::10/64"]);
+        assert_eq!(eth0.gateway6.as_deref(), Some("2001:db8::1"));
+        assert_eq!(eth0.nameservers.addresses, vec!["2001:db8::53"]);
+        assert!(eth0.is_ipv6_only());
+
+        let eth1 = &config.ethernets["eth1"].common;
+        assert!(eth1.dhcp6_stateless());
+        assert_eq!(eth1.accept_ra, Some(true));
+    }
+
+    #[test]
+    fn test_parse_eni_errors() {
+        assert!(parse_eni("iface eth0 inet").is_err());
+        assert!(parse_eni("iface eth0 inet ppp").is_err());
+        assert!(parse_eni("iface eth0 inet dhcp\n  mtu big\n").is_err());
+        assert!(!parse_eni("").unwrap().has_interfaces());
+    }
+}
@@ -0,0 +1,493 @@
+//! Ephemeral networking for metadata access
+//!
+//! Some datasources (EC2, Azure, ...) can only be reached over the
+//! network, but the Local stage runs before the system network is
+//! configured. This module brings up just enough networking on the primary
+//! NIC to reach the metadata service, then removes it again so the Network
+//! stage starts from a clean slate - the equivalent of Python cloud-init's
+//! `EphemeralDHCPv4`.
+//!
+//! Two flavours are supported:
+//! - [`EphemeralDhcp`]: obtain a DHCPv4 lease with the built-in client
+//!   ([`super::dhcp`]) and apply it with `ip`.
+//! - [`EphemeralIpv4Network`]: apply a static (typically link-local)
+//!   address supplied by a datasource.
+//!
+//! Every change is recorded as an undo command and reverted in reverse
+//! order by `teardown`, including when setup fails half-way.
+
+use super::dhcp::DhcpClient;
+use crate::CloudInitError;
+use crate::runner::{SystemCommand, SystemRunner};
+use std::future::Future;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+/// sysfs directory listing network interfaces
+pub const SYS_CLASS_NET: &str = "/sys/class/net";
+
+/// Kernel IPv4 routing table
+pub const PROC_NET_ROUTE: &str = "/proc/net/route";
+
+/// Kernel IPv6 routing table
+pub const PROC_NET_IPV6_ROUTE: &str = "/proc/net/ipv6_route";
+
+/// A DHCPv4 lease
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhcpLease {
+    /// Interface the lease was obtained on
+    pub interface: String,
+    /// Leased address
+    pub address: Ipv4Addr,
+    /// Prefix length derived from the subnet mask
+    pub prefix_len: u8,
+    /// Default gateway
+    pub router: Option<Ipv4Addr>,
+    /// Broadcast address
+    pub broadcast: Option<Ipv4Addr>,
+    /// Interface MTU
+    pub mtu: Option<u16>,
+    /// DNS servers
+    pub dns_servers: Vec<Ipv4Addr>,
+    /// Classless static routes (option 121)
+    pub static_routes: Vec<StaticRoute>,
+}
+
+/// A static route received via DHCP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaticRoute {
+    /// Destination network
+    pub destination: Ipv4Addr,
+    /// Destination prefix length (0 for the default route)
+    pub prefix_len: u8,
+    /// Next hop (`0.0.0.0` for on-link destinations)
+    pub gateway: Ipv4Addr,
+}
+
+/// Pick the NIC to use for ephemeral networking
+///
+/// Only physical devices (with a `device` link) are considered. `eth0` is
+/// preferred, then interfaces reporting carrier, then the first by name.
+pub async fn find_primary_nic(sys_class_net: &Path) -> Option<String> {
+    let mut entries = fs::read_dir(sys_class_net).await.ok()?;
+    let mut candidates = Vec::new();
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let dir = entry.path();
+        if name == "lo" || !dir.join("device").exists() {
+            continue;
+        }
+        let carrier = fs::read_to_string(dir.join("carrier"))
+            .await
+            .is_ok_and(|c| c.trim() == "1");
+        candidates.push((name, carrier));
+    }
+
+    candidates.sort_by(|(a, a_up), (b, b_up)| {
+        (a != "eth0", !a_up, a.as_str()).cmp(&(b != "eth0", !b_up, b.as_str()))
+    });
+    candidates.into_iter().next().map(|(name, _)| name)
+}
+
+/// Whether `/proc/net/route` content contains an IPv4 default route
+pub fn has_default_route(proc_net_route: &str) -> bool {
+    proc_net_route.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.len() > 7 && fields[1] == "00000000" && fields[7] == "00000000"
+    })
+}
+
+/// Whether `/proc/net/ipv6_route` content contains an IPv6 default route
+///
+/// The kernel keeps an unreachable `::/0` entry on `lo`, which does not
+/// count.
+pub fn has_ipv6_default_route(proc_net_ipv6_route: &str) -> bool {
+    proc_net_ipv6_route.lines().any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.len() == 10
+            && fields[0].bytes().all(|b| b == b'0')
+            && fields[1] == "00"
+            && fields[9] != "lo"
+    })
+}
+
+/// Whether the host can only reach the network over IPv6
+///
+/// True when there is an IPv6 default route but no IPv4 one, as on
+/// IPv6-only subnets once router advertisements have been processed.
+pub async fn is_ipv6_only_host() -> bool {
+    let ipv4 = fs::read_to_string(PROC_NET_ROUTE).await.unwrap_or_default();
+    let ipv6 = fs::read_to_string(PROC_NET_IPV6_ROUTE)
+        .await
+        .unwrap_or_default();
+    !has_default_route(&ipv4) && has_ipv6_default_route(&ipv6)
+}
+
+/// A temporary IPv4 configuration on one interface
+#[derive(Debug)]
+pub struct EphemeralIpv4Network {
+    interface: String,
+    undo: Vec<Vec<String>>,
+}
+
+impl EphemeralIpv4Network {
+    /// Bring `interface` up with `address/prefix_len` and optional gateway
+    ///
+    /// On failure any partial configuration is removed before returning.
+    pub async fn setup(
+        runner: &dyn SystemRunner,
+        interface: &str,
+        address: Ipv4Addr,
+        prefix_len: u8,
+        router: Option<Ipv4Addr>,
+        routes: &[StaticRoute],
+        mtu: Option<u16>,
+    ) -> Result<Self, CloudInitError> {
+        info!(
+            "Bringing up ephemeral network on {}: {}/{}",
+            interface, address, prefix_len
+        );
+
+        let mut network = Self {
+            interface: interface.to_string(),
+            undo: Vec::new(),
+        };
+
+        for (cmd, undo) in setup_commands(interface, address, prefix_len, router, routes, mtu) {
+            if let Err(e) = run_ip(runner, &cmd).await {
+                network.teardown(runner).await;
+                return Err(e);
+            }
+            if let Some(undo) = undo {
+                network.undo.push(undo);
+            }
+        }
+
+        Ok(network)
+    }
+
+    /// Interface this configuration was applied to
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    /// Remove everything `setup` added, most recent first
+    pub async fn teardown(mut self, runner: &dyn SystemRunner) {
+        debug!("Tearing down ephemeral network on {}", self.interface);
+        while let Some(cmd) = self.undo.pop() {
+            if let Err(e) = run_ip(runner, &cmd).await {
+                warn!("Ephemeral network teardown step failed: {}", e);
+            }
+        }
+    }
+}
+
+/// `ip` invocations (and their undo) to apply an address to an interface
+fn setup_commands(
+    interface: &str,
+    address: Ipv4Addr,
+    prefix_len: u8,
+    router: Option<Ipv4Addr>,
+    routes: &[StaticRoute],
+    mtu: Option<u16>,
+) -> Vec<(Vec<String>, Option<Vec<String>>)> {
+    let args = |s: &[&str]| s.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+    let cidr = format!("{}/{}", address, prefix_len);
+    let mut cmds = Vec::new();
+
+    if let Some(mtu) = mtu {
+        cmds.push((
+            args(&["link", "set", "dev", interface, "mtu", &mtu.to_string()]),
+            None,
+        ));
+    }
+    cmds.push((
+        args(&["-4", "addr", "add", &cidr, "dev", interface]),
+        Some(args(&["-4", "addr", "del", &cidr, "dev", interface])),
+    ));
+    cmds.push((
+        args(&["link", "set", "dev", interface, "up"]),
+        Some(args(&["link", "set", "dev", interface, "down"])),
+    ));
+
+    if let Some(router) = router {
+        let router = router.to_string();
+        // The gateway may be outside the leased subnet (common on clouds),
+        // so add an explicit host route to it first
+        cmds.push((
+            args(&[
+                "-4",
+                "route",
+                "replace",
+                &router,
+                "dev",
+                interface,
+                "src",
+                &address.to_string(),
+            ]),
+            Some(args(&["-4", "route", "del", &router, "dev", interface])),
+        ));
+        cmds.push((
+            args(&[
+                "-4", "route", "replace", "default", "via", &router, "dev", interface,
+            ]),
+            Some(args(&["-4", "route", "del", "default", "dev", interface])),
+        ));
+    }
+
+    for route in routes {
+        let dest = if route.prefix_len == 0 {
+            "default".to_string()
+        } else {
+            format!("{}/{}", route.destination, route.prefix_len)
+        };
+        let mut add = args(&["-4", "route", "replace", &dest]);
+        if !route.gateway.is_unspecified() {
+            add.extend(args(&["via", &route.gateway.to_string()]));
+        }
+        add.extend(args(&["dev", interface]));
+        cmds.push((
+            add,
+            Some(args(&["-4", "route", "del", &dest, "dev", interface])),
+        ));
+    }
+
+    cmds
+}
+
+/// Run `ip` with the given arguments
+async fn run_ip(runner: &dyn SystemRunner, args: &[String]) -> Result<(), CloudInitError> {
+    let command = SystemCommand::new("ip").args(args);
+    debug!("Running: {}", command);
+    let output = runner.run(&command).await?;
+
+    if output.is_success() {
+        Ok(())
+    } else {
+        Err(CloudInitError::Command(format!(
+            "{} failed: {}",
+            command,
+            output.stderr.trim()
+        )))
+    }
+}
+
+/// A DHCP lease applied temporarily to the primary NIC
+#[derive(Debug)]
+pub struct EphemeralDhcp {
+    lease: DhcpLease,
+    network: EphemeralIpv4Network,
+}
+
+impl EphemeralDhcp {
+    /// Obtain a lease on `interface` and apply it
+    pub async fn start(runner: &dyn SystemRunner, interface: &str) -> Result<Self, CloudInitError> {
+        let lease = DhcpClient::for_interface(interface)
+            .await?
+            .obtain_lease()
+            .await?;
+        let network = EphemeralIpv4Network::setup(
+            runner,
+            interface,
+            lease.address,
+            lease.prefix_len,
+            lease.router,
+            &lease.static_routes,
+            lease.mtu,
+        )
+        .await?;
+        Ok(Self { lease, network })
+    }
+
+    /// The lease in use
+    pub fn lease(&self) -> &DhcpLease {
+        &self.lease
+    }
+
+    /// Remove the ephemeral configuration
+    pub async fn teardown(self, runner: &dyn SystemRunner) {
+        self.network.teardown(runner).await;
+    }
+}
+
+/// Run `f` with an ephemeral DHCP lease on `interface`
+///
+/// The lease is torn down afterwards whether or not `f` succeeds.
+pub async fn with_ephemeral_dhcp<T, F>(
+    runner: &dyn SystemRunner,
+    interface: &str,
+    f: F,
+) -> Result<T, CloudInitError>
+where
+    F: Future<Output = Result<T, CloudInitError>>,
+{
+    let dhcp = EphemeralDhcp::start(runner, interface).await?;
+    info!(
+        "Ephemeral DHCP lease {} on {}",
+        dhcp.lease().address,
+        interface
+    );
+    let result = f.await;
+    dhcp.teardown(runner).await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_has_default_route() {
+        let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                     eth0\t00000000\t0100000A\t0003\t0\t0\t100\t00000000\n";
+        assert!(has_default_route(table));
+
+        let no_default = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                          eth0\t0000000A\t00000000\t0001\t0\t0\t100\t00FFFFFF\n";
+        assert!(!has_default_route(no_default));
+        assert!(!has_default_route(""));
+    }
+
+    #[test]
+    fn test_has_ipv6_default_route() {
+        let table = "00000000000000000000000000000000 00 00000000000000000000000000000000 00 \
+                     fe800000000000000000000000000001 00000400 00000001 00000000 00000003 eth0\n\
+                     20010db8000000000000000000000000 40 00000000000000000000000000000000 00 \
+                     00000000000000000000000000000000 00000100 00000001 00000000 00000001 eth0\n";
+        assert!(has_ipv6_default_route(table));
+
+        let unreachable = "00000000000000000000000000000000 00 00000000000000000000000000000000 00 \
+                           00000000000000000000000000000000 ffffffff 00000001 00000000 00200200 lo\n";
+        assert!(!has_ipv6_default_route(unreachable));
+        assert!(!has_ipv6_default_route(""));
+    }
+
+    #[test]
+    fn test_setup_commands_with_router_and_mtu() {
+        let cmds = setup_commands(
+            "eth0",
+            Ipv4Addr::new(10, 0, 0, 4),
+            24,
+            Some(Ipv4Addr::new(10, 0, 0, 1)),
+            &[],
+            Some(1500),
+        );
+        let joined: Vec<String> = cmds.iter().map(|(c, _)| c.join(" ")).collect();
+        assert_eq!(
+            joined,
+            vec![
+                "link set dev eth0 mtu 1500",
+                "-4 addr add 10.0.0.4/24 dev eth0",
+                "link set dev eth0 up",
+                "-4 route replace 10.0.0.1 dev eth0 src 10.0.0.4",
+                "-4 route replace default via 10.0.0.1 dev eth0",
+            ]
+        );
+        assert!(cmds[0].1.is_none());
+        assert_eq!(
+            cmds[1].1.as_ref().unwrap().join(" "),
+            "-4 addr del 10.0.0.4/24 dev eth0"
+        );
+    }
+
+    #[test]
+    fn test_setup_commands_link_local_without_router() {
+        let cmds = setup_commands("ens5", Ipv4Addr::new(169, 254, 0, 1), 16, None, &[], None);
+        assert_eq!(cmds.len(), 2);
+        assert!(cmds.iter().all(|(_, undo)| undo.is_some()));
+    }
+
+    #[test]
+    fn test_setup_commands_classless_routes() {
+        let routes = [
+            StaticRoute {
+                destination: Ipv4Addr::new(169, 254, 169, 254),
+                prefix_len: 32,
+                gateway: Ipv4Addr::UNSPECIFIED,
+            },
+            StaticRoute {
+                destination: Ipv4Addr::UNSPECIFIED,
+                prefix_len: 0,
+                gateway: Ipv4Addr::new(10, 0, 0, 1),
+            },
+        ];
+        let cmds = setup_commands("eth0", Ipv4Addr::new(10, 0, 0, 4), 24, None, &routes, None);
+        let joined: Vec<String> = cmds.iter().map(|(c, _)| c.join(" ")).collect();
+        assert_eq!(
+            joined[2..],
+            [
+                "-4 route replace 169.254.169.254/32 dev eth0",
+                "-4 route replace default via 10.0.0.1 dev eth0",
+            ]
+        );
+        assert_eq!(
+            cmds[3].1.as_ref().unwrap().join(" "),
+            "-4 route del default dev eth0"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_setup_failure_undoes_earlier_steps() {
+        let runner = RecordingRunner::new().with_response(
+            "ip link set dev eth0 up",
+            CommandOutput::failure(2, "Cannot find device"),
+        );
+        let err = EphemeralIpv4Network::setup(
+            &runner,
+            "eth0",
+            Ipv4Addr::new(10, 0, 0, 4),
+            24,
+            None,
+            &[],
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("Cannot find device"));
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "ip -4 addr add 10.0.0.4/24 dev eth0",
+                "ip link set dev eth0 up",
+                "ip -4 addr del 10.0.0.4/24 dev eth0",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_find_primary_nic_prefers_eth0_then_carrier() {
+        let dir = TempDir::new().unwrap();
+        let add = |name: &str, physical: bool, carrier: &str| {
+            let nic = dir.path().join(name);
+            std::fs::create_dir_all(&nic).unwrap();
+            if physical {
+                std::fs::create_dir_all(nic.join("device")).unwrap();
+            }
+            std::fs::write(nic.join("carrier"), carrier).unwrap();
+        };
+        add("lo", false, "1\n");
+        add("docker0", false, "1\n");
+        add("ens3", true, "0\n");
+        add("ens4", true, "1\n");
+        assert_eq!(find_primary_nic(dir.path()).await.as_deref(), Some("ens4"));
+
+        add("eth0", true, "0\n");
+        assert_eq!(find_primary_nic(dir.path()).await.as_deref(), Some("eth0"));
+    }
+
+    #[tokio::test]
+    async fn test_find_primary_nic_none() {
+        let dir = TempDir::new().unwrap();
+        assert!(find_primary_nic(dir.path()).await.is_none());
+        assert!(
+            find_primary_nic(&dir.path().join("missing"))
+                .await
+                .is_none()
+        );
+    }
+}
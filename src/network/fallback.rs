@@ -0,0 +1,158 @@
+//! Fallback network configuration
+//!
+//! When neither system config nor the datasource supplies network config,
+//! DHCP on the first connected physical NIC, as Python cloud-init does.
+//! The interface is marked optional so a NIC that never gets a lease does
+//! not hold up boot waiting for the network to come online.
+
+use super::{EthernetConfig, InterfaceCommon, MatchConfig, NetworkConfig};
+use std::path::Path;
+use tokio::fs;
+use tracing::{debug, info};
+
+/// Pick the NIC to configure by default
+///
+/// Only physical devices (with a `device` link) are considered. NICs
+/// reporting carrier come first, then `eth0`, then the rest by name.
+pub async fn find_fallback_nic(sys_class_net: &Path) -> Option<String> {
+    let mut entries = fs::read_dir(sys_class_net).await.ok()?;
+    let mut candidates = Vec::new();
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let dir = entry.path();
+        if name == "lo" || !dir.join("device").exists() {
+            continue;
+        }
+        // Reading carrier fails with EINVAL while the link is down
+        let carrier = fs::read_to_string(dir.join("carrier"))
+            .await
+            .is_ok_and(|c| c.trim() == "1");
+        candidates.push((name, carrier));
+    }
+
+    candidates.sort_by(|(a, a_up), (b, b_up)| {
+        (!a_up, a != "eth0", a.as_str()).cmp(&(!b_up, b != "eth0", b.as_str()))
+    });
+    candidates.into_iter().next().map(|(name, _)| name)
+}
+
+/// Generate a DHCP config for the fallback NIC
+///
+/// The NIC is matched by MAC and pinned to its current name with
+/// `set-name`. With `ipv6`, DHCPv6 and router advertisements are enabled
+/// as well. Returns `None` when no suitable NIC exists.
+pub async fn generate_fallback_config(sys_class_net: &Path, ipv6: bool) -> Option<NetworkConfig> {
+    let Some(name) = find_fallback_nic(sys_class_net).await else {
+        debug!("No physical NIC available for fallback networking");
+        return None;
+    };
+
+    let mac = fs::read_to_string(sys_class_net.join(&name).join("address"))
+        .await
+        .ok()
+        .map(|m| m.trim().to_lowercase())
+        .filter(|m| !m.is_empty());
+    info!("Generated fallback DHCP network config for {}", name);
+
+    let ethernet = EthernetConfig {
+        common: InterfaceCommon {
+            dhcp4: Some(true),
+            dhcp6: ipv6.then_some(true),
+            accept_ra: ipv6.then_some(true),
+            set_name: mac.as_ref().map(|_| name.clone()),
+            optional: Some(true),
+            ..Default::default()
+        },
+        match_config: mac.map(|mac| MatchConfig {
+            macaddress: Some(mac),
+            ..Default::default()
+        }),
+    };
+
+    Some(NetworkConfig {
+        version: 2,
+        ethernets: [(name, ethernet)].into_iter().collect(),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn fake_nic(root: &Path, name: &str, physical: bool, carrier: Option<&str>, mac: &str) {
+        let dir = root.join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        if physical {
+            std::fs::create_dir_all(dir.join("device")).unwrap();
+        }
+        if let Some(carrier) = carrier {
+            std::fs::write(dir.join("carrier"), carrier).unwrap();
+        }
+        std::fs::write(dir.join("address"), format!("{}\n", mac)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_prefers_connected_nic() {
+        let temp = TempDir::new().unwrap();
+        fake_nic(temp.path(), "lo", false, Some("1\n"), "00:00:00:00:00:00");
+        fake_nic(temp.path(), "eth0", true, Some("0\n"), "52:54:00:00:00:00");
+        fake_nic(temp.path(), "ens4", true, Some("1\n"), "52:54:00:00:00:04");
+        fake_nic(temp.path(), "br0", false, Some("1\n"), "52:54:00:00:00:99");
+
+        assert_eq!(
+            find_fallback_nic(temp.path()).await.as_deref(),
+            Some("ens4")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_eth0_then_name_order_without_carrier() {
+        let temp = TempDir::new().unwrap();
+        fake_nic(temp.path(), "ens3", true, None, "52:54:00:00:00:03");
+        fake_nic(temp.path(), "eth0", true, None, "52:54:00:00:00:00");
+        assert_eq!(
+            find_fallback_nic(temp.path()).await.as_deref(),
+            Some("eth0")
+        );
+
+        let temp = TempDir::new().unwrap();
+        fake_nic(temp.path(), "ens5", true, None, "52:54:00:00:00:05");
+        fake_nic(temp.path(), "ens3", true, None, "52:54:00:00:00:03");
+        assert_eq!(
+            find_fallback_nic(temp.path()).await.as_deref(),
+            Some("ens3")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_generate_fallback_config() {
+        let temp = TempDir::new().unwrap();
+        fake_nic(temp.path(), "ens3", true, Some("1\n"), "52:54:00:AB:CD:EF");
+
+        let config = generate_fallback_config(temp.path(), false).await.unwrap();
+        let ens3 = &config.ethernets["ens3"];
+        assert_eq!(ens3.common.dhcp4, Some(true));
+        assert_eq!(ens3.common.dhcp6, None);
+        assert_eq!(ens3.common.optional, Some(true));
+        assert_eq!(ens3.common.set_name.as_deref(), Some("ens3"));
+        assert_eq!(
+            ens3.match_config.as_ref().unwrap().macaddress.as_deref(),
+            Some("52:54:00:ab:cd:ef")
+        );
+        assert!(crate::network::validate::check(&config).is_empty());
+
+        let config = generate_fallback_config(temp.path(), true).await.unwrap();
+        assert_eq!(config.ethernets["ens3"].common.dhcp6, Some(true));
+        assert_eq!(config.ethernets["ens3"].common.accept_ra, Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_no_physical_nic() {
+        let temp = TempDir::new().unwrap();
+        fake_nic(temp.path(), "lo", false, Some("1\n"), "00:00:00:00:00:00");
+        assert!(generate_fallback_config(temp.path(), false).await.is_none());
+    }
+}
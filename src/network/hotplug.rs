@@ -0,0 +1,407 @@
+//! Network hotplug
+//!
+//! NICs attached to a running instance (an EC2 ENI, an OpenStack port)
+//! miss the configuration applied at boot. When the `updates` key lists
+//! `hotplug` for network, [`handle`] refetches the network config from
+//! the datasource and applies the part that configures the new interface.
+//!
+//! It is reached two ways: `cloud-init-rs devel hotplug-hook handle`, for
+//! udev rules, and [`listen`], a long-lived listener on the kernel's
+//! uevent netlink socket run by `cloud-init-hotplugd.service`.
+
+use super::NetworkConfig;
+use super::ephemeral::SYS_CLASS_NET;
+use super::render::{RendererType, apply_network_config};
+use super::state::{LinkState, NetworkState};
+use crate::CloudInitError;
+use crate::config::{CloudConfig, UpdateEvent, load_merged_config, merge_configs};
+use crate::datasources::cache;
+use crate::root::RootContext;
+use crate::state::CloudPaths;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use tracing::{debug, info, warn};
+
+/// What happened to a device, as in udev's `ACTION`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotplugAction {
+    Add,
+    Remove,
+}
+
+impl FromStr for HotplugAction {
+    type Err = CloudInitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "add" => Ok(Self::Add),
+            "remove" => Ok(Self::Remove),
+            other => Err(CloudInitError::Config(format!(
+                "Unsupported hotplug action '{}', expected add or remove",
+                other
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for HotplugAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Add => "add",
+            Self::Remove => "remove",
+        })
+    }
+}
+
+/// A network interface uevent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetUevent {
+    pub action: HotplugAction,
+    pub interface: String,
+}
+
+/// Parse a datagram from the kernel uevent socket
+///
+/// Kernel messages are an `action@devpath` header followed by
+/// NUL-separated `KEY=value` pairs. `None` is returned for other
+/// subsystems and actions. A `move` (udev renaming the interface) counts
+/// as an add, so the interface is configured under its final name.
+pub fn parse_uevent(buf: &[u8]) -> Option<NetUevent> {
+    let mut fields = buf.split(|&b| b == 0);
+    // udevd's own messages start with "libudev" and a binary header
+    if !fields.next()?.contains(&b'@') {
+        return None;
+    }
+
+    let (mut action, mut subsystem, mut interface) = (None, None, None);
+    for field in fields {
+        let Some((key, value)) = std::str::from_utf8(field).ok()?.split_once('=') else {
+            continue;
+        };
+        match key {
+            "ACTION" => action = Some(value),
+            "SUBSYSTEM" => subsystem = Some(value),
+            "INTERFACE" => interface = Some(value),
+            _ => {}
+        }
+    }
+
+    if subsystem != Some("net") {
+        return None;
+    }
+    let action = match action? {
+        "add" | "move" => HotplugAction::Add,
+        "remove" => HotplugAction::Remove,
+        _ => return None,
+    };
+    Some(NetUevent {
+        action,
+        interface: interface?.to_string(),
+    })
+}
+
+/// System config with the instance's cloud-config on top
+async fn effective_config() -> CloudConfig {
+    let system = load_merged_config(&CloudPaths::new())
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to load system config: {}", e);
+            CloudConfig::default()
+        });
+    match crate::stages::config::load_cloud_config().await {
+        Ok(user) => merge_configs(&system, &user),
+        Err(e) => {
+            warn!("Failed to load cloud-config: {}", e);
+            system
+        }
+    }
+}
+
+/// Whether `updates.network.when` includes `hotplug`
+pub async fn is_enabled() -> bool {
+    effective_config()
+        .await
+        .network_update_enabled(UpdateEvent::Hotplug)
+}
+
+/// The part of `config` that configures `link`
+///
+/// Ethernets with a `match: {macaddress}` are matched by MAC address,
+/// others by `set-name` or their key. Bonds, bridges and VLANs are left
+/// out; they are set up at boot.
+pub fn config_for_link(config: &NetworkConfig, link: &LinkState) -> Option<NetworkConfig> {
+    let (name, ethernet) = config.ethernets.iter().find(|(name, eth)| {
+        match eth
+            .match_config
+            .as_ref()
+            .and_then(|m| m.macaddress.as_ref())
+        {
+            Some(mac) => link
+                .mac
+                .as_deref()
+                .is_some_and(|m| m.eq_ignore_ascii_case(mac)),
+            None => eth.common.set_name.as_deref().unwrap_or(name) == link.name,
+        }
+    })?;
+    Some(NetworkConfig {
+        version: config.version,
+        ethernets: [(name.clone(), ethernet.clone())].into(),
+        renderer: config.renderer.clone(),
+        ..Default::default()
+    })
+}
+
+/// Handle one interface being added or removed
+///
+/// Does nothing unless hotplug is enabled. Configuration rendered for a
+/// removed interface is left in place, as it only matches that interface.
+pub async fn handle(action: HotplugAction, interface: &str) -> Result<(), CloudInitError> {
+    if !is_enabled().await {
+        info!(
+            "Hotplug not enabled for network, ignoring {} of {}",
+            action, interface
+        );
+        return Ok(());
+    }
+    match action {
+        HotplugAction::Add => add_interface(interface).await,
+        HotplugAction::Remove => {
+            info!("Interface {} removed", interface);
+            Ok(())
+        }
+    }
+}
+
+async fn add_interface(interface: &str) -> Result<(), CloudInitError> {
+    let state = NetworkState::from_sys(Path::new(SYS_CLASS_NET)).await;
+    let link = state.links.get(interface).ok_or_else(|| {
+        CloudInitError::Network(format!("Interface {} does not exist", interface))
+    })?;
+
+    let ds = cache::current_datasource().await?;
+    let Some(config) = ds.get_network_config().await? else {
+        info!("{} supplies no network config for {}", ds.name(), interface);
+        return Ok(());
+    };
+
+    // ENI keeps every interface in one file, so it gets the whole config
+    let root = &RootContext::current();
+    let renderer = match config.renderer.as_deref() {
+        Some(hint) => RendererType::from_hint(hint),
+        None => RendererType::detect(root).await,
+    };
+    let config = if renderer == Some(RendererType::Eni) {
+        config
+    } else {
+        match config_for_link(&config, link) {
+            Some(config) => config,
+            None => {
+                info!("{} network config does not cover {}", ds.name(), interface);
+                return Ok(());
+            }
+        }
+    };
+
+    info!("Configuring hotplugged interface {}", interface);
+    apply_network_config(root, &config, config.renderer.as_deref()).await
+}
+
+/// Kernel uevent multicast group
+#[cfg(target_os = "linux")]
+const KERNEL_UEVENT_GROUP: u32 = 1;
+
+/// Handle network interface uevents until the uevent socket fails
+///
+/// Returns at once when hotplug is not enabled. Each event is handled
+/// after the previous one finished, so events for the same interface
+/// cannot race.
+#[cfg(target_os = "linux")]
+pub async fn listen() -> Result<(), CloudInitError> {
+    use netlink_sys::{Socket, SocketAddr, protocols::NETLINK_KOBJECT_UEVENT};
+
+    if !is_enabled().await {
+        info!("Hotplug not enabled for network, not listening");
+        return Ok(());
+    }
+
+    let mut socket = Socket::new(NETLINK_KOBJECT_UEVENT)?;
+    socket.bind(&SocketAddr::new(0, KERNEL_UEVENT_GROUP))?;
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let reader = tokio::task::spawn_blocking(move || {
+        loop {
+            match socket.recv_from_full() {
+                Ok((buf, _)) => {
+                    if let Some(event) = parse_uevent(&buf)
+                        && tx.send(event).is_err()
+                    {
+                        return Ok(());
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    });
+
+    info!("Listening for network hotplug events");
+    while let Some(event) = rx.recv().await {
+        debug!("uevent: {} {}", event.action, event.interface);
+        if let Err(e) = handle(event.action, &event.interface).await {
+            warn!(
+                "Hotplug {} of {} failed: {}",
+                event.action, event.interface, e
+            );
+        }
+    }
+
+    reader
+        .await
+        .map_err(|e| CloudInitError::Network(format!("uevent reader: {}", e)))?
+        .map_err(|e| CloudInitError::Network(format!("uevent socket: {}", e)))
+}
+
+/// Handle network interface uevents; needs Linux's uevent socket
+#[cfg(not(target_os = "linux"))]
+pub async fn listen() -> Result<(), CloudInitError> {
+    Err(CloudInitError::Network(
+        "The hotplug listener is only supported on Linux".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{EthernetConfig, InterfaceCommon, MatchConfig};
+
+    fn uevent(fields: &[&str]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for field in fields {
+            buf.extend_from_slice(field.as_bytes());
+            buf.push(0);
+        }
+        buf
+    }
+
+    #[test]
+    fn test_parse_uevent() {
+        let add = uevent(&[
+            "add@/devices/pci0000:00/0000:00:05.0/net/eth1",
+            "ACTION=add",
+            "DEVPATH=/devices/pci0000:00/0000:00:05.0/net/eth1",
+            "SUBSYSTEM=net",
+            "INTERFACE=eth1",
+            "IFINDEX=3",
+            "SEQNUM=2301",
+        ]);
+        assert_eq!(
+            parse_uevent(&add),
+            Some(NetUevent {
+                action: HotplugAction::Add,
+                interface: "eth1".to_string(),
+            })
+        );
+
+        let moved = uevent(&[
+            "move@/x/net/ens6",
+            "ACTION=move",
+            "SUBSYSTEM=net",
+            "INTERFACE=ens6",
+        ]);
+        assert_eq!(parse_uevent(&moved).unwrap().action, HotplugAction::Add);
+
+        let remove = uevent(&[
+            "remove@/x/net/eth1",
+            "ACTION=remove",
+            "SUBSYSTEM=net",
+            "INTERFACE=eth1",
+        ]);
+        assert_eq!(parse_uevent(&remove).unwrap().action, HotplugAction::Remove);
+
+        let block = uevent(&[
+            "add@/x/block/vdb",
+            "ACTION=add",
+            "SUBSYSTEM=block",
+            "DEVNAME=vdb",
+        ]);
+        assert_eq!(parse_uevent(&block), None);
+        let change = uevent(&[
+            "change@/x/net/eth1",
+            "ACTION=change",
+            "SUBSYSTEM=net",
+            "INTERFACE=eth1",
+        ]);
+        assert_eq!(parse_uevent(&change), None);
+        assert_eq!(parse_uevent(b"libudev\0\xfe\xed"), None);
+    }
+
+    #[test]
+    fn test_hotplug_action_from_str() {
+        assert_eq!("add".parse::<HotplugAction>().unwrap(), HotplugAction::Add);
+        assert_eq!(
+            "remove".parse::<HotplugAction>().unwrap(),
+            HotplugAction::Remove
+        );
+        assert!("change".parse::<HotplugAction>().is_err());
+    }
+
+    #[test]
+    fn test_config_for_link() {
+        let mut config = NetworkConfig {
+            version: 2,
+            renderer: Some("networkd".to_string()),
+            ..Default::default()
+        };
+        config.ethernets.insert(
+            "eth0".to_string(),
+            EthernetConfig {
+                common: InterfaceCommon {
+                    dhcp4: Some(true),
+                    ..Default::default()
+                },
+                match_config: None,
+            },
+        );
+        config.ethernets.insert(
+            "secondary".to_string(),
+            EthernetConfig {
+                common: InterfaceCommon {
+                    dhcp4: Some(true),
+                    ..Default::default()
+                },
+                match_config: Some(MatchConfig {
+                    macaddress: Some("0A:00:00:00:00:02".to_string()),
+                    ..Default::default()
+                }),
+            },
+        );
+
+        let attached = LinkState {
+            name: "ens6".to_string(),
+            mac: Some("0a:00:00:00:00:02".to_string()),
+            ..Default::default()
+        };
+        let subset = config_for_link(&config, &attached).unwrap();
+        assert_eq!(subset.interface_names(), vec!["secondary"]);
+        assert_eq!(subset.renderer.as_deref(), Some("networkd"));
+
+        let by_name = LinkState {
+            name: "eth0".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            config_for_link(&config, &by_name)
+                .unwrap()
+                .interface_names(),
+            vec!["eth0"]
+        );
+
+        let unknown = LinkState {
+            name: "eth7".to_string(),
+            mac: Some("0a:00:00:00:00:07".to_string()),
+            ..Default::default()
+        };
+        assert!(config_for_link(&config, &unknown).is_none());
+    }
+}
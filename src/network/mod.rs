@@ -5,10 +5,18 @@
 //! Supports:
 //! - Network config v2 (Netplan format) - ethernets, bonds, bridges, vlans
 //! - Network config v1 (legacy dictionary format)
+//! - ENI (`/etc/network/interfaces`) text from legacy NoCloud seeds
 //! - Multiple renderers: networkd, NetworkManager, ENI
 
+pub mod dhcp;
+pub mod eni;
+pub mod ephemeral;
+pub mod fallback;
+pub mod hotplug;
 pub mod render;
+pub mod state;
 pub mod v1;
+pub mod validate;
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -35,6 +43,10 @@ pub struct NetworkConfig {
     #[serde(default)]
     pub vlans: HashMap<String, VlanConfig>,
 
+    /// Wifi configurations
+    #[serde(default)]
+    pub wifis: HashMap<String, WifiConfig>,
+
     /// Renderer hint (networkd, NetworkManager)
     pub renderer: Option<String>,
 }
@@ -46,6 +58,10 @@ pub struct InterfaceCommon {
     pub dhcp4: Option<bool>,
     /// Enable DHCPv6
     pub dhcp6: Option<bool>,
+    /// How DHCPv6 is used when `dhcp6` is set (cloud-init-rs extension,
+    /// filled in from v1 and OpenStack `ipv6_dhcpv6-*` subnet types)
+    #[serde(rename = "dhcp6-mode")]
+    pub dhcp6_mode: Option<Dhcp6Mode>,
     /// Static addresses (CIDR notation, e.g., "192.168.1.10/24")
     #[serde(default)]
     pub addresses: Vec<String>,
@@ -78,6 +94,35 @@ pub struct InterfaceCommon {
     pub optional: Option<bool>,
 }
 
+/// DHCPv6 flavour of an interface
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dhcp6Mode {
+    /// Addresses and options from the DHCPv6 server
+    #[default]
+    Stateful,
+    /// Addresses from router advertisements (SLAAC); the DHCPv6 server
+    /// only hands out DNS servers and other options
+    Stateless,
+}
+
+impl InterfaceCommon {
+    /// Whether DHCPv6 is enabled in stateless (information-only) mode
+    pub fn dhcp6_stateless(&self) -> bool {
+        self.dhcp6 == Some(true) && self.dhcp6_mode == Some(Dhcp6Mode::Stateless)
+    }
+
+    /// Whether no IPv4 is configured, only IPv6
+    pub fn is_ipv6_only(&self) -> bool {
+        self.dhcp4 != Some(true)
+            && self.gateway4.is_none()
+            && !self.addresses.iter().any(|a| !a.contains(':'))
+            && (self.dhcp6 == Some(true)
+                || self.accept_ra == Some(true)
+                || self.addresses.iter().any(|a| a.contains(':')))
+    }
+}
+
 /// Ethernet interface configuration
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct EthernetConfig {
@@ -89,6 +134,37 @@ pub struct EthernetConfig {
     pub match_config: Option<MatchConfig>,
 }
 
+/// Wifi interface configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WifiConfig {
+    /// Common interface settings
+    #[serde(flatten)]
+    pub common: InterfaceCommon,
+    /// Interface matching configuration
+    #[serde(rename = "match")]
+    pub match_config: Option<MatchConfig>,
+    /// Access points keyed by SSID
+    #[serde(default, rename = "access-points")]
+    pub access_points: HashMap<String, AccessPointConfig>,
+}
+
+/// Wifi access point settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AccessPointConfig {
+    /// WPA pre-shared key; open network when unset
+    pub password: Option<String>,
+    /// Operating mode (infrastructure, ap, adhoc)
+    pub mode: Option<String>,
+    /// Frequency band (2.4GHz or 5GHz)
+    pub band: Option<String>,
+    /// Channel within the band
+    pub channel: Option<u32>,
+    /// Restrict to one access point by BSSID
+    pub bssid: Option<String>,
+    /// Network does not broadcast its SSID
+    pub hidden: Option<bool>,
+}
+
 /// Bond configuration
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct BondConfig {
@@ -189,6 +265,8 @@ pub struct RouteConfig {
     /// On-link flag
     #[serde(rename = "on-link")]
     pub on_link: Option<bool>,
+    /// Route scope (global, link, host)
+    pub scope: Option<String>,
 }
 
 /// Routing policy rule
@@ -263,6 +341,7 @@ impl NetworkConfig {
             || !self.bonds.is_empty()
             || !self.bridges.is_empty()
             || !self.vlans.is_empty()
+            || !self.wifis.is_empty()
     }
 
     /// Get all interface names
@@ -272,6 +351,7 @@ impl NetworkConfig {
         names.extend(self.bonds.keys().cloned());
         names.extend(self.bridges.keys().cloned());
         names.extend(self.vlans.keys().cloned());
+        names.extend(self.wifis.keys().cloned());
         names
     }
 }
@@ -409,6 +489,32 @@ ethernets:
         assert_eq!(routes[0].metric, Some(100));
     }
 
+    #[test]
+    fn test_parse_dhcp6_stateless() {
+        let yaml = r#"
+version: 2
+ethernets:
+  eth0:
+    dhcp6: true
+    dhcp6-mode: stateless
+    accept-ra: true
+    nameservers:
+      addresses: ["2001:4860:4860::8888"]
+"#;
+        let config = NetworkConfig::from_yaml(yaml).unwrap();
+        let eth0 = &config.ethernets["eth0"].common;
+        assert_eq!(eth0.dhcp6_mode, Some(Dhcp6Mode::Stateless));
+        assert!(eth0.dhcp6_stateless());
+        assert!(eth0.is_ipv6_only());
+
+        let dual = NetworkConfig::from_yaml(
+            "version: 2\nethernets:\n  eth0:\n    dhcp4: true\n    dhcp6: true\n",
+        )
+        .unwrap();
+        assert!(!dual.ethernets["eth0"].common.dhcp6_stateless());
+        assert!(!dual.ethernets["eth0"].common.is_ipv6_only());
+    }
+
     #[test]
     fn test_parse_with_network_wrapper() {
         let yaml = r#"
@@ -422,4 +528,31 @@ network:
         assert_eq!(config.version, 2);
         assert!(config.ethernets.contains_key("eth0"));
     }
+
+    #[test]
+    fn test_parse_wifis() {
+        let yaml = r#"
+version: 2
+wifis:
+  wlan0:
+    dhcp4: true
+    access-points:
+      "home-net":
+        password: "s3cret-pass"
+        band: 5GHz
+        channel: 36
+      "guest": {}
+"#;
+        let config = NetworkConfig::from_yaml(yaml).unwrap();
+        let wlan0 = &config.wifis["wlan0"];
+        assert_eq!(wlan0.common.dhcp4, Some(true));
+        assert_eq!(wlan0.access_points.len(), 2);
+        let home = &wlan0.access_points["home-net"];
+        assert_eq!(home.password.as_deref(), Some("s3cret-pass"));
+        assert_eq!(home.band.as_deref(), Some("5GHz"));
+        assert_eq!(home.channel, Some(36));
+        assert!(wlan0.access_points["guest"].password.is_none());
+        assert!(config.has_interfaces());
+        assert_eq!(config.interface_names(), vec!["wlan0"]);
+    }
 }
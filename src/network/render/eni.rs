@@ -1,12 +1,27 @@
 //! Debian ENI (Ethernet Network Interfaces) renderer
 //!
 //! Generates /etc/network/interfaces format configuration.
+//!
+//! Bonds use the ifenslave `bond-*` options, bridges the bridge-utils
+//! `bridge_*` options and VLANs the vlan package's `vlan-raw-device`.
+//! Wifi uses the wpasupplicant `wpa-*` options, which only describe one
+//! network per interface. As those include the PSK, wifi interfaces are
+//! written to a separate file readable only by root, which `interfaces`
+//! sources.
 
 use super::{RenderedFile, Renderer, RendererType};
 use crate::CloudInitError;
-use crate::network::{EthernetConfig, NetworkConfig};
+use crate::network::{
+    BondConfig, BondParameters, BridgeConfig, BridgeParameters, InterfaceCommon, NetworkConfig,
+    VlanConfig, WifiConfig,
+};
+use std::collections::BTreeMap;
 use std::fmt::Write;
 use std::path::Path;
+use tracing::warn;
+
+/// Wifi interfaces, relative to `/etc/network`, sourced from `interfaces`
+const WIFI_FILE: &str = "interfaces.d/50-cloud-init-wifi";
 
 /// Debian ENI renderer
 pub struct EniRenderer;
@@ -16,122 +31,115 @@ impl EniRenderer {
         Self
     }
 
-    fn render_interface(&self, name: &str, config: &EthernetConfig) -> String {
+    /// Render one interface
+    ///
+    /// `options` are device-specific lines (bond, bridge, VLAN settings)
+    /// placed at the top of the IPv4 stanza.
+    fn render_interface(&self, name: &str, common: &InterfaceCommon, options: &[String]) -> String {
         let mut content = String::new();
+        let ipv4_addrs: Vec<_> = common
+            .addresses
+            .iter()
+            .filter(|a| !a.contains(':'))
+            .collect();
 
         // Determine the interface configuration method
-        if config.common.dhcp4 == Some(true) {
-            writeln!(content, "auto {}", name).unwrap();
+        writeln!(content, "auto {}", name).unwrap();
+        if common.dhcp4 == Some(true) {
             writeln!(content, "iface {} inet dhcp", name).unwrap();
-        } else if !config.common.addresses.is_empty() {
+            write_options(&mut content, options);
+        } else if !ipv4_addrs.is_empty() {
             // Static configuration
-            let ipv4_addrs: Vec<_> = config
-                .common
-                .addresses
-                .iter()
-                .filter(|a| !a.contains(':'))
-                .collect();
+            writeln!(content, "iface {} inet static", name).unwrap();
+            write_options(&mut content, options);
+
+            // Parse first address for primary config
+            if let Some(addr) = ipv4_addrs.first() {
+                let (ip, mask) = self.parse_cidr(addr);
+                writeln!(content, "    address {}", ip).unwrap();
+                writeln!(content, "    netmask {}", mask).unwrap();
+            }
 
-            if !ipv4_addrs.is_empty() {
-                writeln!(content, "auto {}", name).unwrap();
-                writeln!(content, "iface {} inet static", name).unwrap();
-
-                // Parse first address for primary config
-                if let Some(addr) = ipv4_addrs.first() {
-                    let (ip, mask) = self.parse_cidr(addr);
-                    writeln!(content, "    address {}", ip).unwrap();
-                    writeln!(content, "    netmask {}", mask).unwrap();
-                }
-
-                if let Some(gw) = &config.common.gateway4 {
-                    writeln!(content, "    gateway {}", gw).unwrap();
-                }
-
-                // DNS
-                if !config.common.nameservers.addresses.is_empty() {
-                    writeln!(
-                        content,
-                        "    dns-nameservers {}",
-                        config.common.nameservers.addresses.join(" ")
-                    )
-                    .unwrap();
-                }
-
-                if !config.common.nameservers.search.is_empty() {
-                    writeln!(
-                        content,
-                        "    dns-search {}",
-                        config.common.nameservers.search.join(" ")
-                    )
-                    .unwrap();
-                }
-
-                // Additional addresses
-                for addr in ipv4_addrs.iter().skip(1) {
-                    let (ip, mask) = self.parse_cidr(addr);
-                    writeln!(content, "    up ip addr add {}/{} dev {}", ip, mask, name).unwrap();
-                }
+            if let Some(gw) = &common.gateway4 {
+                writeln!(content, "    gateway {}", gw).unwrap();
+            }
+
+            // DNS
+            write_dns(&mut content, common);
+
+            // Additional addresses
+            for addr in ipv4_addrs.iter().skip(1) {
+                let (ip, mask) = self.parse_cidr(addr);
+                writeln!(content, "    up ip addr add {}/{} dev {}", ip, mask, name).unwrap();
             }
         } else {
             // Manual mode (no auto-config)
-            writeln!(content, "auto {}", name).unwrap();
             writeln!(content, "iface {} inet manual", name).unwrap();
+            write_options(&mut content, options);
         }
 
         // MTU
-        if let Some(mtu) = config.common.mtu {
+        if let Some(mtu) = common.mtu {
             writeln!(content, "    mtu {}", mtu).unwrap();
         }
 
         // WoL
-        if config.common.wakeonlan == Some(true) {
+        if common.wakeonlan == Some(true) {
             writeln!(content, "    ethernet-wol g").unwrap();
         }
 
         // Routes
-        for route in &config.common.routes {
-            if route.to.contains(':') {
-                continue; // Skip IPv6 routes
-            }
-            let mut route_cmd = format!("    up ip route add {}", route.to);
-            if let Some(via) = &route.via {
-                route_cmd = format!("{} via {}", route_cmd, via);
-            }
-            if let Some(metric) = route.metric {
-                route_cmd = format!("{} metric {}", route_cmd, metric);
-            }
-            writeln!(content, "{}", route_cmd).unwrap();
-        }
+        write_routes(&mut content, common, false);
 
         // IPv6 configuration
-        if config.common.dhcp6 == Some(true) {
-            writeln!(content).unwrap();
-            writeln!(content, "iface {} inet6 dhcp", name).unwrap();
-        } else if config.common.accept_ra == Some(true) {
-            writeln!(content).unwrap();
-            writeln!(content, "iface {} inet6 auto", name).unwrap();
+        let ipv6_addrs: Vec<_> = common
+            .addresses
+            .iter()
+            .filter(|a| a.contains(':'))
+            .collect();
+        let method = if common.dhcp6_stateless()
+            || (common.accept_ra == Some(true) && common.dhcp6 != Some(true))
+        {
+            "auto"
+        } else if common.dhcp6 == Some(true) {
+            "dhcp"
+        } else if !ipv6_addrs.is_empty() {
+            "static"
         } else {
-            let ipv6_addrs: Vec<_> = config
-                .common
-                .addresses
-                .iter()
-                .filter(|a| a.contains(':'))
-                .collect();
-
-            if !ipv6_addrs.is_empty() {
-                writeln!(content).unwrap();
-                writeln!(content, "iface {} inet6 static", name).unwrap();
-
-                if let Some(addr) = ipv6_addrs.first() {
-                    writeln!(content, "    address {}", addr).unwrap();
-                }
+            return content;
+        };
+        writeln!(content).unwrap();
+        writeln!(content, "iface {} inet6 {}", name, method).unwrap();
 
-                if let Some(gw) = &config.common.gateway6 {
-                    writeln!(content, "    gateway {}", gw).unwrap();
-                }
+        let mut extra_addrs = ipv6_addrs.iter();
+        if method == "static" {
+            if let Some(addr) = extra_addrs.next() {
+                writeln!(content, "    address {}", addr).unwrap();
+            }
+            if let Some(gw) = &common.gateway6 {
+                writeln!(content, "    gateway {}", gw).unwrap();
             }
+        } else if common.dhcp6_stateless() {
+            // Stateless DHCPv6 for DNS and other options on top of SLAAC
+            writeln!(content, "    dhcp 1").unwrap();
+        } else if common.accept_ra == Some(false) {
+            writeln!(content, "    accept_ra 0").unwrap();
         }
 
+        // IPv6-only interfaces have no inet stanza carrying DNS
+        if common.is_ipv6_only() {
+            write_dns(&mut content, common);
+        }
+        for addr in extra_addrs {
+            writeln!(content, "    up ip -6 addr add {} dev {}", addr, name).unwrap();
+        }
+        if method != "static"
+            && let Some(gw) = &common.gateway6
+        {
+            writeln!(content, "    up ip -6 route add default via {}", gw).unwrap();
+        }
+        write_routes(&mut content, common, true);
+
         content
     }
 
@@ -147,11 +155,10 @@ impl EniRenderer {
     }
 
     fn prefix_to_netmask(&self, prefix: u8) -> String {
-        let mask: u32 = if prefix >= 32 {
-            0xffffffff
-        } else {
-            0xffffffff << (32 - prefix)
-        };
+        // A /0 route shifts by the full width, which overflows
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(prefix.min(32)))
+            .unwrap_or(0);
         format!(
             "{}.{}.{}.{}",
             (mask >> 24) & 0xff,
@@ -162,6 +169,172 @@ impl EniRenderer {
     }
 }
 
+/// Write indented option lines
+fn write_options(content: &mut String, options: &[String]) {
+    for option in options {
+        writeln!(content, "    {}", option).unwrap();
+    }
+}
+
+/// resolvconf `dns-*` options for the static nameservers
+fn write_dns(content: &mut String, common: &InterfaceCommon) {
+    if !common.nameservers.addresses.is_empty() {
+        writeln!(
+            content,
+            "    dns-nameservers {}",
+            common.nameservers.addresses.join(" ")
+        )
+        .unwrap();
+    }
+
+    if !common.nameservers.search.is_empty() {
+        writeln!(
+            content,
+            "    dns-search {}",
+            common.nameservers.search.join(" ")
+        )
+        .unwrap();
+    }
+}
+
+/// `up ip route` lines for the static routes of one address family
+fn write_routes(content: &mut String, common: &InterfaceCommon, ipv6: bool) {
+    let ip = if ipv6 { "ip -6" } else { "ip" };
+    for route in common.routes.iter().filter(|r| r.to.contains(':') == ipv6) {
+        let mut route_cmd = format!("    up {} route add {}", ip, route.to);
+        if let Some(via) = &route.via {
+            route_cmd = format!("{} via {}", route_cmd, via);
+        }
+        if let Some(metric) = route.metric {
+            route_cmd = format!("{} metric {}", route_cmd, metric);
+        }
+        writeln!(content, "{}", route_cmd).unwrap();
+    }
+}
+
+/// ifenslave options for a bond
+fn bond_options(bond: &BondConfig) -> Vec<String> {
+    let mut options = Vec::new();
+    if bond.interfaces.is_empty() {
+        options.push("bond-slaves none".to_string());
+    } else {
+        options.push(format!("bond-slaves {}", bond.interfaces.join(" ")));
+    }
+
+    let Some(BondParameters {
+        mode,
+        mii_monitor_interval,
+        primary,
+        transmit_hash_policy,
+        lacp_rate,
+        arp_interval,
+        arp_ip_targets,
+    }) = &bond.parameters
+    else {
+        return options;
+    };
+
+    if let Some(mode) = mode {
+        options.push(format!("bond-mode {}", mode));
+    }
+    if let Some(miimon) = mii_monitor_interval {
+        options.push(format!("bond-miimon {}", miimon));
+    }
+    if let Some(primary) = primary {
+        options.push(format!("bond-primary {}", primary));
+    }
+    if let Some(policy) = transmit_hash_policy {
+        options.push(format!("bond-xmit-hash-policy {}", policy));
+    }
+    if let Some(rate) = lacp_rate {
+        options.push(format!("bond-lacp-rate {}", rate));
+    }
+    if let Some(interval) = arp_interval {
+        options.push(format!("bond-arp-interval {}", interval));
+    }
+    if !arp_ip_targets.is_empty() {
+        options.push(format!("bond-arp-ip-target {}", arp_ip_targets.join(" ")));
+    }
+    options
+}
+
+/// bridge-utils options for a bridge
+fn bridge_options(bridge: &BridgeConfig) -> Vec<String> {
+    let mut options = Vec::new();
+    if bridge.interfaces.is_empty() {
+        options.push("bridge_ports none".to_string());
+    } else {
+        options.push(format!("bridge_ports {}", bridge.interfaces.join(" ")));
+    }
+
+    let Some(BridgeParameters {
+        ageing_time,
+        forward_delay,
+        hello_time,
+        max_age,
+        priority,
+        stp,
+    }) = &bridge.parameters
+    else {
+        return options;
+    };
+
+    if let Some(stp) = stp {
+        options.push(format!("bridge_stp {}", if *stp { "on" } else { "off" }));
+    }
+    if let Some(delay) = forward_delay {
+        options.push(format!("bridge_fd {}", delay));
+    }
+    if let Some(hello) = hello_time {
+        options.push(format!("bridge_hello {}", hello));
+    }
+    if let Some(age) = max_age {
+        options.push(format!("bridge_maxage {}", age));
+    }
+    if let Some(ageing) = ageing_time {
+        options.push(format!("bridge_ageing {}", ageing));
+    }
+    if let Some(priority) = priority {
+        options.push(format!("bridge_bridgeprio {}", priority));
+    }
+    options
+}
+
+/// wpasupplicant options for the first access point of a wifi interface
+fn wifi_options(name: &str, wifi: &WifiConfig) -> Vec<String> {
+    let access_points: BTreeMap<_, _> = wifi.access_points.iter().collect();
+    if access_points.len() > 1 {
+        warn!(
+            "ENI supports one access point per interface; only the first is rendered for {}",
+            name
+        );
+    }
+    let Some((ssid, ap)) = access_points.into_iter().next() else {
+        return Vec::new();
+    };
+
+    let mut options = vec![format!("wpa-ssid {}", ssid)];
+    match &ap.password {
+        Some(password) => options.push(format!("wpa-psk {}", password)),
+        None => options.push("wpa-key-mgmt NONE".to_string()),
+    }
+    if ap.hidden == Some(true) {
+        options.push("wpa-scan-ssid 1".to_string());
+    }
+    if let Some(bssid) = &ap.bssid {
+        options.push(format!("wpa-bssid {}", bssid));
+    }
+    options
+}
+
+/// vlan package options for a VLAN
+fn vlan_options(vlan: &VlanConfig) -> Vec<String> {
+    vec![
+        format!("vlan-raw-device {}", vlan.link),
+        format!("vlan_id {}", vlan.id),
+    ]
+}
+
 impl Default for EniRenderer {
     fn default() -> Self {
         Self::new()
@@ -186,19 +359,75 @@ impl Renderer for EniRenderer {
         writeln!(content, "iface lo inet loopback").unwrap();
         writeln!(content).unwrap();
 
-        // Render all ethernet interfaces
-        for (name, eth_config) in &config.ethernets {
-            content.push_str(&self.render_interface(name, eth_config));
+        // Which bond each member interface belongs to
+        let bond_masters: BTreeMap<&str, &str> = config
+            .bonds
+            .iter()
+            .flat_map(|(bond, cfg)| {
+                cfg.interfaces
+                    .iter()
+                    .map(move |i| (i.as_str(), bond.as_str()))
+            })
+            .collect();
+
+        // Render all ethernet interfaces (sorted for stable output)
+        let ethernets: BTreeMap<_, _> = config.ethernets.iter().collect();
+        for (name, eth_config) in ethernets {
+            let options: Vec<String> = bond_masters
+                .get(name.as_str())
+                .map(|bond| format!("bond-master {}", bond))
+                .into_iter()
+                .collect();
+            content.push_str(&self.render_interface(name, &eth_config.common, &options));
+            writeln!(content).unwrap();
+        }
+
+        let bonds: BTreeMap<_, _> = config.bonds.iter().collect();
+        for (name, bond) in bonds {
+            content.push_str(&self.render_interface(name, &bond.common, &bond_options(bond)));
             writeln!(content).unwrap();
         }
 
-        // TODO: Implement bonds and bridges for ENI
+        let bridges: BTreeMap<_, _> = config.bridges.iter().collect();
+        for (name, bridge) in bridges {
+            content.push_str(&self.render_interface(name, &bridge.common, &bridge_options(bridge)));
+            writeln!(content).unwrap();
+        }
+
+        let vlans: BTreeMap<_, _> = config.vlans.iter().collect();
+        for (name, vlan) in vlans {
+            content.push_str(&self.render_interface(name, &vlan.common, &vlan_options(vlan)));
+            writeln!(content).unwrap();
+        }
+
+        // Wifi stanzas hold the PSKs, so they go to a file only root reads
+        let mut wifi_content = String::new();
+        let wifis: BTreeMap<_, _> = config.wifis.iter().collect();
+        for (name, wifi) in wifis {
+            wifi_content.push_str(&self.render_interface(
+                name,
+                &wifi.common,
+                &wifi_options(name, wifi),
+            ));
+            writeln!(wifi_content).unwrap();
+        }
+        if !wifi_content.is_empty() {
+            writeln!(content, "source /etc/network/{}", WIFI_FILE).unwrap();
+        }
 
-        Ok(vec![RenderedFile {
+        let mut files = vec![RenderedFile {
             path: "interfaces".to_string(),
             content,
             mode: 0o644,
-        }])
+        }];
+        if !wifi_content.is_empty() {
+            files.push(RenderedFile {
+                path: WIFI_FILE.to_string(),
+                content: format!("# This file is generated by cloud-init\n\n{}", wifi_content),
+                mode: 0o600,
+            });
+        }
+        Ok(files)
     }
 
     fn renderer_type(&self) -> RendererType {
@@ -213,7 +442,7 @@ impl Renderer for EniRenderer {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::network::{InterfaceCommon, NameserverConfig};
+    use crate::network::{EthernetConfig, NameserverConfig};
     use std::collections::HashMap;
 
     #[test]
@@ -281,6 +510,165 @@ mod tests {
         assert!(files[0].content.contains("dns-nameservers 8.8.8.8"));
     }
 
+    #[test]
+    fn test_render_bond_with_members() {
+        let yaml = r#"
+version: 2
+ethernets:
+  eth0: {}
+  eth1: {}
+bonds:
+  bond0:
+    interfaces: [eth0, eth1]
+    addresses: [10.0.0.5/24]
+    gateway4: 10.0.0.1
+    parameters:
+      mode: 802.3ad
+      mii-monitor-interval: 100
+      lacp-rate: fast
+      transmit-hash-policy: layer3+4
+"#;
+        let config: NetworkConfig = serde_yaml::from_str(yaml).unwrap();
+        let files = EniRenderer::new()
+            .render(&config, Path::new("/tmp"))
+            .unwrap();
+        let content = &files[0].content;
+
+        assert!(content.contains("iface eth0 inet manual\n    bond-master bond0\n"));
+        assert!(content.contains("iface eth1 inet manual\n    bond-master bond0\n"));
+        assert!(content.contains(
+            "auto bond0\niface bond0 inet static\n    bond-slaves eth0 eth1\n    bond-mode 802.3ad\n    bond-miimon 100\n    bond-xmit-hash-policy layer3+4\n    bond-lacp-rate fast\n    address 10.0.0.5\n"
+        ));
+        assert!(content.contains("    gateway 10.0.0.1"));
+
+        // Members come before the bond that uses them
+        assert!(content.find("iface eth1").unwrap() < content.find("iface bond0").unwrap());
+    }
+
+    #[test]
+    fn test_render_bridge() {
+        let yaml = r#"
+version: 2
+ethernets:
+  eth0: {}
+bridges:
+  br0:
+    interfaces: [eth0]
+    dhcp4: true
+    parameters:
+      stp: false
+      forward-delay: 4
+      priority: 32768
+"#;
+        let config: NetworkConfig = serde_yaml::from_str(yaml).unwrap();
+        let files = EniRenderer::new()
+            .render(&config, Path::new("/tmp"))
+            .unwrap();
+        assert!(files[0].content.contains(
+            "auto br0\niface br0 inet dhcp\n    bridge_ports eth0\n    bridge_stp off\n    bridge_fd 4\n    bridge_bridgeprio 32768\n"
+        ));
+    }
+
+    #[test]
+    fn test_render_vlan() {
+        let yaml = r#"
+version: 2
+ethernets:
+  eth0:
+    dhcp4: true
+vlans:
+  vlan100:
+    id: 100
+    link: eth0
+    addresses: [192.168.100.2/24]
+"#;
+        let config: NetworkConfig = serde_yaml::from_str(yaml).unwrap();
+        let files = EniRenderer::new()
+            .render(&config, Path::new("/tmp"))
+            .unwrap();
+        assert!(files[0].content.contains(
+            "auto vlan100\niface vlan100 inet static\n    vlan-raw-device eth0\n    vlan_id 100\n    address 192.168.100.2\n    netmask 255.255.255.0\n"
+        ));
+    }
+
+    #[test]
+    fn test_render_bridge_without_ports_or_params() {
+        let mut bridges = HashMap::new();
+        bridges.insert("br1".to_string(), BridgeConfig::default());
+        let config = NetworkConfig {
+            version: 2,
+            bridges,
+            ..Default::default()
+        };
+        let files = EniRenderer::new()
+            .render(&config, Path::new("/tmp"))
+            .unwrap();
+        assert!(
+            files[0]
+                .content
+                .contains("iface br1 inet manual\n    bridge_ports none\n")
+        );
+    }
+
+    #[test]
+    fn test_render_ipv6_only_gets_manual_inet_stanza() {
+        let mut ethernets = HashMap::new();
+        ethernets.insert(
+            "eth0".to_string(),
+            EthernetConfig {
+                common: InterfaceCommon {
+                    addresses: vec!["2001:db8::10/64".to_string()],
+                    mtu: Some(9000),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        let config = NetworkConfig {
+            version: 2,
+            ethernets,
+            ..Default::default()
+        };
+        let files = EniRenderer::new()
+            .render(&config, Path::new("/tmp"))
+            .unwrap();
+        assert!(
+            files[0]
+                .content
+                .contains("auto eth0\niface eth0 inet manual\n    mtu 9000\n")
+        );
+        assert!(files[0].content.contains("iface eth0 inet6 static"));
+    }
+
+    #[test]
+    fn test_render_wifi() {
+        let yaml = r#"
+version: 2
+wifis:
+  wlan0:
+    dhcp4: true
+    access-points:
+      "home net":
+        password: "s3cret-pass"
+        hidden: true
+"#;
+        let config: NetworkConfig = serde_yaml::from_str(yaml).unwrap();
+        let files = EniRenderer::new()
+            .render(&config, Path::new("/tmp"))
+            .unwrap();
+        assert!(!files[0].content.contains("s3cret-pass"));
+        assert!(
+            files[0]
+                .content
+                .ends_with("source /etc/network/interfaces.d/50-cloud-init-wifi\n")
+        );
+        assert_eq!(files[1].path, "interfaces.d/50-cloud-init-wifi");
+        assert_eq!(files[1].mode, 0o600);
+        assert!(files[1].content.contains(
+            "auto wlan0\niface wlan0 inet dhcp\n    wpa-ssid home net\n    wpa-psk s3cret-pass\n    wpa-scan-ssid 1\n"
+        ));
+    }
+
     #[test]
     fn test_prefix_to_netmask() {
         let renderer = EniRenderer::new();
@@ -290,4 +678,33 @@ mod tests {
         assert_eq!(renderer.prefix_to_netmask(25), "255.255.255.128");
         assert_eq!(renderer.prefix_to_netmask(32), "255.255.255.255");
     }
+
+    mod properties {
+        use super::*;
+        use crate::network::v1::netmask_to_prefix;
+        use proptest::prelude::*;
+
+        proptest! {
+            #[test]
+            fn netmask_round_trips(prefix in 0u8..=32) {
+                let netmask = EniRenderer::new().prefix_to_netmask(prefix);
+                prop_assert_eq!(netmask_to_prefix(&netmask), prefix);
+            }
+
+            #[test]
+            fn parse_cidr_keeps_address_and_prefix(ip in any::<[u8; 4]>(), prefix in 0u8..=32) {
+                let address = format!("{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3]);
+                let (parsed, netmask) =
+                    EniRenderer::new().parse_cidr(&format!("{}/{}", address, prefix));
+                prop_assert_eq!(parsed, address);
+                prop_assert_eq!(netmask_to_prefix(&netmask), prefix);
+            }
+
+            #[test]
+            fn parse_cidr_never_panics(cidr in "\\PC{0,40}") {
+                let (_, netmask) = EniRenderer::new().parse_cidr(&cidr);
+                prop_assert_eq!(netmask.split('.').count(), 4);
+            }
+        }
+    }
 }
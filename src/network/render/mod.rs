@@ -6,15 +6,25 @@
 //! - `networkd` - systemd-networkd (*.network files)
 //! - `network_manager` - NetworkManager (*.nmconnection files)
 //! - `eni` - Debian ENI (/etc/network/interfaces)
+//! - `sysconfig` - ifcfg files (RHEL/CentOS 7 network-scripts, SUSE wicked)
+//! - `rc_conf` - FreeBSD rc.conf (with the `freebsd` feature)
 
 pub mod eni;
 pub mod network_manager;
 pub mod networkd;
+#[cfg(feature = "freebsd")]
+pub mod rc_conf;
+pub mod sysconfig;
 
 use crate::CloudInitError;
+use crate::distro::Distro;
 use crate::network::NetworkConfig;
+use crate::network::state::{self, NetworkState};
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use crate::service::ServiceManager;
 use std::path::Path;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 /// Network renderer types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -25,29 +35,50 @@ pub enum RendererType {
     NetworkManager,
     /// Debian ENI (/etc/network/interfaces)
     Eni,
+    /// ifcfg files read by network-scripts or wicked
+    Sysconfig,
+    /// FreeBSD rc.conf
+    #[cfg(feature = "freebsd")]
+    RcConf,
 }
 
 impl RendererType {
-    /// Detect the appropriate renderer for this system
-    pub async fn detect() -> Option<Self> {
-        // Check for systemd-networkd
-        if Path::new("/run/systemd/system").exists()
-            && Path::new("/lib/systemd/systemd-networkd").exists()
-        {
-            return Some(Self::Networkd);
-        }
-
-        // Check for NetworkManager
-        if Path::new("/usr/sbin/NetworkManager").exists() || Path::new("/usr/bin/nmcli").exists() {
-            return Some(Self::NetworkManager);
-        }
+    /// Detect the appropriate renderer for the system at `root`
+    ///
+    /// The first installed renderer in the distribution's order of
+    /// preference wins.
+    pub async fn detect(root: &RootContext) -> Option<Self> {
+        let distro = Distro::detect(root).await;
+        distro
+            .renderer_preference()
+            .iter()
+            .copied()
+            .find(|renderer| renderer.is_installed(root))
+    }
 
-        // Check for ENI (Debian/Ubuntu without systemd)
-        if Path::new("/etc/network/interfaces").exists() {
-            return Some(Self::Eni);
+    /// Whether the system at `root` can use this renderer
+    ///
+    /// Under an alternate root systemd is not running, so networkd counts
+    /// whenever it is installed.
+    fn is_installed(self, root: &RootContext) -> bool {
+        match self {
+            Self::Networkd => {
+                (!root.is_host() || Path::new("/run/systemd/system").exists())
+                    && root.path("/lib/systemd/systemd-networkd").exists()
+            }
+            Self::NetworkManager => {
+                root.path("/usr/sbin/NetworkManager").exists()
+                    || root.path("/usr/bin/nmcli").exists()
+            }
+            Self::Eni => root.path("/etc/network/interfaces").exists(),
+            Self::Sysconfig => {
+                root.path("/etc/sysconfig/network-scripts/network-functions")
+                    .exists()
+                    || root.path("/usr/sbin/wicked").exists()
+            }
+            #[cfg(feature = "freebsd")]
+            Self::RcConf => root.path("/etc/rc.d/netif").exists(),
         }
-
-        None
     }
 
     /// Get renderer from string hint
@@ -56,6 +87,9 @@ impl RendererType {
             "networkd" | "systemd-networkd" => Some(Self::Networkd),
             "networkmanager" | "network-manager" | "nm" => Some(Self::NetworkManager),
             "eni" | "interfaces" | "ifupdown" => Some(Self::Eni),
+            "sysconfig" | "ifcfg" | "network-scripts" | "wicked" => Some(Self::Sysconfig),
+            #[cfg(feature = "freebsd")]
+            "freebsd" | "rc.conf" => Some(Self::RcConf),
             _ => None,
         }
     }
@@ -89,17 +123,24 @@ pub struct RenderedFile {
 }
 
 /// Apply network configuration using the appropriate renderer
+///
+/// Files are written beneath `root`. Under an alternate root the network
+/// services are not reloaded; the config takes effect when it boots.
 pub async fn apply_network_config(
+    root: &RootContext,
     config: &NetworkConfig,
     renderer_hint: Option<&str>,
 ) -> Result<(), CloudInitError> {
+    // Refuse to write anything for a config that would render broken files
+    crate::network::validate::validate(config)?;
+
     // Determine renderer
     let renderer_type = if let Some(hint) = renderer_hint {
         RendererType::from_hint(hint)
     } else if let Some(hint) = &config.renderer {
         RendererType::from_hint(hint)
     } else {
-        RendererType::detect().await
+        RendererType::detect(root).await
     };
 
     let renderer_type = renderer_type.ok_or_else(|| CloudInitError::Module {
@@ -110,10 +151,14 @@ pub async fn apply_network_config(
     info!("Using network renderer: {:?}", renderer_type);
 
     // Get output directory based on renderer
+    let flavor = sysconfig::SysconfigFlavor::detect(root).await;
     let output_dir = match renderer_type {
         RendererType::Networkd => Path::new("/etc/systemd/network"),
         RendererType::NetworkManager => Path::new("/etc/NetworkManager/system-connections"),
         RendererType::Eni => Path::new("/etc/network"),
+        RendererType::Sysconfig => flavor.output_dir(),
+        #[cfg(feature = "freebsd")]
+        RendererType::RcConf => Path::new(rc_conf::OUTPUT_DIR),
     };
 
     // Create renderer and render files
@@ -130,70 +175,179 @@ pub async fn apply_network_config(
             let renderer = eni::EniRenderer::new();
             renderer.render(config, output_dir)?
         }
-    };
-
-    // Write files
-    for file in &files {
-        let full_path = output_dir.join(&file.path);
-        debug!("Writing network config: {}", full_path.display());
-
-        // Create parent directories
-        if let Some(parent) = full_path.parent() {
-            tokio::fs::create_dir_all(parent).await?;
+        RendererType::Sysconfig => {
+            let renderer = sysconfig::SysconfigRenderer::new(flavor);
+            renderer.render(config, output_dir)?
+        }
+        #[cfg(feature = "freebsd")]
+        RendererType::RcConf => {
+            let renderer = rc_conf::RcConfRenderer::new();
+            renderer.render(config, output_dir)?
         }
+    };
 
-        // Write file
-        tokio::fs::write(&full_path, &file.content).await?;
+    let target_dir = root.path(output_dir);
+    if !root.is_host() {
+        write_files(root, &target_dir, &files).await?;
+        info!(
+            "Wrote {} network configuration files under {}",
+            files.len(),
+            root.root().display()
+        );
+        return Ok(());
+    }
 
-        // Set permissions
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            tokio::fs::set_permissions(&full_path, std::fs::Permissions::from_mode(file.mode))
-                .await?;
+    // Per-boot runs usually render exactly what is already on disk; only
+    // touch networking when the files or the live state differ
+    if state::files_unchanged(&target_dir, &files).await {
+        let drift = NetworkState::read().await.drift(config);
+        if drift.is_empty() {
+            info!("Network configuration unchanged and already applied");
+            return Ok(());
         }
+        info!(
+            "Network configuration unchanged but not in effect ({}); reloading",
+            drift.join("; ")
+        );
+    } else {
+        write_files(root, &target_dir, &files).await?;
+        info!("Wrote {} network configuration files", files.len());
     }
 
-    info!("Wrote {} network configuration files", files.len());
-
     // Reload/restart network service
+    let runner = root.runner();
     match renderer_type {
         RendererType::Networkd => {
-            reload_networkd().await?;
+            reload_networkd(runner.as_ref()).await?;
+            for name in config.wifis.keys() {
+                start_wpa_supplicant(runner.as_ref(), name).await;
+            }
         }
         RendererType::NetworkManager => {
-            reload_network_manager().await?;
+            reload_network_manager(runner.as_ref()).await?;
+        }
+        RendererType::Eni if ServiceManager::detect(root) == ServiceManager::OpenRc => {
+            // ifupdown-ng, as on Alpine, brings up what is not up yet
+            bring_up_interfaces(runner.as_ref()).await;
         }
         RendererType::Eni => {
             // ENI typically requires ifup/ifdown or reboot
             debug!("ENI config written, may require ifup or reboot");
         }
+        RendererType::Sysconfig => {
+            restart_network_service(runner.as_ref()).await;
+        }
+        #[cfg(feature = "freebsd")]
+        RendererType::RcConf => {
+            restart_freebsd_network(runner.as_ref()).await;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write rendered files under `output_dir` with their permissions
+///
+/// Files with an absolute path (e.g. wpa_supplicant configs) are placed
+/// at that path beneath `root` instead.
+async fn write_files(
+    root: &RootContext,
+    output_dir: &Path,
+    files: &[RenderedFile],
+) -> Result<(), CloudInitError> {
+    for file in files {
+        let full_path = if Path::new(&file.path).is_absolute() {
+            root.path(&file.path)
+        } else {
+            output_dir.join(&file.path)
+        };
+        debug!("Writing network config: {}", full_path.display());
+
+        // Create parent directories
+        if let Some(parent) = full_path.parent() {
+            root.create_dir_all(parent).await?;
+        }
+
+        // Created with its mode, so secrets are never readable by others
+        root.write_file_mode(&full_path, &file.content, file.mode)
+            .await?;
     }
 
     Ok(())
 }
 
+/// Start the per-interface wpa_supplicant unit networkd relies on for wifi
+async fn start_wpa_supplicant(runner: &dyn SystemRunner, interface: &str) {
+    let unit = format!("wpa_supplicant@{}.service", interface);
+    debug!("Starting {}", unit);
+
+    match runner
+        .run(&SystemCommand::new("systemctl").args(["enable", "--now", &unit]))
+        .await
+    {
+        Ok(o) if o.is_success() => info!("Started {}", unit),
+        Ok(o) => warn!("Failed to start {}: {}", unit, o.stderr.trim()),
+        Err(e) => warn!("Failed to run systemctl for {}: {}", unit, e),
+    }
+}
+
+/// Bring up the interfaces in /etc/network/interfaces
+async fn bring_up_interfaces(runner: &dyn SystemRunner) {
+    match runner.run(&SystemCommand::new("ifup").arg("-a")).await {
+        Ok(o) if o.is_success() => info!("Brought up ENI interfaces"),
+        Ok(o) => warn!("ifup -a failed: {}", o.stderr.trim()),
+        Err(e) => warn!("Failed to run ifup: {}", e),
+    }
+}
+
+/// Restart the network service reading ifcfg files
+///
+/// `network.service` is network-scripts on RHEL and an alias of wicked
+/// on SUSE.
+async fn restart_network_service(runner: &dyn SystemRunner) {
+    match runner
+        .run(&SystemCommand::new("systemctl").args(["restart", "network"]))
+        .await
+    {
+        Ok(o) if o.is_success() => info!("Network service restarted"),
+        Ok(o) => warn!("Failed to restart network service: {}", o.stderr.trim()),
+        Err(e) => warn!("Failed to run systemctl: {}", e),
+    }
+}
+
+/// Restart FreeBSD's interface and routing rc scripts
+#[cfg(feature = "freebsd")]
+async fn restart_freebsd_network(runner: &dyn SystemRunner) {
+    for script in ["netif", "routing"] {
+        match runner
+            .run(&SystemCommand::new("service").args([script, "restart"]))
+            .await
+        {
+            Ok(o) if o.is_success() => info!("Restarted {}", script),
+            Ok(o) => warn!("Failed to restart {}: {}", script, o.stderr.trim()),
+            Err(e) => warn!("Failed to run service {} restart: {}", script, e),
+        }
+    }
+}
+
 /// Reload systemd-networkd
-async fn reload_networkd() -> Result<(), CloudInitError> {
+async fn reload_networkd(runner: &dyn SystemRunner) -> Result<(), CloudInitError> {
     debug!("Reloading systemd-networkd");
 
-    let output = tokio::process::Command::new("networkctl")
-        .arg("reload")
-        .output()
+    let output = runner
+        .run(&SystemCommand::new("networkctl").arg("reload"))
         .await;
 
     match output {
-        Ok(o) if o.status.success() => {
+        Ok(o) if o.is_success() => {
             info!("systemd-networkd reloaded");
             Ok(())
         }
         Ok(o) => {
-            let stderr = String::from_utf8_lossy(&o.stderr);
-            debug!("networkctl reload failed: {}", stderr);
+            debug!("networkctl reload failed: {}", o.stderr);
             // Try systemctl restart as fallback
-            let _ = tokio::process::Command::new("systemctl")
-                .args(["restart", "systemd-networkd"])
-                .output()
+            let _ = runner
+                .run(&SystemCommand::new("systemctl").args(["restart", "systemd-networkd"]))
                 .await;
             Ok(())
         }
@@ -205,21 +359,19 @@ async fn reload_networkd() -> Result<(), CloudInitError> {
 }
 
 /// Reload NetworkManager
-async fn reload_network_manager() -> Result<(), CloudInitError> {
+async fn reload_network_manager(runner: &dyn SystemRunner) -> Result<(), CloudInitError> {
     debug!("Reloading NetworkManager connections");
 
-    let output = tokio::process::Command::new("nmcli")
-        .args(["connection", "reload"])
-        .output()
+    let output = runner
+        .run(&SystemCommand::new("nmcli").args(["connection", "reload"]))
         .await;
 
     match output {
-        Ok(o) if o.status.success() => {
+        Ok(o) if o.is_success() => {
             info!("NetworkManager connections reloaded");
         }
         Ok(o) => {
-            let stderr = String::from_utf8_lossy(&o.stderr);
-            debug!("nmcli reload failed: {}", stderr);
+            debug!("nmcli reload failed: {}", o.stderr);
         }
         Err(e) => {
             debug!("nmcli not available: {}", e);
@@ -244,6 +396,37 @@ mod tests {
             Some(RendererType::NetworkManager)
         );
         assert_eq!(RendererType::from_hint("eni"), Some(RendererType::Eni));
+        assert_eq!(
+            RendererType::from_hint("sysconfig"),
+            Some(RendererType::Sysconfig)
+        );
         assert_eq!(RendererType::from_hint("unknown"), None);
     }
+
+    #[tokio::test]
+    async fn test_detect_follows_distro_preference() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        for dir in ["/etc/network", "/lib/systemd", "/usr/bin"] {
+            std::fs::create_dir_all(root.path(dir)).unwrap();
+        }
+        for file in [
+            "/etc/network/interfaces",
+            "/lib/systemd/systemd-networkd",
+            "/usr/bin/nmcli",
+        ] {
+            std::fs::write(root.path(file), "").unwrap();
+        }
+
+        let detect = |os_release: &str| {
+            std::fs::write(root.path("/etc/os-release"), os_release).unwrap();
+            RendererType::detect(&root)
+        };
+        assert_eq!(detect("ID=ubuntu\n").await, Some(RendererType::Networkd));
+        assert_eq!(
+            detect("ID=rocky\nID_LIKE=rhel\n").await,
+            Some(RendererType::NetworkManager)
+        );
+        assert_eq!(detect("ID=debian\n").await, Some(RendererType::Eni));
+    }
 }
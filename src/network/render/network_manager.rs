@@ -1,13 +1,22 @@
 //! NetworkManager renderer
 //!
 //! Generates .nmconnection files for NetworkManager.
+//!
+//! Bonds, bridges and VLANs get their own profiles; member interfaces
+//! reference their bond or bridge through `controller`/`port-type`.
+//! Each wifi access point becomes its own `type=wifi` profile.
 
 use super::{RenderedFile, Renderer, RendererType};
 use crate::CloudInitError;
-use crate::network::{EthernetConfig, InterfaceCommon, NetworkConfig};
+use crate::network::{
+    BondConfig, BridgeConfig, Dhcp6Mode, EthernetConfig, InterfaceCommon, MatchConfig,
+    NetworkConfig, VlanConfig, WifiConfig,
+};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::fmt::Write;
 use std::path::Path;
-use uuid::Uuid;
+use uuid::{Builder, Uuid};
 
 /// NetworkManager renderer
 pub struct NetworkManagerRenderer;
@@ -17,38 +26,248 @@ impl NetworkManagerRenderer {
         Self
     }
 
-    fn render_ethernet(&self, name: &str, config: &EthernetConfig) -> RenderedFile {
-        let uuid = Uuid::new_v4();
+    fn render_ethernet(
+        &self,
+        name: &str,
+        config: &EthernetConfig,
+        port: Option<&Port<'_>>,
+    ) -> RenderedFile {
+        let mac = config
+            .match_config
+            .as_ref()
+            .and_then(|m| m.macaddress.as_deref());
+        let interface_name = interface_name(name, &config.match_config, &config.common);
+
+        let mut ethernet = Vec::new();
+        if let Some(mac) = mac {
+            ethernet.push(format!("mac-address={}", mac));
+        }
+        if let Some(mtu) = config.common.mtu {
+            ethernet.push(format!("mtu={}", mtu));
+        }
+        if let Some(wol) = config.common.wakeonlan {
+            ethernet.push(format!("wake-on-lan={}", if wol { 64 } else { 0 }));
+        }
+
+        self.render_connection(
+            name,
+            "ethernet",
+            interface_name,
+            &config.common,
+            ("ethernet", &ethernet),
+            port,
+        )
+    }
+
+    /// Render one profile per access point of a wifi interface
+    fn render_wifi(&self, name: &str, config: &WifiConfig) -> Vec<RenderedFile> {
+        let mac = config
+            .match_config
+            .as_ref()
+            .and_then(|m| m.macaddress.as_deref());
+        let interface_name = interface_name(name, &config.match_config, &config.common);
+
+        let access_points: BTreeMap<_, _> = config.access_points.iter().collect();
+        access_points
+            .into_iter()
+            .map(|(ssid, ap)| {
+                let mut wifi = vec![format!("ssid={}", ssid)];
+                wifi.push(format!(
+                    "mode={}",
+                    ap.mode.as_deref().unwrap_or("infrastructure")
+                ));
+                match ap.band.as_deref() {
+                    Some("5GHz") => wifi.push("band=a".to_string()),
+                    Some("2.4GHz") => wifi.push("band=bg".to_string()),
+                    _ => {}
+                }
+                if let Some(channel) = ap.channel {
+                    wifi.push(format!("channel={}", channel));
+                }
+                if let Some(bssid) = &ap.bssid {
+                    wifi.push(format!("bssid={}", bssid));
+                }
+                if ap.hidden == Some(true) {
+                    wifi.push("hidden=true".to_string());
+                }
+                if let Some(mac) = mac {
+                    wifi.push(format!("mac-address={}", mac));
+                }
+                if let Some(mtu) = config.common.mtu {
+                    wifi.push(format!("mtu={}", mtu));
+                }
+
+                let id = format!("{}-{}", name, sanitize_id(ssid));
+                let mut file = self.render_connection(
+                    &id,
+                    "wifi",
+                    interface_name,
+                    &config.common,
+                    ("wifi", &wifi),
+                    None,
+                );
+                if let Some(password) = &ap.password {
+                    writeln!(file.content, "[wifi-security]").unwrap();
+                    writeln!(file.content, "key-mgmt=wpa-psk").unwrap();
+                    writeln!(file.content, "psk={}", password).unwrap();
+                    writeln!(file.content).unwrap();
+                }
+                file
+            })
+            .collect()
+    }
+
+    fn render_bond(
+        &self,
+        name: &str,
+        config: &BondConfig,
+        port: Option<&Port<'_>>,
+    ) -> RenderedFile {
+        let mut bond = Vec::new();
+        if let Some(params) = &config.parameters {
+            if let Some(mode) = &params.mode {
+                bond.push(format!("mode={}", mode));
+            }
+            if let Some(miimon) = params.mii_monitor_interval {
+                bond.push(format!("miimon={}", miimon));
+            }
+            if let Some(primary) = &params.primary {
+                bond.push(format!("primary={}", primary));
+            }
+            if let Some(policy) = &params.transmit_hash_policy {
+                bond.push(format!("xmit_hash_policy={}", policy));
+            }
+            if let Some(rate) = &params.lacp_rate {
+                bond.push(format!("lacp_rate={}", rate));
+            }
+            if let Some(interval) = params.arp_interval {
+                bond.push(format!("arp_interval={}", interval));
+            }
+            if !params.arp_ip_targets.is_empty() {
+                bond.push(format!("arp_ip_target={}", params.arp_ip_targets.join(",")));
+            }
+        }
+
+        self.render_connection(
+            name,
+            "bond",
+            Some(name),
+            &config.common,
+            ("bond", &bond),
+            port,
+        )
+    }
+
+    fn render_bridge(
+        &self,
+        name: &str,
+        config: &BridgeConfig,
+        port: Option<&Port<'_>>,
+    ) -> RenderedFile {
+        let mut bridge = Vec::new();
+        if let Some(params) = &config.parameters {
+            if let Some(stp) = params.stp {
+                bridge.push(format!("stp={}", stp));
+            }
+            if let Some(priority) = params.priority {
+                bridge.push(format!("priority={}", priority));
+            }
+            if let Some(delay) = params.forward_delay {
+                bridge.push(format!("forward-delay={}", delay));
+            }
+            if let Some(hello) = params.hello_time {
+                bridge.push(format!("hello-time={}", hello));
+            }
+            if let Some(age) = params.max_age {
+                bridge.push(format!("max-age={}", age));
+            }
+            if let Some(ageing) = params.ageing_time {
+                bridge.push(format!("ageing-time={}", ageing));
+            }
+        }
+
+        self.render_connection(
+            name,
+            "bridge",
+            Some(name),
+            &config.common,
+            ("bridge", &bridge),
+            port,
+        )
+    }
+
+    fn render_vlan(
+        &self,
+        name: &str,
+        config: &VlanConfig,
+        port: Option<&Port<'_>>,
+    ) -> RenderedFile {
+        let vlan = vec![
+            format!("id={}", config.id),
+            format!("parent={}", config.link),
+        ];
+        self.render_connection(
+            name,
+            "vlan",
+            Some(name),
+            &config.common,
+            ("vlan", &vlan),
+            port,
+        )
+    }
+
+    /// Render a complete keyfile profile
+    ///
+    /// Ports of a bond or bridge carry `controller`/`port-type` and no IP
+    /// configuration; NetworkManager ignores it on ports anyway.
+    fn render_connection(
+        &self,
+        name: &str,
+        conn_type: &str,
+        interface_name: Option<&str>,
+        common: &InterfaceCommon,
+        (section, settings): (&str, &[String]),
+        port: Option<&Port<'_>>,
+    ) -> RenderedFile {
+        let uuid = profile_uuid(name);
         let mut content = String::new();
 
         // [connection] section
         writeln!(content, "[connection]").unwrap();
         writeln!(content, "id={}", name).unwrap();
         writeln!(content, "uuid={}", uuid).unwrap();
-        writeln!(content, "type=ethernet").unwrap();
-        writeln!(content, "interface-name={}", name).unwrap();
+        writeln!(content, "type={}", conn_type).unwrap();
+        if let Some(interface_name) = interface_name {
+            writeln!(content, "interface-name={}", interface_name).unwrap();
+        }
+        if let Some(port) = port {
+            writeln!(content, "controller={}", port.controller).unwrap();
+            writeln!(content, "port-type={}", port.port_type).unwrap();
+        }
         writeln!(content).unwrap();
 
-        // [ethernet] section
-        writeln!(content, "[ethernet]").unwrap();
-        if let Some(match_config) = &config.match_config
-            && let Some(mac) = &match_config.macaddress
-        {
-            writeln!(content, "mac-address={}", mac).unwrap();
+        // Type-specific section, always present so the profile is explicit
+        writeln!(content, "[{}]", section).unwrap();
+        for setting in settings {
+            writeln!(content, "{}", setting).unwrap();
         }
-        if let Some(mtu) = config.common.mtu {
+        if !matches!(conn_type, "ethernet" | "wifi")
+            && let Some(mtu) = common.mtu
+        {
+            // Non-ethernet types carry MTU in [ethernet] too
+            writeln!(content).unwrap();
+            writeln!(content, "[ethernet]").unwrap();
             writeln!(content, "mtu={}", mtu).unwrap();
         }
-        if let Some(wol) = config.common.wakeonlan {
-            writeln!(content, "wake-on-lan={}", if wol { 64 } else { 0 }).unwrap();
-        }
         writeln!(content).unwrap();
 
-        // IPv4 section
-        self.write_ipv4_section(&mut content, &config.common);
+        if port.is_none() {
+            // IPv4 section
+            self.write_ipv4_section(&mut content, common);
 
-        // IPv6 section
-        self.write_ipv6_section(&mut content, &config.common);
+            // IPv6 section
+            self.write_ipv6_section(&mut content, common);
+        }
 
         RenderedFile {
             path: format!("{}.nmconnection", name),
@@ -60,18 +279,17 @@ impl NetworkManagerRenderer {
     fn write_ipv4_section(&self, content: &mut String, common: &InterfaceCommon) {
         writeln!(content, "[ipv4]").unwrap();
 
+        // Filter IPv4 addresses
+        let ipv4_addrs: Vec<_> = common
+            .addresses
+            .iter()
+            .filter(|a| !a.contains(':'))
+            .collect();
+
         if common.dhcp4 == Some(true) {
             writeln!(content, "method=auto").unwrap();
-        } else if !common.addresses.is_empty() {
+        } else if !ipv4_addrs.is_empty() {
             writeln!(content, "method=manual").unwrap();
-
-            // Filter IPv4 addresses
-            let ipv4_addrs: Vec<_> = common
-                .addresses
-                .iter()
-                .filter(|a| !a.contains(':'))
-                .collect();
-
             for (i, addr) in ipv4_addrs.iter().enumerate() {
                 writeln!(content, "address{}={}", i + 1, addr).unwrap();
             }
@@ -96,29 +314,12 @@ impl NetworkManagerRenderer {
             writeln!(content, "dns={}", ipv4_dns.join(";")).unwrap();
         }
 
-        if !common.nameservers.search.is_empty() {
-            writeln!(
-                content,
-                "dns-search={}",
-                common.nameservers.search.join(";")
-            )
-            .unwrap();
+        if !ipv4_disabled(common) {
+            write_dns_search(content, common);
         }
 
         // Routes
-        for (i, route) in common.routes.iter().enumerate() {
-            if route.to.contains(':') {
-                continue; // Skip IPv6 routes
-            }
-            let mut route_str = route.to.clone();
-            if let Some(via) = &route.via {
-                route_str = format!("{},{}", route_str, via);
-            }
-            if let Some(metric) = route.metric {
-                route_str = format!("{},{}", route_str, metric);
-            }
-            writeln!(content, "route{}={}", i + 1, route_str).unwrap();
-        }
+        write_routes(content, common, false);
 
         writeln!(content).unwrap();
     }
@@ -126,26 +327,35 @@ impl NetworkManagerRenderer {
     fn write_ipv6_section(&self, content: &mut String, common: &InterfaceCommon) {
         writeln!(content, "[ipv6]").unwrap();
 
-        if common.dhcp6 == Some(true) {
-            writeln!(content, "method=auto").unwrap();
-        } else if common.accept_ra == Some(true) {
+        // Static IPv6 addresses, kept alongside DHCPv6 and SLAAC ones
+        let ipv6_addrs: Vec<_> = common
+            .addresses
+            .iter()
+            .filter(|a| a.contains(':'))
+            .collect();
+
+        // method=auto follows the RA's M and O flags, which covers both
+        // SLAAC with stateless DHCPv6 and RA-triggered stateful DHCPv6;
+        // method=dhcp runs stateful DHCPv6 whatever the RA says
+        if common.dhcp6_stateless()
+            || (common.accept_ra == Some(true) && common.dhcp6 != Some(true))
+        {
             writeln!(content, "method=auto").unwrap();
             writeln!(content, "addr-gen-mode=eui64").unwrap();
+        } else if common.dhcp6 == Some(true)
+            && (common.accept_ra == Some(false) || common.dhcp6_mode == Some(Dhcp6Mode::Stateful))
+        {
+            writeln!(content, "method=dhcp").unwrap();
+        } else if common.dhcp6 == Some(true) {
+            writeln!(content, "method=auto").unwrap();
+        } else if !ipv6_addrs.is_empty() {
+            writeln!(content, "method=manual").unwrap();
         } else {
-            // Check for IPv6 static addresses
-            let ipv6_addrs: Vec<_> = common
-                .addresses
-                .iter()
-                .filter(|a| a.contains(':'))
-                .collect();
-
-            if !ipv6_addrs.is_empty() {
-                writeln!(content, "method=manual").unwrap();
-                for (i, addr) in ipv6_addrs.iter().enumerate() {
-                    writeln!(content, "address{}={}", i + 1, addr).unwrap();
-                }
-            } else {
-                writeln!(content, "method=ignore").unwrap();
+            writeln!(content, "method=ignore").unwrap();
+        }
+        if common.dhcp6 == Some(true) || common.accept_ra == Some(true) || !ipv6_addrs.is_empty() {
+            for (i, addr) in ipv6_addrs.iter().enumerate() {
+                writeln!(content, "address{}={}", i + 1, addr).unwrap();
             }
         }
 
@@ -166,10 +376,120 @@ impl NetworkManagerRenderer {
             writeln!(content, "dns={}", ipv6_dns.join(";")).unwrap();
         }
 
+        // NetworkManager ignores DNS settings of a disabled family, so
+        // IPv6-only profiles carry the search domains here
+        if ipv4_disabled(common) {
+            write_dns_search(content, common);
+        }
+
+        // Routes
+        write_routes(content, common, true);
+
         writeln!(content).unwrap();
     }
 }
 
+/// Whether a profile's `[ipv4]` section is `method=disabled`
+fn ipv4_disabled(common: &InterfaceCommon) -> bool {
+    common.dhcp4 != Some(true) && !common.addresses.iter().any(|a| !a.contains(':'))
+}
+
+/// Write `dns-search` for the profile's search domains, if any
+fn write_dns_search(content: &mut String, common: &InterfaceCommon) {
+    if !common.nameservers.search.is_empty() {
+        writeln!(
+            content,
+            "dns-search={}",
+            common.nameservers.search.join(";")
+        )
+        .unwrap();
+    }
+}
+
+/// Interface name a profile binds to
+///
+/// Without a MAC match the profile binds by name; a literal match.name
+/// wins over the config key. With a MAC match the profile only binds by
+/// name when set-name asks for a rename.
+fn interface_name<'a>(
+    name: &'a str,
+    match_config: &'a Option<MatchConfig>,
+    common: &'a InterfaceCommon,
+) -> Option<&'a str> {
+    let match_config = match_config.as_ref();
+    match (
+        &common.set_name,
+        match_config.and_then(|m| m.macaddress.as_ref()),
+    ) {
+        (Some(set_name), _) => Some(set_name.as_str()),
+        (None, Some(_)) => None,
+        (None, None) => Some(
+            match_config
+                .and_then(|m| m.name.as_deref())
+                .filter(|n| !n.contains(['*', '?', '[']))
+                .unwrap_or(name),
+        ),
+    }
+}
+
+/// Stable UUID for a profile id, so re-rendering yields identical files
+fn profile_uuid(id: &str) -> Uuid {
+    let digest = Sha256::digest(format!("cloud-init-rs:{}", id).as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    Builder::from_custom_bytes(bytes).into_uuid()
+}
+
+/// Make an SSID safe for a profile id and file name
+fn sanitize_id(ssid: &str) -> String {
+    ssid.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Bond or bridge membership of a port interface
+struct Port<'a> {
+    controller: &'a str,
+    port_type: &'static str,
+}
+
+/// Write keyfile `routeN` entries for one address family
+///
+/// Keyfile routes are `dest,gateway,metric`; an all-zeros gateway
+/// stands in for a directly connected route that still needs a metric.
+fn write_routes(content: &mut String, common: &InterfaceCommon, ipv6: bool) {
+    let unspecified = if ipv6 { "::" } else { "0.0.0.0" };
+    let routes = common.routes.iter().filter(|r| r.to.contains(':') == ipv6);
+
+    for (i, route) in routes.enumerate() {
+        let mut route_str = route.to.clone();
+        match (&route.via, route.metric) {
+            (Some(via), Some(metric)) => write!(route_str, ",{},{}", via, metric).unwrap(),
+            (Some(via), None) => write!(route_str, ",{}", via).unwrap(),
+            (None, Some(metric)) => write!(route_str, ",{},{}", unspecified, metric).unwrap(),
+            (None, None) => {}
+        }
+        writeln!(content, "route{}={}", i + 1, route_str).unwrap();
+
+        let mut options = Vec::new();
+        if let Some(table) = route.table {
+            options.push(format!("table={}", table));
+        }
+        if route.on_link == Some(true) {
+            options.push("onlink=true".to_string());
+        }
+        if !options.is_empty() {
+            writeln!(content, "route{}_options={}", i + 1, options.join(",")).unwrap();
+        }
+    }
+}
+
 impl Default for NetworkManagerRenderer {
     fn default() -> Self {
         Self::new()
@@ -184,13 +504,56 @@ impl Renderer for NetworkManagerRenderer {
     ) -> Result<Vec<RenderedFile>, CloudInitError> {
         let mut files = Vec::new();
 
-        // Render ethernets
-        for (name, eth_config) in &config.ethernets {
-            files.push(self.render_ethernet(name, eth_config));
+        // Which controller each port belongs to
+        let mut ports: BTreeMap<&str, Port<'_>> = BTreeMap::new();
+        for (controller, bond) in &config.bonds {
+            for member in &bond.interfaces {
+                ports.insert(
+                    member,
+                    Port {
+                        controller,
+                        port_type: "bond",
+                    },
+                );
+            }
+        }
+        for (controller, bridge) in &config.bridges {
+            for member in &bridge.interfaces {
+                ports.insert(
+                    member,
+                    Port {
+                        controller,
+                        port_type: "bridge",
+                    },
+                );
+            }
+        }
+
+        // Render in a stable order: ethernets, bonds, bridges, VLANs, wifis
+        let ethernets: BTreeMap<_, _> = config.ethernets.iter().collect();
+        for (name, eth_config) in ethernets {
+            files.push(self.render_ethernet(name, eth_config, ports.get(name.as_str())));
         }
 
-        // TODO: Implement bonds, bridges, VLANs for NetworkManager
-        // These require additional connection types and more complex configuration
+        let bonds: BTreeMap<_, _> = config.bonds.iter().collect();
+        for (name, bond) in bonds {
+            files.push(self.render_bond(name, bond, ports.get(name.as_str())));
+        }
+
+        let bridges: BTreeMap<_, _> = config.bridges.iter().collect();
+        for (name, bridge) in bridges {
+            files.push(self.render_bridge(name, bridge, ports.get(name.as_str())));
+        }
+
+        let vlans: BTreeMap<_, _> = config.vlans.iter().collect();
+        for (name, vlan) in vlans {
+            files.push(self.render_vlan(name, vlan, ports.get(name.as_str())));
+        }
+
+        let wifis: BTreeMap<_, _> = config.wifis.iter().collect();
+        for (name, wifi) in wifis {
+            files.extend(self.render_wifi(name, wifi));
+        }
 
         Ok(files)
     }
@@ -273,4 +636,191 @@ mod tests {
         assert!(files[0].content.contains("gateway=192.168.1.1"));
         assert!(files[0].content.contains("dns=8.8.8.8"));
     }
+
+    fn render_yaml(yaml: &str) -> Vec<RenderedFile> {
+        let config: NetworkConfig = serde_yaml::from_str(yaml).unwrap();
+        NetworkManagerRenderer::new()
+            .render(&config, Path::new("/tmp"))
+            .unwrap()
+    }
+
+    fn file<'a>(files: &'a [RenderedFile], name: &str) -> &'a str {
+        &files
+            .iter()
+            .find(|f| f.path == format!("{}.nmconnection", name))
+            .unwrap()
+            .content
+    }
+
+    #[test]
+    fn test_render_bond_with_ports() {
+        let files = render_yaml(
+            r#"
+version: 2
+ethernets:
+  eth0: {}
+  eth1: {}
+bonds:
+  bond0:
+    interfaces: [eth0, eth1]
+    dhcp4: true
+    parameters:
+      mode: active-backup
+      mii-monitor-interval: 100
+      primary: eth0
+"#,
+        );
+        assert_eq!(files.len(), 3);
+
+        let eth0 = file(&files, "eth0");
+        assert!(eth0.contains("controller=bond0\nport-type=bond\n"));
+        assert!(!eth0.contains("[ipv4]"));
+
+        let bond = file(&files, "bond0");
+        assert!(bond.contains("type=bond\ninterface-name=bond0\n"));
+        assert!(bond.contains("[bond]\nmode=active-backup\nmiimon=100\nprimary=eth0\n"));
+        assert!(bond.contains("[ipv4]\nmethod=auto"));
+    }
+
+    #[test]
+    fn test_render_bridge_over_bond_and_vlan() {
+        let files = render_yaml(
+            r#"
+version: 2
+ethernets:
+  eth0: {}
+bonds:
+  bond0:
+    interfaces: [eth0]
+bridges:
+  br0:
+    interfaces: [bond0]
+    addresses: [10.0.0.2/24]
+    parameters:
+      stp: false
+      forward-delay: 0
+vlans:
+  vlan10:
+    id: 10
+    link: br0
+    mtu: 1400
+"#,
+        );
+
+        let bond = file(&files, "bond0");
+        assert!(bond.contains("controller=br0\nport-type=bridge\n"));
+
+        let bridge = file(&files, "br0");
+        assert!(bridge.contains("type=bridge"));
+        assert!(bridge.contains("[bridge]\nstp=false\nforward-delay=0\n"));
+        assert!(bridge.contains("address1=10.0.0.2/24"));
+
+        let vlan = file(&files, "vlan10");
+        assert!(vlan.contains("type=vlan\ninterface-name=vlan10\n"));
+        assert!(vlan.contains("[vlan]\nid=10\nparent=br0\n"));
+        assert!(vlan.contains("[ethernet]\nmtu=1400\n"));
+    }
+
+    #[test]
+    fn test_match_by_mac_omits_interface_name() {
+        let files = render_yaml(
+            r#"
+version: 2
+ethernets:
+  lan:
+    match:
+      macaddress: "52:54:00:12:34:56"
+    dhcp4: true
+  wan:
+    match:
+      macaddress: "52:54:00:ab:cd:ef"
+    set-name: wan0
+    dhcp4: true
+  mgmt:
+    match:
+      name: enp1s0
+    dhcp4: true
+"#,
+        );
+
+        let lan = file(&files, "lan");
+        assert!(!lan.contains("interface-name="));
+        assert!(lan.contains("mac-address=52:54:00:12:34:56"));
+
+        let wan = file(&files, "wan");
+        assert!(wan.contains("interface-name=wan0"));
+
+        let mgmt = file(&files, "mgmt");
+        assert!(mgmt.contains("interface-name=enp1s0"));
+    }
+
+    #[test]
+    fn test_route_metrics_and_options() {
+        let files = render_yaml(
+            r#"
+version: 2
+ethernets:
+  eth0:
+    addresses: [10.0.0.2/24, "2001:db8::2/64"]
+    routes:
+      - to: 0.0.0.0/0
+        via: 10.0.0.1
+        metric: 100
+      - to: "2001:db8:1::/48"
+        via: "2001:db8::1"
+      - to: 192.168.0.0/16
+        metric: 50
+        table: 200
+        on-link: true
+"#,
+        );
+        let eth0 = file(&files, "eth0");
+        assert!(eth0.contains("route1=0.0.0.0/0,10.0.0.1,100\n"));
+        assert!(eth0.contains("route2=192.168.0.0/16,0.0.0.0,50\n"));
+        assert!(eth0.contains("route2_options=table=200,onlink=true\n"));
+        // IPv6 routes are numbered separately in [ipv6]
+        let ipv6 = &eth0[eth0.find("[ipv6]").unwrap()..];
+        assert!(ipv6.contains("route1=2001:db8:1::/48,2001:db8::1\n"));
+    }
+
+    #[test]
+    fn test_render_wifi_profiles() {
+        let files = render_yaml(
+            r#"
+version: 2
+wifis:
+  wlan0:
+    dhcp4: true
+    access-points:
+      "home net":
+        password: "s3cret-pass"
+        band: 2.4GHz
+      guest:
+        hidden: true
+"#,
+        );
+        assert_eq!(files.len(), 2);
+
+        let guest = file(&files, "wlan0-guest");
+        assert!(guest.contains("type=wifi\ninterface-name=wlan0\n"));
+        assert!(guest.contains("[wifi]\nssid=guest\nmode=infrastructure\nhidden=true\n"));
+        assert!(!guest.contains("[wifi-security]"));
+
+        let home = file(&files, "wlan0-home_net");
+        assert!(home.contains("id=wlan0-home_net\n"));
+        assert!(home.contains("ssid=home net\n"));
+        assert!(home.contains("band=bg\n"));
+        assert!(home.contains("[wifi-security]\nkey-mgmt=wpa-psk\npsk=s3cret-pass\n"));
+        assert!(home.contains("[ipv4]\nmethod=auto"));
+    }
+
+    #[test]
+    fn test_profile_uuid_is_stable() {
+        assert_eq!(profile_uuid("eth0"), profile_uuid("eth0"));
+        assert_ne!(profile_uuid("eth0"), profile_uuid("eth1"));
+
+        let first = render_yaml("version: 2\nethernets:\n  eth0:\n    dhcp4: true\n");
+        let second = render_yaml("version: 2\nethernets:\n  eth0:\n    dhcp4: true\n");
+        assert_eq!(first[0].content, second[0].content);
+    }
 }
@@ -1,14 +1,29 @@
 //! systemd-networkd renderer
 //!
 //! Generates .network, .netdev, and .link files for systemd-networkd.
+//! networkd has no wifi support of its own, so wifi interfaces also get
+//! a per-interface wpa_supplicant config for `wpa_supplicant@<iface>`.
+//!
+//! Bond and bridge ports are enslaved from their own .network file: they
+//! take the controller's MTU unless they set one, and get no addresses,
+//! link-local ones included. VLANs are listed in their parent's file.
 
 use super::{RenderedFile, Renderer, RendererType};
 use crate::CloudInitError;
 use crate::network::{
-    BondConfig, BridgeConfig, EthernetConfig, InterfaceCommon, NetworkConfig, VlanConfig,
+    AccessPointConfig, BondConfig, BridgeConfig, Dhcp6Mode, EthernetConfig, InterfaceCommon,
+    NetworkConfig, RouteConfig, VlanConfig, WifiConfig,
 };
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Write;
 use std::path::Path;
+use tracing::warn;
+
+/// Directory for per-interface wpa_supplicant configs
+pub const WPA_SUPPLICANT_DIR: &str = "/etc/wpa_supplicant";
 
 /// systemd-networkd renderer
 pub struct NetworkdRenderer;
@@ -23,12 +38,14 @@ impl NetworkdRenderer {
         name: &str,
         config: &EthernetConfig,
         priority: u32,
+        topology: &Topology,
     ) -> Vec<RenderedFile> {
         let mut files = Vec::new();
+        let attachment = topology.attachment(name);
 
         // Create .network file
         let network_content =
-            self.render_network_section(name, &config.common, &config.match_config);
+            self.render_network_section(name, &config.common, &config.match_config, &attachment);
         files.push(RenderedFile {
             path: format!("{:02}-{}.network", priority, name),
             content: network_content,
@@ -39,7 +56,8 @@ impl NetworkdRenderer {
         if let Some(match_config) = &config.match_config
             && (match_config.macaddress.is_some() || match_config.driver.is_some())
         {
-            let link_content = self.render_link_section(name, match_config, &config.common);
+            let common = attachment.apply(name, &config.common);
+            let link_content = self.render_link_section(name, match_config, &common);
             files.push(RenderedFile {
                 path: format!("{:02}-{}.link", priority, name),
                 content: link_content,
@@ -50,7 +68,39 @@ impl NetworkdRenderer {
         files
     }
 
-    fn render_bond(&self, name: &str, config: &BondConfig, priority: u32) -> Vec<RenderedFile> {
+    fn render_wifi(
+        &self,
+        name: &str,
+        config: &WifiConfig,
+        priority: u32,
+        topology: &Topology,
+    ) -> Vec<RenderedFile> {
+        vec![
+            RenderedFile {
+                path: format!("{:02}-{}.network", priority, name),
+                content: self.render_network_section(
+                    name,
+                    &config.common,
+                    &config.match_config,
+                    &topology.attachment(name),
+                ),
+                mode: 0o644,
+            },
+            RenderedFile {
+                path: format!("{}/wpa_supplicant-{}.conf", WPA_SUPPLICANT_DIR, name),
+                content: wpa_supplicant_conf(config),
+                mode: 0o600, // Holds the PSKs
+            },
+        ]
+    }
+
+    fn render_bond(
+        &self,
+        name: &str,
+        config: &BondConfig,
+        priority: u32,
+        topology: &Topology,
+    ) -> Vec<RenderedFile> {
         let mut files = Vec::new();
 
         // Create .netdev for the bond
@@ -86,33 +136,25 @@ impl NetworkdRenderer {
         });
 
         // Create .network for the bond interface
-        let network_content = self.render_network_section(name, &config.common, &None);
+        let network_content =
+            self.render_network_section(name, &config.common, &None, &topology.attachment(name));
         files.push(RenderedFile {
             path: format!("{:02}-{}.network", priority, name),
             content: network_content,
             mode: 0o644,
         });
 
-        // Create .network files for member interfaces
-        for (i, member) in config.interfaces.iter().enumerate() {
-            let mut member_network = String::new();
-            writeln!(member_network, "[Match]").unwrap();
-            writeln!(member_network, "Name={}", member).unwrap();
-            writeln!(member_network).unwrap();
-            writeln!(member_network, "[Network]").unwrap();
-            writeln!(member_network, "Bond={}", name).unwrap();
-
-            files.push(RenderedFile {
-                path: format!("{:02}-{}-{}.network", priority + 1, name, i),
-                content: member_network,
-                mode: 0o644,
-            });
-        }
-
+        files.extend(self.render_ports(name, &config.interfaces, priority, topology));
         files
     }
 
-    fn render_bridge(&self, name: &str, config: &BridgeConfig, priority: u32) -> Vec<RenderedFile> {
+    fn render_bridge(
+        &self,
+        name: &str,
+        config: &BridgeConfig,
+        priority: u32,
+        topology: &Topology,
+    ) -> Vec<RenderedFile> {
         let mut files = Vec::new();
 
         // Create .netdev for the bridge
@@ -148,33 +190,52 @@ impl NetworkdRenderer {
         });
 
         // Create .network for the bridge interface
-        let network_content = self.render_network_section(name, &config.common, &None);
+        let network_content =
+            self.render_network_section(name, &config.common, &None, &topology.attachment(name));
         files.push(RenderedFile {
             path: format!("{:02}-{}.network", priority, name),
             content: network_content,
             mode: 0o644,
         });
 
-        // Create .network files for member interfaces
-        for (i, member) in config.interfaces.iter().enumerate() {
-            let mut member_network = String::new();
-            writeln!(member_network, "[Match]").unwrap();
-            writeln!(member_network, "Name={}", member).unwrap();
-            writeln!(member_network).unwrap();
-            writeln!(member_network, "[Network]").unwrap();
-            writeln!(member_network, "Bridge={}", name).unwrap();
+        files.extend(self.render_ports(name, &config.interfaces, priority, topology));
+        files
+    }
 
-            files.push(RenderedFile {
-                path: format!("{:02}-{}-{}.network", priority + 1, name, i),
-                content: member_network,
+    /// .network files for the ports of a bond or bridge that have no
+    /// config of their own; configured ports carry their port settings
+    /// in their own .network file, which networkd would match first
+    fn render_ports(
+        &self,
+        controller: &str,
+        ports: &[String],
+        priority: u32,
+        topology: &Topology,
+    ) -> Vec<RenderedFile> {
+        ports
+            .iter()
+            .enumerate()
+            .filter(|(_, port)| !topology.is_configured(port))
+            .map(|(i, port)| RenderedFile {
+                path: format!("{:02}-{}-{}.network", priority + 1, controller, i),
+                content: self.render_network_section(
+                    port,
+                    &InterfaceCommon::default(),
+                    &None,
+                    &topology.attachment(port),
+                ),
                 mode: 0o644,
-            });
-        }
-
-        files
+            })
+            .collect()
     }
 
-    fn render_vlan(&self, name: &str, config: &VlanConfig, priority: u32) -> Vec<RenderedFile> {
+    fn render_vlan(
+        &self,
+        name: &str,
+        config: &VlanConfig,
+        priority: u32,
+        topology: &Topology,
+    ) -> Vec<RenderedFile> {
         let mut files = Vec::new();
 
         // Create .netdev for the VLAN
@@ -193,26 +254,33 @@ impl NetworkdRenderer {
         });
 
         // Create .network for the VLAN interface
-        let network_content = self.render_network_section(name, &config.common, &None);
+        let network_content =
+            self.render_network_section(name, &config.common, &None, &topology.attachment(name));
         files.push(RenderedFile {
             path: format!("{:02}-{}.network", priority, name),
             content: network_content,
             mode: 0o644,
         });
 
-        // Add VLAN reference to parent interface
-        let mut parent_network = String::new();
-        writeln!(parent_network, "[Match]").unwrap();
-        writeln!(parent_network, "Name={}", config.link).unwrap();
-        writeln!(parent_network).unwrap();
-        writeln!(parent_network, "[Network]").unwrap();
-        writeln!(parent_network, "VLAN={}", name).unwrap();
-
-        files.push(RenderedFile {
-            path: format!("{:02}-{}-vlan.network", priority + 1, config.link),
-            content: parent_network,
-            mode: 0o644,
-        });
+        // A parent without config of its own gets one file listing all
+        // of its VLANs, written with the first of them; configured
+        // parents and bond or bridge ports list them in their own file
+        let parent = topology.attachment(&config.link);
+        if !topology.is_configured(&config.link)
+            && parent.port.is_none()
+            && parent.vlans.first() == Some(&name)
+        {
+            files.push(RenderedFile {
+                path: format!("{:02}-{}-vlan.network", priority + 1, config.link),
+                content: self.render_network_section(
+                    &config.link,
+                    &InterfaceCommon::default(),
+                    &None,
+                    &parent,
+                ),
+                mode: 0o644,
+            });
+        }
 
         files
     }
@@ -222,8 +290,10 @@ impl NetworkdRenderer {
         name: &str,
         common: &InterfaceCommon,
         match_config: &Option<crate::network::MatchConfig>,
+        attachment: &Attachment,
     ) -> String {
         let mut content = String::new();
+        let common = &*attachment.apply(name, common);
 
         // [Match] section
         writeln!(content, "[Match]").unwrap();
@@ -244,12 +314,23 @@ impl NetworkdRenderer {
 
         // [Network] section
         writeln!(content, "[Network]").unwrap();
+        if let Some(port) = &attachment.port {
+            writeln!(content, "{}={}", port.kind, port.controller).unwrap();
+            // The controller holds the addresses; no fe80:: on ports
+            writeln!(content, "LinkLocalAddressing=no").unwrap();
+        }
+        for vlan in &attachment.vlans {
+            writeln!(content, "VLAN={}", vlan).unwrap();
+        }
 
-        if common.dhcp4 == Some(true) && common.dhcp6 == Some(true) {
+        // Stateless DHCPv6 is started by router advertisements carrying
+        // the O flag, not by DHCP=
+        let dhcp6 = common.dhcp6 == Some(true) && !common.dhcp6_stateless();
+        if common.dhcp4 == Some(true) && dhcp6 {
             writeln!(content, "DHCP=yes").unwrap();
         } else if common.dhcp4 == Some(true) {
             writeln!(content, "DHCP=ipv4").unwrap();
-        } else if common.dhcp6 == Some(true) {
+        } else if dhcp6 {
             writeln!(content, "DHCP=ipv6").unwrap();
         }
 
@@ -258,14 +339,6 @@ impl NetworkdRenderer {
             writeln!(content, "Address={}", addr).unwrap();
         }
 
-        // Gateways
-        if let Some(gw) = &common.gateway4 {
-            writeln!(content, "Gateway={}", gw).unwrap();
-        }
-        if let Some(gw) = &common.gateway6 {
-            writeln!(content, "Gateway={}", gw).unwrap();
-        }
-
         // DNS
         for dns in &common.nameservers.addresses {
             writeln!(content, "DNS={}", dns).unwrap();
@@ -275,7 +348,10 @@ impl NetworkdRenderer {
         }
 
         // IPv6 RA
-        if let Some(accept_ra) = common.accept_ra {
+        let accept_ra = common
+            .accept_ra
+            .or(common.dhcp6_stateless().then_some(true));
+        if let Some(accept_ra) = accept_ra {
             writeln!(
                 content,
                 "IPv6AcceptRA={}",
@@ -284,10 +360,37 @@ impl NetworkdRenderer {
             .unwrap();
         }
 
+        // DHCPv6 client mode. Without RAs the client only starts when told
+        // to; an explicit stateful mode solicits addresses even when the
+        // RA lacks the M flag.
+        if common.dhcp6 == Some(true) {
+            if accept_ra == Some(false) {
+                writeln!(content).unwrap();
+                writeln!(content, "[DHCPv6]").unwrap();
+                writeln!(content, "WithoutRA=solicit").unwrap();
+            } else if let Some(mode) = common.dhcp6_mode {
+                writeln!(content).unwrap();
+                writeln!(content, "[IPv6AcceptRA]").unwrap();
+                let client = match mode {
+                    Dhcp6Mode::Stateful => "always",
+                    Dhcp6Mode::Stateless => "yes",
+                };
+                writeln!(content, "DHCPv6Client={}", client).unwrap();
+            }
+        }
+
         // [Link] section for MTU
-        if common.mtu.is_some() || common.macaddress.is_some() || common.wakeonlan.is_some() {
+        if common.mtu.is_some()
+            || common.macaddress.is_some()
+            || common.wakeonlan.is_some()
+            || common.optional == Some(true)
+        {
             writeln!(content).unwrap();
             writeln!(content, "[Link]").unwrap();
+            // Optional links do not hold up systemd-networkd-wait-online
+            if common.optional == Some(true) {
+                writeln!(content, "RequiredForOnline=no").unwrap();
+            }
             if let Some(mtu) = common.mtu {
                 writeln!(content, "MTUBytes={}", mtu).unwrap();
             }
@@ -299,20 +402,17 @@ impl NetworkdRenderer {
             }
         }
 
-        // [Route] sections
-        for route in &common.routes {
+        // [Route] sections. gateway4/gateway6 become default routes rather
+        // than Gateway= in [Network], which newer systemd rejects when it
+        // cannot tell which of several addresses the gateway belongs to.
+        for gw in [&common.gateway4, &common.gateway6].into_iter().flatten() {
             writeln!(content).unwrap();
             writeln!(content, "[Route]").unwrap();
-            writeln!(content, "Destination={}", route.to).unwrap();
-            if let Some(via) = &route.via {
-                writeln!(content, "Gateway={}", via).unwrap();
-            }
-            if let Some(metric) = route.metric {
-                writeln!(content, "Metric={}", metric).unwrap();
-            }
-            if let Some(table) = route.table {
-                writeln!(content, "Table={}", table).unwrap();
-            }
+            writeln!(content, "Gateway={}", gw).unwrap();
+        }
+        for route in &common.routes {
+            writeln!(content).unwrap();
+            write_route_section(&mut content, route);
         }
 
         // [RoutingPolicyRule] sections
@@ -331,6 +431,9 @@ impl NetworkdRenderer {
             if let Some(prio) = rule.priority {
                 writeln!(content, "Priority={}", prio).unwrap();
             }
+            if let Some(mark) = rule.mark {
+                writeln!(content, "FirewallMark={}", mark).unwrap();
+            }
         }
 
         content
@@ -381,6 +484,257 @@ impl NetworkdRenderer {
     }
 }
 
+/// Bond or bridge membership of a port interface
+#[derive(Debug, Clone)]
+struct Port<'a> {
+    controller: &'a str,
+    /// `Bond` or `Bridge`, the [Network] key naming the controller
+    kind: &'static str,
+    /// The controller's MTU, which its ports must match
+    mtu: Option<u32>,
+}
+
+/// What the devices stacked on an interface require of it
+#[derive(Debug, Clone, Default)]
+struct Attachment<'a> {
+    port: Option<Port<'a>>,
+    /// VLANs whose link is the interface
+    vlans: Vec<&'a str>,
+}
+
+impl Attachment<'_> {
+    /// `common` as it applies to the interface
+    ///
+    /// A port keeps only its link settings, taking the controller's MTU
+    /// unless it sets its own; addressing and routes belong to the
+    /// controller.
+    fn apply<'c>(&self, name: &str, common: &'c InterfaceCommon) -> Cow<'c, InterfaceCommon> {
+        let Some(port) = &self.port else {
+            return Cow::Borrowed(common);
+        };
+        if common.dhcp4 == Some(true)
+            || common.dhcp6 == Some(true)
+            || !common.addresses.is_empty()
+            || !common.routes.is_empty()
+        {
+            warn!(
+                "{} is a port of {}; its addresses and routes are not rendered",
+                name, port.controller
+            );
+        }
+        Cow::Owned(InterfaceCommon {
+            mtu: common.mtu.or(port.mtu),
+            macaddress: common.macaddress.clone(),
+            set_name: common.set_name.clone(),
+            wakeonlan: common.wakeonlan,
+            optional: common.optional,
+            ..Default::default()
+        })
+    }
+}
+
+/// How the interfaces of a config are stacked
+struct Topology<'a> {
+    attachments: BTreeMap<&'a str, Attachment<'a>>,
+    configured: BTreeSet<&'a str>,
+}
+
+impl<'a> Topology<'a> {
+    fn new(config: &'a NetworkConfig) -> Self {
+        let mut attachments: BTreeMap<&str, Attachment> = BTreeMap::new();
+        // Bridges first: a bond in a bridge passes the bridge MTU it
+        // inherits on to its own ports
+        for (name, bridge) in sorted(&config.bridges) {
+            for port in &bridge.interfaces {
+                attachments.entry(port).or_default().port = Some(Port {
+                    controller: name,
+                    kind: "Bridge",
+                    mtu: bridge.common.mtu,
+                });
+            }
+        }
+        for (name, bond) in sorted(&config.bonds) {
+            let inherited = attachments
+                .get(name.as_str())
+                .and_then(|a| a.port.as_ref())
+                .and_then(|p| p.mtu);
+            for port in &bond.interfaces {
+                attachments.entry(port).or_default().port = Some(Port {
+                    controller: name,
+                    kind: "Bond",
+                    mtu: bond.common.mtu.or(inherited),
+                });
+            }
+        }
+        for (name, vlan) in sorted(&config.vlans) {
+            attachments.entry(&vlan.link).or_default().vlans.push(name);
+        }
+
+        let configured = config
+            .ethernets
+            .keys()
+            .chain(config.bonds.keys())
+            .chain(config.bridges.keys())
+            .chain(config.vlans.keys())
+            .chain(config.wifis.keys())
+            .map(String::as_str)
+            .collect();
+        Self {
+            attachments,
+            configured,
+        }
+    }
+
+    fn attachment(&self, name: &str) -> Attachment<'a> {
+        self.attachments.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Whether the interface has config, and so a .network file, of its own
+    fn is_configured(&self, name: &str) -> bool {
+        self.configured.contains(name)
+    }
+}
+
+/// Iterate a config map in name order, so file names and priorities
+/// are the same on every run
+fn sorted<V>(
+    map: &std::collections::HashMap<String, V>,
+) -> std::collections::BTreeMap<&String, &V> {
+    map.iter().collect()
+}
+
+/// Render a wpa_supplicant config with one network block per access point
+fn wpa_supplicant_conf(config: &WifiConfig) -> String {
+    let mut content = String::new();
+    writeln!(content, "ctrl_interface=/run/wpa_supplicant").unwrap();
+    writeln!(content, "update_config=0").unwrap();
+
+    for (ssid, ap) in sorted(&config.access_points) {
+        writeln!(content).unwrap();
+        writeln!(content, "network={{").unwrap();
+        writeln!(content, "    ssid={}", wpa_string(ssid)).unwrap();
+        match &ap.password {
+            Some(password) => writeln!(content, "    psk={}", wpa_psk(ssid, password)).unwrap(),
+            None => writeln!(content, "    key_mgmt=NONE").unwrap(),
+        }
+        if ap.hidden == Some(true) {
+            writeln!(content, "    scan_ssid=1").unwrap();
+        }
+        if let Some(bssid) = &ap.bssid {
+            writeln!(content, "    bssid={}", bssid).unwrap();
+        }
+        match ap.mode.as_deref() {
+            Some("ap") => writeln!(content, "    mode=2").unwrap(),
+            Some("adhoc") => writeln!(content, "    mode=1").unwrap(),
+            _ => {}
+        }
+        if let Some(freq) = channel_frequency(ap) {
+            writeln!(content, "    frequency={}", freq).unwrap();
+        }
+        writeln!(content, "}}").unwrap();
+    }
+
+    content
+}
+
+/// Quote a wpa_supplicant string, falling back to hex when quoting is unsafe
+fn wpa_string(value: &str) -> String {
+    if is_quotable(value) {
+        format!("\"{}\"", value)
+    } else {
+        hex(value.as_bytes())
+    }
+}
+
+/// The `psk=` value for a passphrase
+///
+/// Unlike `ssid=`, an unquoted `psk=` is the raw 256-bit key, not the
+/// passphrase in hex. A passphrase that cannot be quoted is therefore
+/// turned into that key the way `wpa_passphrase` does, and 64 hex digits
+/// are taken to be a raw key already, as netplan does.
+fn wpa_psk(ssid: &str, password: &str) -> String {
+    if password.len() == 64 && password.bytes().all(|b| b.is_ascii_hexdigit()) {
+        password.to_ascii_lowercase()
+    } else if is_quotable(password) {
+        format!("\"{}\"", password)
+    } else {
+        hex(&passphrase_psk(ssid, password))
+    }
+}
+
+/// Whether `value` can be written between double quotes as it is
+fn is_quotable(value: &str) -> bool {
+    value
+        .chars()
+        .all(|c| c == ' ' || (c.is_ascii_graphic() && c != '"' && c != '\\'))
+}
+
+/// WPA pre-shared key for a passphrase: PBKDF2-HMAC-SHA1 with the SSID as
+/// salt and 4096 rounds (IEEE 802.11i)
+fn passphrase_psk(ssid: &str, passphrase: &str) -> [u8; 32] {
+    let mac = Hmac::<Sha1>::new_from_slice(passphrase.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    let mut psk = [0u8; 32];
+    for (index, block) in (1u32..).zip(psk.chunks_mut(20)) {
+        let mut u = mac
+            .clone()
+            .chain_update(ssid.as_bytes())
+            .chain_update(index.to_be_bytes())
+            .finalize()
+            .into_bytes();
+        let mut sum = u;
+        for _ in 1..4096 {
+            u = mac.clone().chain_update(u).finalize().into_bytes();
+            sum.iter_mut().zip(&u).for_each(|(s, u)| *s ^= u);
+        }
+        block.copy_from_slice(&sum[..block.len()]);
+    }
+    psk
+}
+
+/// Lowercase hex digits of `bytes`
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Centre frequency in MHz for an access point's band and channel
+fn channel_frequency(ap: &AccessPointConfig) -> Option<u32> {
+    let channel = ap.channel?;
+    match ap.band.as_deref()? {
+        "2.4GHz" if channel == 14 => Some(2484),
+        "2.4GHz" => Some(2407 + 5 * channel),
+        "5GHz" => Some(5000 + 5 * channel),
+        _ => None,
+    }
+}
+
+/// Write one [Route] section
+fn write_route_section(content: &mut String, route: &RouteConfig) {
+    writeln!(content, "[Route]").unwrap();
+    // "default" (and the all-zeros networks) mean no Destination=
+    if !matches!(route.to.as_str(), "default" | "0.0.0.0/0" | "::/0") {
+        writeln!(content, "Destination={}", route.to).unwrap();
+    }
+    if let Some(via) = &route.via {
+        writeln!(content, "Gateway={}", via).unwrap();
+    }
+    if route.on_link == Some(true) {
+        writeln!(content, "GatewayOnLink=yes").unwrap();
+    }
+    if let Some(route_type) = &route.route_type {
+        writeln!(content, "Type={}", route_type).unwrap();
+    }
+    if let Some(scope) = &route.scope {
+        writeln!(content, "Scope={}", scope).unwrap();
+    }
+    if let Some(metric) = route.metric {
+        writeln!(content, "Metric={}", metric).unwrap();
+    }
+    if let Some(table) = route.table {
+        writeln!(content, "Table={}", table).unwrap();
+    }
+}
+
 impl Default for NetworkdRenderer {
     fn default() -> Self {
         Self::new()
@@ -395,28 +749,35 @@ impl Renderer for NetworkdRenderer {
     ) -> Result<Vec<RenderedFile>, CloudInitError> {
         let mut files = Vec::new();
         let mut priority = 10u32;
+        let topology = Topology::new(config);
 
         // Render ethernets
-        for (name, eth_config) in &config.ethernets {
-            files.extend(self.render_ethernet(name, eth_config, priority));
+        for (name, eth_config) in sorted(&config.ethernets) {
+            files.extend(self.render_ethernet(name, eth_config, priority, &topology));
             priority += 10;
         }
 
         // Render bonds
-        for (name, bond_config) in &config.bonds {
-            files.extend(self.render_bond(name, bond_config, priority));
+        for (name, bond_config) in sorted(&config.bonds) {
+            files.extend(self.render_bond(name, bond_config, priority, &topology));
             priority += 10;
         }
 
         // Render bridges
-        for (name, bridge_config) in &config.bridges {
-            files.extend(self.render_bridge(name, bridge_config, priority));
+        for (name, bridge_config) in sorted(&config.bridges) {
+            files.extend(self.render_bridge(name, bridge_config, priority, &topology));
             priority += 10;
         }
 
         // Render VLANs
-        for (name, vlan_config) in &config.vlans {
-            files.extend(self.render_vlan(name, vlan_config, priority));
+        for (name, vlan_config) in sorted(&config.vlans) {
+            files.extend(self.render_vlan(name, vlan_config, priority, &topology));
+            priority += 10;
+        }
+
+        // Render wifis
+        for (name, wifi_config) in sorted(&config.wifis) {
+            files.extend(self.render_wifi(name, wifi_config, priority, &topology));
             priority += 10;
         }
 
@@ -499,4 +860,242 @@ mod tests {
         assert!(files[0].content.contains("Gateway=192.168.1.1"));
         assert!(files[0].content.contains("DNS=8.8.8.8"));
     }
+
+    fn render_eth0(yaml: &str) -> String {
+        let config: NetworkConfig = serde_yaml::from_str(yaml).unwrap();
+        let files = NetworkdRenderer::new()
+            .render(&config, Path::new("/tmp"))
+            .unwrap();
+        files[0].content.clone()
+    }
+
+    #[test]
+    fn test_gateways_become_default_routes() {
+        let content = render_eth0(
+            r#"
+version: 2
+ethernets:
+  eth0:
+    addresses: [10.0.0.2/24, 10.0.1.2/24, "2001:db8::2/64"]
+    gateway4: 10.0.0.1
+    gateway6: "2001:db8::1"
+"#,
+        );
+        let network = &content[content.find("[Network]").unwrap()..];
+        let network = &network[..network.find("\n\n").unwrap()];
+        assert!(!network.contains("Gateway="));
+        assert!(content.contains("[Route]\nGateway=10.0.0.1\n"));
+        assert!(content.contains("[Route]\nGateway=2001:db8::1\n"));
+    }
+
+    #[test]
+    fn test_route_options() {
+        let content = render_eth0(
+            r#"
+version: 2
+ethernets:
+  eth0:
+    addresses: [10.0.0.2/32]
+    routes:
+      - to: default
+        via: 10.255.255.1
+        on-link: true
+        metric: 100
+      - to: 192.0.2.0/24
+        type: blackhole
+      - to: 198.51.100.0/24
+        scope: link
+        table: 100
+"#,
+        );
+        assert!(content.contains("[Route]\nGateway=10.255.255.1\nGatewayOnLink=yes\nMetric=100\n"));
+        assert!(content.contains("[Route]\nDestination=192.0.2.0/24\nType=blackhole\n"));
+        assert!(content.contains("[Route]\nDestination=198.51.100.0/24\nScope=link\nTable=100\n"));
+    }
+
+    #[test]
+    fn test_optional_not_required_for_online() {
+        let content =
+            render_eth0("version: 2\nethernets:\n  eth0:\n    dhcp4: true\n    optional: true\n");
+        assert!(content.contains("[Link]\nRequiredForOnline=no\n"));
+    }
+
+    fn render_all(yaml: &str) -> BTreeMap<String, String> {
+        let config: NetworkConfig = serde_yaml::from_str(yaml).unwrap();
+        NetworkdRenderer::new()
+            .render(&config, Path::new("/tmp"))
+            .unwrap()
+            .into_iter()
+            .map(|file| (file.path, file.content))
+            .collect()
+    }
+
+    #[test]
+    fn test_ports_inherit_controller_mtu() {
+        let files = render_all(
+            r#"
+version: 2
+ethernets:
+  eth0:
+    match:
+      macaddress: "52:54:00:00:00:01"
+  eth1:
+    mtu: 1500
+bonds:
+  bond0:
+    interfaces: [eth0, eth1, eth2]
+bridges:
+  br0:
+    interfaces: [bond0]
+    mtu: 9000
+    dhcp4: true
+"#,
+        );
+        // Configured ports get the port settings in their own file
+        assert!(
+            files["10-eth0.network"].contains(
+                "[Network]\nBond=bond0\nLinkLocalAddressing=no\n\n[Link]\nMTUBytes=9000\n"
+            )
+        );
+        assert!(files["10-eth0.link"].contains("MTUBytes=9000\n"));
+        assert!(files["20-eth1.network"].contains("MTUBytes=1500\n"));
+        assert!(!files.contains_key("31-bond0-0.network"));
+        // Unconfigured ports get a file of their own
+        assert_eq!(
+            files["31-bond0-2.network"],
+            "[Match]\nName=eth2\n\n[Network]\nBond=bond0\nLinkLocalAddressing=no\n\n\
+             [Link]\nMTUBytes=9000\n"
+        );
+        // The bond is a bridge port itself, without addresses
+        let bond = &files["30-bond0.network"];
+        assert!(bond.contains("Bridge=br0\nLinkLocalAddressing=no\n"));
+        assert!(!bond.contains("DHCP="));
+        assert!(files["40-br0.network"].contains("DHCP=ipv4\n"));
+    }
+
+    #[test]
+    fn test_vlans_listed_in_parent_network() {
+        let files = render_all(
+            r#"
+version: 2
+ethernets:
+  eth0:
+    dhcp4: true
+vlans:
+  vlan10: {id: 10, link: eth0}
+  vlan20: {id: 20, link: eth0}
+  vlan30: {id: 30, link: eth1}
+  vlan40: {id: 40, link: eth1}
+"#,
+        );
+        assert!(
+            files["10-eth0.network"].contains("[Network]\nVLAN=vlan10\nVLAN=vlan20\nDHCP=ipv4\n")
+        );
+        assert!(!files.contains_key("21-eth0-vlan.network"));
+        assert_eq!(
+            files["41-eth1-vlan.network"],
+            "[Match]\nName=eth1\n\n[Network]\nVLAN=vlan30\nVLAN=vlan40\n"
+        );
+        assert!(!files.contains_key("51-eth1-vlan.network"));
+    }
+
+    #[test]
+    fn test_dhcpv6_modes() {
+        let stateless = render_eth0(
+            "version: 2\nethernets:\n  eth0:\n    dhcp6: true\n    dhcp6-mode: stateless\n",
+        );
+        assert!(!stateless.contains("DHCP="));
+        assert!(stateless.contains("IPv6AcceptRA=yes\n"));
+        assert!(stateless.contains("[IPv6AcceptRA]\nDHCPv6Client=yes\n"));
+
+        let stateful = render_eth0(
+            "version: 2\nethernets:\n  eth0:\n    dhcp6: true\n    dhcp6-mode: stateful\n",
+        );
+        assert!(stateful.contains("DHCP=ipv6\n"));
+        assert!(stateful.contains("[IPv6AcceptRA]\nDHCPv6Client=always\n"));
+
+        let without_ra =
+            render_eth0("version: 2\nethernets:\n  eth0:\n    dhcp6: true\n    accept-ra: false\n");
+        assert!(without_ra.contains("DHCP=ipv6\n"));
+        assert!(without_ra.contains("IPv6AcceptRA=no\n"));
+        assert!(without_ra.contains("[DHCPv6]\nWithoutRA=solicit\n"));
+    }
+
+    #[test]
+    fn test_routing_policy_rules() {
+        let content = render_eth0(
+            r#"
+version: 2
+ethernets:
+  eth0:
+    dhcp4: true
+    routing-policy:
+      - from: 10.0.0.0/24
+        table: 100
+        priority: 10
+        mark: 7
+"#,
+        );
+        assert!(content.contains(
+            "[RoutingPolicyRule]\nFrom=10.0.0.0/24\nTable=100\nPriority=10\nFirewallMark=7\n"
+        ));
+    }
+
+    #[test]
+    fn test_render_wifi() {
+        let config = NetworkConfig::from_yaml(
+            r#"
+version: 2
+wifis:
+  wlan0:
+    dhcp4: true
+    access-points:
+      "home net":
+        password: "s3cret-pass"
+        band: 5GHz
+        channel: 36
+        mode: ap
+      "open":
+        hidden: true
+      "quo\"te": {}
+"#,
+        )
+        .unwrap();
+        let files = NetworkdRenderer::new()
+            .render(&config, Path::new("/tmp"))
+            .unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].path, "10-wlan0.network");
+        assert!(files[0].content.contains("Name=wlan0"));
+        assert!(files[0].content.contains("DHCP=ipv4"));
+
+        let wpa = &files[1];
+        assert_eq!(wpa.path, "/etc/wpa_supplicant/wpa_supplicant-wlan0.conf");
+        assert_eq!(wpa.mode, 0o600);
+        assert!(wpa.content.contains(
+            "network={\n    ssid=\"home net\"\n    psk=\"s3cret-pass\"\n    mode=2\n    frequency=5180\n}"
+        ));
+        assert!(
+            wpa.content
+                .contains("ssid=\"open\"\n    key_mgmt=NONE\n    scan_ssid=1\n")
+        );
+        assert!(wpa.content.contains("ssid=71756f227465\n"));
+    }
+
+    #[test]
+    fn test_wpa_psk() {
+        assert_eq!(wpa_psk("home", "s3cret-pass"), "\"s3cret-pass\"");
+        // IEEE 802.11i test vector
+        assert_eq!(
+            hex(&passphrase_psk("IEEE", "password")),
+            "f42c6fc52df0ebef9ebb4b90b38a5f902e83fe1b135a70e23aed762e9710a12e"
+        );
+        // Passphrases that cannot be quoted become the key, not hex text
+        assert_eq!(
+            wpa_psk("home", "pa\"ss wörd"),
+            hex(&passphrase_psk("home", "pa\"ss wörd"))
+        );
+        let raw = "F42C6FC52DF0EBEF9EBB4B90B38A5F902E83FE1B135A70E23AED762E9710A12E";
+        assert_eq!(wpa_psk("home", raw), raw.to_ascii_lowercase());
+    }
 }
@@ -0,0 +1,528 @@
+//! FreeBSD rc.conf renderer
+//!
+//! Generates rc.conf(5) variables in /etc/rc.conf.d, which the rc scripts
+//! read after /etc/rc.conf: interface settings in `network`, gateways and
+//! static routes in `routing`, and `rtsold` when router advertisements
+//! are accepted. Static nameservers go to /etc/resolv.conf.
+//!
+//! Bonds become lagg(4) and bridges if_bridge(4) devices; VLANs are
+//! created through `vlans_<parent>`. A lagg or bridge whose name is not
+//! one FreeBSD can create (`lagg0`, `bridge0`) is created under such a
+//! name and renamed. FreeBSD names NICs after their driver (`vtnet0`,
+//! `em0`), so ethernets are configured by name; a MAC match cannot be
+//! expressed in rc.conf.
+
+use super::{RenderedFile, Renderer, RendererType};
+use crate::CloudInitError;
+use crate::network::{BondConfig, BridgeConfig, InterfaceCommon, NetworkConfig, RouteConfig};
+use crate::root::RootContext;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use tracing::warn;
+
+/// Where the rendered files go
+pub const OUTPUT_DIR: &str = "/etc/rc.conf.d";
+
+/// Ordered rc.conf variables of one file
+#[derive(Debug, Default)]
+struct RcConf {
+    lines: Vec<(String, String)>,
+}
+
+impl RcConf {
+    fn set(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.lines.push((key.into(), value.into()));
+    }
+
+    fn render(&self) -> String {
+        let mut content = String::from("# This file is generated by cloud-init\n");
+        for (key, value) in &self.lines {
+            writeln!(
+                content,
+                "{}=\"{}\"",
+                key,
+                value.replace(['"', '\\', '$', '`'], "")
+            )
+            .unwrap();
+        }
+        content
+    }
+}
+
+/// An interface name as used in rc.conf variable names
+fn var(name: &str) -> String {
+    name.replace(['.', '-'], "_")
+}
+
+/// lagg(4) protocol for a bond mode
+fn lagg_proto(name: &str, mode: Option<&str>) -> &'static str {
+    match mode {
+        None | Some("active-backup") => "failover",
+        Some("802.3ad") => "lacp",
+        Some("balance-rr") => "roundrobin",
+        Some("balance-xor") => "loadbalance",
+        Some("broadcast") => "broadcast",
+        Some(other) => {
+            warn!(
+                "{}: bond mode {} has no lagg equivalent; using failover",
+                name, other
+            );
+            "failover"
+        }
+    }
+}
+
+/// FreeBSD rc.conf renderer
+pub struct RcConfRenderer;
+
+impl RcConfRenderer {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Set the `ifconfig_*` variables of one interface
+    ///
+    /// `device` words (lagg ports, bridge members, ...) come before the
+    /// address settings.
+    fn render_interface(
+        &self,
+        network: &mut RcConf,
+        name: &str,
+        common: &InterfaceCommon,
+        device: &[String],
+    ) {
+        let var = var(name);
+        let ipv4: Vec<&String> = common
+            .addresses
+            .iter()
+            .filter(|a| !a.contains(':'))
+            .collect();
+        let ipv6: Vec<&String> = common
+            .addresses
+            .iter()
+            .filter(|a| a.contains(':'))
+            .collect();
+
+        let mut words: Vec<String> = device.to_vec();
+        if common.dhcp4 == Some(true) {
+            words.push("DHCP".to_string());
+        } else if let Some(first) = ipv4.first() {
+            words.push(format!("inet {}", first));
+        } else {
+            words.push("up".to_string());
+        }
+        if let Some(mtu) = common.mtu {
+            words.push(format!("mtu {}", mtu));
+        }
+        if let Some(mac) = &common.macaddress {
+            words.push(format!("ether {}", mac));
+        }
+        network.set(format!("ifconfig_{}", var), words.join(" "));
+
+        let mut aliases = ipv4
+            .iter()
+            .skip(1)
+            .map(|address| format!("inet {}", address))
+            .collect::<Vec<_>>();
+        if common.dhcp6 == Some(true) || common.accept_ra == Some(true) {
+            if common.dhcp6 == Some(true) && !common.dhcp6_stateless() {
+                warn!(
+                    "FreeBSD has no DHCPv6 client in base; {} gets SLAAC addresses only",
+                    name
+                );
+            }
+            network.set(format!("ifconfig_{}_ipv6", var), "inet6 accept_rtadv");
+            aliases.extend(ipv6.iter().map(|address| format!("inet6 {}", address)));
+        } else if let Some((first, rest)) = ipv6.split_first() {
+            network.set(format!("ifconfig_{}_ipv6", var), format!("inet6 {}", first));
+            aliases.extend(rest.iter().map(|address| format!("inet6 {}", address)));
+        }
+        for (i, alias) in aliases.into_iter().enumerate() {
+            network.set(format!("ifconfig_{}_alias{}", var, i), alias);
+        }
+    }
+
+    /// lagg device words for a bond
+    fn bond_device(&self, name: &str, bond: &BondConfig) -> Vec<String> {
+        let mode = bond.parameters.as_ref().and_then(|p| p.mode.as_deref());
+        let mut words = vec![format!("laggproto {}", lagg_proto(name, mode))];
+        words.extend(
+            bond.interfaces
+                .iter()
+                .map(|port| format!("laggport {}", port)),
+        );
+        words
+    }
+
+    /// if_bridge device words for a bridge
+    fn bridge_device(&self, bridge: &BridgeConfig) -> Vec<String> {
+        let stp = bridge.parameters.as_ref().and_then(|p| p.stp) == Some(true);
+        let mut words: Vec<String> = bridge
+            .interfaces
+            .iter()
+            .map(|member| format!("addm {}", member))
+            .collect();
+        if stp {
+            words.extend(
+                bridge
+                    .interfaces
+                    .iter()
+                    .map(|member| format!("stp {}", member)),
+            );
+        }
+        words
+    }
+}
+
+/// Create cloned devices of `kind` for `names`, renaming the ones FreeBSD
+/// cannot create by that name
+fn clone_devices<'a>(
+    network: &mut RcConf,
+    cloned: &mut Vec<String>,
+    kind: &str,
+    names: impl Iterator<Item = &'a String>,
+) {
+    let (creatable, renamed): (Vec<&String>, Vec<&String>) = names.partition(|name| {
+        name.strip_prefix(kind)
+            .is_some_and(|unit| !unit.is_empty() && unit.chars().all(|c| c.is_ascii_digit()))
+    });
+    cloned.extend(creatable.into_iter().cloned());
+
+    let mut next = 0;
+    for name in renamed {
+        let device = loop {
+            let device = format!("{}{}", kind, next);
+            next += 1;
+            if !cloned.contains(&device) {
+                break device;
+            }
+        };
+        network.set(format!("ifconfig_{}_name", device), name.as_str());
+        cloned.push(device);
+    }
+}
+
+/// Gateways and static routes of every interface
+fn render_routing(interfaces: &[(&str, &InterfaceCommon)]) -> RcConf {
+    let mut routing = RcConf::default();
+    let mut routes: [Vec<String>; 2] = Default::default();
+    let mut gateways: [Option<&String>; 2] = [None, None];
+
+    for (name, common) in interfaces {
+        for (family, gateway) in [&common.gateway4, &common.gateway6].into_iter().enumerate() {
+            if gateways[family].is_none() {
+                gateways[family] = gateway.as_ref();
+            }
+        }
+        for RouteConfig {
+            to, via, metric, ..
+        } in &common.routes
+        {
+            let family =
+                usize::from(to.contains(':') || via.as_ref().is_some_and(|v| v.contains(':')));
+            if matches!(to.as_str(), "default" | "0.0.0.0/0" | "::/0") {
+                if let Some(via) = via
+                    && gateways[family].is_none()
+                {
+                    gateways[family] = Some(via);
+                }
+                continue;
+            }
+            if metric.is_some() {
+                warn!(
+                    "{}: FreeBSD routes have no metric; ignoring it for {}",
+                    name, to
+                );
+            }
+            routes[family].push(match via {
+                Some(via) => format!("-net {} {}", to, via),
+                None => format!("-net {} -interface {}", to, name),
+            });
+        }
+    }
+
+    for (family, (router, list, prefix)) in [
+        ("defaultrouter", "static_routes", "route"),
+        ("ipv6_defaultrouter", "ipv6_static_routes", "ipv6_route"),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        if let Some(gateway) = gateways[family] {
+            routing.set(router, gateway.as_str());
+        }
+        if routes[family].is_empty() {
+            continue;
+        }
+        let names: Vec<String> = (0..routes[family].len())
+            .map(|i| format!("cloudinit{}", i))
+            .collect();
+        routing.set(list, names.join(" "));
+        for (name, route) in names.iter().zip(&routes[family]) {
+            routing.set(format!("{}_{}", prefix, name), route.as_str());
+        }
+    }
+    routing
+}
+
+/// /etc/resolv.conf for the static nameservers of every interface
+fn render_resolv_conf(interfaces: &[(&str, &InterfaceCommon)]) -> Option<String> {
+    let mut servers: Vec<&String> = Vec::new();
+    let mut search: Vec<&String> = Vec::new();
+    for (_, common) in interfaces {
+        for server in &common.nameservers.addresses {
+            if !servers.contains(&server) {
+                servers.push(server);
+            }
+        }
+        for domain in &common.nameservers.search {
+            if !search.contains(&domain) {
+                search.push(domain);
+            }
+        }
+    }
+    if servers.is_empty() && search.is_empty() {
+        return None;
+    }
+    let mut content = String::from("# This file is generated by cloud-init\n");
+    if !search.is_empty() {
+        let search: Vec<&str> = search.iter().map(|s| s.as_str()).collect();
+        writeln!(content, "search {}", search.join(" ")).unwrap();
+    }
+    for server in servers {
+        writeln!(content, "nameserver {}", server).unwrap();
+    }
+    Some(content)
+}
+
+impl Default for RcConfRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Renderer for RcConfRenderer {
+    fn render(
+        &self,
+        config: &NetworkConfig,
+        _output_dir: &Path,
+    ) -> Result<Vec<RenderedFile>, CloudInitError> {
+        let mut network = RcConf::default();
+
+        let bonds: BTreeMap<_, _> = config.bonds.iter().collect();
+        let bridges: BTreeMap<_, _> = config.bridges.iter().collect();
+        let mut cloned = Vec::new();
+        clone_devices(&mut network, &mut cloned, "lagg", bonds.keys().copied());
+        clone_devices(&mut network, &mut cloned, "bridge", bridges.keys().copied());
+        if !cloned.is_empty() {
+            network.set("cloned_interfaces", cloned.join(" "));
+        }
+
+        // VLANs by parent, created by the vlans_<parent> lists
+        let vlans: BTreeMap<_, _> = config.vlans.iter().collect();
+        let mut by_parent: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (name, vlan) in &vlans {
+            by_parent.entry(&vlan.link).or_default().push(name);
+        }
+        for (parent, names) in by_parent {
+            network.set(format!("vlans_{}", var(parent)), names.join(" "));
+        }
+        for (name, vlan) in &vlans {
+            network.set(
+                format!("create_args_{}", var(name)),
+                format!("vlan {}", vlan.id),
+            );
+        }
+
+        // Render in a stable order: ethernets, bonds, bridges, VLANs
+        let mut interfaces: Vec<(&str, &InterfaceCommon)> = Vec::new();
+        let ethernets: BTreeMap<_, _> = config.ethernets.iter().collect();
+        for (name, eth) in ethernets {
+            let name = eth.common.set_name.as_deref().unwrap_or(name);
+            if eth
+                .match_config
+                .as_ref()
+                .is_some_and(|m| m.macaddress.is_some())
+            {
+                warn!(
+                    "rc.conf cannot match interfaces by MAC address; configuring {} by name",
+                    name
+                );
+            }
+            self.render_interface(&mut network, name, &eth.common, &[]);
+            interfaces.push((name, &eth.common));
+        }
+        for (name, bond) in bonds {
+            self.render_interface(
+                &mut network,
+                name,
+                &bond.common,
+                &self.bond_device(name, bond),
+            );
+            interfaces.push((name, &bond.common));
+        }
+        for (name, bridge) in bridges {
+            self.render_interface(
+                &mut network,
+                name,
+                &bridge.common,
+                &self.bridge_device(bridge),
+            );
+            interfaces.push((name, &bridge.common));
+        }
+        for (name, vlan) in vlans {
+            self.render_interface(&mut network, name, &vlan.common, &[]);
+            interfaces.push((name, &vlan.common));
+        }
+        if !config.wifis.is_empty() {
+            warn!("The rc.conf renderer does not support wifi; wifis are not rendered");
+        }
+
+        let mut files = vec![
+            RenderedFile {
+                path: "network".to_string(),
+                content: network.render(),
+                mode: 0o644,
+            },
+            RenderedFile {
+                path: "routing".to_string(),
+                content: render_routing(&interfaces).render(),
+                mode: 0o644,
+            },
+        ];
+        // rtsold solicits router advertisements so SLAAC does not wait
+        // for the next unsolicited one
+        if interfaces
+            .iter()
+            .any(|(_, common)| common.dhcp6 == Some(true) || common.accept_ra == Some(true))
+        {
+            let mut rtsold = RcConf::default();
+            rtsold.set("rtsold_enable", "YES");
+            files.push(RenderedFile {
+                path: "rtsold".to_string(),
+                content: rtsold.render(),
+                mode: 0o644,
+            });
+        }
+        if let Some(content) = render_resolv_conf(&interfaces) {
+            files.push(RenderedFile {
+                path: "/etc/resolv.conf".to_string(),
+                content,
+                mode: 0o644,
+            });
+        }
+        Ok(files)
+    }
+
+    fn renderer_type(&self) -> RendererType {
+        RendererType::RcConf
+    }
+
+    fn is_available(&self) -> bool {
+        RendererType::RcConf.is_installed(&RootContext::current())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(yaml: &str) -> Vec<RenderedFile> {
+        let config: NetworkConfig = serde_yaml::from_str(yaml).unwrap();
+        RcConfRenderer::new()
+            .render(&config, Path::new(OUTPUT_DIR))
+            .unwrap()
+    }
+
+    fn file<'a>(files: &'a [RenderedFile], path: &str) -> &'a str {
+        &files
+            .iter()
+            .find(|f| f.path == path)
+            .unwrap_or_else(|| panic!("no {}", path))
+            .content
+    }
+
+    #[test]
+    fn test_render_static_and_dhcp() {
+        let files = render(
+            r#"
+version: 2
+ethernets:
+  vtnet0:
+    addresses: [192.168.1.10/24, 192.168.1.11/24, "2001:db8::10/64"]
+    gateway4: 192.168.1.1
+    gateway6: "2001:db8::1"
+    mtu: 9000
+    nameservers:
+      addresses: [8.8.8.8]
+      search: [example.com]
+    routes:
+      - to: 10.0.0.0/8
+        via: 192.168.1.254
+      - to: 172.16.0.0/12
+  vtnet1:
+    dhcp4: true
+    dhcp6: true
+"#,
+        );
+        let network = file(&files, "network");
+        assert!(network.contains("ifconfig_vtnet0=\"inet 192.168.1.10/24 mtu 9000\"\n"));
+        assert!(network.contains("ifconfig_vtnet0_ipv6=\"inet6 2001:db8::10/64\"\n"));
+        assert!(network.contains("ifconfig_vtnet0_alias0=\"inet 192.168.1.11/24\"\n"));
+        assert!(network.contains("ifconfig_vtnet1=\"DHCP\"\n"));
+        assert!(network.contains("ifconfig_vtnet1_ipv6=\"inet6 accept_rtadv\"\n"));
+        assert!(file(&files, "rtsold").contains("rtsold_enable=\"YES\"\n"));
+
+        assert_eq!(
+            file(&files, "routing"),
+            "# This file is generated by cloud-init\n\
+             defaultrouter=\"192.168.1.1\"\n\
+             static_routes=\"cloudinit0 cloudinit1\"\n\
+             route_cloudinit0=\"-net 10.0.0.0/8 192.168.1.254\"\n\
+             route_cloudinit1=\"-net 172.16.0.0/12 -interface vtnet0\"\n\
+             ipv6_defaultrouter=\"2001:db8::1\"\n"
+        );
+        assert_eq!(
+            file(&files, "/etc/resolv.conf"),
+            "# This file is generated by cloud-init\nsearch example.com\nnameserver 8.8.8.8\n"
+        );
+    }
+
+    #[test]
+    fn test_render_lagg_bridge_vlan() {
+        let files = render(
+            r#"
+version: 2
+ethernets:
+  em0: {}
+  em1: {}
+bonds:
+  bond0:
+    interfaces: [em0, em1]
+    parameters:
+      mode: 802.3ad
+bridges:
+  bridge0:
+    interfaces: [bond0]
+    dhcp4: true
+vlans:
+  em0.100:
+    id: 100
+    link: em0
+    addresses: [10.100.0.5/24]
+"#,
+        );
+        let network = file(&files, "network");
+        assert!(network.contains("ifconfig_lagg0_name=\"bond0\"\n"));
+        assert!(network.contains("cloned_interfaces=\"lagg0 bridge0\"\n"));
+        assert!(network.contains("vlans_em0=\"em0.100\"\ncreate_args_em0_100=\"vlan 100\"\n"));
+        assert!(network.contains("ifconfig_em0=\"up\"\n"));
+        assert!(
+            network.contains("ifconfig_bond0=\"laggproto lacp laggport em0 laggport em1 up\"\n")
+        );
+        assert!(network.contains("ifconfig_bridge0=\"addm bond0 DHCP\"\n"));
+        assert!(network.contains("ifconfig_em0_100=\"inet 10.100.0.5/24\"\n"));
+        assert!(files.iter().all(|f| f.path != "/etc/resolv.conf"));
+    }
+}
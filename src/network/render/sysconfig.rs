@@ -0,0 +1,778 @@
+//! sysconfig renderer
+//!
+//! Generates `ifcfg-*` files for the initscripts of RHEL/CentOS 7 in
+//! /etc/sysconfig/network-scripts, and for wicked on SUSE in
+//! /etc/sysconfig/network. The two read similar but not identical keys:
+//!
+//! | Setting        | RHEL                         | SUSE                          |
+//! |----------------|------------------------------|-------------------------------|
+//! | Addresses      | `IPADDR`/`PREFIX`, `IPADDR1` | `IPADDR=a/p`, `IPADDR_1`      |
+//! | Bond           | `BONDING_OPTS`, `MASTER`     | `BONDING_MODULE_OPTS`, `BONDING_SLAVE_0` |
+//! | Bridge         | `TYPE=Bridge`, `BRIDGE`      | `BRIDGE=yes`, `BRIDGE_PORTS`  |
+//! | VLAN           | `VLAN=yes`, `PHYSDEV`        | `ETHERDEVICE`, `VLAN_ID`      |
+//! | Static routes  | `route-*`, `route6-*`        | `ifroute-*`                   |
+//!
+//! SUSE keeps DNS servers in the global netconfig settings rather than in
+//! `ifcfg` files, so they are not rendered there. Wifi and routing policy
+//! are not supported.
+
+use super::{RenderedFile, Renderer, RendererType};
+use crate::CloudInitError;
+use crate::distro::{Distro, DistroFamily};
+use crate::network::{
+    BondConfig, BondParameters, BridgeConfig, Dhcp6Mode, InterfaceCommon, NetworkConfig,
+    RouteConfig, VlanConfig,
+};
+use crate::root::RootContext;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use tracing::warn;
+
+/// Which sysconfig dialect to write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SysconfigFlavor {
+    /// initscripts network-scripts (RHEL/CentOS 7)
+    Rhel,
+    /// wicked (SUSE)
+    Suse,
+}
+
+impl SysconfigFlavor {
+    /// The flavor for the distribution installed under `root`
+    pub async fn detect(root: &RootContext) -> Self {
+        match Distro::detect(root).await.family() {
+            DistroFamily::Suse => Self::Suse,
+            _ => Self::Rhel,
+        }
+    }
+
+    /// Directory the `ifcfg` files go to
+    pub fn output_dir(self) -> &'static Path {
+        match self {
+            Self::Rhel => Path::new("/etc/sysconfig/network-scripts"),
+            Self::Suse => Path::new("/etc/sysconfig/network"),
+        }
+    }
+}
+
+/// Bond or bridge membership of an interface
+#[derive(Debug, Clone, Copy)]
+enum Membership<'a> {
+    Bond(&'a str),
+    Bridge(&'a str),
+}
+
+/// Ordered `KEY=value` lines of one `ifcfg` file
+#[derive(Debug, Default)]
+struct Ifcfg {
+    lines: Vec<(String, String)>,
+}
+
+impl Ifcfg {
+    fn set(&mut self, key: impl Into<String>, value: impl ToString) {
+        self.lines.push((key.into(), value.to_string()));
+    }
+
+    fn render(&self) -> String {
+        let mut content = String::from("# This file is generated by cloud-init\n");
+        for (key, value) in &self.lines {
+            writeln!(content, "{}={}", key, quote(value)).unwrap();
+        }
+        content
+    }
+}
+
+/// Quote a value for the shell that sources `ifcfg` files
+fn quote(value: &str) -> String {
+    if !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:/+@,=".contains(c))
+    {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace(['"', '\\', '$', '`'], ""))
+    }
+}
+
+/// The `index`th of a numbered key: `KEY` for the first, then
+/// `KEY<separator>N`
+fn numbered(key: &str, separator: &str, index: usize) -> String {
+    match index {
+        0 => key.to_string(),
+        n => format!("{}{}{}", key, separator, n),
+    }
+}
+
+/// Split `address/prefix`; addresses without one get the family's host
+/// prefix
+fn split_cidr(cidr: &str) -> (&str, &str) {
+    match cidr.split_once('/') {
+        Some((address, prefix)) => (address, prefix),
+        None if cidr.contains(':') => (cidr, "128"),
+        None => (cidr, "32"),
+    }
+}
+
+/// sysconfig renderer
+pub struct SysconfigRenderer {
+    flavor: SysconfigFlavor,
+}
+
+impl SysconfigRenderer {
+    pub fn new(flavor: SysconfigFlavor) -> Self {
+        Self { flavor }
+    }
+
+    /// Render one interface: its `ifcfg` file and any route files
+    ///
+    /// `device` lines (type, bond, bridge, VLAN settings) come right after
+    /// the device name.
+    fn render_interface(
+        &self,
+        name: &str,
+        common: &InterfaceCommon,
+        mac: Option<&str>,
+        device: Ifcfg,
+        membership: Option<Membership<'_>>,
+    ) -> Vec<RenderedFile> {
+        let name = common.set_name.as_deref().unwrap_or(name);
+        let mut ifcfg = Ifcfg::default();
+        match self.flavor {
+            SysconfigFlavor::Rhel => {
+                ifcfg.set("DEVICE", name);
+                ifcfg.set("ONBOOT", "yes");
+                ifcfg.set("NM_CONTROLLED", "no");
+                ifcfg.set("USERCTL", "no");
+                if let Some(mac) = mac {
+                    ifcfg.set("HWADDR", mac);
+                }
+            }
+            SysconfigFlavor::Suse => {
+                ifcfg.set(
+                    "STARTMODE",
+                    if membership.is_some() {
+                        "hotplug"
+                    } else {
+                        "auto"
+                    },
+                );
+            }
+        }
+        ifcfg.lines.extend(device.lines);
+
+        match (self.flavor, membership) {
+            (SysconfigFlavor::Rhel, Some(Membership::Bond(bond))) => {
+                ifcfg.set("MASTER", bond);
+                ifcfg.set("SLAVE", "yes");
+            }
+            (SysconfigFlavor::Rhel, Some(Membership::Bridge(bridge))) => {
+                ifcfg.set("BRIDGE", bridge);
+            }
+            _ => {}
+        }
+
+        let ipv4: Vec<&str> = common
+            .addresses
+            .iter()
+            .filter(|a| !a.contains(':'))
+            .map(String::as_str)
+            .collect();
+        let ipv6: Vec<&str> = common
+            .addresses
+            .iter()
+            .filter(|a| a.contains(':'))
+            .map(String::as_str)
+            .collect();
+        let dhcp4 = common.dhcp4 == Some(true);
+        let dhcp6 = common.dhcp6 == Some(true);
+        let autoconf = common.accept_ra == Some(true) || common.dhcp6_stateless();
+
+        match self.flavor {
+            SysconfigFlavor::Rhel => {
+                ifcfg.set("BOOTPROTO", if dhcp4 { "dhcp" } else { "none" });
+                for (i, cidr) in ipv4.iter().enumerate() {
+                    let (address, prefix) = split_cidr(cidr);
+                    ifcfg.set(numbered("IPADDR", "", i), address);
+                    ifcfg.set(numbered("PREFIX", "", i), prefix);
+                }
+                if let Some(gateway) = &common.gateway4 {
+                    ifcfg.set("GATEWAY", gateway);
+                    ifcfg.set("DEFROUTE", "yes");
+                }
+                if !ipv6.is_empty() || dhcp6 || autoconf {
+                    ifcfg.set("IPV6INIT", "yes");
+                    if dhcp6 {
+                        ifcfg.set("DHCPV6C", "yes");
+                    }
+                    if common.dhcp6_stateless() {
+                        // dhclient -S: information request only
+                        ifcfg.set("DHCPV6C_OPTIONS", "-S");
+                    }
+                    ifcfg.set("IPV6_AUTOCONF", if autoconf { "yes" } else { "no" });
+                    if let Some((first, rest)) = ipv6.split_first() {
+                        ifcfg.set("IPV6ADDR", first);
+                        if !rest.is_empty() {
+                            ifcfg.set("IPV6ADDR_SECONDARIES", rest.join(" "));
+                        }
+                    }
+                    if let Some(gateway) = &common.gateway6 {
+                        ifcfg.set("IPV6_DEFAULTGW", gateway);
+                        ifcfg.set("IPV6_DEFROUTE", "yes");
+                    }
+                }
+                for (i, server) in common.nameservers.addresses.iter().enumerate() {
+                    ifcfg.set(format!("DNS{}", i + 1), server);
+                }
+                if !common.nameservers.search.is_empty() {
+                    ifcfg.set("DOMAIN", common.nameservers.search.join(" "));
+                }
+            }
+            SysconfigFlavor::Suse => {
+                let bootproto = match (dhcp4, dhcp6) {
+                    (true, true) => "dhcp",
+                    (true, false) => "dhcp4",
+                    (false, true) => "dhcp6",
+                    _ if !common.addresses.is_empty() => "static",
+                    _ if autoconf => "auto6",
+                    _ => "none",
+                };
+                ifcfg.set("BOOTPROTO", bootproto);
+                if common.dhcp6_stateless() {
+                    ifcfg.set("DHCLIENT6_MODE", "info");
+                } else if dhcp6 && common.dhcp6_mode == Some(Dhcp6Mode::Stateful) {
+                    ifcfg.set("DHCLIENT6_MODE", "managed");
+                }
+                for (i, cidr) in ipv4.iter().chain(&ipv6).enumerate() {
+                    let (address, prefix) = split_cidr(cidr);
+                    ifcfg.set(
+                        numbered("IPADDR", "_", i),
+                        format!("{}/{}", address, prefix),
+                    );
+                }
+                if !common.nameservers.addresses.is_empty() {
+                    warn!(
+                        "{}: SUSE reads DNS servers from netconfig, not ifcfg files; not rendered",
+                        name
+                    );
+                }
+            }
+        }
+
+        if let Some(mtu) = common.mtu {
+            ifcfg.set("MTU", mtu);
+        }
+        if let Some(macaddress) = &common.macaddress {
+            match self.flavor {
+                SysconfigFlavor::Rhel => ifcfg.set("MACADDR", macaddress),
+                SysconfigFlavor::Suse => ifcfg.set("LLADDR", macaddress),
+            }
+        }
+
+        let mut files = vec![RenderedFile {
+            path: format!("ifcfg-{}", name),
+            content: ifcfg.render(),
+            mode: 0o644,
+        }];
+        files.extend(self.render_routes(name, common));
+        files
+    }
+
+    /// Route files of one interface: `route-*`/`route6-*` in `ip route`
+    /// syntax on RHEL, `ifroute-*` on SUSE
+    fn render_routes(&self, name: &str, common: &InterfaceCommon) -> Vec<RenderedFile> {
+        let mut files = Vec::new();
+        match self.flavor {
+            SysconfigFlavor::Rhel => {
+                for (prefix, ipv6) in [("route", false), ("route6", true)] {
+                    let routes: Vec<&RouteConfig> = common
+                        .routes
+                        .iter()
+                        .filter(|r| r.to.contains(':') == ipv6)
+                        .collect();
+                    if routes.is_empty() {
+                        continue;
+                    }
+                    let mut content = String::from("# This file is generated by cloud-init\n");
+                    for route in routes {
+                        let mut line = route.to.clone();
+                        if let Some(via) = &route.via {
+                            write!(line, " via {}", via).unwrap();
+                        }
+                        write!(line, " dev {}", name).unwrap();
+                        if let Some(metric) = route.metric {
+                            write!(line, " metric {}", metric).unwrap();
+                        }
+                        if let Some(table) = route.table {
+                            write!(line, " table {}", table).unwrap();
+                        }
+                        if route.on_link == Some(true) {
+                            line.push_str(" onlink");
+                        }
+                        writeln!(content, "{}", line).unwrap();
+                    }
+                    files.push(RenderedFile {
+                        path: format!("{}-{}", prefix, name),
+                        content,
+                        mode: 0o644,
+                    });
+                }
+            }
+            SysconfigFlavor::Suse => {
+                // Gateways are routes too; wicked has no GATEWAY key
+                let gateways = [&common.gateway4, &common.gateway6]
+                    .into_iter()
+                    .flatten()
+                    .map(|gateway| (String::from("default"), Some(gateway), None));
+                let routes = common
+                    .routes
+                    .iter()
+                    .map(|r| (r.to.clone(), r.via.as_ref(), r.metric));
+                let lines: Vec<String> = gateways
+                    .chain(routes)
+                    .map(|(to, via, metric)| {
+                        let mut line = format!(
+                            "{} {} - {}",
+                            to,
+                            via.map(String::as_str).unwrap_or("-"),
+                            name
+                        );
+                        if let Some(metric) = metric {
+                            write!(line, " metric {}", metric).unwrap();
+                        }
+                        line
+                    })
+                    .collect();
+                if !lines.is_empty() {
+                    files.push(RenderedFile {
+                        path: format!("ifroute-{}", name),
+                        content: format!(
+                            "# This file is generated by cloud-init\n{}\n",
+                            lines.join("\n")
+                        ),
+                        mode: 0o644,
+                    });
+                }
+            }
+        }
+        files
+    }
+
+    fn bond_device(&self, bond: &BondConfig) -> Ifcfg {
+        let mut device = Ifcfg::default();
+        let options = bond
+            .parameters
+            .as_ref()
+            .map(bonding_options)
+            .unwrap_or_default()
+            .join(" ");
+        match self.flavor {
+            SysconfigFlavor::Rhel => {
+                device.set("TYPE", "Bond");
+                device.set("BONDING_MASTER", "yes");
+                if !options.is_empty() {
+                    device.set("BONDING_OPTS", options);
+                }
+            }
+            SysconfigFlavor::Suse => {
+                device.set("BONDING_MASTER", "yes");
+                if !options.is_empty() {
+                    device.set("BONDING_MODULE_OPTS", options);
+                }
+                for (i, member) in bond.interfaces.iter().enumerate() {
+                    device.set(format!("BONDING_SLAVE_{}", i), member);
+                }
+            }
+        }
+        device
+    }
+
+    fn bridge_device(&self, bridge: &BridgeConfig) -> Ifcfg {
+        let mut device = Ifcfg::default();
+        let parameters = bridge.parameters.clone().unwrap_or_default();
+        match self.flavor {
+            SysconfigFlavor::Rhel => {
+                device.set("TYPE", "Bridge");
+                if let Some(stp) = parameters.stp {
+                    device.set("STP", if stp { "on" } else { "off" });
+                }
+                if let Some(delay) = parameters.forward_delay {
+                    device.set("DELAY", delay);
+                }
+                let options: Vec<String> = [
+                    ("ageing_time", parameters.ageing_time),
+                    ("hello_time", parameters.hello_time),
+                    ("max_age", parameters.max_age),
+                    ("priority", parameters.priority),
+                ]
+                .into_iter()
+                .filter_map(|(key, value)| Some(format!("{}={}", key, value?)))
+                .collect();
+                if !options.is_empty() {
+                    device.set("BRIDGING_OPTS", options.join(" "));
+                }
+            }
+            SysconfigFlavor::Suse => {
+                device.set("BRIDGE", "yes");
+                device.set("BRIDGE_PORTS", bridge.interfaces.join(" "));
+                if let Some(stp) = parameters.stp {
+                    device.set("BRIDGE_STP", if stp { "on" } else { "off" });
+                }
+                if let Some(delay) = parameters.forward_delay {
+                    device.set("BRIDGE_FORWARDDELAY", delay);
+                }
+                if let Some(age) = parameters.max_age {
+                    device.set("BRIDGE_MAXAGE", age);
+                }
+                if let Some(hello) = parameters.hello_time {
+                    device.set("BRIDGE_HELLOTIME", hello);
+                }
+                if let Some(ageing) = parameters.ageing_time {
+                    device.set("BRIDGE_AGEINGTIME", ageing);
+                }
+                if let Some(priority) = parameters.priority {
+                    device.set("BRIDGE_PRIORITY", priority);
+                }
+            }
+        }
+        device
+    }
+
+    fn vlan_device(&self, vlan: &VlanConfig) -> Ifcfg {
+        let mut device = Ifcfg::default();
+        match self.flavor {
+            SysconfigFlavor::Rhel => {
+                device.set("VLAN", "yes");
+                device.set("PHYSDEV", &vlan.link);
+                device.set("VLAN_ID", vlan.id);
+            }
+            SysconfigFlavor::Suse => {
+                device.set("ETHERDEVICE", &vlan.link);
+                device.set("VLAN_ID", vlan.id);
+            }
+        }
+        device
+    }
+}
+
+/// Kernel bonding driver options for `BONDING_OPTS`
+fn bonding_options(parameters: &BondParameters) -> Vec<String> {
+    let mut options = Vec::new();
+    if let Some(mode) = &parameters.mode {
+        options.push(format!("mode={}", mode));
+    }
+    if let Some(miimon) = parameters.mii_monitor_interval {
+        options.push(format!("miimon={}", miimon));
+    }
+    if let Some(primary) = &parameters.primary {
+        options.push(format!("primary={}", primary));
+    }
+    if let Some(policy) = &parameters.transmit_hash_policy {
+        options.push(format!("xmit_hash_policy={}", policy));
+    }
+    if let Some(rate) = &parameters.lacp_rate {
+        options.push(format!("lacp_rate={}", rate));
+    }
+    if let Some(interval) = parameters.arp_interval {
+        options.push(format!("arp_interval={}", interval));
+    }
+    if !parameters.arp_ip_targets.is_empty() {
+        options.push(format!(
+            "arp_ip_target={}",
+            parameters.arp_ip_targets.join(",")
+        ));
+    }
+    options
+}
+
+impl Renderer for SysconfigRenderer {
+    fn render(
+        &self,
+        config: &NetworkConfig,
+        _output_dir: &Path,
+    ) -> Result<Vec<RenderedFile>, CloudInitError> {
+        let mut files = Vec::new();
+
+        // Which bond or bridge each member interface belongs to
+        let mut members: BTreeMap<&str, Membership<'_>> = BTreeMap::new();
+        for (bond, cfg) in &config.bonds {
+            for member in &cfg.interfaces {
+                members.insert(member, Membership::Bond(bond));
+            }
+        }
+        for (bridge, cfg) in &config.bridges {
+            for member in &cfg.interfaces {
+                members.insert(member, Membership::Bridge(bridge));
+            }
+        }
+
+        // Render in a stable order: ethernets, bonds, bridges, VLANs
+        let ethernets: BTreeMap<_, _> = config.ethernets.iter().collect();
+        for (name, eth) in ethernets {
+            let mac = eth
+                .match_config
+                .as_ref()
+                .and_then(|m| m.macaddress.as_deref());
+            let mut device = Ifcfg::default();
+            if self.flavor == SysconfigFlavor::Rhel {
+                device.set("TYPE", "Ethernet");
+            }
+            files.extend(self.render_interface(
+                name,
+                &eth.common,
+                mac,
+                device,
+                members.get(name.as_str()).copied(),
+            ));
+        }
+
+        let bonds: BTreeMap<_, _> = config.bonds.iter().collect();
+        for (name, bond) in bonds {
+            files.extend(self.render_interface(
+                name,
+                &bond.common,
+                None,
+                self.bond_device(bond),
+                members.get(name.as_str()).copied(),
+            ));
+        }
+
+        let bridges: BTreeMap<_, _> = config.bridges.iter().collect();
+        for (name, bridge) in bridges {
+            files.extend(self.render_interface(
+                name,
+                &bridge.common,
+                None,
+                self.bridge_device(bridge),
+                members.get(name.as_str()).copied(),
+            ));
+        }
+
+        let vlans: BTreeMap<_, _> = config.vlans.iter().collect();
+        for (name, vlan) in vlans {
+            files.extend(self.render_interface(
+                name,
+                &vlan.common,
+                None,
+                self.vlan_device(vlan),
+                members.get(name.as_str()).copied(),
+            ));
+        }
+
+        if !config.wifis.is_empty() {
+            warn!("The sysconfig renderer does not support wifi; wifis are not rendered");
+        }
+
+        Ok(files)
+    }
+
+    fn renderer_type(&self) -> RendererType {
+        RendererType::Sysconfig
+    }
+
+    fn is_available(&self) -> bool {
+        RendererType::Sysconfig.is_installed(&RootContext::current())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(flavor: SysconfigFlavor, yaml: &str) -> Vec<RenderedFile> {
+        let config: NetworkConfig = serde_yaml::from_str(yaml).unwrap();
+        SysconfigRenderer::new(flavor)
+            .render(&config, flavor.output_dir())
+            .unwrap()
+    }
+
+    fn file<'a>(files: &'a [RenderedFile], path: &str) -> &'a str {
+        &files
+            .iter()
+            .find(|f| f.path == path)
+            .unwrap_or_else(|| panic!("no {}", path))
+            .content
+    }
+
+    const STATIC: &str = r#"
+version: 2
+ethernets:
+  eth0:
+    match:
+      macaddress: "52:54:00:12:34:56"
+    addresses: [192.168.1.10/24, 192.168.1.11/24, "2001:db8::10/64"]
+    gateway4: 192.168.1.1
+    nameservers:
+      addresses: [8.8.8.8, 1.1.1.1]
+      search: [example.com, corp.example.com]
+    mtu: 9000
+    routes:
+      - to: 10.0.0.0/8
+        via: 192.168.1.254
+        metric: 100
+      - to: "2001:db8:1::/48"
+        via: "2001:db8::1"
+"#;
+
+    #[test]
+    fn test_render_rhel_static() {
+        let files = render(SysconfigFlavor::Rhel, STATIC);
+        let ifcfg = file(&files, "ifcfg-eth0");
+        for line in [
+            "DEVICE=eth0\n",
+            "ONBOOT=yes\n",
+            "HWADDR=52:54:00:12:34:56\n",
+            "TYPE=Ethernet\n",
+            "BOOTPROTO=none\n",
+            "IPADDR=192.168.1.10\nPREFIX=24\nIPADDR1=192.168.1.11\nPREFIX1=24\n",
+            "GATEWAY=192.168.1.1\n",
+            "IPV6INIT=yes\n",
+            "IPV6ADDR=2001:db8::10/64\n",
+            "DNS1=8.8.8.8\nDNS2=1.1.1.1\n",
+            "DOMAIN=\"example.com corp.example.com\"\n",
+            "MTU=9000\n",
+        ] {
+            assert!(ifcfg.contains(line), "{:?} not in\n{}", line, ifcfg);
+        }
+        assert_eq!(
+            file(&files, "route-eth0"),
+            "# This file is generated by cloud-init\n10.0.0.0/8 via 192.168.1.254 dev eth0 metric 100\n"
+        );
+        assert!(file(&files, "route6-eth0").contains("2001:db8:1::/48 via 2001:db8::1 dev eth0\n"));
+    }
+
+    #[test]
+    fn test_render_suse_static() {
+        let files = render(SysconfigFlavor::Suse, STATIC);
+        let ifcfg = file(&files, "ifcfg-eth0");
+        assert!(ifcfg.contains("STARTMODE=auto\nBOOTPROTO=static\n"));
+        assert!(ifcfg.contains(
+            "IPADDR=192.168.1.10/24\nIPADDR_1=192.168.1.11/24\nIPADDR_2=2001:db8::10/64\n"
+        ));
+        assert!(!ifcfg.contains("DNS"));
+        assert_eq!(
+            file(&files, "ifroute-eth0"),
+            "# This file is generated by cloud-init\ndefault 192.168.1.1 - eth0\n10.0.0.0/8 192.168.1.254 - eth0 metric 100\n2001:db8:1::/48 2001:db8::1 - eth0\n"
+        );
+    }
+
+    const IPV6_ONLY: &str = r#"
+version: 2
+ethernets:
+  eth0:
+    addresses: ["2001:db8::10/64"]
+    gateway6: "2001:db8::1"
+    nameservers:
+      addresses: ["2001:db8::53"]
+  eth1:
+    dhcp6: true
+    dhcp6-mode: stateless
+"#;
+
+    #[test]
+    fn test_render_rhel_ipv6_only() {
+        let files = render(SysconfigFlavor::Rhel, IPV6_ONLY);
+        let eth0 = file(&files, "ifcfg-eth0");
+        for line in [
+            "BOOTPROTO=none\n",
+            "IPV6INIT=yes\nIPV6_AUTOCONF=no\nIPV6ADDR=2001:db8::10/64\n",
+            "IPV6_DEFAULTGW=2001:db8::1\nIPV6_DEFROUTE=yes\n",
+            "DNS1=2001:db8::53\n",
+        ] {
+            assert!(eth0.contains(line), "{:?} not in\n{}", line, eth0);
+        }
+        assert!(!eth0.contains("IPADDR="));
+        let eth1 = file(&files, "ifcfg-eth1");
+        assert!(
+            eth1.contains("IPV6INIT=yes\nDHCPV6C=yes\nDHCPV6C_OPTIONS=-S\nIPV6_AUTOCONF=yes\n")
+        );
+    }
+
+    #[test]
+    fn test_render_suse_ipv6_only() {
+        let files = render(SysconfigFlavor::Suse, IPV6_ONLY);
+        assert!(file(&files, "ifcfg-eth0").contains("BOOTPROTO=static\nIPADDR=2001:db8::10/64\n"));
+        assert!(file(&files, "ifcfg-eth1").contains("BOOTPROTO=dhcp6\nDHCLIENT6_MODE=info\n"));
+        assert!(file(&files, "ifroute-eth0").contains("default 2001:db8::1 - eth0\n"));
+    }
+
+    const VIRTUAL: &str = r#"
+version: 2
+ethernets:
+  eth0: {}
+  eth1: {}
+bonds:
+  bond0:
+    interfaces: [eth0, eth1]
+    parameters:
+      mode: 802.3ad
+      mii-monitor-interval: 100
+      transmit-hash-policy: layer3+4
+bridges:
+  br0:
+    interfaces: [bond0]
+    dhcp4: true
+    parameters:
+      stp: false
+      forward-delay: 4
+vlans:
+  bond0.100:
+    id: 100
+    link: bond0
+    addresses: [10.100.0.5/24]
+"#;
+
+    #[test]
+    fn test_render_rhel_bond_bridge_vlan() {
+        let files = render(SysconfigFlavor::Rhel, VIRTUAL);
+        assert!(file(&files, "ifcfg-eth1").contains("MASTER=bond0\nSLAVE=yes\n"));
+
+        let bond = file(&files, "ifcfg-bond0");
+        assert!(bond.contains("TYPE=Bond\nBONDING_MASTER=yes\n"));
+        assert!(
+            bond.contains("BONDING_OPTS=\"mode=802.3ad miimon=100 xmit_hash_policy=layer3+4\"\n")
+        );
+        assert!(bond.contains("BRIDGE=br0\n"));
+
+        let bridge = file(&files, "ifcfg-br0");
+        assert!(bridge.contains("TYPE=Bridge\nSTP=off\nDELAY=4\n"));
+        assert!(bridge.contains("BOOTPROTO=dhcp\n"));
+
+        let vlan = file(&files, "ifcfg-bond0.100");
+        assert!(vlan.contains("VLAN=yes\nPHYSDEV=bond0\nVLAN_ID=100\n"));
+        assert!(vlan.contains("IPADDR=10.100.0.5\nPREFIX=24\n"));
+    }
+
+    #[test]
+    fn test_render_suse_bond_bridge_vlan() {
+        let files = render(SysconfigFlavor::Suse, VIRTUAL);
+        assert!(file(&files, "ifcfg-eth0").contains("STARTMODE=hotplug\nBOOTPROTO=none\n"));
+
+        let bond = file(&files, "ifcfg-bond0");
+        assert!(bond.contains("BONDING_MASTER=yes\n"));
+        assert!(bond.contains(
+            "BONDING_MODULE_OPTS=\"mode=802.3ad miimon=100 xmit_hash_policy=layer3+4\"\n"
+        ));
+        assert!(bond.contains("BONDING_SLAVE_0=eth0\nBONDING_SLAVE_1=eth1\n"));
+
+        let bridge = file(&files, "ifcfg-br0");
+        assert!(
+            bridge.contains(
+                "BRIDGE=yes\nBRIDGE_PORTS=bond0\nBRIDGE_STP=off\nBRIDGE_FORWARDDELAY=4\n"
+            )
+        );
+        assert!(bridge.contains("BOOTPROTO=dhcp4\n"));
+
+        let vlan = file(&files, "ifcfg-bond0.100");
+        assert!(vlan.contains("ETHERDEVICE=bond0\nVLAN_ID=100\n"));
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("eth0"), "eth0");
+        assert_eq!(quote("a b"), "\"a b\"");
+        assert_eq!(quote(""), "\"\"");
+        assert_eq!(quote("$(reboot)"), "\"(reboot)\"");
+    }
+}
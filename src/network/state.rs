@@ -0,0 +1,348 @@
+//! Live network state
+//!
+//! Reads the links the kernel currently has from `/sys/class/net` and
+//! their addresses from `ip -json addr`, and compares them with a desired
+//! [`NetworkConfig`]. Together with [`files_unchanged`] this lets
+//! `apply_network_config` leave networking alone on per-boot runs where
+//! nothing has changed, instead of rewriting files and bouncing links.
+
+use super::ephemeral::SYS_CLASS_NET;
+use super::render::RenderedFile;
+use super::{InterfaceCommon, MatchConfig, NetworkConfig};
+use crate::CloudInitError;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::fs;
+use tracing::debug;
+
+/// State of one network link
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkState {
+    /// Interface name
+    pub name: String,
+    /// Hardware address, lowercase
+    pub mac: Option<String>,
+    /// Current MTU
+    pub mtu: Option<u32>,
+    /// Kernel operstate (up, down, unknown, ...)
+    pub operstate: Option<String>,
+    /// Assigned addresses in CIDR notation
+    pub addresses: Vec<String>,
+}
+
+/// Links currently present on the system, keyed by name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkState {
+    pub links: BTreeMap<String, LinkState>,
+}
+
+/// One entry of `ip -json addr` output
+#[derive(Debug, Deserialize)]
+struct IpLink {
+    ifname: String,
+    #[serde(default)]
+    addr_info: Vec<IpAddrInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IpAddrInfo {
+    local: String,
+    prefixlen: u8,
+}
+
+impl NetworkState {
+    /// Read the live state of this system
+    pub async fn read() -> Self {
+        let mut state = Self::from_sys(Path::new(SYS_CLASS_NET)).await;
+
+        match tokio::process::Command::new("ip")
+            .args(["-json", "addr"])
+            .output()
+            .await
+        {
+            Ok(o) if o.status.success() => {
+                if let Err(e) = state.merge_ip_json(&String::from_utf8_lossy(&o.stdout)) {
+                    debug!("Ignoring unparseable ip addr output: {}", e);
+                }
+            }
+            Ok(o) => debug!(
+                "ip addr failed: {}",
+                String::from_utf8_lossy(&o.stderr).trim()
+            ),
+            Err(e) => debug!("Failed to run ip: {}", e),
+        }
+
+        state
+    }
+
+    /// Read links from a `/sys/class/net`-style directory
+    pub async fn from_sys(sys_class_net: &Path) -> Self {
+        let mut links = BTreeMap::new();
+        let Ok(mut entries) = fs::read_dir(sys_class_net).await else {
+            return Self { links };
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let dir = entry.path();
+            let read = |file: &str| {
+                let path = dir.join(file);
+                async move {
+                    fs::read_to_string(path)
+                        .await
+                        .ok()
+                        .map(|s| s.trim().to_string())
+                        .filter(|s| !s.is_empty())
+                }
+            };
+
+            let link = LinkState {
+                name: name.clone(),
+                mac: read("address").await.map(|m| m.to_lowercase()),
+                mtu: read("mtu").await.and_then(|m| m.parse().ok()),
+                operstate: read("operstate").await,
+                addresses: Vec::new(),
+            };
+            links.insert(name, link);
+        }
+
+        Self { links }
+    }
+
+    /// Add addresses from `ip -json addr` output
+    ///
+    /// Links that only appear in the JSON are added as well.
+    pub fn merge_ip_json(&mut self, json: &str) -> Result<(), CloudInitError> {
+        let parsed: Vec<IpLink> = serde_json::from_str(json)?;
+        for ip_link in parsed {
+            let link = self
+                .links
+                .entry(ip_link.ifname.clone())
+                .or_insert_with(|| LinkState {
+                    name: ip_link.ifname.clone(),
+                    ..Default::default()
+                });
+            link.addresses = ip_link
+                .addr_info
+                .iter()
+                .map(|a| format!("{}/{}", a.local, a.prefixlen))
+                .collect();
+        }
+        Ok(())
+    }
+
+    /// Find the link an interface config refers to
+    fn find_link(
+        &self,
+        name: &str,
+        match_config: Option<&MatchConfig>,
+        common: &InterfaceCommon,
+    ) -> Option<&LinkState> {
+        if let Some(mac) = match_config.and_then(|m| m.macaddress.as_ref()) {
+            let mac = mac.to_lowercase();
+            return self
+                .links
+                .values()
+                .find(|l| l.mac.as_deref() == Some(mac.as_str()));
+        }
+        let name = common.set_name.as_deref().unwrap_or(name);
+        self.links.get(name)
+    }
+
+    /// Describe every way the live state differs from `config`
+    ///
+    /// Only what the config pins down is compared: missing links, missing
+    /// static addresses and MTU. DHCP-assigned addresses are not checked.
+    /// An empty result means the config is already in effect.
+    pub fn drift(&self, config: &NetworkConfig) -> Vec<String> {
+        let mut wanted: Vec<(&str, Option<&MatchConfig>, &InterfaceCommon)> = Vec::new();
+        wanted.extend(
+            config
+                .ethernets
+                .iter()
+                .map(|(n, c)| (n.as_str(), c.match_config.as_ref(), &c.common)),
+        );
+        wanted.extend(
+            config
+                .wifis
+                .iter()
+                .map(|(n, c)| (n.as_str(), c.match_config.as_ref(), &c.common)),
+        );
+        wanted.extend(
+            config
+                .bonds
+                .iter()
+                .map(|(n, c)| (n.as_str(), None, &c.common)),
+        );
+        wanted.extend(
+            config
+                .bridges
+                .iter()
+                .map(|(n, c)| (n.as_str(), None, &c.common)),
+        );
+        wanted.extend(
+            config
+                .vlans
+                .iter()
+                .map(|(n, c)| (n.as_str(), None, &c.common)),
+        );
+        wanted.sort_by_key(|(name, _, _)| *name);
+
+        let mut drift = Vec::new();
+        for (name, match_config, common) in wanted {
+            let Some(link) = self.find_link(name, match_config, common) else {
+                if common.optional != Some(true) {
+                    drift.push(format!("{}: link not present", name));
+                }
+                continue;
+            };
+
+            for addr in &common.addresses {
+                if !link.addresses.iter().any(|a| a == addr) {
+                    drift.push(format!("{}: address {} not assigned", name, addr));
+                }
+            }
+            if let Some(mtu) = common.mtu
+                && link.mtu != Some(mtu)
+            {
+                drift.push(format!(
+                    "{}: mtu is {} not {}",
+                    name,
+                    link.mtu.map_or("unknown".to_string(), |m| m.to_string()),
+                    mtu
+                ));
+            }
+        }
+        drift
+    }
+}
+
+/// Whether every rendered file already exists under `output_dir` with
+/// identical content
+pub async fn files_unchanged(output_dir: &Path, files: &[RenderedFile]) -> bool {
+    for file in files {
+        let path = output_dir.join(&file.path);
+        match fs::read_to_string(&path).await {
+            Ok(existing) if existing == file.content => {}
+            _ => {
+                debug!("Network config {} differs from disk", path.display());
+                return false;
+            }
+        }
+    }
+    !files.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    async fn fake_link(root: &Path, name: &str, mac: &str, mtu: u32) {
+        let dir = root.join(name);
+        fs::create_dir_all(&dir).await.unwrap();
+        fs::write(dir.join("address"), format!("{}\n", mac))
+            .await
+            .unwrap();
+        fs::write(dir.join("mtu"), format!("{}\n", mtu))
+            .await
+            .unwrap();
+        fs::write(dir.join("operstate"), "up\n").await.unwrap();
+    }
+
+    const IP_JSON: &str = r#"[
+        {"ifindex":1,"ifname":"lo","addr_info":[{"family":"inet","local":"127.0.0.1","prefixlen":8}]},
+        {"ifindex":2,"ifname":"eth0","addr_info":[
+            {"family":"inet","local":"192.0.2.10","prefixlen":24},
+            {"family":"inet6","local":"fe80::1","prefixlen":64}
+        ]}
+    ]"#;
+
+    async fn sample_state() -> (TempDir, NetworkState) {
+        let temp = TempDir::new().unwrap();
+        fake_link(temp.path(), "eth0", "52:54:00:AA:BB:CC", 1500).await;
+        fake_link(temp.path(), "lo", "00:00:00:00:00:00", 65536).await;
+        let mut state = NetworkState::from_sys(temp.path()).await;
+        state.merge_ip_json(IP_JSON).unwrap();
+        (temp, state)
+    }
+
+    #[tokio::test]
+    async fn test_read_sys_and_ip_json() {
+        let (_temp, state) = sample_state().await;
+        let eth0 = &state.links["eth0"];
+        assert_eq!(eth0.mac.as_deref(), Some("52:54:00:aa:bb:cc"));
+        assert_eq!(eth0.mtu, Some(1500));
+        assert_eq!(eth0.operstate.as_deref(), Some("up"));
+        assert_eq!(eth0.addresses, vec!["192.0.2.10/24", "fe80::1/64"]);
+    }
+
+    #[tokio::test]
+    async fn test_no_drift_when_applied() {
+        let (_temp, state) = sample_state().await;
+        let config = NetworkConfig::from_yaml(
+            r#"
+version: 2
+ethernets:
+  lan:
+    match:
+      macaddress: "52:54:00:aa:bb:cc"
+    addresses: [192.0.2.10/24]
+    mtu: 1500
+  wlan0:
+    optional: true
+    dhcp4: true
+"#,
+        )
+        .unwrap();
+        assert!(state.drift(&config).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_drift_reports_differences() {
+        let (_temp, state) = sample_state().await;
+        let config = NetworkConfig::from_yaml(
+            r#"
+version: 2
+ethernets:
+  eth0:
+    addresses: [192.0.2.11/24]
+    mtu: 9000
+  eth1:
+    dhcp4: true
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            state.drift(&config),
+            vec![
+                "eth0: address 192.0.2.11/24 not assigned",
+                "eth0: mtu is 1500 not 9000",
+                "eth1: link not present",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_files_unchanged() {
+        let temp = TempDir::new().unwrap();
+        let files = vec![RenderedFile {
+            path: "10-eth0.network".to_string(),
+            content: "[Match]\nName=eth0\n".to_string(),
+            mode: 0o644,
+        }];
+
+        assert!(!files_unchanged(temp.path(), &files).await);
+
+        fs::write(temp.path().join("10-eth0.network"), "[Match]\nName=eth0\n")
+            .await
+            .unwrap();
+        assert!(files_unchanged(temp.path(), &files).await);
+
+        fs::write(temp.path().join("10-eth0.network"), "[Match]\nName=eth1\n")
+            .await
+            .unwrap();
+        assert!(!files_unchanged(temp.path(), &files).await);
+    }
+}
@@ -4,8 +4,8 @@
 //! This format is still used by some cloud providers and tools.
 
 use super::{
-    BondConfig, BondParameters, BridgeConfig, EthernetConfig, InterfaceCommon, MatchConfig,
-    NameserverConfig, NetworkConfig, RouteConfig, VlanConfig,
+    BondConfig, BondParameters, BridgeConfig, Dhcp6Mode, EthernetConfig, InterfaceCommon,
+    MatchConfig, NameserverConfig, NetworkConfig, RouteConfig, VlanConfig,
 };
 use serde::{Deserialize, Serialize};
 use tracing::debug;
@@ -351,11 +351,17 @@ impl NetworkConfigV1 {
                         }
                     }
                 }
-                "ipv6_slaac" | "ipv6_dhcpv6-stateless" => {
+                "ipv6_slaac" => {
+                    common.accept_ra = Some(true);
+                }
+                "ipv6_dhcpv6-stateless" => {
+                    common.dhcp6 = Some(true);
+                    common.dhcp6_mode = Some(Dhcp6Mode::Stateless);
                     common.accept_ra = Some(true);
                 }
                 "ipv6_dhcpv6-stateful" => {
                     common.dhcp6 = Some(true);
+                    common.dhcp6_mode = Some(Dhcp6Mode::Stateful);
                 }
                 _ => {}
             }
@@ -399,12 +405,17 @@ impl NetworkConfigV1 {
 }
 
 /// Convert netmask to CIDR prefix length
-fn netmask_to_prefix(netmask: &str) -> u8 {
+pub(crate) fn netmask_to_prefix(netmask: &str) -> u8 {
     // Handle CIDR notation directly
     if let Ok(prefix) = netmask.parse::<u8>() {
         return prefix;
     }
 
+    // IPv6 netmasks (ffff:ffff:ffff:ffff::) count bits the same way
+    if let Ok(mask) = netmask.parse::<std::net::Ipv6Addr>() {
+        return mask.to_bits().count_ones() as u8;
+    }
+
     // Convert dotted-decimal netmask to prefix
     let octets: Vec<u8> = netmask.split('.').filter_map(|s| s.parse().ok()).collect();
 
@@ -490,6 +501,37 @@ config:
         assert_eq!(eth0.common.nameservers.addresses, vec!["8.8.8.8"]);
     }
 
+    #[test]
+    fn test_parse_v1_dhcpv6_modes() {
+        let yaml = r#"
+version: 1
+config:
+  - type: physical
+    name: eth0
+    subnets:
+      - type: ipv6_dhcpv6-stateless
+  - type: physical
+    name: eth1
+    subnets:
+      - type: ipv6_dhcpv6-stateful
+  - type: physical
+    name: eth2
+    subnets:
+      - type: ipv6_slaac
+"#;
+        let v2 = NetworkConfigV1::from_yaml(yaml).unwrap().to_v2();
+
+        let eth0 = &v2.ethernets["eth0"].common;
+        assert!(eth0.dhcp6_stateless());
+        assert_eq!(eth0.accept_ra, Some(true));
+        let eth1 = &v2.ethernets["eth1"].common;
+        assert_eq!(eth1.dhcp6, Some(true));
+        assert_eq!(eth1.dhcp6_mode, Some(Dhcp6Mode::Stateful));
+        let eth2 = &v2.ethernets["eth2"].common;
+        assert_eq!(eth2.dhcp6, None);
+        assert_eq!(eth2.accept_ra, Some(true));
+    }
+
     #[test]
     fn test_parse_v1_bond() {
         let yaml = r#"
@@ -527,6 +569,8 @@ config:
         assert_eq!(netmask_to_prefix("255.0.0.0"), 8);
         assert_eq!(netmask_to_prefix("255.255.255.128"), 25);
         assert_eq!(netmask_to_prefix("24"), 24);
+        assert_eq!(netmask_to_prefix("ffff:ffff:ffff:ffff::"), 64);
+        assert_eq!(netmask_to_prefix("ffff:ffff:ffff:ff00::"), 56);
     }
 
     #[test]
@@ -553,4 +597,174 @@ ethernets:
         assert!(config1.ethernets.contains_key("eth0"));
         assert!(config2.ethernets.contains_key("eth0"));
     }
+
+    mod properties {
+        use super::*;
+        use crate::network::render::Renderer;
+        use crate::network::render::eni::EniRenderer;
+        use crate::network::render::network_manager::NetworkManagerRenderer;
+        use crate::network::render::networkd::NetworkdRenderer;
+        use proptest::collection::vec;
+        use proptest::option::of;
+        use proptest::prelude::*;
+        use std::path::Path;
+
+        const NAME: &str = "[a-z][a-z0-9.]{0,10}";
+        const ADDRESS: &str = "[0-9a-f.:/]{0,24}";
+
+        /// Netmasks as providers send them, and garbage
+        fn netmask() -> impl Strategy<Value = String> {
+            prop_oneof![
+                any::<[u8; 4]>().prop_map(|[a, b, c, d]| format!("{}.{}.{}.{}", a, b, c, d)),
+                any::<u8>().prop_map(|prefix| prefix.to_string()),
+                "\\PC{0,20}",
+            ]
+        }
+
+        fn route() -> impl Strategy<Value = RouteConfigV1> {
+            (
+                of(ADDRESS),
+                of(ADDRESS),
+                of(any::<u32>()),
+                of(ADDRESS),
+                of(netmask()),
+            )
+                .prop_map(|(destination, gateway, metric, network, netmask)| {
+                    RouteConfigV1 {
+                        destination,
+                        gateway,
+                        metric,
+                        network,
+                        netmask,
+                    }
+                })
+        }
+
+        fn subnet() -> impl Strategy<Value = SubnetConfig> {
+            (
+                prop::sample::select(vec![
+                    "static",
+                    "static6",
+                    "dhcp",
+                    "dhcp6",
+                    "ipv6_slaac",
+                    "ipv6_dhcpv6-stateful",
+                    "ipv6_dhcpv6-stateless",
+                    "manual",
+                ]),
+                of(ADDRESS),
+                of(netmask()),
+                of(ADDRESS),
+                vec(ADDRESS, 0..3),
+                vec(route(), 0..3),
+            )
+                .prop_map(
+                    |(kind, address, netmask, gateway, dns_nameservers, routes)| SubnetConfig {
+                        subnet_type: kind.to_string(),
+                        address,
+                        netmask,
+                        gateway,
+                        dns_nameservers,
+                        routes,
+                        ..Default::default()
+                    },
+                )
+        }
+
+        fn item() -> impl Strategy<Value = ConfigItem> {
+            prop_oneof![
+                (
+                    NAME,
+                    of("[0-9a-f:]{0,17}"),
+                    of(any::<u32>()),
+                    vec(subnet(), 0..3)
+                )
+                    .prop_map(|(name, mac_address, mtu, subnets)| {
+                        ConfigItem::Physical(PhysicalConfig {
+                            name,
+                            mac_address,
+                            mtu,
+                            subnets,
+                            wakeonlan: None,
+                        })
+                    }),
+                (NAME, vec(NAME, 0..3), of("\\PC{0,12}"), vec(subnet(), 0..2)).prop_map(
+                    |(name, bond_interfaces, bond_mode, subnets)| {
+                        ConfigItem::Bond(BondConfigV1 {
+                            name,
+                            bond_interfaces,
+                            bond_mode,
+                            subnets,
+                            ..Default::default()
+                        })
+                    }
+                ),
+                (NAME, vec(NAME, 0..3), vec(subnet(), 0..2)).prop_map(
+                    |(name, bridge_interfaces, subnets)| {
+                        ConfigItem::Bridge(BridgeConfigV1 {
+                            name,
+                            bridge_interfaces,
+                            subnets,
+                            ..Default::default()
+                        })
+                    }
+                ),
+                (NAME, any::<u16>(), NAME, vec(subnet(), 0..2)).prop_map(
+                    |(name, vlan_id, vlan_link, subnets)| {
+                        ConfigItem::Vlan(VlanConfigV1 {
+                            name,
+                            vlan_id,
+                            vlan_link,
+                            mtu: None,
+                            subnets,
+                        })
+                    }
+                ),
+                (vec(ADDRESS, 0..3), vec(NAME, 0..2)).prop_map(|(address, search)| {
+                    ConfigItem::Nameserver(NameserverConfigV1 { address, search })
+                }),
+                route().prop_map(ConfigItem::Route),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn netmask_to_prefix_never_panics(netmask in netmask()) {
+                netmask_to_prefix(&netmask);
+            }
+
+            #[test]
+            fn dotted_netmask_prefix_counts_bits(octets in any::<[u8; 4]>()) {
+                let netmask = format!("{}.{}.{}.{}", octets[0], octets[1], octets[2], octets[3]);
+                prop_assert_eq!(
+                    u32::from(netmask_to_prefix(&netmask)),
+                    u32::from_be_bytes(octets).count_ones()
+                );
+            }
+
+            #[test]
+            fn any_v1_config_converts_and_renders(items in vec(item(), 0..6)) {
+                let v1 = NetworkConfigV1 { version: 1, config: items };
+                let v2 = v1.to_v2();
+                for item in &v1.config {
+                    if let ConfigItem::Physical(phys) = item {
+                        prop_assert!(v2.ethernets.contains_key(&phys.name));
+                    }
+                }
+
+                // Through YAML, as a datasource hands it over
+                let yaml = serde_yaml::to_string(&v1).unwrap();
+                let parsed = parse_network_config(&yaml).unwrap();
+                prop_assert_eq!(parsed.ethernets.len(), v2.ethernets.len());
+
+                for renderer in [
+                    &EniRenderer::new() as &dyn Renderer,
+                    &NetworkdRenderer::new(),
+                    &NetworkManagerRenderer::new(),
+                ] {
+                    let _ = renderer.render(&v2, Path::new("/tmp"));
+                }
+            }
+        }
+    }
 }
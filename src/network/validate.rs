@@ -0,0 +1,485 @@
+//! Network configuration validation
+//!
+//! Catches mistakes in a parsed [`NetworkConfig`] before any renderer
+//! runs, so a typo in user-supplied network config produces an error
+//! naming the interface and field instead of a broken file on disk.
+
+use super::{InterfaceCommon, NetworkConfig};
+use crate::CloudInitError;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+
+/// A single problem found in a network config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    /// Interface the problem belongs to
+    pub interface: String,
+    /// What is wrong and how to fix it
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.interface, self.message)
+    }
+}
+
+/// Validate a network config, failing with every problem found
+pub fn validate(config: &NetworkConfig) -> Result<(), CloudInitError> {
+    let errors = check(config);
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    let details: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    Err(CloudInitError::Network(format!(
+        "Invalid network config: {}",
+        details.join("; ")
+    )))
+}
+
+/// Collect every problem in a network config
+///
+/// Interfaces are checked in name order so the output is stable.
+pub fn check(config: &NetworkConfig) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    let mut report = |interface: &str, message: String| {
+        errors.push(ValidationError {
+            interface: interface.to_string(),
+            message,
+        });
+    };
+
+    let interfaces: BTreeMap<&str, &InterfaceCommon> = config
+        .ethernets
+        .iter()
+        .map(|(n, c)| (n.as_str(), &c.common))
+        .chain(config.bonds.iter().map(|(n, c)| (n.as_str(), &c.common)))
+        .chain(config.bridges.iter().map(|(n, c)| (n.as_str(), &c.common)))
+        .chain(config.vlans.iter().map(|(n, c)| (n.as_str(), &c.common)))
+        .chain(config.wifis.iter().map(|(n, c)| (n.as_str(), &c.common)))
+        .collect();
+
+    // Addresses, gateways, routes, nameservers and MACs per interface
+    let mut seen_addresses: HashMap<IpAddr, &str> = HashMap::new();
+    for (&name, common) in &interfaces {
+        let mut subnets = Vec::new();
+        for addr in &common.addresses {
+            match parse_cidr(addr) {
+                Some((ip, prefix)) => {
+                    subnets.push((ip, prefix));
+                    if let Some(other) = seen_addresses.insert(ip, name) {
+                        report(
+                            name,
+                            format!("address {} is also assigned to {}", ip, other),
+                        );
+                    }
+                }
+                None => report(
+                    name,
+                    format!("address '{}' is not valid CIDR (e.g. 192.0.2.10/24)", addr),
+                ),
+            }
+        }
+
+        for (field, gateway, want_v6) in [
+            ("gateway4", &common.gateway4, false),
+            ("gateway6", &common.gateway6, true),
+        ] {
+            let Some(gateway) = gateway else { continue };
+            match gateway.parse::<IpAddr>() {
+                Ok(ip) if ip.is_ipv6() != want_v6 => report(
+                    name,
+                    format!("{} {} has the wrong address family", field, gateway),
+                ),
+                Ok(ip) => {
+                    let same_family: Vec<_> = subnets
+                        .iter()
+                        .filter(|(addr, _)| addr.is_ipv6() == want_v6)
+                        .collect();
+                    // DHCP-only interfaces and IPv6 link-local gateways
+                    // have no static subnet to check against
+                    if !same_family.is_empty()
+                        && !is_link_local(&ip)
+                        && !same_family.iter().any(|(net, p)| in_subnet(&ip, net, *p))
+                    {
+                        report(
+                            name,
+                            format!(
+                                "{} {} is outside every configured subnet; use a route with on-link: true",
+                                field, gateway
+                            ),
+                        );
+                    }
+                }
+                Err(_) => report(
+                    name,
+                    format!("{} '{}' is not an IP address", field, gateway),
+                ),
+            }
+        }
+
+        for route in &common.routes {
+            if route.to != "default"
+                && parse_cidr(&route.to).is_none()
+                && route.to.parse::<IpAddr>().is_err()
+            {
+                report(
+                    name,
+                    format!("route destination '{}' is not valid CIDR", route.to),
+                );
+            }
+            if let Some(via) = &route.via
+                && via.parse::<IpAddr>().is_err()
+            {
+                report(
+                    name,
+                    format!("route gateway '{}' is not an IP address", via),
+                );
+            }
+        }
+
+        for server in &common.nameservers.addresses {
+            if server.parse::<IpAddr>().is_err() {
+                report(
+                    name,
+                    format!("nameserver '{}' is not an IP address", server),
+                );
+            }
+        }
+
+        if let Some(mac) = &common.macaddress
+            && !is_valid_mac(mac)
+        {
+            report(name, format!("macaddress '{}' is not a valid MAC", mac));
+        }
+    }
+
+    // match.macaddress on ethernets
+    let ethernets: BTreeMap<_, _> = config.ethernets.iter().collect();
+    for (name, eth) in ethernets {
+        if let Some(mac) = eth
+            .match_config
+            .as_ref()
+            .and_then(|m| m.macaddress.as_ref())
+            && !is_valid_mac(mac)
+        {
+            report(
+                name,
+                format!("match.macaddress '{}' is not a valid MAC", mac),
+            );
+        }
+    }
+
+    // Wifi match MACs, access point bands and WPA key lengths
+    let wifis: BTreeMap<_, _> = config.wifis.iter().collect();
+    for (name, wifi) in wifis {
+        if let Some(mac) = wifi
+            .match_config
+            .as_ref()
+            .and_then(|m| m.macaddress.as_ref())
+            && !is_valid_mac(mac)
+        {
+            report(
+                name,
+                format!("match.macaddress '{}' is not a valid MAC", mac),
+            );
+        }
+        let access_points: BTreeMap<_, _> = wifi.access_points.iter().collect();
+        for (ssid, ap) in access_points {
+            if let Some(band) = &ap.band
+                && !matches!(band.as_str(), "2.4GHz" | "5GHz")
+            {
+                report(
+                    name,
+                    format!(
+                        "access point {} band '{}' must be 2.4GHz or 5GHz",
+                        ssid, band
+                    ),
+                );
+            }
+            // WPA passphrases are 8-63 characters (64 means a raw hex key)
+            if let Some(password) = &ap.password
+                && !(8..=64).contains(&password.len())
+            {
+                report(
+                    name,
+                    format!("access point {} password must be 8-63 characters", ssid),
+                );
+            }
+        }
+    }
+
+    // Bond and bridge members must be defined interfaces
+    let bonds: BTreeMap<_, _> = config.bonds.iter().collect();
+    for (name, bond) in bonds {
+        for member in &bond.interfaces {
+            if !config.ethernets.contains_key(member) {
+                report(
+                    name,
+                    format!("bond member {} is not defined under ethernets", member),
+                );
+            }
+        }
+    }
+    let bridges: BTreeMap<_, _> = config.bridges.iter().collect();
+    for (name, bridge) in bridges {
+        for member in &bridge.interfaces {
+            if !interfaces.contains_key(member.as_str()) {
+                report(
+                    name,
+                    format!("bridge member {} is not a defined interface", member),
+                );
+            }
+        }
+    }
+
+    // VLAN ids and parent links
+    let vlans: BTreeMap<_, _> = config.vlans.iter().collect();
+    for (name, vlan) in vlans {
+        if !(1..=4094).contains(&vlan.id) {
+            report(
+                name,
+                format!("VLAN id {} is outside the valid range 1-4094", vlan.id),
+            );
+        }
+        if !interfaces.contains_key(vlan.link.as_str()) {
+            report(
+                name,
+                format!("VLAN link {} is not a defined interface", vlan.link),
+            );
+        }
+    }
+
+    errors
+}
+
+/// Parse `address/prefix`, rejecting prefixes too long for the family
+fn parse_cidr(cidr: &str) -> Option<(IpAddr, u8)> {
+    let (addr, prefix) = cidr.split_once('/')?;
+    let ip: IpAddr = addr.parse().ok()?;
+    let prefix: u8 = prefix.parse().ok()?;
+    let max = if ip.is_ipv6() { 128 } else { 32 };
+    (prefix <= max).then_some((ip, prefix))
+}
+
+/// Whether `ip` falls inside `network/prefix`
+fn in_subnet(ip: &IpAddr, network: &IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(*ip) & mask == u32::from(*net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(*ip) & mask == u128::from(*net) & mask
+        }
+        _ => false,
+    }
+}
+
+/// IPv6 link-local gateways (fe80::/10) are reachable from any subnet
+fn is_link_local(ip: &IpAddr) -> bool {
+    matches!(ip, IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80)
+}
+
+/// Six colon-separated hex octets
+fn is_valid_mac(mac: &str) -> bool {
+    let octets: Vec<&str> = mac.split(':').collect();
+    octets.len() == 6
+        && octets
+            .iter()
+            .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(yaml: &str) -> Vec<String> {
+        let config = NetworkConfig::from_yaml(yaml).unwrap();
+        check(&config).iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn test_valid_config_passes() {
+        let yaml = r#"
+version: 2
+ethernets:
+  eth0:
+    match:
+      macaddress: "52:54:00:12:34:56"
+    addresses: [192.0.2.10/24, "2001:db8::10/64"]
+    gateway4: 192.0.2.1
+    gateway6: "fe80::1"
+    nameservers:
+      addresses: [192.0.2.53]
+    routes:
+      - to: default
+        via: 192.0.2.1
+  eth1: {}
+bonds:
+  bond0:
+    interfaces: [eth1]
+    dhcp4: true
+vlans:
+  vlan10:
+    id: 10
+    link: bond0
+"#;
+        let config = NetworkConfig::from_yaml(yaml).unwrap();
+        assert!(check(&config).is_empty());
+        assert!(validate(&config).is_ok());
+    }
+
+    #[test]
+    fn test_bad_cidr_and_gateway_outside_subnet() {
+        let errs = errors(
+            r#"
+version: 2
+ethernets:
+  eth0:
+    addresses: [192.0.2.10, 10.0.0.1/33, 192.0.2.11/24]
+    gateway4: 198.51.100.1
+"#,
+        );
+        assert_eq!(errs.len(), 3, "{:?}", errs);
+        assert!(errs[0].contains("'192.0.2.10' is not valid CIDR"));
+        assert!(errs[1].contains("'10.0.0.1/33'"));
+        assert!(errs[2].contains("gateway4 198.51.100.1 is outside every configured subnet"));
+    }
+
+    #[test]
+    fn test_gateway_wrong_family() {
+        let errs = errors(
+            r#"
+version: 2
+ethernets:
+  eth0:
+    dhcp4: true
+    gateway4: "2001:db8::1"
+"#,
+        );
+        assert_eq!(
+            errs,
+            vec!["eth0: gateway4 2001:db8::1 has the wrong address family"]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_address_across_interfaces() {
+        let errs = errors(
+            r#"
+version: 2
+ethernets:
+  eth0:
+    addresses: [192.0.2.10/24]
+  eth1:
+    addresses: [192.0.2.10/25]
+"#,
+        );
+        assert_eq!(
+            errs,
+            vec!["eth1: address 192.0.2.10 is also assigned to eth0"]
+        );
+    }
+
+    #[test]
+    fn test_vlan_and_member_references() {
+        let errs = errors(
+            r#"
+version: 2
+ethernets:
+  eth0: {}
+bonds:
+  bond0:
+    interfaces: [eth0, eth9]
+bridges:
+  br0:
+    interfaces: [bond0, tap0]
+vlans:
+  vlan0:
+    id: 0
+    link: eth0
+  vlan5000:
+    id: 4095
+    link: missing
+"#,
+        );
+        assert_eq!(
+            errs,
+            vec![
+                "bond0: bond member eth9 is not defined under ethernets",
+                "br0: bridge member tap0 is not a defined interface",
+                "vlan0: VLAN id 0 is outside the valid range 1-4094",
+                "vlan5000: VLAN id 4095 is outside the valid range 1-4094",
+                "vlan5000: VLAN link missing is not a defined interface",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mac_format() {
+        let errs = errors(
+            r#"
+version: 2
+ethernets:
+  eth0:
+    match:
+      macaddress: "52:54:00:12:34"
+    macaddress: "zz:54:00:12:34:56"
+    dhcp4: true
+"#,
+        );
+        assert_eq!(errs.len(), 2);
+        assert!(errs[0].contains("macaddress 'zz:54:00:12:34:56'"));
+        assert!(errs[1].contains("match.macaddress '52:54:00:12:34'"));
+    }
+
+    #[test]
+    fn test_wifi_access_points() {
+        let errs = errors(
+            r#"
+version: 2
+wifis:
+  wlan0:
+    dhcp4: true
+    access-points:
+      short:
+        password: abc
+      odd-band:
+        band: 6GHz
+      fine:
+        password: long-enough
+"#,
+        );
+        assert_eq!(
+            errs,
+            vec![
+                "wlan0: access point odd-band band '6GHz' must be 2.4GHz or 5GHz",
+                "wlan0: access point short password must be 8-63 characters",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_error_lists_all_problems() {
+        let config = NetworkConfig::from_yaml(
+            "version: 2\nethernets:\n  eth0:\n    addresses: [bogus]\n    nameservers:\n      addresses: [dns.example]\n",
+        )
+        .unwrap();
+        let err = validate(&config).unwrap_err().to_string();
+        assert!(err.contains("Invalid network config"));
+        assert!(err.contains("'bogus'"));
+        assert!(err.contains("'dns.example'"));
+    }
+
+    #[test]
+    fn test_in_subnet() {
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        assert!(in_subnet(&ip, &"10.0.0.0".parse().unwrap(), 8));
+        assert!(!in_subnet(&ip, &"10.0.0.0".parse().unwrap(), 16));
+        assert!(in_subnet(&ip, &"192.168.0.1".parse().unwrap(), 0));
+    }
+}
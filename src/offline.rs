@@ -0,0 +1,45 @@
+//! Offline mode
+//!
+//! For building and testing images in sandboxes without network access.
+//! Only the NoCloud datasource is considered, and modules that need the
+//! network are skipped, reported as `skipped: offline`, instead of
+//! running into timeouts. Enabled with `--offline`, or in cloud.cfg with:
+//!
+//! ```yaml
+//! network: disabled
+//! ```
+//!
+//! Unlike `network: {config: disabled}`, which only leaves the network
+//! configuration alone, this assumes there is no network at all.
+
+/// Modules that need the network, by event name
+pub const NETWORK_MODULES: &[&str] = &[
+    "ephemeral_network",
+    "package_update_upgrade_install",
+    "phone_home",
+    "gce_guest_attributes",
+    "ansible",
+    "chef",
+    "puppet",
+    "salt_minion",
+    "rh_subscription",
+    "suse_register",
+    "ubuntu_pro",
+    "landscape",
+    "journald_upload",
+    "wireguard",
+    "grub_dpkg",
+    "byobu_by_default",
+    "fan",
+];
+
+/// Whether the enclosing [`CloudInit::scope`](crate::CloudInit::scope) is
+/// offline
+pub fn is_offline() -> bool {
+    crate::embed::Scope::with(|scope| scope.offline).unwrap_or(false)
+}
+
+/// Whether `module` needs the network
+pub fn needs_network(module: &str) -> bool {
+    NETWORK_MODULES.contains(&module)
+}
@@ -0,0 +1,338 @@
+//! Platform detection from DMI/SMBIOS
+//!
+//! Every datasource used to read `/sys/class/dmi/id` on its own and match
+//! vendor strings slightly differently. This module reads the identifying
+//! DMI fields once and maps them to a typed [`Platform`], which each
+//! datasource's `is_available` consults before probing metadata services.
+//! All readers take the sysfs root as a parameter so detection can be
+//! tested against a fake tree. FreeBSD has no sysfs; with the `freebsd`
+//! feature the same fields come from the kernel environment (`kenv`).
+
+use std::fmt;
+use std::path::Path;
+use tokio::fs;
+
+/// Root of sysfs on a live system
+pub const SYSFS_ROOT: &str = "/sys";
+
+/// Azure's fixed chassis asset tag
+pub const AZURE_ASSET_TAG: &str = "7783-7084-3265-9085-8269-3286-77";
+
+/// Chassis asset tags used by OpenStack-based public clouds
+const OPENSTACK_ASSET_TAGS: &[&str] = &["OpenTelekomCloud", "SAP CCloud VM", "HUAWEICLOUD"];
+
+/// Identifying fields read from `/sys/class/dmi/id`
+///
+/// Fields that are missing, unreadable (some need root) or empty are
+/// `None`. Values are trimmed but otherwise kept as the firmware reports
+/// them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DmiInfo {
+    pub sys_vendor: Option<String>,
+    pub product_name: Option<String>,
+    pub product_uuid: Option<String>,
+    pub product_serial: Option<String>,
+    pub bios_vendor: Option<String>,
+    pub chassis_vendor: Option<String>,
+    pub chassis_asset_tag: Option<String>,
+    /// Xen's `/sys/hypervisor/uuid`, which older EC2 instances expose
+    /// instead of DMI vendor strings
+    pub hypervisor_uuid: Option<String>,
+}
+
+impl DmiInfo {
+    /// Read DMI data of the running system
+    pub async fn read() -> Self {
+        let dmi = Self::read_from(Path::new(SYSFS_ROOT)).await;
+        #[cfg(feature = "freebsd")]
+        if dmi == Self::default()
+            && let Ok(output) = tokio::process::Command::new("kenv").output().await
+            && output.status.success()
+        {
+            return Self::from_kenv(&String::from_utf8_lossy(&output.stdout));
+        }
+        dmi
+    }
+
+    /// Parse the `smbios.*` variables of FreeBSD's `kenv` output
+    #[cfg(feature = "freebsd")]
+    pub fn from_kenv(output: &str) -> Self {
+        let mut dmi = Self::default();
+        for line in output.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value =
+                Some(value.trim().trim_matches('"').trim().to_string()).filter(|v| !v.is_empty());
+            let field = match key {
+                "smbios.system.maker" => &mut dmi.sys_vendor,
+                "smbios.system.product" => &mut dmi.product_name,
+                "smbios.system.uuid" => &mut dmi.product_uuid,
+                "smbios.system.serial" => &mut dmi.product_serial,
+                "smbios.bios.vendor" => &mut dmi.bios_vendor,
+                "smbios.chassis.maker" => &mut dmi.chassis_vendor,
+                "smbios.chassis.tag" => &mut dmi.chassis_asset_tag,
+                _ => continue,
+            };
+            *field = value;
+        }
+        dmi
+    }
+
+    /// Read DMI data from a sysfs tree rooted at `sysfs`
+    pub async fn read_from(sysfs: &Path) -> Self {
+        let dmi = sysfs.join("class/dmi/id");
+        Self {
+            sys_vendor: read_field(&dmi.join("sys_vendor")).await,
+            product_name: read_field(&dmi.join("product_name")).await,
+            product_uuid: read_field(&dmi.join("product_uuid")).await,
+            product_serial: read_field(&dmi.join("product_serial")).await,
+            bios_vendor: read_field(&dmi.join("bios_vendor")).await,
+            chassis_vendor: read_field(&dmi.join("chassis_vendor")).await,
+            chassis_asset_tag: read_field(&dmi.join("chassis_asset_tag")).await,
+            hypervisor_uuid: read_field(&sysfs.join("hypervisor/uuid")).await,
+        }
+    }
+
+    /// Whether `id` is the system UUID, ignoring case
+    ///
+    /// `None` if the firmware reports no UUID.
+    pub fn uuid_matches(&self, id: &str) -> Option<bool> {
+        let uuid = self.product_uuid.as_deref()?;
+        Some(uuid.eq_ignore_ascii_case(id))
+    }
+
+    /// Whether any vendor/product field contains `needle` (case-insensitive)
+    fn vendor_contains(&self, needle: &str) -> bool {
+        [
+            &self.sys_vendor,
+            &self.product_name,
+            &self.bios_vendor,
+            &self.chassis_vendor,
+        ]
+        .into_iter()
+        .flatten()
+        .any(|v| v.to_lowercase().contains(needle))
+    }
+}
+
+async fn read_field(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .await
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+/// Cloud or hypervisor identified from DMI data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Platform {
+    Ec2,
+    Azure,
+    Gce,
+    OpenStack,
+    VMware,
+    /// Generic QEMU/KVM guest, which may still be an OpenStack instance
+    Kvm,
+    Unknown,
+}
+
+impl Platform {
+    /// Detect the platform of the running system
+    pub async fn detect() -> Self {
+        Self::from_dmi(&DmiInfo::read().await)
+    }
+
+    /// Classify already-read DMI data
+    ///
+    /// Specific clouds are checked before generic hypervisors, since
+    /// e.g. EC2 Nitro and GCE instances are KVM guests too.
+    pub fn from_dmi(dmi: &DmiInfo) -> Self {
+        let asset_tag = dmi.chassis_asset_tag.as_deref().unwrap_or_default();
+
+        if asset_tag.eq_ignore_ascii_case(AZURE_ASSET_TAG)
+            || dmi.vendor_contains("microsoft")
+            || dmi.vendor_contains("azure")
+        {
+            Platform::Azure
+        } else if dmi.vendor_contains("google") {
+            Platform::Gce
+        } else if dmi.vendor_contains("amazon")
+            || dmi.vendor_contains("ec2")
+            || dmi
+                .hypervisor_uuid
+                .as_deref()
+                .is_some_and(|u| u.to_lowercase().starts_with("ec2"))
+        {
+            Platform::Ec2
+        } else if dmi.vendor_contains("openstack") || OPENSTACK_ASSET_TAGS.contains(&asset_tag) {
+            Platform::OpenStack
+        } else if dmi.vendor_contains("vmware") {
+            Platform::VMware
+        } else if ["qemu", "kvm", "bochs", "rhev"]
+            .iter()
+            .any(|n| dmi.vendor_contains(n))
+        {
+            Platform::Kvm
+        } else {
+            Platform::Unknown
+        }
+    }
+
+    /// Short lowercase name, as used in `cloud-id` style output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Platform::Ec2 => "aws",
+            Platform::Azure => "azure",
+            Platform::Gce => "gce",
+            Platform::OpenStack => "openstack",
+            Platform::VMware => "vmware",
+            Platform::Kvm => "kvm",
+            Platform::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn fake_sysfs(fields: &[(&str, &str)]) -> TempDir {
+        let temp = TempDir::new().unwrap();
+        let dmi = temp.path().join("class/dmi/id");
+        std::fs::create_dir_all(&dmi).unwrap();
+        for (name, value) in fields {
+            let path = if *name == "hypervisor_uuid" {
+                std::fs::create_dir_all(temp.path().join("hypervisor")).unwrap();
+                temp.path().join("hypervisor/uuid")
+            } else {
+                dmi.join(name)
+            };
+            std::fs::write(path, format!("{}\n", value)).unwrap();
+        }
+        temp
+    }
+
+    async fn detect(fields: &[(&str, &str)]) -> Platform {
+        let temp = fake_sysfs(fields);
+        Platform::from_dmi(&DmiInfo::read_from(temp.path()).await)
+    }
+
+    #[tokio::test]
+    async fn test_read_dmi_fields() {
+        let temp = fake_sysfs(&[
+            ("sys_vendor", "QEMU"),
+            ("product_uuid", "6C3B1D5E-0000-4000-8000-000000000001"),
+            ("product_serial", "ds=nocloud;s=http://10.0.0.1/"),
+            ("chassis_asset_tag", ""),
+        ]);
+        let dmi = DmiInfo::read_from(temp.path()).await;
+        assert_eq!(dmi.sys_vendor.as_deref(), Some("QEMU"));
+        assert_eq!(
+            dmi.product_uuid.as_deref(),
+            Some("6C3B1D5E-0000-4000-8000-000000000001")
+        );
+        assert_eq!(
+            dmi.product_serial.as_deref(),
+            Some("ds=nocloud;s=http://10.0.0.1/")
+        );
+        assert_eq!(dmi.chassis_asset_tag, None);
+        assert_eq!(dmi.product_name, None);
+        assert_eq!(
+            dmi.uuid_matches("6c3b1d5e-0000-4000-8000-000000000001"),
+            Some(true)
+        );
+        assert_eq!(dmi.uuid_matches("i-0123"), Some(false));
+        assert_eq!(DmiInfo::default().uuid_matches("i-0123"), None);
+    }
+
+    #[tokio::test]
+    async fn test_detect_clouds() {
+        assert_eq!(
+            detect(&[("sys_vendor", "Amazon EC2"), ("product_name", "m5.large")]).await,
+            Platform::Ec2
+        );
+        assert_eq!(
+            detect(&[("hypervisor_uuid", "ec2e1916-9099-7caf-fd21-012345abcdef")]).await,
+            Platform::Ec2
+        );
+        assert_eq!(
+            detect(&[
+                ("sys_vendor", "Microsoft Corporation"),
+                ("chassis_asset_tag", AZURE_ASSET_TAG),
+            ])
+            .await,
+            Platform::Azure
+        );
+        assert_eq!(
+            detect(&[
+                ("sys_vendor", "Google"),
+                ("product_name", "Google Compute Engine"),
+            ])
+            .await,
+            Platform::Gce
+        );
+        assert_eq!(
+            detect(&[
+                ("sys_vendor", "OpenStack Foundation"),
+                ("product_name", "OpenStack Nova")
+            ])
+            .await,
+            Platform::OpenStack
+        );
+        assert_eq!(
+            detect(&[("sys_vendor", "QEMU"), ("chassis_asset_tag", "HUAWEICLOUD")]).await,
+            Platform::OpenStack
+        );
+        assert_eq!(
+            detect(&[("sys_vendor", "VMware, Inc.")]).await,
+            Platform::VMware
+        );
+    }
+
+    #[tokio::test]
+    async fn test_detect_generic_and_unknown() {
+        assert_eq!(
+            detect(&[
+                ("sys_vendor", "QEMU"),
+                ("product_name", "Standard PC (Q35 + ICH9, 2009)")
+            ])
+            .await,
+            Platform::Kvm
+        );
+        assert_eq!(
+            detect(&[("sys_vendor", "Dell Inc.")]).await,
+            Platform::Unknown
+        );
+        // A non-EC2 Xen guest
+        assert_eq!(
+            detect(&[("hypervisor_uuid", "4a5b6c7d-0000-0000-0000-000000000000")]).await,
+            Platform::Unknown
+        );
+        assert_eq!(detect(&[]).await, Platform::Unknown);
+    }
+
+    #[cfg(feature = "freebsd")]
+    #[test]
+    fn test_from_kenv() {
+        let dmi = DmiInfo::from_kenv(
+            "kern.vm_guest=\"kvm\"\n\
+             smbios.bios.vendor=\"Amazon EC2\"\n\
+             smbios.chassis.tag=\"\"\n\
+             smbios.system.maker=\"Amazon EC2\"\n\
+             smbios.system.product=\"t3.micro\"\n\
+             smbios.system.uuid=\"ec2e1916-9099-7caf-fd21-012345abcdef\"\n",
+        );
+        assert_eq!(dmi.sys_vendor.as_deref(), Some("Amazon EC2"));
+        assert_eq!(dmi.product_name.as_deref(), Some("t3.micro"));
+        assert_eq!(dmi.chassis_asset_tag, None);
+        assert_eq!(Platform::from_dmi(&dmi), Platform::Ec2);
+    }
+}
@@ -0,0 +1,305 @@
+//! Instance data queries
+//!
+//! `cloud-init-rs query <key>` prints one value of the instance data, the
+//! document templates render against: `v1` with the standardized keys
+//! every cloud has (`instance_id`, `region`, `cloud_name`, ...), `ds`
+//! with the datasource's own metadata, and the system facts. Keys are
+//! paths separated by `.` or `/`, e.g. `v1.region` or `ds/meta_data`;
+//! keys not found at the top level are looked up in `v1`, so `region`
+//! works too. Secrets are masked as in logs, see [`crate::redact`].
+//!
+//! `cloud-init-rs serve-metadata` serves the same document, read-only,
+//! to agents and scripts that would rather not shell out:
+//!
+//! ```text
+//! curl --unix-socket /run/cloud-init/metadata.sock http://localhost/v1/region
+//! ```
+//!
+//! `GET /` returns the whole document and `GET /<path>` one value; strings
+//! come back as plain text and everything else as JSON. With `--listen`
+//! the server also answers on a loopback TCP address.
+
+use crate::config::CloudConfig;
+use crate::datasources::cache;
+use crate::root::RootContext;
+use crate::template::{SystemFacts, build_system_context};
+use crate::{CloudInitError, IoContext};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UnixListener};
+use tracing::{debug, info, warn};
+
+/// Longest request line or header accepted
+const MAX_LINE: usize = 8192;
+
+/// Most headers read from one request
+const MAX_HEADERS: usize = 100;
+
+/// The instance data of this instance
+pub async fn instance_data() -> Result<Value, CloudInitError> {
+    let ds = cache::current_datasource().await?;
+    let metadata = ds.get_metadata().await?;
+    let facts = SystemFacts::detect(&RootContext::current()).await;
+    let mut context = build_system_context(&metadata, &facts, &CloudConfig::default());
+    // System config is not instance data
+    context.remove("conf");
+
+    let mut data = serde_json::to_value(context)?;
+    crate::redact::current().redact_json(&mut data);
+    Ok(data)
+}
+
+/// The value at `key` in `data`, falling back to `v1`
+pub fn lookup<'a>(data: &'a Value, key: &str) -> Option<&'a Value> {
+    let find = |root: &'a Value| {
+        key.split(['.', '/'])
+            .filter(|segment| !segment.is_empty())
+            .try_fold(root, |value, segment| match value {
+                Value::Object(map) => map.get(segment),
+                Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+                _ => None,
+            })
+    };
+    find(data).or_else(|| find(data.get("v1")?))
+}
+
+/// A value as printed by `query` and served over HTTP: strings as they
+/// are, anything else as JSON
+pub fn render(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => serde_json::to_string_pretty(other).unwrap_or_default(),
+    }
+}
+
+/// An HTTP response
+#[derive(Debug, PartialEq, Eq)]
+struct Response {
+    status: u16,
+    reason: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn error(status: u16, reason: &'static str) -> Self {
+        Self {
+            status,
+            reason,
+            content_type: "text/plain",
+            body: format!("{}\n", reason),
+        }
+    }
+}
+
+/// Answer `method` on `target` from `data`
+fn respond(method: &str, target: &str, data: &Value) -> Response {
+    if method != "GET" && method != "HEAD" {
+        return Response::error(405, "Method Not Allowed");
+    }
+    let path = target.split(['?', '#']).next().unwrap_or_default();
+    let Some(value) = lookup(data, path) else {
+        return Response::error(404, "Not Found");
+    };
+    let content_type = match value {
+        Value::String(_) => "text/plain",
+        _ => "application/json",
+    };
+    Response {
+        status: 200,
+        reason: "OK",
+        content_type,
+        body: render(value),
+    }
+}
+
+/// Read one request from `stream` and answer it
+async fn handle<S>(stream: S, data: &Value) -> Result<(), CloudInitError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    (&mut stream)
+        .take(MAX_LINE as u64)
+        .read_line(&mut request_line)
+        .await?;
+    // Headers carry nothing of use, but must be read before answering
+    for _ in 0..MAX_HEADERS {
+        let mut header = String::new();
+        let read = (&mut stream)
+            .take(MAX_LINE as u64)
+            .read_line(&mut header)
+            .await?;
+        if read == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => {
+            debug!("{} {}", method, target);
+            let response = respond(method, target, data);
+            match method {
+                "HEAD" => Response {
+                    body: String::new(),
+                    ..response
+                },
+                _ => response,
+            }
+        }
+        _ => Response::error(400, "Bad Request"),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason,
+        response.content_type,
+        response.body.len()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Bind `path` for anyone to connect to, replacing a stale socket
+async fn bind_socket(path: &Path) -> Result<UnixListener, CloudInitError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await.with_path(parent)?;
+    }
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e).with_path(path),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path).with_path(path)?;
+    // Read-only and without secrets, like instance-data.json
+    fs::set_permissions(path, std::fs::Permissions::from_mode(0o666))
+        .await
+        .with_path(path)?;
+    Ok(listener)
+}
+
+/// Serve `data` on the unix socket at `socket`, and on `listen` if given,
+/// until the process is stopped
+///
+/// `listen` must be a loopback address; the data is for this instance
+/// only.
+pub async fn serve(
+    data: Value,
+    socket: &Path,
+    listen: Option<SocketAddr>,
+) -> Result<(), CloudInitError> {
+    if let Some(addr) = listen
+        && !addr.ip().is_loopback()
+    {
+        return Err(CloudInitError::Config(format!(
+            "{} is not a loopback address",
+            addr
+        )));
+    }
+    let unix = bind_socket(socket).await?;
+    info!("Serving instance data on {}", socket.display());
+    let tcp = match listen {
+        Some(addr) => {
+            info!("Serving instance data on http://{}", addr);
+            Some(TcpListener::bind(addr).await?)
+        }
+        None => None,
+    };
+
+    let data = Arc::new(data);
+    loop {
+        let data = Arc::clone(&data);
+        tokio::select! {
+            accepted = unix.accept() => {
+                let (stream, _) = accepted?;
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, &data).await {
+                        warn!("Metadata request failed: {}", e);
+                    }
+                });
+            }
+            accepted = async { tcp.as_ref()?.accept().await.ok() }, if tcp.is_some() => {
+                let Some((stream, _)) = accepted else { continue };
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, &data).await {
+                        warn!("Metadata request failed: {}", e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn data() -> Value {
+        json!({
+            "v1": {"instance_id": "i-1", "region": "eu-west-1", "public_ssh_keys": ["ssh-ed25519 AAA"]},
+            "ds": {"meta_data": {"tags": ["web"]}},
+            "instance_id": "i-1",
+        })
+    }
+
+    #[test]
+    fn test_lookup() {
+        let data = data();
+        assert_eq!(lookup(&data, "v1.region"), Some(&json!("eu-west-1")));
+        assert_eq!(lookup(&data, "/v1/region"), Some(&json!("eu-west-1")));
+        assert_eq!(lookup(&data, "region"), Some(&json!("eu-west-1")));
+        assert_eq!(lookup(&data, "ds/meta_data/tags/0"), Some(&json!("web")));
+        assert_eq!(lookup(&data, "/"), Some(&data));
+        assert_eq!(lookup(&data, "v1.zone"), None);
+    }
+
+    #[test]
+    fn test_respond() {
+        let data = data();
+        let response = respond("GET", "/v1/instance_id", &data);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.content_type, "text/plain");
+        assert_eq!(response.body, "i-1");
+
+        let response = respond("GET", "/v1/public_ssh_keys?x=1", &data);
+        assert_eq!(response.content_type, "application/json");
+        assert_eq!(
+            serde_json::from_str::<Value>(&response.body).unwrap(),
+            json!(["ssh-ed25519 AAA"])
+        );
+
+        assert_eq!(respond("GET", "/nope", &data).status, 404);
+        assert_eq!(respond("POST", "/", &data).status, 405);
+    }
+
+    #[tokio::test]
+    async fn test_handle() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let data = data();
+        let serving = handle(server, &data);
+        let asking = async {
+            client
+                .write_all(b"GET /v1/region HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let mut answer = String::new();
+            client.read_to_string(&mut answer).await.unwrap();
+            answer
+        };
+        let (served, answer) = tokio::join!(serving, asking);
+        served.unwrap();
+        assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(answer.contains("Content-Length: 9\r\n"));
+        assert!(answer.ends_with("\r\n\r\neu-west-1"));
+    }
+}
@@ -0,0 +1,105 @@
+//! Built-in reporting handlers
+
+use super::{Event, EventType, ReportingHandler};
+use crate::CloudInitError;
+use async_trait::async_trait;
+use std::path::PathBuf;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+/// Emits events through `tracing` (and therefore the journal/console)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogHandler;
+
+#[async_trait]
+impl ReportingHandler for LogHandler {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    async fn publish(&self, event: &Event) -> Result<(), CloudInitError> {
+        match event.event_type {
+            EventType::Start => info!("start: {}: {}", event.name, event.description),
+            EventType::Finish => info!(
+                "finish: {}: {:?}: {}",
+                event.name, event.result, event.description
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// Appends events as JSON lines to a file
+#[derive(Debug, Clone)]
+pub struct FileHandler {
+    path: PathBuf,
+}
+
+impl FileHandler {
+    /// Create a handler appending to `path`
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl ReportingHandler for FileHandler {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    async fn publish(&self, event: &Event) -> Result<(), CloudInitError> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        // tokio completes writes in the background; flush so the event is
+        // on disk before the next one (or process exit)
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reporting::EventResult;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_file_handler_appends_json_lines() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("log/events.json");
+        let handler = FileHandler::new(&path);
+
+        handler
+            .publish(&Event::start("init-local", "go"))
+            .await
+            .unwrap();
+        handler
+            .publish(&Event::finish("init-local", "ok", EventResult::Success))
+            .await
+            .unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let events = crate::analyze::parse_events(&content);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].result, Some(EventResult::Success));
+    }
+
+    #[tokio::test]
+    async fn test_log_handler_never_fails() {
+        let event = Event::start("init-local", "go");
+        assert!(LogHandler.publish(&event).await.is_ok());
+    }
+}
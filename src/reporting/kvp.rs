@@ -0,0 +1,412 @@
+//! Hyper-V KVP reporting handler for Azure
+//!
+//! Azure hosts read provisioning telemetry from the Hyper-V key-value pair
+//! exchange. Guests append fixed-size records to `/var/lib/hyperv/.kvp_pool_1`,
+//! which the `hv_kvp_daemon` forwards to the host. Each record is a
+//! 512-byte NUL-padded key followed by a 2048-byte NUL-padded value.
+//!
+//! Event keys follow Python cloud-init so existing Azure tooling can read
+//! them: `CLOUD_INIT|<incarnation>|<event_type>|<name>|<uuid>`, where the
+//! incarnation is the kernel boot time. Values are small JSON documents.
+//!
+//! Besides per-event records, [`report_success_to_host`] and
+//! [`report_failure_to_host`] write the `PROVISIONING_REPORT` key the
+//! Azure platform uses to decide whether provisioning succeeded.
+
+use super::{Event, EventType, ReportingHandler};
+use crate::CloudInitError;
+use async_trait::async_trait;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+/// Guest-to-host KVP pool
+pub const KVP_POOL_FILE: &str = "/var/lib/hyperv/.kvp_pool_1";
+
+/// Size of the key field of a KVP record
+pub const KVP_KEY_SIZE: usize = 512;
+
+/// Size of the value field of a KVP record
+pub const KVP_VALUE_SIZE: usize = 2048;
+
+/// Size of a whole KVP record
+pub const KVP_RECORD_SIZE: usize = KVP_KEY_SIZE + KVP_VALUE_SIZE;
+
+/// Largest value Azure will accept for an event record
+const AZURE_MAX_VALUE_SIZE: usize = 1024;
+
+/// Key prefix for event records
+const EVENT_PREFIX: &str = "CLOUD_INIT";
+
+/// Key read by the Azure platform for the provisioning outcome
+pub const PROVISIONING_REPORT_KEY: &str = "PROVISIONING_REPORT";
+
+/// Writes events into the Hyper-V KVP pool
+#[derive(Debug)]
+pub struct KvpHandler {
+    path: PathBuf,
+    incarnation: u64,
+    truncated: AtomicBool,
+    lock: Mutex<()>,
+}
+
+impl KvpHandler {
+    /// Create a handler for `path`, using the kernel boot time as incarnation
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let incarnation = std::fs::read_to_string("/proc/stat")
+            .ok()
+            .and_then(|stat| crate::analyze::parse_btime(&stat))
+            .map_or(0, |btime| btime as u64);
+        Self::with_incarnation(path, incarnation)
+    }
+
+    /// Create a handler with an explicit incarnation number
+    pub fn with_incarnation(path: impl Into<PathBuf>, incarnation: u64) -> Self {
+        Self {
+            path: path.into(),
+            incarnation,
+            truncated: AtomicBool::new(false),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Key for an event record
+    fn event_key(&self, event: &Event) -> String {
+        let event_type = match event.event_type {
+            EventType::Start => "start",
+            EventType::Finish => "finish",
+        };
+        format!(
+            "{}|{}|{}|{}|{}",
+            EVENT_PREFIX,
+            self.incarnation,
+            event_type,
+            event.name,
+            uuid::Uuid::new_v4()
+        )
+    }
+
+    /// Drop records left over from a previous boot
+    ///
+    /// Done once per handler. The pool is only truncated if it was last
+    /// modified before this boot's incarnation.
+    async fn truncate_stale_pool(&self) -> Result<(), CloudInitError> {
+        if self.truncated.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let Ok(meta) = fs::metadata(&self.path).await else {
+            return Ok(());
+        };
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+
+        if modified < self.incarnation {
+            debug!("Truncating stale KVP pool {}", self.path.display());
+            fs::write(&self.path, b"").await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ReportingHandler for KvpHandler {
+    fn name(&self) -> &'static str {
+        "hyperv"
+    }
+
+    async fn publish(&self, event: &Event) -> Result<(), CloudInitError> {
+        let _guard = self.lock.lock().await;
+        self.truncate_stale_pool().await?;
+
+        let record = encode_record(&self.event_key(event), &event_value(event))?;
+        append_records(&self.path, &record).await
+    }
+
+    async fn provisioning_complete(
+        &self,
+        instance_id: Option<&str>,
+        error: Option<&str>,
+    ) -> Result<(), CloudInitError> {
+        let _guard = self.lock.lock().await;
+        match error {
+            None => report_success_to_host(&self.path, instance_id.unwrap_or_default()).await,
+            Some(reason) => report_failure_to_host(&self.path, reason).await,
+        }
+    }
+}
+
+/// JSON value for an event, trimming the message to Azure's size limit
+fn event_value(event: &Event) -> String {
+    let mut msg = event.description.clone();
+    loop {
+        let mut value = json!({
+            "name": event.name,
+            "type": match event.event_type {
+                EventType::Start => "start",
+                EventType::Finish => "finish",
+            },
+            "ts": format_timestamp(event.timestamp),
+            "msg": msg,
+        });
+        if let Some(result) = event.result {
+            value["result"] = serde_json::to_value(result).unwrap_or_default();
+        }
+
+        let value = value.to_string();
+        if value.len() <= AZURE_MAX_VALUE_SIZE || msg.is_empty() {
+            return value;
+        }
+
+        // Shrink by the overflow, keeping to a char boundary
+        let mut cut = msg.len().saturating_sub(value.len() - AZURE_MAX_VALUE_SIZE);
+        while !msg.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        msg.truncate(cut);
+    }
+}
+
+/// Encode a key/value pair as a NUL-padded KVP record
+pub fn encode_record(key: &str, value: &str) -> Result<Vec<u8>, CloudInitError> {
+    if key.len() >= KVP_KEY_SIZE || value.len() >= KVP_VALUE_SIZE {
+        return Err(CloudInitError::InvalidData(format!(
+            "KVP record too large for key {}",
+            key
+        )));
+    }
+
+    let mut record = vec![0u8; KVP_RECORD_SIZE];
+    record[..key.len()].copy_from_slice(key.as_bytes());
+    record[KVP_KEY_SIZE..KVP_KEY_SIZE + value.len()].copy_from_slice(value.as_bytes());
+    Ok(record)
+}
+
+/// Decode all complete records in a KVP pool
+pub fn decode_records(data: &[u8]) -> Vec<(String, String)> {
+    let field = |bytes: &[u8]| {
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).into_owned()
+    };
+
+    data.chunks_exact(KVP_RECORD_SIZE)
+        .map(|record| {
+            (
+                field(&record[..KVP_KEY_SIZE]),
+                field(&record[KVP_KEY_SIZE..]),
+            )
+        })
+        .collect()
+}
+
+/// Append encoded records to the pool
+async fn append_records(path: &Path, records: &[u8]) -> Result<(), CloudInitError> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(records).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+/// Tell the Azure host that provisioning succeeded
+pub async fn report_success_to_host(path: &Path, vm_id: &str) -> Result<(), CloudInitError> {
+    let report = provisioning_report("success", &[("vm_id", vm_id)]);
+    append_records(path, &encode_record(PROVISIONING_REPORT_KEY, &report)?).await
+}
+
+/// Tell the Azure host that provisioning failed
+pub async fn report_failure_to_host(path: &Path, reason: &str) -> Result<(), CloudInitError> {
+    // `|` separates fields in the report, so it may not appear in values
+    let reason = reason.replace('|', "/");
+    let report = provisioning_report("error", &[("reason", &reason)]);
+    append_records(path, &encode_record(PROVISIONING_REPORT_KEY, &report)?).await
+}
+
+/// Build a `PROVISIONING_REPORT` value (`key=value|...` pairs)
+fn provisioning_report(result: &str, extra: &[(&str, &str)]) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+
+    let mut fields = vec![
+        format!("result={}", result),
+        format!("agent=cloud-init-rs/{}", env!("CARGO_PKG_VERSION")),
+    ];
+    fields.extend(extra.iter().map(|(k, v)| format!("{}={}", k, v)));
+    fields.push(format!("timestamp={}", format_timestamp(now)));
+
+    let mut report = fields.join("|");
+    if report.len() >= KVP_VALUE_SIZE {
+        // The reason may hold any text, so cut on a char boundary
+        let mut cut = KVP_VALUE_SIZE - 1;
+        while !report.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        report.truncate(cut);
+    }
+    report
+}
+
+/// Format epoch seconds as an ISO 8601 UTC timestamp
+fn format_timestamp(epoch: f64) -> String {
+    let micros = (epoch * 1_000_000.0).round() as i64;
+    let secs = micros.div_euclid(1_000_000);
+    let frac = micros.rem_euclid(1_000_000);
+    let days = secs.div_euclid(86_400);
+    let tod = secs.rem_euclid(86_400);
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}+00:00",
+        year,
+        month,
+        day,
+        tod / 3600,
+        tod % 3600 / 60,
+        tod % 60,
+        frac
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reporting::EventResult;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0.0), "1970-01-01T00:00:00.000000+00:00");
+        assert_eq!(
+            format_timestamp(1_700_000_000.25),
+            "2023-11-14T22:13:20.250000+00:00"
+        );
+        assert_eq!(
+            format_timestamp(951_782_400.0),
+            "2000-02-29T00:00:00.000000+00:00"
+        );
+    }
+
+    #[test]
+    fn test_record_roundtrip() {
+        let record = encode_record("key", "value").unwrap();
+        assert_eq!(record.len(), KVP_RECORD_SIZE);
+
+        let mut pool = record.clone();
+        pool.extend(encode_record("other", "v2").unwrap());
+        pool.extend_from_slice(b"partial");
+        assert_eq!(
+            decode_records(&pool),
+            vec![
+                ("key".to_string(), "value".to_string()),
+                ("other".to_string(), "v2".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_record_rejects_oversized() {
+        assert!(encode_record(&"k".repeat(KVP_KEY_SIZE), "v").is_err());
+        assert!(encode_record("k", &"v".repeat(KVP_VALUE_SIZE)).is_err());
+    }
+
+    #[test]
+    fn test_event_value_truncates_long_messages() {
+        let mut event = Event::finish("init-local", "x", EventResult::Fail);
+        event.description = "é".repeat(2000);
+        let value = event_value(&event);
+        assert!(value.len() <= AZURE_MAX_VALUE_SIZE);
+
+        let json: serde_json::Value = serde_json::from_str(&value).unwrap();
+        assert_eq!(json["result"], "FAIL");
+        assert_eq!(json["type"], "finish");
+    }
+
+    #[tokio::test]
+    async fn test_publish_appends_records() {
+        let dir = TempDir::new().unwrap();
+        let pool = dir.path().join(".kvp_pool_1");
+        let handler = KvpHandler::with_incarnation(&pool, 1);
+
+        handler
+            .publish(&Event::start("init-local", "local"))
+            .await
+            .unwrap();
+        handler
+            .publish(&Event::finish("init-local", "ok", EventResult::Success))
+            .await
+            .unwrap();
+
+        let records = decode_records(&std::fs::read(&pool).unwrap());
+        assert_eq!(records.len(), 2);
+        assert!(records[0].0.starts_with("CLOUD_INIT|1|start|init-local|"));
+        assert!(records[1].0.starts_with("CLOUD_INIT|1|finish|init-local|"));
+        assert!(records[1].1.contains("\"result\":\"SUCCESS\""));
+    }
+
+    #[tokio::test]
+    async fn test_publish_truncates_stale_pool() {
+        let dir = TempDir::new().unwrap();
+        let pool = dir.path().join(".kvp_pool_1");
+        std::fs::write(&pool, encode_record("old", "boot").unwrap()).unwrap();
+
+        // An incarnation in the future makes the existing pool stale
+        let handler = KvpHandler::with_incarnation(&pool, u64::MAX);
+        handler
+            .publish(&Event::start("init-local", "local"))
+            .await
+            .unwrap();
+
+        let records = decode_records(&std::fs::read(&pool).unwrap());
+        assert_eq!(records.len(), 1);
+        assert!(records[0].0.starts_with("CLOUD_INIT|"));
+    }
+
+    #[tokio::test]
+    async fn test_provisioning_reports() {
+        let dir = TempDir::new().unwrap();
+        let pool = dir.path().join(".kvp_pool_1");
+        report_success_to_host(&pool, "vm-123").await.unwrap();
+        report_failure_to_host(&pool, "bad|thing").await.unwrap();
+
+        let records = decode_records(&std::fs::read(&pool).unwrap());
+        assert_eq!(records[0].0, PROVISIONING_REPORT_KEY);
+        assert!(
+            records[0]
+                .1
+                .starts_with("result=success|agent=cloud-init-rs/")
+        );
+        assert!(records[0].1.contains("|vm_id=vm-123|"));
+        assert!(records[1].1.contains("|reason=bad/thing|"));
+    }
+
+    #[test]
+    fn test_provisioning_report_truncates_on_char_boundary() {
+        let reason = "ü".repeat(KVP_VALUE_SIZE);
+        let report = provisioning_report("error", &[("reason", &reason)]);
+        assert!(report.len() < KVP_VALUE_SIZE);
+        assert!(report.ends_with('ü'));
+    }
+}
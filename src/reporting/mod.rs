@@ -0,0 +1,453 @@
+//! Structured event reporting
+//!
+//! Records start/finish events for every stage and module. Events are
+//! fanned out to a set of [`ReportingHandler`]s; by default they are
+//! appended to [`EVENT_LOG_PATH`] as JSON lines (read back by the
+//! `analyze` subcommand) and echoed through `tracing`, which ends up in the
+//! journal when running under systemd.
+//!
+//! Event names are slash-separated, mirroring Python cloud-init:
+//! `init-local`, `modules-config/config-users`, ...
+
+pub mod handlers;
+pub mod kvp;
+pub mod webhook;
+
+pub use handlers::{FileHandler, LogHandler};
+pub use kvp::KvpHandler;
+pub use webhook::WebhookHandler;
+
+use crate::config::ReportingConfig;
+use crate::root::RootContext;
+use crate::state::CloudPaths;
+use crate::{CloudInitError, Stage};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+#[cfg(feature = "ds-azure")]
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Default location of the structured event log
+pub const EVENT_LOG_PATH: &str = "/var/log/cloud-init-events.json";
+
+/// Start or finish marker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventType {
+    Start,
+    Finish,
+}
+
+/// Outcome recorded on finish events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum EventResult {
+    Success,
+    Warn,
+    Fail,
+}
+
+/// A single reporting event
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    /// Whether this marks the start or end of `name`
+    pub event_type: EventType,
+    /// Slash-separated event name, e.g. `modules-config/config-users`
+    pub name: String,
+    /// Epoch seconds
+    pub timestamp: f64,
+    /// Human readable description
+    #[serde(default)]
+    pub description: String,
+    /// Outcome, only present on finish events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<EventResult>,
+}
+
+impl Event {
+    /// Create a start event stamped with the current time
+    pub fn start(name: impl Into<String>, description: impl Into<String>) -> Self {
+        Self {
+            event_type: EventType::Start,
+            name: name.into(),
+            timestamp: now_epoch(),
+            description: description.into(),
+            result: None,
+        }
+    }
+
+    /// Create a finish event stamped with the current time
+    pub fn finish(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        result: EventResult,
+    ) -> Self {
+        Self {
+            event_type: EventType::Finish,
+            name: name.into(),
+            timestamp: now_epoch(),
+            description: description.into(),
+            result: Some(result),
+        }
+    }
+}
+
+/// Current time as fractional seconds since the epoch
+fn now_epoch() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Top-level event name for a stage
+pub fn stage_event_name(stage: Stage) -> &'static str {
+    match stage {
+        Stage::Local => "init-local",
+        Stage::Network => "init-network",
+        Stage::Config => "modules-config",
+        Stage::Final => "modules-final",
+    }
+}
+
+/// Event name for a module running within a stage
+pub fn module_event_name(stage: Stage, module: &str) -> String {
+    format!("{}/config-{}", stage_event_name(stage), module)
+}
+
+/// Destination for reporting events
+#[async_trait]
+pub trait ReportingHandler: Send + Sync {
+    /// Name of this handler, for diagnostics
+    fn name(&self) -> &'static str;
+
+    /// Deliver a single event
+    async fn publish(&self, event: &Event) -> Result<(), CloudInitError>;
+
+    /// Finish delivering events published so far, called at the end of
+    /// each stage
+    ///
+    /// Handlers that deliver in the background bound how long this waits.
+    async fn flush(&self) -> Result<(), CloudInitError> {
+        Ok(())
+    }
+
+    /// Report the overall provisioning outcome to the platform
+    ///
+    /// Called once after the final stage (or the first failing stage).
+    /// Most handlers have nothing extra to send.
+    async fn provisioning_complete(
+        &self,
+        _instance_id: Option<&str>,
+        _error: Option<&str>,
+    ) -> Result<(), CloudInitError> {
+        Ok(())
+    }
+}
+
+/// Fans events out to all registered handlers
+///
+/// Handler failures are logged and never abort provisioning.
+#[derive(Default)]
+pub struct Reporter {
+    handlers: Vec<Box<dyn ReportingHandler>>,
+}
+
+impl std::fmt::Debug for Reporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<_> = self.handlers.iter().map(|h| h.name()).collect();
+        f.debug_struct("Reporter")
+            .field("handlers", &names)
+            .finish()
+    }
+}
+
+impl Reporter {
+    /// Create a reporter with no handlers (events are dropped)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a reporter that logs events and appends them to the event log
+    /// of `root`
+    pub fn with_defaults(root: &RootContext) -> Self {
+        let mut reporter = Self::new();
+        reporter.add_handler(Box::new(LogHandler));
+        reporter.add_handler(Box::new(FileHandler::new(root.path(EVENT_LOG_PATH))));
+        reporter
+    }
+
+    /// Create the reporter for a boot from the system configuration
+    ///
+    /// Starts from [`Reporter::with_defaults`], adds the handlers listed
+    /// under `reporting` in `/etc/cloud/cloud.cfg{,.d}` and, on Azure,
+    /// enables KVP reporting when the Hyper-V KVP daemon's pool directory
+    /// exists.
+    pub async fn from_system(root: &RootContext, paths: &CloudPaths) -> Self {
+        let mut reporter = Self::with_defaults(root);
+        match crate::config::load_merged_config(paths).await {
+            Ok(config) => reporter.add_configured(&config.reporting),
+            Err(e) => warn!("Could not load reporting configuration: {}", e),
+        }
+
+        #[cfg(feature = "ds-azure")]
+        {
+            let pool = root.path(kvp::KVP_POOL_FILE);
+            if !reporter.has_handler("hyperv")
+                && pool.parent().is_some_and(Path::exists)
+                && crate::platform::Platform::detect().await == crate::platform::Platform::Azure
+            {
+                reporter.add_handler(Box::new(KvpHandler::new(pool)));
+            }
+        }
+        reporter
+    }
+
+    /// Register an additional handler
+    pub fn add_handler(&mut self, handler: Box<dyn ReportingHandler>) {
+        self.handlers.push(handler);
+    }
+
+    /// Add the handlers configured under the `reporting` key
+    ///
+    /// Handlers are added in name order. A `log` handler is only added if
+    /// one is not already registered; webhook handlers that cannot be
+    /// created are logged and skipped.
+    pub fn add_configured(&mut self, config: &HashMap<String, ReportingConfig>) {
+        let mut names: Vec<&String> = config.keys().collect();
+        names.sort();
+
+        for name in names {
+            match &config[name] {
+                ReportingConfig::Log => {
+                    if !self.has_handler("log") {
+                        self.add_handler(Box::new(LogHandler));
+                    }
+                }
+                ReportingConfig::Webhook(hook) => match WebhookHandler::new(hook) {
+                    Ok(handler) => self.add_handler(Box::new(handler)),
+                    Err(e) => warn!("Skipping webhook reporting handler '{}': {}", name, e),
+                },
+                ReportingConfig::Hyperv(kvp) => {
+                    if !self.has_handler("hyperv") {
+                        let path = kvp.kvp_file_path.as_deref().unwrap_or(kvp::KVP_POOL_FILE);
+                        self.add_handler(Box::new(KvpHandler::new(path)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether a handler with the given name is registered
+    pub fn has_handler(&self, name: &str) -> bool {
+        self.handlers.iter().any(|h| h.name() == name)
+    }
+
+    /// Number of registered handlers
+    pub fn handler_count(&self) -> usize {
+        self.handlers.len()
+    }
+
+    /// Send an event to every handler
+    pub async fn publish(&self, event: &Event) {
+        for handler in &self.handlers {
+            if let Err(e) = handler.publish(event).await {
+                warn!("Reporting handler '{}' failed: {}", handler.name(), e);
+            }
+        }
+    }
+
+    /// Flush every handler, see [`ReportingHandler::flush`]
+    pub async fn flush(&self) {
+        for handler in &self.handlers {
+            if let Err(e) = handler.flush().await {
+                warn!("Reporting handler '{}' failed: {}", handler.name(), e);
+            }
+        }
+    }
+
+    /// Report the overall provisioning outcome through every handler
+    pub async fn provisioning_complete(&self, instance_id: Option<&str>, error: Option<&str>) {
+        for handler in &self.handlers {
+            if let Err(e) = handler.provisioning_complete(instance_id, error).await {
+                warn!(
+                    "Reporting handler '{}' could not report provisioning: {}",
+                    handler.name(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Report the start of `name`
+    pub async fn start(&self, name: &str, description: &str) {
+        self.publish(&Event::start(name, description)).await;
+    }
+
+    /// Report the end of `name`
+    pub async fn finish(&self, name: &str, description: &str, result: EventResult) {
+        self.publish(&Event::finish(name, description, result))
+            .await;
+    }
+
+    /// Run `fut` between a start and a finish event for `name`
+    ///
+    /// The finish event carries `SUCCESS` or `FAIL` depending on the
+    /// future's result, which is passed through unchanged.
+    pub async fn scope<T, F>(
+        &self,
+        name: &str,
+        description: &str,
+        fut: F,
+    ) -> Result<T, CloudInitError>
+    where
+        F: Future<Output = Result<T, CloudInitError>>,
+    {
+        self.start(name, description).await;
+        let result = fut.await;
+        let (outcome, message) = match &result {
+            Ok(_) => (EventResult::Success, format!("{description} succeeded")),
+            Err(e) => (EventResult::Fail, format!("{description} failed: {e}")),
+        };
+        self.finish(name, &message, outcome).await;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CloudConfig;
+    use std::sync::{Arc, Mutex};
+
+    /// Handler that records every event it sees
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+    #[async_trait]
+    impl ReportingHandler for Recorder {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+
+        async fn publish(&self, event: &Event) -> Result<(), CloudInitError> {
+            self.0.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+    }
+
+    struct Failing;
+
+    #[async_trait]
+    impl ReportingHandler for Failing {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+
+        async fn publish(&self, _event: &Event) -> Result<(), CloudInitError> {
+            Err(CloudInitError::InvalidData("nope".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_event_names() {
+        assert_eq!(stage_event_name(Stage::Network), "init-network");
+        assert_eq!(
+            module_event_name(Stage::Config, "users"),
+            "modules-config/config-users"
+        );
+    }
+
+    #[test]
+    fn test_event_json_shape() {
+        let event = Event::finish("init-local", "done", EventResult::Success);
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event_type"], "finish");
+        assert_eq!(json["result"], "SUCCESS");
+        assert!(json["timestamp"].is_f64());
+
+        let start = serde_json::to_value(Event::start("init-local", "go")).unwrap();
+        assert!(start.get("result").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_scope_reports_success() {
+        let recorder = Recorder::default();
+        let mut reporter = Reporter::new();
+        reporter.add_handler(Box::new(recorder.clone()));
+
+        let value = reporter
+            .scope("modules-config/config-users", "users", async { Ok(7) })
+            .await
+            .unwrap();
+        assert_eq!(value, 7);
+
+        let events = recorder.0.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event_type, EventType::Start);
+        assert_eq!(events[1].result, Some(EventResult::Success));
+        assert!(events[1].timestamp >= events[0].timestamp);
+    }
+
+    #[tokio::test]
+    async fn test_scope_reports_failure() {
+        let recorder = Recorder::default();
+        let mut reporter = Reporter::new();
+        reporter.add_handler(Box::new(recorder.clone()));
+
+        let result: Result<(), _> = reporter
+            .scope("init-local", "local", async {
+                Err(CloudInitError::Config("bad".to_string()))
+            })
+            .await;
+        assert!(result.is_err());
+
+        let events = recorder.0.lock().unwrap();
+        assert_eq!(events[1].result, Some(EventResult::Fail));
+        assert!(events[1].description.contains("bad"));
+    }
+
+    #[tokio::test]
+    async fn test_add_configured_handlers() {
+        let config = CloudConfig::from_yaml(
+            "reporting:\n  logging: {type: log}\n  hook: {type: webhook, endpoint: 'http://127.0.0.1:9/'}\n",
+        )
+        .unwrap();
+
+        let mut reporter = Reporter::new();
+        reporter.add_configured(&config.reporting);
+        assert!(reporter.has_handler("log"));
+        assert!(reporter.has_handler("webhook"));
+
+        // A second log handler is not stacked on top of the default one
+        let mut reporter = Reporter::with_defaults(&RootContext::new("/"));
+        reporter.add_configured(&config.reporting);
+        assert_eq!(reporter.handler_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_failing_handler_does_not_block_others() {
+        let recorder = Recorder::default();
+        let mut reporter = Reporter::new();
+        reporter.add_handler(Box::new(Failing));
+        reporter.add_handler(Box::new(recorder.clone()));
+
+        reporter.start("init-local", "x").await;
+        assert_eq!(recorder.0.lock().unwrap().len(), 1);
+        assert_eq!(reporter.handler_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_default_event_log_is_under_root() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let reporter = Reporter::with_defaults(&root);
+
+        reporter.start("init-local", "x").await;
+        let log = std::fs::read_to_string(root.path(EVENT_LOG_PATH)).unwrap();
+        assert!(log.contains("\"init-local\""));
+    }
+}
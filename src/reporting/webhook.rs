@@ -0,0 +1,258 @@
+//! Webhook reporting handler
+//!
+//! POSTs every event as JSON to a configured endpoint so an external
+//! orchestrator can follow instance bring-up as it happens. When a shared
+//! secret is configured the request body is signed with HMAC-SHA256 and the
+//! hex digest sent in the `X-Cloud-Init-Signature` header as
+//! `sha256=<digest>`, so receivers can reject forged events.
+//!
+//! Events are queued and sent in order by a background task, so a slow or
+//! unreachable endpoint does not hold up the modules that report. At the
+//! end of each stage the reporter flushes the queue, waiting at most the
+//! configured `timeout`; events still queued then are dropped.
+
+use super::{Event, ReportingHandler};
+use crate::CloudInitError;
+use crate::config::WebhookReportingConfig;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use sha2::Sha256;
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
+
+/// Header carrying the request body signature
+pub const SIGNATURE_HEADER: &str = "X-Cloud-Init-Signature";
+
+/// Default per-request timeout in seconds
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+/// Delay between retries
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Sends events to an HTTP endpoint
+///
+/// Created within a Tokio runtime, which runs its delivery task.
+#[derive(Debug)]
+pub struct WebhookHandler {
+    endpoint: String,
+    queue: mpsc::UnboundedSender<Message>,
+    /// Longest [`ReportingHandler::flush`] waits for the queue to drain
+    flush_timeout: Duration,
+    /// Number of events queued so far
+    queued: AtomicU64,
+    /// Events numbered below this are dropped instead of sent
+    skip_before: Arc<AtomicU64>,
+}
+
+/// Work for the delivery task
+#[derive(Debug)]
+enum Message {
+    /// An event's number and JSON body
+    Event(u64, Vec<u8>),
+    /// Answer with the number of events not delivered since the last flush
+    /// once everything queued before is done
+    Flush(oneshot::Sender<usize>),
+}
+
+/// Posts queued events, one after another
+#[derive(Debug)]
+struct Delivery {
+    client: Client,
+    endpoint: String,
+    secret: Option<String>,
+    retries: u32,
+    skip_before: Arc<AtomicU64>,
+}
+
+impl WebhookHandler {
+    /// Create a handler from its `reporting` configuration
+    pub fn new(config: &WebhookReportingConfig) -> Result<Self, CloudInitError> {
+        let timeout = Duration::from_secs(config.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS));
+        let client = crate::http::client_builder()?
+            .timeout(timeout)
+            .connect_timeout(timeout)
+            .build()?;
+        let skip_before = Arc::new(AtomicU64::new(0));
+        let delivery = Delivery {
+            client,
+            endpoint: config.endpoint.clone(),
+            secret: config.secret.clone(),
+            retries: config.retries.unwrap_or(0),
+            skip_before: skip_before.clone(),
+        };
+
+        let runtime = tokio::runtime::Handle::try_current()
+            .map_err(|e| CloudInitError::Config(format!("webhook delivery: {}", e)))?;
+        let (queue, messages) = mpsc::unbounded_channel();
+        runtime.spawn(delivery.run(messages));
+        Ok(Self {
+            endpoint: config.endpoint.clone(),
+            queue,
+            flush_timeout: timeout,
+            queued: AtomicU64::new(0),
+            skip_before,
+        })
+    }
+
+    /// JSON body sent for an event
+    fn payload(event: &Event) -> Result<Vec<u8>, CloudInitError> {
+        let mut value = serde_json::to_value(event)?;
+        if let Some(map) = value.as_object_mut() {
+            map.insert("origin".to_string(), "cloudinit".into());
+        }
+        Ok(serde_json::to_vec(&value)?)
+    }
+}
+
+impl Delivery {
+    /// Deliver `messages` until the handler is dropped
+    ///
+    /// After a flush gave up waiting, the events queued before it are
+    /// dropped rather than sent late.
+    async fn run(self, mut messages: mpsc::UnboundedReceiver<Message>) {
+        let mut failed = 0;
+        while let Some(message) = messages.recv().await {
+            match message {
+                Message::Event(number, _) if number < self.skip_before.load(Ordering::Relaxed) => {
+                    debug!(
+                        "Dropping event for webhook {} after a flush timed out",
+                        self.endpoint
+                    );
+                    failed += 1;
+                }
+                Message::Event(_, body) => {
+                    if let Err(e) = self.send(&body).await {
+                        warn!("Webhook {} did not take an event: {}", self.endpoint, e);
+                        failed += 1;
+                    }
+                }
+                Message::Flush(done) => {
+                    let _ = done.send(std::mem::take(&mut failed));
+                }
+            }
+        }
+    }
+
+    /// POST `body`, retrying as configured
+    async fn send(&self, body: &[u8]) -> Result<(), CloudInitError> {
+        let mut attempt = 0;
+        loop {
+            match self.post(body).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    debug!("Webhook delivery failed ({}), retry {}", e, attempt);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn post(&self, body: &[u8]) -> Result<(), CloudInitError> {
+        let mut request = self
+            .client
+            .post(&self.endpoint)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_vec());
+        if let Some(secret) = &self.secret {
+            request = request.header(
+                SIGNATURE_HEADER,
+                format!("sha256={}", sign(secret.as_bytes(), body)),
+            );
+        }
+
+        let response = request.send().await?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(CloudInitError::Network(format!(
+                "webhook {} returned {}",
+                self.endpoint,
+                response.status()
+            )))
+        }
+    }
+}
+
+#[async_trait]
+impl ReportingHandler for WebhookHandler {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    /// Queue `event`; it is sent in the background
+    async fn publish(&self, event: &Event) -> Result<(), CloudInitError> {
+        let body = Self::payload(event)?;
+        let number = self.queued.fetch_add(1, Ordering::Relaxed);
+        self.queue.send(Message::Event(number, body)).map_err(|_| {
+            CloudInitError::Network(format!("webhook {} delivery stopped", self.endpoint))
+        })
+    }
+
+    /// Wait up to the request timeout for the queued events to be sent
+    async fn flush(&self) -> Result<(), CloudInitError> {
+        let (done, failed) = oneshot::channel();
+        if self.queue.send(Message::Flush(done)).is_err() {
+            return Ok(());
+        }
+        match tokio::time::timeout(self.flush_timeout, failed).await {
+            Ok(Ok(0)) | Ok(Err(_)) => Ok(()),
+            Ok(Ok(failed)) => Err(CloudInitError::Network(format!(
+                "webhook {}: {} event(s) not delivered",
+                self.endpoint, failed
+            ))),
+            Err(_) => {
+                self.skip_before
+                    .store(self.queued.load(Ordering::Relaxed), Ordering::Relaxed);
+                Err(CloudInitError::Timeout(format!(
+                    "webhook {} to take queued events (limit {}s)",
+                    self.endpoint,
+                    self.flush_timeout.as_secs()
+                )))
+            }
+        }
+    }
+}
+
+/// Hex-encoded HMAC-SHA256 of `body` keyed with `secret`
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(body);
+
+    let digest = mac.finalize().into_bytes();
+    digest.iter().fold(String::with_capacity(64), |mut out, b| {
+        let _ = write!(out, "{b:02x}");
+        out
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reporting::EventResult;
+
+    #[test]
+    fn test_sign_rfc4231_vector() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_payload_includes_origin() {
+        let event = Event::finish("init-local", "done", EventResult::Fail);
+        let body = WebhookHandler::payload(&event).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["origin"], "cloudinit");
+        assert_eq!(json["result"], "FAIL");
+        assert_eq!(json["name"], "init-local");
+    }
+}
@@ -0,0 +1,461 @@
+//! Alternate root filesystem support
+//!
+//! With `--root <dir>` every file cloud-init-rs writes (configuration
+//! files, network config, state under `/var/lib/cloud`) lands below `dir`
+//! instead of on the running system, and system commands run inside it
+//! via `chroot`. This is used to customize a mounted disk image, e.g.
+//! `cloud-init-rs --root /mnt/image config`.
+//!
+//! A [`RootContext`] is passed down to modules and renderers explicitly.
+//! A [`CloudInit`](crate::CloudInit) runs its stages inside
+//! [`CloudInit::scope`](crate::CloudInit::scope), whose root is what
+//! [`CloudPaths::new`] and the stages pick up through
+//! [`RootContext::current`]. The scope belongs to the task running the
+//! stages, so several `CloudInit`s in one process each see their own root.
+//!
+//! File changes go through the context's `write_file`, `create_dir_all`,
+//! ... helpers rather than `tokio::fs` directly, so a dry run (a context
+//! with an [`ActionRecorder`]) can record them instead.
+
+use crate::actions::{Action, ActionRecorder};
+use crate::runner::{ChrootRunner, DryRunRunner, HostRunner, SystemRunner};
+use crate::state::CloudPaths;
+use crate::state::paths::{CLOUD_DIR, CONFIG_DIR, RUN_DIR};
+use crate::{CloudInitError, IoContext};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// Root filesystem that cloud-init-rs operates on
+#[derive(Debug, Clone)]
+pub struct RootContext {
+    root: PathBuf,
+    /// Set for dry runs: changes are recorded here instead of made
+    recorder: Option<ActionRecorder>,
+}
+
+impl Default for RootContext {
+    fn default() -> Self {
+        Self::host()
+    }
+}
+
+impl RootContext {
+    /// The running system
+    pub fn host() -> Self {
+        Self::new("/")
+    }
+
+    /// A root filesystem mounted at `root`
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            recorder: None,
+        }
+    }
+
+    /// Record changes with `recorder` instead of making them
+    pub fn with_recorder(mut self, recorder: ActionRecorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Recorder collecting the plan, if this is a dry run
+    pub fn recorder(&self) -> Option<&ActionRecorder> {
+        self.recorder.as_ref()
+    }
+
+    /// Whether changes are only recorded
+    pub fn is_dry_run(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Directory the root filesystem is mounted at
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Whether this is the running system rather than an alternate root
+    ///
+    /// Callers use this to skip actions that only make sense on a live
+    /// system, such as restarting services or setting the kernel hostname.
+    pub fn is_host(&self) -> bool {
+        self.root == Path::new("/")
+    }
+
+    /// Map an absolute in-system path to where it lives on this host
+    ///
+    /// `/etc/hostname` becomes `<root>/etc/hostname`. `..` components are
+    /// dropped so the result cannot escape the root.
+    pub fn path(&self, path: impl AsRef<Path>) -> PathBuf {
+        let mut mapped = self.root.clone();
+        for component in path.as_ref().components() {
+            if let Component::Normal(part) = component {
+                mapped.push(part);
+            }
+        }
+        mapped
+    }
+
+    /// Standard cloud-init state and config paths beneath this root
+    pub fn cloud_paths(&self) -> CloudPaths {
+        CloudPaths {
+            base: self.path(CLOUD_DIR),
+            config: self.path(CONFIG_DIR),
+            run: self.path(RUN_DIR),
+        }
+    }
+
+    /// Runner executing commands on this root
+    pub fn runner(&self) -> Box<dyn SystemRunner> {
+        let runner: Box<dyn SystemRunner> = if self.is_host() {
+            Box::new(HostRunner)
+        } else {
+            Box::new(ChrootRunner::new(&self.root))
+        };
+        match &self.recorder {
+            Some(recorder) => Box::new(DryRunRunner::new(runner, recorder.clone())),
+            None => runner,
+        }
+    }
+
+    /// Record `action` if this is a dry run; returns whether it was recorded
+    fn record(&self, action: impl FnOnce() -> Action) -> bool {
+        match &self.recorder {
+            Some(recorder) => {
+                recorder.record(action());
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The helpers below take host paths, i.e. already mapped with `path()`,
+    // and mirror the `tokio::fs` function of the same name. Errors name
+    // the path involved.
+
+    /// Create or overwrite a file
+    pub async fn write_file(
+        &self,
+        path: &Path,
+        contents: impl AsRef<[u8]>,
+    ) -> Result<(), CloudInitError> {
+        let contents = contents.as_ref();
+        if self.record(|| Action::WriteFile {
+            path: path.to_path_buf(),
+            size: contents.len(),
+        }) {
+            return Ok(());
+        }
+        fs::write(path, contents).await.with_path(path)
+    }
+
+    /// Replace a file atomically, see [`write_atomic`]
+    pub async fn write_file_atomic(
+        &self,
+        path: &Path,
+        contents: impl AsRef<[u8]>,
+    ) -> Result<(), CloudInitError> {
+        let contents = contents.as_ref();
+        if self.record(|| Action::WriteFile {
+            path: path.to_path_buf(),
+            size: contents.len(),
+        }) {
+            return Ok(());
+        }
+        write_atomic(path, contents, DEFAULT_FILE_MODE).await
+    }
+
+    /// Replace a file atomically, readable only as `mode` allows
+    ///
+    /// The file never exists with looser permissions, unlike
+    /// [`write_file`](Self::write_file) followed by
+    /// [`set_mode`](Self::set_mode).
+    pub async fn write_file_mode(
+        &self,
+        path: &Path,
+        contents: impl AsRef<[u8]>,
+        mode: u32,
+    ) -> Result<(), CloudInitError> {
+        let contents = contents.as_ref();
+        if self.recorder.is_some() {
+            self.record(|| Action::WriteFile {
+                path: path.to_path_buf(),
+                size: contents.len(),
+            });
+            self.record(|| Action::SetMode {
+                path: path.to_path_buf(),
+                mode,
+            });
+            return Ok(());
+        }
+        write_atomic(path, contents, mode).await
+    }
+
+    /// Append to a file, creating it if needed
+    ///
+    /// Unlike reading and rewriting, this also works for character
+    /// devices such as `/dev/urandom`.
+    pub async fn append_file(
+        &self,
+        path: &Path,
+        contents: impl AsRef<[u8]>,
+    ) -> Result<(), CloudInitError> {
+        use tokio::io::AsyncWriteExt;
+
+        let contents = contents.as_ref();
+        if self.record(|| Action::AppendFile {
+            path: path.to_path_buf(),
+            size: contents.len(),
+        }) {
+            return Ok(());
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_path(path)?;
+        file.write_all(contents).await.with_path(path)?;
+        file.flush().await.with_path(path)
+    }
+
+    /// Create a directory and any missing parents
+    pub async fn create_dir_all(&self, path: &Path) -> Result<(), CloudInitError> {
+        if self.record(|| Action::CreateDir {
+            path: path.to_path_buf(),
+        }) {
+            return Ok(());
+        }
+        fs::create_dir_all(path).await.with_path(path)
+    }
+
+    /// Set a file's permission bits
+    pub async fn set_mode(&self, path: &Path, mode: u32) -> Result<(), CloudInitError> {
+        if self.record(|| Action::SetMode {
+            path: path.to_path_buf(),
+            mode,
+        }) {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                .await
+                .with_path(path)?;
+        }
+        Ok(())
+    }
+
+    /// Set a file's owner and group
+    pub async fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> Result<(), CloudInitError> {
+        if self.record(|| Action::SetOwner {
+            path: path.to_path_buf(),
+            uid,
+            gid,
+        }) {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::chown(path, Some(uid), Some(gid)).with_path(path)?;
+        }
+        Ok(())
+    }
+
+    /// Create a symbolic link at `link` pointing to `target`
+    ///
+    /// `target` is stored as given, so in-system absolute paths stay
+    /// valid when the root is booted.
+    pub async fn symlink(&self, target: &Path, link: &Path) -> Result<(), CloudInitError> {
+        if self.record(|| Action::Symlink {
+            path: link.to_path_buf(),
+            target: target.to_path_buf(),
+        }) {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        {
+            fs::symlink(target, link).await.with_path(link)?;
+        }
+        Ok(())
+    }
+
+    /// Remove a file
+    pub async fn remove_file(&self, path: &Path) -> Result<(), CloudInitError> {
+        if self.record(|| Action::RemoveFile {
+            path: path.to_path_buf(),
+        }) {
+            return Ok(());
+        }
+        fs::remove_file(path).await.with_path(path)
+    }
+
+    /// Remove a directory and everything in it
+    pub async fn remove_dir_all(&self, path: &Path) -> Result<(), CloudInitError> {
+        if self.record(|| Action::RemoveDir {
+            path: path.to_path_buf(),
+        }) {
+            return Ok(());
+        }
+        fs::remove_dir_all(path).await.with_path(path)
+    }
+
+    /// The root of the enclosing [`CloudInit::scope`](crate::CloudInit::scope),
+    /// or the running system outside one
+    pub fn current() -> RootContext {
+        crate::embed::Scope::with(|scope| scope.root.clone()).unwrap_or_else(RootContext::host)
+    }
+}
+
+/// Mode of files written by [`write_atomic`] through
+/// [`RootContext::write_file_atomic`]
+pub const DEFAULT_FILE_MODE: u32 = 0o644;
+
+/// Replace `path` with `contents` so that readers, and the next boot after
+/// a crash, see either the old or the new file but never a partial one
+///
+/// The contents go to a temporary file in the same directory, created
+/// with `mode`, which is synced and renamed over `path`. The directory is
+/// synced as well so the rename itself survives a power loss.
+pub async fn write_atomic(path: &Path, contents: &[u8], mode: u32) -> Result<(), CloudInitError> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let name = path.file_name().ok_or_else(|| {
+        CloudInitError::InvalidData(format!("{} does not name a file", path.display()))
+    })?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let temp = dir.join(format!(
+        ".{}.{}-{}.tmp",
+        name.to_string_lossy(),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let written = async {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(mode);
+        let mut file = options.open(&temp).await.with_path(&temp)?;
+        // Exactly `mode`, whatever the umask
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(mode))
+                .await
+                .with_path(&temp)?;
+        }
+        file.write_all(contents).await.with_path(&temp)?;
+        file.sync_all().await.with_path(&temp)?;
+        fs::rename(&temp, path).await.with_path(path)
+    }
+    .await;
+    if written.is_err() {
+        let _ = fs::remove_file(&temp).await;
+        return written;
+    }
+
+    #[cfg(unix)]
+    {
+        let handle = fs::File::open(dir).await.with_path(dir)?;
+        handle.sync_all().await.with_path(dir)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_write_atomic() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("status.json");
+        std::fs::write(&path, "{\"old\": true}").unwrap();
+
+        write_atomic(&path, b"{}", 0o600).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{}");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o600);
+        // Only the target is left behind
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_host_paths_unchanged() {
+        let host = RootContext::host();
+        assert!(host.is_host());
+        assert_eq!(host.path("/etc/hostname"), PathBuf::from("/etc/hostname"));
+        assert_eq!(host.cloud_paths().base, PathBuf::from(CLOUD_DIR));
+    }
+
+    #[test]
+    fn test_alternate_root_paths() {
+        let image = RootContext::new("/mnt/image");
+        assert!(!image.is_host());
+        assert_eq!(
+            image.path("/etc/hostname"),
+            PathBuf::from("/mnt/image/etc/hostname")
+        );
+        assert_eq!(
+            image.path("etc/../../../etc/shadow"),
+            PathBuf::from("/mnt/image/etc/etc/shadow")
+        );
+
+        let paths = image.cloud_paths();
+        assert_eq!(paths.base, PathBuf::from("/mnt/image/var/lib/cloud"));
+        assert_eq!(paths.config, PathBuf::from("/mnt/image/etc/cloud"));
+        assert_eq!(paths.run, PathBuf::from("/mnt/image/run/cloud-init"));
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_records_instead_of_writing() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let recorder = ActionRecorder::new();
+        let root = RootContext::new(temp.path()).with_recorder(recorder.clone());
+        let dir = root.path("/etc/app");
+        let file = dir.join("app.conf");
+
+        root.create_dir_all(&dir).await.unwrap();
+        root.write_file(&file, "key=value\n").await.unwrap();
+        root.set_mode(&file, 0o600).await.unwrap();
+        root.runner()
+            .run(&crate::runner::SystemCommand::new("systemctl").args(["restart", "app"]))
+            .await
+            .unwrap();
+
+        assert!(!dir.exists());
+        let actions: Vec<_> = recorder
+            .plan()
+            .actions
+            .into_iter()
+            .map(|a| a.action)
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                Action::CreateDir { path: dir },
+                Action::WriteFile {
+                    path: file.clone(),
+                    size: 10
+                },
+                Action::SetMode {
+                    path: file,
+                    mode: 0o600
+                },
+                Action::RunCommand {
+                    command: "systemctl restart app".to_string()
+                },
+            ]
+        );
+    }
+}
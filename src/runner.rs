@@ -0,0 +1,454 @@
+//! System command execution
+//!
+//! Modules that shell out (`useradd`, `chpasswd`, package managers, ...)
+//! go through a [`SystemRunner`] instead of spawning processes directly.
+//! Production code passes [`HostRunner`]; tests pass a [`RecordingRunner`],
+//! which records every command and returns canned results, so the exact
+//! command lines a module generates can be checked without root.
+//! Dry runs use a [`DryRunRunner`], which records commands as planned
+//! actions.
+
+use crate::CloudInitError;
+use crate::actions::{Action, ActionRecorder};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Mutex;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+
+/// A command to execute
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SystemCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Extra environment variables on top of the inherited environment
+    pub env: Vec<(String, String)>,
+    /// Data written to the command's stdin
+    pub stdin: Option<String>,
+    /// Only inspects the system (`which`, `systemctl is-enabled`, ...),
+    /// so dry runs still execute it
+    pub probe: bool,
+    /// Copy output to the output log line by line while the command runs,
+    /// for long-running commands
+    pub stream: bool,
+}
+
+impl SystemCommand {
+    /// Create a command running `program` with no arguments
+    pub fn new(program: impl Into<String>) -> Self {
+        Self {
+            program: program.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Append one argument
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Append several arguments
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set an environment variable
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Feed `input` to the command's stdin
+    pub fn stdin(mut self, input: impl Into<String>) -> Self {
+        self.stdin = Some(input.into());
+        self
+    }
+
+    /// Mark the command as a read-only probe
+    pub fn probe(mut self) -> Self {
+        self.probe = true;
+        self
+    }
+
+    /// Stream the command's output to the output log as it is produced
+    pub fn stream(mut self) -> Self {
+        self.stream = true;
+        self
+    }
+}
+
+impl fmt::Display for SystemCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.program)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Result of a finished command
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    /// Exit code, `None` if the process was killed by a signal
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    /// A successful run printing `stdout`
+    pub fn success(stdout: impl Into<String>) -> Self {
+        Self {
+            code: Some(0),
+            stdout: stdout.into(),
+            stderr: String::new(),
+        }
+    }
+
+    /// A failed run exiting with `code`
+    pub fn failure(code: i32, stderr: impl Into<String>) -> Self {
+        Self {
+            code: Some(code),
+            stdout: String::new(),
+            stderr: stderr.into(),
+        }
+    }
+
+    /// Whether the command exited with status 0
+    pub fn is_success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// Executes system commands
+///
+/// An `Err` means the command could not be run at all; a command that ran
+/// and failed is reported through [`CommandOutput::code`].
+#[async_trait]
+pub trait SystemRunner: Send + Sync {
+    async fn run(&self, command: &SystemCommand) -> Result<CommandOutput, CloudInitError>;
+}
+
+/// Runs commands on the host
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HostRunner;
+
+#[async_trait]
+impl SystemRunner for HostRunner {
+    async fn run(&self, command: &SystemCommand) -> Result<CommandOutput, CloudInitError> {
+        let mut cmd = tokio::process::Command::new(&command.program);
+        cmd.args(&command.args)
+            .envs(command.env.iter().map(|(k, v)| (k, v)))
+            .stdin(if command.stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // A module abandoned by its watchdog takes its commands with it
+            .kill_on_drop(true);
+
+        let spawn_err =
+            |e: std::io::Error| CloudInitError::Command(format!("{}: {}", command.program, e));
+        let mut child = cmd.spawn().map_err(spawn_err)?;
+        if let (Some(input), Some(mut stdin)) = (&command.stdin, child.stdin.take()) {
+            stdin.write_all(input.as_bytes()).await.map_err(spawn_err)?;
+        }
+        if command.stream {
+            let (stdout, stderr) = tokio::join!(
+                stream_lines(child.stdout.take()),
+                stream_lines(child.stderr.take())
+            );
+            let status = child.wait().await.map_err(spawn_err)?;
+            return Ok(CommandOutput {
+                code: status.code(),
+                stdout,
+                stderr,
+            });
+        }
+        let output = child.wait_with_output().await.map_err(spawn_err)?;
+
+        let output = CommandOutput {
+            code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        };
+        crate::logging::log_command_output(&output.stdout, &output.stderr);
+        Ok(output)
+    }
+}
+
+/// Copy `pipe` to the output log a line at a time, returning everything read
+async fn stream_lines(pipe: Option<impl AsyncRead + Unpin>) -> String {
+    let Some(pipe) = pipe else {
+        return String::new();
+    };
+    let mut reader = BufReader::new(pipe);
+    let mut collected = String::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&line);
+                crate::logging::log_command_output(&line, "");
+                collected.push_str(&line);
+            }
+        }
+    }
+    collected
+}
+
+/// Runs commands inside an alternate root with `chroot(8)`
+#[derive(Debug, Clone)]
+pub struct ChrootRunner {
+    root: PathBuf,
+}
+
+impl ChrootRunner {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// The `chroot` invocation equivalent to running `command` in the root
+    pub fn wrap(&self, command: &SystemCommand) -> SystemCommand {
+        SystemCommand {
+            program: "chroot".to_string(),
+            args: [
+                self.root.to_string_lossy().into_owned(),
+                command.program.clone(),
+            ]
+            .into_iter()
+            .chain(command.args.iter().cloned())
+            .collect(),
+            env: command.env.clone(),
+            stdin: command.stdin.clone(),
+            probe: command.probe,
+            stream: command.stream,
+        }
+    }
+}
+
+#[async_trait]
+impl SystemRunner for ChrootRunner {
+    async fn run(&self, command: &SystemCommand) -> Result<CommandOutput, CloudInitError> {
+        HostRunner.run(&self.wrap(command)).await
+    }
+}
+
+/// Runner for dry runs
+///
+/// Probes are passed to the wrapped runner so modules still see the real
+/// system; every other command is recorded and reported as successful
+/// without running.
+pub struct DryRunRunner {
+    inner: Box<dyn SystemRunner>,
+    recorder: ActionRecorder,
+}
+
+impl DryRunRunner {
+    pub fn new(inner: Box<dyn SystemRunner>, recorder: ActionRecorder) -> Self {
+        Self { inner, recorder }
+    }
+}
+
+#[async_trait]
+impl SystemRunner for DryRunRunner {
+    async fn run(&self, command: &SystemCommand) -> Result<CommandOutput, CloudInitError> {
+        if command.probe {
+            return self.inner.run(command).await;
+        }
+        self.recorder.record(Action::RunCommand {
+            command: crate::redact::text(&command.to_string()),
+        });
+        Ok(CommandOutput::success(""))
+    }
+}
+
+/// Fake runner that records commands instead of executing them
+///
+/// Every command succeeds with empty output unless a response was
+/// registered with [`RecordingRunner::with_response`], either for the
+/// full command line or for the program alone.
+///
+/// # Example
+/// ```
+/// use cloud_init_rs::runner::{CommandOutput, RecordingRunner};
+///
+/// let runner = RecordingRunner::new()
+///     .with_response("useradd", CommandOutput::failure(9, "user exists"));
+/// assert!(runner.commands().is_empty());
+/// ```
+#[derive(Debug, Default)]
+pub struct RecordingRunner {
+    calls: Mutex<Vec<SystemCommand>>,
+    responses: HashMap<String, CommandOutput>,
+}
+
+impl RecordingRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return `output` when a command matches `pattern`
+    ///
+    /// `pattern` is either a full command line (`"which dnf"`), which
+    /// takes precedence, or a program name (`"dnf"`).
+    pub fn with_response(mut self, pattern: &str, output: CommandOutput) -> Self {
+        self.responses.insert(pattern.to_string(), output);
+        self
+    }
+
+    /// Commands run so far, in order
+    pub fn calls(&self) -> Vec<SystemCommand> {
+        self.calls.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Command lines run so far, as `program arg...` strings
+    pub fn commands(&self) -> Vec<String> {
+        self.calls().iter().map(ToString::to_string).collect()
+    }
+}
+
+#[async_trait]
+impl SystemRunner for RecordingRunner {
+    async fn run(&self, command: &SystemCommand) -> Result<CommandOutput, CloudInitError> {
+        self.calls
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(command.clone());
+        Ok(self
+            .responses
+            .get(&command.to_string())
+            .or_else(|| self.responses.get(&command.program))
+            .cloned()
+            .unwrap_or_else(|| CommandOutput::success("")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_host_runner_captures_output() {
+        let output = HostRunner
+            .run(&SystemCommand::new("sh").args(["-c", "echo out; echo err >&2; exit 3"]))
+            .await
+            .unwrap();
+        assert_eq!(output.code, Some(3));
+        assert_eq!(output.stdout, "out\n");
+        assert_eq!(output.stderr, "err\n");
+        assert!(!output.is_success());
+    }
+
+    #[tokio::test]
+    async fn test_host_runner_stdin_and_env() {
+        let output = HostRunner
+            .run(
+                &SystemCommand::new("sh")
+                    .args(["-c", "cat; printf %s \"$GREETING\""])
+                    .env("GREETING", "hi")
+                    .stdin("alice:x\n"),
+            )
+            .await
+            .unwrap();
+        assert!(output.is_success());
+        assert_eq!(output.stdout, "alice:x\nhi");
+    }
+
+    #[tokio::test]
+    async fn test_host_runner_streamed_output() {
+        let output = HostRunner
+            .run(
+                &SystemCommand::new("sh")
+                    .args([
+                        "-c",
+                        "echo one; echo two; echo err >&2; printf tail; exit 2",
+                    ])
+                    .stream(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(output.code, Some(2));
+        assert_eq!(output.stdout, "one\ntwo\ntail");
+        assert_eq!(output.stderr, "err\n");
+    }
+
+    #[tokio::test]
+    async fn test_host_runner_missing_program() {
+        let result = HostRunner
+            .run(&SystemCommand::new("nonexistent_command_xyz_12345"))
+            .await;
+        assert!(matches!(result, Err(CloudInitError::Command(_))));
+    }
+
+    #[test]
+    fn test_chroot_wrap() {
+        let runner = ChrootRunner::new("/mnt/image");
+        let wrapped = runner.wrap(&SystemCommand::new("useradd").args(["-m", "alice"]));
+        assert_eq!(wrapped.to_string(), "chroot /mnt/image useradd -m alice");
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_runner_only_executes_probes() {
+        let inner = RecordingRunner::new().with_response("which", CommandOutput::failure(1, ""));
+        let recorder = ActionRecorder::new();
+        let runner = DryRunRunner::new(Box::new(inner), recorder.clone());
+
+        let probe = runner
+            .run(&SystemCommand::new("which").arg("dnf").probe())
+            .await
+            .unwrap();
+        let change = runner
+            .run(&SystemCommand::new("useradd").arg("alice"))
+            .await
+            .unwrap();
+
+        assert_eq!(probe.code, Some(1));
+        assert!(change.is_success());
+        assert_eq!(
+            recorder.plan().actions[0].action,
+            Action::RunCommand {
+                command: "useradd alice".to_string()
+            }
+        );
+        assert_eq!(recorder.plan().actions.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_recording_runner() {
+        let runner = RecordingRunner::new()
+            .with_response("false", CommandOutput::failure(1, "nope"))
+            .with_response("false --quiet", CommandOutput::failure(2, ""));
+
+        let ok = runner
+            .run(&SystemCommand::new("true").arg("--flag"))
+            .await
+            .unwrap();
+        let failed = runner.run(&SystemCommand::new("false")).await.unwrap();
+        let quiet = runner
+            .run(&SystemCommand::new("false").arg("--quiet"))
+            .await
+            .unwrap();
+
+        assert!(ok.is_success());
+        assert_eq!(failed, CommandOutput::failure(1, "nope"));
+        assert_eq!(quiet.code, Some(2));
+        assert_eq!(
+            runner.commands(),
+            vec!["true --flag", "false", "false --quiet"]
+        );
+    }
+}
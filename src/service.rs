@@ -0,0 +1,160 @@
+//! Service management
+//!
+//! Modules enable, restart and reload services without caring which init
+//! system the image uses. [`ServiceManager`] builds the commands for
+//! systemd (`systemctl`) or OpenRC (`rc-update`/`rc-service`, as on
+//! Alpine).
+//!
+//! On the running system the manager is the one that booted it; under an
+//! alternate root it is the one installed there, systemd if both are.
+
+use crate::root::RootContext;
+use crate::runner::SystemCommand;
+use std::path::Path;
+
+/// Present while systemd is the running init
+const SYSTEMD_RUN_DIR: &str = "/run/systemd/system";
+
+/// Present while OpenRC is the running init
+const OPENRC_RUN_DIR: &str = "/run/openrc";
+
+/// The systemd binary, under either usual prefix
+const SYSTEMD_BINARIES: &[&str] = &["/lib/systemd/systemd", "/usr/lib/systemd/systemd"];
+
+/// Interpreter of OpenRC init scripts
+const OPENRC_RUN: &str = "/sbin/openrc-run";
+
+/// Runlevel services are enabled in under OpenRC
+const OPENRC_RUNLEVEL: &str = "default";
+
+/// Init system that manages services
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceManager {
+    Systemd,
+    OpenRc,
+}
+
+impl ServiceManager {
+    /// The service manager of the system at `root`
+    ///
+    /// Defaults to systemd when neither can be found.
+    pub fn detect(root: &RootContext) -> Self {
+        if root.is_host() {
+            if Path::new(SYSTEMD_RUN_DIR).exists() {
+                return Self::Systemd;
+            }
+            if Path::new(OPENRC_RUN_DIR).exists() {
+                return Self::OpenRc;
+            }
+        }
+        let systemd = SYSTEMD_BINARIES.iter().any(|path| root.path(path).exists());
+        if !systemd && root.path(OPENRC_RUN).exists() {
+            Self::OpenRc
+        } else {
+            Self::Systemd
+        }
+    }
+
+    /// Start `service` on every boot
+    pub fn enable(self, service: &str) -> SystemCommand {
+        match self {
+            Self::Systemd => SystemCommand::new("systemctl").args(["enable", service]),
+            Self::OpenRc => SystemCommand::new("rc-update").args(["add", service, OPENRC_RUNLEVEL]),
+        }
+    }
+
+    /// Restart `service`, starting it if it is stopped
+    pub fn restart(self, service: &str) -> SystemCommand {
+        match self {
+            Self::Systemd => SystemCommand::new("systemctl").args(["restart", service]),
+            Self::OpenRc => SystemCommand::new("rc-service").args([service, "restart"]),
+        }
+    }
+
+    /// Restart `service` if it is running; a stopped one stays stopped
+    pub fn try_restart(self, service: &str) -> SystemCommand {
+        match self {
+            Self::Systemd => SystemCommand::new("systemctl").args(["try-restart", service]),
+            Self::OpenRc => {
+                SystemCommand::new("rc-service").args(["--ifstarted", service, "restart"])
+            }
+        }
+    }
+
+    /// Make a running `service` read its configuration again; a stopped
+    /// one stays stopped
+    pub fn try_reload(self, service: &str) -> SystemCommand {
+        match self {
+            Self::Systemd => {
+                SystemCommand::new("systemctl").args(["try-reload-or-restart", service])
+            }
+            Self::OpenRc => {
+                SystemCommand::new("rc-service").args(["--ifstarted", service, "reload"])
+            }
+        }
+    }
+
+    /// Probe whether `service` is set up to run
+    ///
+    /// OpenRC only answers whether its init script exists.
+    pub fn is_enabled(self, service: &str) -> SystemCommand {
+        match self {
+            Self::Systemd => SystemCommand::new("systemctl").args(["is-enabled", service]),
+            Self::OpenRc => SystemCommand::new("rc-service").args(["--exists", service]),
+        }
+        .probe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_detect_under_root() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        assert_eq!(ServiceManager::detect(&root), ServiceManager::Systemd);
+
+        std::fs::create_dir_all(root.path("/sbin")).unwrap();
+        std::fs::write(root.path(OPENRC_RUN), "").unwrap();
+        assert_eq!(ServiceManager::detect(&root), ServiceManager::OpenRc);
+
+        std::fs::create_dir_all(root.path("/usr/lib/systemd")).unwrap();
+        std::fs::write(root.path("/usr/lib/systemd/systemd"), "").unwrap();
+        assert_eq!(ServiceManager::detect(&root), ServiceManager::Systemd);
+    }
+
+    #[test]
+    fn test_commands() {
+        let openrc = ServiceManager::OpenRc;
+        assert_eq!(
+            openrc.enable("sshd").to_string(),
+            "rc-update add sshd default"
+        );
+        assert_eq!(
+            openrc.restart("chronyd").to_string(),
+            "rc-service chronyd restart"
+        );
+        assert_eq!(
+            openrc.try_reload("sshd").to_string(),
+            "rc-service --ifstarted sshd reload"
+        );
+        assert_eq!(
+            openrc.try_restart("rsyslog").to_string(),
+            "rc-service --ifstarted rsyslog restart"
+        );
+
+        let systemd = ServiceManager::Systemd;
+        assert_eq!(systemd.enable("ssh").to_string(), "systemctl enable ssh");
+        assert_eq!(
+            systemd.try_reload("ssh").to_string(),
+            "systemctl try-reload-or-restart ssh"
+        );
+        assert_eq!(
+            systemd.try_restart("rsyslog").to_string(),
+            "systemctl try-restart rsyslog"
+        );
+    }
+}
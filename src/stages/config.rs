@@ -1,158 +1,729 @@
 //! Config stage - applies user configuration
 //!
 //! Responsibilities:
+//! - Ship logs off-box (rsyslog remotes, journald upload) before anything else
+//! - Set the hostname and keep it and /etc/hosts current every boot
+//! - Add `mounts` to /etc/fstab, resolving device aliases such as `ephemeral0`
 //! - Create users and groups
 //! - Install packages
 //! - Write files (write_files directive)
 //! - Configure services
+//! - On Flatcar and Fedora CoreOS, turn packages and runcmd into a unit
 
+use super::{ModuleErrors, ModuleTask};
 use crate::CloudInitError;
-use crate::config::CloudConfig;
+#[cfg(feature = "mod-packages")]
+use crate::actions::Action;
+#[cfg(any(feature = "mod-packages", feature = "plugins-wasm"))]
+use crate::config::load_merged_config;
+use crate::config::{CloudConfig, ManageEtcHosts, UserConfig, merge};
+use crate::datasources::cache;
+use crate::modules::container_os::{self, ContainerOs};
+#[cfg(feature = "mod-grub-dpkg")]
+use crate::modules::grub_dpkg;
+#[cfg(feature = "mod-landscape")]
+use crate::modules::landscape;
+use crate::modules::plugins::PluginKind;
+#[cfg(feature = "mod-rh-subscription")]
+use crate::modules::rh_subscription;
+#[cfg(feature = "mod-suse-register")]
+use crate::modules::suse_register;
+#[cfg(feature = "mod-ubuntu-pro")]
+use crate::modules::ubuntu_pro;
+#[cfg(feature = "plugins-wasm")]
+use crate::modules::wasm_plugins;
+#[cfg(feature = "mod-wireguard")]
+use crate::modules::wireguard;
+#[cfg(feature = "mod-yum-add-repo")]
+use crate::modules::yum_add_repo;
+#[cfg(feature = "mod-zypper-add-repo")]
+use crate::modules::zypper_add_repo;
 use crate::modules::{
-    groups, hostname, locale, packages, rh_subscription, timezone, users, write_files, yum_add_repo,
+    groups, hostname, journald_upload, locale, mounts, plugins, random_seed, rsyslog, ssh_keys,
+    sshd_config, timezone, users, write_files,
 };
-use crate::state::InstanceState;
+#[cfg(feature = "mod-packages")]
+use crate::modules::{package_mirrors, packages};
+use crate::reporting::Reporter;
+use crate::root::RootContext;
+use crate::state::{CloudPaths, InstanceState};
 use tokio::fs;
 use tracing::{debug, info, warn};
 
+/// Modules that configure the system before `write_files` runs
+const SYSTEM_MODULES: &[&str] = &[
+    "rsyslog",
+    "journald_upload",
+    "seed_random",
+    "ssh_host_keys",
+    "set_hostname",
+    "update_hostname",
+    "update_etc_hosts",
+    "timezone",
+    "locale",
+    "mounts",
+    "groups",
+    "users",
+    "sshd_config",
+];
+
+/// Modules that set up package sources before packages are installed
+#[cfg(feature = "mod-packages")]
+const REPOSITORY_MODULES: &[&str] = &[
+    "write_files",
+    "package_mirrors",
+    "rh_subscription",
+    "suse_register",
+    "ubuntu_pro",
+    "yum_add_repo",
+    "zypper_add_repo",
+];
+
 /// Run the config stage
-pub async fn run() -> Result<(), CloudInitError> {
+pub async fn run(reporter: &Reporter, modules: &mut ModuleErrors) -> Result<(), CloudInitError> {
     info!("Config stage: applying user configuration");
 
     // Load cloud-config from instance state
     let config = load_cloud_config().await?;
+    warn_uncompiled_modules(&config);
+    let root = &RootContext::current();
+
+    // Modules start in this order; with `module_parallelism` above 1 each
+    // starts as soon as the modules it is declared after are done
+    let mut tasks = vec![
+        // 0. Remote logging, so the rest of provisioning is shipped off-box
+        ModuleTask::new("rsyslog", "configure rsyslog", apply_rsyslog(root, &config)),
+        ModuleTask::new(
+            "journald_upload",
+            "configure journal upload",
+            apply_journald_upload(root, &config),
+        ),
+        // 1. Random seed, so the host keys below get fresh entropy
+        ModuleTask::new(
+            "seed_random",
+            "seed random number generator",
+            apply_random_seed(root, &config),
+        ),
+        // 2. SSH host keys, so instances cloned from an image differ
+        ModuleTask::new(
+            "ssh_host_keys",
+            "regenerate SSH host keys",
+            apply_ssh_host_keys(root, &config),
+        )
+        .after(&["seed_random"]),
+        // 3. System configuration (hostname, timezone, locale)
+        ModuleTask::new(
+            "set_hostname",
+            "set hostname",
+            apply_hostname(root, &config),
+        ),
+        ModuleTask::new(
+            "update_hostname",
+            "update hostname",
+            apply_update_hostname(root, &config),
+        )
+        .after(&["set_hostname"]),
+        ModuleTask::new(
+            "update_etc_hosts",
+            "update /etc/hosts",
+            apply_update_etc_hosts(root, &config),
+        )
+        .after(&["set_hostname", "update_hostname"]),
+        ModuleTask::new("timezone", "set timezone", apply_timezone(root, &config)),
+        ModuleTask::new("locale", "set locale", apply_locale(root, &config)),
+        // Ephemeral disks and swap, before files are written to them
+        ModuleTask::new("mounts", "configure mounts", apply_mounts(root, &config)),
+        // 4. Groups (before users, so users can be added to groups)
+        ModuleTask::new("groups", "create groups", apply_groups(root, &config)),
+        // 5. Users, whose home directories may be on a mounted disk
+        ModuleTask::new("users", "create users", apply_users(root, &config))
+            .after(&["groups", "mounts"]),
+        // 6. SSH server settings and root's keys, once users exist
+        ModuleTask::new(
+            "sshd_config",
+            "configure sshd",
+            apply_sshd_config(root, &config),
+        )
+        .after(&["users", "ssh_host_keys"]),
+        // 7. Write files (non-deferred), which may replace any file above
+        ModuleTask::new(
+            "write_files",
+            "write files",
+            apply_write_files(root, &config, false),
+        )
+        .after(SYSTEM_MODULES),
+    ];
+
+    // 8. Red Hat / SUSE subscription and Ubuntu Pro (before packages, so
+    // repos are available)
+    #[cfg(feature = "mod-rh-subscription")]
+    tasks.push(
+        ModuleTask::new(
+            "rh_subscription",
+            "register Red Hat subscription",
+            apply_rh_subscription(root, &config),
+        )
+        .after(&["write_files"]),
+    );
+    #[cfg(feature = "mod-suse-register")]
+    tasks.push(
+        ModuleTask::new(
+            "suse_register",
+            "register with SUSEConnect",
+            apply_suse_register(root, &config),
+        )
+        .after(&["write_files"]),
+    );
+    #[cfg(feature = "mod-ubuntu-pro")]
+    tasks.push(
+        ModuleTask::new(
+            "ubuntu_pro",
+            "attach Ubuntu Pro",
+            apply_ubuntu_pro(root, &config),
+        )
+        .after(&["write_files"]),
+    );
 
-    // Apply configuration modules in order
-    // 1. System configuration (hostname, timezone, locale)
-    apply_system_config(&config).await?;
+    // 9. YUM and zypper repositories (before package installation)
+    #[cfg(feature = "mod-yum-add-repo")]
+    tasks.push(
+        ModuleTask::new(
+            "yum_add_repo",
+            "add yum repositories",
+            apply_yum_repos(root, &config),
+        )
+        .after(&["write_files"]),
+    );
+    #[cfg(feature = "mod-zypper-add-repo")]
+    tasks.push(
+        ModuleTask::new(
+            "zypper_add_repo",
+            "add zypper repositories",
+            apply_zypper(root, &config),
+        )
+        .after(&["write_files"]),
+    );
 
-    // 2. Groups (before users, so users can be added to groups)
-    apply_groups(&config).await?;
+    // 10. Package mirrors for this region, then package management
+    #[cfg(feature = "mod-packages")]
+    tasks.push(
+        ModuleTask::new(
+            "package_mirrors",
+            "select package mirrors",
+            apply_package_mirrors(root),
+        )
+        .after(&["write_files"]),
+    );
+    #[cfg(feature = "mod-packages")]
+    tasks.push(
+        ModuleTask::new(
+            "package_update_upgrade_install",
+            "install packages",
+            apply_packages(root, &config),
+        )
+        .after(REPOSITORY_MODULES),
+    );
 
-    // 3. Users
-    apply_users(&config).await?;
+    // 11. Write files (deferred - after packages installed)
+    tasks.push(
+        ModuleTask::new(
+            "write_files_deferred",
+            "write deferred files",
+            apply_write_files(root, &config, true),
+        )
+        .after(&[
+            "write_files",
+            "rh_subscription",
+            "suse_register",
+            "ubuntu_pro",
+            "yum_add_repo",
+            "zypper_add_repo",
+            "package_mirrors",
+            "package_update_upgrade_install",
+        ]),
+    );
 
-    // 4. Write files (non-deferred)
-    apply_write_files(&config, false).await?;
+    // 12-14 install packages of their own, so they run one after another
+    // 12. WireGuard tunnels, once packages and key files are in place
+    #[cfg(feature = "mod-wireguard")]
+    tasks.push(
+        ModuleTask::new(
+            "wireguard",
+            "configure wireguard",
+            apply_wireguard(root, &config),
+        )
+        .after(&["write_files_deferred"]),
+    );
 
-    // 5. Red Hat subscription (before packages, so repos are available)
-    apply_rh_subscription(&config).await?;
+    // 13. Landscape client registration
+    #[cfg(feature = "mod-landscape")]
+    tasks.push(
+        ModuleTask::new(
+            "landscape",
+            "configure landscape",
+            apply_landscape(root, &config),
+        )
+        .after(&["write_files_deferred", "wireguard"]),
+    );
 
-    // 6. YUM repositories (before package installation)
-    apply_yum_repos(&config).await?;
+    // 14. Bootloader: GRUB install device and serial console
+    #[cfg(feature = "mod-grub-dpkg")]
+    tasks.push(
+        ModuleTask::new("grub_dpkg", "configure grub", apply_grub(root, &config)).after(&[
+            "write_files_deferred",
+            "wireguard",
+            "landscape",
+        ]),
+    );
 
-    // 7. Package management
-    apply_packages(&config).await?;
+    // 15. Container-optimized OSes: packages and runcmd as a unit
+    tasks.push(
+        ModuleTask::new(
+            "container_os",
+            "generate compat unit",
+            apply_container_os(root, &config),
+        )
+        .after(&["write_files_deferred"]),
+    );
 
-    // 8. Write files (deferred - after packages installed)
-    apply_write_files(&config, true).await?;
+    modules.run_all(reporter, tasks).await;
+
+    // 16. Drop-in plugin modules, one after another
+    let plugins = plugins::discover(root).await?;
+    if !plugins.is_empty() {
+        let raw = load_cloud_config_json().await?;
+        #[cfg(feature = "plugins-wasm")]
+        let system = load_merged_config(&root.cloud_paths())
+            .await
+            .unwrap_or_default();
+        let runner = root.runner();
+        for plugin in &plugins {
+            let run = async {
+                match plugin.kind {
+                    PluginKind::Executable => plugins::run(runner.as_ref(), plugin, &raw).await,
+                    #[cfg(feature = "plugins-wasm")]
+                    PluginKind::Wasm => {
+                        let permissions = wasm_plugins::Permissions::from_config(&system, plugin);
+                        wasm_plugins::run(runner.as_ref(), root, plugin, &raw, permissions).await
+                    }
+                    #[cfg(not(feature = "plugins-wasm"))]
+                    PluginKind::Wasm => Ok(()),
+                }
+            };
+            modules
+                .run(reporter, &plugin.name, "run plugin module", run)
+                .await;
+        }
+    }
 
     info!("Config stage: completed");
     Ok(())
 }
 
 /// Load cloud-config from instance state directory
-async fn load_cloud_config() -> Result<CloudConfig, CloudInitError> {
+///
+/// Vendor-data cloud-config is merged underneath, so user-data adds to
+/// it and wins where both set a key.
+pub(crate) async fn load_cloud_config() -> Result<CloudConfig, CloudInitError> {
     debug!("Loading cloud-config");
 
     let mut state = InstanceState::new();
 
     // Try to load cached instance ID
-    if let Some(instance_id) = state.load_cached_instance_id().await? {
-        debug!("Found cached instance ID: {}", instance_id);
+    let Some(instance_id) = state.load_cached_instance_id().await? else {
+        debug!("No cloud-config found, using defaults");
+        return Ok(CloudConfig::default());
+    };
+    debug!("Found cached instance ID: {}", instance_id);
+    let paths = state.paths();
+
+    let user = load_user_config(paths, &instance_id).await?;
+    let vendor_path = paths.vendor_data(&instance_id);
+    let vendor = if vendor_path.exists() {
+        let content = fs::read_to_string(&vendor_path).await?;
+        match CloudConfig::from_yaml(&content) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                warn!("Ignoring unparseable vendor-data: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    Ok(match (vendor, user) {
+        (Some(vendor), Some(user)) => merge::merge_configs(&vendor, &user),
+        (vendor, user) => user.or(vendor).unwrap_or_else(|| {
+            debug!("No cloud-config found, using defaults");
+            CloudConfig::default()
+        }),
+    })
+}
+
+/// Load the merged cloud-config as JSON for plugins
+///
+/// Unlike [`load_cloud_config`] this keeps keys cloud-init-rs does not
+/// know, which is where plugins find their settings.
+async fn load_cloud_config_json() -> Result<serde_json::Value, CloudInitError> {
+    let mut state = InstanceState::new();
+    let mut merged = serde_yaml::Value::Mapping(Default::default());
+    let Some(instance_id) = state.load_cached_instance_id().await? else {
+        return Ok(serde_json::to_value(&merged)?);
+    };
+    let paths = state.paths();
+
+    let userdata_path = paths.user_data(&instance_id);
+    let user_path = match paths.cloud_config(&instance_id) {
+        path if path.exists() => Some(path),
+        _ if userdata_path.exists()
+            && CloudConfig::is_cloud_config(&fs::read_to_string(&userdata_path).await?) =>
+        {
+            Some(userdata_path)
+        }
+        _ => None,
+    };
+    for path in [Some(paths.vendor_data(&instance_id)), user_path]
+        .into_iter()
+        .flatten()
+        .filter(|path| path.exists())
+    {
+        let content = fs::read_to_string(&path).await?;
+        match serde_yaml::from_str::<serde_yaml::Value>(&content) {
+            Ok(serde_yaml::Value::Null) => {}
+            Ok(value) => {
+                merged = merge::merge_yaml_values(&merged, &value, merge::ListMergeStrategy::Append)
+            }
+            Err(e) => warn!("Ignoring unparseable {}: {}", path.display(), e),
+        }
+    }
+    Ok(serde_json::to_value(&merged)?)
+}
 
-        // Try to read cloud-config from instance directory
-        let paths = state.paths();
-        let config_path = paths.cloud_config(&instance_id);
+/// The instance's user cloud-config, if it has one
+async fn load_user_config(
+    paths: &CloudPaths,
+    instance_id: &str,
+) -> Result<Option<CloudConfig>, CloudInitError> {
+    // Try to read cloud-config from instance directory
+    let config_path = paths.cloud_config(instance_id);
+    if config_path.exists() {
+        let content = fs::read_to_string(&config_path).await?;
+        return parse_reporting(&content, "cloud-config").map(Some);
+    }
 
-        if config_path.exists() {
-            let content = fs::read_to_string(&config_path).await?;
-            return CloudConfig::from_yaml(&content).map_err(|e| {
-                CloudInitError::InvalidData(format!("Failed to parse cloud-config: {}", e))
-            });
+    // Try user-data as fallback
+    let userdata_path = paths.user_data(instance_id);
+    if userdata_path.exists() {
+        let content = fs::read_to_string(&userdata_path).await?;
+        if CloudConfig::is_cloud_config(&content) {
+            return parse_reporting(&content, "user-data").map(Some);
         }
+    }
+    Ok(None)
+}
 
-        // Try user-data as fallback
-        let userdata_path = paths.user_data(&instance_id);
-        if userdata_path.exists() {
-            let content = fs::read_to_string(&userdata_path).await?;
-            if CloudConfig::is_cloud_config(&content) {
-                return CloudConfig::from_yaml(&content).map_err(|e| {
-                    CloudInitError::InvalidData(format!("Failed to parse user-data: {}", e))
-                });
-            }
+/// Parse user cloud-config, warning about keys that have no effect
+fn parse_reporting(content: &str, what: &str) -> Result<CloudConfig, CloudInitError> {
+    let (config, ignored) = CloudConfig::from_yaml_reporting(content)
+        .map_err(|e| CloudInitError::InvalidData(format!("Failed to parse {}: {}", what, e)))?;
+    if !ignored.is_empty() {
+        warn!(
+            "Ignoring unsupported {} key(s): {}",
+            what,
+            ignored.join(", ")
+        );
+    }
+    Ok(config)
+}
+
+/// Warn about configuration for modules left out of this build
+fn warn_uncompiled_modules(config: &CloudConfig) {
+    let optional = [
+        (
+            "packages",
+            cfg!(feature = "mod-packages"),
+            !config.packages.is_empty()
+                || config.package_update == Some(true)
+                || config.package_upgrade == Some(true),
+        ),
+        (
+            "ansible",
+            cfg!(feature = "mod-ansible"),
+            config.ansible.is_some(),
+        ),
+        ("chef", cfg!(feature = "mod-chef"), config.chef.is_some()),
+        (
+            "puppet",
+            cfg!(feature = "mod-puppet"),
+            config.puppet.is_some(),
+        ),
+        (
+            "salt_minion",
+            cfg!(feature = "mod-salt-minion"),
+            config.salt_minion.is_some(),
+        ),
+        (
+            "rh_subscription",
+            cfg!(feature = "mod-rh-subscription"),
+            config.rh_subscription.is_some(),
+        ),
+        (
+            "suse_register",
+            cfg!(feature = "mod-suse-register"),
+            config.suse_register.is_some(),
+        ),
+        (
+            "ubuntu_pro",
+            cfg!(feature = "mod-ubuntu-pro"),
+            config.ubuntu_pro.is_some(),
+        ),
+        (
+            "landscape",
+            cfg!(feature = "mod-landscape"),
+            config.landscape.is_some(),
+        ),
+        (
+            "grub_dpkg",
+            cfg!(feature = "mod-grub-dpkg"),
+            config.grub_dpkg.is_some() || config.serial_console.is_some(),
+        ),
+        (
+            "wireguard",
+            cfg!(feature = "mod-wireguard"),
+            config.wireguard.is_some(),
+        ),
+        (
+            "yum_add_repo",
+            cfg!(feature = "mod-yum-add-repo"),
+            !config.yum_repos.is_empty(),
+        ),
+        (
+            "zypper_add_repo",
+            cfg!(feature = "mod-zypper-add-repo"),
+            config.zypper.is_some(),
+        ),
+        (
+            "byobu_by_default",
+            cfg!(feature = "mod-byobu"),
+            config.byobu_by_default.is_some(),
+        ),
+        ("fan", cfg!(feature = "mod-fan"), config.fan.is_some()),
+        (
+            "gce_guest_attributes",
+            cfg!(feature = "mod-gce-guest-attributes"),
+            config.gce_guest_attributes.is_some(),
+        ),
+    ];
+
+    for (module, compiled, configured) in optional {
+        if configured && !compiled {
+            warn!(
+                "Ignoring {} configuration: module not compiled into this build",
+                module
+            );
         }
     }
+}
+
+/// Apply hostname configuration
+async fn apply_hostname(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
+    if config.hostname.is_some() || config.fqdn.is_some() {
+        debug!("Configuring hostname");
+        hostname::configure_hostname(root, config).await?;
+    }
+    Ok(())
+}
 
-    // Return empty config if nothing found
-    debug!("No cloud-config found, using defaults");
-    Ok(CloudConfig::default())
+/// Re-apply the hostname every boot (`update_hostname`)
+async fn apply_update_hostname(
+    root: &RootContext,
+    config: &CloudConfig,
+) -> Result<(), CloudInitError> {
+    if config.preserve_hostname == Some(true) {
+        return Ok(());
+    }
+    let metadata_hostname = metadata_hostname(config).await;
+    let previous = CloudPaths::new().previous_hostname();
+    hostname::update_hostname(root, config, metadata_hostname.as_deref(), &previous).await
 }
 
-/// Apply system configuration (hostname, timezone, locale)
-async fn apply_system_config(config: &CloudConfig) -> Result<(), CloudInitError> {
-    // Set hostname
-    if let Some(ref name) = config.hostname {
-        debug!("Setting hostname to: {}", name);
-        let manage_hosts = config.manage_etc_hosts.unwrap_or(false);
-        if let Err(e) =
-            hostname::set_hostname_fqdn(name, config.fqdn.as_deref(), manage_hosts).await
-        {
-            warn!("Failed to set hostname: {}", e);
+/// Re-render /etc/hosts every boot (`update_etc_hosts`)
+async fn apply_update_etc_hosts(
+    root: &RootContext,
+    config: &CloudConfig,
+) -> Result<(), CloudInitError> {
+    if config.manage_etc_hosts.unwrap_or_default() == ManageEtcHosts::Disabled {
+        return Ok(());
+    }
+    let metadata_hostname = metadata_hostname(config).await;
+    hostname::sync_etc_hosts(root, config, metadata_hostname.as_deref()).await
+}
+
+/// The metadata's hostname, only looked up when cloud-config names none
+async fn metadata_hostname(config: &CloudConfig) -> Option<String> {
+    if config.hostname.is_some() || config.fqdn.is_some() {
+        return None;
+    }
+    let metadata = match cache::stage_datasource().await {
+        Ok(ds) => ds.get_metadata().await,
+        Err(e) => Err(e),
+    };
+    match metadata {
+        Ok(metadata) => metadata.local_hostname,
+        Err(e) => {
+            debug!("No metadata hostname: {}", e);
+            None
         }
     }
+}
+
+/// Add `mounts` to /etc/fstab, resolving aliases against the metadata
+async fn apply_mounts(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
+    let metadata = match cache::stage_datasource().await {
+        Ok(ds) => ds.get_metadata().await,
+        Err(e) => Err(e),
+    };
+    // Without metadata only device names, not aliases, resolve
+    let metadata = metadata.unwrap_or_else(|e| {
+        debug!("No metadata for device aliases: {}", e);
+        Default::default()
+    });
+    mounts::configure_mounts(
+        root.runner().as_ref(),
+        root,
+        config.mounts.as_deref(),
+        config.mount_default_fields.as_deref(),
+        &metadata,
+    )
+    .await
+}
 
-    // Set timezone
+/// Apply timezone configuration
+async fn apply_timezone(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
     if let Some(ref tz) = config.timezone {
         debug!("Setting timezone to: {}", tz);
-        if let Err(e) = timezone::set_timezone(tz).await {
-            warn!("Failed to set timezone: {}", e);
-        }
+        timezone::set_timezone(root, tz).await?;
     }
+    Ok(())
+}
 
-    // Set locale
+/// Apply locale configuration
+async fn apply_locale(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
     if let Some(ref loc) = config.locale {
         debug!("Setting locale to: {}", loc);
-        if let Err(e) = locale::set_locale(loc).await {
-            warn!("Failed to set locale: {}", e);
-        }
+        locale::configure_locale(root, loc, config.locale_configfile.as_deref()).await?;
     }
-
     Ok(())
 }
 
 /// Apply group configuration
-async fn apply_groups(config: &CloudConfig) -> Result<(), CloudInitError> {
+async fn apply_groups(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
     if config.groups.is_empty() {
         return Ok(());
     }
 
     debug!("Creating {} groups", config.groups.len());
 
-    if let Err(e) = groups::create_groups(&config.groups).await {
-        warn!("Failed to create groups: {}", e);
-    }
-
-    Ok(())
+    groups::create_groups(root.runner().as_ref(), root, &config.groups).await
 }
 
 /// Apply user configuration
-async fn apply_users(config: &CloudConfig) -> Result<(), CloudInitError> {
-    if config.users.is_empty() {
+/// Replace the image's SSH host keys
+///
+/// Skipped under an alternate root: keys generated into an image would be
+/// shared by every instance booted from it.
+async fn apply_ssh_host_keys(
+    root: &RootContext,
+    config: &CloudConfig,
+) -> Result<(), CloudInitError> {
+    if !root.is_host() {
+        debug!("Not generating SSH host keys under alternate root");
         return Ok(());
     }
+    let key_types = config.ssh_genkeytypes.clone().unwrap_or_else(|| {
+        ssh_keys::DEFAULT_HOST_KEY_TYPES
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    });
+    ssh_keys::regenerate_host_keys(
+        root.runner().as_ref(),
+        root,
+        config.ssh_deletekeys.unwrap_or(true),
+        &key_types,
+    )
+    .await
+}
+
+async fn apply_users(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
+    if config.users.is_empty() && config.user.is_none() {
+        return Ok(());
+    }
+
+    debug!("Creating {} users", config.users.len().max(1));
+
+    // Platform keys are only needed to redirect logins to the default user
+    let redirect = config
+        .users
+        .iter()
+        .any(|user| matches!(user, UserConfig::Full(user) if user.ssh_redirect_user == Some(true)));
+    let public_keys = if redirect {
+        let metadata = match cache::stage_datasource().await {
+            Ok(ds) => ds.get_metadata().await,
+            Err(e) => Err(e),
+        };
+        metadata.map(|m| m.public_keys()).unwrap_or_else(|e| {
+            debug!("No metadata for redirected SSH keys: {}", e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+
+    users::create_users(root.runner().as_ref(), root, config, &public_keys).await
+}
 
-    debug!("Creating {} users", config.users.len());
+/// Write rsyslog configuration and remotes
+async fn apply_rsyslog(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
+    if let Some(rsyslog) = &config.rsyslog {
+        rsyslog::configure_rsyslog(root.runner().as_ref(), root, rsyslog).await?;
+    }
+    Ok(())
+}
 
-    if let Err(e) = users::create_users(&config.users).await {
-        warn!("Failed to create users: {}", e);
+/// Point systemd-journal-upload at a remote server
+async fn apply_journald_upload(
+    root: &RootContext,
+    config: &CloudConfig,
+) -> Result<(), CloudInitError> {
+    if let Some(upload) = &config.journald_upload {
+        journald_upload::configure_journald_upload(root.runner().as_ref(), root, upload).await?;
     }
+    Ok(())
+}
 
+/// Seed the random number generator
+async fn apply_random_seed(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
+    if let Some(seed) = &config.random_seed {
+        random_seed::seed_random(root.runner().as_ref(), root, seed).await?;
+    }
     Ok(())
 }
 
+/// Apply sshd settings and install root's authorized keys
+async fn apply_sshd_config(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
+    let runner = root.runner();
+    sshd_config::configure_sshd(runner.as_ref(), root, config).await?;
+    sshd_config::configure_root_keys(runner.as_ref(), root, config).await
+}
+
 /// Apply write_files configuration
-async fn apply_write_files(config: &CloudConfig, deferred: bool) -> Result<(), CloudInitError> {
+async fn apply_write_files(
+    root: &RootContext,
+    config: &CloudConfig,
+    deferred: bool,
+) -> Result<(), CloudInitError> {
     let files: Vec<_> = config
         .write_files
         .iter()
@@ -169,45 +740,164 @@ async fn apply_write_files(config: &CloudConfig, deferred: bool) -> Result<(), C
         if deferred { "deferred" } else { "immediate" }
     );
 
+    // Keep going after a failure so one bad entry does not drop the rest
+    let total = files.len();
+    let mut failed = Vec::new();
     for file_config in files {
-        if let Err(e) = write_files::write_file(file_config).await {
+        if let Err(e) = write_files::write_file(root, file_config).await {
             warn!("Failed to write file {}: {}", file_config.path, e);
+            failed.push(file_config.path.as_str());
         }
     }
 
-    Ok(())
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(CloudInitError::module(
+            "write_files",
+            format!(
+                "{} of {} files failed: {}",
+                failed.len(),
+                total,
+                failed.join(", ")
+            ),
+        ))
+    }
 }
 
 /// Apply Red Hat subscription configuration
-async fn apply_rh_subscription(config: &CloudConfig) -> Result<(), CloudInitError> {
+#[cfg(feature = "mod-ubuntu-pro")]
+async fn apply_ubuntu_pro(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
+    if let Some(ref pro) = config.ubuntu_pro {
+        ubuntu_pro::configure_ubuntu_pro(root.runner().as_ref(), root, pro).await?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "mod-landscape")]
+async fn apply_landscape(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
+    if let Some(ref landscape) = config.landscape {
+        landscape::configure_landscape(root.runner().as_ref(), root, landscape).await?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "mod-grub-dpkg")]
+async fn apply_grub(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
+    let runner = root.runner();
+    if let Some(ref grub) = config.grub_dpkg {
+        grub_dpkg::configure_grub_dpkg(runner.as_ref(), root, grub).await?;
+    }
+    if let Some(ref serial) = config.serial_console {
+        grub_dpkg::configure_serial_console(runner.as_ref(), root, serial).await?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "mod-wireguard")]
+async fn apply_wireguard(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
+    if let Some(ref wg) = config.wireguard {
+        wireguard::configure_wireguard(root.runner().as_ref(), root, wg).await?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "mod-rh-subscription")]
+async fn apply_rh_subscription(
+    root: &RootContext,
+    config: &CloudConfig,
+) -> Result<(), CloudInitError> {
     if let Some(ref rh_sub) = config.rh_subscription {
         debug!("Configuring Red Hat subscription");
-        if let Err(e) = rh_subscription::configure_rh_subscription(rh_sub).await {
-            warn!("Failed to configure rh_subscription: {}", e);
-        }
+        rh_subscription::configure_rh_subscription(root.runner().as_ref(), rh_sub).await?;
+    }
+    Ok(())
+}
+
+/// Register with the SUSE Customer Center or an RMT server
+#[cfg(feature = "mod-suse-register")]
+async fn apply_suse_register(
+    root: &RootContext,
+    config: &CloudConfig,
+) -> Result<(), CloudInitError> {
+    if let Some(ref suse) = config.suse_register {
+        debug!("Configuring SUSE registration");
+        suse_register::configure_suse_register(root.runner().as_ref(), root, suse).await?;
     }
     Ok(())
 }
 
 /// Apply YUM repository configuration
-async fn apply_yum_repos(config: &CloudConfig) -> Result<(), CloudInitError> {
+#[cfg(feature = "mod-yum-add-repo")]
+async fn apply_yum_repos(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
     if config.yum_repos.is_empty() {
         return Ok(());
     }
 
     debug!("Adding {} YUM repo(s)", config.yum_repos.len());
-    if let Err(e) = yum_add_repo::add_yum_repos(&config.yum_repos).await {
-        warn!("Failed to add YUM repos: {}", e);
+    yum_add_repo::add_yum_repos(root, &config.yum_repos).await
+}
+
+/// Apply zypper repositories and settings
+#[cfg(feature = "mod-zypper-add-repo")]
+async fn apply_zypper(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
+    let Some(zypper) = &config.zypper else {
+        return Ok(());
+    };
+    debug!("Adding {} zypper repo(s)", zypper.repos.len());
+    zypper_add_repo::configure_zypper(root, zypper).await
+}
+
+/// Point package sources at the mirrors for this instance's region
+#[cfg(feature = "mod-packages")]
+async fn apply_package_mirrors(root: &RootContext) -> Result<(), CloudInitError> {
+    let system = load_merged_config(&root.cloud_paths()).await?;
+    if system.package_mirrors.is_empty() {
+        return Ok(());
     }
-    Ok(())
+    let metadata = match cache::stage_datasource().await {
+        Ok(ds) => ds.get_metadata().await,
+        Err(e) => Err(e),
+    };
+    // Without metadata only templates free of placeholders can match
+    let metadata = metadata.unwrap_or_else(|e| {
+        debug!("No metadata for package mirrors: {}", e);
+        Default::default()
+    });
+    package_mirrors::configure(
+        root.runner().as_ref(),
+        root,
+        &system.package_mirrors,
+        &metadata,
+    )
+    .await
+}
+
+async fn apply_container_os(
+    root: &RootContext,
+    config: &CloudConfig,
+) -> Result<(), CloudInitError> {
+    let Some(os) = ContainerOs::detect(root).await else {
+        return Ok(());
+    };
+    container_os::configure_container_os(root.runner().as_ref(), root, os, config).await
 }
 
 /// Apply package configuration
-async fn apply_packages(config: &CloudConfig) -> Result<(), CloudInitError> {
+#[cfg(feature = "mod-packages")]
+async fn apply_packages(root: &RootContext, config: &CloudConfig) -> Result<(), CloudInitError> {
+    if let Some(os) = ContainerOs::detect(root).await {
+        debug!(
+            "No package manager on {}; packages go into the compat unit",
+            os
+        );
+        return Ok(());
+    }
+
     // Update package cache if requested
     if config.package_update == Some(true) {
         info!("Updating package cache");
-        if let Err(e) = packages::update_package_cache().await {
+        if let Err(e) = packages::update_package_cache(root.runner().as_ref(), root).await {
             warn!("Failed to update package cache: {}", e);
             // Continue anyway - package install might still work
         }
@@ -216,7 +906,7 @@ async fn apply_packages(config: &CloudConfig) -> Result<(), CloudInitError> {
     // Upgrade packages if requested
     if config.package_upgrade == Some(true) {
         info!("Upgrading packages");
-        if let Err(e) = packages::upgrade_packages().await {
+        if let Err(e) = packages::upgrade_packages(root.runner().as_ref(), root).await {
             warn!("Failed to upgrade packages: {}", e);
         }
     }
@@ -224,7 +914,12 @@ async fn apply_packages(config: &CloudConfig) -> Result<(), CloudInitError> {
     // Install packages
     if !config.packages.is_empty() {
         info!("Installing {} packages", config.packages.len());
-        packages::install_packages(&config.packages).await?;
+        if let Some(recorder) = root.recorder() {
+            recorder.record(Action::InstallPackages {
+                packages: config.packages.clone(),
+            });
+        }
+        packages::install_packages(root.runner().as_ref(), root, &config.packages).await?;
     }
 
     Ok(())
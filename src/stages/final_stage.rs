@@ -1,34 +1,264 @@
 //! Final stage - runs user scripts and final tasks
 //!
 //! Responsibilities:
+//! - Bootstrap configuration management (puppet, chef, salt_minion, ansible)
+//! - Ubuntu conveniences (byobu_by_default, motd, fan)
 //! - Execute runcmd directives
 //! - Run user scripts from scripts-user
 //! - Phone home (notify completion)
+//! - Publish host keys and completion to GCE guest attributes
+//! - Block the metadata service (disable_ec2_metadata)
 //! - Final message
+//! - Reboot or power off (power_state_change)
 
+use super::ModuleErrors;
 use crate::CloudInitError;
-use tracing::{debug, info, warn};
+#[cfg(any(
+    feature = "mod-puppet",
+    feature = "mod-chef",
+    feature = "mod-salt-minion",
+    feature = "mod-ansible"
+))]
+use crate::config::CloudConfig;
+#[cfg(feature = "mod-gce-guest-attributes")]
+use crate::datasources::{cache, gce::Gce};
+#[cfg(feature = "mod-byobu")]
+use crate::distro::Distro;
+#[cfg(feature = "mod-ansible")]
+use crate::modules::ansible;
+#[cfg(feature = "mod-byobu")]
+use crate::modules::byobu;
+#[cfg(feature = "mod-chef")]
+use crate::modules::chef;
+#[cfg(feature = "mod-fan")]
+use crate::modules::fan;
+#[cfg(feature = "mod-gce-guest-attributes")]
+use crate::modules::gce_guest_attributes;
+#[cfg(feature = "mod-puppet")]
+use crate::modules::puppet;
+#[cfg(feature = "mod-salt-minion")]
+use crate::modules::salt_minion;
+use crate::modules::{disable_ec2_metadata, motd, power_state_change};
+use crate::network::render::RendererType;
+use crate::reporting::Reporter;
+use crate::root::RootContext;
+use crate::state::InstanceState;
+use tracing::{debug, info};
 
 /// Run the final stage
-pub async fn run() -> Result<(), CloudInitError> {
+pub async fn run(reporter: &Reporter, modules: &mut ModuleErrors) -> Result<(), CloudInitError> {
     info!("Final stage: executing user scripts");
 
+    // Configuration management agents, so they are up before user scripts
+    #[cfg(any(
+        feature = "mod-puppet",
+        feature = "mod-chef",
+        feature = "mod-salt-minion",
+        feature = "mod-ansible"
+    ))]
+    let config = super::config::load_cloud_config().await?;
+    #[cfg(feature = "mod-puppet")]
+    modules
+        .run(
+            reporter,
+            "puppet",
+            "configure puppet",
+            apply_puppet(&config),
+        )
+        .await;
+    #[cfg(feature = "mod-chef")]
+    modules
+        .run(reporter, "chef", "configure chef", apply_chef(&config))
+        .await;
+    #[cfg(feature = "mod-salt-minion")]
+    modules
+        .run(
+            reporter,
+            "salt_minion",
+            "configure salt minion",
+            apply_salt_minion(&config),
+        )
+        .await;
+    #[cfg(feature = "mod-ansible")]
+    modules
+        .run(
+            reporter,
+            "ansible",
+            "run ansible-pull",
+            apply_ansible(&config),
+        )
+        .await;
+
+    // Login conveniences of Ubuntu images
+    #[cfg(feature = "mod-byobu")]
+    modules
+        .run(
+            reporter,
+            "byobu_by_default",
+            "configure byobu",
+            apply_byobu(),
+        )
+        .await;
+    modules
+        .run(reporter, "motd", "write message of the day", apply_motd())
+        .await;
+    #[cfg(feature = "mod-fan")]
+    modules
+        .run(reporter, "fan", "configure ubuntu fan", apply_fan())
+        .await;
+
     // Execute runcmd
-    execute_runcmd().await?;
+    modules
+        .run(reporter, "runcmd", "run runcmd commands", execute_runcmd())
+        .await;
 
     // Run user scripts
-    run_user_scripts().await?;
+    modules
+        .run(
+            reporter,
+            "scripts_user",
+            "run user scripts",
+            run_user_scripts(),
+        )
+        .await;
 
     // Phone home if configured
-    phone_home().await?;
+    modules
+        .run(reporter, "phone_home", "phone home", phone_home())
+        .await;
+
+    // Tell GCE tooling the host keys and that provisioning is done,
+    // while the metadata server is still reachable
+    #[cfg(feature = "mod-gce-guest-attributes")]
+    modules
+        .run(
+            reporter,
+            "gce_guest_attributes",
+            "publish guest attributes",
+            apply_gce_guest_attributes(),
+        )
+        .await;
+
+    // Block the metadata service now that provisioning is done
+    modules
+        .run(
+            reporter,
+            "disable_ec2_metadata",
+            "block metadata service",
+            apply_disable_ec2_metadata(),
+        )
+        .await;
 
     // Write final message
-    write_final_message().await?;
+    modules
+        .run(
+            reporter,
+            "final_message",
+            "write final message",
+            write_final_message(),
+        )
+        .await;
+
+    // Reboot or power off last; the change waits for this process to exit
+    modules
+        .run(
+            reporter,
+            "power_state_change",
+            "change power state",
+            apply_power_state(),
+        )
+        .await;
 
     info!("Final stage: completed");
     Ok(())
 }
 
+/// Cached instance ID, used to name the node
+#[cfg(any(feature = "mod-puppet", feature = "mod-chef"))]
+async fn instance_id() -> Option<String> {
+    InstanceState::new()
+        .load_cached_instance_id()
+        .await
+        .ok()
+        .flatten()
+}
+
+#[cfg(feature = "mod-puppet")]
+async fn apply_puppet(config: &CloudConfig) -> Result<(), CloudInitError> {
+    let Some(puppet) = &config.puppet else {
+        return Ok(());
+    };
+    let root = &RootContext::current();
+    let fqdn = config.fqdn.as_deref().or(config.hostname.as_deref());
+    let instance_id = instance_id().await;
+    puppet::configure_puppet(
+        root.runner().as_ref(),
+        root,
+        puppet,
+        instance_id.as_deref(),
+        fqdn,
+    )
+    .await
+}
+
+#[cfg(feature = "mod-chef")]
+async fn apply_chef(config: &CloudConfig) -> Result<(), CloudInitError> {
+    let Some(chef) = &config.chef else {
+        return Ok(());
+    };
+    let root = &RootContext::current();
+    let instance_id = instance_id().await;
+    chef::configure_chef(root.runner().as_ref(), root, chef, instance_id.as_deref()).await
+}
+
+#[cfg(feature = "mod-salt-minion")]
+async fn apply_salt_minion(config: &CloudConfig) -> Result<(), CloudInitError> {
+    let Some(salt) = &config.salt_minion else {
+        return Ok(());
+    };
+    let root = &RootContext::current();
+    salt_minion::configure_salt_minion(root.runner().as_ref(), root, salt).await
+}
+
+#[cfg(feature = "mod-ansible")]
+async fn apply_ansible(config: &CloudConfig) -> Result<(), CloudInitError> {
+    let Some(ansible) = &config.ansible else {
+        return Ok(());
+    };
+    let root = &RootContext::current();
+    ansible::configure_ansible(root.runner().as_ref(), root, ansible).await
+}
+
+#[cfg(feature = "mod-byobu")]
+async fn apply_byobu() -> Result<(), CloudInitError> {
+    let config = super::config::load_cloud_config().await?;
+    let Some(value) = &config.byobu_by_default else {
+        return Ok(());
+    };
+    let root = &RootContext::current();
+    let distro = Distro::detect(root).await;
+    let user = crate::modules::users::configured_default_user(&config, &distro);
+    byobu::configure_byobu(root.runner().as_ref(), root, value, user.as_deref()).await
+}
+
+async fn apply_motd() -> Result<(), CloudInitError> {
+    let config = super::config::load_cloud_config().await?;
+    let Some(text) = &config.motd else {
+        return Ok(());
+    };
+    motd::write_motd(&RootContext::current(), text).await
+}
+
+#[cfg(feature = "mod-fan")]
+async fn apply_fan() -> Result<(), CloudInitError> {
+    let config = super::config::load_cloud_config().await?;
+    let Some(fan) = &config.fan else {
+        return Ok(());
+    };
+    let root = &RootContext::current();
+    fan::configure_fan(root.runner().as_ref(), root, fan).await
+}
+
 async fn execute_runcmd() -> Result<(), CloudInitError> {
     debug!("Executing runcmd directives");
     // TODO: Parse and execute runcmd from cloud-config
@@ -51,29 +281,57 @@ async fn phone_home() -> Result<(), CloudInitError> {
     Ok(())
 }
 
+#[cfg(feature = "mod-gce-guest-attributes")]
+async fn apply_gce_guest_attributes() -> Result<(), CloudInitError> {
+    let root = &RootContext::current();
+    // Guest attributes describe the running instance, not an image
+    if !root.is_host() {
+        return Ok(());
+    }
+    let on_gce = cache::load_cached(&root.cloud_paths())
+        .await
+        .is_some_and(|cached| cached.datasource.eq_ignore_ascii_case("GCE"));
+    if !on_gce {
+        return Ok(());
+    }
+    let config = super::config::load_cloud_config().await?;
+    let settings = config.gce_guest_attributes.unwrap_or_default();
+    gce_guest_attributes::publish_guest_attributes(&Gce::new(), root, &settings).await
+}
+
+async fn apply_disable_ec2_metadata() -> Result<(), CloudInitError> {
+    let config = super::config::load_cloud_config().await?;
+    if !config.disable_ec2_metadata.unwrap_or(false) {
+        return Ok(());
+    }
+    let root = &RootContext::current();
+    let renderer = RendererType::detect(root).await;
+    disable_ec2_metadata::disable_ec2_metadata(root.runner().as_ref(), root, renderer).await
+}
+
+async fn apply_power_state() -> Result<(), CloudInitError> {
+    let config = super::config::load_cloud_config().await?;
+    let Some(power_state) = &config.power_state else {
+        return Ok(());
+    };
+    let root = &RootContext::current();
+    power_state_change::power_state_change(root.runner().as_ref(), root, power_state).await
+}
+
 async fn write_final_message() -> Result<(), CloudInitError> {
     debug!("Writing final message");
-    // Write completion status to /run/cloud-init/result.json
-    let result = serde_json::json!({
-        "v1": {
-            "datasource": null,
-            "errors": []
-        }
-    });
-
-    let result_path = "/run/cloud-init/result.json";
-
-    // Only write if we have permissions (likely won't during development)
-    match tokio::fs::create_dir_all("/run/cloud-init").await {
-        Ok(_) => {
-            if let Err(e) = tokio::fs::write(result_path, result.to_string()).await {
-                warn!("Could not write result file: {}", e);
-            }
-        }
-        Err(e) => {
-            debug!("Could not create cloud-init run directory: {}", e);
-        }
+    // Completion status (result.json/status.json) is recorded by the
+    // stage runner once this stage returns; the instance's boot-finished
+    // marker tells later boots that this instance has booted before
+    let mut state = InstanceState::new();
+    if state.load_cached_instance_id().await?.is_some() {
+        state.mark_boot_finished().await?;
+    }
+    // The first boot of a sealed image is over
+    let root = &RootContext::current();
+    let sealed = root.cloud_paths().sealed_marker();
+    if sealed.exists() {
+        root.remove_file(&sealed).await?;
     }
-
     Ok(())
 }
@@ -5,31 +5,79 @@
 //! - Resize filesystem
 //! - Mount additional volumes
 //! - Set up disk partitions
+//! - Crawl network-only datasources over ephemeral DHCP
 //! - Apply network configuration
 
-use crate::CloudInitError;
+use super::ModuleErrors;
+use crate::config::{CloudConfig, load_merged_config};
+use crate::datasources::cache::{self, CacheDecision};
+use crate::datasources::snapshot::{self, Snapshot};
+use crate::datasources::{self, Datasource, nocloud::NoCloud};
 use crate::network::render::apply_network_config;
 use crate::network::v1::parse_network_config;
-use crate::state::InstanceState;
+use crate::network::{NetworkConfig, ephemeral, fallback};
+use crate::reporting::Reporter;
+use crate::root::RootContext;
+use crate::state::{CloudPaths, InstanceState, cloud_id};
+use crate::userdata::{boothook, handlers};
+use crate::{CloudInitError, UserData};
 use std::path::Path;
 use tokio::fs;
 use tracing::{debug, info, warn};
 
 /// Run the local stage
-pub async fn run() -> Result<(), CloudInitError> {
+pub async fn run(reporter: &Reporter, modules: &mut ModuleErrors) -> Result<(), CloudInitError> {
     info!("Local stage: starting pre-network initialization");
 
     // Check for NoCloud datasource (local files)
-    check_nocloud_datasource().await?;
+    modules
+        .run(
+            reporter,
+            "seed_nocloud",
+            "check for NoCloud seed",
+            check_nocloud_datasource(),
+        )
+        .await;
+
+    // Reach network-only datasources before the system network is up
+    modules
+        .run(
+            reporter,
+            "ephemeral_network",
+            "crawl metadata over ephemeral network",
+            crawl_metadata_ephemeral(),
+        )
+        .await;
 
     // Apply network configuration (before network comes up)
-    apply_network_configuration().await?;
+    modules
+        .run(
+            reporter,
+            "network",
+            "apply network configuration",
+            apply_network_configuration(),
+        )
+        .await;
 
     // Grow partition if needed
-    grow_partition().await?;
+    modules
+        .run(
+            reporter,
+            "growpart",
+            "grow root partition",
+            grow_partition(),
+        )
+        .await;
 
     // Resize filesystem
-    resize_filesystem().await?;
+    modules
+        .run(
+            reporter,
+            "resizefs",
+            "resize root filesystem",
+            resize_filesystem(),
+        )
+        .await;
 
     info!("Local stage: completed");
     Ok(())
@@ -44,50 +92,272 @@ async fn check_nocloud_datasource() -> Result<(), CloudInitError> {
     Ok(())
 }
 
-/// Apply network configuration from various sources
+/// Fetch metadata over an ephemeral DHCP lease if the network is not up
+///
+/// Skipped when the instance cache is still valid, a NoCloud seed is
+/// present or an IPv4 or IPv6 default route already exists. Failures are
+/// logged; the Network stage will try again once the real network
+/// configuration is applied.
+async fn crawl_metadata_ephemeral() -> Result<(), CloudInitError> {
+    let root = RootContext::current();
+    if root.is_dry_run() {
+        debug!("Dry run, not bringing up ephemeral networking");
+        return Ok(());
+    }
+    if !root.is_host() {
+        // The interfaces belong to the host, not the image being prepared
+        debug!("Not bringing up ephemeral networking under alternate root");
+        return Ok(());
+    }
+    if let CacheDecision::Reuse(_) = cache::check_cache().await {
+        debug!("Instance cache valid, ephemeral networking not needed");
+        return Ok(());
+    }
+    if NoCloud::new().is_available().await {
+        debug!("NoCloud seed present, ephemeral networking not needed");
+        return Ok(());
+    }
+
+    let routes = fs::read_to_string(ephemeral::PROC_NET_ROUTE)
+        .await
+        .unwrap_or_default();
+    if ephemeral::has_default_route(&routes) {
+        debug!("Default route present, ephemeral networking not needed");
+        return Ok(());
+    }
+    if ephemeral::is_ipv6_only_host().await {
+        // DHCPv4 would only time out; the metadata service is reached
+        // over the IPv6 route the kernel already has
+        debug!("IPv6 default route present, ephemeral DHCPv4 not needed");
+        return Ok(());
+    }
+
+    let Some(nic) = ephemeral::find_primary_nic(Path::new(ephemeral::SYS_CLASS_NET)).await else {
+        debug!("No network interface available for ephemeral DHCP");
+        return Ok(());
+    };
+
+    if let Err(e) =
+        ephemeral::with_ephemeral_dhcp(root.runner().as_ref(), &nic, cache_datasource()).await
+    {
+        warn!(
+            "Could not crawl metadata over ephemeral network on {}: {}",
+            nic, e
+        );
+    }
+    Ok(())
+}
+
+/// Detect the datasource and cache its instance ID and user data
+///
+/// A changed instance ID marks a new instance, see
+/// [`InstanceState::set_instance_id`]. Everything fetched is also kept in
+/// a [`Snapshot`] for the later stages of this boot.
+pub(crate) async fn cache_datasource() -> Result<(), CloudInitError> {
+    let ds = datasources::detect_datasource().await?;
+    let metadata = ds.get_metadata().await?;
+    let Some(instance_id) = metadata.instance_id.clone() else {
+        return Err(CloudInitError::Datasource(format!(
+            "{} returned no instance-id",
+            ds.name()
+        )));
+    };
+
+    let mut state = InstanceState::new();
+    state.initialize().await?;
+    state.set_instance_id(&instance_id).await?;
+    state.save_datasource(ds.name()).await?;
+    cloud_id::write(state.paths(), ds.name()).await?;
+
+    let network_config = ds.get_network_config().await?;
+    if let Some(network) = &network_config {
+        state.save_network_config(network).await?;
+    }
+
+    let userdata = ds.get_userdata().await?;
+    match &userdata {
+        UserData::CloudConfig(config) => {
+            let yaml = serde_yaml::to_string(config)?;
+            state
+                .save_cloud_config(&format!("#cloud-config\n{}", yaml))
+                .await?;
+        }
+        UserData::Script(script) => state.save_userdata(script).await?,
+        UserData::MultiPart(_) | UserData::None => {}
+    }
+    let root = RootContext::host();
+    let hooks = boothook::boothooks(&userdata);
+    boothook::save(&root, state.paths(), &instance_id, &hooks).await?;
+    let handlers = load_merged_config(state.paths())
+        .await
+        .map(|system| system.handlers)
+        .unwrap_or_default();
+    handlers::dispatch(root.runner().as_ref(), &handlers, &instance_id, &userdata).await;
+
+    let vendordata = ds.get_vendordata().await.unwrap_or_else(|e| {
+        debug!("No vendor data from {}: {}", ds.name(), e);
+        None
+    });
+    if let Some(UserData::CloudConfig(config)) = &vendordata {
+        let yaml = serde_yaml::to_string(config)?;
+        state
+            .save_vendordata(&format!("#cloud-config\n{}", yaml))
+            .await?;
+    }
+    let fetched = Snapshot {
+        datasource: ds.name().to_string(),
+        metadata,
+        userdata,
+        vendordata,
+        network_config,
+    };
+    if let Err(e) = snapshot::save(state.paths(), &fetched).await {
+        warn!("Could not save datasource snapshot: {}", e);
+    }
+
+    info!("Cached metadata from {} for {}", ds.name(), instance_id);
+    Ok(())
+}
+
+/// Network configuration files read ahead of the merged system config
+const SYSTEM_NETWORK_FILES: &[&str] = &[
+    "/etc/cloud/cloud.cfg.d/50-curtin-networking.cfg",
+    "/etc/cloud/cloud.cfg.d/network-config",
+];
+
+/// Network configuration chosen by system config
+pub(crate) enum SystemNetwork {
+    /// `network: {config: disabled}`
+    Disabled,
+    /// `network: {config: preserve}` on a reboot of the same instance
+    Preserved,
+    /// An explicit config that overrides anything a datasource supplies
+    Config(Box<NetworkConfig>),
+    /// Nothing set; the datasource decides
+    Unset,
+}
+
+/// Resolve network configuration from system config
+pub(crate) async fn system_network() -> Result<SystemNetwork, CloudInitError> {
+    let system = load_merged_config(&CloudPaths::new())
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to load system config: {}", e);
+            CloudConfig::default()
+        });
+    if system.network_disabled() {
+        return Ok(SystemNetwork::Disabled);
+    }
+    if system.network_preserved() && instance_booted_before().await {
+        return Ok(SystemNetwork::Preserved);
+    }
+
+    for path_str in SYSTEM_NETWORK_FILES {
+        let path = RootContext::current().path(path_str);
+        if !path.exists() {
+            continue;
+        }
+        info!("Found network config at: {}", path.display());
+        match fs::read_to_string(&path).await {
+            Ok(content) => {
+                let config = parse_network_config(&content).map_err(|e| {
+                    CloudInitError::InvalidData(format!("Failed to parse network config: {}", e))
+                })?;
+                return Ok(SystemNetwork::Config(Box::new(config)));
+            }
+            Err(e) => warn!(
+                "Failed to read network config from {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    Ok(system
+        .network_config()
+        .map_or(SystemNetwork::Unset, |c| SystemNetwork::Config(Box::new(c))))
+}
+
+/// Whether the cached instance has finished a boot already
+async fn instance_booted_before() -> bool {
+    let mut state = InstanceState::new();
+    matches!(state.load_cached_instance_id().await, Ok(Some(_))) && state.is_boot_finished()
+}
+
+/// Apply network configuration
+///
+/// System config wins over the datasource. Without either, a local
+/// NoCloud seed's network-config or one cached during the ephemeral
+/// crawl is used, and failing that a fallback DHCP config for the first
+/// connected NIC. Network-only datasources are handled again by the
+/// Network stage.
 async fn apply_network_configuration() -> Result<(), CloudInitError> {
     debug!("Checking for network configuration");
 
-    // Standard network config locations (in order of precedence)
-    let config_paths = [
-        "/etc/cloud/cloud.cfg.d/50-curtin-networking.cfg",
-        "/etc/cloud/cloud.cfg.d/network-config",
-        "/var/lib/cloud/seed/nocloud/network-config",
-        "/var/lib/cloud/seed/nocloud-net/network-config",
-    ];
-
-    for path_str in &config_paths {
-        let path = Path::new(path_str);
-        if path.exists() {
-            info!("Found network config at: {}", path_str);
-            match fs::read_to_string(path).await {
-                Ok(content) => {
-                    return apply_network_from_content(&content).await;
-                }
-                Err(e) => {
-                    warn!("Failed to read network config from {}: {}", path_str, e);
+    let config = match system_network().await? {
+        SystemNetwork::Disabled => {
+            info!("Network configuration disabled by system config");
+            return Ok(());
+        }
+        SystemNetwork::Preserved => {
+            info!("Keeping the existing network configuration (network: {{config: preserve}})");
+            return Ok(());
+        }
+        SystemNetwork::Config(config) => *config,
+        SystemNetwork::Unset => match local_datasource_network().await {
+            Some(config) => config,
+            // The host's NICs say nothing about the machine an image boots on
+            None if !RootContext::current().is_host() => {
+                debug!("Skipping fallback network config under alternate root");
+                return Ok(());
+            }
+            None => {
+                match fallback::generate_fallback_config(Path::new(ephemeral::SYS_CLASS_NET), false)
+                    .await
+                {
+                    Some(config) => config,
+                    None => {
+                        debug!("No network configuration found");
+                        return Ok(());
+                    }
                 }
             }
+        },
+    };
+
+    apply_parsed_network(&config).await
+}
+
+/// Network config from a NoCloud seed or cached by the ephemeral crawl
+async fn local_datasource_network() -> Option<NetworkConfig> {
+    let nocloud = NoCloud::new();
+    if nocloud.is_available().await {
+        match nocloud.get_network_config().await {
+            Ok(Some(config)) => {
+                info!("Using network config from NoCloud seed");
+                return Some(config);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Ignoring NoCloud network config: {}", e),
         }
     }
 
-    // Check instance state for network config
     let mut state = InstanceState::new();
-    if let Ok(Some(_instance_id)) = state.load_cached_instance_id().await {
-        // Could load network config from instance-specific location
-        debug!("No network configuration found in standard locations");
+    if let Ok(Some(_)) = state.load_cached_instance_id().await {
+        match state.load_network_config().await {
+            Ok(Some(config)) => {
+                info!("Using cached datasource network config");
+                return Some(config);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Ignoring cached network config: {}", e),
+        }
     }
-
-    Ok(())
+    None
 }
 
-/// Apply network configuration from YAML content
-async fn apply_network_from_content(content: &str) -> Result<(), CloudInitError> {
-    // Parse network config (auto-detects v1 or v2)
-    let config = parse_network_config(content).map_err(|e| {
-        CloudInitError::InvalidData(format!("Failed to parse network config: {}", e))
-    })?;
-
+/// Apply a parsed network configuration
+pub(crate) async fn apply_parsed_network(config: &NetworkConfig) -> Result<(), CloudInitError> {
     if !config.has_interfaces() {
         debug!("Network config has no interfaces defined");
         return Ok(());
@@ -99,7 +369,7 @@ async fn apply_network_from_content(content: &str) -> Result<(), CloudInitError>
     );
 
     // Apply the configuration using the appropriate renderer
-    apply_network_config(&config, config.renderer.as_deref()).await?;
+    apply_network_config(&RootContext::current(), config, config.renderer.as_deref()).await?;
 
     Ok(())
 }
@@ -10,3 +10,555 @@ pub mod config;
 pub mod final_stage;
 pub mod local;
 pub mod network;
+
+use crate::config::{ModulePolicy, ModulePolicyConfig, TimeoutConfig};
+use crate::reporting::{EventResult, Reporter, module_event_name};
+use crate::root::RootContext;
+use crate::state::{Frequency, SemaphoreManager};
+use crate::{CloudInitError, Stage};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::future::Future;
+use std::pin::Pin;
+use tracing::{Instrument, error, info, info_span, warn};
+
+/// How often `module` runs in `stage`
+///
+/// Local and Network stage modules detect the platform and apply what it
+/// reports, so they run every boot; so do user scripts, which carry their
+/// own per-boot/per-instance directories, the metadata route added by
+/// `disable_ec2_metadata`, which does not survive a reboot, and the final
+/// message. Every
+/// other Config and Final module applies user configuration once per
+/// instance, like cloud-init's `PER_INSTANCE`.
+pub fn module_frequency(stage: Stage, module: &str) -> Frequency {
+    match (stage, module) {
+        (Stage::Local | Stage::Network, _) => Frequency::PerBoot,
+        (Stage::Config, "update_hostname" | "update_etc_hosts") => Frequency::PerBoot,
+        (Stage::Final, "scripts_user" | "disable_ec2_metadata" | "final_message") => {
+            Frequency::PerBoot
+        }
+        _ => Frequency::PerInstance,
+    }
+}
+
+/// Modules a run is limited to
+///
+/// With an allow list only the listed modules run; modules on the deny
+/// list never run. Names are those of [`module_frequency`] and the
+/// reporting events, e.g. `users` or `write_files_deferred`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModuleFilter {
+    pub allow: Option<Vec<String>>,
+    pub deny: Vec<String>,
+}
+
+impl ModuleFilter {
+    /// Whether `module` may run
+    pub fn allows(&self, module: &str) -> bool {
+        let allowed = self
+            .allow
+            .as_ref()
+            .is_none_or(|allow| allow.iter().any(|m| m == module));
+        allowed && !self.deny.iter().any(|m| m == module)
+    }
+}
+
+/// A module that failed, and the policy it ran under
+#[derive(Debug)]
+pub struct ModuleFailure {
+    pub error: CloudInitError,
+    pub policy: ModulePolicy,
+}
+
+/// A module for [`ModuleErrors::run_all`], and the modules it must follow
+pub struct ModuleTask<'a> {
+    module: &'static str,
+    description: &'static str,
+    after: &'static [&'static str],
+    fut: Pin<Box<dyn Future<Output = Result<(), CloudInitError>> + Send + 'a>>,
+}
+
+impl<'a> ModuleTask<'a> {
+    pub fn new<F>(module: &'static str, description: &'static str, fut: F) -> Self
+    where
+        F: Future<Output = Result<(), CloudInitError>> + Send + 'a,
+    {
+        Self {
+            module,
+            description,
+            after: &[],
+            fut: Box::pin(fut),
+        }
+    }
+
+    /// Start only once `modules` have finished or been skipped
+    pub fn after(mut self, modules: &'static [&'static str]) -> Self {
+        self.after = modules;
+        self
+    }
+}
+
+/// Module failures collected while a stage runs
+///
+/// A failing module never stops the rest of its stage. Stage `run`
+/// functions only return `Err` for errors that leave the stage unable to
+/// continue (e.g. unreadable cloud-config); the caller then decides from
+/// the collected failures whether a required module failed.
+///
+/// Each module runs under the watchdog limit from [`TimeoutConfig`]; a
+/// module that exceeds it is abandoned and recorded as a
+/// [`CloudInitError::Timeout`] failure.
+///
+/// With the instance's semaphores attached, a module that already ran for
+/// this instance at its [`module_frequency`] is skipped, and one that runs
+/// is marked done whether or not it succeeds.
+///
+/// Modules handed to [`ModuleErrors::run_all`] run side by side, up to the
+/// `module_parallelism` limit from cloud.cfg (default 1, one at a time).
+#[derive(Debug)]
+pub struct ModuleErrors {
+    stage: Stage,
+    policy: ModulePolicyConfig,
+    timeouts: TimeoutConfig,
+    semaphores: Option<SemaphoreManager>,
+    filter: ModuleFilter,
+    /// Skip modules that need the network, see [`crate::offline`]
+    offline: bool,
+    /// Modules [`ModuleErrors::run_all`] keeps in flight at once
+    parallelism: usize,
+    failures: Vec<ModuleFailure>,
+}
+
+impl ModuleErrors {
+    pub fn new(stage: Stage, policy: ModulePolicyConfig) -> Self {
+        Self {
+            stage,
+            policy,
+            timeouts: TimeoutConfig::default(),
+            semaphores: None,
+            filter: ModuleFilter::default(),
+            offline: crate::offline::is_offline(),
+            parallelism: 1,
+            failures: Vec::new(),
+        }
+    }
+
+    /// Use `timeouts` instead of the default module limits
+    pub fn with_timeouts(mut self, timeouts: TimeoutConfig) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Skip modules that already ran according to `semaphores`
+    pub fn with_semaphores(mut self, semaphores: SemaphoreManager) -> Self {
+        self.semaphores = Some(semaphores);
+        self
+    }
+
+    /// Only run the modules `filter` allows
+    pub fn with_filter(mut self, filter: ModuleFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Skip modules that need the network, see [`crate::offline`]
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Run up to `parallelism` independent modules at once
+    pub fn with_parallelism(mut self, parallelism: usize) -> Self {
+        self.parallelism = parallelism.max(1);
+        self
+    }
+
+    /// Run one module under its reporting scope, keeping any error
+    pub async fn run<F>(&mut self, reporter: &Reporter, module: &str, description: &str, fut: F)
+    where
+        F: Future<Output = Result<(), CloudInitError>>,
+    {
+        if let Some(failure) = self.execute(reporter, module, description, fut).await {
+            self.failures.push(failure);
+        }
+    }
+
+    /// Run `tasks`, starting each once the modules it follows are done
+    ///
+    /// Ready tasks start in the order given, so with a parallelism of 1
+    /// this is the same as calling [`ModuleErrors::run`] for each in turn.
+    /// Modules named in `after` but not among `tasks` are not waited for.
+    /// Failures are kept in the order the modules finished.
+    pub async fn run_all(&mut self, reporter: &Reporter, tasks: Vec<ModuleTask<'_>>) {
+        // Dry runs attribute each action to the one module in flight
+        let limit = match RootContext::current().is_dry_run() {
+            true => 1,
+            false => self.parallelism,
+        };
+        let names: Vec<&str> = tasks.iter().map(|task| task.module).collect();
+        let mut pending: Vec<Option<ModuleTask>> = tasks.into_iter().map(Some).collect();
+
+        let failures = {
+            let this = &*self;
+            let mut failures = Vec::new();
+            let mut finished: Vec<&str> = Vec::new();
+            let mut running = FuturesUnordered::new();
+            loop {
+                for slot in pending.iter_mut() {
+                    if running.len() >= limit {
+                        break;
+                    }
+                    let ready = slot.as_ref().is_some_and(|task| {
+                        task.after
+                            .iter()
+                            .all(|dep| !names.contains(dep) || finished.contains(dep))
+                    });
+                    if ready && let Some(task) = slot.take() {
+                        running.push(async move {
+                            let failure = this
+                                .execute(reporter, task.module, task.description, task.fut)
+                                .await;
+                            (task.module, failure)
+                        });
+                    }
+                }
+                let Some((module, failure)) = running.next().await else {
+                    break;
+                };
+                finished.push(module);
+                failures.extend(failure);
+            }
+
+            // Only a dependency cycle leaves tasks behind; run them in order
+            for task in pending.into_iter().flatten() {
+                warn!(
+                    "{} waits on a module that never finishes, running it anyway",
+                    task.module
+                );
+                failures.extend(
+                    this.execute(reporter, task.module, task.description, task.fut)
+                        .await,
+                );
+            }
+            failures
+        };
+        self.failures.extend(failures);
+    }
+
+    /// Run one module, returning its failure instead of keeping it
+    async fn execute<F>(
+        &self,
+        reporter: &Reporter,
+        module: &str,
+        description: &str,
+        fut: F,
+    ) -> Option<ModuleFailure>
+    where
+        F: Future<Output = Result<(), CloudInitError>>,
+    {
+        if !self.filter.allows(module) {
+            info!("Skipping {}: excluded from this run", module);
+            return None;
+        }
+        if self.offline && crate::offline::needs_network(module) {
+            info!("Skipping {}: offline", module);
+            let name = module_event_name(self.stage, module);
+            reporter.start(&name, description).await;
+            reporter
+                .finish(&name, "skipped: offline", EventResult::Success)
+                .await;
+            return None;
+        }
+        let frequency = module_frequency(self.stage, module);
+        if let Some(semaphores) = &self.semaphores
+            && !semaphores
+                .should_run(module, frequency)
+                .await
+                .unwrap_or(true)
+        {
+            info!("Skipping {}: already ran ({})", module, frequency);
+            return None;
+        }
+
+        let name = module_event_name(self.stage, module);
+        let limit = self.timeouts.module_timeout(module);
+        let watched = async {
+            match limit {
+                Some(limit) => tokio::time::timeout(limit, fut).await.map_err(|_| {
+                    CloudInitError::Timeout(format!(
+                        "module to finish (limit {}s)",
+                        limit.as_secs()
+                    ))
+                })?,
+                None => fut.await,
+            }
+        };
+        let span = info_span!("module", module);
+        let failure = match reporter
+            .scope(&name, description, watched)
+            .instrument(span)
+            .await
+        {
+            Ok(()) => None,
+            Err(e) => {
+                let error = e.in_module(module);
+                let policy = self.policy.policy_for(module);
+                match policy {
+                    ModulePolicy::Required => error!("{} (required module)", error),
+                    ModulePolicy::BestEffort => warn!("{}", error),
+                }
+                Some(ModuleFailure { error, policy })
+            }
+        };
+
+        // Dry runs leave the semaphores as they are, so the plan can be
+        // followed by a real run
+        if let Some(semaphores) = &self.semaphores
+            && !RootContext::current().is_dry_run()
+            && let Err(e) = semaphores.mark_done(module, frequency).await
+        {
+            warn!("Could not mark {} as done: {}", module, e);
+        }
+        failure
+    }
+
+    /// Fail if any required module failed
+    pub fn check_required(&self) -> Result<(), CloudInitError> {
+        let required: Vec<_> = self
+            .failures
+            .iter()
+            .filter(|f| f.policy == ModulePolicy::Required)
+            .filter_map(|f| f.error.module_name())
+            .collect();
+        if required.is_empty() {
+            return Ok(());
+        }
+        Err(CloudInitError::stage(
+            self.stage.to_string(),
+            format!("required module(s) failed: {}", required.join(", ")),
+        ))
+    }
+
+    /// Failures collected so far, in the order the modules ran
+    pub fn into_failures(self) -> Vec<ModuleFailure> {
+        self.failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_module_errors_do_not_stop_stage() {
+        let reporter = Reporter::new();
+        let mut modules = ModuleErrors::new(Stage::Config, ModulePolicyConfig::default());
+        let mut ran = Vec::new();
+
+        modules
+            .run(&reporter, "users", "create users", async {
+                ran.push("users");
+                Err(CloudInitError::UserGroup("useradd failed".to_string()))
+            })
+            .await;
+        modules
+            .run(&reporter, "write_files", "write files", async {
+                ran.push("write_files");
+                Ok(())
+            })
+            .await;
+
+        assert_eq!(ran, vec!["users", "write_files"]);
+        assert!(modules.check_required().is_ok());
+        let failures = modules.into_failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].policy, ModulePolicy::BestEffort);
+        assert_eq!(failures[0].error.module_name(), Some("users"));
+        assert_eq!(failures[0].error.exit_code(), crate::exit_code::FAILURE);
+        assert!(failures[0].error.to_string().contains("useradd failed"));
+    }
+
+    #[tokio::test]
+    async fn test_module_filter() {
+        let reporter = Reporter::new();
+        let filter = ModuleFilter {
+            allow: Some(vec!["users".to_string(), "runcmd".to_string()]),
+            deny: vec!["runcmd".to_string()],
+        };
+        let mut modules =
+            ModuleErrors::new(Stage::Config, ModulePolicyConfig::default()).with_filter(filter);
+        let mut ran = Vec::new();
+        for module in ["users", "runcmd", "write_files"] {
+            modules
+                .run(&reporter, module, module, async {
+                    ran.push(module);
+                    Ok(())
+                })
+                .await;
+        }
+        assert_eq!(ran, vec!["users"]);
+    }
+
+    #[tokio::test]
+    async fn test_offline_skips_network_modules() {
+        let reporter = Reporter::new();
+        let mut modules = ModuleErrors::new(Stage::Config, ModulePolicyConfig::default());
+        modules.offline = true;
+        let mut ran = Vec::new();
+        for module in ["users", "package_update_upgrade_install"] {
+            modules
+                .run(&reporter, module, module, async {
+                    ran.push(module);
+                    Ok(())
+                })
+                .await;
+        }
+        assert_eq!(ran, vec!["users"]);
+    }
+
+    #[tokio::test]
+    async fn test_per_instance_modules_run_once() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let semaphores = SemaphoreManager::new(temp.path().join("sem"), temp.path().join("data"));
+        let reporter = Reporter::new();
+        let mut ran = Vec::new();
+
+        for _boot in 0..2 {
+            let mut modules = ModuleErrors::new(Stage::Final, ModulePolicyConfig::default())
+                .with_semaphores(semaphores.clone());
+            modules
+                .run(&reporter, "runcmd", "run commands", async {
+                    ran.push("runcmd");
+                    Err(CloudInitError::Command("false".to_string()))
+                })
+                .await;
+            modules
+                .run(&reporter, "final_message", "write final message", async {
+                    ran.push("final_message");
+                    Ok(())
+                })
+                .await;
+        }
+
+        assert_eq!(ran, vec!["runcmd", "final_message", "final_message"]);
+        assert_eq!(
+            module_frequency(Stage::Local, "growpart"),
+            Frequency::PerBoot
+        );
+        assert_eq!(
+            module_frequency(Stage::Config, "users"),
+            Frequency::PerInstance
+        );
+        assert_eq!(
+            module_frequency(Stage::Config, "update_etc_hosts"),
+            Frequency::PerBoot
+        );
+    }
+
+    #[tokio::test]
+    async fn test_module_timeout() {
+        let reporter = Reporter::new();
+        let timeouts = TimeoutConfig {
+            modules: [("hung".to_string(), 1), ("quick".to_string(), 0)]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let mut modules =
+            ModuleErrors::new(Stage::Final, ModulePolicyConfig::default()).with_timeouts(timeouts);
+
+        tokio::time::pause();
+        modules
+            .run(&reporter, "hung", "never finishes", std::future::pending())
+            .await;
+        modules
+            .run(&reporter, "quick", "finishes", async { Ok(()) })
+            .await;
+
+        let failures = modules.into_failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].error.module_name(), Some("hung"));
+        assert_eq!(
+            failures[0].error.to_string(),
+            "Module 'hung' failed: Timeout waiting for module to finish (limit 1s)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_required_module_failure() {
+        let reporter = Reporter::new();
+        let policy = ModulePolicyConfig {
+            required: vec!["users".to_string(), "ntp".to_string()],
+            ..Default::default()
+        };
+        let mut modules = ModuleErrors::new(Stage::Config, policy);
+
+        modules
+            .run(&reporter, "users", "create users", async {
+                Err(CloudInitError::UserGroup("useradd failed".to_string()))
+            })
+            .await;
+        modules
+            .run(&reporter, "ntp", "configure ntp", async { Ok(()) })
+            .await;
+        modules
+            .run(&reporter, "runcmd", "run commands", async {
+                Err(CloudInitError::Command("false".to_string()))
+            })
+            .await;
+
+        let err = modules.check_required().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Stage 'config' failed: required module(s) failed: users"
+        );
+        let policies: Vec<_> = modules.into_failures().iter().map(|f| f.policy).collect();
+        assert_eq!(
+            policies,
+            vec![ModulePolicy::Required, ModulePolicy::BestEffort]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_all_overlaps_independent_modules() {
+        let reporter = Reporter::new();
+        let mut modules =
+            ModuleErrors::new(Stage::Config, ModulePolicyConfig::default()).with_parallelism(2);
+        // Passes only once timezone and locale are both in flight
+        let barrier = tokio::sync::Barrier::new(2);
+        let finished = std::sync::Mutex::new(Vec::new());
+        let step = |module: &'static str| {
+            let (barrier, finished) = (&barrier, &finished);
+            async move {
+                if module != "users" {
+                    barrier.wait().await;
+                }
+                finished.lock().unwrap().push(module);
+                match module {
+                    "locale" => Err(CloudInitError::Command("locale-gen failed".to_string())),
+                    _ => Ok(()),
+                }
+            }
+        };
+
+        let tasks = vec![
+            ModuleTask::new("timezone", "set timezone", step("timezone")),
+            ModuleTask::new("users", "create users", step("users")).after(&["timezone"]),
+            ModuleTask::new("locale", "set locale", step("locale")),
+        ];
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            modules.run_all(&reporter, tasks),
+        )
+        .await
+        .expect("independent modules ran one at a time");
+
+        let finished = finished.into_inner().unwrap();
+        assert_eq!(finished.len(), 3);
+        let position = |module| finished.iter().position(|m| *m == module).unwrap();
+        assert!(position("users") > position("timezone"));
+        let failures = modules.into_failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].error.module_name(), Some("locale"));
+    }
+}
@@ -5,23 +5,75 @@
 //! - Configure SSH authorized keys
 //! - Set hostname
 //! - Configure network (if cloud-config specifies)
+//! - Apply network config from network-only datasources
 
+use super::ModuleErrors;
+use super::local::{SystemNetwork, apply_parsed_network, cache_datasource, system_network};
 use crate::CloudInitError;
-use tracing::{debug, info};
+use crate::datasources::cache::{self, CacheDecision};
+use crate::reporting::Reporter;
+use crate::root::RootContext;
+use crate::state::{CloudPaths, InstanceState, cloud_id};
+use crate::userdata::boothook;
+use tracing::{debug, info, warn};
 
 /// Run the network stage
-pub async fn run() -> Result<(), CloudInitError> {
+pub async fn run(reporter: &Reporter, modules: &mut ModuleErrors) -> Result<(), CloudInitError> {
     info!("Network stage: fetching metadata and configuring instance");
 
     // Detect and query datasource
     let metadata = fetch_metadata().await?;
     debug!("Retrieved metadata: {:?}", metadata);
 
+    // Re-crawl the datasource unless the cached instance is still valid
+    modules
+        .run(
+            reporter,
+            "datasource",
+            "check instance cache",
+            refresh_instance_cache(),
+        )
+        .await;
+
+    // Boothooks run on every boot, before any other module
+    modules
+        .run(
+            reporter,
+            "boothooks",
+            "run cloud boothooks",
+            run_boothooks(),
+        )
+        .await;
+
+    // Network config from datasources only reachable now
+    modules
+        .run(
+            reporter,
+            "network",
+            "apply datasource network configuration",
+            apply_datasource_network(),
+        )
+        .await;
+
     // Set hostname from metadata
-    configure_hostname(&metadata).await?;
+    modules
+        .run(
+            reporter,
+            "set_hostname",
+            "set hostname from metadata",
+            configure_hostname(&metadata),
+        )
+        .await;
 
     // Configure SSH keys
-    configure_ssh_keys(&metadata).await?;
+    modules
+        .run(
+            reporter,
+            "ssh",
+            "configure SSH authorized keys",
+            configure_ssh_keys(&metadata),
+        )
+        .await;
 
     info!("Network stage: completed");
     Ok(())
@@ -49,6 +101,82 @@ async fn fetch_metadata() -> Result<Metadata, CloudInitError> {
     Ok(Metadata::default())
 }
 
+/// Detect the datasource again unless the cache policy trusts the cache
+///
+/// Finding no datasource is not an error: the instance is then
+/// configured from system config alone.
+async fn refresh_instance_cache() -> Result<(), CloudInitError> {
+    if let CacheDecision::Reuse(cached) = cache::check_cache().await {
+        // /run does not survive a reboot; the cached cloud still applies
+        cloud_id::write(&CloudPaths::new(), &cached.datasource).await?;
+        return Ok(());
+    }
+    match cache_datasource().await {
+        Err(CloudInitError::NoDatasource) => {
+            debug!("No datasource found, nothing to cache");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Run the boothooks saved for the current instance
+async fn run_boothooks() -> Result<(), CloudInitError> {
+    let mut state = InstanceState::new();
+    let Some(instance_id) = state.load_cached_instance_id().await? else {
+        debug!("No cached instance, no boothooks to run");
+        return Ok(());
+    };
+    let root = RootContext::host();
+    boothook::run_all(root.runner().as_ref(), &root, &instance_id).await
+}
+
+/// Apply network config supplied by the datasource
+///
+/// Skipped when system config disables networking or provides its own
+/// config, which the Local stage has already applied. Re-applying what
+/// the Local stage rendered is a no-op.
+async fn apply_datasource_network() -> Result<(), CloudInitError> {
+    match system_network().await? {
+        SystemNetwork::Disabled => {
+            debug!("Network configuration disabled by system config");
+            return Ok(());
+        }
+        SystemNetwork::Preserved => {
+            debug!("Network configuration preserved on reboot");
+            return Ok(());
+        }
+        SystemNetwork::Config(_) => {
+            debug!("System network config takes precedence over datasource");
+            return Ok(());
+        }
+        SystemNetwork::Unset => {}
+    }
+
+    let ds = match cache::stage_datasource().await {
+        Ok(ds) => ds,
+        Err(e) => {
+            debug!("No datasource for network config: {}", e);
+            return Ok(());
+        }
+    };
+
+    match ds.get_network_config().await {
+        Ok(Some(config)) => {
+            info!("Using network config from {}", ds.name());
+            apply_parsed_network(&config).await
+        }
+        Ok(None) => {
+            debug!("{} supplied no network config", ds.name());
+            Ok(())
+        }
+        Err(e) => {
+            warn!("Failed to fetch network config from {}: {}", ds.name(), e);
+            Ok(())
+        }
+    }
+}
+
 async fn configure_hostname(metadata: &Metadata) -> Result<(), CloudInitError> {
     if let Some(hostname) = &metadata.hostname {
         debug!("Setting hostname to: {}", hostname);
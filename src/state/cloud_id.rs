@@ -0,0 +1,97 @@
+//! Canonical cloud name
+//!
+//! Once a datasource is known, `/run/cloud-init/cloud-id-<name>` holds the
+//! canonical name of the cloud (`aws`, `azure`, `gce`, ...) and
+//! `/run/cloud-init/cloud-id` links to it, as in Python cloud-init. Boot
+//! tooling reads the file, tests for the variant, or runs
+//! `cloud-init-rs cloud-id` to branch per cloud without parsing metadata.
+
+use crate::root::RootContext;
+use crate::state::CloudPaths;
+use crate::{CloudInitError, IoContext};
+use std::path::Path;
+use tokio::fs;
+use tracing::debug;
+
+/// Canonical cloud name of the datasource called `datasource`
+pub fn canonical(datasource: &str) -> String {
+    match datasource.to_ascii_lowercase().as_str() {
+        "ec2" => "aws".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Write the cloud-id files for `datasource` and return the cloud name
+///
+/// Variants left by another datasource in an earlier boot are removed.
+pub async fn write(paths: &CloudPaths, datasource: &str) -> Result<String, CloudInitError> {
+    let cloud_id = canonical(datasource);
+    let root = &RootContext::current();
+    let run_dir = paths.run_dir();
+    root.create_dir_all(&run_dir).await?;
+
+    let variant = paths.cloud_id_variant(&cloud_id);
+    remove_stale(&run_dir, &variant).await?;
+    root.write_file(&variant, format!("{}\n", cloud_id)).await?;
+
+    let link = paths.cloud_id_file();
+    if link.is_symlink() || link.exists() {
+        root.remove_file(&link).await?;
+    }
+    // Relative, so the link stays valid under --root
+    let target = variant.file_name().map(Path::new).unwrap_or(&variant);
+    root.symlink(target, &link).await?;
+    debug!("Wrote cloud-id {}", cloud_id);
+    Ok(cloud_id)
+}
+
+/// Remove `cloud-id-*` files in `run_dir` other than `keep`
+async fn remove_stale(run_dir: &Path, keep: &Path) -> Result<(), CloudInitError> {
+    let mut entries = match fs::read_dir(run_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_path(run_dir),
+    };
+    while let Some(entry) = entries.next_entry().await.with_path(run_dir)? {
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with("cloud-id-") && path != keep {
+            RootContext::current().remove_file(&path).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The cloud name written this boot
+pub async fn read(paths: &CloudPaths) -> Result<String, CloudInitError> {
+    let path = paths.cloud_id_file();
+    let content = fs::read_to_string(&path).await.with_path(&path)?;
+    Ok(content.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_canonical() {
+        assert_eq!(canonical("EC2"), "aws");
+        assert_eq!(canonical("NoCloud"), "nocloud");
+        assert_eq!(canonical("GCE"), "gce");
+    }
+
+    #[tokio::test]
+    async fn test_write_replaces_previous_cloud() {
+        let temp = TempDir::new().unwrap();
+        let paths = CloudPaths::with_base(temp.path());
+
+        write(&paths, "NoCloud").await.unwrap();
+        assert_eq!(read(&paths).await.unwrap(), "nocloud");
+
+        assert_eq!(write(&paths, "EC2").await.unwrap(), "aws");
+        assert_eq!(read(&paths).await.unwrap(), "aws");
+        assert!(paths.cloud_id_file().is_symlink());
+        assert!(paths.cloud_id_variant("aws").exists());
+        assert!(!paths.cloud_id_variant("nocloud").exists());
+    }
+}
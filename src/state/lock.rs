@@ -0,0 +1,111 @@
+//! Lock against overlapping runs
+//!
+//! A stage holds an exclusive advisory lock on `/run/cloud-init/.lock`
+//! while it runs, so a stage started by a systemd retry, or by hand while
+//! boot is still in progress, cannot interleave its writes to the state
+//! directory with the running one. The second run waits for the lock for
+//! up to `timeouts: {lock: N}` seconds (default 300) and then fails;
+//! `lock: 0` fails at once. The kernel drops the lock when the holder
+//! exits, so a crashed run does not leave it behind.
+
+use crate::state::CloudPaths;
+use crate::{CloudInitError, IoContext, Stage};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+/// How often a waiting run retries the lock
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Exclusive lock on the state directory, released on drop
+#[derive(Debug)]
+pub struct RunLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl RunLock {
+    /// Take the lock for `stage`, waiting up to `wait` for another run
+    pub async fn acquire(
+        paths: &CloudPaths,
+        stage: Stage,
+        wait: Duration,
+    ) -> Result<Self, CloudInitError> {
+        let path = paths.lock_file();
+        tokio::fs::create_dir_all(paths.run_dir())
+            .await
+            .with_path(paths.run_dir())?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_path(&path)?;
+
+        let deadline = Instant::now() + wait;
+        let mut waiting = false;
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::Error(e)) => return Err(e).with_path(&path),
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    if !waiting {
+                        info!(
+                            "Waiting up to {}s for another cloud-init-rs run to finish",
+                            wait.as_secs()
+                        );
+                        waiting = true;
+                    }
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(CloudInitError::Stage {
+                        stage: stage.to_string(),
+                        message: format!(
+                            "another cloud-init-rs run holds {} (waited {}s)",
+                            path.display(),
+                            wait.as_secs()
+                        ),
+                    });
+                }
+            }
+        }
+        debug!("Acquired {}", path.display());
+        Ok(Self { file, path })
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        // Closing the file would release it too; this makes it explicit
+        let _ = self.file.unlock();
+        debug!("Released {}", self.path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_second_run_fails_until_released() {
+        let temp = TempDir::new().unwrap();
+        let paths = CloudPaths::with_base(temp.path());
+
+        let held = RunLock::acquire(&paths, Stage::Local, Duration::ZERO)
+            .await
+            .unwrap();
+        let err = RunLock::acquire(&paths, Stage::Network, Duration::from_millis(250))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("another cloud-init-rs run"));
+
+        drop(held);
+        RunLock::acquire(&paths, Stage::Network, Duration::ZERO)
+            .await
+            .unwrap();
+    }
+}
@@ -4,18 +4,39 @@
 //! - Instance tracking (current vs previous)
 //! - Semaphore files for module execution control
 //! - Cached data and status
-
+//!
+//! Writes go through [`RootContext::current`] so dry runs only record them.
+//!
+//! User data, vendor data and the configuration derived from them are
+//! written readable by root only ([`PRIVATE_FILE_MODE`]), whatever the
+//! umask; the state directories are kept at [`STATE_DIR_MODE`].
+//! [`InstanceState::verify_permissions`] brings trees written by older
+//! versions in line.
+
+pub mod cloud_id;
+pub mod lock;
 pub mod paths;
+pub mod report;
 pub mod semaphore;
 
 pub use paths::CloudPaths;
+pub use report::{BootReporter, ResultReport, StatusReport};
 pub use semaphore::{Frequency, SemaphoreManager};
 
-use crate::CloudInitError;
+use crate::network::NetworkConfig;
+use crate::root::RootContext;
+use crate::{CloudInitError, IoContext};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tokio::fs;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+
+/// Mode of instance files holding user data, vendor data or config
+/// derived from them
+pub const PRIVATE_FILE_MODE: u32 = 0o600;
+
+/// Mode of the state, instance and semaphore directories
+pub const STATE_DIR_MODE: u32 = 0o755;
 
 /// Instance state manager
 #[derive(Debug)]
@@ -100,20 +121,71 @@ impl InstanceState {
         info!("Initializing cloud-init state directories");
 
         // Create base directories
-        fs::create_dir_all(self.paths.data_dir()).await?;
-        fs::create_dir_all(self.paths.instances_dir()).await?;
-        fs::create_dir_all(self.paths.scripts_per_boot()).await?;
-        fs::create_dir_all(self.paths.scripts_per_instance()).await?;
-        fs::create_dir_all(self.paths.scripts_per_once()).await?;
-        fs::create_dir_all(self.paths.seed_dir()).await?;
+        let root = &RootContext::current();
+        root.create_dir_all(&self.paths.data_dir()).await?;
+        root.create_dir_all(&self.paths.instances_dir()).await?;
+        root.create_dir_all(&self.paths.scripts_per_boot()).await?;
+        root.create_dir_all(&self.paths.scripts_per_instance())
+            .await?;
+        root.create_dir_all(&self.paths.scripts_per_once()).await?;
+        root.create_dir_all(&self.paths.seed_dir()).await?;
 
         debug!(
             "Created cloud-init directories under {}",
             self.paths.base.display()
         );
+        if let Err(e) = self.verify_permissions().await {
+            warn!("Could not fix permissions of cloud-init state: {}", e);
+        }
         Ok(())
     }
 
+    /// Fix the modes and ownership of the state tree, for every instance
+    ///
+    /// Ownership is only fixed when running as root. A path that cannot be
+    /// fixed is logged and the rest are still checked. Returns the paths
+    /// that were changed.
+    pub async fn verify_permissions(&self) -> Result<Vec<PathBuf>, CloudInitError> {
+        let mut fixed = Vec::new();
+        let fix_owner = running_as_root();
+        for dir in [self.paths.data_dir(), self.paths.instances_dir()] {
+            warn_unfixed(&dir, ensure_mode(&dir, STATE_DIR_MODE, &mut fixed).await);
+        }
+        let instances_dir = self.paths.instances_dir();
+        let mut entries = match fs::read_dir(&instances_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(fixed),
+            Err(e) => return Err(e).with_path(&instances_dir),
+        };
+        while let Some(entry) = entries.next_entry().await.with_path(&instances_dir)? {
+            if !entry.file_type().await.is_ok_and(|t| t.is_dir()) {
+                continue;
+            }
+            let id = entry.file_name().to_string_lossy().into_owned();
+            for dir in [self.paths.instance_dir(&id), self.paths.sem_dir(&id)] {
+                warn_unfixed(&dir, ensure_mode(&dir, STATE_DIR_MODE, &mut fixed).await);
+            }
+            for file in [
+                self.paths.user_data(&id),
+                self.paths.vendor_data(&id),
+                self.paths.cloud_config(&id),
+                self.paths.network_config(&id),
+            ] {
+                warn_unfixed(
+                    &file,
+                    ensure_mode(&file, PRIVATE_FILE_MODE, &mut fixed).await,
+                );
+                if fix_owner {
+                    warn_unfixed(&file, ensure_root_owned(&file, &mut fixed).await);
+                }
+            }
+        }
+        for path in &fixed {
+            info!("Fixed permissions of {}", path.display());
+        }
+        Ok(fixed)
+    }
+
     /// Set the current instance ID and initialize instance-specific state
     pub async fn set_instance_id(&mut self, instance_id: &str) -> Result<bool, CloudInitError> {
         info!("Setting instance ID: {}", instance_id);
@@ -122,21 +194,32 @@ impl InstanceState {
         let is_new_instance = self.check_instance_change(instance_id).await?;
 
         // Create instance directory
+        let root = &RootContext::current();
         let instance_dir = self.paths.instance_dir(instance_id);
-        fs::create_dir_all(&instance_dir).await?;
+        root.create_dir_all(&instance_dir).await?;
 
         // Create sem directory
         let sem_dir = self.paths.sem_dir(instance_id);
-        fs::create_dir_all(&sem_dir).await?;
+        let semaphores = SemaphoreManager::new(&sem_dir, self.paths.data_dir());
+        if is_new_instance {
+            // An instance ID seen before (or baked into the image) starts
+            // over, so its per-instance modules run again
+            semaphores.clear_all().await?;
+        }
+        root.create_dir_all(&sem_dir).await?;
+        let mut fixed = Vec::new();
+        for dir in [&instance_dir, &sem_dir] {
+            ensure_mode(dir, STATE_DIR_MODE, &mut fixed).await?;
+        }
 
         // Update instance symlink
         self.update_instance_link(instance_id).await?;
 
         // Save instance ID to cache
-        fs::write(self.paths.cached_instance_id(), instance_id).await?;
+        root.write_file_atomic(&self.paths.cached_instance_id(), instance_id)
+            .await?;
 
-        // Initialize semaphore manager
-        self.semaphores = Some(SemaphoreManager::new(sem_dir, self.paths.data_dir()));
+        self.semaphores = Some(semaphores);
         self.instance_id = Some(instance_id.to_string());
 
         if is_new_instance {
@@ -156,7 +239,9 @@ impl InstanceState {
 
             if cached_id != new_id {
                 // Save previous instance ID
-                fs::write(self.paths.previous_instance_id(), cached_id).await?;
+                RootContext::current()
+                    .write_file_atomic(&self.paths.previous_instance_id(), cached_id)
+                    .await?;
                 return Ok(true);
             }
             return Ok(false);
@@ -170,15 +255,17 @@ impl InstanceState {
         let link_path = self.paths.instance_link();
         let target = self.paths.instance_dir(instance_id);
 
+        let root = &RootContext::current();
+
         // Remove existing symlink if present
         if link_path.exists() || link_path.is_symlink() {
-            fs::remove_file(&link_path).await.ok();
+            root.remove_file(&link_path).await.ok();
         }
 
         // Create new symlink
         #[cfg(unix)]
         {
-            std::os::unix::fs::symlink(&target, &link_path)?;
+            root.symlink(&target, &link_path).await?;
             debug!(
                 "Created instance symlink: {} -> {}",
                 link_path.display(),
@@ -189,7 +276,8 @@ impl InstanceState {
         #[cfg(not(unix))]
         {
             // On non-Unix, just write the path to a file
-            fs::write(&link_path, target.to_string_lossy().as_bytes()).await?;
+            root.write_file(&link_path, target.to_string_lossy().as_bytes())
+                .await?;
         }
 
         Ok(())
@@ -199,7 +287,9 @@ impl InstanceState {
     pub async fn save_userdata(&self, data: &str) -> Result<(), CloudInitError> {
         if let Some(id) = &self.instance_id {
             let path = self.paths.user_data(id);
-            fs::write(&path, data).await?;
+            RootContext::current()
+                .write_file_mode(&path, data, PRIVATE_FILE_MODE)
+                .await?;
             debug!("Saved user-data to {}", path.display());
         }
         Ok(())
@@ -209,7 +299,9 @@ impl InstanceState {
     pub async fn save_vendordata(&self, data: &str) -> Result<(), CloudInitError> {
         if let Some(id) = &self.instance_id {
             let path = self.paths.vendor_data(id);
-            fs::write(&path, data).await?;
+            RootContext::current()
+                .write_file_mode(&path, data, PRIVATE_FILE_MODE)
+                .await?;
             debug!("Saved vendor-data to {}", path.display());
         }
         Ok(())
@@ -219,17 +311,49 @@ impl InstanceState {
     pub async fn save_cloud_config(&self, data: &str) -> Result<(), CloudInitError> {
         if let Some(id) = &self.instance_id {
             let path = self.paths.cloud_config(id);
-            fs::write(&path, data).await?;
+            RootContext::current()
+                .write_file_mode(&path, data, PRIVATE_FILE_MODE)
+                .await?;
             debug!("Saved cloud-config to {}", path.display());
         }
         Ok(())
     }
 
+    /// Save datasource-supplied network config to instance directory
+    pub async fn save_network_config(&self, config: &NetworkConfig) -> Result<(), CloudInitError> {
+        if let Some(id) = &self.instance_id {
+            let path = self.paths.network_config(id);
+            RootContext::current()
+                .write_file_mode(
+                    &path,
+                    serde_json::to_string_pretty(config)?,
+                    PRIVATE_FILE_MODE,
+                )
+                .await?;
+            debug!("Saved network config to {}", path.display());
+        }
+        Ok(())
+    }
+
+    /// Load network config saved by [`save_network_config`](Self::save_network_config)
+    pub async fn load_network_config(&self) -> Result<Option<NetworkConfig>, CloudInitError> {
+        let Some(id) = &self.instance_id else {
+            return Ok(None);
+        };
+        match fs::read_to_string(self.paths.network_config(id)).await {
+            Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     /// Save datasource identifier
     pub async fn save_datasource(&self, datasource: &str) -> Result<(), CloudInitError> {
         if let Some(id) = &self.instance_id {
             let path = self.paths.datasource_file(id);
-            fs::write(&path, datasource).await?;
+            RootContext::current()
+                .write_file_atomic(&path, datasource)
+                .await?;
             debug!("Saved datasource identifier: {}", datasource);
         }
         Ok(())
@@ -246,7 +370,9 @@ impl InstanceState {
                     .unwrap_or_default()
                     .as_secs()
             );
-            fs::write(&path, timestamp).await?;
+            RootContext::current()
+                .write_file_atomic(&path, timestamp)
+                .await?;
             info!("Boot finished marker created");
         }
         Ok(())
@@ -265,17 +391,23 @@ impl InstanceState {
     pub async fn update_status(&self, status: &CloudInitStatus) -> Result<(), CloudInitError> {
         let path = self.paths.status_file();
         let json = serde_json::to_string_pretty(status)?;
-        fs::write(&path, json).await?;
+        RootContext::current()
+            .write_file_atomic(&path, json)
+            .await?;
         Ok(())
     }
 
     /// Read current status
+    ///
+    /// A file cut short by a crash reads as not started.
     pub async fn read_status(&self) -> Result<CloudInitStatus, CloudInitError> {
         let path = self.paths.status_file();
         if path.exists() {
             let content = fs::read_to_string(&path).await?;
-            let status: CloudInitStatus = serde_json::from_str(&content)?;
-            Ok(status)
+            Ok(serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable {}: {}", path.display(), e);
+                CloudInitStatus::default()
+            }))
         } else {
             Ok(CloudInitStatus::default())
         }
@@ -336,6 +468,63 @@ impl InstanceState {
     }
 }
 
+/// Whether the process runs as root, and so may change ownership
+fn running_as_root() -> bool {
+    #[cfg(unix)]
+    {
+        rustix::process::geteuid().is_root()
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+/// Log a permission fix of `path` that failed, so the others still run
+fn warn_unfixed(path: &Path, result: Result<(), CloudInitError>) {
+    if let Err(e) = result {
+        warn!("Could not fix permissions of {}: {}", path.display(), e);
+    }
+}
+
+/// Set `path` to `mode` if it exists with another one
+async fn ensure_mode(
+    path: &Path,
+    mode: u32,
+    fixed: &mut Vec<PathBuf>,
+) -> Result<(), CloudInitError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let Ok(metadata) = fs::symlink_metadata(path).await else {
+            return Ok(());
+        };
+        if metadata.permissions().mode() & 0o7777 != mode {
+            RootContext::current().set_mode(path, mode).await?;
+            fixed.push(path.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// Give `path` to root:root if it exists with another owner
+async fn ensure_root_owned(path: &Path, fixed: &mut Vec<PathBuf>) -> Result<(), CloudInitError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let Ok(metadata) = fs::symlink_metadata(path).await else {
+            return Ok(());
+        };
+        if metadata.uid() != 0 || metadata.gid() != 0 {
+            RootContext::current().set_owner(path, 0, 0).await?;
+            if !fixed.iter().any(|p| p == path) {
+                fixed.push(path.to_path_buf());
+            }
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -393,6 +582,35 @@ mod tests {
         assert_eq!(prev.trim(), "i-old");
     }
 
+    #[tokio::test]
+    async fn test_returning_instance_reruns_per_instance_modules() {
+        let (mut state, _temp) = create_test_state().await;
+        state.initialize().await.unwrap();
+
+        state.set_instance_id("i-a").await.unwrap();
+        let sem = state.semaphores().unwrap().clone();
+        sem.mark_done("users", Frequency::PerInstance)
+            .await
+            .unwrap();
+
+        // A reboot of the same instance keeps its semaphores
+        state.set_instance_id("i-a").await.unwrap();
+        assert!(
+            !sem.should_run("users", Frequency::PerInstance)
+                .await
+                .unwrap()
+        );
+
+        // Coming back from another instance starts over
+        state.set_instance_id("i-b").await.unwrap();
+        state.set_instance_id("i-a").await.unwrap();
+        assert!(
+            sem.should_run("users", Frequency::PerInstance)
+                .await
+                .unwrap()
+        );
+    }
+
     #[tokio::test]
     async fn test_save_userdata() {
         let (mut state, temp) = create_test_state().await;
@@ -410,6 +628,47 @@ mod tests {
         assert!(content.contains("hostname: test"));
     }
 
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_verify_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        let (mut state, temp) = create_test_state().await;
+        state.initialize().await.unwrap();
+        state.set_instance_id("i-test").await.unwrap();
+        state.save_userdata("#cloud-config\n").await.unwrap();
+        assert_eq!(mode(&state.paths().user_data("i-test")), PRIVATE_FILE_MODE);
+
+        // A tree written under a loose umask
+        let vendor_data = state.paths().vendor_data("i-test");
+        std::fs::write(&vendor_data, "#cloud-config\n").unwrap();
+        std::fs::set_permissions(&vendor_data, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let sem_dir = temp.path().join("instances/i-test/sem");
+        std::fs::set_permissions(&sem_dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+
+        let fixed = state.verify_permissions().await.unwrap();
+        assert!(fixed.contains(&vendor_data));
+        assert_eq!(mode(&vendor_data), PRIVATE_FILE_MODE);
+        assert_eq!(mode(&sem_dir), STATE_DIR_MODE);
+        assert!(state.verify_permissions().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_network_config_roundtrip() {
+        let (mut state, _temp) = create_test_state().await;
+        state.initialize().await.unwrap();
+        state.set_instance_id("i-test").await.unwrap();
+        assert!(state.load_network_config().await.unwrap().is_none());
+
+        let config =
+            NetworkConfig::from_yaml("version: 2\nethernets:\n  eth0:\n    dhcp4: true\n").unwrap();
+        state.save_network_config(&config).await.unwrap();
+
+        let loaded = state.load_network_config().await.unwrap().unwrap();
+        assert_eq!(loaded.ethernets["eth0"].common.dhcp4, Some(true));
+    }
+
     #[tokio::test]
     async fn test_boot_finished() {
         let (mut state, _temp) = create_test_state().await;
@@ -441,6 +700,16 @@ mod tests {
         assert_eq!(loaded.stage, Some("config".to_string()));
     }
 
+    #[tokio::test]
+    async fn test_truncated_status_reads_as_not_started() {
+        let (mut state, _temp) = create_test_state().await;
+        state.initialize().await.unwrap();
+        std::fs::write(state.paths().status_file(), "{\"status\": \"runn").unwrap();
+
+        let loaded = state.read_status().await.unwrap();
+        assert_eq!(loaded.status, "not-started");
+    }
+
     #[tokio::test]
     async fn test_clean() {
         let (mut state, temp) = create_test_state().await;
@@ -10,6 +10,9 @@ pub const CLOUD_DIR: &str = "/var/lib/cloud";
 /// Cloud configuration directory
 pub const CONFIG_DIR: &str = "/etc/cloud";
 
+/// Runtime directory for per-boot status files
+pub const RUN_DIR: &str = "/run/cloud-init";
+
 /// Standard cloud-init paths
 #[derive(Debug, Clone)]
 pub struct CloudPaths {
@@ -17,6 +20,8 @@ pub struct CloudPaths {
     pub base: PathBuf,
     /// Config directory (default: /etc/cloud)
     pub config: PathBuf,
+    /// Runtime directory (default: /run/cloud-init)
+    pub run: PathBuf,
 }
 
 impl Default for CloudPaths {
@@ -26,12 +31,10 @@ impl Default for CloudPaths {
 }
 
 impl CloudPaths {
-    /// Create with default paths
+    /// Create with default paths, beneath the `--root` directory if one
+    /// was given
     pub fn new() -> Self {
-        Self {
-            base: PathBuf::from(CLOUD_DIR),
-            config: PathBuf::from(CONFIG_DIR),
-        }
+        crate::root::RootContext::current().cloud_paths()
     }
 
     /// Create with custom base directory (useful for testing)
@@ -39,6 +42,7 @@ impl CloudPaths {
         Self {
             base: base.as_ref().to_path_buf(),
             config: PathBuf::from(CONFIG_DIR),
+            run: base.as_ref().join("run"),
         }
     }
 
@@ -47,6 +51,7 @@ impl CloudPaths {
         Self {
             base: base.as_ref().to_path_buf(),
             config: config.as_ref().to_path_buf(),
+            run: base.as_ref().join("run"),
         }
     }
 
@@ -57,6 +62,11 @@ impl CloudPaths {
         self.base.join("data")
     }
 
+    /// /var/lib/cloud/data/include-cache - Cached `#include` responses
+    pub fn include_cache_dir(&self) -> PathBuf {
+        self.data_dir().join("include-cache")
+    }
+
     /// /var/lib/cloud/instances - All instances directory
     pub fn instances_dir(&self) -> PathBuf {
         self.base.join("instances")
@@ -67,16 +77,27 @@ impl CloudPaths {
         self.base.join("instance")
     }
 
+    /// /var/lib/cloud/instance/added-groups.json - Supplementary groups the
+    /// users module added each user to on this instance
+    pub fn added_groups(&self) -> PathBuf {
+        self.instance_link().join("added-groups.json")
+    }
+
     /// /var/lib/cloud/scripts - Scripts directory
     pub fn scripts_dir(&self) -> PathBuf {
         self.base.join("scripts")
     }
 
-    /// /var/lib/cloud/seed - NoCloud seed directory
+    /// /var/lib/cloud/seed - Seed directories baked into images
     pub fn seed_dir(&self) -> PathBuf {
         self.base.join("seed")
     }
 
+    /// `/var/lib/cloud/seed/<name>` - Seed for one datasource, e.g. `nocloud`
+    pub fn datasource_seed_dir(&self, name: &str) -> PathBuf {
+        self.seed_dir().join(name)
+    }
+
     // ==================== Instance-specific Paths ====================
 
     /// `/var/lib/cloud/instances/<id>` - Instance directory
@@ -94,6 +115,11 @@ impl CloudPaths {
         self.instance_dir(instance_id).join("boot-finished")
     }
 
+    /// `/var/lib/cloud/instances/<id>/boothooks` - Saved cloud boothooks
+    pub fn boothooks_dir(&self, instance_id: &str) -> PathBuf {
+        self.instance_dir(instance_id).join("boothooks")
+    }
+
     /// `/var/lib/cloud/instances/<id>/cloud-config.txt` - Merged cloud-config
     pub fn cloud_config(&self, instance_id: &str) -> PathBuf {
         self.instance_dir(instance_id).join("cloud-config.txt")
@@ -109,6 +135,11 @@ impl CloudPaths {
         self.instance_dir(instance_id).join("vendor-data.txt")
     }
 
+    /// `/var/lib/cloud/instances/<id>/network-config.json` - Datasource network config
+    pub fn network_config(&self, instance_id: &str) -> PathBuf {
+        self.instance_dir(instance_id).join("network-config.json")
+    }
+
     /// `/var/lib/cloud/instances/<id>/datasource` - Datasource identifier
     pub fn datasource_file(&self, instance_id: &str) -> PathBuf {
         self.instance_dir(instance_id).join("datasource")
@@ -155,6 +186,17 @@ impl CloudPaths {
         self.data_dir().join("previous-instance-id")
     }
 
+    /// /var/lib/cloud/data/previous-hostname - Hostname cloud-init last set
+    pub fn previous_hostname(&self) -> PathBuf {
+        self.data_dir().join("previous-hostname")
+    }
+
+    /// /var/lib/cloud/data/sealed - Left by `clean --seal` until the next
+    /// boot finishes
+    pub fn sealed_marker(&self) -> PathBuf {
+        self.data_dir().join("sealed")
+    }
+
     /// /var/lib/cloud/data/result.json - Execution result
     pub fn result_file(&self) -> PathBuf {
         self.data_dir().join("result.json")
@@ -164,6 +206,53 @@ impl CloudPaths {
     pub fn status_file(&self) -> PathBuf {
         self.data_dir().join("status.json")
     }
+
+    // ==================== Runtime Paths ====================
+
+    /// /run/cloud-init - Runtime directory
+    pub fn run_dir(&self) -> PathBuf {
+        self.run.clone()
+    }
+
+    /// /run/cloud-init/result.json - Link to the execution result
+    pub fn run_result_file(&self) -> PathBuf {
+        self.run.join("result.json")
+    }
+
+    /// /run/cloud-init/status.json - Link to the current status
+    pub fn run_status_file(&self) -> PathBuf {
+        self.run.join("status.json")
+    }
+
+    /// /run/cloud-init/datasource-snapshot.json - Data fetched this boot
+    pub fn datasource_snapshot(&self) -> PathBuf {
+        self.run.join("datasource-snapshot.json")
+    }
+
+    /// /run/cloud-init/cloud-id - Link to the cloud-id variant in use
+    pub fn cloud_id_file(&self) -> PathBuf {
+        self.run.join("cloud-id")
+    }
+
+    /// /run/cloud-init/cloud-id-<name> - Canonical name of the cloud
+    pub fn cloud_id_variant(&self, cloud_id: &str) -> PathBuf {
+        self.run.join(format!("cloud-id-{}", cloud_id))
+    }
+
+    /// /run/cloud-init/.lock - Held by the stage that is running
+    pub fn lock_file(&self) -> PathBuf {
+        self.run.join(".lock")
+    }
+
+    /// /run/cloud-init/daemon.sock - Stage trigger socket of the daemon
+    pub fn daemon_socket(&self) -> PathBuf {
+        self.run.join("daemon.sock")
+    }
+
+    /// /run/cloud-init/metadata.sock - Socket of `serve-metadata`
+    pub fn metadata_socket(&self) -> PathBuf {
+        self.run.join("metadata.sock")
+    }
 }
 
 #[cfg(test)]
@@ -201,6 +290,10 @@ mod tests {
             paths.boot_finished(id),
             PathBuf::from("/var/lib/cloud/instances/i-1234567890abcdef0/boot-finished")
         );
+        assert_eq!(
+            paths.network_config(id),
+            PathBuf::from("/var/lib/cloud/instances/i-1234567890abcdef0/network-config.json")
+        );
     }
 
     #[test]
@@ -220,6 +313,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_run_paths() {
+        let paths = CloudPaths::new();
+        assert_eq!(paths.run_dir(), PathBuf::from("/run/cloud-init"));
+        assert_eq!(
+            paths.run_status_file(),
+            PathBuf::from("/run/cloud-init/status.json")
+        );
+        assert_eq!(
+            paths.run_result_file(),
+            PathBuf::from("/run/cloud-init/result.json")
+        );
+        assert_eq!(
+            paths.cloud_id_variant("aws"),
+            PathBuf::from("/run/cloud-init/cloud-id-aws")
+        );
+
+        let custom = CloudPaths::with_base("/tmp/cloud");
+        assert_eq!(custom.run_dir(), PathBuf::from("/tmp/cloud/run"));
+    }
+
     #[test]
     fn test_config_paths() {
         let paths = CloudPaths::new();
@@ -0,0 +1,511 @@
+//! Boot-time status reporting
+//!
+//! Writes `status.json` and `result.json` in the schema used by Python
+//! cloud-init so that tooling polling these files (Packer, test harnesses,
+//! monitoring agents) works unchanged.
+//!
+//! The real files live in `/var/lib/cloud/data`; `/run/cloud-init` holds
+//! relative symlinks to them, exactly as Python cloud-init lays them out.
+//! Each stage runs in its own process, so the report is reloaded from disk
+//! at the start of every invocation and updated in place.
+
+use crate::state::CloudPaths;
+use crate::{CloudInitError, Stage};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tracing::debug;
+
+/// Timing and errors for a single stage
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StageReport {
+    /// Errors raised by the stage, including failed modules
+    pub errors: Vec<String>,
+    /// Epoch time the stage finished
+    pub finished: Option<f64>,
+    /// Non-fatal errors, keyed by log level
+    pub recoverable_errors: BTreeMap<String, Vec<String>>,
+    /// Epoch time the stage started
+    pub start: Option<f64>,
+}
+
+/// Version 1 body of `status.json`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatusV1 {
+    /// Datasource description (e.g. `DataSourceNoCloud`)
+    pub datasource: Option<String>,
+    /// Network stage
+    pub init: StageReport,
+    /// Local stage
+    #[serde(rename = "init-local")]
+    pub init_local: StageReport,
+    /// Config stage
+    #[serde(rename = "modules-config")]
+    pub modules_config: StageReport,
+    /// Final stage
+    #[serde(rename = "modules-final")]
+    pub modules_final: StageReport,
+    /// Name of the stage currently running, `null` when idle
+    pub stage: Option<String>,
+}
+
+/// `status.json` document
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatusReport {
+    pub v1: StatusV1,
+}
+
+/// Version 1 body of `result.json`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResultV1 {
+    /// Datasource description
+    pub datasource: Option<String>,
+    /// All errors across every stage
+    pub errors: Vec<String>,
+    /// Non-fatal errors across every stage, keyed by log level
+    #[serde(default)]
+    pub recoverable_errors: BTreeMap<String, Vec<String>>,
+}
+
+/// `result.json` document
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResultReport {
+    pub v1: ResultV1,
+}
+
+/// Level under which warnings go in `recoverable_errors`
+pub const WARNING_LEVEL: &str = "WARNING";
+
+/// Warnings recorded by this process and not yet in a report
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Record a warning for the `recoverable_errors` of the running stage
+///
+/// For problems found deep inside a module that should not fail it but
+/// that the user needs to see, such as user-data parts that cannot run.
+pub fn record_warning(message: impl Into<String>) {
+    WARNINGS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(message.into());
+}
+
+/// Warnings recorded since the last call
+pub fn take_warnings() -> Vec<String> {
+    std::mem::take(&mut *WARNINGS.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+/// Key used for a stage in `status.json`
+pub fn stage_key(stage: Stage) -> &'static str {
+    match stage {
+        Stage::Local => "init-local",
+        Stage::Network => "init",
+        Stage::Config => "modules-config",
+        Stage::Final => "modules-final",
+    }
+}
+
+/// Current time as fractional seconds since the epoch
+fn now_epoch() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+impl StatusReport {
+    /// Mutable access to the entry for `stage`
+    pub fn stage_mut(&mut self, stage: Stage) -> &mut StageReport {
+        match stage {
+            Stage::Local => &mut self.v1.init_local,
+            Stage::Network => &mut self.v1.init,
+            Stage::Config => &mut self.v1.modules_config,
+            Stage::Final => &mut self.v1.modules_final,
+        }
+    }
+
+    /// Record the start of a stage
+    pub fn stage_started(&mut self, stage: Stage) {
+        self.v1.stage = Some(stage_key(stage).to_string());
+        let entry = self.stage_mut(stage);
+        *entry = StageReport {
+            start: Some(now_epoch()),
+            ..Default::default()
+        };
+    }
+
+    /// Record the end of a stage, with its fatal error if any
+    ///
+    /// Errors are stored with secrets masked, see [`crate::redact`].
+    pub fn stage_finished(&mut self, stage: Stage, error: Option<String>) {
+        self.v1.stage = None;
+        let entry = self.stage_mut(stage);
+        entry.finished = Some(now_epoch());
+        entry.errors.extend(error.map(|e| crate::redact::text(&e)));
+    }
+
+    /// Record an error in `stage` that did not abort it, e.g. a failed
+    /// module
+    pub fn add_error(&mut self, stage: Stage, message: String) {
+        self.stage_mut(stage)
+            .errors
+            .push(crate::redact::text(&message));
+    }
+
+    /// Record a warning in `stage` under `recoverable_errors`
+    pub fn add_warning(&mut self, stage: Stage, message: String) {
+        self.stage_mut(stage)
+            .recoverable_errors
+            .entry(WARNING_LEVEL.to_string())
+            .or_default()
+            .push(crate::redact::text(&message));
+    }
+
+    /// Warnings recorded across every stage
+    pub fn warnings(&self) -> Vec<&str> {
+        let v1 = &self.v1;
+        [
+            &v1.init_local,
+            &v1.init,
+            &v1.modules_config,
+            &v1.modules_final,
+        ]
+        .into_iter()
+        .filter_map(|s| s.recoverable_errors.get(WARNING_LEVEL))
+        .flatten()
+        .map(String::as_str)
+        .collect()
+    }
+
+    /// Overall state as `cloud-init status` names it: `not started`,
+    /// `running`, `error` or `done`
+    pub fn summary(&self) -> &'static str {
+        let v1 = &self.v1;
+        let stages = [
+            &v1.init_local,
+            &v1.init,
+            &v1.modules_config,
+            &v1.modules_final,
+        ];
+        if stages.iter().all(|s| s.start.is_none()) {
+            "not started"
+        } else if stages.iter().any(|s| !s.errors.is_empty()) {
+            "error"
+        } else if v1.stage.is_none() && v1.modules_final.finished.is_some() {
+            "done"
+        } else {
+            "running"
+        }
+    }
+
+    /// Build the matching `result.json` from the collected stage errors
+    pub fn to_result(&self) -> ResultReport {
+        let v1 = &self.v1;
+        let stages = [
+            &v1.init_local,
+            &v1.init,
+            &v1.modules_config,
+            &v1.modules_final,
+        ];
+        let errors = stages
+            .iter()
+            .flat_map(|s| s.errors.iter().cloned())
+            .collect();
+        let mut recoverable_errors: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for stage in stages {
+            for (level, messages) in &stage.recoverable_errors {
+                recoverable_errors
+                    .entry(level.clone())
+                    .or_default()
+                    .extend(messages.iter().cloned());
+            }
+        }
+
+        ResultReport {
+            v1: ResultV1 {
+                datasource: v1.datasource.clone(),
+                errors,
+                recoverable_errors,
+            },
+        }
+    }
+}
+
+/// Reads and writes the status/result files for a set of paths
+#[derive(Debug)]
+pub struct BootReporter {
+    paths: CloudPaths,
+    status: StatusReport,
+}
+
+impl BootReporter {
+    /// Load the existing status (if any) for the given paths
+    pub async fn load(paths: CloudPaths) -> Self {
+        let status = match fs::read_to_string(paths.status_file()).await {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                debug!("Ignoring unreadable status.json: {}", e);
+                StatusReport::default()
+            }),
+            Err(_) => StatusReport::default(),
+        };
+        Self { paths, status }
+    }
+
+    /// Current status document
+    pub fn status(&self) -> &StatusReport {
+        &self.status
+    }
+
+    /// Mutable status document
+    pub fn status_mut(&mut self) -> &mut StatusReport {
+        &mut self.status
+    }
+
+    /// Set the datasource description reported in both files
+    pub fn set_datasource(&mut self, datasource: impl Into<String>) {
+        self.status.v1.datasource = Some(datasource.into());
+    }
+
+    /// Pick up the datasource recorded for the cached instance, if any
+    pub async fn refresh_datasource(&mut self) {
+        let Ok(id) = fs::read_to_string(self.paths.cached_instance_id()).await else {
+            return;
+        };
+        if let Ok(ds) = fs::read_to_string(self.paths.datasource_file(id.trim())).await {
+            let ds = ds.trim();
+            if !ds.is_empty() {
+                self.set_datasource(ds);
+            }
+        }
+    }
+
+    /// Write `status.json` and its `/run/cloud-init` link
+    pub async fn write_status(&self) -> Result<(), CloudInitError> {
+        let path = self.paths.status_file();
+        write_json(&path, &self.status).await?;
+        link_into_run_dir(&path, &self.paths.run_status_file()).await
+    }
+
+    /// Write `result.json` and its `/run/cloud-init` link
+    pub async fn write_result(&self) -> Result<(), CloudInitError> {
+        let path = self.paths.result_file();
+        write_json(&path, &self.status.to_result()).await?;
+        link_into_run_dir(&path, &self.paths.run_result_file()).await
+    }
+}
+
+/// Serialize `value` as pretty JSON to `path`, creating parent directories
+async fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), CloudInitError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let json = serde_json::to_string_pretty(value)?;
+    crate::root::write_atomic(
+        path,
+        (json + "\n").as_bytes(),
+        crate::root::DEFAULT_FILE_MODE,
+    )
+    .await?;
+    debug!("Wrote {}", path.display());
+    Ok(())
+}
+
+/// Point `link` at `target` (used for the `/run/cloud-init` copies)
+async fn link_into_run_dir(target: &Path, link: &Path) -> Result<(), CloudInitError> {
+    if let Some(parent) = link.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    if link.exists() || link.is_symlink() {
+        fs::remove_file(link).await?;
+    }
+
+    #[cfg(unix)]
+    {
+        fs::symlink(target, link).await?;
+    }
+
+    #[cfg(not(unix))]
+    {
+        fs::copy(target, link).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_stage_keys() {
+        assert_eq!(stage_key(Stage::Local), "init-local");
+        assert_eq!(stage_key(Stage::Network), "init");
+        assert_eq!(stage_key(Stage::Config), "modules-config");
+        assert_eq!(stage_key(Stage::Final), "modules-final");
+    }
+
+    #[test]
+    fn test_status_json_schema() {
+        let mut status = StatusReport::default();
+        status.v1.datasource = Some("DataSourceNoCloud".to_string());
+        status.stage_started(Stage::Local);
+
+        let json = serde_json::to_value(&status).unwrap();
+        let v1 = &json["v1"];
+        assert_eq!(v1["datasource"], "DataSourceNoCloud");
+        assert_eq!(v1["stage"], "init-local");
+        assert!(v1["init-local"]["start"].is_f64());
+        assert!(v1["init-local"]["finished"].is_null());
+        assert!(v1["init-local"]["errors"].as_array().unwrap().is_empty());
+        assert!(v1["init-local"]["recoverable_errors"].is_object());
+        for key in ["init", "modules-config", "modules-final"] {
+            assert!(v1[key]["start"].is_null(), "{key} should not have started");
+        }
+    }
+
+    #[test]
+    fn test_warnings_are_recoverable() {
+        let mut status = StatusReport::default();
+        status.stage_started(Stage::Network);
+        status.add_warning(Stage::Network, "unsupported part".to_string());
+        status.stage_finished(Stage::Network, None);
+
+        assert_eq!(status.warnings(), vec!["unsupported part"]);
+        assert_eq!(status.summary(), "running");
+        assert_eq!(
+            status.to_result().v1.recoverable_errors[WARNING_LEVEL],
+            vec!["unsupported part".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_stage_finished_records_error() {
+        let mut status = StatusReport::default();
+        status.stage_started(Stage::Config);
+        status.stage_finished(Stage::Config, Some("boom".to_string()));
+
+        assert!(status.v1.stage.is_none());
+        let entry = &status.v1.modules_config;
+        assert!(entry.finished.unwrap() >= entry.start.unwrap());
+        assert_eq!(entry.errors, vec!["boom"]);
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut status = StatusReport::default();
+        assert_eq!(status.summary(), "not started");
+        status.stage_started(Stage::Local);
+        assert_eq!(status.summary(), "running");
+        status.stage_finished(Stage::Local, None);
+        assert_eq!(status.summary(), "running");
+        status.stage_started(Stage::Final);
+        status.stage_finished(Stage::Final, None);
+        assert_eq!(status.summary(), "done");
+        status.add_error(Stage::Local, "boom".to_string());
+        assert_eq!(status.summary(), "error");
+    }
+
+    #[test]
+    fn test_to_result_collects_errors() {
+        let mut status = StatusReport::default();
+        status.v1.datasource = Some("DataSourceEc2".to_string());
+        status.add_error(Stage::Network, "users failed".to_string());
+        status.stage_finished(Stage::Network, Some("net".to_string()));
+        status.stage_finished(Stage::Final, Some("final".to_string()));
+        status
+            .stage_mut(Stage::Config)
+            .recoverable_errors
+            .insert("WARNING".to_string(), vec!["deprecated key".to_string()]);
+
+        let result = serde_json::to_value(status.to_result()).unwrap();
+        assert_eq!(
+            result,
+            serde_json::json!({
+                "v1": {
+                    "datasource": "DataSourceEc2",
+                    "errors": ["users failed", "net", "final"],
+                    "recoverable_errors": {"WARNING": ["deprecated key"]}
+                }
+            })
+        );
+        assert_eq!(status.v1.init.errors, vec!["users failed", "net"]);
+    }
+
+    #[test]
+    fn test_parse_python_status_json() {
+        let json = r#"{
+          "v1": {
+            "datasource": "DataSourceNoCloud [seed=/dev/sr0][dsmode=net]",
+            "init": {"errors": [], "finished": 1700000010.5, "recoverable_errors": {}, "start": 1700000009.1},
+            "init-local": {"errors": [], "finished": 1700000005.2, "recoverable_errors": {}, "start": 1700000004.0},
+            "modules-config": {"errors": [], "finished": null, "recoverable_errors": {}, "start": null},
+            "modules-final": {"errors": [], "finished": null, "recoverable_errors": {}, "start": null},
+            "stage": null
+          }
+        }"#;
+        let status: StatusReport = serde_json::from_str(json).unwrap();
+        assert_eq!(status.v1.init.finished, Some(1700000010.5));
+        assert!(status.v1.stage.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reporter_writes_files_and_links() {
+        let temp = TempDir::new().unwrap();
+        let paths = CloudPaths::with_base(temp.path());
+
+        let mut reporter = BootReporter::load(paths.clone()).await;
+        reporter.set_datasource("DataSourceNoCloud");
+        reporter.status_mut().stage_started(Stage::Final);
+        reporter.status_mut().stage_finished(Stage::Final, None);
+        reporter.write_status().await.unwrap();
+        reporter.write_result().await.unwrap();
+
+        let status: StatusReport =
+            serde_json::from_str(&std::fs::read_to_string(paths.run_status_file()).unwrap())
+                .unwrap();
+        assert!(status.v1.modules_final.finished.is_some());
+
+        let result: ResultReport =
+            serde_json::from_str(&std::fs::read_to_string(paths.run_result_file()).unwrap())
+                .unwrap();
+        assert_eq!(result.v1.datasource.as_deref(), Some("DataSourceNoCloud"));
+        assert!(paths.run_result_file().is_symlink());
+    }
+
+    #[tokio::test]
+    async fn test_reporter_reloads_previous_stages() {
+        let temp = TempDir::new().unwrap();
+        let paths = CloudPaths::with_base(temp.path());
+
+        let mut first = BootReporter::load(paths.clone()).await;
+        first.status_mut().stage_started(Stage::Local);
+        first.status_mut().stage_finished(Stage::Local, None);
+        first.write_status().await.unwrap();
+
+        let second = BootReporter::load(paths).await;
+        assert!(second.status().v1.init_local.finished.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_datasource_from_instance() {
+        let temp = TempDir::new().unwrap();
+        let paths = CloudPaths::with_base(temp.path());
+        std::fs::create_dir_all(paths.instance_dir("i-1")).unwrap();
+        std::fs::create_dir_all(paths.data_dir()).unwrap();
+        std::fs::write(paths.cached_instance_id(), "i-1\n").unwrap();
+        std::fs::write(paths.datasource_file("i-1"), "DataSourceGCE\n").unwrap();
+
+        let mut reporter = BootReporter::load(paths).await;
+        reporter.refresh_datasource().await;
+        assert_eq!(
+            reporter.status().v1.datasource.as_deref(),
+            Some("DataSourceGCE")
+        );
+    }
+}
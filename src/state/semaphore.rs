@@ -4,8 +4,11 @@
 //! - per-instance: Run once per instance ID
 //! - per-boot: Run every boot
 //! - per-once: Run once ever (across all instances)
+//!
+//! Writes go through [`RootContext::current`] so dry runs only record them.
 
 use crate::CloudInitError;
+use crate::root::RootContext;
 use std::path::{Path, PathBuf};
 use tokio::fs;
 use tracing::debug;
@@ -98,14 +101,15 @@ impl SemaphoreManager {
     /// Mark a module as having run (create semaphore)
     pub async fn mark_done(&self, module: &str, freq: Frequency) -> Result<(), CloudInitError> {
         if let Some(path) = self.sem_path(module, freq) {
+            let root = &RootContext::current();
             // Ensure parent directory exists
             if let Some(parent) = path.parent() {
-                fs::create_dir_all(parent).await?;
+                root.create_dir_all(parent).await?;
             }
 
             // Write timestamp to semaphore file
             let timestamp = chrono_lite_timestamp();
-            fs::write(&path, timestamp.as_bytes()).await?;
+            root.write_file_atomic(&path, timestamp).await?;
 
             debug!("Created semaphore: {}", path.display());
         }
@@ -117,7 +121,7 @@ impl SemaphoreManager {
     pub async fn clear(&self, module: &str, freq: Frequency) -> Result<(), CloudInitError> {
         if let Some(path) = self.sem_path(module, freq) {
             if path.exists() {
-                fs::remove_file(&path).await?;
+                RootContext::current().remove_file(&path).await?;
                 debug!("Removed semaphore: {}", path.display());
             }
         }
@@ -127,7 +131,11 @@ impl SemaphoreManager {
     /// Clear all semaphores for this instance
     pub async fn clear_all(&self) -> Result<(), CloudInitError> {
         if self.sem_dir.exists() {
-            fs::remove_dir_all(&self.sem_dir).await?;
+            let root = &RootContext::current();
+            let mut entries = fs::read_dir(&self.sem_dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                root.remove_file(&entry.path()).await?;
+            }
             debug!("Cleared all semaphores in: {}", self.sem_dir.display());
         }
         Ok(())
@@ -295,6 +303,9 @@ mod tests {
         assert_eq!(list.len(), 2);
         assert!(list.contains(&"module_a".to_string()));
         assert!(list.contains(&"module_b".to_string()));
+
+        manager.clear_all().await.unwrap();
+        assert!(manager.list().await.unwrap().is_empty());
     }
 
     #[test]
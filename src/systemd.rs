@@ -0,0 +1,240 @@
+//! systemd integration
+//!
+//! Provides the cloud-init service units (embedded from the `systemd/`
+//! directory so packaging and the binary never drift apart) and a systemd
+//! generator that hooks `cloud-init.target` into `multi-user.target`
+//! unless cloud-init is disabled on the kernel command line.
+//!
+//! The generator is exposed through the hidden `systemd-generate`
+//! subcommand; packages install a small wrapper script into the systemd
+//! generator directory that forwards its arguments to it.
+
+use crate::CloudInitError;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::{debug, info};
+
+/// Kernel command line file
+pub const KERNEL_CMDLINE: &str = "/proc/cmdline";
+
+/// Directory packaged units are installed into
+pub const SYSTEM_UNIT_DIR: &str = "/lib/systemd/system";
+
+/// Name of the target that pulls in all cloud-init stages
+pub const CLOUD_INIT_TARGET: &str = "cloud-init.target";
+
+/// A systemd unit file shipped with cloud-init-rs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnitFile {
+    /// Unit file name (e.g. `cloud-init.service`)
+    pub name: &'static str,
+    /// Unit file contents
+    pub contents: &'static str,
+}
+
+/// All cloud-init-rs units in stage order, followed by the target, the
+/// network hotplug listener and the optional stage daemon
+pub const UNITS: [UnitFile; 7] = [
+    UnitFile {
+        name: "cloud-init-local.service",
+        contents: include_str!("../systemd/cloud-init-local.service"),
+    },
+    UnitFile {
+        name: "cloud-init.service",
+        contents: include_str!("../systemd/cloud-init.service"),
+    },
+    UnitFile {
+        name: "cloud-config.service",
+        contents: include_str!("../systemd/cloud-config.service"),
+    },
+    UnitFile {
+        name: "cloud-final.service",
+        contents: include_str!("../systemd/cloud-final.service"),
+    },
+    UnitFile {
+        name: CLOUD_INIT_TARGET,
+        contents: include_str!("../systemd/cloud-init.target"),
+    },
+    UnitFile {
+        name: "cloud-init-hotplugd.service",
+        contents: include_str!("../systemd/cloud-init-hotplugd.service"),
+    },
+    UnitFile {
+        name: "cloud-init-daemon.service",
+        contents: include_str!("../systemd/cloud-init-daemon.service"),
+    },
+];
+
+/// Write all unit files into `dir`, creating it if necessary
+pub async fn write_units(dir: &Path) -> Result<Vec<PathBuf>, CloudInitError> {
+    fs::create_dir_all(dir).await?;
+
+    let mut written = Vec::with_capacity(UNITS.len());
+    for unit in &UNITS {
+        let path = dir.join(unit.name);
+        fs::write(&path, unit.contents).await?;
+        debug!("Wrote unit file {}", path.display());
+        written.push(path);
+    }
+
+    info!("Wrote {} unit files to {}", written.len(), dir.display());
+    Ok(written)
+}
+
+/// Check whether the kernel command line disables cloud-init
+///
+/// Matches Python cloud-init's `cloud-init=disabled` switch.
+pub fn is_disabled_by_cmdline(cmdline: &str) -> bool {
+    cmdline
+        .split_whitespace()
+        .any(|arg| arg == "cloud-init=disabled")
+}
+
+/// Outcome of running the generator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeneratorResult {
+    /// `cloud-init.target` was linked into `multi-user.target.wants`
+    Enabled,
+    /// cloud-init is disabled; no links were created
+    Disabled,
+}
+
+/// Run the systemd generator
+///
+/// `normal_dir` is the first directory systemd passes to generators. When
+/// cloud-init is enabled a `multi-user.target.wants/cloud-init.target`
+/// symlink is created there; when disabled nothing is written so the
+/// stages never start.
+pub async fn run_generator(
+    normal_dir: &Path,
+    cmdline: &str,
+) -> Result<GeneratorResult, CloudInitError> {
+    if is_disabled_by_cmdline(cmdline) {
+        info!("cloud-init disabled by kernel command line");
+        return Ok(GeneratorResult::Disabled);
+    }
+
+    let wants_dir = normal_dir.join("multi-user.target.wants");
+    fs::create_dir_all(&wants_dir).await?;
+
+    let link = wants_dir.join(CLOUD_INIT_TARGET);
+    let target = Path::new(SYSTEM_UNIT_DIR).join(CLOUD_INIT_TARGET);
+
+    if link.exists() || link.is_symlink() {
+        fs::remove_file(&link).await?;
+    }
+
+    #[cfg(unix)]
+    {
+        fs::symlink(&target, &link).await?;
+    }
+
+    debug!("Linked {} -> {}", link.display(), target.display());
+    Ok(GeneratorResult::Enabled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_units_stage_ordering() {
+        let names: Vec<_> = UNITS.iter().map(|u| u.name).collect();
+        assert_eq!(
+            names,
+            vec![
+                "cloud-init-local.service",
+                "cloud-init.service",
+                "cloud-config.service",
+                "cloud-final.service",
+                "cloud-init.target",
+                "cloud-init-hotplugd.service",
+                "cloud-init-daemon.service",
+            ]
+        );
+
+        // Each service runs after the previous stage
+        assert!(UNITS[1].contents.contains("After=cloud-init-local.service"));
+        assert!(
+            UNITS[2]
+                .contents
+                .contains("After=network-online.target cloud-init.service")
+        );
+        assert!(
+            UNITS[3]
+                .contents
+                .contains("After=network-online.target cloud-config.service")
+        );
+        assert!(UNITS[0].contents.contains("Before=sysinit.target"));
+        // The daemon is ready before the first stage triggers it
+        assert!(
+            UNITS[6]
+                .contents
+                .contains("Before=cloud-init-local.service")
+        );
+    }
+
+    #[test]
+    fn test_units_exec_matching_subcommands() {
+        for (unit, stage) in UNITS.iter().zip(["local", "network", "config", "final"]) {
+            assert!(
+                unit.contents
+                    .contains(&format!("ExecStart=/usr/bin/cloud-init-rs {stage}")),
+                "{} should run the {} stage",
+                unit.name,
+                stage
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_disabled_by_cmdline() {
+        assert!(is_disabled_by_cmdline(
+            "BOOT_IMAGE=/vmlinuz root=/dev/sda1 cloud-init=disabled quiet"
+        ));
+        assert!(!is_disabled_by_cmdline(
+            "BOOT_IMAGE=/vmlinuz root=/dev/sda1"
+        ));
+        assert!(!is_disabled_by_cmdline("cloud-init=enabled"));
+        assert!(!is_disabled_by_cmdline("xcloud-init=disabled"));
+    }
+
+    #[tokio::test]
+    async fn test_write_units() {
+        let dir = TempDir::new().unwrap();
+        let written = write_units(&dir.path().join("units")).await.unwrap();
+        assert_eq!(written.len(), 7);
+        let content = std::fs::read_to_string(&written[1]).unwrap();
+        assert_eq!(content, UNITS[1].contents);
+    }
+
+    #[tokio::test]
+    async fn test_run_generator_enabled_creates_link() {
+        let dir = TempDir::new().unwrap();
+        let result = run_generator(dir.path(), "root=/dev/sda1").await.unwrap();
+        assert_eq!(result, GeneratorResult::Enabled);
+
+        let link = dir.path().join("multi-user.target.wants/cloud-init.target");
+        assert_eq!(
+            std::fs::read_link(&link).unwrap(),
+            Path::new("/lib/systemd/system/cloud-init.target")
+        );
+
+        // Idempotent when run again
+        assert_eq!(
+            run_generator(dir.path(), "").await.unwrap(),
+            GeneratorResult::Enabled
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_generator_disabled_writes_nothing() {
+        let dir = TempDir::new().unwrap();
+        let result = run_generator(dir.path(), "quiet cloud-init=disabled")
+            .await
+            .unwrap();
+        assert_eq!(result, GeneratorResult::Disabled);
+        assert!(!dir.path().join("multi-user.target.wants").exists());
+    }
+}
@@ -1,11 +1,84 @@
 //! Template context building
 //!
 //! Builds the context for Jinja2 template rendering from instance metadata.
-
-use crate::InstanceMetadata;
+//! [`build_system_context`] adds what templates test about the machine
+//! itself: the distribution from os-release (`distro`, `distro_version`,
+//! `distro_release`, `variant`), `machine` and `kernel`, both at the top
+//! level and in `v1`, and the merged system configuration as `conf`:
+//!
+//! ```text
+//! {% if distro == 'ubuntu' and conf.manage_etc_hosts %}...{% endif %}
+//! ```
+
+use crate::config::CloudConfig;
+use crate::distro::Distro;
+use crate::root::RootContext;
+use crate::{CloudInitError, InstanceMetadata};
 use minijinja::value::Value;
 use std::collections::HashMap;
 
+/// Kernel release of the running system
+const KERNEL_RELEASE_PATH: &str = "/proc/sys/kernel/osrelease";
+
+/// Facts about the system a template runs on
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SystemFacts {
+    /// os-release `ID`, e.g. `ubuntu`
+    pub distro: String,
+    /// os-release `VERSION_ID`, e.g. `24.04`
+    pub distro_version: String,
+    /// os-release `VERSION_CODENAME`, e.g. `noble`
+    pub distro_release: String,
+    /// os-release `VARIANT_ID`
+    pub variant: String,
+    /// CPU architecture, e.g. `x86_64`
+    pub machine: String,
+    /// Kernel release, e.g. `6.8.0-31-generic`
+    pub kernel: String,
+}
+
+impl SystemFacts {
+    /// Facts of the system under `root`; the kernel is the running one
+    pub async fn detect(root: &RootContext) -> Self {
+        let kernel = tokio::fs::read_to_string(KERNEL_RELEASE_PATH)
+            .await
+            .unwrap_or_default();
+        Self {
+            kernel: kernel.trim().to_string(),
+            ..Self::from_distro(Distro::detect(root).await)
+        }
+    }
+
+    /// Distribution facts from os-release content
+    pub fn from_os_release(content: &str) -> Self {
+        Self::from_distro(Distro::from_os_release(content))
+    }
+
+    fn from_distro(distro: Distro) -> Self {
+        Self {
+            distro: distro.id,
+            distro_version: distro.version_id,
+            distro_release: distro.version_codename,
+            variant: distro.variant_id,
+            machine: std::env::consts::ARCH.to_string(),
+            kernel: String::new(),
+        }
+    }
+
+    /// The facts by the names templates use, `kernel` as `kernel_release`
+    /// when `v1` is set, as in `v1.kernel_release`
+    fn entries(&self, v1: bool) -> [(&'static str, &str); 6] {
+        [
+            ("distro", &self.distro),
+            ("distro_version", &self.distro_version),
+            ("distro_release", &self.distro_release),
+            ("variant", &self.variant),
+            ("machine", &self.machine),
+            (if v1 { "kernel_release" } else { "kernel" }, &self.kernel),
+        ]
+    }
+}
+
 /// Build the template context from instance metadata
 pub fn build_context(metadata: &InstanceMetadata) -> HashMap<String, Value> {
     let mut ctx = HashMap::new();
@@ -17,7 +90,7 @@ pub fn build_context(metadata: &InstanceMetadata) -> HashMap<String, Value> {
     ctx.insert("instance".to_string(), build_instance_context(metadata));
 
     // Add v1 data format (cloud-init compatibility)
-    ctx.insert("v1".to_string(), build_v1_context(metadata));
+    ctx.insert("v1".to_string(), build_v1_context(metadata, None));
 
     // Add local_hostname as top-level variable (commonly used)
     if let Some(hostname) = &metadata.local_hostname {
@@ -32,12 +105,43 @@ pub fn build_context(metadata: &InstanceMetadata) -> HashMap<String, Value> {
     ctx
 }
 
+/// Build the full template context: metadata, system facts and `conf`
+pub fn build_system_context(
+    metadata: &InstanceMetadata,
+    facts: &SystemFacts,
+    conf: &CloudConfig,
+) -> HashMap<String, Value> {
+    let mut ctx = build_context(metadata);
+    ctx.insert("v1".to_string(), build_v1_context(metadata, Some(facts)));
+    add_system_facts(&mut ctx, facts, conf);
+    ctx
+}
+
+/// Add the top-level system facts and `conf` to `ctx`
+///
+/// Variables already in `ctx`, e.g. from an `instance-data.json`, are
+/// kept.
+pub fn add_system_facts(ctx: &mut HashMap<String, Value>, facts: &SystemFacts, conf: &CloudConfig) {
+    for (key, value) in facts.entries(false) {
+        ctx.entry(key.to_string())
+            .or_insert_with(|| Value::from(value));
+    }
+    ctx.entry("conf".to_string())
+        .or_insert_with(|| Value::from_serialize(conf));
+}
+
 /// Build datasource (ds) context
 fn build_ds_context(metadata: &InstanceMetadata) -> Value {
     let mut ds = HashMap::new();
 
-    // ds.meta_data - instance metadata
+    // ds.meta_data - instance metadata, starting from the provider's own
+    // keys so templates can reach provider-specific data
     let mut meta_data = HashMap::new();
+    if let serde_json::Value::Object(raw) = &metadata.raw {
+        for (key, value) in raw {
+            meta_data.insert(key.clone(), Value::from_serialize(value));
+        }
+    }
 
     if let Some(id) = &metadata.instance_id {
         meta_data.insert("instance-id".to_string(), Value::from(id.clone()));
@@ -115,15 +219,21 @@ fn build_instance_context(metadata: &InstanceMetadata) -> Value {
 }
 
 /// Build v1 data context (cloud-init compatibility format)
-fn build_v1_context(metadata: &InstanceMetadata) -> Value {
+///
+/// Besides the standardized keys this has their older hyphenated
+/// spellings and `public_ssh_keys`, as Python cloud-init's `v1` does,
+/// and the instance `tags` as a map of name to value.
+fn build_v1_context(metadata: &InstanceMetadata, facts: Option<&SystemFacts>) -> Value {
     let mut v1 = HashMap::new();
 
     if let Some(id) = &metadata.instance_id {
         v1.insert("instance_id".to_string(), Value::from(id.clone()));
+        v1.insert("instance-id".to_string(), Value::from(id.clone()));
     }
 
     if let Some(hostname) = &metadata.local_hostname {
         v1.insert("local_hostname".to_string(), Value::from(hostname.clone()));
+        v1.insert("local-hostname".to_string(), Value::from(hostname.clone()));
     }
 
     if let Some(region) = &metadata.region {
@@ -132,19 +242,56 @@ fn build_v1_context(metadata: &InstanceMetadata) -> Value {
 
     if let Some(az) = &metadata.availability_zone {
         v1.insert("availability_zone".to_string(), Value::from(az.clone()));
+        v1.insert("availability-zone".to_string(), Value::from(az.clone()));
     }
 
     if let Some(cloud) = &metadata.cloud_name {
         v1.insert("cloud_name".to_string(), Value::from(cloud.clone()));
+        v1.insert("cloud-name".to_string(), Value::from(cloud.clone()));
     }
 
     if let Some(platform) = &metadata.platform {
         v1.insert("platform".to_string(), Value::from(platform.clone()));
     }
 
+    if let Some(instance_type) = &metadata.instance_type {
+        v1.insert(
+            "instance_type".to_string(),
+            Value::from(instance_type.clone()),
+        );
+    }
+
+    v1.insert(
+        "public_ssh_keys".to_string(),
+        Value::from_serialize(metadata.public_keys()),
+    );
+    v1.insert("tags".to_string(), Value::from_serialize(metadata.tags()));
+
+    for (key, value) in facts.iter().flat_map(|facts| facts.entries(true)) {
+        v1.insert(key.to_string(), Value::from(value));
+    }
+
     Value::from_serialize(&v1)
 }
 
+/// Build the template context from an `instance-data.json` document
+///
+/// Its top-level keys (`v1`, `ds`, ...) become the template variables,
+/// as when Python cloud-init renders against the file.
+pub fn context_from_instance_data(
+    data: &serde_json::Value,
+) -> Result<HashMap<String, Value>, CloudInitError> {
+    let serde_json::Value::Object(data) = data else {
+        return Err(CloudInitError::InvalidData(
+            "instance data must be a JSON object".to_string(),
+        ));
+    };
+    Ok(data
+        .iter()
+        .map(|(key, value)| (key.clone(), Value::from_serialize(value)))
+        .collect())
+}
+
 /// Merge additional variables into context
 pub fn merge_context(base: &mut HashMap<String, Value>, additional: HashMap<String, Value>) {
     for (key, value) in additional {
@@ -165,6 +312,7 @@ mod tests {
             cloud_name: Some("aws".to_string()),
             platform: Some("ec2".to_string()),
             instance_type: Some("t3.micro".to_string()),
+            ..Default::default()
         }
     }
 
@@ -189,6 +337,31 @@ mod tests {
         assert!(!ds.is_undefined());
     }
 
+    #[test]
+    fn test_ds_context_includes_raw_metadata() {
+        let metadata = InstanceMetadata {
+            instance_id: Some("i-1".to_string()),
+            raw: serde_json::json!({"instance-id": "stale", "security-groups": ["web"]}),
+            ..Default::default()
+        };
+        let ds = build_ds_context(&metadata);
+        let meta_data = ds.get_attr("meta_data").unwrap();
+        assert_eq!(
+            meta_data
+                .get_attr("security-groups")
+                .unwrap()
+                .get_item(&Value::from(0))
+                .unwrap()
+                .to_string(),
+            "web"
+        );
+        // The typed fields win
+        assert_eq!(
+            meta_data.get_attr("instance-id").unwrap().to_string(),
+            "i-1"
+        );
+    }
+
     #[test]
     fn test_build_instance_context() {
         let metadata = test_metadata();
@@ -200,9 +373,79 @@ mod tests {
     #[test]
     fn test_build_v1_context() {
         let metadata = test_metadata();
-        let v1 = build_v1_context(&metadata);
+        let v1 = build_v1_context(&metadata, None);
 
         assert!(!v1.is_undefined());
+        assert_eq!(v1.get_attr("cloud-name").unwrap().to_string(), "aws");
+    }
+
+    #[test]
+    fn test_v1_tags() {
+        let metadata = InstanceMetadata {
+            raw: serde_json::json!({"tags": {"instance": {"role": "web", "Name": "web-1"}}}),
+            ..test_metadata()
+        };
+        let rendered = crate::template::render_template_with_context(
+            "{% if v1.tags.role == 'web' %}{{ v1.tags.Name }}{% endif %}",
+            &build_context(&metadata),
+        )
+        .unwrap();
+        assert_eq!(rendered, "web-1");
+
+        // Instances without tags in their metadata get an empty map
+        let v1 = build_v1_context(&test_metadata(), None);
+        assert_eq!(v1.get_attr("tags").unwrap().len(), Some(0));
+    }
+
+    #[test]
+    fn test_os_release_facts() {
+        let facts = SystemFacts::from_os_release(
+            "NAME=\"Ubuntu\"\nID=ubuntu\nVERSION_ID=\"24.04\"\nVERSION_CODENAME=noble\n",
+        );
+        assert_eq!(facts.distro, "ubuntu");
+        assert_eq!(facts.distro_version, "24.04");
+        assert_eq!(facts.distro_release, "noble");
+        assert_eq!(facts.machine, std::env::consts::ARCH);
+    }
+
+    #[test]
+    fn test_system_context() {
+        let facts = SystemFacts {
+            kernel: "6.8.0-31-generic".to_string(),
+            ..SystemFacts::from_os_release("ID=ubuntu\nVERSION_ID=24.04\n")
+        };
+        let conf = CloudConfig {
+            hostname: Some("base".to_string()),
+            ..Default::default()
+        };
+        let ctx = build_system_context(&test_metadata(), &facts, &conf);
+        let rendered = crate::template::render_template_with_context(
+            "{% if distro == 'ubuntu' %}{{ v1.distro_version }} {{ kernel }} \
+             {{ v1.kernel_release }} {{ conf.hostname }} {{ v1.cloud_name }}{% endif %}",
+            &ctx,
+        )
+        .unwrap();
+        assert_eq!(rendered, "24.04 6.8.0-31-generic 6.8.0-31-generic base aws");
+
+        // instance-data.json variables are kept
+        let mut ctx = context_from_instance_data(&serde_json::json!({"distro": "debian"})).unwrap();
+        add_system_facts(&mut ctx, &facts, &conf);
+        assert_eq!(ctx["distro"].to_string(), "debian");
+        assert_eq!(ctx["distro_version"].to_string(), "24.04");
+    }
+
+    #[test]
+    fn test_context_from_instance_data() {
+        let data = serde_json::json!({"v1": {"cloud_name": "aws", "region": "us-east-1"}});
+        let ctx = context_from_instance_data(&data).unwrap();
+        let rendered = crate::template::render_template_with_context(
+            "## template: jinja\n{{ v1.cloud_name }}/{{ v1.region }}",
+            &ctx,
+        )
+        .unwrap();
+        assert_eq!(rendered, "aws/us-east-1");
+
+        assert!(context_from_instance_data(&serde_json::json!([1])).is_err());
     }
 
     #[test]
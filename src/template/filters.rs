@@ -0,0 +1,261 @@
+//! Filters and functions that cloud-init templates rely on
+//!
+//! Python cloud-init templates are rendered by Jinja2 with a few extras
+//! that minijinja does not ship:
+//!
+//! - `b64encode`, `b64decode`
+//! - `regex_replace(pattern, replacement)`, with `\1` back-references
+//! - `to_json`/`tojson`, `to_yaml`
+//! - IP math on addresses and CIDRs: `ip_network`, `ip_netmask`,
+//!   `ip_prefix`, `ip_add(n)` and `ip_in_network(cidr)`
+//!
+//! ```text
+//! {{ "10.0.0.7/24" | ip_network }}            10.0.0.0/24
+//! {{ "10.0.0.7" | ip_add(3) }}                10.0.0.10
+//! {{ v1.local_hostname | regex_replace('\\.internal$', '') }}
+//! ```
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use minijinja::value::Value;
+use minijinja::{Environment, Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Register the filters, also as functions, in `env`
+pub fn register(env: &mut Environment<'_>) {
+    env.add_filter("b64encode", b64encode);
+    env.add_filter("b64decode", b64decode);
+    env.add_filter("regex_replace", regex_replace);
+    env.add_filter("to_json", to_json);
+    env.add_filter("tojson", to_json);
+    env.add_filter("to_yaml", to_yaml);
+    env.add_filter("ip_network", ip_network);
+    env.add_filter("ip_netmask", ip_netmask);
+    env.add_filter("ip_prefix", ip_prefix);
+    env.add_filter("ip_add", ip_add);
+    env.add_filter("ip_in_network", ip_in_network);
+    env.add_function("ip_network", ip_network);
+    env.add_function("ip_netmask", ip_netmask);
+    env.add_function("ip_in_network", ip_in_network);
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidOperation, message)
+}
+
+fn b64encode(value: String) -> String {
+    BASE64.encode(value)
+}
+
+fn b64decode(value: String) -> Result<String, Error> {
+    let bytes = BASE64
+        .decode(value.trim())
+        .map_err(|e| invalid(format!("b64decode: {}", e)))?;
+    String::from_utf8(bytes).map_err(|e| invalid(format!("b64decode: {}", e)))
+}
+
+/// `re.sub` semantics: `\1` and `\g<name>` refer to groups
+fn regex_replace(value: String, pattern: String, replacement: String) -> Result<String, Error> {
+    let regex = regex::Regex::new(&pattern)
+        .map_err(|e| invalid(format!("regex_replace: bad pattern {:?}: {}", pattern, e)))?;
+    Ok(regex
+        .replace_all(&value, python_replacement(&replacement))
+        .into_owned())
+}
+
+/// A Python replacement string in the syntax of the `regex` crate
+fn python_replacement(replacement: &str) -> String {
+    let mut out = String::with_capacity(replacement.len());
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '$' => out.push_str("$$"),
+            '\\' => match chars.peek().copied() {
+                Some(d) if d.is_ascii_digit() => {
+                    let mut group = String::new();
+                    while let Some(d) = chars.next_if(char::is_ascii_digit) {
+                        group.push(d);
+                    }
+                    out.push_str(&format!("${{{}}}", group));
+                }
+                Some('g') => {
+                    chars.next();
+                    let name: String = match chars.next_if_eq(&'<') {
+                        Some(_) => chars.by_ref().take_while(|&c| c != '>').collect(),
+                        None => String::new(),
+                    };
+                    out.push_str(&format!("${{{}}}", name));
+                }
+                Some('\\') => {
+                    chars.next();
+                    out.push('\\');
+                }
+                _ => out.push('\\'),
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn to_json(value: Value) -> Result<String, Error> {
+    serde_json::to_string(&value).map_err(|e| invalid(format!("to_json: {}", e)))
+}
+
+fn to_yaml(value: Value) -> Result<String, Error> {
+    serde_yaml::to_string(&value).map_err(|e| invalid(format!("to_yaml: {}", e)))
+}
+
+/// An address with an optional `/prefix`, the full length if absent
+fn parse_cidr(value: &str) -> Result<(IpAddr, u8), Error> {
+    let (addr, prefix) = match value.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (value, None),
+    };
+    let addr: IpAddr = addr
+        .trim()
+        .parse()
+        .map_err(|_| invalid(format!("not an IP address: {:?}", value)))?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix
+            .trim()
+            .parse()
+            .ok()
+            .filter(|&p| p <= max)
+            .ok_or_else(|| invalid(format!("bad prefix length in {:?}", value)))?,
+        None => max,
+    };
+    Ok((addr, prefix))
+}
+
+/// The mask of `prefix` bits for addresses like `addr`
+fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(_) => {
+            let bits = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(bits))
+        }
+        IpAddr::V6(_) => {
+            let bits = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(bits))
+        }
+    }
+}
+
+fn network(addr: IpAddr, prefix: u8) -> IpAddr {
+    match (addr, mask(addr, prefix)) {
+        (IpAddr::V4(a), IpAddr::V4(m)) => IpAddr::V4(Ipv4Addr::from(a.to_bits() & m.to_bits())),
+        (IpAddr::V6(a), IpAddr::V6(m)) => IpAddr::V6(Ipv6Addr::from(a.to_bits() & m.to_bits())),
+        _ => unreachable!("mask has the address family of its address"),
+    }
+}
+
+/// `10.0.0.7/24` → `10.0.0.0/24`
+fn ip_network(value: String) -> Result<String, Error> {
+    let (addr, prefix) = parse_cidr(&value)?;
+    Ok(format!("{}/{}", network(addr, prefix), prefix))
+}
+
+/// `10.0.0.7/24` → `255.255.255.0`
+fn ip_netmask(value: String) -> Result<String, Error> {
+    let (addr, prefix) = parse_cidr(&value)?;
+    Ok(mask(addr, prefix).to_string())
+}
+
+/// `10.0.0.7/24` → `24`
+fn ip_prefix(value: String) -> Result<u8, Error> {
+    Ok(parse_cidr(&value)?.1)
+}
+
+/// The address `offset` places after (or before) the given one
+fn ip_add(value: String, offset: i64) -> Result<String, Error> {
+    let (addr, _) = parse_cidr(&value)?;
+    let overflow = || invalid(format!("{} + {} is out of range", value, offset));
+    let added = match addr {
+        IpAddr::V4(a) => {
+            let bits = i64::from(a.to_bits())
+                .checked_add(offset)
+                .and_then(|bits| u32::try_from(bits).ok())
+                .ok_or_else(overflow)?;
+            IpAddr::V4(Ipv4Addr::from(bits))
+        }
+        IpAddr::V6(a) => {
+            let bits = a
+                .to_bits()
+                .checked_add_signed(i128::from(offset))
+                .ok_or_else(overflow)?;
+            IpAddr::V6(Ipv6Addr::from(bits))
+        }
+    };
+    Ok(added.to_string())
+}
+
+/// Whether the address is inside `cidr`
+fn ip_in_network(value: String, cidr: String) -> Result<bool, Error> {
+    let (addr, _) = parse_cidr(&value)?;
+    let (net, prefix) = parse_cidr(&cidr)?;
+    Ok(addr.is_ipv4() == net.is_ipv4() && network(addr, prefix) == network(net, prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use minijinja::context;
+
+    fn render(template: &str) -> String {
+        let mut env = Environment::new();
+        register(&mut env);
+        env.render_str(template, context! { data => context! { a => 1 } })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_encoding_filters() {
+        assert_eq!(render("{{ 'hi' | b64encode }}"), "aGk=");
+        assert_eq!(render("{{ 'aGk=' | b64decode }}"), "hi");
+        assert_eq!(render("{{ data | to_json }}"), r#"{"a":1}"#);
+        assert_eq!(render("{{ data | to_yaml }}"), "a: 1\n");
+    }
+
+    #[test]
+    fn test_regex_replace() {
+        // As in Jinja2, a backslash in a string literal needs escaping
+        assert_eq!(
+            render(r"{{ 'host.internal' | regex_replace('^(\\w+)\\.internal$', 'x-\\1') }}"),
+            "x-host"
+        );
+        assert_eq!(
+            render(r"{{ 'a1b2' | regex_replace('(?P<d>\\d)', '[\\g<d>]$') }}"),
+            "a[1]$b[2]$"
+        );
+    }
+
+    #[test]
+    fn test_ip_math() {
+        assert_eq!(render("{{ '10.0.0.7/24' | ip_network }}"), "10.0.0.0/24");
+        assert_eq!(render("{{ ip_netmask('10.0.0.7/20') }}"), "255.255.240.0");
+        assert_eq!(render("{{ '2001:db8::1/64' | ip_prefix }}"), "64");
+        assert_eq!(render("{{ '10.0.0.250' | ip_add(10) }}"), "10.0.1.4");
+        assert_eq!(render("{{ '2001:db8::1' | ip_add(-1) }}"), "2001:db8::");
+        assert_eq!(
+            render("{{ '10.0.0.7' | ip_in_network('10.0.0.0/24') }}"),
+            "True"
+        );
+        assert_eq!(
+            render("{{ '10.0.1.7' | ip_in_network('10.0.0.0/24') }}"),
+            "False"
+        );
+
+        let mut env = Environment::new();
+        register(&mut env);
+        assert!(
+            env.render_str("{{ '255.255.255.255' | ip_add(1) }}", ())
+                .is_err()
+        );
+        assert!(
+            env.render_str("{{ '10.0.0.1/33' | ip_network }}", ())
+                .is_err()
+        );
+    }
+}
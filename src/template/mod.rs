@@ -3,17 +3,98 @@
 //! Renders cloud-config templates using instance metadata.
 //!
 //! Templates can use the `## template: jinja` header marker to enable
-//! Jinja2 processing.
+//! Jinja2 processing. The filters Python cloud-init templates use are
+//! available too (see [`filters`]).
+//!
+//! Undefined variables render as empty by default. [`UndefinedVariables`]
+//! can make them an error instead, or render them as
+//! `CI_MISSING_JINJA_VAR/<name>` like Python cloud-init does.
 
 pub mod context;
+pub mod filters;
 
-pub use context::{build_context, merge_context};
+pub use context::{
+    SystemFacts, add_system_facts, build_context, build_system_context, context_from_instance_data,
+    merge_context,
+};
 
 use crate::{CloudInitError, InstanceMetadata};
-use minijinja::Environment;
+use minijinja::{Environment, UndefinedBehavior};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::str::FromStr;
 use tracing::debug;
 
+/// Prefix Python cloud-init renders undefined variables with
+pub const MISSING_VAR_MARKER: &str = "CI_MISSING_JINJA_VAR/";
+
+/// How a template treats variables missing from its context
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UndefinedVariables {
+    /// Render as empty (default)
+    #[default]
+    Lenient,
+    /// Fail the render; testing one with `{% if %}` is still allowed
+    Strict,
+    /// Render top-level variables as `CI_MISSING_JINJA_VAR/<name>`
+    Marker,
+}
+
+impl FromStr for UndefinedVariables {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lenient" => Ok(Self::Lenient),
+            "strict" => Ok(Self::Strict),
+            "marker" => Ok(Self::Marker),
+            other => Err(format!(
+                "unknown undefined-variable mode '{}' (expected lenient, strict or marker)",
+                other
+            )),
+        }
+    }
+}
+
+/// An environment with the cloud-init filters and `undefined` behavior
+fn environment(undefined: UndefinedVariables) -> Environment<'static> {
+    let mut env = Environment::new();
+    filters::register(&mut env);
+    if undefined == UndefinedVariables::Strict {
+        env.set_undefined_behavior(UndefinedBehavior::SemiStrict);
+    }
+    env
+}
+
+/// Render `template` in `env`, which applies `undefined`
+fn render_in(
+    mut env: Environment<'static>,
+    template: &str,
+    context: &HashMap<String, minijinja::Value>,
+    undefined: UndefinedVariables,
+) -> Result<String, CloudInitError> {
+    env.add_template_owned("template", strip_template_marker(template).to_string())
+        .map_err(|e| CloudInitError::InvalidData(format!("Template parse error: {}", e)))?;
+    let tmpl = env
+        .get_template("template")
+        .map_err(|e| CloudInitError::InvalidData(format!("Template error: {}", e)))?;
+
+    let rendered = if undefined == UndefinedVariables::Marker {
+        let mut context = context.clone();
+        for name in tmpl.undeclared_variables(false) {
+            if !context.contains_key(&name) && !env.globals().any(|(global, _)| global == name) {
+                let marker = format!("{}{}", MISSING_VAR_MARKER, name);
+                context.insert(name, marker.into());
+            }
+        }
+        tmpl.render(&context)
+    } else {
+        tmpl.render(context)
+    };
+    rendered.map_err(|e| CloudInitError::InvalidData(format!("Template render error: {}", e)))
+}
+
 /// Check if content is a Jinja template (has the template marker)
 pub fn is_jinja_template(content: &str) -> bool {
     let trimmed = content.trim_start();
@@ -47,28 +128,8 @@ pub fn render_template_with_context(
     context: &HashMap<String, minijinja::Value>,
 ) -> Result<String, CloudInitError> {
     debug!("Rendering Jinja template");
-
-    // Strip template marker if present
-    let template_content = strip_template_marker(template);
-
-    // Create environment
-    let mut env = Environment::new();
-
-    // Add template
-    env.add_template("template", template_content)
-        .map_err(|e| CloudInitError::InvalidData(format!("Template parse error: {}", e)))?;
-
-    // Get template
-    let tmpl = env
-        .get_template("template")
-        .map_err(|e| CloudInitError::InvalidData(format!("Template error: {}", e)))?;
-
-    // Render with context
-    let rendered = tmpl
-        .render(context)
-        .map_err(|e| CloudInitError::InvalidData(format!("Template render error: {}", e)))?;
-
-    Ok(rendered)
+    let undefined = UndefinedVariables::default();
+    render_in(environment(undefined), template, context, undefined)
 }
 
 /// Process content that may or may not be a template
@@ -87,25 +148,34 @@ pub fn process_template(
 pub struct TemplateRenderer {
     env: Environment<'static>,
     context: HashMap<String, minijinja::Value>,
+    undefined: UndefinedVariables,
 }
 
 impl TemplateRenderer {
     /// Create a new template renderer
     pub fn new() -> Self {
         Self {
-            env: Environment::new(),
+            env: environment(UndefinedVariables::default()),
             context: HashMap::new(),
+            undefined: UndefinedVariables::default(),
         }
     }
 
     /// Create with instance metadata context
     pub fn with_metadata(metadata: &InstanceMetadata) -> Self {
         Self {
-            env: Environment::new(),
             context: build_context(metadata),
+            ..Self::new()
         }
     }
 
+    /// Treat undefined variables as `undefined` says
+    pub fn with_undefined(mut self, undefined: UndefinedVariables) -> Self {
+        self.env = environment(undefined);
+        self.undefined = undefined;
+        self
+    }
+
     /// Add a variable to the context
     pub fn add_var(&mut self, name: impl Into<String>, value: impl Into<minijinja::Value>) {
         self.context.insert(name.into(), value.into());
@@ -118,18 +188,7 @@ impl TemplateRenderer {
 
     /// Render a template string
     pub fn render(&self, template: &str) -> Result<String, CloudInitError> {
-        let template_content = strip_template_marker(template);
-
-        let mut env = self.env.clone();
-        env.add_template("template", template_content)
-            .map_err(|e| CloudInitError::InvalidData(format!("Template parse error: {}", e)))?;
-
-        let tmpl = env
-            .get_template("template")
-            .map_err(|e| CloudInitError::InvalidData(format!("Template error: {}", e)))?;
-
-        tmpl.render(&self.context)
-            .map_err(|e| CloudInitError::InvalidData(format!("Template render error: {}", e)))
+        render_in(self.env.clone(), template, &self.context, self.undefined)
     }
 
     /// Check if content needs template processing
@@ -166,6 +225,7 @@ mod tests {
             cloud_name: Some("aws".to_string()),
             platform: Some("ec2".to_string()),
             instance_type: Some("t3.micro".to_string()),
+            ..Default::default()
         }
     }
 
@@ -312,6 +372,31 @@ packages:
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_undefined_variables() {
+        let template = "## template: jinja\nvalue: {{ missing_var }}";
+        let renderer = TemplateRenderer::new();
+
+        let strict = renderer.with_undefined(UndefinedVariables::Strict);
+        let err = strict.render(template).unwrap_err();
+        assert!(err.to_string().contains("undefined"), "{}", err);
+        strict.render("{% if nope %}x{% endif %}ok").unwrap();
+
+        let marker = TemplateRenderer::new().with_undefined(UndefinedVariables::Marker);
+        assert_eq!(
+            marker.render(template).unwrap(),
+            "value: CI_MISSING_JINJA_VAR/missing_var"
+        );
+        // Globals such as range() are not variables
+        assert_eq!(marker.render("{{ range(2) | list }}").unwrap(), "[0, 1]");
+
+        assert_eq!(
+            "marker".parse::<UndefinedVariables>(),
+            Ok(UndefinedVariables::Marker)
+        );
+        assert!("loud".parse::<UndefinedVariables>().is_err());
+    }
+
     #[test]
     fn test_render_invalid_syntax() {
         let template = "## template: jinja\nvalue: {{ invalid";
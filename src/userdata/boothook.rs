@@ -0,0 +1,226 @@
+//! Cloud boothooks
+//!
+//! A `#cloud-boothook` user-data part (or a `text/cloud-boothook` MIME
+//! part) is a script that runs on every boot, as early as user-data is
+//! processed and before the modules of the Network stage. Boothooks are
+//! written to the instance's `boothooks` directory when user-data is
+//! cached, with the `#cloud-boothook` line removed, and run from there
+//! with `INSTANCE_ID` set, so they can guard one-time work themselves:
+//!
+//! ```text
+//! #cloud-boothook
+//! #!/bin/sh
+//! [ -e /var/lib/my-once-$INSTANCE_ID ] && exit 0
+//! ...
+//! ```
+
+use super::{ContentType, ScriptPart};
+use crate::root::RootContext;
+use crate::runner::{SystemCommand, SystemRunner};
+use crate::state::CloudPaths;
+use crate::{CloudInitError, IoContext, UserData};
+use std::path::Path;
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+/// First line that marks a boothook
+pub const BOOTHOOK_HEADER: &str = "#cloud-boothook";
+
+/// Interpreter for boothooks without a `#!` line
+const DEFAULT_SHEBANG: &str = "#!/bin/sh\n";
+
+/// Boothooks written with this mode
+const BOOTHOOK_MODE: u32 = 0o700;
+
+/// The boothooks in `userdata`, in order
+pub fn boothooks(userdata: &UserData) -> Vec<ScriptPart> {
+    match userdata {
+        UserData::Script(script) if is_boothook(script) => vec![ScriptPart {
+            content: script.clone(),
+            filename: None,
+        }],
+        UserData::MultiPart(parts) => parts
+            .iter()
+            .filter(|part| {
+                ContentType::from_mime(&part.content_type) == ContentType::CloudBoothook
+                    || is_boothook(&part.content)
+            })
+            .map(|part| ScriptPart {
+                content: part.content.clone(),
+                filename: part.filename.clone(),
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn is_boothook(content: &str) -> bool {
+    content.trim_start().starts_with(BOOTHOOK_HEADER)
+}
+
+/// The script to run for a boothook: the header dropped, and a shell
+/// interpreter line added if there is none
+fn script(content: &str) -> String {
+    let body = content.trim_start();
+    let body = match body.strip_prefix(BOOTHOOK_HEADER) {
+        Some(rest) => rest.split_once('\n').map_or("", |(_, rest)| rest),
+        None => body,
+    };
+    if body.starts_with("#!") {
+        body.to_string()
+    } else {
+        format!("{}{}", DEFAULT_SHEBANG, body)
+    }
+}
+
+/// Replace the boothooks of `instance_id` with `hooks`
+pub async fn save(
+    root: &RootContext,
+    paths: &CloudPaths,
+    instance_id: &str,
+    hooks: &[ScriptPart],
+) -> Result<(), CloudInitError> {
+    let dir = paths.boothooks_dir(instance_id);
+    if dir.exists() {
+        root.remove_dir_all(&dir).await?;
+    }
+    if hooks.is_empty() {
+        return Ok(());
+    }
+    root.create_dir_all(&dir).await?;
+    for (i, hook) in hooks.iter().enumerate() {
+        let path = dir.join(format!("part-{:03}", i + 1));
+        root.write_file_mode(&path, script(&hook.content), BOOTHOOK_MODE)
+            .await?;
+        debug!("Saved boothook {}", path.display());
+    }
+    info!("Saved {} boothook(s) for {}", hooks.len(), instance_id);
+    Ok(())
+}
+
+/// Run the saved boothooks of `instance_id` in order
+///
+/// A failing boothook is logged and the rest still run.
+pub async fn run_all(
+    runner: &dyn SystemRunner,
+    root: &RootContext,
+    instance_id: &str,
+) -> Result<(), CloudInitError> {
+    let dir = root.cloud_paths().boothooks_dir(instance_id);
+    let mut entries = match fs::read_dir(&dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_path(&dir),
+    };
+    let mut names = Vec::new();
+    while let Some(entry) = entries.next_entry().await.with_path(&dir)? {
+        names.push(entry.file_name());
+    }
+    names.sort();
+
+    // Commands run inside the root, so name the scripts as seen from there
+    let inside = RootContext::host().cloud_paths().boothooks_dir(instance_id);
+    for name in names {
+        let path = inside.join(&name);
+        info!("Running boothook {}", path.display());
+        let command = SystemCommand::new(path_str(&path)).env("INSTANCE_ID", instance_id);
+        match runner.run(&command).await {
+            Ok(output) if output.is_success() => debug!("Boothook {} finished", path.display()),
+            Ok(output) => warn!(
+                "Boothook {} failed with {:?}: {}",
+                path.display(),
+                output.code,
+                output.stderr.trim()
+            ),
+            Err(e) => warn!("Could not run boothook {}: {}", path.display(), e),
+        }
+    }
+    Ok(())
+}
+
+fn path_str(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UserDataPart;
+    use crate::runner::{CommandOutput, RecordingRunner};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_boothooks_from_userdata() {
+        let single = UserData::Script("#cloud-boothook\n#!/bin/bash\necho hi\n".to_string());
+        assert_eq!(boothooks(&single).len(), 1);
+        assert!(boothooks(&UserData::Script("#!/bin/sh\necho hi\n".to_string())).is_empty());
+
+        let multipart = UserData::MultiPart(vec![
+            UserDataPart {
+                content_type: "text/cloud-boothook".to_string(),
+                content: "echo early\n".to_string(),
+                filename: Some("early.sh".to_string()),
+            },
+            UserDataPart {
+                content_type: "text/x-shellscript".to_string(),
+                content: "#!/bin/sh\necho late\n".to_string(),
+                filename: None,
+            },
+        ]);
+        let hooks = boothooks(&multipart);
+        assert_eq!(hooks.len(), 1);
+        assert_eq!(hooks[0].filename.as_deref(), Some("early.sh"));
+    }
+
+    #[test]
+    fn test_script() {
+        assert_eq!(
+            script("#cloud-boothook\n#!/bin/bash\necho hi\n"),
+            "#!/bin/bash\necho hi\n"
+        );
+        assert_eq!(script("#cloud-boothook\necho hi\n"), "#!/bin/sh\necho hi\n");
+        assert_eq!(script("echo hi\n"), "#!/bin/sh\necho hi\n");
+    }
+
+    #[tokio::test]
+    async fn test_save_and_run() {
+        let temp = TempDir::new().unwrap();
+        let root = RootContext::new(temp.path());
+        let paths = root.cloud_paths();
+        let hook = |content: &str| ScriptPart {
+            content: content.to_string(),
+            filename: None,
+        };
+        save(
+            &root,
+            &paths,
+            "i-1",
+            &[hook("#cloud-boothook\necho one\n"), hook("echo two\n")],
+        )
+        .await
+        .unwrap();
+        let first = paths.boothooks_dir("i-1").join("part-001");
+        assert_eq!(
+            std::fs::read_to_string(&first).unwrap(),
+            "#!/bin/sh\necho one\n"
+        );
+
+        // A failure does not stop the next boothook
+        let runner = RecordingRunner::new().with_response(
+            "/var/lib/cloud/instances/i-1/boothooks/part-001",
+            CommandOutput::failure(1, "boom"),
+        );
+        run_all(&runner, &root, "i-1").await.unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "/var/lib/cloud/instances/i-1/boothooks/part-001",
+                "/var/lib/cloud/instances/i-1/boothooks/part-002",
+            ]
+        );
+
+        // New user-data without boothooks removes the old ones
+        save(&root, &paths, "i-1", &[]).await.unwrap();
+        assert!(!paths.boothooks_dir("i-1").exists());
+    }
+}
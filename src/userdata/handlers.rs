@@ -0,0 +1,220 @@
+//! User-data part handlers
+//!
+//! Python cloud-init runs `text/part-handler` parts as Python code to
+//! process other parts. cloud-init-rs cannot run them; such parts, and any
+//! other part it has no use for, are listed as warnings in `status.json`
+//! and by `cloud-init-rs status` instead of being dropped silently.
+//!
+//! The native alternative is an executable on disk, mapped to the content
+//! types it handles in cloud.cfg:
+//!
+//! ```yaml
+//! handlers:
+//!   text/x-my-format: /usr/local/lib/cloud-handlers/my-format
+//! ```
+//!
+//! Each matching part is written to the handler's stdin when user-data is
+//! processed, with `CONTENT_TYPE`, `PART_FILENAME` (if the part has one)
+//! and `INSTANCE_ID` set. A mapped handler takes precedence over the
+//! built-in handling of a content type.
+
+use super::ContentType;
+use crate::runner::{SystemCommand, SystemRunner};
+use crate::state::report::record_warning;
+use crate::{UserData, UserDataPart};
+use std::collections::BTreeMap;
+use tracing::{debug, info, warn};
+
+/// Content types cloud-init-rs handles itself
+fn is_supported(content_type: ContentType) -> bool {
+    matches!(
+        content_type,
+        ContentType::CloudConfig
+            | ContentType::JinjaTemplate
+            | ContentType::Script
+            | ContentType::CloudBoothook
+            | ContentType::IncludeUrl
+    )
+}
+
+/// MIME type without parameters, lowercased
+fn normalize(mime: &str) -> String {
+    mime.split(';').next().unwrap_or(mime).trim().to_lowercase()
+}
+
+/// The parts of `userdata` with their MIME types
+///
+/// Single-part user-data has no MIME type of its own; it is the one its
+/// content is detected as.
+fn parts(userdata: &UserData) -> Vec<UserDataPart> {
+    match userdata {
+        UserData::MultiPart(parts) => parts.clone(),
+        UserData::Script(script) => vec![UserDataPart {
+            content_type: ContentType::detect_from_text(script)
+                .mime_type()
+                .to_string(),
+            content: script.clone(),
+            filename: None,
+        }],
+        UserData::CloudConfig(_) | UserData::None => Vec::new(),
+    }
+}
+
+fn describe(index: usize, part: &UserDataPart) -> String {
+    match &part.filename {
+        Some(filename) => format!("part {} ({})", index + 1, filename),
+        None => format!("part {}", index + 1),
+    }
+}
+
+/// Hand the parts of `userdata` to their `handlers`, and warn about the
+/// parts nothing handles
+///
+/// Returns the warnings, which are also recorded for `status.json`. A
+/// failing handler is one of them; the other parts are still handled.
+pub async fn dispatch(
+    runner: &dyn SystemRunner,
+    handlers: &BTreeMap<String, String>,
+    instance_id: &str,
+    userdata: &UserData,
+) -> Vec<String> {
+    let handlers: BTreeMap<String, &str> = handlers
+        .iter()
+        .map(|(mime, path)| (normalize(mime), path.as_str()))
+        .collect();
+    let mut warnings = Vec::new();
+
+    for (index, part) in parts(userdata).iter().enumerate() {
+        let mime = normalize(&part.content_type);
+        let name = describe(index, part);
+
+        if let Some(handler) = handlers.get(&mime) {
+            info!("Handing user-data {} ({}) to {}", name, mime, handler);
+            let mut command = SystemCommand::new(*handler)
+                .env("CONTENT_TYPE", &mime)
+                .env("INSTANCE_ID", instance_id)
+                .stdin(part.content.as_str());
+            if let Some(filename) = &part.filename {
+                command = command.env("PART_FILENAME", filename);
+            }
+            match runner.run(&command).await {
+                Ok(output) if output.is_success() => debug!("Handler {} finished", handler),
+                Ok(output) => warnings.push(format!(
+                    "handler {} failed on user-data {} ({}) with {:?}: {}",
+                    handler,
+                    name,
+                    mime,
+                    output.code,
+                    output.stderr.trim()
+                )),
+                Err(e) => warnings.push(format!(
+                    "could not run handler {} on user-data {} ({}): {}",
+                    handler, name, mime, e
+                )),
+            }
+            continue;
+        }
+
+        let content_type = ContentType::from_mime(&mime);
+        if is_supported(content_type) {
+            continue;
+        }
+        let reason = match content_type {
+            ContentType::PartHandler => "Python part-handlers are not supported",
+            ContentType::UpstartJob => "upstart jobs are not supported",
+            _ => "no handler for this content type",
+        };
+        warnings.push(format!(
+            "ignored user-data {} ({}): {}; map its content type to an executable under `handlers:` in cloud.cfg to process it",
+            name, mime, reason
+        ));
+    }
+
+    for warning in &warnings {
+        warn!("{}", warning);
+        record_warning(warning.clone());
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, RecordingRunner};
+
+    fn part(content_type: &str, content: &str, filename: Option<&str>) -> UserDataPart {
+        UserDataPart {
+            content_type: content_type.to_string(),
+            content: content.to_string(),
+            filename: filename.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_parts_warn() {
+        let runner = RecordingRunner::new();
+        let userdata = UserData::MultiPart(vec![
+            part("text/cloud-config", "#cloud-config\n", None),
+            part(
+                "text/part-handler",
+                "#part-handler\ndef list_types(): ...\n",
+                Some("handler.py"),
+            ),
+            part("text/upstart-job", "start on runlevel\n", None),
+        ]);
+
+        let warnings = dispatch(&runner, &BTreeMap::new(), "i-1", &userdata).await;
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("part 2 (handler.py) (text/part-handler)"));
+        assert!(warnings[0].contains("Python part-handlers are not supported"));
+        assert!(warnings[1].contains("upstart jobs"));
+        assert!(runner.commands().is_empty());
+
+        let single = UserData::Script("#part-handler\ndef handle_part(): ...\n".to_string());
+        let warnings = dispatch(&runner, &BTreeMap::new(), "i-1", &single).await;
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_handler_receives_part() {
+        let runner = RecordingRunner::new().with_response(
+            "/usr/local/bin/broken",
+            CommandOutput::failure(2, "bad input"),
+        );
+        let handlers = BTreeMap::from([
+            (
+                "text/X-My-Format".to_string(),
+                "/usr/local/bin/my-format".to_string(),
+            ),
+            (
+                "text/x-other".to_string(),
+                "/usr/local/bin/broken".to_string(),
+            ),
+        ]);
+        let userdata = UserData::MultiPart(vec![
+            part("text/x-my-format; charset=utf-8", "payload", Some("a.fmt")),
+            part("text/x-other", "other", None),
+        ]);
+
+        let warnings = dispatch(&runner, &handlers, "i-1", &userdata).await;
+        assert_eq!(
+            runner.commands(),
+            vec!["/usr/local/bin/my-format", "/usr/local/bin/broken"]
+        );
+        let first = &runner.calls()[0];
+        assert_eq!(first.stdin.as_deref(), Some("payload"));
+        assert!(
+            first
+                .env
+                .contains(&("CONTENT_TYPE".to_string(), "text/x-my-format".to_string()))
+        );
+        assert!(
+            first
+                .env
+                .contains(&("PART_FILENAME".to_string(), "a.fmt".to_string()))
+        );
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("/usr/local/bin/broken failed"));
+        assert!(warnings[0].contains("bad input"));
+    }
+}
@@ -0,0 +1,184 @@
+//! `#include` URL fetching with an on-disk cache
+//!
+//! Included parts are fetched again on every boot that processes them.
+//! Responses with an `ETag` or `Last-Modified` header are kept under
+//! `/var/lib/cloud/data/include-cache`, and the next fetch of the URL is
+//! a conditional request: when the server answers `304 Not Modified` the
+//! cached body is used instead of downloading it again.
+//!
+//! Responses larger than `user_data_limits.max_include_size` are rejected
+//! (see [`super::limits`]).
+
+use crate::root::RootContext;
+use crate::{CloudInitError, IoContext};
+use bytes::Bytes;
+use reqwest::StatusCode;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs;
+use tracing::{debug, warn};
+
+/// Limit for fetching one `#include` URL
+const INCLUDE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Validators of a cached response
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Cache files of one URL: validators and body
+fn cache_files(cache_dir: &Path, url: &str) -> (PathBuf, PathBuf) {
+    let key: String = Sha256::digest(url.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    (
+        cache_dir.join(format!("{}.json", key)),
+        cache_dir.join(format!("{}.body", key)),
+    )
+}
+
+/// The cached validators of `url`, if its body is cached too
+async fn cached_entry(entry_path: &Path, body_path: &Path, url: &str) -> Option<CacheEntry> {
+    let entry: CacheEntry = serde_json::from_slice(&fs::read(entry_path).await.ok()?).ok()?;
+    (entry.url == url && fs::metadata(body_path).await.is_ok()).then_some(entry)
+}
+
+/// Fetch `url`, revalidating the copy cached under `cache_dir`
+///
+/// The body is handed over as received, without copying it.
+pub async fn fetch(url: &str, cache_dir: &Path) -> Result<Bytes, CloudInitError> {
+    debug!("Fetching include {}", url);
+    let (entry_path, body_path) = cache_files(cache_dir, url);
+    let cached = cached_entry(&entry_path, &body_path, url).await;
+
+    let client = crate::http::client_builder()?
+        .timeout(INCLUDE_TIMEOUT)
+        .build()?;
+    let mut request = client.get(url);
+    if let Some(entry) = &cached {
+        if let Some(etag) = &entry.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &entry.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let response = request.send().await?;
+
+    if response.status() == StatusCode::NOT_MODIFIED && cached.is_some() {
+        debug!("Include {} not modified, using cached copy", url);
+        return fs::read(&body_path)
+            .await
+            .map(Bytes::from)
+            .with_path(&body_path);
+    }
+    if !response.status().is_success() {
+        return Err(CloudInitError::Network(format!(
+            "Failed to fetch include {}: {}",
+            url,
+            response.status()
+        )));
+    }
+
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let entry = CacheEntry {
+        url: url.to_string(),
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
+    let body = super::limits::read_body(
+        response,
+        &format!("Include {}", url),
+        super::limits::current().max_include_size(),
+        "max_include_size",
+    )
+    .await?;
+    if (entry.etag.is_some() || entry.last_modified.is_some())
+        && let Err(e) = store(cache_dir, &entry_path, &body_path, &entry, &body).await
+    {
+        warn!("Could not cache include {}: {}", url, e);
+    }
+    Ok(body)
+}
+
+async fn store(
+    cache_dir: &Path,
+    entry_path: &Path,
+    body_path: &Path,
+    entry: &CacheEntry,
+    body: &[u8],
+) -> Result<(), CloudInitError> {
+    let root = &RootContext::current();
+    root.create_dir_all(cache_dir).await?;
+    // Body first: an entry is only used when its body exists
+    root.write_file(body_path, body).await?;
+    root.write_file(entry_path, serde_json::to_vec(entry)?)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use wiremock::matchers::{header, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_conditional_refetch() {
+        let server = MockServer::start().await;
+        Mock::given(path("/base.yaml"))
+            .and(header("If-None-Match", "\"v1\""))
+            .respond_with(ResponseTemplate::new(304))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/base.yaml"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("ETag", "\"v1\"")
+                    .set_body_string("#cloud-config\n"),
+            )
+            .mount(&server)
+            .await;
+
+        let temp = TempDir::new().unwrap();
+        let url = format!("{}/base.yaml", server.uri());
+        for _ in 0..2 {
+            assert_eq!(
+                fetch(&url, temp.path()).await.unwrap(),
+                b"#cloud-config\n".as_slice()
+            );
+        }
+        let requests = server.received_requests().await.unwrap();
+        assert!(!requests[0].headers.contains_key("If-None-Match"));
+        assert_eq!(requests[1].headers["If-None-Match"], "\"v1\"");
+    }
+
+    #[tokio::test]
+    async fn test_uncacheable_response_not_stored() {
+        let server = MockServer::start().await;
+        Mock::given(path("/plain"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("#!/bin/sh\n"))
+            .mount(&server)
+            .await;
+
+        let temp = TempDir::new().unwrap();
+        fetch(&format!("{}/plain", server.uri()), temp.path())
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 0);
+    }
+}
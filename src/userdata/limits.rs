@@ -0,0 +1,154 @@
+//! Size limits for user-data from untrusted sources
+//!
+//! User-data, `#include` responses and gzip payloads come from whoever
+//! controls the instance's metadata, so they are read against the limits
+//! from the `user_data_limits` key of cloud.cfg (see [`UserDataLimits`])
+//! instead of into memory unbounded. Exceeding a limit is an error that
+//! names the limit; nothing is silently truncated.
+
+use crate::config::{CloudConfig, UserDataLimits};
+use crate::{CloudInitError, IoContext};
+use bytes::{Bytes, BytesMut};
+use flate2::read::GzDecoder;
+use std::io::Read;
+use std::path::Path;
+use std::sync::OnceLock;
+
+static LIMITS: OnceLock<UserDataLimits> = OnceLock::new();
+
+/// Use the limits of `config` for the rest of the process
+///
+/// Only the first call has an effect.
+pub fn configure(config: &CloudConfig) {
+    if let Some(limits) = &config.user_data_limits {
+        let _ = LIMITS.set(limits.clone());
+    }
+}
+
+/// The configured limits, or the defaults
+pub fn current() -> &'static UserDataLimits {
+    static DEFAULT: UserDataLimits = UserDataLimits {
+        max_size: None,
+        max_include_size: None,
+        max_decompressed_size: None,
+    };
+    LIMITS.get().unwrap_or(&DEFAULT)
+}
+
+/// Fail if `what`, `size` bytes long, is over `limit`
+pub fn check(what: &str, size: usize, limit: usize, key: &str) -> Result<(), CloudInitError> {
+    if size > limit {
+        return Err(CloudInitError::InvalidData(format!(
+            "{} is larger than the {}-byte limit (user_data_limits.{})",
+            what, limit, key
+        )));
+    }
+    Ok(())
+}
+
+/// Read the body of `response`, failing as soon as it is over `limit`
+///
+/// A body whose `Content-Length` fits is taken as received; one without
+/// is read chunk by chunk so an endless stream stops at the limit.
+pub async fn read_body(
+    mut response: reqwest::Response,
+    what: &str,
+    limit: usize,
+    key: &str,
+) -> Result<Bytes, CloudInitError> {
+    if let Some(length) = response.content_length() {
+        check(
+            what,
+            usize::try_from(length).unwrap_or(usize::MAX),
+            limit,
+            key,
+        )?;
+        return Ok(response.bytes().await?);
+    }
+    let mut body = BytesMut::new();
+    while let Some(chunk) = response.chunk().await? {
+        check(what, body.len() + chunk.len(), limit, key)?;
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+/// Read the user-data file at `path`, checking its size before reading
+pub async fn read_file(path: &Path) -> Result<Vec<u8>, CloudInitError> {
+    let size = tokio::fs::metadata(path).await.with_path(path)?.len();
+    check(
+        &format!("User-data {}", path.display()),
+        usize::try_from(size).unwrap_or(usize::MAX),
+        current().max_size(),
+        "max_size",
+    )?;
+    tokio::fs::read(path).await.with_path(path)
+}
+
+/// Decompress gzip `data` into `out`, stopping at `limit` bytes of output
+///
+/// Guards against decompression bombs: the output is never allowed to
+/// grow past the limit, however much the input claims to expand to.
+pub fn gunzip(data: &[u8], out: &mut Vec<u8>, limit: usize) -> Result<(), CloudInitError> {
+    let limit_plus_one = u64::try_from(limit).unwrap_or(u64::MAX).saturating_add(1);
+    GzDecoder::new(data)
+        .take(limit_plus_one)
+        .read_to_end(out)
+        .map_err(|e| CloudInitError::InvalidData(format!("Gzip decompression failed: {}", e)))?;
+    check(
+        "Decompressed gzip data",
+        out.len(),
+        limit,
+        "max_decompressed_size",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    use wiremock::matchers::path;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_gunzip_stops_at_limit() {
+        // 1 MiB of zeros compresses to about a kilobyte
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&vec![0u8; 1024 * 1024]).unwrap();
+        let bomb = encoder.finish().unwrap();
+
+        let mut out = Vec::new();
+        let err = gunzip(&bomb, &mut out, 4096).unwrap_err();
+        assert!(err.to_string().contains("max_decompressed_size"));
+        assert!(out.len() <= 4097);
+
+        let mut out = Vec::new();
+        gunzip(&bomb, &mut out, 1024 * 1024).unwrap();
+        assert_eq!(out.len(), 1024 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_read_body_limit() {
+        let server = MockServer::start().await;
+        Mock::given(path("/big"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![b'x'; 2048]))
+            .mount(&server)
+            .await;
+
+        let fetch = |limit| {
+            let url = format!("{}/big", server.uri());
+            async move {
+                let response = reqwest::get(url).await.unwrap();
+                read_body(response, "User-data", limit, "max_size").await
+            }
+        };
+        assert_eq!(fetch(2048).await.unwrap().len(), 2048);
+        let err = fetch(1024).await.unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("larger than the 1024-byte limit (user_data_limits.max_size)")
+        );
+    }
+}
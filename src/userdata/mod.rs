@@ -6,7 +6,16 @@
 //! - MIME multipart messages
 //! - Gzip compressed data
 //! - Include directives
-
+//!
+//! User-data can be as large as 16MB (the EC2 limit) and is parsed during
+//! early boot, so the pipeline avoids copies: plain payloads are borrowed
+//! from the caller's buffer, gzip is decoded straight into one buffer that
+//! then becomes the text, and part bodies are moved rather than cloned.
+
+pub mod boothook;
+pub mod handlers;
+pub mod include;
+pub mod limits;
 pub mod mime;
 pub mod types;
 
@@ -15,8 +24,7 @@ pub use types::ContentType;
 
 use crate::{CloudInitError, UserData, UserDataPart, config::CloudConfig};
 use base64::Engine;
-use flate2::read::GzDecoder;
-use std::io::Read;
+use std::borrow::Cow;
 use tracing::{debug, warn};
 
 /// Parse raw user-data bytes into structured UserData
@@ -33,7 +41,7 @@ pub fn parse_userdata(data: &[u8]) -> Result<UserData, CloudInitError> {
     debug!("Detected user-data content type: {}", content_type);
 
     // Convert to string for text processing
-    let text = String::from_utf8_lossy(&data);
+    let text = into_text(data);
 
     match content_type {
         ContentType::CloudConfig | ContentType::JinjaTemplate => {
@@ -80,19 +88,38 @@ pub fn parse_userdata(data: &[u8]) -> Result<UserData, CloudInitError> {
     }
 }
 
-/// Decompress gzip data if needed
-fn decompress_if_needed(data: &[u8]) -> Result<Vec<u8>, CloudInitError> {
+/// Most memory reserved up front from a gzip size trailer
+///
+/// The trailer is untrusted, so a larger claim only grows the buffer as
+/// data actually arrives.
+const GZIP_RESERVE_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Decompress gzip data if needed, borrowing anything else
+fn decompress_if_needed(data: &[u8]) -> Result<Cow<'_, [u8]>, CloudInitError> {
     // Check for gzip magic bytes
     if data.len() >= 2 && data[0] == 0x1f && data[1] == 0x8b {
         debug!("Decompressing gzip user-data");
-        let mut decoder = GzDecoder::new(data);
-        let mut decompressed = Vec::new();
-        decoder.read_to_end(&mut decompressed).map_err(|e| {
-            CloudInitError::InvalidData(format!("Gzip decompression failed: {}", e))
-        })?;
-        Ok(decompressed)
+        // ISIZE, the last four bytes, is the decompressed size modulo 2^32
+        let size_hint = data
+            .last_chunk::<4>()
+            .map_or(0, |isize| u32::from_le_bytes(*isize) as usize);
+        let limit = limits::current().max_decompressed_size();
+        let mut decompressed = Vec::with_capacity(size_hint.min(GZIP_RESERVE_LIMIT).min(limit));
+        limits::gunzip(data, &mut decompressed, limit)?;
+        Ok(Cow::Owned(decompressed))
     } else {
-        Ok(data.to_vec())
+        Ok(Cow::Borrowed(data))
+    }
+}
+
+/// User-data as text, reusing an owned buffer when it is valid UTF-8
+fn into_text(data: Cow<'_, [u8]>) -> Cow<'_, str> {
+    match data {
+        Cow::Borrowed(bytes) => String::from_utf8_lossy(bytes),
+        Cow::Owned(bytes) => match String::from_utf8(bytes) {
+            Ok(text) => Cow::Owned(text),
+            Err(e) => Cow::Owned(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+        },
     }
 }
 
@@ -102,10 +129,9 @@ fn decode_base64(data: &str) -> Result<Vec<u8>, CloudInitError> {
     let cleaned: String = data
         .lines()
         .filter(|l| !l.starts_with("-----"))
-        .collect::<Vec<_>>()
-        .join("");
-
-    let cleaned: String = cleaned.chars().filter(|c| !c.is_whitespace()).collect();
+        .flat_map(str::chars)
+        .filter(|c| !c.is_whitespace())
+        .collect();
 
     base64::engine::general_purpose::STANDARD
         .decode(&cleaned)
@@ -139,8 +165,20 @@ fn parse_include_urls(data: &str) -> Result<Vec<UserDataPart>, CloudInitError> {
     Ok(parts)
 }
 
+/// Fetch an `#include` URL and parse what it returns
+///
+/// Uses the outbound HTTP client, so the proxy and `http_tls` settings
+/// from cloud.cfg apply, and revalidates the copy in the include cache
+/// (see [`include`]) instead of downloading it again.
+pub async fn fetch_include(url: &str) -> Result<UserData, CloudInitError> {
+    let cache_dir = crate::state::CloudPaths::new().include_cache_dir();
+    parse_userdata(&include::fetch(url, &cache_dir).await?)
+}
+
 /// Process multipart user-data and merge cloud-configs
-pub fn process_multipart(parts: &[UserDataPart]) -> ProcessedUserData {
+///
+/// Takes the parts by value so their bodies move into the result.
+pub fn process_multipart(parts: Vec<UserDataPart>) -> ProcessedUserData {
     let mut cloud_configs = Vec::new();
     let mut scripts = Vec::new();
     let mut boothooks = Vec::new();
@@ -151,22 +189,22 @@ pub fn process_multipart(parts: &[UserDataPart]) -> ProcessedUserData {
 
         match content_type {
             ContentType::CloudConfig | ContentType::JinjaTemplate => {
-                cloud_configs.push(part.content.clone());
+                cloud_configs.push(part.content);
             }
             ContentType::Script => {
                 scripts.push(ScriptPart {
-                    content: part.content.clone(),
-                    filename: part.filename.clone(),
+                    content: part.content,
+                    filename: part.filename,
                 });
             }
             ContentType::CloudBoothook => {
                 boothooks.push(ScriptPart {
-                    content: part.content.clone(),
-                    filename: part.filename.clone(),
+                    content: part.content,
+                    filename: part.filename,
                 });
             }
             ContentType::IncludeUrl => {
-                includes.push(part.content.clone());
+                includes.push(part.content);
             }
             _ => {
                 debug!("Ignoring part with content type: {}", part.content_type);
@@ -205,6 +243,31 @@ pub struct ScriptPart {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use wiremock::matchers::path;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_fetch_include() {
+        let server = MockServer::start().await;
+        Mock::given(path("/base.yaml"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string("#cloud-config\nhostname: inc\n"),
+            )
+            .mount(&server)
+            .await;
+
+        match fetch_include(&format!("{}/base.yaml", server.uri()))
+            .await
+            .unwrap()
+        {
+            UserData::CloudConfig(config) => assert_eq!(config.hostname.as_deref(), Some("inc")),
+            other => panic!("Expected cloud-config, got {:?}", other),
+        }
+        let err = fetch_include(&format!("{}/missing.yaml", server.uri()))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("404"));
+    }
 
     #[test]
     fn test_parse_cloud_config() {
@@ -260,6 +323,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_plain_data_is_borrowed() {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let plain = b"#!/bin/sh\necho hi\n".as_slice();
+        assert!(matches!(
+            decompress_if_needed(plain).unwrap(),
+            Cow::Borrowed(_)
+        ));
+        assert!(matches!(into_text(Cow::Borrowed(plain)), Cow::Borrowed(_)));
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(plain).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let decompressed = decompress_if_needed(&compressed).unwrap();
+        assert_eq!(decompressed.as_ref(), plain);
+        assert_eq!(decompressed.into_owned().capacity(), plain.len());
+    }
+
     #[test]
     fn test_parse_multipart() {
         let data = br#"MIME-Version: 1.0
@@ -312,7 +396,7 @@ echo hello
             },
         ];
 
-        let processed = process_multipart(&parts);
+        let processed = process_multipart(parts);
 
         assert_eq!(processed.cloud_configs.len(), 1);
         assert_eq!(processed.scripts.len(), 1);
@@ -1,4 +1,10 @@
 //! Integration tests for cloud datasources using wiremock
+#![cfg(all(
+    feature = "ds-ec2",
+    feature = "ds-gce",
+    feature = "ds-azure",
+    feature = "ds-openstack"
+))]
 
 use cloud_init_rs::datasources::{
     Datasource, azure::Azure, ec2::Ec2, gce::Gce, openstack::OpenStack,
@@ -339,12 +345,232 @@ async fn test_ec2_metadata_partial_failure() {
     assert!(metadata.availability_zone.is_none());
 }
 
+#[tokio::test]
+async fn test_ec2_get_network_config() {
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/latest/meta-data/network/interfaces/macs/"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_string("0a:00:00:00:00:01/\n0A:00:00:00:00:02/"),
+        )
+        .mount(&mock_server)
+        .await;
+    for (mac, device) in [("0a:00:00:00:00:01", "0"), ("0A:00:00:00:00:02", "1")] {
+        Mock::given(method("GET"))
+            .and(path(format!(
+                "/latest/meta-data/network/interfaces/macs/{}/device-number",
+                mac
+            )))
+            .respond_with(ResponseTemplate::new(200).set_body_string(device))
+            .mount(&mock_server)
+            .await;
+    }
+    Mock::given(method("GET"))
+        .and(path(
+            "/latest/meta-data/network/interfaces/macs/0a:00:00:00:00:01/ipv6s",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_string("2600:1f18::10"))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(
+            "/latest/meta-data/network/interfaces/macs/0a:00:00:00:00:01/local-ipv4s",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_string("172.31.0.10"))
+        .mount(&mock_server)
+        .await;
+
+    let ec2 = Ec2::with_base_url(&mock_server.uri());
+    let config = ec2.get_network_config().await.unwrap().unwrap();
+
+    assert_eq!(config.ethernets.len(), 2);
+    let eth0 = &config.ethernets["eth0"];
+    assert_eq!(eth0.common.dhcp4, Some(true));
+    assert_eq!(eth0.common.dhcp6, Some(true));
+    let eth1 = &config.ethernets["eth1"];
+    assert_eq!(
+        eth1.match_config.as_ref().unwrap().macaddress.as_deref(),
+        Some("0a:00:00:00:00:02")
+    );
+    assert_eq!(eth1.common.dhcp6, None);
+}
+
+#[tokio::test]
+async fn test_ec2_get_network_config_ipv6_only() {
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/latest/meta-data/network/interfaces/macs/"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("0a:00:00:00:00:01/"))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(
+            "/latest/meta-data/network/interfaces/macs/0a:00:00:00:00:01/ipv6s",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_string("2600:1f18::10"))
+        .mount(&mock_server)
+        .await;
+
+    let ec2 = Ec2::with_base_url(&mock_server.uri());
+    let config = ec2.get_network_config().await.unwrap().unwrap();
+
+    let eth0 = &config.ethernets["eth0"].common;
+    assert_eq!(eth0.dhcp4, None);
+    assert_eq!(eth0.dhcp6, Some(true));
+    assert!(eth0.is_ipv6_only());
+}
+
+#[tokio::test]
+async fn test_ec2_block_device_mapping() {
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/latest/meta-data/instance-id"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("i-abc123"))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/latest/meta-data/block-device-mapping/"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("ami\nephemeral0\nswap"))
+        .mount(&mock_server)
+        .await;
+    for (name, device) in [("ami", "/dev/xvda"), ("ephemeral0", "sdb"), ("swap", "sdc")] {
+        Mock::given(method("GET"))
+            .and(path(format!(
+                "/latest/meta-data/block-device-mapping/{}",
+                name
+            )))
+            .respond_with(ResponseTemplate::new(200).set_body_string(device))
+            .mount(&mock_server)
+            .await;
+    }
+
+    let ec2 = Ec2::with_base_url(&mock_server.uri());
+    let mapping = ec2.get_metadata().await.unwrap().block_device_mapping();
+    assert_eq!(mapping.len(), 3);
+    assert_eq!(mapping["ephemeral0"], "sdb");
+    assert_eq!(mapping["swap"], "sdc");
+}
+
+#[tokio::test]
+async fn test_ec2_instance_tags() {
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/latest/meta-data/instance-id"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("i-abc123"))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/latest/meta-data/tags/instance"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("Name\nrole"))
+        .mount(&mock_server)
+        .await;
+    for (key, value) in [("Name", "web-1"), ("role", "web")] {
+        Mock::given(method("GET"))
+            .and(path(format!("/latest/meta-data/tags/instance/{}", key)))
+            .respond_with(ResponseTemplate::new(200).set_body_string(value))
+            .mount(&mock_server)
+            .await;
+    }
+
+    let ec2 = Ec2::with_base_url(&mock_server.uri());
+    let tags = ec2.get_metadata().await.unwrap().tags();
+    assert_eq!(tags.len(), 2);
+    assert_eq!(tags["role"], "web");
+    assert_eq!(tags["Name"], "web-1");
+}
+
+#[tokio::test]
+async fn test_ec2_instance_tags_not_allowed() {
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/latest/meta-data/instance-id"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("i-abc123"))
+        .mount(&mock_server)
+        .await;
+
+    // IMDS answers 404 unless tags in instance metadata are allowed
+    let ec2 = Ec2::with_base_url(&mock_server.uri());
+    let metadata = ec2.get_metadata().await.unwrap();
+    assert!(metadata.tags().is_empty());
+    assert!(metadata.raw_get("tags").is_none());
+}
+
+#[tokio::test]
+async fn test_ec2_get_network_config_unavailable() {
+    let mock_server = MockServer::start().await;
+    let ec2 = Ec2::with_base_url(&mock_server.uri());
+    assert!(ec2.get_network_config().await.unwrap().is_none());
+}
+
 #[test]
 fn test_ec2_default() {
     let ec2 = Ec2::default();
     assert_eq!(ec2.name(), "EC2");
 }
 
+/// Serve the signed identity document fixture, with `document` in place
+/// of the signed one
+async fn mock_identity(mock_server: &MockServer, document: &str) {
+    let signature = include_str!("fixtures/ec2-identity/signature");
+    Mock::given(method("GET"))
+        .and(path("/latest/dynamic/instance-identity/document"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(document))
+        .mount(mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/latest/dynamic/instance-identity/signature"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(signature))
+        .mount(mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/latest/meta-data/instance-id"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("i-1234567890abcdef0"))
+        .mount(mock_server)
+        .await;
+}
+
+#[tokio::test]
+async fn test_ec2_verified_identity() {
+    let mock_server = MockServer::start().await;
+    mock_identity(
+        &mock_server,
+        include_str!("fixtures/ec2-identity/document.json"),
+    )
+    .await;
+
+    let ec2 = Ec2::with_base_url(&mock_server.uri())
+        .with_identity_certificates("tests/fixtures/ec2-identity/certificate.pem");
+    let metadata = ec2.get_metadata().await.unwrap();
+    assert_eq!(
+        metadata.instance_id,
+        Some("i-1234567890abcdef0".to_string())
+    );
+    assert_eq!(metadata.region, Some("us-east-1".to_string()));
+    assert_eq!(metadata.instance_type, Some("t3.micro".to_string()));
+    assert_eq!(
+        metadata.raw_get("instance-identity/availabilityZone"),
+        Some(&serde_json::json!("us-east-1a"))
+    );
+}
+
+#[tokio::test]
+async fn test_ec2_tampered_identity_rejected() {
+    let mock_server = MockServer::start().await;
+    let document =
+        include_str!("fixtures/ec2-identity/document.json").replace("us-east-1", "eu-west-1");
+    mock_identity(&mock_server, &document).await;
+
+    let ec2 = Ec2::with_base_url(&mock_server.uri())
+        .with_identity_certificates("tests/fixtures/ec2-identity/certificate.pem");
+    let err = ec2.get_metadata().await.unwrap_err();
+    assert!(err.to_string().contains("signature does not verify"));
+}
+
 // ============================================================================
 // GCE Tests
 // ============================================================================
@@ -642,6 +868,54 @@ async fn test_azure_metadata() {
     assert_eq!(metadata.instance_type, Some("Standard_D2s_v3".to_string()));
 }
 
+#[tokio::test]
+async fn test_azure_vendordata_from_os_profile() {
+    let mock_server = MockServer::start().await;
+
+    let azure_response = serde_json::json!({
+        "compute": {
+            "vmId": "azure-vm-12345",
+            "osProfile": {
+                "adminUsername": "azureuser",
+                "computerName": "test-hostname",
+                "disablePasswordAuthentication": "true"
+            },
+            "publicKeys": [{
+                "keyData": "ssh-ed25519 AAAA azureuser@portal",
+                "path": "/home/azureuser/.ssh/authorized_keys"
+            }]
+        }
+    });
+
+    Mock::given(method("GET"))
+        .and(path("/instance"))
+        .and(header("Metadata", "true"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&azure_response))
+        .mount(&mock_server)
+        .await;
+
+    let azure = Azure::with_base_url(&mock_server.uri());
+    match azure.get_vendordata().await.unwrap() {
+        Some(cloud_init_rs::UserData::CloudConfig(config)) => {
+            match &config.users[0] {
+                cloud_init_rs::config::UserConfig::Full(user) => {
+                    assert_eq!(user.name, "azureuser");
+                    assert_eq!(
+                        user.ssh_authorized_keys,
+                        vec!["ssh-ed25519 AAAA azureuser@portal"]
+                    );
+                }
+                other => panic!("Expected full user config, got {:?}", other),
+            }
+            assert_eq!(
+                config.ssh_pwauth,
+                Some(cloud_init_rs::config::SshPwauth::Disabled)
+            );
+        }
+        other => panic!("Expected cloud-config vendor-data, got {:?}", other),
+    }
+}
+
 #[tokio::test]
 async fn test_azure_userdata_base64() {
     use base64::Engine;
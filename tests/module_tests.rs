@@ -1,6 +1,6 @@
 //! Tests for configuration modules
 
-use cloud_init_rs::config::{CloudConfig, RunCmd, WriteFileConfig};
+use cloud_init_rs::config::{CloudConfig, ManageEtcHosts, RunCmd, WriteFileConfig};
 use std::fs;
 use tempfile::TempDir;
 
@@ -50,6 +50,8 @@ fn test_write_file_base64_decode() {
         permissions: Some("0755".to_string()),
         append: None,
         defer: None,
+        source: None,
+        checksum: None,
     };
 
     assert_eq!(config.encoding, Some("base64".to_string()));
@@ -139,7 +141,7 @@ manage_etc_hosts: true
     let config = CloudConfig::from_yaml(yaml).unwrap();
     assert_eq!(config.hostname, Some("my-server".to_string()));
     assert_eq!(config.fqdn, Some("my-server.example.com".to_string()));
-    assert_eq!(config.manage_etc_hosts, Some(true));
+    assert_eq!(config.manage_etc_hosts, Some(ManageEtcHosts::Template));
 }
 
 /// Test hostname with special characters
@@ -582,6 +584,7 @@ fn test_yum_repos_absent() {
 }
 
 /// Test yum_add_repo::build_repo_content produces correct INI format
+#[cfg(feature = "mod-yum-add-repo")]
 #[test]
 fn test_build_repo_content_basic() {
     use cloud_init_rs::config::YumRepoConfig;
@@ -604,6 +607,7 @@ fn test_build_repo_content_basic() {
 }
 
 /// Test build_repo_content with all optional fields
+#[cfg(feature = "mod-yum-add-repo")]
 #[test]
 fn test_build_repo_content_full() {
     use cloud_init_rs::config::YumRepoConfig;
@@ -636,6 +640,7 @@ fn test_build_repo_content_full() {
 }
 
 /// Test build_repo_content falls back to id when name is absent
+#[cfg(feature = "mod-yum-add-repo")]
 #[test]
 fn test_build_repo_content_name_fallback() {
     use cloud_init_rs::config::YumRepoConfig;
@@ -652,6 +657,7 @@ fn test_build_repo_content_name_fallback() {
 }
 
 /// Test build_repo_content default enabled=true when not specified
+#[cfg(feature = "mod-yum-add-repo")]
 #[test]
 fn test_build_repo_content_default_enabled() {
     use cloud_init_rs::config::YumRepoConfig;
@@ -668,6 +674,7 @@ fn test_build_repo_content_default_enabled() {
 }
 
 /// Test write_repo_file to a temp directory
+#[cfg(feature = "mod-yum-add-repo")]
 #[tokio::test]
 async fn test_write_repo_file() {
     use cloud_init_rs::config::YumRepoConfig;
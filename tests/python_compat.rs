@@ -0,0 +1,244 @@
+//! Compatibility harness against Python cloud-init example configs
+//!
+//! Parses the examples in `tests/fixtures/python-compat`, which follow
+//! upstream's `doc/examples`, or every `cloud-config*.txt` in the directory
+//! named by `CLOUD_INIT_EXAMPLES` (e.g. an upstream checkout). Each key an
+//! example sets must either take effect or be reported as ignored by
+//! `CloudConfig::from_yaml_reporting`. The outcome is written
+//! as a Markdown matrix to `COMPAT_MATRIX`, by default `compat-matrix.md`
+//! in the test's scratch directory:
+//!
+//! ```text
+//! cargo test --features compat-tests --test python_compat -- --nocapture
+//! ```
+//!
+//! For the bundled examples the ignored keys must match `unsupported.txt`
+//! and the examples that fail to parse `unparseable.txt`, so support
+//! gained or lost shows up in review.
+#![cfg(feature = "compat-tests")]
+
+use cloud_init_rs::config::CloudConfig;
+use serde_yaml::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Old spellings that parse into a differently named field
+const ALIASES: &[(&str, &str)] = &[
+    ("ubuntu_advantage", "ubuntu_pro"),
+    ("grub-dpkg", "grub_dpkg"),
+];
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/python-compat")
+}
+
+/// Non-comment lines of an expectation file next to the examples
+fn expectations(file: &str) -> BTreeSet<String> {
+    fs::read_to_string(fixtures_dir().join(file))
+        .unwrap()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Complaints about `actual` differing from the expectations in `file`
+fn compare(file: &str, actual: &BTreeSet<String>, gained: &str, lost: &str) -> Vec<String> {
+    let expected = expectations(file);
+    let lost = actual
+        .difference(&expected)
+        .map(|entry| format!("`{}` {}, but is not in {}", entry, lost, file));
+    let gained = expected
+        .difference(actual)
+        .map(|entry| format!("`{}` {} now, remove it from {}", entry, gained, file));
+    lost.chain(gained).collect()
+}
+
+fn examples() -> (Vec<PathBuf>, bool) {
+    let (dir, bundled) = match std::env::var_os("CLOUD_INIT_EXAMPLES") {
+        Some(dir) => (PathBuf::from(dir), false),
+        None => (fixtures_dir(), true),
+    };
+    let mut examples: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("cannot read {}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("cloud-config") && name.ends_with(".txt"))
+        })
+        .collect();
+    examples.sort();
+    (examples, bundled)
+}
+
+/// `users.3.inactive` as `users.*.inactive`, so entries compare alike
+fn normalize(path: &str) -> String {
+    path.split('.')
+        .map(|segment| match segment.parse::<usize>() {
+            Ok(_) => "*",
+            Err(_) => segment,
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// How one top-level key fared across the examples
+#[derive(Default)]
+struct KeyReport {
+    unsupported: bool,
+    ignored: BTreeSet<String>,
+    examples: BTreeSet<String>,
+}
+
+impl KeyReport {
+    fn status(&self) -> &'static str {
+        match (self.unsupported, self.ignored.is_empty()) {
+            (true, _) => "unsupported",
+            (false, true) => "supported",
+            (false, false) => "partial",
+        }
+    }
+}
+
+fn render_matrix(
+    keys: &BTreeMap<String, KeyReport>,
+    examples: usize,
+    unparseable: &BTreeMap<String, String>,
+) -> String {
+    let count = |status| keys.values().filter(|r| r.status() == status).count();
+    let (supported, partial) = (count("supported"), count("partial"));
+    let mut out = String::new();
+    writeln!(out, "# cloud-config compatibility\n").unwrap();
+    writeln!(
+        out,
+        "{} example(s) use {} top-level keys: {} supported, {} partially \
+         supported, {} unsupported ({:.0}% fully supported).\n",
+        examples,
+        keys.len(),
+        supported,
+        partial,
+        keys.len() - supported - partial,
+        100.0 * supported as f64 / keys.len().max(1) as f64
+    )
+    .unwrap();
+    writeln!(out, "| Key | Status | Ignored settings | Examples |").unwrap();
+    writeln!(out, "|-----|--------|------------------|----------|").unwrap();
+    for (key, report) in keys {
+        writeln!(
+            out,
+            "| `{}` | {} | {} | {} |",
+            key,
+            report.status(),
+            report
+                .ignored
+                .iter()
+                .map(|path| format!("`{}`", path))
+                .collect::<Vec<_>>()
+                .join(", "),
+            report
+                .examples
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+        .unwrap();
+    }
+    if !unparseable.is_empty() {
+        writeln!(
+            out,
+            "\nExamples that do not parse, so none of their keys apply:\n"
+        )
+        .unwrap();
+        for (example, error) in unparseable {
+            writeln!(out, "- {}: {}", example, error).unwrap();
+        }
+    }
+    out
+}
+
+#[test]
+fn examples_are_supported_or_reported() {
+    let (examples, bundled) = examples();
+    assert!(!examples.is_empty(), "no cloud-config examples found");
+
+    let mut keys: BTreeMap<String, KeyReport> = BTreeMap::new();
+    let mut ignored_paths = BTreeSet::new();
+    let mut unparseable = BTreeMap::new();
+    let mut failures = Vec::new();
+    for example in &examples {
+        let name = example.file_name().unwrap().to_string_lossy().into_owned();
+        let content = fs::read_to_string(example).unwrap();
+        let (config, ignored) = match CloudConfig::from_yaml_reporting(&content) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                unparseable.insert(name, e.to_string());
+                continue;
+            }
+        };
+        let raw: Value = serde_yaml::from_str(&content).unwrap();
+        let parsed = serde_yaml::to_value(&config).unwrap();
+
+        for key in raw.as_mapping().into_iter().flat_map(|m| m.keys()) {
+            let Some(key) = key.as_str() else { continue };
+            let report = keys.entry(key.to_string()).or_default();
+            report.examples.insert(name.clone());
+            if ignored.iter().any(|path| path == key) {
+                report.unsupported = true;
+                continue;
+            }
+            // Not reported, so the setting must have landed in the config
+            let field = ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == key)
+                .map_or(key, |(_, field)| *field);
+            if parsed.get(field).is_none_or(Value::is_null) {
+                failures.push(format!("{}: `{}` silently dropped", name, key));
+            }
+        }
+        for path in &ignored {
+            let path = normalize(path);
+            let top = path.split('.').next().unwrap_or_default();
+            if path != top
+                && let Some(report) = keys.get_mut(top)
+            {
+                report.ignored.insert(path.clone());
+            }
+            ignored_paths.insert(path);
+        }
+    }
+
+    let matrix = render_matrix(&keys, examples.len(), &unparseable);
+    let out = std::env::var_os("COMPAT_MATRIX")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_TARGET_TMPDIR")).join("compat-matrix.md"));
+    fs::write(&out, &matrix).unwrap();
+    println!("{}\nWrote {}", matrix, out.display());
+
+    if bundled {
+        failures.extend(compare(
+            "unsupported.txt",
+            &ignored_paths,
+            "is supported",
+            "is ignored",
+        ));
+        let unparseable = unparseable.keys().cloned().collect();
+        failures.extend(compare(
+            "unparseable.txt",
+            &unparseable,
+            "parses",
+            "does not parse",
+        ));
+    } else {
+        failures.extend(
+            unparseable
+                .iter()
+                .map(|(example, e)| format!("{}: does not parse: {}", example, e)),
+        );
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
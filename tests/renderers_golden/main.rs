@@ -0,0 +1,174 @@
+//! Golden-file tests for the network renderers
+//!
+//! Every `cases/<name>.yaml` is a network config, v1 or v2, as a datasource
+//! would hand it over. It is rendered with each renderer and compared with
+//! the files checked in under `expected/<name>/<renderer>/`, so a renderer
+//! change shows up as an exact diff of its output. After an intended
+//! change, regenerate the expected files and review them:
+//!
+//! ```text
+//! UPDATE_GOLDEN=1 cargo test --test renderers_golden
+//! ```
+
+use cloud_init_rs::network::render::Renderer;
+use cloud_init_rs::network::render::eni::EniRenderer;
+use cloud_init_rs::network::render::network_manager::NetworkManagerRenderer;
+use cloud_init_rs::network::render::networkd::NetworkdRenderer;
+use cloud_init_rs::network::render::sysconfig::{SysconfigFlavor, SysconfigRenderer};
+use cloud_init_rs::network::v1::parse_network_config;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/renderers_golden")
+}
+
+/// Renderers by the directory their expected output lives in
+fn renderers() -> Vec<(&'static str, Box<dyn Renderer>)> {
+    vec![
+        ("eni", Box::new(EniRenderer::new())),
+        ("network-manager", Box::new(NetworkManagerRenderer::new())),
+        ("networkd", Box::new(NetworkdRenderer::new())),
+        (
+            "sysconfig",
+            Box::new(SysconfigRenderer::new(SysconfigFlavor::Rhel)),
+        ),
+    ]
+}
+
+/// Rendered files keyed by their path below the renderer directory
+fn render(renderer: &dyn Renderer, yaml: &str) -> BTreeMap<String, String> {
+    let config = parse_network_config(yaml).expect("case does not parse");
+    renderer
+        .render(&config, Path::new("/"))
+        .expect("case does not render")
+        .into_iter()
+        .map(|file| (file.path.trim_start_matches('/').to_string(), file.content))
+        .collect()
+}
+
+/// Files under `dir`, keyed by their path relative to it
+fn read_tree(dir: &Path) -> BTreeMap<String, String> {
+    fn walk(base: &Path, dir: &Path, files: &mut BTreeMap<String, String>) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                walk(base, &path, files);
+            } else {
+                let relative = path.strip_prefix(base).unwrap();
+                files.insert(
+                    relative.to_string_lossy().into_owned(),
+                    fs::read_to_string(&path).unwrap(),
+                );
+            }
+        }
+    }
+    let mut files = BTreeMap::new();
+    walk(dir, dir, &mut files);
+    files
+}
+
+fn write_tree(dir: &Path, files: &BTreeMap<String, String>) {
+    if dir.exists() {
+        fs::remove_dir_all(dir).unwrap();
+    }
+    for (path, content) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+}
+
+/// What differs between the expected and rendered files
+fn describe_mismatch(
+    expected: &BTreeMap<String, String>,
+    rendered: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let paths: BTreeSet<_> = expected.keys().chain(rendered.keys()).collect();
+    let mut problems = Vec::new();
+    for path in paths {
+        match (expected.get(path), rendered.get(path)) {
+            (Some(_), None) => problems.push(format!("  {}: not rendered", path)),
+            (None, Some(_)) => problems.push(format!("  {}: rendered but not expected", path)),
+            (Some(want), Some(got)) if want != got => {
+                let line = want
+                    .lines()
+                    .zip(got.lines())
+                    .position(|(w, g)| w != g)
+                    .unwrap_or_else(|| want.lines().count().min(got.lines().count()));
+                problems.push(format!(
+                    "  {} differs at line {}:\n    expected: {:?}\n    rendered: {:?}",
+                    path,
+                    line + 1,
+                    want.lines().nth(line).unwrap_or("<end of file>"),
+                    got.lines().nth(line).unwrap_or("<end of file>"),
+                ));
+            }
+            _ => {}
+        }
+    }
+    problems
+}
+
+#[test]
+fn renderers_match_golden_files() {
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let mut cases: Vec<PathBuf> = fs::read_dir(golden_dir().join("cases"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "yaml"))
+        .collect();
+    cases.sort();
+    assert!(!cases.is_empty(), "no golden cases found");
+
+    let mut failures = Vec::new();
+    for case in &cases {
+        let name = case.file_stem().unwrap().to_string_lossy();
+        let yaml = fs::read_to_string(case).unwrap();
+        for (renderer_name, renderer) in renderers() {
+            let dir = golden_dir()
+                .join("expected")
+                .join(&*name)
+                .join(renderer_name);
+            let rendered = render(renderer.as_ref(), &yaml);
+            if update {
+                write_tree(&dir, &rendered);
+                continue;
+            }
+            let problems = describe_mismatch(&read_tree(&dir), &rendered);
+            if !problems.is_empty() {
+                failures.push(format!(
+                    "{} ({}):\n{}",
+                    name,
+                    renderer_name,
+                    problems.join("\n")
+                ));
+            }
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "rendered output differs from the golden files \
+         (rerun with UPDATE_GOLDEN=1 if the change is intended):\n{}",
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn every_case_has_expected_output() {
+    for entry in fs::read_dir(golden_dir().join("cases")).unwrap() {
+        let case = entry.unwrap().path();
+        let name = case.file_stem().unwrap().to_string_lossy().into_owned();
+        for (renderer_name, _) in renderers() {
+            let dir = golden_dir()
+                .join("expected")
+                .join(&name)
+                .join(renderer_name);
+            assert!(dir.is_dir(), "missing expected output {}", dir.display());
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Integration tests for the webhook reporting handler using wiremock
+
+use cloud_init_rs::config::WebhookReportingConfig;
+use cloud_init_rs::reporting::webhook::{SIGNATURE_HEADER, sign};
+use cloud_init_rs::reporting::{Event, EventResult, ReportingHandler, WebhookHandler};
+use std::time::{Duration, Instant};
+use wiremock::matchers::{header, header_exists, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn webhook_config(
+    server: &MockServer,
+    secret: Option<&str>,
+    retries: u32,
+) -> WebhookReportingConfig {
+    WebhookReportingConfig {
+        endpoint: format!("{}/events", server.uri()),
+        secret: secret.map(str::to_string),
+        timeout: Some(2),
+        retries: Some(retries),
+    }
+}
+
+#[tokio::test]
+async fn test_webhook_posts_signed_event() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/events"))
+        .and(header("content-type", "application/json"))
+        .and(header_exists(SIGNATURE_HEADER))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let handler = WebhookHandler::new(&webhook_config(&server, Some("s3cret"), 0)).unwrap();
+    let event = Event::finish("modules-config/config-users", "users", EventResult::Success);
+    handler.publish(&event).await.unwrap();
+    handler.flush().await.unwrap();
+
+    let requests = server.received_requests().await.unwrap();
+    let request = &requests[0];
+    let signature = request.headers[SIGNATURE_HEADER].to_str().unwrap();
+    assert_eq!(
+        signature,
+        format!("sha256={}", sign(b"s3cret", &request.body))
+    );
+
+    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    assert_eq!(body["event_type"], "finish");
+    assert_eq!(body["name"], "modules-config/config-users");
+    assert_eq!(body["result"], "SUCCESS");
+}
+
+#[tokio::test]
+async fn test_webhook_unsigned_without_secret() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&server)
+        .await;
+
+    let handler = WebhookHandler::new(&webhook_config(&server, None, 0)).unwrap();
+    handler
+        .publish(&Event::start("init-local", "local"))
+        .await
+        .unwrap();
+    handler.flush().await.unwrap();
+
+    let requests = server.received_requests().await.unwrap();
+    assert!(!requests[0].headers.contains_key(SIGNATURE_HEADER));
+}
+
+#[tokio::test]
+async fn test_webhook_retries_then_fails() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let handler = WebhookHandler::new(&webhook_config(&server, None, 1)).unwrap();
+    handler
+        .publish(&Event::start("init-local", "local"))
+        .await
+        .unwrap();
+    let err = handler.flush().await.unwrap_err();
+    assert!(err.to_string().contains("1 event(s) not delivered"));
+}
+
+#[tokio::test]
+async fn test_webhook_slow_endpoint_does_not_hold_up_events() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+        .mount(&server)
+        .await;
+    let config = WebhookReportingConfig {
+        timeout: Some(1),
+        ..webhook_config(&server, None, 0)
+    };
+    let handler = WebhookHandler::new(&config).unwrap();
+
+    let start = Instant::now();
+    for module in ["users", "ntp", "runcmd"] {
+        handler
+            .publish(&Event::start("modules-config", module))
+            .await
+            .unwrap();
+    }
+    assert!(start.elapsed() < Duration::from_millis(500));
+
+    // The flush gives up after the timeout, and the rest are dropped
+    let err = handler.flush().await.unwrap_err();
+    assert!(err.to_string().contains("limit 1s"));
+    assert!(start.elapsed() < Duration::from_secs(2));
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(server.received_requests().await.unwrap().len(), 1);
+}